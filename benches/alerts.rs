@@ -0,0 +1,13 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use quadcopter_preflight::bench;
+
+fn alerts(c: &mut Criterion) {
+    bench::alert_rules(50);
+    let readings = bench::readings();
+    c.bench_function("evaluate 50 alert rules", |b| {
+        b.iter(|| bench::evaluate(black_box(&readings)))
+    });
+}
+
+criterion_group!(benches, alerts);
+criterion_main!(benches);
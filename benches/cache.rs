@@ -0,0 +1,38 @@
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use quadcopter_preflight::bench;
+
+/// Loads with the cache to themselves, and while it's published to nonstop, with other
+/// readers at it too. Publishing builds and serializes the snapshot before taking the
+/// lock, so loads shouldn't take longer.
+fn cache(c: &mut Criterion) {
+    bench::publish();
+    let mut group = c.benchmark_group("cache load");
+    group.bench_function("idle", |b| b.iter(bench::load));
+
+    let stop = AtomicBool::new(false);
+    thread::scope(|s| {
+        s.spawn(|| {
+            while !stop.load(Ordering::Relaxed) {
+                bench::publish();
+            }
+        });
+        for _ in 0..3 {
+            s.spawn(|| {
+                while !stop.load(Ordering::Relaxed) {
+                    bench::load();
+                }
+            });
+        }
+        group.bench_function("while publishing", |b| b.iter(bench::load));
+        stop.store(true, Ordering::Relaxed);
+    });
+    group.finish();
+}
+
+criterion_group!(benches, cache);
+criterion_main!(benches);
@@ -0,0 +1,20 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use quadcopter_preflight::bench;
+
+fn decode(c: &mut Criterion) {
+    let frame = bench::frame();
+    c.bench_function("decode readings", |b| {
+        b.iter(|| bench::decode(black_box(&frame)))
+    });
+
+    #[cfg(feature = "flight-controller")]
+    {
+        let frame = bench::fc_frame();
+        c.bench_function("decode fc frame", |b| {
+            b.iter(|| bench::decode_fc(black_box(&frame)))
+        });
+    }
+}
+
+criterion_group!(benches, decode);
+criterion_main!(benches);
@@ -0,0 +1,15 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use quadcopter_preflight::bench::History;
+
+fn history(c: &mut Criterion) {
+    let history = History::new(100_000);
+    let mut group = c.benchmark_group("history");
+    group.sample_size(20);
+    group.bench_function("hourly buckets of 100k samples", |b| {
+        b.iter(|| history.buckets())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, history);
+criterion_main!(benches);
@@ -0,0 +1,13 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use quadcopter_preflight::bench;
+
+fn readings(c: &mut Criterion) {
+    bench::publish();
+    let mut group = c.benchmark_group("readings json");
+    group.bench_function("cached", |b| b.iter(bench::readings_json));
+    group.bench_function("uncached", |b| b.iter(bench::readings_json_uncached));
+    group.finish();
+}
+
+criterion_group!(benches, readings);
+criterion_main!(benches);
@@ -0,0 +1,40 @@
+//! Embeds the git commit and build time, for `/api/version`. Both are optional: builds
+//! from a source archive, without git, have no commit.
+
+use std::{
+    env,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+fn main() {
+    let commit = Command::new("git")
+        .args(["rev-parse", "--short=12", "HEAD"])
+        .output()
+        .ok()
+        .filter(|o| o.status.success())
+        .and_then(|o| String::from_utf8(o.stdout).ok());
+    if let Some(commit) = commit {
+        let dirty = Command::new("git")
+            .args(["status", "--porcelain", "--untracked-files=no"])
+            .output()
+            .is_ok_and(|o| !o.stdout.is_empty());
+        let suffix = if dirty { "-dirty" } else { "" };
+        println!(
+            "cargo:rustc-env=WATER_MON_GIT_COMMIT={}{}",
+            commit.trim(),
+            suffix
+        );
+    }
+
+    // Reproducible builds set this, so the same source gives the same binary.
+    let built = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|s| s.parse::<u64>().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs())
+        });
+    println!("cargo:rustc-env=WATER_MON_BUILD_TIME={}", built);
+}
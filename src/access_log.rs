@@ -0,0 +1,277 @@
+//! An optional access log: records each request's method, path, status, client IP, and
+//! handling time to a size-rotated file, with `?token=` values redacted, and keeps the most recent requests in memory
+//! so we can report the slowest ones. File writes happen on their own thread, so
+//! handling a request only costs a timestamp, a ring-buffer push, and a channel send.
+
+use std::{
+    collections::VecDeque,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+    sync::{
+        mpsc::{self, Receiver, SyncSender},
+        Mutex,
+    },
+    thread,
+    time::Instant,
+};
+
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    response::content,
+    Data, Request, Response,
+};
+
+use serde::Serialize;
+
+use crate::{
+    auth::Admin,
+    config::AccessLogConfig,
+    memory::{Buffer, Policy},
+    proxy,
+    request_id::RequestId,
+};
+
+/// How many recent requests we keep for the slow-requests report.
+const RING_SIZE: usize = 500;
+
+/// Lines waiting to be written; if the writer falls this far behind, we drop lines
+/// rather than slow down request handling.
+const WRITE_QUEUE_SIZE: usize = 1_024;
+
+pub const RECENT_BUFFER: Buffer = Buffer {
+    name: "access_log.recent",
+    cap: RING_SIZE,
+    policy: Policy::DropOldest,
+    about: "Recent requests, for `/api/debug/slow-requests`",
+};
+
+pub const WRITE_BUFFER: Buffer = Buffer {
+    name: "access_log.writes",
+    cap: WRITE_QUEUE_SIZE,
+    policy: Policy::DropNewest,
+    about: "Access log lines waiting to be written",
+};
+
+static RECENT: Mutex<VecDeque<RequestRecord>> = Mutex::new(VecDeque::new());
+
+#[derive(Clone, Serialize)]
+pub struct RequestRecord {
+    /// RFC 3339, UTC.
+    pub time: String,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    pub client_ip: Option<String>,
+    pub duration_ms: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+/// Stored in request-local cache when a request arrives.
+struct RequestStart(Option<Instant>);
+
+pub struct AccessLog {
+    tx: SyncSender<String>,
+}
+
+impl AccessLog {
+    /// Start the writer thread, and return the fairing that feeds it.
+    pub fn new(cfg: &AccessLogConfig) -> Result<Self, io::Error> {
+        let writer = RotatingFile::open(
+            PathBuf::from(&cfg.path),
+            cfg.max_size_kb * 1_024,
+            cfg.keep_files,
+        )?;
+        let (tx, rx) = mpsc::sync_channel(WRITE_QUEUE_SIZE);
+
+        thread::spawn(move || write_lines(writer, rx));
+
+        Ok(Self { tx })
+    }
+}
+
+/// A request's URI, to log, with the values of `token` in its query, which are access
+/// tokens, redacted.
+fn logged_uri(uri: &str) -> String {
+    let (path, query) = match uri.split_once('?') {
+        Some(parts) => parts,
+        None => return uri.to_owned(),
+    };
+    let query: Vec<&str> = query
+        .split('&')
+        .map(|pair| {
+            if pair.starts_with("token=") {
+                "token=(redacted)"
+            } else {
+                pair
+            }
+        })
+        .collect();
+    format!("{}?{}", path, query.join("&"))
+}
+
+impl Fairing for AccessLog {
+    fn info(&self) -> Info {
+        Info {
+            name: "Access log",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        request.local_cache(|| RequestStart(Some(Instant::now())));
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        let start = match request.local_cache(|| RequestStart(None)).0 {
+            Some(s) => s,
+            None => return,
+        };
+
+        let record = RequestRecord {
+            time: chrono::Utc::now().to_rfc3339(),
+            method: request.method().as_str().to_owned(),
+            path: logged_uri(&request.uri().to_string()),
+            status: response.status().code,
+            client_ip: proxy::Client::from(request).ip.map(|ip| ip.to_string()),
+            duration_ms: start.elapsed().as_secs_f32() * 1_000.,
+            request_id: request.local_cache(|| RequestId(None)).0.clone(),
+        };
+
+        let line = format!(
+            "{} {} {} {} {} {:.2}ms {}\n",
+            record.time,
+            record.client_ip.as_deref().unwrap_or("-"),
+            record.method,
+            record.path,
+            record.status,
+            record.duration_ms,
+            record.request_id.as_deref().unwrap_or("-")
+        );
+        // A full queue means the writer is stuck; drop the line instead of blocking.
+        match self.tx.try_send(line) {
+            Ok(()) => WRITE_BUFFER.added(),
+            Err(_) => WRITE_BUFFER.dropped(1),
+        }
+
+        let mut recent = RECENT.lock().unwrap();
+        if recent.len() == RING_SIZE {
+            recent.pop_front();
+            RECENT_BUFFER.dropped(1);
+        }
+        recent.push_back(record);
+        RECENT_BUFFER.note(recent.len());
+    }
+}
+
+fn write_lines(mut writer: RotatingFile, rx: Receiver<String>) {
+    for line in rx {
+        WRITE_BUFFER.removed();
+        if let Err(e) = writer.write(line.as_bytes()) {
+            println!("Problem writing to the access log: {}", e);
+        }
+    }
+}
+
+/// A log file that's renamed to `<path>.1` once it reaches `max_size` bytes, shifting
+/// older files to `.2`, `.3` etc, and deleting files past `keep`.
+struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    keep: u32,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_size: u64, keep: u32) -> Result<Self, io::Error> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            path,
+            max_size,
+            keep,
+            file,
+            size,
+        })
+    }
+
+    fn rotated_path(&self, i: u32) -> PathBuf {
+        let mut p = self.path.clone().into_os_string();
+        p.push(format!(".{}", i));
+        p.into()
+    }
+
+    fn rotate(&mut self) -> Result<(), io::Error> {
+        if self.keep == 0 {
+            self.file.set_len(0)?;
+        } else {
+            // The oldest file, if present, is overwritten by the rename chain.
+            for i in (1..self.keep).rev() {
+                let from = self.rotated_path(i);
+                if from.exists() {
+                    fs::rename(&from, self.rotated_path(i + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+            self.file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<(), io::Error> {
+        if self.size + buf.len() as u64 > self.max_size && self.size > 0 {
+            self.rotate()?;
+        }
+        self.file.write_all(buf)?;
+        self.size += buf.len() as u64;
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+struct SlowRequests {
+    /// False if the access log is disabled, in which case nothing is recorded.
+    enabled: bool,
+    requests: Vec<RequestRecord>,
+}
+
+/// The slowest requests among the most recent ones, slowest first.
+#[get("/debug/slow-requests?<limit>")]
+pub fn view_slow_requests(_admin: Admin, limit: Option<usize>) -> content::Json<String> {
+    let mut requests: Vec<RequestRecord> = RECENT.lock().unwrap().iter().cloned().collect();
+    requests.sort_by(|a, b| b.duration_ms.total_cmp(&a.duration_ms));
+    requests.truncate(limit.unwrap_or(20));
+
+    let result = SlowRequests {
+        enabled: crate::config::get().access_log.enabled,
+        requests,
+    };
+    content::Json(serde_json::to_string(&result).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_arent_logged() {
+        assert_eq!(logged_uri("/api/readings"), "/api/readings");
+        assert_eq!(
+            logged_uri("/api/history?token=wm_secret&from=2021-06-01"),
+            "/api/history?token=(redacted)&from=2021-06-01"
+        );
+        assert_eq!(
+            logged_uri("/api/export.csv?from=1d&token=wm_secret"),
+            "/api/export.csv?from=1d&token=(redacted)"
+        );
+        // Only the parameter named `token`.
+        assert_eq!(logged_uri("/api/x?tokens=2"), "/api/x?tokens=2");
+    }
+}
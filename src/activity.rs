@@ -0,0 +1,131 @@
+//! Tracks when clients last asked for anything, so the poller can slow down while nobody
+//! is watching, eg on a solar-powered setup. Any HTTP request counts, as do Modbus and
+//! SNMP requests. The first request after an idle spell wakes the poller, so it polls
+//! straight away.
+
+use std::{
+    sync::{Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    Data, Request,
+};
+use serde::Serialize;
+
+use crate::{
+    config::{self, PollingConfig},
+    systemd, REFRESH_INTERVAL,
+};
+
+/// How long the poller sleeps at most between checks of systemd's watchdog.
+const WAIT_SLICE: Duration = Duration::from_secs(1);
+
+static LAST_ACTIVITY: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Set to wake the poller early.
+static WAKE: Mutex<bool> = Mutex::new(false);
+static WAKE_CONDVAR: Condvar = Condvar::new();
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    Fast,
+    Idle,
+}
+
+fn mode(cfg: &PollingConfig, last: Option<Instant>) -> Mode {
+    let idle_after = Duration::from_secs(cfg.idle_after_secs as u64);
+    if cfg.adaptive && last.is_none_or(|t| t.elapsed() >= idle_after) {
+        Mode::Idle
+    } else {
+        Mode::Fast
+    }
+}
+
+/// Note that a client asked for something.
+pub fn touch() {
+    let cfg = config::get().polling;
+    let mut last = LAST_ACTIVITY.lock().unwrap();
+    let was_idle = mode(&cfg, *last) == Mode::Idle;
+    *last = Some(Instant::now());
+
+    if was_idle {
+        wake();
+    }
+}
+
+/// Cut the poller's current wait short, so it polls straight away.
+pub fn wake() {
+    *WAKE.lock().unwrap() = true;
+    WAKE_CONDVAR.notify_all();
+}
+
+/// The time between polls, which depends on whether anyone's watching.
+pub fn interval() -> (Duration, Mode) {
+    let cfg = config::get().polling;
+    match mode(&cfg, *LAST_ACTIVITY.lock().unwrap()) {
+        Mode::Fast => (Duration::from_millis(REFRESH_INTERVAL as u64), Mode::Fast),
+        Mode::Idle => (
+            Duration::from_secs(cfg.idle_interval_secs as u64),
+            Mode::Idle,
+        ),
+    }
+}
+
+/// Sleep for `duration`, or until a client shows up after an idle spell. Keeps pinging
+/// systemd's watchdog, since idle intervals can be longer than its timeout.
+pub fn wait(duration: Duration) {
+    let deadline = Instant::now() + duration;
+    let mut wake = WAKE.lock().unwrap();
+
+    while !*wake {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        wake = WAKE_CONDVAR
+            .wait_timeout(wake, (deadline - now).min(WAIT_SLICE))
+            .unwrap()
+            .0;
+        systemd::ping_watchdog();
+    }
+    *wake = false;
+}
+
+#[derive(Serialize)]
+pub struct PollingStatus {
+    pub mode: Mode,
+    pub interval_ms: u64,
+    /// Since the last client request, if there's been one.
+    pub seconds_since_activity: Option<f32>,
+}
+
+pub fn status() -> PollingStatus {
+    let (interval, mode) = interval();
+    PollingStatus {
+        mode,
+        interval_ms: interval.as_millis() as u64,
+        seconds_since_activity: LAST_ACTIVITY
+            .lock()
+            .unwrap()
+            .map(|t| t.elapsed().as_secs_f32()),
+    }
+}
+
+/// Counts every HTTP request as activity.
+pub struct ActivityTracker;
+
+impl Fairing for ActivityTracker {
+    fn info(&self) -> Info {
+        Info {
+            name: "Client activity",
+            kind: Kind::Request,
+        }
+    }
+
+    fn on_request(&self, _request: &mut Request, _: &Data) {
+        touch();
+    }
+}
@@ -0,0 +1,839 @@
+//! Alert rules on combinations of readings, for problems no single target range shows,
+//! like ORP below 600 while pH is above 7.8, or EC rising while temperature is stable.
+//! A rule's condition nests `all` and `any` of sensor thresholds and trends, where a
+//! trend is the least-squares rate of change per hour over a window of recent readings,
+//! and it can be limited to a time of day. A sensor can also be tested against its
+//! `baseline`, a normal band learned from history, and a binary input, like a float
+//! switch, on whether it's `active`, and the water-quality score, as `sensor = "score"`,
+//! `below` or `above` a value. Conditions on sensors the flow sensor `gates` aren't
+//! met while there's no flow. Rules are evaluated each poll cycle, or for a `device`
+//! that pushes its readings, on each push; one becoming true, for `for_mins` if set, raises an alert event, and `/api/alerts`
+//! shows each sub-condition's state, and learned band, for debugging a rule that won't
+//! fire.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io::{self, Read},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use chrono::{NaiveTime, Utc};
+use rocket::{http::Status, response::content, Data};
+use serde::Serialize;
+
+use crate::{
+    api::{self, ErrorResponse},
+    auth::Admin,
+    baseline::{self, BaselineState},
+    config::{self, AlertBaseline, AlertCondition, AlertRule, AppConfig, InputConfig},
+    device_meta,
+    events::{self, Severity},
+    flow, freshness, inputs, maintenance,
+    memory::{Buffer, Policy},
+    precision,
+    registry::{self, SensorDef},
+    score,
+    tokens::Viewer,
+    tz,
+    units::{self, Quantity},
+    warmup, Readings,
+};
+
+const DEFAULT_WINDOW_MINS: u32 = 15;
+
+const MAX_WINDOW_MINS: u32 = 24 * 60;
+
+/// Readings kept for trends are at least this far apart, so a day's fits in memory.
+const MIN_SAMPLE_GAP: Duration = Duration::from_secs(5);
+
+/// A day's, at `MIN_SAMPLE_GAP`, in case the clock jumps.
+const MAX_SAMPLES: usize = (MAX_WINDOW_MINS as u64 * 60 / MIN_SAMPLE_GAP.as_secs()) as usize + 1;
+
+pub const BUFFER: Buffer = Buffer {
+    name: "alerts.samples",
+    cap: MAX_SAMPLES,
+    policy: Policy::DropOldest,
+    about: "Recent readings, for trend conditions",
+};
+
+/// How deeply `all` and `any` may nest.
+const MAX_DEPTH: usize = 5;
+
+const MAX_BASELINE_DAYS: u32 = 366;
+
+/// Recent readings, oldest first, for trends.
+/// Recent readings, by device.
+static SAMPLES: Mutex<BTreeMap<String, VecDeque<(Instant, Readings)>>> =
+    Mutex::new(BTreeMap::new());
+
+/// By rule name.
+static STATES: Mutex<BTreeMap<String, RuleState>> = Mutex::new(BTreeMap::new());
+
+#[derive(Default)]
+struct RuleState {
+    active: bool,
+    /// RFC 3339, UTC.
+    since: Option<String>,
+    /// When the condition last became true, for `for_mins`.
+    met_since: Option<Instant>,
+    when: Option<ConditionState>,
+    in_hours: bool,
+}
+
+/// A condition's state at the last evaluation.
+#[derive(Clone, Serialize)]
+pub struct ConditionState {
+    /// What it tests, eg `ORP below 600`.
+    pub condition: String,
+    pub met: bool,
+    /// The reading, rate per hour, or input's 1 or 0, it was tested on. `None` for an
+    /// error, or a trend without enough readings yet.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<f32>,
+    /// For a `baseline`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub baseline: Option<BaselineState>,
+    /// Of `all` and `any`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub conditions: Vec<ConditionState>,
+}
+
+#[derive(Serialize)]
+pub struct AlertState {
+    pub name: String,
+    pub active: bool,
+    /// When it became active.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub since: Option<String>,
+    /// If it's within its `between` times, or has none.
+    pub in_hours: bool,
+    /// If a baseline it tests is still being learned.
+    pub learning: bool,
+    /// `None` until the first poll cycle after the rule was added.
+    pub when: Option<ConditionState>,
+}
+
+enum Test {
+    Below(f32),
+    Above(f32),
+    Rising(f32),
+    Falling(f32),
+    Stable(f32),
+    Baseline(AlertBaseline),
+}
+
+enum Condition<'a> {
+    All(&'a [AlertCondition]),
+    Any(&'a [AlertCondition]),
+    Input {
+        name: &'a str,
+        active: bool,
+    },
+    Sensor {
+        sensor: &'static str,
+        test: Test,
+        window_mins: u32,
+    },
+    /// `Below` or `Above`.
+    Score(Test),
+}
+
+/// What a condition is, or why it's invalid.
+fn parse(c: &AlertCondition) -> Result<Condition<'_>, String> {
+    if let Some(name) = &c.input {
+        let others = c.sensor.is_some()
+            || c.window_mins.is_some()
+            || !c.all.is_empty()
+            || !c.any.is_empty()
+            || c.below.is_some()
+            || c.above.is_some()
+            || c.rising.is_some()
+            || c.falling.is_some()
+            || c.stable.is_some()
+            || c.baseline.is_some()
+            || c.unit.is_some();
+        if others {
+            return Err("An `input` condition can only have `active`".into());
+        }
+        return Ok(Condition::Input {
+            name,
+            active: c.active.unwrap_or(true),
+        });
+    }
+    if c.active.is_some() {
+        return Err("Only `input` conditions have `active`".into());
+    }
+    let tests = [
+        c.below.map(Test::Below),
+        c.above.map(Test::Above),
+        c.rising.map(Test::Rising),
+        c.falling.map(Test::Falling),
+        c.stable.map(Test::Stable),
+        c.baseline.clone().map(Test::Baseline),
+    ];
+    let kinds = tests.iter().filter(|t| t.is_some()).count()
+        + !c.all.is_empty() as usize
+        + !c.any.is_empty() as usize;
+    if kinds != 1 {
+        return Err(
+            "Each condition needs an `input`, or exactly one of `all`, `any`, `below`, \
+             `above`, `rising`, `falling`, `stable` or `baseline`"
+                .into(),
+        );
+    }
+    if c.unit.is_some() && c.sensor.is_none() {
+        return Err("Only sensor conditions have a `unit`".into());
+    }
+    if !c.all.is_empty() {
+        return Ok(Condition::All(&c.all));
+    }
+    if !c.any.is_empty() {
+        return Ok(Condition::Any(&c.any));
+    }
+
+    if c.sensor.as_deref() == Some(score::SENSOR) {
+        if c.unit.is_some() || c.window_mins.is_some() {
+            return Err("The score has no `unit` or `window_mins`".into());
+        }
+        return match tests.into_iter().flatten().next().unwrap() {
+            t @ (Test::Below(_) | Test::Above(_)) => Ok(Condition::Score(t)),
+            _ => Err("The score can only be `below` or `above` a value".into()),
+        };
+    }
+    let sensor = match &c.sensor {
+        Some(s) => registry::get(s).ok_or_else(|| format!("Unknown sensor `{}`", s))?,
+        None => return Err("A threshold, trend or baseline needs a `sensor`".into()),
+    };
+    // Evaluated in the registry's units.
+    let unit = c.unit.as_deref().unwrap_or(units::canonical(sensor));
+    units::check(sensor, unit)?;
+    let to = units::canonical(sensor);
+    let level = |v| units::convert(sensor, unit, to, v, Quantity::Level);
+    let change = |v| units::convert(sensor, unit, to, v, Quantity::Change);
+    let test = match tests.into_iter().flatten().next().unwrap() {
+        Test::Below(x) => Test::Below(level(x)),
+        Test::Above(x) => Test::Above(level(x)),
+        Test::Rising(x) => Test::Rising(change(x)),
+        Test::Falling(x) => Test::Falling(change(x)),
+        Test::Stable(x) => Test::Stable(change(x)),
+        Test::Baseline(mut b) => {
+            b.low = b.low.map(level);
+            b.high = b.high.map(level);
+            Test::Baseline(b)
+        }
+    };
+    let sensor = sensor.id;
+    let trend = matches!(test, Test::Rising(_) | Test::Falling(_) | Test::Stable(_));
+    let window_mins = match c.window_mins {
+        Some(_) if !trend => return Err("Only trends have a `window_mins`".into()),
+        Some(w) if w == 0 || w > MAX_WINDOW_MINS => {
+            return Err(format!(
+                "`window_mins` must be from 1 to {}",
+                MAX_WINDOW_MINS
+            ))
+        }
+        Some(w) => w,
+        None => DEFAULT_WINDOW_MINS,
+    };
+    if let Test::Rising(r) | Test::Falling(r) | Test::Stable(r) = test {
+        if r.is_nan() || r < 0. {
+            return Err("Rates can't be negative; use `falling` for a drop".into());
+        }
+    }
+    if let Test::Baseline(b) = &test {
+        check_baseline(b)?;
+    }
+    Ok(Condition::Sensor {
+        sensor,
+        test,
+        window_mins,
+    })
+}
+
+fn check_baseline(b: &AlertBaseline) -> Result<(), String> {
+    if b.days == 0 || b.days > MAX_BASELINE_DAYS {
+        return Err(format!(
+            "A baseline's `days` must be from 1 to {}",
+            MAX_BASELINE_DAYS
+        ));
+    }
+    if b.min_days > b.days {
+        return Err("A baseline's `min_days` can't be more than its `days`".into());
+    }
+    if !(0. ..=100.).contains(&b.low_pct)
+        || !(0. ..=100.).contains(&b.high_pct)
+        || b.low_pct >= b.high_pct
+    {
+        return Err(
+            "A baseline's `low_pct` and `high_pct` must be from 0 to 100, low below high".into(),
+        );
+    }
+    if b.margin_pct.is_nan() || b.margin_pct < 0. {
+        return Err("A baseline's `margin_pct` can't be negative".into());
+    }
+    if let (Some(low), Some(high)) = (b.low, b.high) {
+        if low >= high {
+            return Err("A baseline's `low` must be below its `high`".into());
+        }
+    }
+    Ok(())
+}
+
+fn check_condition(c: &AlertCondition, inputs: &[InputConfig], depth: usize) -> Result<(), String> {
+    if depth > MAX_DEPTH {
+        return Err(format!("`all` and `any` nest at most {} deep", MAX_DEPTH));
+    }
+    match parse(c)? {
+        Condition::All(cs) | Condition::Any(cs) => cs
+            .iter()
+            .try_for_each(|c| check_condition(c, inputs, depth + 1)),
+        Condition::Input { name, .. } if !inputs.iter().any(|i| i.name == name) => {
+            Err(format!("Unknown input `{}`", name))
+        }
+        Condition::Input { .. } | Condition::Sensor { .. } | Condition::Score(_) => Ok(()),
+    }
+}
+
+fn parse_time(s: &str) -> Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(s, "%H:%M").map_err(|_| format!("`{}` isn't a time like `22:00`", s))
+}
+
+/// What in a condition only this device has, if anything.
+fn local_only(c: &AlertCondition) -> Option<&'static str> {
+    if c.input.is_some() {
+        Some("an input")
+    } else if c.baseline.is_some() {
+        Some("a baseline")
+    } else if c.sensor.as_deref() == Some(score::SENSOR) {
+        Some("the score")
+    } else {
+        c.all.iter().chain(&c.any).find_map(local_only)
+    }
+}
+
+/// The device a rule tests the readings of.
+fn device(rule: &AlertRule) -> &str {
+    rule.device.as_deref().unwrap_or(device_meta::LOCAL)
+}
+
+/// Check a rule's condition, against the configured inputs, its times, and its device.
+pub fn check_rule(rule: &AlertRule, cfg: &AppConfig) -> Result<(), String> {
+    if rule.name.is_empty() {
+        return Err("An alert rule has no name".into());
+    }
+    let device = device(rule);
+    if device != device_meta::LOCAL {
+        if !cfg
+            .devices
+            .iter()
+            .any(|d| d.name == device && d.url.is_none())
+        {
+            return Err(format!(
+                "Alert `{}`: no device `{}` pushes its readings",
+                rule.name, device
+            ));
+        }
+        if let Some(what) = local_only(&rule.when) {
+            return Err(format!(
+                "Alert `{}`: a rule on `{}` can't test {}, which is this device's",
+                rule.name, device, what
+            ));
+        }
+    }
+    if let Some([start, end]) = &rule.between {
+        parse_time(start)?;
+        parse_time(end)?;
+    }
+    if rule.for_mins.is_some_and(|m| m > MAX_WINDOW_MINS) {
+        return Err(format!(
+            "Alert `{}`: `for_mins` must be at most {}",
+            rule.name, MAX_WINDOW_MINS
+        ));
+    }
+    check_condition(&rule.when, &cfg.inputs, 1).map_err(|e| format!("Alert `{}`: {}", rule.name, e))
+}
+
+/// Check `[[alerts]]`.
+pub fn check(cfg: &AppConfig) -> Result<(), io::Error> {
+    let invalid = |msg: String| {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Config error: {}", msg),
+        ))
+    };
+    for (i, rule) in cfg.alerts.iter().enumerate() {
+        if let Err(e) = check_rule(rule, cfg) {
+            return invalid(e);
+        }
+        if cfg.alerts[..i].iter().any(|r| r.name == rule.name) {
+            return invalid(format!("There's more than one alert named `{}`", rule.name));
+        }
+    }
+    Ok(())
+}
+
+/// The least-squares rate of change of a sensor, per hour, over the window. `None`
+/// until readings span at least half of it.
+fn rate(
+    samples: &VecDeque<(Instant, Readings)>,
+    sensor: &str,
+    window: Duration,
+    now: Instant,
+) -> Option<f32> {
+    let points: Vec<(f64, f64)> = samples
+        .iter()
+        .filter(|(t, _)| now - *t <= window)
+        .filter_map(|(t, r)| {
+            let hours = -(now - *t).as_secs_f64() / 3_600.;
+            r.reading(sensor).ok().map(|v| (hours, v as f64))
+        })
+        .collect();
+    let (first, last) = (points.first()?, points.last()?);
+    if last.0 - first.0 < window.as_secs_f64() / 3_600. / 2. {
+        return None;
+    }
+
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|p| p.0).sum::<f64>() / n;
+    let mean_y = points.iter().map(|p| p.1).sum::<f64>() / n;
+    let (mut num, mut den) = (0., 0.);
+    for (x, y) in points.iter() {
+        num += (x - mean_x) * (y - mean_y);
+        den += (x - mean_x).powi(2);
+    }
+    (den > 0.).then(|| (num / den) as f32)
+}
+
+/// `local` if the readings are this device's, whose flow, staleness and warm-up apply.
+fn evaluate_condition(
+    c: &AlertCondition,
+    local: bool,
+    readings: &Readings,
+    samples: &VecDeque<(Instant, Readings)>,
+    now: Instant,
+    cfg: &AppConfig,
+) -> ConditionState {
+    // Rules are checked when they're added, so this doesn't fail.
+    let condition = match parse(c) {
+        Ok(c) => c,
+        Err(e) => {
+            return ConditionState {
+                condition: e,
+                met: false,
+                value: None,
+                baseline: None,
+                conditions: Vec::new(),
+            }
+        }
+    };
+
+    if let (true, Condition::Sensor { sensor, .. }) = (local, &condition) {
+        let sensor = *sensor;
+        let skipped = if flow::gated(&cfg.flow, sensor) {
+            Some("no flow")
+        } else if freshness::stale(sensor, &cfg.status) {
+            Some("stale reading")
+        } else if warmup::stabilizing(sensor) {
+            Some("stabilizing")
+        } else {
+            None
+        };
+        if let Some(reason) = skipped {
+            return ConditionState {
+                condition: format!("{} not tested: {}", sensor, reason),
+                met: false,
+                value: None,
+                baseline: None,
+                conditions: Vec::new(),
+            };
+        }
+    }
+
+    match condition {
+        Condition::All(cs) | Condition::Any(cs) => {
+            let conditions: Vec<_> = cs
+                .iter()
+                .map(|c| evaluate_condition(c, local, readings, samples, now, cfg))
+                .collect();
+            let (label, met) = if matches!(condition, Condition::All(_)) {
+                ("all of", conditions.iter().all(|c| c.met))
+            } else {
+                ("any of", conditions.iter().any(|c| c.met))
+            };
+            ConditionState {
+                condition: label.into(),
+                met,
+                value: None,
+                baseline: None,
+                conditions,
+            }
+        }
+        Condition::Input { name, active } => {
+            let actual = inputs::active(name);
+            ConditionState {
+                condition: format!(
+                    "input {} {}",
+                    name,
+                    if active { "active" } else { "inactive" }
+                ),
+                met: actual == Some(active),
+                value: actual.map(|a| if a { 1. } else { 0. }),
+                baseline: None,
+                conditions: Vec::new(),
+            }
+        }
+        Condition::Score(test) => {
+            let actual = score::current(cfg, readings).score.map(f32::from);
+            let (label, met) = match test {
+                Test::Below(x) => (format!("below {}", x), actual.is_some_and(|a| a < x)),
+                Test::Above(x) => (format!("above {}", x), actual.is_some_and(|a| a > x)),
+                _ => unreachable!(),
+            };
+            ConditionState {
+                condition: format!("{} {}", score::SENSOR, label),
+                met,
+                value: actual,
+                baseline: None,
+                conditions: Vec::new(),
+            }
+        }
+        Condition::Sensor {
+            sensor,
+            test: Test::Baseline(b),
+            ..
+        } => {
+            let band = baseline::state(sensor, &b);
+            let actual = readings.reading(sensor).ok();
+            let met = actual.is_some_and(|v| {
+                band.low.is_some_and(|low| v < low) || band.high.is_some_and(|high| v > high)
+            });
+            ConditionState {
+                condition: format!("{} outside its baseline", sensor),
+                met,
+                value: actual.map(|v| precision::value(sensor, v, &cfg.precision)),
+                baseline: Some(band),
+                conditions: Vec::new(),
+            }
+        }
+        Condition::Sensor {
+            sensor,
+            test,
+            window_mins,
+        } => {
+            let window = Duration::from_secs(window_mins as u64 * 60);
+            let over = format!("/h over {} min", window_mins);
+            let (label, value, met): (String, _, fn(f32, f32) -> bool) = match test {
+                Test::Below(x) => (format!("below {}", x), x, |v, x| v < x),
+                Test::Above(x) => (format!("above {}", x), x, |v, x| v > x),
+                Test::Rising(x) => (format!("rising {}{}", x, over), x, |v, x| v >= x),
+                Test::Falling(x) => (format!("falling {}{}", x, over), x, |v, x| -v >= x),
+                Test::Stable(x) => (format!("stable within {}{}", x, over), x, |v, x| {
+                    v.abs() <= x
+                }),
+                Test::Baseline(_) => unreachable!(),
+            };
+            let actual = match test {
+                Test::Below(_) | Test::Above(_) => readings.reading(sensor).ok(),
+                _ => rate(samples, sensor, window, now),
+            };
+            ConditionState {
+                condition: format!("{} {}", sensor, label),
+                met: actual.is_some_and(|a| met(a, value)),
+                // Tested as measured, and shown to the sensor's precision, as are rates.
+                value: actual.map(|a| precision::value(sensor, a, &cfg.precision)),
+                baseline: None,
+                conditions: Vec::new(),
+            }
+        }
+    }
+}
+
+fn learning(state: &ConditionState) -> bool {
+    state.baseline.as_ref().is_some_and(|b| b.learning) || state.conditions.iter().any(learning)
+}
+
+/// If `t` is from `start` up to `end`, which is the next day if it's earlier.
+fn in_between(between: &[String; 2], t: NaiveTime) -> bool {
+    match (parse_time(&between[0]), parse_time(&between[1])) {
+        (Ok(start), Ok(end)) if start <= end => t >= start && t < end,
+        (Ok(start), Ok(end)) => t >= start || t < end,
+        _ => true,
+    }
+}
+
+/// The met leaf conditions, for the alert message.
+fn met_leaves(state: &ConditionState, out: &mut Vec<String>) {
+    if state.conditions.is_empty() {
+        if state.met {
+            match state.value {
+                Some(v) => out.push(format!("{} (at {})", state.condition, v)),
+                None => out.push(state.condition.clone()),
+            }
+        }
+    } else {
+        state.conditions.iter().for_each(|c| met_leaves(c, out));
+    }
+}
+
+/// Evaluate each rule on `device` against its latest readings, and raise or clear its
+/// alert. Called by the poller, each cycle with readings, and by `ingest`, for each push.
+/// Not in maintenance, when this device's readings are off as probes are serviced; its
+/// rules stay as they were, but must hold for `for_mins` anew.
+pub fn evaluate(device: &str, readings: &Readings) {
+    let cfg = config::get();
+    let local = device == device_meta::LOCAL;
+    let rules = || cfg.alerts.iter().filter(|r| self::device(r) == device);
+    if local && maintenance::active() {
+        let mut states = STATES.lock().unwrap();
+        for rule in rules() {
+            if let Some(s) = states.get_mut(&rule.name) {
+                s.met_since = None;
+            }
+        }
+        return;
+    }
+    let now = Instant::now();
+
+    let mut all_samples = SAMPLES.lock().unwrap();
+    all_samples.retain(|d, _| d == device_meta::LOCAL || cfg.devices.iter().any(|c| &c.name == d));
+    let samples = all_samples.entry(device.to_owned()).or_default();
+    if samples
+        .back()
+        .is_none_or(|(t, _)| now - *t >= MIN_SAMPLE_GAP)
+    {
+        if samples.len() == MAX_SAMPLES {
+            samples.pop_front();
+            BUFFER.dropped(1);
+        }
+        samples.push_back((now, readings.clone()));
+    }
+    let keep = Duration::from_secs(MAX_WINDOW_MINS as u64 * 60);
+    while samples.front().is_some_and(|(t, _)| now - *t > keep) {
+        samples.pop_front();
+    }
+    BUFFER.note(samples.len());
+    if cfg.alerts.is_empty() {
+        return;
+    }
+
+    let local_time = Utc::now().with_timezone(&tz::configured()).time();
+    let mut states = STATES.lock().unwrap();
+    states.retain(|name, _| cfg.alerts.iter().any(|r| &r.name == name));
+
+    for rule in rules() {
+        let when = evaluate_condition(&rule.when, local, readings, samples, now, &cfg);
+        let in_hours = rule
+            .between
+            .as_ref()
+            .is_none_or(|b| in_between(b, local_time));
+        let state = states.entry(rule.name.clone()).or_default();
+        state.met_since = if when.met {
+            state.met_since.or(Some(now))
+        } else {
+            None
+        };
+        let held = Duration::from_secs(rule.for_mins.unwrap_or(0) as u64 * 60);
+        let active = in_hours && state.met_since.is_some_and(|t| now - t >= held);
+
+        if active && !state.active {
+            let mut leaves = Vec::new();
+            met_leaves(&when, &mut leaves);
+            events::record_rule(
+                Severity::Alert,
+                &rule.name,
+                format!("`{}`: {}.", rule.name, leaves.join(", and ")),
+            );
+            state.since = Some(Utc::now().to_rfc3339());
+        } else if !active && state.active {
+            events::record_rule(
+                Severity::Info,
+                &rule.name,
+                format!("`{}` has cleared.", rule.name),
+            );
+            state.since = None;
+        }
+        state.active = active;
+        state.in_hours = in_hours;
+        state.when = Some(when);
+    }
+}
+
+/// The rules that are active, as of the last poll cycle.
+pub fn active() -> Vec<String> {
+    STATES
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, s)| s.active)
+        .map(|(name, _)| name.clone())
+        .collect()
+}
+
+/// Each rule, if it's active, and its conditions' states as of the last poll cycle.
+#[get("/alerts")]
+pub fn view_alerts(_viewer: Viewer) -> content::Json<String> {
+    let states = STATES.lock().unwrap();
+    let alerts: Vec<_> = config::get()
+        .alerts
+        .into_iter()
+        .map(|rule| {
+            let state = states.get(&rule.name);
+            AlertState {
+                active: state.is_some_and(|s| s.active),
+                since: state.and_then(|s| s.since.clone()),
+                in_hours: state.is_none_or(|s| s.in_hours),
+                learning: state.and_then(|s| s.when.as_ref()).is_some_and(learning),
+                when: state.and_then(|s| s.when.clone()),
+                name: rule.name,
+            }
+        })
+        .collect();
+    content::Json(serde_json::to_string(&alerts).unwrap())
+}
+
+/// A condition with its sensors' values, and units, in those `to` gives. Ones without a
+/// unit are taken to be in `default`'s. Invalid units are left for `parse` to report.
+fn in_units(
+    c: &AlertCondition,
+    default: fn(&SensorDef) -> &'static str,
+    to: fn(&SensorDef) -> &'static str,
+) -> AlertCondition {
+    let mut c = c.clone();
+    c.all = c.all.iter().map(|c| in_units(c, default, to)).collect();
+    c.any = c.any.iter().map(|c| in_units(c, default, to)).collect();
+    let Some(sensor) = c.sensor.as_deref().and_then(registry::get) else {
+        return c;
+    };
+    let from = c.unit.clone().unwrap_or_else(|| default(sensor).into());
+    if units::check(sensor, &from).is_err() {
+        return c;
+    }
+    let to = to(sensor);
+    let level = |v| units::convert(sensor, &from, to, v, Quantity::Level);
+    let change = |v| units::convert(sensor, &from, to, v, Quantity::Change);
+    c.below = c.below.map(level);
+    c.above = c.above.map(level);
+    c.rising = c.rising.map(change);
+    c.falling = c.falling.map(change);
+    c.stable = c.stable.map(change);
+    if let Some(b) = c.baseline.as_mut() {
+        b.low = b.low.map(level);
+        b.high = b.high.map(level);
+    }
+    c.unit = Some(to.into());
+    c
+}
+
+/// Rules as the API gives them: in the locale's units, each with its unit.
+fn displayed(rules: &[AlertRule]) -> Vec<AlertRule> {
+    rules
+        .iter()
+        .map(|r| AlertRule {
+            when: in_units(&r.when, units::canonical, units::display),
+            ..r.clone()
+        })
+        .collect()
+}
+
+/// The rules, with values in the locale's units, eg °F in the US.
+#[get("/alerts/rules")]
+pub fn view_rules() -> content::Json<String> {
+    content::Json(serde_json::to_string(&displayed(&config::get().alerts)).unwrap())
+}
+
+fn save_rules(rules: &[AlertRule]) -> Result<content::Json<String>, ErrorResponse> {
+    let config = config::set_section("alerts", &rules).map_err(|e| {
+        api::error(
+            Status::InternalServerError,
+            &format!("Problem saving the config: {}", e),
+        )
+    })?;
+    Ok(content::Json(
+        serde_json::to_string(&displayed(&config.alerts)).unwrap(),
+    ))
+}
+
+/// Add a rule, in the config file too. It's evaluated from the next poll cycle. Values
+/// without a `unit` are in the locale's units; they're saved in the registry's, with
+/// the unit, so they mean the same if the locale changes.
+#[post("/alerts/rules", data = "<data>")]
+pub fn add_rule(_admin: Admin, data: Data) -> Result<content::Json<String>, ErrorResponse> {
+    let mut body = String::new();
+    data.open()
+        .take(16 * 1_024)
+        .read_to_string(&mut body)
+        .map_err(|e| api::error(Status::BadRequest, &e.to_string()))?;
+    let mut rule: AlertRule = serde_json::from_str(&body)
+        .map_err(|e| api::error(Status::BadRequest, &format!("Invalid rule: {}", e)))?;
+    check_rule(&rule, &config::get()).map_err(|e| api::error(Status::BadRequest, &e))?;
+    rule.when = in_units(&rule.when, units::display, units::canonical);
+
+    let mut rules = config::get().alerts;
+    if rules.iter().any(|r| r.name == rule.name) {
+        return Err(api::error(
+            Status::Conflict,
+            &format!("There's already an alert named `{}`", rule.name),
+        ));
+    }
+    rules.push(rule);
+    save_rules(&rules)
+}
+
+#[delete("/alerts/rules/<name>")]
+pub fn delete_rule(_admin: Admin, name: String) -> Result<content::Json<String>, ErrorResponse> {
+    let mut rules = config::get().alerts;
+    let len = rules.len();
+    rules.retain(|r| r.name != name);
+    if rules.len() == len {
+        return Err(api::error(
+            Status::NotFound,
+            &format!("No alert named `{}`", name),
+        ));
+    }
+    save_rules(&rules)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_rules_are_on_pushing_devices() {
+        let cfg: AppConfig = toml::from_str(
+            r#"
+            [[devices]]
+            name = "north"
+            url = "http://192.168.1.21"
+            [[devices]]
+            name = "well"
+            token = "secret"
+            "#,
+        )
+        .unwrap();
+        let rule = |toml: &str| -> AlertRule { toml::from_str(toml).unwrap() };
+
+        let warm = rule("name = 'warm'\ndevice = 'well'\nwhen = { sensor = 'T', above = 15 }");
+        assert!(check_rule(&warm, &cfg).is_ok());
+
+        let fetched = rule("name = 'warm'\ndevice = 'north'\nwhen = { sensor = 'T', above = 15 }");
+        assert!(check_rule(&fetched, &cfg).is_err());
+
+        let score = rule(
+            "name = 'poor'\ndevice = 'well'\n\
+             when = { any = [{ sensor = 'T', above = 15 }, { sensor = 'score', below = 50 }] }",
+        );
+        assert!(check_rule(&score, &cfg).unwrap_err().contains("the score"));
+    }
+
+    #[test]
+    fn score_rules_are_below_or_above() {
+        let cfg = AppConfig::default();
+        let rule = |when: &str| -> AlertRule {
+            toml::from_str(&format!("name = 'poor'\nwhen = {}", when)).unwrap()
+        };
+        assert!(check_rule(&rule("{ sensor = 'score', below = 50 }"), &cfg).is_ok());
+        assert!(check_rule(&rule("{ sensor = 'score', rising = 5 }"), &cfg).is_err());
+        assert!(check_rule(&rule("{ sensor = 'score', above = 80, unit = '%' }"), &cfg).is_err());
+    }
+}
@@ -0,0 +1,68 @@
+//! API versioning. Each version is a separate mount whose routes share handlers
+//! parameterized by `ApiVersion`; a version's response shapes are frozen once it's
+//! released, so shape changes go in a new version mounted alongside the old ones.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use rocket::{
+    http::Status,
+    response::{self, content, status, Responder, Response},
+    Request,
+};
+
+use crate::ApiError;
+
+/// An error response, with an `ApiError` body.
+pub type ErrorResponse = status::Custom<content::Json<String>>;
+
+pub fn error(status: Status, msg: &str) -> ErrorResponse {
+    status::Custom(status, content::Json(ApiError::json(msg)))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ApiVersion {
+    /// The original routes directly under `/api`. Deprecated in favor of `V1`.
+    Unversioned,
+    V1,
+}
+
+impl ApiVersion {
+    /// Where this version's routes are mounted.
+    pub fn base(&self) -> &'static str {
+        match self {
+            Self::Unversioned => "/api",
+            Self::V1 => "/api/v1",
+        }
+    }
+}
+
+static DEPRECATION_WARNED: AtomicBool = AtomicBool::new(false);
+
+/// Wraps a response from a deprecated route: adds a `Deprecation` header and a link
+/// to its successor, and logs a warning the first time any deprecated route is used.
+pub struct Deprecated<R> {
+    pub inner: R,
+    /// Path of the route replacing this one, eg `/api/v1/readings`.
+    pub successor: &'static str,
+}
+
+impl<'r, R: Responder<'r>> Responder<'r> for Deprecated<R> {
+    fn respond_to(self, req: &Request) -> response::Result<'r> {
+        if !DEPRECATION_WARNED.swap(true, Ordering::Relaxed) {
+            println!(
+                "Warning: a client requested the deprecated route `{}`; use `{}` instead. \
+                 (Further deprecated requests won't be logged.)",
+                req.uri().path(),
+                self.successor
+            );
+        }
+
+        Response::build_from(self.inner.respond_to(req)?)
+            .raw_header("Deprecation", "true")
+            .raw_header(
+                "Link",
+                format!("<{}>; rel=\"successor-version\"", self.successor),
+            )
+            .ok()
+    }
+}
@@ -0,0 +1,76 @@
+//! Authorization for admin-only routes. Admin requests must send the configured
+//! `auth.admin_token` as `Authorization: Bearer <token>`, or have a session from logging
+//! in with the password. Until a token or password is configured, admin routes are
+//! disabled.
+
+use rocket::{
+    http::Status,
+    request::{self, FromRequest},
+    Outcome, Request,
+};
+
+use crate::{
+    api::{self, ErrorResponse},
+    config, session,
+};
+
+/// A request guard for admin-only routes.
+pub struct Admin;
+
+impl<'a, 'r> FromRequest<'a, 'r> for Admin {
+    type Error = &'static str;
+
+    fn from_request(req: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        if !enabled() {
+            return Outcome::Failure((
+                Status::Forbidden,
+                "Admin routes are disabled until `auth.admin_token` or a password is set",
+            ));
+        }
+
+        let given = req
+            .headers()
+            .get_one("Authorization")
+            .and_then(|h| h.strip_prefix("Bearer "));
+
+        match (given, admin_token()) {
+            (Some(g), Some(token)) if constant_time_eq(g.as_bytes(), token.as_bytes()) => {
+                Outcome::Success(Admin)
+            }
+            _ if session::valid(req) => Outcome::Success(Admin),
+            _ => Outcome::Failure((
+                Status::Unauthorized,
+                "Missing or wrong admin token, and not logged in",
+            )),
+        }
+    }
+}
+
+/// Compare without leaking, through timing, how much of a guess was right.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn admin_token() -> Option<String> {
+    config::get().auth.admin_token.filter(|t| !t.is_empty())
+}
+
+fn enabled() -> bool {
+    admin_token().is_some() || session::enabled(&config::get().auth)
+}
+
+/// For routes where only some requests need admin, taking `Result<Admin, &str>` as a
+/// guard: the error response the guard would have given.
+pub fn require(admin: Result<Admin, &'static str>) -> Result<Admin, ErrorResponse> {
+    admin.map_err(|msg| {
+        let status = if !enabled() {
+            Status::Forbidden
+        } else {
+            Status::Unauthorized
+        };
+        api::error(status, msg)
+    })
+}
@@ -0,0 +1,377 @@
+//! Backing up and restoring the history database and config, as a zip with a versioned
+//! manifest. Calibration lives on the Water Monitor itself, so there's no calibration
+//! state here to include.
+//!
+//! Backups are admin-only. Secrets are redacted from their config, as in the support
+//! bundle; restoring keeps the current secrets, and the current `[auth]` settings.
+
+use std::{
+    env,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use chrono::Utc;
+use rocket::{
+    http::{ContentType, Status},
+    response::{self, content, Responder, Response},
+    Data, Request,
+};
+use rusqlite::{params, Connection, DatabaseName, OpenFlags};
+use serde::{Deserialize, Serialize};
+use zip::{write::FileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::{
+    api::{self, ErrorResponse},
+    auth::Admin,
+    config::{self, AppConfig, CONFIG_PATH},
+    events::{self, Severity},
+    history::{self, SCHEMA_VERSION},
+    validate,
+};
+
+/// Bump this when changing what's in a backup, and keep reading the older formats in
+/// `restore`.
+const FORMAT_VERSION: u32 = 1;
+
+const MANIFEST_FILE: &str = "manifest.json";
+const HISTORY_FILE: &str = "history.sqlite3";
+const CONFIG_FILE: &str = "water-mon.toml";
+
+/// Largest backup we accept for restoring.
+const MAX_UPLOAD_BYTES: u64 = 4 * 1_024 * 1_024 * 1_024;
+
+/// One backup or restore at a time.
+static BUSY: Mutex<()> = Mutex::new(());
+
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    format_version: u32,
+    /// The history schema version, if the backup includes history.
+    schema_version: Option<i32>,
+    app_version: String,
+    created: String,
+    /// The config's settings that were redacted.
+    #[serde(default)]
+    redacted: Vec<String>,
+}
+
+/// A path in the temp dir that's not been used, with a random name, so another user on
+/// the host can't have put a file or symlink there first.
+fn temp_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("water-mon-{:016x}-{}", rand::random::<u64>(), name))
+}
+
+/// A new file at `path`, failing if there's already one.
+fn create_temp(path: &Path) -> Result<File, io::Error> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(path)
+}
+
+fn backup_error(e: impl std::fmt::Display) -> ErrorResponse {
+    api::error(
+        Status::InternalServerError,
+        &format!("Problem making a backup: {}", e),
+    )
+}
+
+fn restore_error(e: impl std::fmt::Display) -> ErrorResponse {
+    api::error(Status::BadRequest, &format!("Problem restoring: {}", e))
+}
+
+pub struct BackupFile {
+    file: File,
+    name: String,
+}
+
+impl<'r> Responder<'r> for BackupFile {
+    fn respond_to(self, _req: &Request) -> response::Result<'r> {
+        Response::build()
+            .header(ContentType::new("application", "zip"))
+            .raw_header(
+                "Content-Disposition",
+                format!("attachment; filename=\"{}\"", self.name),
+            )
+            .streamed_body(self.file)
+            .ok()
+    }
+}
+
+/// A zip of the history database and config. The database is copied with
+/// `VACUUM INTO`, which reads a consistent snapshot while the poller keeps writing.
+#[get("/backup")]
+pub fn view_backup(_admin: Admin) -> Result<BackupFile, ErrorResponse> {
+    let _busy = BUSY.lock().unwrap_or_else(|e| e.into_inner());
+    let cfg = config::get();
+    let history_cfg = &cfg.history;
+
+    // `VACUUM INTO` creates it, and fails if it's there.
+    let snapshot = temp_path("backup.sqlite3");
+    let zip_path = temp_path("backup.zip");
+
+    let (cfg_text, redacted) = validate::effective(&cfg).map_err(backup_error)?;
+    let mut manifest = Manifest {
+        format_version: FORMAT_VERSION,
+        schema_version: None,
+        app_version: env!("CARGO_PKG_VERSION").into(),
+        created: Utc::now().to_rfc3339(),
+        redacted,
+    };
+
+    if history_cfg.enabled {
+        let conn = history::open_reader()?;
+        manifest.schema_version = Some(history::schema_version(&conn).map_err(backup_error)?);
+        conn.execute(
+            "VACUUM INTO ?1",
+            params![snapshot.to_string_lossy().into_owned()],
+        )
+        .map_err(backup_error)?;
+    }
+
+    let result = (|| -> Result<File, Box<dyn std::error::Error>> {
+        let mut zip = ZipWriter::new(create_temp(&zip_path)?);
+        let options = FileOptions::default()
+            .compression_method(CompressionMethod::Deflated)
+            .large_file(true);
+
+        zip.start_file(MANIFEST_FILE, options)?;
+        zip.write_all(serde_json::to_string_pretty(&manifest)?.as_bytes())?;
+
+        zip.start_file(CONFIG_FILE, options)?;
+        zip.write_all(cfg_text.as_bytes())?;
+
+        if manifest.schema_version.is_some() {
+            zip.start_file(HISTORY_FILE, options)?;
+            io::copy(&mut File::open(&snapshot)?, &mut zip)?;
+        }
+
+        let mut file = zip.finish()?;
+        file.seek(SeekFrom::Start(0))?;
+        Ok(file)
+    })();
+    let _ = fs::remove_file(&snapshot);
+    // On unix, the file lives on until it's closed, after the response. Elsewhere, an
+    // open file can't be removed, so it's left in the temp dir.
+    let _ = fs::remove_file(&zip_path);
+    let file = result.map_err(backup_error)?;
+
+    Ok(BackupFile {
+        file,
+        name: format!(
+            "water-mon-backup-{}.zip",
+            Utc::now().format("%Y%m%d-%H%M%S")
+        ),
+    })
+}
+
+#[derive(Serialize)]
+struct RestoreResult {
+    created: String,
+    restored_history: bool,
+    restored_config: bool,
+}
+
+/// Replace the history database and config with those from a backup. Writers are
+/// stopped while the database is replaced, then resume.
+#[post("/restore", data = "<data>")]
+pub fn restore(_admin: Admin, data: Data) -> Result<content::Json<String>, ErrorResponse> {
+    let _busy = BUSY.lock().unwrap_or_else(|e| e.into_inner());
+
+    let upload = temp_path("restore.zip");
+    let extracted = temp_path("restore.sqlite3");
+
+    let result = (|| {
+        let size = io::copy(
+            &mut data.open().take(MAX_UPLOAD_BYTES + 1),
+            &mut create_temp(&upload).map_err(restore_error)?,
+        )
+        .map_err(restore_error)?;
+        if size > MAX_UPLOAD_BYTES {
+            return Err(api::error(
+                Status::PayloadTooLarge,
+                &format!(
+                    "Backups over {} GiB aren't supported",
+                    MAX_UPLOAD_BYTES >> 30
+                ),
+            ));
+        }
+
+        restore_from(&upload, &extracted)
+    })();
+
+    let _ = fs::remove_file(&upload);
+    let _ = fs::remove_file(&extracted);
+
+    let result = result?;
+    events::record(
+        Severity::Info,
+        "backup",
+        format!("Restored a backup from {}.", result.created),
+    );
+    Ok(content::Json(serde_json::to_string(&result).unwrap()))
+}
+
+fn restore_from(upload: &Path, extracted: &Path) -> Result<RestoreResult, ErrorResponse> {
+    let mut zip = ZipArchive::new(File::open(upload).map_err(restore_error)?)
+        .map_err(|e| restore_error(format!("not a valid zip: {}", e)))?;
+
+    let manifest: Manifest = {
+        let mut text = String::new();
+        zip.by_name(MANIFEST_FILE)
+            .map_err(|_| restore_error("this isn't a backup from this app; it has no manifest"))?
+            .read_to_string(&mut text)
+            .map_err(restore_error)?;
+        serde_json::from_str(&text).map_err(|e| restore_error(format!("bad manifest: {}", e)))?
+    };
+
+    match manifest.format_version {
+        FORMAT_VERSION => (),
+        v => {
+            return Err(restore_error(format!(
+                "the backup's format version is {}, but this app reads version {}",
+                v, FORMAT_VERSION
+            )))
+        }
+    }
+
+    // Check everything before changing anything.
+    let config = match zip.by_name(CONFIG_FILE) {
+        Ok(mut f) => {
+            let mut text = String::new();
+            f.read_to_string(&mut text).map_err(restore_error)?;
+            Some(restored_config(&text, &config::get())?)
+        }
+        Err(_) => None,
+    };
+
+    let history_cfg = config::get().history;
+    let has_history = manifest.schema_version.is_some();
+    if has_history {
+        if !history_cfg.enabled {
+            return Err(restore_error(
+                "the backup includes history, but history is disabled",
+            ));
+        }
+
+        let mut f = zip.by_name(HISTORY_FILE).map_err(|_| {
+            restore_error("the manifest lists history, but the backup doesn't include it")
+        })?;
+        io::copy(&mut f, &mut create_temp(extracted).map_err(restore_error)?)
+            .map_err(restore_error)?;
+
+        check_database(extracted)?;
+    }
+
+    if has_history {
+        let mut conn = history::open(&history_cfg.path).map_err(restore_error)?;
+        let _lock = history::lock_writes();
+        conn.restore(
+            DatabaseName::Main,
+            extracted,
+            None::<fn(rusqlite::backup::Progress)>,
+        )
+        .map_err(restore_error)?;
+        // Migrate a backup from an older schema.
+        history::open(&history_cfg.path).map_err(restore_error)?;
+    }
+
+    let restored_config = config.is_some();
+    if let Some(config) = config {
+        write_config(config)?;
+    }
+
+    Ok(RestoreResult {
+        created: manifest.created,
+        restored_history: has_history,
+        restored_config,
+    })
+}
+
+/// Check a database from a backup is intact, and not from a newer schema version.
+fn check_database(path: &Path) -> Result<(), ErrorResponse> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(restore_error)?;
+
+    let integrity: String = conn
+        .query_row("PRAGMA integrity_check", [], |row| row.get(0))
+        .map_err(restore_error)?;
+    if integrity != "ok" {
+        return Err(restore_error(format!(
+            "the backup's history database is corrupt: {}",
+            integrity
+        )));
+    }
+
+    // Older versions are migrated when the restored database is reopened.
+    let version = history::schema_version(&conn).map_err(restore_error)?;
+    if version > SCHEMA_VERSION {
+        return Err(restore_error(format!(
+            "the backup's history has schema version {}, from a newer version of this app; \
+             this version uses {}",
+            version, SCHEMA_VERSION
+        )));
+    }
+
+    Ok(())
+}
+
+/// The config from a backup, with our current secrets, where it has them redacted, and
+/// current `[auth]` settings, checked as at startup.
+fn restored_config(text: &str, current: &AppConfig) -> Result<AppConfig, ErrorResponse> {
+    let mut config: AppConfig = toml::from_str(text)
+        .map_err(|e| restore_error(format!("the backup's config is invalid: {}", e)))?;
+    validate::unredact(&mut config, current);
+    config.auth = current.auth.clone();
+    validate::check(&config)
+        .map_err(|e| restore_error(format!("the backup's config is invalid: {}", e)))?;
+    Ok(config)
+}
+
+fn write_config(config: AppConfig) -> Result<(), ErrorResponse> {
+    // Through a `Value`, which puts tables after plain values, as TOML needs.
+    let text = toml::Value::try_from(&config)
+        .and_then(|v| toml::to_string(&v))
+        .map_err(restore_error)?;
+    fs::write(CONFIG_PATH, text).map_err(restore_error)?;
+    // Some settings, like ports, only apply after a restart.
+    config::set(config);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restored_config_is_checked() {
+        let current: AppConfig = toml::from_str("").unwrap();
+        assert!(restored_config("[validation_hook]\ntimeout_ms = 500\n", &current).is_ok());
+
+        let e = restored_config("[validation_hook]\ntimeout_ms = 0\n", &current).unwrap_err();
+        assert_eq!(e.0, Status::BadRequest);
+        assert!(e.1 .0.contains("timeout_ms"));
+    }
+
+    #[test]
+    fn restored_config_keeps_current_auth() {
+        let current: AppConfig =
+            toml::from_str("[auth]\nadmin_token = \"an-admin-token-that-is-long\"\n").unwrap();
+        let cfg = restored_config("[auth]\nadmin_token = \"(redacted)\"\n", &current).unwrap();
+        assert_eq!(cfg.auth.admin_token, current.auth.admin_token);
+    }
+
+    #[test]
+    fn temp_paths_are_unpredictable() {
+        let path = temp_path("backup.zip");
+        assert_ne!(path, temp_path("backup.zip"));
+        let _file = create_temp(&path).unwrap();
+        assert!(create_temp(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
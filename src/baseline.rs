@@ -0,0 +1,217 @@
+//! Learning sensors' normal bands from history, for alert rules with a `baseline`
+//! instead of fixed thresholds, where a good threshold varies by pool, like ORP's. Each
+//! band is a pair of percentiles over the last so many days, widened by a margin, and
+//! is relearned after local midnight; until history covers enough days, it's reported
+//! as learning, and doesn't fire. Learning is on its own thread, since it reads weeks
+//! of history.
+
+use std::{
+    collections::BTreeMap,
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+use chrono::{NaiveDate, Utc};
+use rusqlite::{params, Connection};
+use serde::Serialize;
+
+use crate::{
+    config::{self, AlertBaseline, AlertCondition},
+    distribution,
+    history::{self, AGGREGATE_TIERS},
+    registry, tz,
+};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often a band that's still learning is retried, rather than waiting a night.
+const RETRY_LEARNING: Duration = Duration::from_secs(60 * 60);
+
+const DAY_MS: i64 = 24 * 3_600_000;
+
+/// By `key`.
+static LEARNED: Mutex<BTreeMap<String, Learned>> = Mutex::new(BTreeMap::new());
+
+struct Learned {
+    /// The percentiles, or `None` with too little history.
+    percentiles: Option<[f32; 2]>,
+    history_days: f32,
+    /// RFC 3339, UTC.
+    computed: String,
+    /// Local.
+    date: NaiveDate,
+    at: Instant,
+}
+
+/// A baseline's band, for `/api/alerts`.
+#[derive(Clone, Serialize)]
+pub struct BaselineState {
+    /// If history doesn't cover `min_days` yet, or the band hasn't been learned since
+    /// the rule was added.
+    pub learning: bool,
+    /// The band that applies: the learned one with its margin, except where overridden.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub low: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub high: Option<f32>,
+    /// The learned percentiles, without the margin.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub learned: Option<[f32; 2]>,
+    /// Of history it was learned from.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub history_days: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub computed: Option<String>,
+}
+
+/// Rules with the same sensor and percentiles share a band.
+fn key(sensor: &str, b: &AlertBaseline) -> String {
+    format!("{}:{}:{}:{}", sensor, b.days, b.low_pct, b.high_pct)
+}
+
+/// The band for a sensor's baseline, as last learned.
+pub fn state(sensor: &str, b: &AlertBaseline) -> BaselineState {
+    let learned = LEARNED.lock().unwrap();
+    let entry = learned.get(&key(sensor, b));
+    let percentiles = entry.and_then(|l| l.percentiles);
+    let (low, high) = match percentiles {
+        Some([p_low, p_high]) => {
+            let margin = (p_high - p_low) * b.margin_pct / 100.;
+            (Some(p_low - margin), Some(p_high + margin))
+        }
+        None => (None, None),
+    };
+    BaselineState {
+        learning: percentiles.is_none(),
+        low: b.low.or(low),
+        high: b.high.or(high),
+        learned: percentiles,
+        history_days: entry.map(|l| l.history_days),
+        computed: entry.map(|l| l.computed.clone()),
+    }
+}
+
+/// Baselines in a condition, with their sensors.
+fn collect<'a>(c: &'a AlertCondition, out: &mut Vec<(&'static str, &'a AlertBaseline)>) {
+    if let (Some(b), Some(sensor)) = (&c.baseline, c.sensor.as_deref().and_then(registry::get)) {
+        out.push((sensor.id, b));
+    }
+    c.all
+        .iter()
+        .chain(c.any.iter())
+        .for_each(|c| collect(c, out));
+}
+
+/// The `low_pct` and `high_pct` percentiles of the last `days` of a sensor's history,
+/// weighting compacted rows by their samples, and how many days it covers.
+fn learn(
+    conn: &Connection,
+    sensor: &str,
+    b: &AlertBaseline,
+) -> Result<(Option<[f32; 2]>, f32), rusqlite::Error> {
+    let to_ms = Utc::now().timestamp_millis();
+    let from_ms = to_ms - b.days as i64 * DAY_MS;
+
+    let mut firsts = vec![format!(
+        "SELECT MIN(time) AS t FROM samples \
+         WHERE time >= ?1 AND time < ?2 AND {} IS NOT NULL AND NOT maintenance AND {}",
+        sensor,
+        history::settled(sensor)
+    )];
+    for (table, _) in AGGREGATE_TIERS.iter() {
+        firsts.push(format!(
+            "SELECT MIN(time) FROM {} WHERE time >= ?1 AND time < ?2 AND {}_n > 0",
+            table, sensor
+        ));
+    }
+    let values = distribution::weighted_values(sensor);
+    let (first, count): (Option<i64>, i64) = conn.query_row(
+        &format!(
+            "SELECT (SELECT MIN(t) FROM ({})), (SELECT COALESCE(SUM(w), 0) FROM ({}))",
+            firsts.join(" UNION ALL "),
+            values
+        ),
+        params![from_ms, to_ms],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    let history_days = first.map_or(0., |f| (to_ms - f) as f32 / DAY_MS as f32);
+    if count == 0 || history_days < b.min_days as f32 {
+        return Ok((None, history_days));
+    }
+
+    let targets = [b.low_pct as f64 / 100., b.high_pct as f64 / 100.];
+    let mut found = Vec::with_capacity(2);
+    let mut seen = 0;
+    let mut stmt = conn.prepare(&format!("SELECT v, w FROM ({}) ORDER BY v", values))?;
+    let mut rows = stmt.query(params![from_ms, to_ms])?;
+    while let Some(row) = rows.next()? {
+        let v: f64 = row.get(0)?;
+        let w: i64 = row.get(1)?;
+        seen += w;
+        while found.len() < targets.len() && seen as f64 >= targets[found.len()] * count as f64 {
+            found.push(v as f32);
+        }
+    }
+    Ok((
+        (found.len() == 2).then(|| [found[0], found[1]]),
+        history_days,
+    ))
+}
+
+/// Learn bands that are new, from before today, or still learning, for an hour or more;
+/// and forget those no rule uses.
+fn update() {
+    let cfg = config::get();
+    let mut baselines = Vec::new();
+    cfg.alerts
+        .iter()
+        .for_each(|r| collect(&r.when, &mut baselines));
+
+    let today = Utc::now()
+        .with_timezone(&tz::configured())
+        .naive_local()
+        .date();
+    let due: Vec<_> = {
+        let mut learned = LEARNED.lock().unwrap();
+        learned.retain(|k, _| baselines.iter().any(|(s, b)| &key(s, b) == k));
+        baselines
+            .into_iter()
+            .filter(|(sensor, b)| {
+                learned.get(&key(sensor, b)).is_none_or(|l| {
+                    l.date != today || (l.percentiles.is_none() && l.at.elapsed() >= RETRY_LEARNING)
+                })
+            })
+            .collect()
+    };
+    if due.is_empty() {
+        return;
+    }
+
+    // Without history, every band stays learning.
+    let conn = history::open_reader().ok();
+    for (sensor, b) in due {
+        let (percentiles, history_days) = match conn.as_ref().map(|c| learn(c, sensor, b)) {
+            Some(Ok(result)) => result,
+            _ => (None, 0.),
+        };
+        LEARNED.lock().unwrap().insert(
+            key(sensor, b),
+            Learned {
+                percentiles,
+                history_days,
+                computed: Utc::now().to_rfc3339(),
+                date: today,
+                at: Instant::now(),
+            },
+        );
+    }
+}
+
+/// Keep bands learned, forever; run this on its own thread.
+pub fn run() {
+    loop {
+        update();
+        thread::sleep(CHECK_INTERVAL);
+    }
+}
@@ -0,0 +1,137 @@
+//! A basic dashboard, rendered here as plain HTML, so the app is usable without the web
+//! page's files: each sensor's reading in the configured units, colored by status, how
+//! old the readings are, and if the Water Monitor is connected. It reloads itself with a
+//! meta refresh, without scripts. It's always at `/basic`, and stands in for the web
+//! page at `/` when its files are missing.
+
+use std::fmt::Write;
+
+use rocket::response::content;
+
+use crate::{
+    config, instance, locale, poller,
+    snapshot::age,
+    status::{self, SensorStatus},
+    tokens::Viewer,
+};
+
+/// Seconds between reloads.
+const REFRESH_SECS: u32 = 5;
+
+/// For text from the config, eg the instance name.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn color(status: SensorStatus) -> &'static str {
+    match status {
+        SensorStatus::Ok => "#1e8e3e",
+        SensorStatus::Warn => "#c78a00",
+        SensorStatus::Critical => "#c62828",
+        SensorStatus::Error
+        | SensorStatus::Stale
+        | SensorStatus::NoFlow
+        | SensorStatus::Stabilizing
+        | SensorStatus::Rejected => "#777",
+    }
+}
+
+/// The page, noting that the web page's files are missing if `missing_frontend`.
+pub fn page(missing_frontend: bool) -> String {
+    let cfg = config::get();
+    let readings = crate::latest_readings();
+    let statuses = status::classify(&readings, &cfg.status);
+    let locale = locale::configured();
+    let poller = poller::status();
+    let name = escape(&instance::name());
+
+    let mut rows = String::new();
+    for (sensor, reading) in readings.iter() {
+        let status = statuses.get(sensor.id).unwrap_or(SensorStatus::Error);
+        let status_name = serde_json::to_value(status)
+            .ok()
+            .and_then(|s| s.as_str().map(str::to_owned))
+            .unwrap_or_default();
+        let (value, detail) = match reading {
+            Ok(v) => (locale.reading(sensor, v, &cfg.precision), status_name),
+            Err(e) => ("-".into(), e.to_string()),
+        };
+        let _ = write!(
+            rows,
+            "<tr><td><span class=\"dot\" style=\"background:{}\"></span>{}</td>\
+             <td class=\"value\">{}</td><td class=\"detail\">{}</td></tr>",
+            color(status),
+            sensor.label,
+            escape(&value),
+            escape(&detail)
+        );
+    }
+    if rows.is_empty() {
+        rows.push_str("<tr><td colspan=\"3\">No sensors are reading.</td></tr>");
+    }
+
+    let updated = match poller.seconds_since_success {
+        None => "No readings yet".into(),
+        Some(s) if s > cfg.status.stale_secs as f32 => {
+            format!("Stale: last reading {} ago", age(s))
+        }
+        Some(s) => format!("Updated {} ago", age(s)),
+    };
+    let connection = if poller.connected {
+        "Water Monitor connected"
+    } else {
+        "Water Monitor not connected"
+    };
+    let notice = if missing_frontend {
+        "<p class=\"notice\">The app is running, but its web page's files weren't found, so \
+         this is a basic page. Put them in <code>static</code> next to the app, or set \
+         <code>server.static_dir</code>, and restart it. <a href=\"/api/selfcheck\">Startup \
+         checks</a> show where it looked.</p>"
+    } else {
+        ""
+    };
+
+    format!(
+        r##"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="UTF-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1">
+    <meta http-equiv="refresh" content="{refresh}">
+    <title>{name} - AnyLeaf Water Monitor</title>
+    <style>
+        body {{ font-family: sans-serif; max-width: 32em; margin: 1em auto; padding: 0 1em; }}
+        table {{ width: 100%; border-collapse: collapse; font-size: 1.3em; }}
+        td {{ padding: 0.3em 0; border-bottom: 1px solid #ddd; }}
+        .value {{ text-align: right; font-weight: bold; }}
+        .detail {{ text-align: right; color: #777; font-size: 0.7em; }}
+        .dot {{ display: inline-block; width: 0.6em; height: 0.6em; border-radius: 50%; margin-right: 0.5em; }}
+        .notice {{ background: #fff4d6; padding: 0.7em; }}
+        footer {{ color: #777; margin-top: 1em; }}
+    </style>
+</head>
+<body>
+    <h1>{name}</h1>
+    {notice}
+    <table>{rows}</table>
+    <footer>{updated}. {connection}.</footer>
+</body>
+</html>
+"##,
+        refresh = REFRESH_SECS,
+        name = name,
+        notice = notice,
+        rows = rows,
+        updated = updated,
+        connection = connection,
+    )
+}
+
+/// The basic dashboard, whether or not the web page's files are there.
+#[get("/basic")]
+pub fn view_basic(_viewer: Viewer) -> content::Html<String> {
+    content::Html(page(false))
+}
@@ -0,0 +1,145 @@
+//! What the benches in `benches/` time: the hot paths, with the state they need set up.
+//! The modules they're in are private, so this is the only way in; it's not an API.
+
+use std::{env, fs, process};
+
+use chrono::Utc;
+
+use crate::{
+    alerts, api::ApiVersion, cache, config, device_meta, history, tokens::Viewer, Readings,
+};
+
+/// Readings in range, for every sensor the Water Monitor sends.
+pub fn readings() -> Readings {
+    let mut result = Readings::empty();
+    for (id, value) in [
+        ("T", 24.5),
+        ("pH", 7.4),
+        ("ORP", 680.),
+        ("ec", 1_200.),
+        ("DO", 8.1),
+    ] {
+        result.set(id, Some(Ok(value)));
+    }
+    result
+}
+
+/// `readings`, as the Water Monitor frames them.
+pub fn frame() -> Vec<u8> {
+    readings().to_bytes()
+}
+
+pub fn decode(buf: &[u8]) -> Readings {
+    Readings::from_bytes(buf)
+}
+
+/// A flight controller's params packet, framed.
+#[cfg(feature = "flight-controller")]
+pub fn fc_frame() -> Vec<u8> {
+    use crate::fc::{MsgType, Packet};
+    let payload = vec![0x5a; MsgType::Params.payload_size()];
+    Packet::new(MsgType::Params, &payload).to_bytes()
+}
+
+#[cfg(feature = "flight-controller")]
+pub fn decode_fc(buf: &[u8]) -> bool {
+    crate::fc::Packet::from_bytes(buf).is_ok()
+}
+
+/// Publish `readings` as the latest, as the poller does.
+pub fn publish() {
+    cache::publish(readings(), Some(1));
+}
+
+/// The latest snapshot's sequence number, as readers take it.
+pub fn load() -> Option<u64> {
+    cache::latest().seq
+}
+
+/// The readings response, from the published snapshot.
+pub fn readings_json() -> String {
+    crate::readings(ApiVersion::V1).0
+}
+
+/// The readings response, serializing the measured part each time, as it was before
+/// the cache.
+pub fn readings_json_uncached() -> String {
+    publish();
+    readings_json()
+}
+
+/// A history database of samples 10 s apart, ending now, in the temporary directory.
+/// It's the one in use while it's around, and removed when it's dropped.
+pub struct History {
+    path: String,
+    from: String,
+}
+
+impl History {
+    pub fn new(samples: usize) -> Self {
+        let path = env::temp_dir()
+            .join(format!("water-mon-bench-{}.db", process::id()))
+            .to_string_lossy()
+            .into_owned();
+        let _ = fs::remove_file(&path);
+        let cfg = format!("[history]\nenabled = true\npath = '{}'", path);
+        config::set(toml::from_str(&cfg).unwrap());
+
+        let now = Utc::now().timestamp_millis();
+        let samples: Vec<_> = (0..samples as i64)
+            .map(|i| {
+                let mut r = readings();
+                r.set("T", Some(Ok(20. + (i % 100) as f32 / 10.)));
+                (now - i * 10_000, r)
+            })
+            .collect();
+        history::import_into(&path, &samples).unwrap();
+        let from = history::format_time(now - samples.len() as i64 * 10_000);
+        Self { path, from }
+    }
+
+    /// All of it, in hourly buckets.
+    pub fn buckets(&self) -> String {
+        history::view_history(
+            Viewer,
+            Some(self.from.clone()),
+            None,
+            Some("1h".to_owned()),
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+        .unwrap()
+        .0
+    }
+}
+
+impl Drop for History {
+    fn drop(&mut self) {
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = fs::remove_file(format!("{}{}", self.path, suffix));
+        }
+    }
+}
+
+/// Use `rules` alert rules on this device: thresholds, and trends over their window.
+pub fn alert_rules(rules: usize) {
+    let mut text = String::new();
+    for i in 0..rules {
+        let when = match i % 3 {
+            0 => format!("{{ sensor = \"ORP\", below = {} }}", 500 + i),
+            1 => format!("{{ sensor = \"pH\", above = {}.5 }}", 7 + i % 2),
+            _ => "{ any = [{ sensor = \"T\", rising = 2 }, { sensor = \"ec\", falling = 100 }] }"
+                .to_owned(),
+        };
+        text += &format!("[[alerts]]\nname = \"rule {}\"\nwhen = {}\n", i, when);
+    }
+    config::set(toml::from_str(&text).unwrap());
+}
+
+/// Evaluate the rules against `readings`, as each poll cycle does.
+pub fn evaluate(readings: &Readings) {
+    alerts::evaluate(device_meta::LOCAL, readings);
+}
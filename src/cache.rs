@@ -0,0 +1,150 @@
+//! The latest readings, as a snapshot the poller builds whole and then publishes, so
+//! readers never see a partly updated set, like new readings with the last cycle's
+//! sequence number. Published snapshots don't change; readers take an `Arc` of the
+//! current one, and the lock is only held to copy or replace that pointer. So nothing
+//! is cloned or serialized under it, and a slow reader, like an exporter, can't hold up
+//! the poller, or the poller readers. The parts of the readings response that only
+//! depend on the readings are serialized once, when they're published.
+
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+
+use crate::{config, precision, registry::KeyNaming, Readings};
+
+static LATEST: Swap<Snapshot> = Swap::new();
+
+/// A value that's replaced whole. Readers keep what they loaded as long as they like,
+/// without holding up a store.
+pub struct Swap<T>(Mutex<Option<Arc<T>>>);
+
+impl<T> Default for Swap<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Swap<T> {
+    pub const fn new() -> Self {
+        Self(Mutex::new(None))
+    }
+
+    pub fn load(&self) -> Option<Arc<T>> {
+        self.0.lock().unwrap().clone()
+    }
+
+    pub fn store(&self, value: T) {
+        let previous = self.0.lock().unwrap().replace(Arc::new(value));
+        // Dropped after the lock's released: it may be the last reference.
+        drop(previous);
+    }
+}
+
+pub struct Snapshot {
+    pub readings: Readings,
+    /// `None` after a failed poll cycle.
+    pub seq: Option<u64>,
+    /// When it was published.
+    pub time: DateTime<Utc>,
+    /// The measured part of the readings response, for each key naming, rounded to
+    /// each sensor's precision.
+    json: [String; 2],
+}
+
+impl Snapshot {
+    fn new(readings: Readings, seq: Option<u64>) -> Self {
+        let shown = precision::rounded(&readings, &config::get().precision);
+        let json = [KeyNaming::Legacy, KeyNaming::SnakeCase]
+            .map(|naming| crate::measured_json(&shown, seq, naming));
+        Self {
+            readings,
+            seq,
+            time: Utc::now(),
+            json,
+        }
+    }
+
+    /// The readings, their errors and sequence number, as a JSON object.
+    pub fn json(&self, naming: KeyNaming) -> &str {
+        match naming {
+            KeyNaming::Legacy => &self.json[0],
+            KeyNaming::SnakeCase => &self.json[1],
+        }
+    }
+}
+
+/// The latest snapshot; empty readings until the first poll cycle.
+pub fn latest() -> Arc<Snapshot> {
+    LATEST
+        .load()
+        .unwrap_or_else(|| Arc::new(Snapshot::new(Readings::default(), None)))
+}
+
+/// Build a snapshot of these readings, and make it the latest. Called by the poller.
+pub fn publish(readings: Readings, seq: Option<u64>) {
+    LATEST.store(Snapshot::new(readings, seq));
+}
+
+/// Two JSON objects' members, as one object.
+pub fn join(a: &str, b: &str) -> String {
+    let a = a.strip_suffix('}').unwrap_or(a);
+    let b = b.strip_prefix('{').unwrap_or(b);
+    let comma = if a.ends_with('{') || b.starts_with('}') {
+        ""
+    } else {
+        ","
+    };
+    format!("{}{}{}", a, comma, b)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    fn numbered(n: u64) -> Snapshot {
+        Snapshot::new(Readings::default(), Some(n))
+    }
+
+    #[test]
+    fn a_loaded_snapshot_outlives_the_next() {
+        let cell = Swap::new();
+        assert!(cell.load().is_none());
+        cell.store(numbered(0));
+        let held = cell.load().unwrap();
+        cell.store(numbered(1));
+        assert_eq!(held.seq, Some(0));
+        assert_eq!(cell.load().unwrap().seq, Some(1));
+    }
+
+    /// Readers loading while snapshots are published see them in order, and the last.
+    #[test]
+    fn readers_see_publications_in_order() {
+        const PUBLICATIONS: u64 = 200;
+        let cell = Swap::new();
+        cell.store(numbered(0));
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    let mut last = 0;
+                    while last < PUBLICATIONS {
+                        let seq = cell.load().unwrap().seq.unwrap();
+                        assert!(seq >= last, "Loaded {} after {}", seq, last);
+                        last = seq;
+                    }
+                });
+            }
+            for n in 1..=PUBLICATIONS {
+                cell.store(numbered(n));
+            }
+        });
+    }
+
+    #[test]
+    fn objects_join() {
+        assert_eq!(join(r#"{"a":1}"#, r#"{"b":2}"#), r#"{"a":1,"b":2}"#);
+        assert_eq!(join("{}", r#"{"b":2}"#), r#"{"b":2}"#);
+        assert_eq!(join(r#"{"a":1}"#, "{}"), r#"{"a":1}"#);
+    }
+}
@@ -0,0 +1,248 @@
+//! Readings that have changed, for clients on a metered link: `/api/changes?since_seq=N`
+//! has only the sensors whose reading changed by at least its precision, or whose status
+//! or error changed, after reading `N`, and the latest sequence number, to pass as `N`
+//! next time. Small changes add up: a sensor's reading is compared with the one last
+//! reported as a change, not the one before. Changes are tracked since the app started,
+//! so an `N` from before then, or past the latest, gets every sensor, with `complete`.
+//! The response's weak ETag only changes with a change, so a client that sends it back
+//! with the same `N` gets a bodiless 304 until there's news.
+
+use std::{io::Cursor, sync::Mutex};
+
+use rocket::{
+    http::{ContentType, Status},
+    request::{self, FromRequest},
+    response::{self, Responder, Response},
+    Outcome, Request,
+};
+use serde::Serialize;
+
+use crate::{
+    api::{self, ErrorResponse},
+    config::{self, AppConfig, PrecisionConfig},
+    precision,
+    registry::{self, SensorMap},
+    status::{self, SensorStatus},
+    tokens::Viewer,
+    Readings, SensorError,
+};
+
+static STATE: Mutex<State> = Mutex::new(State {
+    first_seq: None,
+    latest_seq: None,
+    last_change_seq: 0,
+    reported: SensorMap::empty(),
+});
+
+struct State {
+    /// The first reading since the app started.
+    first_seq: Option<u64>,
+    latest_seq: Option<u64>,
+    /// For the ETag.
+    last_change_seq: u64,
+    /// Each sensor's reading as of its last change.
+    reported: SensorMap<Reported>,
+}
+
+#[derive(Clone, Copy)]
+struct Reported {
+    reading: Result<f32, SensorError>,
+    status: SensorStatus,
+    seq: u64,
+}
+
+#[derive(Clone, Copy, Serialize)]
+struct Change {
+    /// As shown, at `[precision]`; `None` with an error.
+    value: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<SensorError>,
+    status: SensorStatus,
+    /// The reading it changed at.
+    seq: u64,
+}
+
+#[derive(Serialize)]
+struct ChangesResponse {
+    /// The latest reading's sequence number: `since_seq` for the next request.
+    seq: u64,
+    since_seq: u64,
+    /// If every sensor is listed, since `since_seq` was from before the app started, or
+    /// past the latest reading.
+    complete: bool,
+    sensors: SensorMap<Change>,
+}
+
+/// The `If-None-Match` header, if there is one.
+pub struct IfNoneMatch(Option<String>);
+
+impl IfNoneMatch {
+    fn matches(&self, etag: &str) -> bool {
+        self.0
+            .as_deref()
+            .is_some_and(|h| h.split(',').any(|t| t.trim() == etag || t.trim() == "*"))
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for IfNoneMatch {
+    type Error = ();
+
+    fn from_request(req: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        Outcome::Success(Self(
+            req.headers().get_one("If-None-Match").map(str::to_owned),
+        ))
+    }
+}
+
+pub enum Changes {
+    Changed { json: String, etag: String },
+    NotModified { etag: String },
+}
+
+impl<'r> Responder<'r> for Changes {
+    fn respond_to(self, _req: &Request) -> response::Result<'r> {
+        match self {
+            Self::Changed { json, etag } => Response::build()
+                .header(ContentType::JSON)
+                .raw_header("ETag", etag)
+                .sized_body(Cursor::new(json))
+                .ok(),
+            Self::NotModified { etag } => Response::build()
+                .status(Status::NotModified)
+                .raw_header("ETag", etag)
+                .ok(),
+        }
+    }
+}
+
+/// If `reading` differs enough from `reported` to report. A sensor that isn't rounded
+/// changes with any difference.
+fn changed(
+    sensor: &str,
+    reading: Result<f32, SensorError>,
+    reported: Result<f32, SensorError>,
+    cfg: &PrecisionConfig,
+) -> bool {
+    let step = registry::get(sensor)
+        .and_then(|s| precision::decimals(s, cfg))
+        .map_or(0., |d| 10_f32.powi(-(d as i32)));
+    match (reading, reported) {
+        // Allowing for f32 rounding, so 7.1 to 7.2 counts at 1 decimal.
+        (Ok(a), Ok(b)) if step > 0. => (a - b).abs() >= step * 0.999,
+        (a, b) => a != b,
+    }
+}
+
+/// Note a successful cycle's readings, and which have changed. Called by the poller, as
+/// it publishes them.
+pub fn record(seq: u64, readings: &Readings, cfg: &AppConfig) {
+    let statuses = status::classify(readings, &cfg.status);
+    let mut state = STATE.lock().unwrap();
+    state.first_seq.get_or_insert(seq);
+    state.latest_seq = Some(seq);
+    for (sensor, reading) in readings.iter() {
+        let status = statuses.get(sensor.id).unwrap_or(SensorStatus::Error);
+        let news = match state.reported.get(sensor.id) {
+            Some(r) => r.status != status || changed(sensor.id, reading, r.reading, &cfg.precision),
+            None => true,
+        };
+        if news {
+            state.reported.set(
+                sensor.id,
+                Some(Reported {
+                    reading,
+                    status,
+                    seq,
+                }),
+            );
+            state.last_change_seq = seq;
+        }
+    }
+}
+
+/// Sensors whose reading, status or error changed after reading `since_seq`, and the
+/// latest sequence number. With the ETag from the last response, and the same
+/// `since_seq`, a 304 if nothing's changed since.
+#[get("/changes?<since_seq>")]
+pub fn view_changes(
+    _viewer: Viewer,
+    since_seq: Option<u64>,
+    if_none_match: IfNoneMatch,
+) -> Result<Changes, ErrorResponse> {
+    let since_seq = since_seq.ok_or_else(|| {
+        api::error(
+            Status::BadRequest,
+            "`since_seq` is required: 0 for every sensor, or the last response's `seq`",
+        )
+    })?;
+    let state = STATE.lock().unwrap();
+    let (first_seq, latest_seq) = match (state.first_seq, state.latest_seq) {
+        (Some(first), Some(latest)) => (first, latest),
+        _ => {
+            return Err(api::error(
+                Status::ServiceUnavailable,
+                "There are no readings yet",
+            ))
+        }
+    };
+    let complete = since_seq < first_seq || since_seq > latest_seq;
+    let etag = format!("W/\"{}\"", state.last_change_seq);
+    if !complete && if_none_match.matches(&etag) {
+        return Ok(Changes::NotModified { etag });
+    }
+
+    let cfg = config::get().precision;
+    let mut sensors = SensorMap::empty();
+    for (sensor, r) in state.reported.iter() {
+        if complete || r.seq > since_seq {
+            sensors.set(
+                sensor.id,
+                Some(Change {
+                    value: r.reading.ok().map(|v| precision::value(sensor.id, v, &cfg)),
+                    error: r.reading.err(),
+                    status: r.status,
+                    seq: r.seq,
+                }),
+            );
+        }
+    }
+    let response = ChangesResponse {
+        seq: latest_seq,
+        since_seq,
+        complete,
+        sensors,
+    };
+    Ok(Changes::Changed {
+        json: serde_json::to_string(&response).unwrap(),
+        etag,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changes_are_at_the_configured_precision() {
+        let mut cfg = PrecisionConfig::default();
+        cfg.decimals.insert("pH".into(), 1);
+        assert!(!changed("pH", Ok(7.14), Ok(7.1), &cfg));
+        assert!(changed("pH", Ok(7.2), Ok(7.1), &cfg));
+
+        cfg.round = false;
+        assert!(changed("pH", Ok(7.14), Ok(7.1), &cfg));
+        assert!(changed(
+            "pH",
+            Err(SensorError::BadMeasurement),
+            Ok(7.1),
+            &cfg
+        ));
+    }
+
+    #[test]
+    fn since_seq_is_required() {
+        let e = view_changes(Viewer, None, IfNoneMatch(None)).err().unwrap();
+        assert_eq!(e.0, Status::BadRequest);
+        assert!(e.1 .0.contains("since_seq"));
+    }
+}
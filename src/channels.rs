@@ -0,0 +1,637 @@
+//! Logical channels, merged from several devices' readings of the same water, eg two
+//! monitors in one large pond giving one temperature. Other devices are Water Monitors
+//! running this app, whose readings we fetch over HTTP, or senders that push theirs to
+//! `/api/ingest/<name>`, like an ESP32 by a well. A source drops out of its
+//! channel while its device is unreachable, or its reading is an error or stale, so the
+//! merged value follows the devices still reporting. Each device's own readings are
+//! still listed with the channel.
+//!
+//! Devices with a URL are fetched one at a time, each every `interval_secs`. When several are due,
+//! the highest `priority` goes first, then the one waiting longest. A device that times
+//! out `DEMOTE_AFTER` times in a row is only retried every `DEMOTED_INTERVAL` until it
+//! responds, so it can't hold up the others. Pushed readings go stale `interval_secs`
+//! after they were taken, as fetched ones do after they were fetched, plus
+//! `status.stale_secs`. `/api/devices` has each one's schedule, and when it was last
+//! heard from.
+
+use std::{
+    cmp::Reverse,
+    collections::VecDeque,
+    io::{self, Read, Write},
+    iter,
+    net::{TcpStream, ToSocketAddrs},
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use rocket::response::content;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{
+    config::{self, AppConfig, DeviceConfig, MergeMethod},
+    device_meta::{self, DeviceMeta},
+    events::{self, Severity},
+    history, instance,
+    memory::{Buffer, Policy},
+    net, poller, precision, registry,
+    status::{self, SensorStatus},
+    tokens::Viewer,
+};
+
+/// What sources call this device.
+pub const LOCAL: &str = "local";
+
+/// How often to check for devices due to be fetched, while none are.
+const TICK: Duration = Duration::from_millis(250);
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Timeouts in a row after which a device is demoted to `DEMOTED_INTERVAL`.
+const DEMOTE_AFTER: u32 = 3;
+
+const DEMOTED_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Fetches kept per device, for its effective interval.
+const RATE_WINDOW: usize = 10;
+
+pub const BUFFER: Buffer = Buffer {
+    name: "channels.fetches",
+    cap: RATE_WINDOW,
+    policy: Policy::DropOldest,
+    about: "When each device was last fetched, for its effective interval",
+};
+
+/// Readings responses are well under this.
+const MAX_RESPONSE_SIZE: u64 = 64 * 1_024;
+
+struct Remote {
+    name: String,
+    /// Its instance ID, from its last successful fetch.
+    instance: Option<String>,
+    /// Its latest usable readings, by sensor id.
+    values: Vec<(String, f32)>,
+    /// When we last fetched them, or when those pushed were taken.
+    updated: Option<Instant>,
+    /// The same, in ms since the epoch.
+    seen_ms: Option<i64>,
+    /// Failed fetches in a row, so we only report the first of a run of failures.
+    failures: u32,
+    /// Timed out fetches in a row.
+    timeouts: u32,
+    /// If it's fetched every `DEMOTED_INTERVAL`, after timing out too often.
+    demoted: bool,
+    next_due: Instant,
+    /// When its latest fetches started, oldest first.
+    started: VecDeque<Instant>,
+}
+
+impl Remote {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            instance: None,
+            values: Vec::new(),
+            updated: None,
+            seen_ms: None,
+            failures: 0,
+            timeouts: 0,
+            demoted: false,
+            next_due: Instant::now(),
+            started: VecDeque::new(),
+        }
+    }
+
+    /// The mean time between its latest fetches.
+    fn effective_interval(&self) -> Option<Duration> {
+        let (first, last) = (self.started.front()?, self.started.back()?);
+        let n = self.started.len() as u32 - 1;
+        (n > 0).then(|| (*last - *first) / n)
+    }
+}
+
+static REMOTES: Mutex<Vec<Remote>> = Mutex::new(Vec::new());
+
+#[derive(Serialize)]
+struct Source {
+    device: String,
+    sensor: String,
+    weight: f32,
+    /// Its latest usable reading.
+    value: Option<f32>,
+    /// If it counts towards the merged value.
+    included: bool,
+}
+
+/// Where a device's readings come from.
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum DeviceSource {
+    Fetch,
+    Push,
+}
+
+#[derive(Serialize)]
+struct DeviceSchedule {
+    name: String,
+    source: DeviceSource,
+    priority: i32,
+    interval_secs: u32,
+    /// The mean time between its latest fetches.
+    effective_interval_secs: Option<f32>,
+    demoted: bool,
+    /// Failed fetches in a row.
+    failures: u32,
+    /// When it was last fetched, or when its last pushed readings were taken.
+    last_seen: Option<String>,
+    /// If its readings are stale, or it hasn't been heard from.
+    stale: bool,
+    #[serde(flatten)]
+    meta: DeviceMeta,
+}
+
+/// Why pushed readings weren't taken.
+pub enum PushError {
+    UnknownDevice,
+    /// Older than the latest pushed, whose time this is.
+    OutOfOrder(i64),
+}
+
+#[derive(Serialize)]
+pub struct Channel {
+    pub name: String,
+    pub sensor: String,
+    pub unit: &'static str,
+    pub merge: MergeMethod,
+    /// `None` if no source has a usable reading.
+    pub value: Option<f32>,
+    pub status: SensorStatus,
+    sources: Vec<Source>,
+}
+
+/// Check that channels only refer to known devices and sensors.
+pub fn check(cfg: &AppConfig) -> Result<(), io::Error> {
+    let invalid = |msg: String| {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Config error: {}", msg),
+        ))
+    };
+
+    for device in cfg.devices.iter() {
+        if device.name == LOCAL {
+            return invalid(format!("`{}` is reserved for this device", LOCAL));
+        }
+        if device.interval_secs == 0 {
+            return invalid(format!(
+                "Device `{}` needs an `interval_secs` of at least 1",
+                device.name
+            ));
+        }
+        match (&device.url, &device.token) {
+            (Some(url), None) => {
+                if let Err(e) = parse_url(url) {
+                    return invalid(format!("Device `{}`: {}", device.name, e));
+                }
+            }
+            (None, Some(token)) if !token.is_empty() => (),
+            (None, _) => {
+                return invalid(format!(
+                    "Device `{}` needs a `url` to fetch its readings from, or a `token` for \
+                     it to push them with",
+                    device.name
+                ))
+            }
+            (Some(_), Some(_)) => {
+                return invalid(format!(
+                    "Device `{}` has a `url` and a `token`; it's either fetched from, or \
+                     pushes its readings",
+                    device.name
+                ))
+            }
+        }
+    }
+    for channel in cfg.channels.iter() {
+        if registry::get(&channel.sensor).is_none() {
+            return invalid(format!(
+                "Channel `{}` has an unknown sensor, `{}`",
+                channel.name, channel.sensor
+            ));
+        }
+        for source in channel.sources.iter() {
+            if source.device != LOCAL && !cfg.devices.iter().any(|d| d.name == source.device) {
+                return invalid(format!(
+                    "Channel `{}` has an unknown device, `{}`",
+                    channel.name, source.device
+                ));
+            }
+            if let Some(s) = &source.sensor {
+                if registry::get(s).is_none() {
+                    return invalid(format!(
+                        "Channel `{}` has an unknown sensor, `{}`",
+                        channel.name, s
+                    ));
+                }
+            }
+            if source.weight.is_nan() || source.weight < 0. {
+                return invalid(format!(
+                    "Channel `{}` has a negative weight for `{}`",
+                    channel.name, source.device
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The next device to fetch, if any are due: the highest priority, then the one waiting
+/// longest.
+fn next_due(devices: &[DeviceConfig]) -> Option<DeviceConfig> {
+    let now = Instant::now();
+    let mut remotes = REMOTES.lock().unwrap();
+    for device in devices.iter() {
+        if !remotes.iter().any(|r| r.name == device.name) {
+            remotes.push(Remote::new(&device.name));
+        }
+    }
+    devices
+        .iter()
+        .filter(|d| d.url.is_some())
+        .filter_map(|d| {
+            let remote = remotes.iter().find(|r| r.name == d.name)?;
+            (remote.next_due <= now).then_some((d, remote.next_due))
+        })
+        .max_by_key(|(d, due)| (d.priority, Reverse(*due)))
+        .map(|(d, _)| d.clone())
+}
+
+/// Fetch a device's readings, and schedule its next fetch.
+fn fetch_device(device: &DeviceConfig) {
+    let start = Instant::now();
+    let Some(url) = &device.url else {
+        return;
+    };
+    let result = fetch(url);
+
+    let mut remotes = REMOTES.lock().unwrap();
+    // Devices are told apart by instance ID, not URL, which can change, or name.
+    let result = result.and_then(|fetched| {
+        let id = match &fetched.instance {
+            Some(id) => id,
+            None => return Ok(fetched),
+        };
+        if *id == instance::id() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "It's this app itself; check its URL",
+            ));
+        }
+        match remotes
+            .iter()
+            .find(|r| r.name != device.name && r.instance.as_ref() == Some(id))
+        {
+            Some(other) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("It's the same app as `{}`", other.name),
+            )),
+            None => Ok(fetched),
+        }
+    });
+    let Some(remote) = remotes.iter_mut().find(|r| r.name == device.name) else {
+        return;
+    };
+    if remote.started.len() == RATE_WINDOW {
+        remote.started.pop_front();
+    }
+    remote.started.push_back(start);
+    BUFFER.note(remote.started.len());
+
+    match result {
+        Ok(fetched) => {
+            if remote.failures > 0 {
+                events::record(
+                    Severity::Info,
+                    "channels",
+                    format!("Reading `{}` again", device.name),
+                );
+            }
+            remote.instance = fetched.instance;
+            remote.values = fetched.values;
+            remote.updated = Some(Instant::now());
+            remote.seen_ms = Some(Utc::now().timestamp_millis());
+            remote.failures = 0;
+            remote.timeouts = 0;
+            remote.demoted = false;
+        }
+        Err(e) => {
+            if remote.failures == 0 {
+                events::record(
+                    Severity::Warning,
+                    "channels",
+                    format!(
+                        "Problem reading `{}`, so it's left out of channels: {}",
+                        device.name, e
+                    ),
+                );
+            }
+            remote.instance = None;
+            remote.failures += 1;
+            // Read timeouts are `WouldBlock` on Unix.
+            if matches!(
+                e.kind(),
+                io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+            ) {
+                remote.timeouts += 1;
+            } else {
+                remote.timeouts = 0;
+            }
+            if remote.timeouts >= DEMOTE_AFTER && !remote.demoted {
+                remote.demoted = true;
+                events::record(
+                    Severity::Warning,
+                    "channels",
+                    format!(
+                        "`{}` timed out {} times in a row, so it's only retried every {}s \
+                         until it responds",
+                        device.name,
+                        remote.timeouts,
+                        DEMOTED_INTERVAL.as_secs()
+                    ),
+                );
+            }
+        }
+    }
+
+    remote.next_due = start
+        + if remote.demoted {
+            DEMOTED_INTERVAL
+        } else {
+            Duration::from_secs(device.interval_secs as u64)
+        };
+}
+
+/// Fetch other devices' readings, forever; run this on its own thread.
+pub fn run() {
+    loop {
+        match next_due(&config::get().devices) {
+            Some(device) => fetch_device(&device),
+            None => thread::sleep(TICK),
+        }
+    }
+}
+
+/// The address, and path prefix, from a device's URL.
+fn parse_url(url: &str) -> Result<(String, String), String> {
+    let (address, path) = net::split_url(url)?;
+    Ok((address, path.trim_end_matches('/').to_owned()))
+}
+
+struct Fetched {
+    /// From `X-Instance-Id`; older versions don't send it.
+    instance: Option<String>,
+    /// Usable readings: those without errors, and not stale.
+    values: Vec<(String, f32)>,
+}
+
+/// A device's usable readings, and who it is.
+fn fetch(url: &str) -> Result<Fetched, io::Error> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_owned());
+
+    let (address, path) =
+        parse_url(url).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let socket_addr = address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No address for the host"))?;
+
+    let mut stream = TcpStream::connect_timeout(&socket_addr, FETCH_TIMEOUT)?;
+    stream.set_read_timeout(Some(FETCH_TIMEOUT))?;
+    stream.set_write_timeout(Some(FETCH_TIMEOUT))?;
+    write!(
+        stream,
+        "GET {}/api/v1/readings HTTP/1.0\r\nHost: {}\r\nAccept: application/json\r\n\r\n",
+        path, address
+    )?;
+
+    let mut response = Vec::new();
+    stream.take(MAX_RESPONSE_SIZE).read_to_end(&mut response)?;
+    let response = String::from_utf8_lossy(&response);
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| invalid("Incomplete response"))?;
+    let status_line = head.lines().next().unwrap_or_default();
+    if status_line.split(' ').nth(1) != Some("200") {
+        return Err(invalid(&format!("Got `{}`", status_line)));
+    }
+
+    let instance = head.lines().skip(1).find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        let value = value.trim().to_lowercase();
+        (name.trim().eq_ignore_ascii_case(instance::HEADER) && instance::valid_id(&value))
+            .then_some(value)
+    });
+
+    let readings: Value =
+        serde_json::from_str(body).map_err(|e| invalid(&format!("Invalid readings: {}", e)))?;
+    let statuses = &readings["status"];
+    let values = registry::ids()
+        .filter_map(|id| {
+            let usable = matches!(statuses[id].as_str(), Some("ok" | "warn" | "critical"));
+            let value = readings[id]["Ok"].as_f64()?;
+            usable.then(|| (id.to_owned(), value as f32))
+        })
+        .collect();
+    Ok(Fetched { instance, values })
+}
+
+/// If a device's readings are too old to use.
+fn stale(cfg: &AppConfig, device: &DeviceConfig, remote: &Remote) -> bool {
+    let stale_after =
+        Duration::from_secs(cfg.status.stale_secs as u64 + device.interval_secs as u64);
+    remote.updated.is_none_or(|u| u.elapsed() > stale_after)
+}
+
+/// Take readings a device pushed, taken at `time_ms`: usable values, by sensor id.
+/// Returns `false` if they're a duplicate of the latest, and so were ignored.
+pub fn push(name: &str, time_ms: i64, values: Vec<(String, f32)>) -> Result<bool, PushError> {
+    let cfg = config::get();
+    let device = cfg
+        .devices
+        .iter()
+        .find(|d| d.name == name && d.url.is_none())
+        .ok_or(PushError::UnknownDevice)?;
+
+    let mut remotes = REMOTES.lock().unwrap();
+    if !remotes.iter().any(|r| r.name == device.name) {
+        remotes.push(Remote::new(&device.name));
+    }
+    let remote = remotes.iter_mut().find(|r| r.name == device.name).unwrap();
+    match remote.seen_ms {
+        Some(latest) if time_ms == latest => return Ok(false),
+        Some(latest) if time_ms < latest => return Err(PushError::OutOfOrder(latest)),
+        _ => (),
+    }
+
+    let now = Instant::now();
+    let age = Utc::now().timestamp_millis().saturating_sub(time_ms).max(0);
+    if remote.started.len() == RATE_WINDOW {
+        remote.started.pop_front();
+    }
+    remote.started.push_back(now);
+    BUFFER.note(remote.started.len());
+    remote.values = values;
+    remote.updated = Some(
+        now.checked_sub(Duration::from_millis(age as u64))
+            .unwrap_or(now),
+    );
+    remote.seen_ms = Some(time_ms);
+    Ok(true)
+}
+
+/// A source's latest usable reading.
+fn value(device: &str, sensor: &str) -> Option<f32> {
+    if device == LOCAL {
+        let readings = crate::latest_readings();
+        let statuses = status::classify(&readings, &config::get().status);
+        return match statuses.get(sensor) {
+            Some(SensorStatus::Ok | SensorStatus::Warn | SensorStatus::Critical) => {
+                readings.reading(sensor).ok()
+            }
+            _ => None,
+        };
+    }
+
+    let cfg = config::get();
+    let device = cfg.devices.iter().find(|d| d.name == device)?;
+    let remotes = REMOTES.lock().unwrap();
+    let remote = remotes.iter().find(|r| r.name == device.name)?;
+    if stale(&cfg, device, remote) {
+        return None;
+    }
+    remote
+        .values
+        .iter()
+        .find(|(id, _)| id == sensor)
+        .map(|(_, v)| *v)
+}
+
+/// This device's latest usable readings, and each other device's, by device name.
+pub fn device_values() -> Vec<(String, Vec<(&'static str, f32)>)> {
+    let devices = config::get().devices.into_iter().map(|d| d.name);
+    iter::once(LOCAL.to_owned())
+        .chain(devices)
+        .map(|device| {
+            let values = registry::ids()
+                .filter_map(|id| value(&device, id).map(|v| (id, v)))
+                .collect();
+            (device, values)
+        })
+        .collect()
+}
+
+/// Each channel's merged value, from its sources' latest readings.
+pub fn channels() -> Vec<Channel> {
+    let cfg = config::get();
+
+    cfg.channels
+        .iter()
+        .map(|channel| {
+            let mut sources: Vec<Source> = channel
+                .sources
+                .iter()
+                .map(|s| {
+                    let sensor = s.sensor.clone().unwrap_or_else(|| channel.sensor.clone());
+                    let value = value(&s.device, &sensor);
+                    Source {
+                        device: s.device.clone(),
+                        sensor,
+                        weight: s.weight,
+                        value,
+                        included: value.is_some(),
+                    }
+                })
+                .collect();
+
+            let value = match channel.merge {
+                MergeMethod::Weighted => {
+                    for s in sources.iter_mut() {
+                        s.included &= s.weight > 0.;
+                    }
+                    let included = sources.iter().filter(|s| s.included);
+                    let (sum, weights) = included.fold((0., 0.), |(sum, weights), s| {
+                        (sum + s.value.unwrap() * s.weight, weights + s.weight)
+                    });
+                    if weights > 0. {
+                        Some(sum / weights)
+                    } else {
+                        None
+                    }
+                }
+                MergeMethod::Median => {
+                    poller::median(sources.iter().filter_map(|s| s.value).map(Ok)).ok()
+                }
+            };
+
+            // Merged, and classified, as measured.
+            let status = status::classify_value(value, &channel.sensor, &cfg.status);
+            let shown = |sensor: &str, v: Option<f32>| {
+                v.map(|v| precision::value(sensor, v, &cfg.precision))
+            };
+            for s in sources.iter_mut() {
+                s.value = shown(&s.sensor, s.value);
+            }
+            Channel {
+                name: channel.name.clone(),
+                sensor: channel.sensor.clone(),
+                unit: registry::get(&channel.sensor).map_or("", |s| s.unit),
+                merge: channel.merge,
+                value: shown(&channel.sensor, value),
+                status,
+                sources,
+            }
+        })
+        .collect()
+}
+
+/// Each channel's merged value, status, and sources.
+#[get("/channels")]
+pub fn view_channels(_viewer: Viewer) -> content::Json<String> {
+    content::Json(serde_json::to_string(&channels()).unwrap())
+}
+
+/// Each device's schedule: how often it's meant to be fetched, and is, and its
+/// metadata, by `sort_order`.
+#[get("/devices")]
+pub fn view_devices(_viewer: Viewer) -> content::Json<String> {
+    let cfg = config::get();
+    let remotes = REMOTES.lock().unwrap();
+    let mut devices: Vec<_> = cfg
+        .devices
+        .iter()
+        .map(|d| {
+            let remote = remotes.iter().find(|r| r.name == d.name);
+            DeviceSchedule {
+                source: if d.url.is_some() {
+                    DeviceSource::Fetch
+                } else {
+                    DeviceSource::Push
+                },
+                last_seen: remote.and_then(|r| r.seen_ms).map(history::format_time),
+                stale: remote.is_none_or(|r| stale(&cfg, d, r)),
+                effective_interval_secs: remote
+                    .and_then(Remote::effective_interval)
+                    .map(|i| i.as_secs_f32()),
+                demoted: remote.is_some_and(|r| r.demoted),
+                failures: remote.map_or(0, |r| r.failures),
+                meta: device_meta::get(&d.name),
+                name: d.name.clone(),
+                priority: d.priority,
+                interval_secs: d.interval_secs,
+            }
+        })
+        .collect();
+    devices.sort_by_key(|d| d.meta.sort_order);
+    content::Json(serde_json::to_string(&devices).unwrap())
+}
@@ -0,0 +1,260 @@
+//! Command-line arguments.
+
+use std::path::PathBuf;
+
+pub const USAGE: &str = "\
+Usage: water-mon-app [OPTIONS] [COMMAND]
+
+Commands:
+    install-service [PATH]   Write a systemd unit file (default
+                             /etc/systemd/system/water-mon-app.service)
+    service install          Register the app as a Windows service, started on boot
+    service uninstall        Remove the Windows service
+    service start            Start the Windows service
+    check-config [PATH]      Check a config file (default water-mon.toml), and show
+                             the settings in effect
+    set-password             Set the password for logging in to the web page, read
+                             from stdin
+    migrate import FILE      Import readings from a CSV or NDJSON log into history,
+                             skipping those it already has
+    migrate export           Write history, or a range of it, to stdout as CSV or
+                             NDJSON
+    migrate copy FROM TO     Copy the samples in one history database into another,
+                             skipping those it already has
+    decode-trace FILE        Decode a captured serial trace, from a debug refresh or
+                             a support bundle's `serial.json`, as the poller would
+
+Options:
+    --log-format=FORMAT      `plain` (default), or `journald` for single-line logs
+                             with syslog priorities
+    --simulate-scenario=FILE Replay a scenario, as TOML or JSON, in place of the
+                             Water Monitor, for testing
+    --socket[=PORT]          With install-service, also write a socket unit, so
+                             systemd binds PORT (default 80) and the app needn't
+                             run as root
+    --support-bundle[=PATH]  Write a zip of the config, with secrets redacted,
+                             events, serial stats, startup checks, system details
+                             and the last day of history, for a support request,
+                             without starting the server
+    -h, --help               Show this message
+
+Migrate options:
+    --db=PATH                The history database (default `history.path`)
+    --format=FORMAT          `csv` or `ndjson`; for import, the default is by the
+                             file's extension, and for export, `csv`
+    --time-column=NAME       Import: the column with each reading's time (default
+                             `time`), as RFC 3339, a local time, or seconds or
+                             milliseconds since the epoch
+    --map=COLUMN=SENSOR      Import: the sensor a column has, eg `--map=temp_c=T`.
+                             Columns named for a sensor's id or key need none.
+    --quarantine             Import: put rows with implausible values in the
+                             `quarantine` table, rather than skipping them
+    --from=TIME, --to=TIME   Export: the range (default all of it)
+";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    Plain,
+    Journald,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    /// Run the server. The default.
+    Run,
+    InstallService {
+        path: Option<PathBuf>,
+        /// Write a socket unit for this port too.
+        socket_port: Option<u16>,
+    },
+    Service(ServiceCommand),
+    CheckConfig {
+        path: Option<PathBuf>,
+    },
+    SetPassword,
+    SupportBundle {
+        path: Option<PathBuf>,
+    },
+    Migrate {
+        command: MigrateCommand,
+        options: MigrateOptions,
+    },
+    DecodeTrace {
+        path: PathBuf,
+    },
+    Help,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MigrateCommand {
+    Import { path: PathBuf },
+    Export,
+    Copy { from: PathBuf, to: PathBuf },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DataFormat {
+    Csv,
+    Ndjson,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct MigrateOptions {
+    pub db: Option<PathBuf>,
+    pub format: Option<DataFormat>,
+    pub time_column: Option<String>,
+    /// Columns, and the sensor ids they map to.
+    pub map: Vec<(String, String)>,
+    pub quarantine: bool,
+    pub from: Option<String>,
+    pub to: Option<String>,
+}
+
+/// Managing the Windows service.
+#[derive(Debug, PartialEq)]
+pub enum ServiceCommand {
+    Install,
+    Uninstall,
+    Start,
+    /// Run as the service. Used by the Windows service manager; not for users.
+    Run,
+}
+
+#[derive(Debug)]
+pub struct Args {
+    pub command: Command,
+    pub log_format: LogFormat,
+    pub simulate_scenario: Option<PathBuf>,
+}
+
+impl Args {
+    pub fn parse(args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut command = None;
+        let mut log_format = LogFormat::Plain;
+        let mut simulate_scenario = None;
+        let mut socket_port = None;
+        let mut migrate = MigrateOptions::default();
+        let mut positional = Vec::new();
+
+        for arg in args {
+            if let Some(format) = arg.strip_prefix("--log-format=") {
+                log_format = match format {
+                    "plain" => LogFormat::Plain,
+                    "journald" => LogFormat::Journald,
+                    _ => return Err(format!("Unknown log format `{}`", format)),
+                };
+            } else if let Some(path) = arg.strip_prefix("--simulate-scenario=") {
+                simulate_scenario = Some(PathBuf::from(path));
+            } else if arg == "--socket" {
+                socket_port = Some(80);
+            } else if let Some(port) = arg.strip_prefix("--socket=") {
+                socket_port = Some(
+                    port.parse()
+                        .map_err(|_| format!("Invalid socket port `{}`", port))?,
+                );
+            } else if let Some(path) = arg.strip_prefix("--db=") {
+                migrate.db = Some(PathBuf::from(path));
+            } else if let Some(format) = arg.strip_prefix("--format=") {
+                migrate.format = Some(match format {
+                    "csv" => DataFormat::Csv,
+                    "ndjson" => DataFormat::Ndjson,
+                    _ => return Err(format!("Unknown format `{}`", format)),
+                });
+            } else if let Some(column) = arg.strip_prefix("--time-column=") {
+                migrate.time_column = Some(column.to_owned());
+            } else if let Some(mapping) = arg.strip_prefix("--map=") {
+                let (column, sensor) = mapping
+                    .rsplit_once('=')
+                    .ok_or_else(|| format!("`--map` needs `COLUMN=SENSOR`; got `{}`", mapping))?;
+                migrate.map.push((column.to_owned(), sensor.to_owned()));
+            } else if arg == "--quarantine" {
+                migrate.quarantine = true;
+            } else if let Some(time) = arg.strip_prefix("--from=") {
+                migrate.from = Some(time.to_owned());
+            } else if let Some(time) = arg.strip_prefix("--to=") {
+                migrate.to = Some(time.to_owned());
+            } else if arg == "--support-bundle" {
+                command = Some(Command::SupportBundle { path: None });
+            } else if let Some(path) = arg.strip_prefix("--support-bundle=") {
+                command = Some(Command::SupportBundle {
+                    path: Some(PathBuf::from(path)),
+                });
+            } else if arg == "-h" || arg == "--help" {
+                command = Some(Command::Help);
+            } else if arg.starts_with('-') {
+                return Err(format!("Unknown option `{}`", arg));
+            } else {
+                positional.push(arg);
+            }
+        }
+
+        let mut positional = positional.into_iter();
+        let command = match command {
+            Some(c) => c,
+            None => match positional.next().as_deref() {
+                None => Command::Run,
+                Some("install-service") => Command::InstallService {
+                    path: positional.next().map(PathBuf::from),
+                    socket_port: socket_port.take(),
+                },
+                Some("service") => Command::Service(match positional.next().as_deref() {
+                    Some("install") => ServiceCommand::Install,
+                    Some("uninstall") => ServiceCommand::Uninstall,
+                    Some("start") => ServiceCommand::Start,
+                    Some("run") => ServiceCommand::Run,
+                    Some(c) => return Err(format!("Unknown service command `{}`", c)),
+                    None => return Err("Missing service command".into()),
+                }),
+                Some("check-config") => Command::CheckConfig {
+                    path: positional.next().map(PathBuf::from),
+                },
+                Some("set-password") => Command::SetPassword,
+                Some("migrate") => {
+                    let command = match positional.next().as_deref() {
+                        Some("import") => MigrateCommand::Import {
+                            path: positional.next().ok_or("Missing file to import")?.into(),
+                        },
+                        Some("export") => MigrateCommand::Export,
+                        Some("copy") => match (positional.next(), positional.next()) {
+                            (Some(from), Some(to)) => MigrateCommand::Copy {
+                                from: from.into(),
+                                to: to.into(),
+                            },
+                            _ => {
+                                return Err(
+                                    "`migrate copy` needs a database to copy from, and to".into()
+                                )
+                            }
+                        },
+                        Some(c) => return Err(format!("Unknown migrate command `{}`", c)),
+                        None => return Err("Missing migrate command".into()),
+                    };
+                    Command::Migrate {
+                        command,
+                        options: std::mem::take(&mut migrate),
+                    }
+                }
+                Some("decode-trace") => Command::DecodeTrace {
+                    path: positional.next().ok_or("Missing trace file")?.into(),
+                },
+                Some(c) => return Err(format!("Unknown command `{}`", c)),
+            },
+        };
+
+        if let Some(extra) = positional.next() {
+            return Err(format!("Unexpected argument `{}`", extra));
+        }
+        if socket_port.is_some() {
+            return Err("`--socket` is only for `install-service`".into());
+        }
+        if migrate != MigrateOptions::default() {
+            return Err("That option is only for `migrate`".into());
+        }
+
+        Ok(Self {
+            command,
+            log_format,
+            simulate_scenario,
+        })
+    }
+}
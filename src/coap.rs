@@ -0,0 +1,592 @@
+//! A read-only CoAP server, for constrained clients like battery-powered gateways, for
+//! which polling HTTP is too heavy. `/readings` has the latest value of each sensor, and
+//! `/readings/<sensor>`, by id or snake-case key, just one, from the same cache as the
+//! HTTP API. Payloads are CBOR or compact JSON, per the request's `Accept`, or else
+//! `coap.format`; sensors without a valid reading are `null`. A GET with Observe
+//! registers for a notification after each poll cycle, until the client cancels,
+//! resets one, or leaves `MAX_UNACKED` in a row unacknowledged. Payloads are small, so
+//! there's no block-wise transfer.
+
+use std::{
+    io,
+    net::{SocketAddr, UdpSocket},
+    sync::{Mutex, OnceLock},
+    thread,
+};
+
+use crate::{
+    activity,
+    config::{self, CoapConfig, CoapFormat},
+    events::{self, Severity},
+    memory::{Buffer, Policy},
+    precision,
+    registry::REGISTRY,
+    Readings,
+};
+
+const VERSION: u8 = 1;
+
+const CON: u8 = 0;
+const NON: u8 = 1;
+const ACK: u8 = 2;
+const RST: u8 = 3;
+
+const EMPTY: u8 = 0x00;
+const GET: u8 = 0x01;
+/// 2.05
+const CONTENT: u8 = 0x45;
+/// 4.02
+const BAD_OPTION: u8 = 0x82;
+/// 4.04
+const NOT_FOUND: u8 = 0x84;
+/// 4.05
+const METHOD_NOT_ALLOWED: u8 = 0x85;
+/// 4.06
+const NOT_ACCEPTABLE: u8 = 0x86;
+
+const OBSERVE: u16 = 6;
+const URI_PATH: u16 = 11;
+const CONTENT_FORMAT: u16 = 12;
+const ACCEPT: u16 = 17;
+
+/// Uri-Host, Uri-Port and Uri-Query, which we don't use. Other critical options we
+/// don't know are refused, as the spec requires.
+const IGNORED: [u16; 3] = [3, 7, 15];
+
+const LINK_FORMAT: u32 = 40;
+const JSON: u32 = 50;
+const CBOR: u32 = 60;
+
+const PAYLOAD_MARKER: u8 = 0xff;
+
+/// The spec's suggested maximum message size.
+const MAX_MESSAGE: usize = 1_152;
+
+const MAX_OBSERVERS: usize = 32;
+
+pub const BUFFER: Buffer = Buffer {
+    name: "coap.observers",
+    cap: MAX_OBSERVERS,
+    policy: Policy::Refuse,
+    about: "CoAP clients observing readings; those that stop acknowledging are dropped",
+};
+
+/// Notifications in a row a client can leave unacknowledged before it's dropped.
+const MAX_UNACKED: u8 = 3;
+
+struct Observer {
+    peer: SocketAddr,
+    token: Vec<u8>,
+    /// `None` for all readings.
+    sensor: Option<&'static str>,
+    format: u32,
+    /// The last notification's message ID, until it's acknowledged.
+    pending: Option<u16>,
+    unacked: u8,
+}
+
+struct State {
+    observers: Vec<Observer>,
+    next_message_id: u16,
+    /// Observe's sequence number, 24 bits.
+    sequence: u32,
+}
+
+static SOCKET: OnceLock<UdpSocket> = OnceLock::new();
+
+static STATE: Mutex<State> = Mutex::new(State {
+    observers: Vec::new(),
+    next_message_id: 0,
+    sequence: 0,
+});
+
+struct Message<'a> {
+    type_: u8,
+    code: u8,
+    message_id: u16,
+    token: &'a [u8],
+    /// In order, by number.
+    options: Vec<(u16, &'a [u8])>,
+}
+
+impl<'a> Message<'a> {
+    fn option(&self, number: u16) -> Option<&'a [u8]> {
+        self.options
+            .iter()
+            .find(|(n, _)| *n == number)
+            .map(|(_, v)| *v)
+    }
+}
+
+enum Resource {
+    /// `/.well-known/core`, for discovery.
+    Core,
+    /// `None` for all readings.
+    Readings(Option<&'static str>),
+}
+
+/// An option's delta or length, from its nibble and any bytes extending it.
+fn extended(nibble: u8, buf: &mut &[u8]) -> Option<u16> {
+    let (value, len) = match nibble {
+        0..=12 => return Some(nibble as u16),
+        13 => (*buf.first()? as u16 + 13, 1),
+        14 => {
+            let b = buf.get(..2)?;
+            (u16::from_be_bytes([b[0], b[1]]).checked_add(269)?, 2)
+        }
+        _ => return None,
+    };
+    *buf = &buf[len..];
+    Some(value)
+}
+
+fn parse(buf: &[u8]) -> Option<Message<'_>> {
+    let (&first, rest) = buf.split_first()?;
+    let token_len = (first & 0x0f) as usize;
+    if first >> 6 != VERSION || token_len > 8 || rest.len() < 3 + token_len {
+        return None;
+    }
+
+    let mut options = Vec::new();
+    let mut number: u16 = 0;
+    let mut buf = &rest[3 + token_len..];
+    while let Some(&byte) = buf.first() {
+        if byte == PAYLOAD_MARKER {
+            break;
+        }
+        buf = &buf[1..];
+        let delta = extended(byte >> 4, &mut buf)?;
+        let len = extended(byte & 0x0f, &mut buf)? as usize;
+        number = number.checked_add(delta)?;
+        options.push((number, buf.get(..len)?));
+        buf = &buf[len..];
+    }
+
+    Some(Message {
+        type_: (first >> 4) & 0x03,
+        code: rest[0],
+        message_id: u16::from_be_bytes([rest[1], rest[2]]),
+        token: &rest[3..3 + token_len],
+        options,
+    })
+}
+
+/// An option delta or length's nibble, and the bytes extending it.
+fn nibble(v: u16) -> (u8, Vec<u8>) {
+    match v {
+        0..=12 => (v as u8, Vec::new()),
+        13..=268 => (13, vec![(v - 13) as u8]),
+        _ => (14, (v - 269).to_be_bytes().to_vec()),
+    }
+}
+
+/// `options` must be in order, by number.
+fn encode(
+    type_: u8,
+    code: u8,
+    message_id: u16,
+    token: &[u8],
+    options: &[(u16, Vec<u8>)],
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut result = vec![VERSION << 6 | type_ << 4 | token.len() as u8, code];
+    result.extend_from_slice(&message_id.to_be_bytes());
+    result.extend_from_slice(token);
+
+    let mut last = 0;
+    for (number, value) in options {
+        let (delta, delta_ext) = nibble(number - last);
+        let (len, len_ext) = nibble(value.len() as u16);
+        result.push(delta << 4 | len);
+        result.extend(delta_ext);
+        result.extend(len_ext);
+        result.extend_from_slice(value);
+        last = *number;
+    }
+
+    if !payload.is_empty() {
+        result.push(PAYLOAD_MARKER);
+        result.extend_from_slice(payload);
+    }
+    result
+}
+
+/// Minimal big-endian, as option values are.
+fn encode_uint(v: u32) -> Vec<u8> {
+    let bytes = v.to_be_bytes();
+    let skip = bytes.iter().take_while(|b| **b == 0).count();
+    bytes[skip..].to_vec()
+}
+
+fn decode_uint(value: &[u8]) -> Option<u32> {
+    if value.len() > 4 {
+        return None;
+    }
+    Some(value.iter().fold(0, |acc, b| (acc << 8) | *b as u32))
+}
+
+/// A CBOR item's head, for lengths under 256, which ours are.
+fn cbor_head(major: u8, len: usize, out: &mut Vec<u8>) {
+    if len < 24 {
+        out.push(major << 5 | len as u8);
+    } else {
+        out.extend_from_slice(&[major << 5 | 24, len as u8]);
+    }
+}
+
+/// The latest readings, rounded to each sensor's precision.
+fn shown_readings() -> Readings {
+    precision::rounded(&crate::latest_readings(), &config::get().precision)
+}
+
+fn cbor_value(value: Option<f32>, out: &mut Vec<u8>) {
+    match value {
+        Some(v) => {
+            out.push(0xfa);
+            out.extend_from_slice(&v.to_be_bytes());
+        }
+        None => out.push(0xf6),
+    }
+}
+
+fn json_value(value: Option<f32>) -> String {
+    value.map_or_else(|| "null".into(), |v| serde_json::to_string(&v).unwrap())
+}
+
+/// A sensor's latest value, or all of them, keyed by id, in `format`.
+fn payload(readings: &Readings, sensor: Option<&str>, format: u32) -> Vec<u8> {
+    let valid = |r: Result<f32, _>| r.ok().filter(|v: &f32| !v.is_nan());
+    let values: Vec<_> = match sensor {
+        Some(id) => vec![(id, readings.get(id).and_then(valid))],
+        None => readings.iter().map(|(s, r)| (s.id, valid(r))).collect(),
+    };
+
+    if format == CBOR {
+        let mut out = Vec::new();
+        if sensor.is_none() {
+            cbor_head(5, values.len(), &mut out);
+        }
+        for (id, value) in values {
+            if sensor.is_none() {
+                cbor_head(3, id.len(), &mut out);
+                out.extend_from_slice(id.as_bytes());
+            }
+            cbor_value(value, &mut out);
+        }
+        out
+    } else if sensor.is_some() {
+        json_value(values[0].1).into_bytes()
+    } else {
+        let fields: Vec<_> = values
+            .iter()
+            .map(|(id, value)| format!("\"{}\":{}", id, json_value(*value)))
+            .collect();
+        format!("{{{}}}", fields.join(",")).into_bytes()
+    }
+}
+
+fn resource(path: &[&[u8]]) -> Option<Resource> {
+    match path {
+        [b".well-known", b"core"] => Some(Resource::Core),
+        [b"readings"] => Some(Resource::Readings(None)),
+        [b"readings", sensor] => REGISTRY
+            .iter()
+            .find(|s| s.id.as_bytes() == *sensor || s.key.as_bytes() == *sensor)
+            .map(|s| Resource::Readings(Some(s.id))),
+        _ => None,
+    }
+}
+
+/// Our resources, in CoRE link format.
+fn core_links() -> String {
+    let links: Vec<_> = std::iter::once("/readings".to_owned())
+        .chain(REGISTRY.iter().map(|s| format!("/readings/{}", s.id)))
+        .map(|path| format!("<{}>;obs;ct=\"{} {}\"", path, CBOR, JSON))
+        .collect();
+    links.join(",")
+}
+
+fn next_message_id(state: &mut State) -> u16 {
+    state.next_message_id = state.next_message_id.wrapping_add(1);
+    state.next_message_id
+}
+
+/// Register an observer, replacing any with the same endpoint and token, and return the
+/// sequence number for the response, or `None` if there are too many.
+fn register(
+    peer: SocketAddr,
+    token: &[u8],
+    sensor: Option<&'static str>,
+    format: u32,
+) -> Option<u32> {
+    let mut state = STATE.lock().unwrap();
+    state
+        .observers
+        .retain(|o| !(o.peer == peer && o.token == token));
+    if state.observers.len() >= MAX_OBSERVERS {
+        BUFFER.dropped(1);
+        return None;
+    }
+    state.observers.push(Observer {
+        peer,
+        token: token.to_vec(),
+        sensor,
+        format,
+        pending: None,
+        unacked: 0,
+    });
+    BUFFER.note(state.observers.len());
+    Some(state.sequence)
+}
+
+fn deregister(peer: SocketAddr, token: &[u8]) {
+    STATE
+        .lock()
+        .unwrap()
+        .observers
+        .retain(|o| !(o.peer == peer && o.token == token));
+}
+
+/// An ACK or RST of a notification: the client's still there, or it's done observing.
+fn on_reply(peer: SocketAddr, message_id: u16, reset: bool) {
+    let mut state = STATE.lock().unwrap();
+    let matches = |o: &Observer| o.peer == peer && o.pending == Some(message_id);
+    if reset {
+        state.observers.retain(|o| !matches(o));
+        BUFFER.note(state.observers.len());
+    } else if let Some(o) = state.observers.iter_mut().find(|o| matches(o)) {
+        o.pending = None;
+        o.unacked = 0;
+    }
+}
+
+/// The response to a request.
+fn respond_request(msg: &Message, peer: SocketAddr, default_format: u32) -> Vec<u8> {
+    // Piggybacked on the ACK of a confirmable request.
+    let (type_, message_id) = if msg.type_ == CON {
+        (ACK, msg.message_id)
+    } else {
+        (NON, next_message_id(&mut STATE.lock().unwrap()))
+    };
+    let reply = |code, options: &[(u16, Vec<u8>)], payload: &[u8]| {
+        encode(type_, code, message_id, msg.token, options, payload)
+    };
+
+    let unknown_critical = msg
+        .options
+        .iter()
+        .find(|(n, _)| n % 2 == 1 && ![URI_PATH, ACCEPT].contains(n) && !IGNORED.contains(n));
+    if let Some((number, _)) = unknown_critical {
+        return reply(
+            BAD_OPTION,
+            &[],
+            format!("Unsupported option {}", number).as_bytes(),
+        );
+    }
+    if msg.code != GET {
+        return reply(METHOD_NOT_ALLOWED, &[], b"Only GET is supported");
+    }
+
+    let path: Vec<_> = msg
+        .options
+        .iter()
+        .filter(|(n, _)| *n == URI_PATH)
+        .map(|(_, v)| *v)
+        .collect();
+    let accept = msg.option(ACCEPT).map(decode_uint);
+
+    match resource(&path) {
+        None => reply(NOT_FOUND, &[], b""),
+        Some(Resource::Core) => {
+            if accept.is_some_and(|a| a != Some(LINK_FORMAT)) {
+                return reply(NOT_ACCEPTABLE, &[], b"");
+            }
+            reply(
+                CONTENT,
+                &[(CONTENT_FORMAT, encode_uint(LINK_FORMAT))],
+                core_links().as_bytes(),
+            )
+        }
+        Some(Resource::Readings(sensor)) => {
+            let format = match accept {
+                None => default_format,
+                Some(Some(f)) if f == CBOR || f == JSON => f,
+                _ => return reply(NOT_ACCEPTABLE, &[], b"Use CBOR (60) or JSON (50)"),
+            };
+            let readings = shown_readings();
+            if sensor.is_some_and(|id| readings.get(id).is_none()) {
+                return reply(
+                    NOT_FOUND,
+                    &[],
+                    b"This Water Monitor doesn't have that sensor",
+                );
+            }
+
+            let mut options = Vec::new();
+            // Another request with the same token ends its observation.
+            match msg.option(OBSERVE).and_then(decode_uint) {
+                Some(0) => {
+                    if let Some(sequence) = register(peer, msg.token, sensor, format) {
+                        options.push((OBSERVE, encode_uint(sequence)));
+                    }
+                }
+                _ => deregister(peer, msg.token),
+            }
+            options.push((CONTENT_FORMAT, encode_uint(format)));
+            reply(CONTENT, &options, &payload(&readings, sensor, format))
+        }
+    }
+}
+
+/// The response to a message, if it needs one.
+fn respond(msg: &Message, peer: SocketAddr, default_format: u32) -> Option<Vec<u8>> {
+    match msg.type_ {
+        ACK | RST if msg.code == EMPTY => {
+            on_reply(peer, msg.message_id, msg.type_ == RST);
+            None
+        }
+        // A ping.
+        CON if msg.code == EMPTY => Some(encode(RST, EMPTY, msg.message_id, &[], &[], &[])),
+        CON | NON => Some(respond_request(msg, peer, default_format)),
+        _ => None,
+    }
+}
+
+/// Bind the CoAP port and start answering requests, if enabled.
+pub fn start(cfg: &CoapConfig) -> Result<(), io::Error> {
+    if !cfg.enabled {
+        return Ok(());
+    }
+
+    let socket = UdpSocket::bind(("0.0.0.0", cfg.port)).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Problem listening for CoAP on port {}: {}", cfg.port, e),
+        )
+    })?;
+    let default_format = match cfg.format {
+        CoapFormat::Cbor => CBOR,
+        CoapFormat::Json => JSON,
+    };
+    STATE.lock().unwrap().next_message_id = rand::random();
+    let _ = SOCKET.set(socket.try_clone()?);
+
+    thread::spawn(move || {
+        let mut buf = [0; MAX_MESSAGE];
+        loop {
+            let (len, peer) = match socket.recv_from(&mut buf) {
+                Ok(r) => r,
+                Err(e) => {
+                    events::record(
+                        Severity::Warning,
+                        "coap",
+                        format!("Problem receiving a CoAP message: {}", e),
+                    );
+                    continue;
+                }
+            };
+
+            // Malformed messages are dropped.
+            let Some(msg) = parse(&buf[..len]) else {
+                continue;
+            };
+            activity::touch();
+            if let Some(response) = respond(&msg, peer, default_format) {
+                let _ = socket.send_to(&response, peer);
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Send observers the latest readings, as confirmable notifications, dropping those
+/// that have stopped acknowledging them. Called by the poller, each cycle.
+pub fn notify() {
+    let Some(socket) = SOCKET.get() else {
+        return;
+    };
+    let readings = shown_readings();
+
+    let mut state = STATE.lock().unwrap();
+    state.sequence = (state.sequence + 1) & 0xff_ffff;
+    state.observers.retain(|o| o.unacked < MAX_UNACKED);
+    BUFFER.note(state.observers.len());
+
+    let mut observers = std::mem::take(&mut state.observers);
+    for o in observers.iter_mut() {
+        let message_id = next_message_id(&mut state);
+        let options = [
+            (OBSERVE, encode_uint(state.sequence)),
+            (CONTENT_FORMAT, encode_uint(o.format)),
+        ];
+        let msg = encode(
+            CON,
+            CONTENT,
+            message_id,
+            &o.token,
+            &options,
+            &payload(&readings, o.sensor, o.format),
+        );
+        o.pending = Some(message_id);
+        o.unacked += 1;
+        let _ = socket.send_to(&msg, o.peer);
+    }
+    state.observers = observers;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use proptest::{collection::vec, prelude::*};
+
+    use super::*;
+
+    fn peer() -> SocketAddr {
+        (Ipv4Addr::LOCALHOST, 5683).into()
+    }
+
+    proptest! {
+        #[test]
+        fn any_bytes_are_answered_or_dropped(buf in vec(any::<u8>(), 0..64)) {
+            if let Some(msg) = parse(&buf) {
+                respond(&msg, peer(), JSON);
+            }
+        }
+
+        #[test]
+        fn messages_round_trip(
+            type_ in 0..4u8,
+            code: u8,
+            message_id: u16,
+            token in vec(any::<u8>(), 0..=8),
+            options in vec((0..1_000u16, vec(any::<u8>(), 0..300)), 0..6),
+            payload in vec(any::<u8>(), 0..32),
+        ) {
+            let mut number = 0;
+            let options: Vec<_> = options
+                .into_iter()
+                .map(|(delta, value)| {
+                    number += delta;
+                    (number, value)
+                })
+                .collect();
+            let buf = encode(type_, code, message_id, &token, &options, &payload);
+
+            let msg = parse(&buf).unwrap();
+            prop_assert_eq!(msg.type_, type_);
+            prop_assert_eq!(msg.code, code);
+            prop_assert_eq!(msg.message_id, message_id);
+            prop_assert_eq!(msg.token, &token[..]);
+            let parsed: Vec<_> = msg.options.iter().map(|(n, v)| (*n, v.to_vec())).collect();
+            prop_assert_eq!(parsed, options);
+            respond(&msg, peer(), CBOR);
+        }
+
+        #[test]
+        fn uints_round_trip(v: u32) {
+            prop_assert_eq!(decode_uint(&encode_uint(v)), Some(v));
+        }
+    }
+}
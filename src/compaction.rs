@@ -0,0 +1,205 @@
+//! Rolls old history up into coarser tiers, so the database stays small and queries
+//! over long ranges stay fast: raw samples into 1-minute rows, and 1-minute rows into
+//! 1-hour rows, each once they're older than the configured window.
+//!
+//! Each chunk is aggregated and deleted from its source in one transaction, so an
+//! interrupted run loses nothing, and the next run picks up where it stopped.
+
+use std::{thread, time::Duration};
+
+use chrono::Utc;
+use rusqlite::{params, Connection};
+
+use crate::{
+    config::{self, HistoryConfig},
+    events::{self, Severity},
+    history::{self, AGGREGATE_TIERS},
+    registry, retention,
+};
+
+/// How often to check for rows to compact.
+const INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Pause between chunks, so the history writer gets a turn.
+const CHUNK_PAUSE: Duration = Duration::from_millis(100);
+
+/// Report progress this often during a long run, eg the first after an upgrade.
+const PROGRESS_EVERY_CHUNKS: u32 = 100;
+
+/// A compaction step: from one table into the next tier's aggregates.
+struct Step {
+    source: &'static str,
+    /// If the source is raw samples, rather than aggregates.
+    raw: bool,
+    dest: &'static str,
+    bucket_ms: i64,
+    /// Source rows per transaction, by time.
+    chunk_ms: i64,
+}
+
+const STEPS: [Step; 2] = [
+    Step {
+        source: "samples",
+        raw: true,
+        dest: AGGREGATE_TIERS[0].0,
+        bucket_ms: AGGREGATE_TIERS[0].1,
+        chunk_ms: 60 * 60 * 1_000,
+    },
+    Step {
+        source: AGGREGATE_TIERS[0].0,
+        raw: false,
+        dest: AGGREGATE_TIERS[1].0,
+        bucket_ms: AGGREGATE_TIERS[1].1,
+        chunk_ms: 24 * 60 * 60 * 1_000,
+    },
+];
+
+/// Compact, then apply the retention limits, forever; run this on its own thread.
+pub fn run() {
+    loop {
+        let cfg = config::get().history;
+        if cfg.enabled {
+            if let Err(e) = compact(&cfg) {
+                events::record(
+                    Severity::Warning,
+                    "compaction",
+                    format!("Problem compacting history: {}", e),
+                );
+            }
+            if let Err(e) = retention::run(&cfg) {
+                events::record(
+                    Severity::Warning,
+                    "retention",
+                    format!("Problem pruning history: {}", e),
+                );
+            }
+        }
+        thread::sleep(INTERVAL);
+    }
+}
+
+fn compact(cfg: &HistoryConfig) -> Result<(), Box<dyn std::error::Error>> {
+    let conn = history::open(&cfg.path)?;
+    let now = Utc::now().timestamp_millis();
+    let windows = [
+        cfg.keep_raw_hours as i64 * 60 * 60 * 1_000,
+        cfg.keep_1m_days as i64 * 24 * 60 * 60 * 1_000,
+    ];
+
+    for (step, window) in STEPS.iter().zip(windows.iter()) {
+        // Whole buckets only, so a bucket is never split between runs.
+        let cutoff = now - window;
+        let cutoff = cutoff - cutoff.rem_euclid(step.bucket_ms);
+
+        let mut chunks = 0;
+        let mut rows = 0;
+        while let Some(n) = compact_chunk(&conn, step, cutoff)? {
+            chunks += 1;
+            rows += n;
+            if chunks % PROGRESS_EVERY_CHUNKS == 0 {
+                events::record(
+                    Severity::Info,
+                    "compaction",
+                    format!(
+                        "Compacting history: {} rows from `{}` so far.",
+                        rows, step.source
+                    ),
+                );
+            }
+            thread::sleep(CHUNK_PAUSE);
+        }
+
+        if rows > 0 {
+            events::record(
+                Severity::Info,
+                "compaction",
+                format!(
+                    "Compacted {} rows from `{}` into `{}`.",
+                    rows, step.source, step.dest
+                ),
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Compact the oldest chunk before `cutoff`. Returns the number of source rows
+/// compacted, or `None` if there's nothing left to do.
+fn compact_chunk(
+    conn: &Connection,
+    step: &Step,
+    cutoff: i64,
+) -> Result<Option<usize>, rusqlite::Error> {
+    let oldest: Option<i64> = conn.query_row(
+        &format!("SELECT MIN(time) FROM {} WHERE time < ?1", step.source),
+        params![cutoff],
+        |row| row.get(0),
+    )?;
+
+    let start = match oldest {
+        Some(t) => t - t.rem_euclid(step.bucket_ms),
+        None => return Ok(None),
+    };
+    let end = (start + step.chunk_ms).min(cutoff);
+
+    let _lock = history::lock_writes();
+    let tx = conn.unchecked_transaction()?;
+    tx.execute(&aggregate_sql(step), params![start, end, step.bucket_ms])?;
+    let n = tx.execute(
+        &format!("DELETE FROM {} WHERE time >= ?1 AND time < ?2", step.source),
+        params![start, end],
+    )?;
+    tx.commit()?;
+
+    Ok(Some(n))
+}
+
+/// Aggregate source rows between `?1` and `?2` into buckets of `?3` ms, merging into any
+/// existing rows for the same buckets.
+fn aggregate_sql(step: &Step) -> String {
+    let mut dest_columns = vec!["time".to_owned(), "count".to_owned()];
+    let mut select = vec![
+        "time - time % ?3".to_owned(),
+        if step.raw { "COUNT(*)" } else { "SUM(count)" }.to_owned(),
+    ];
+    let mut merge = vec!["count = count + excluded.count".to_owned()];
+
+    for name in registry::ids() {
+        dest_columns.push(format!("{0}_avg, {0}_min, {0}_max, {0}_n", name));
+
+        select.push(if step.raw {
+            // Readings from a sensor stabilizing are dropped, like errors.
+            let value = format!("CASE WHEN {} THEN {} END", history::settled(name), name);
+            format!("AVG({0}), MIN({0}), MAX({0}), COUNT({0})", value)
+        } else {
+            format!(
+                "SUM({0}_avg * {0}_n) / NULLIF(SUM({0}_n), 0), MIN({0}_min), MAX({0}_max), \
+                 SUM({0}_n)",
+                name
+            )
+        });
+
+        // On the right, column names refer to the existing row.
+        merge.push(format!(
+            "{0}_avg = (COALESCE({0}_avg * {0}_n, 0) + COALESCE(excluded.{0}_avg * excluded.{0}_n, 0)) \
+                 / NULLIF({0}_n + excluded.{0}_n, 0), \
+             {0}_min = MIN(COALESCE({0}_min, excluded.{0}_min), COALESCE(excluded.{0}_min, {0}_min)), \
+             {0}_max = MAX(COALESCE({0}_max, excluded.{0}_max), COALESCE(excluded.{0}_max, {0}_max)), \
+             {0}_n = {0}_n + excluded.{0}_n",
+            name
+        ));
+    }
+
+    // Samples taken during maintenance are dropped, rather than averaged in.
+    format!(
+        "INSERT INTO {} ({}) SELECT {} FROM {} WHERE time >= ?1 AND time < ?2{} GROUP BY 1 \
+         ON CONFLICT (time) DO UPDATE SET {}",
+        step.dest,
+        dest_columns.join(", "),
+        select.join(", "),
+        step.source,
+        if step.raw { " AND NOT maintenance" } else { "" },
+        merge.join(", ")
+    )
+}
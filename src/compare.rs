@@ -0,0 +1,265 @@
+//! Statistics for two ranges of history side by side, eg the weeks before and after a
+//! change to dosing, with the difference between them. Time in range is against the
+//! active `[status.targets]`. The mean, standard deviation and time in range are
+//! weighted by time, as `time_weighted` describes, so a burst of fast polling doesn't
+//! skew them; the arithmetic mean of the samples is there too. Compacted rows count as
+//! their average, so over older ranges, the standard deviation is of those averages.
+
+use chrono::Utc;
+use chrono_tz::Tz;
+use rocket::{http::Status, response::content};
+use rusqlite::{params, Connection};
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::{
+    api::{self, ErrorResponse},
+    config,
+    history::{self, AGGREGATE_TIERS},
+    registry, time_weighted,
+    tokens::Viewer,
+    tz,
+};
+
+#[derive(Clone, Copy)]
+struct Range {
+    from_ms: i64,
+    to_ms: i64,
+}
+
+#[derive(Serialize)]
+struct Stats {
+    count: u64,
+    /// Time-weighted; the arithmetic mean if the samples stand for no time.
+    mean: Option<f64>,
+    /// Of the samples, each compacted row counting for the samples in it.
+    arithmetic_mean: Option<f64>,
+    min: Option<f64>,
+    max: Option<f64>,
+    stddev: Option<f64>,
+    /// The percentage of the time covered that was within the sensor's target; `None`
+    /// without one.
+    in_range_pct: Option<f64>,
+    /// The share of the range, from 0 to 1, that the samples stand for.
+    coverage: f64,
+    /// `time`, or `samples` if the samples stand for no time.
+    weighting: &'static str,
+}
+
+/// B minus A.
+#[derive(Serialize)]
+struct Delta {
+    mean: Option<f64>,
+    min: Option<f64>,
+    max: Option<f64>,
+    stddev: Option<f64>,
+    in_range_pct: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct SensorComparison {
+    a: Stats,
+    b: Stats,
+    delta: Delta,
+}
+
+/// Parse a range: `7d`, the last 7 days; `7d..14d`, from 14 days ago to 7 days ago, in
+/// either order; or `<from>..<to>`, with times as for `/api/history`.
+fn parse_range(s: &str, name: &str, now_ms: i64, zone: Tz) -> Result<Range, ErrorResponse> {
+    let invalid = |detail: &str| {
+        api::error(
+            Status::BadRequest,
+            &format!(
+                "`{}` must be a duration back from now, eg `7d`, two, eg `7d..14d`, or two \
+                 times, eg `2021-06-01..2021-06-08`; got `{}`{}",
+                name, s, detail
+            ),
+        )
+    };
+
+    let range = match s.split_once("..") {
+        None => {
+            let ms = history::parse_duration(s).ok_or_else(|| invalid(""))?;
+            Range {
+                from_ms: now_ms - ms,
+                to_ms: now_ms,
+            }
+        }
+        Some((a, b)) => match (history::parse_duration(a), history::parse_duration(b)) {
+            (Some(a), Some(b)) => Range {
+                from_ms: now_ms - a.max(b),
+                to_ms: now_ms - a.min(b),
+            },
+            _ => Range {
+                from_ms: history::parse_time(a, name, zone)?.timestamp_millis(),
+                to_ms: history::parse_time(b, name, zone)?.timestamp_millis(),
+            },
+        },
+    };
+
+    if range.from_ms >= range.to_ms {
+        return Err(invalid(", which ends before it starts"));
+    }
+    Ok(range)
+}
+
+/// A sensor's stats over a range.
+fn stats(
+    conn: &Connection,
+    name: &str,
+    range: Range,
+    target: Option<[f32; 2]>,
+    max_hold_ms: i64,
+) -> Result<Stats, ErrorResponse> {
+    // Every valid value, with how many samples it stands for, and their extremes, but
+    // not from maintenance, or while it was stabilizing.
+    let mut values = vec![format!(
+        "SELECT {0} AS v, 1 AS w, {0} AS lo, {0} AS hi FROM samples \
+         WHERE time >= ?1 AND time < ?2 AND {0} IS NOT NULL AND NOT maintenance AND {1}",
+        name,
+        history::settled(name)
+    )];
+    for (table, _) in AGGREGATE_TIERS.iter() {
+        values.push(format!(
+            "SELECT {0}_avg, {0}_n, {0}_min, {0}_max FROM {1} \
+             WHERE time >= ?1 AND time < ?2 AND {0}_n > 0",
+            name, table
+        ));
+    }
+    let sql = format!(
+        "SELECT COALESCE(SUM(w), 0), SUM(v * w) / SUM(w), MIN(lo), MAX(hi), \
+         SUM(v * v * w) / SUM(w), SUM(CASE WHEN v >= ?3 AND v <= ?4 THEN w ELSE 0 END) \
+         FROM ({})",
+        values.join(" UNION ALL ")
+    );
+
+    let [min, max] = target.unwrap_or([f32::MIN, f32::MAX]);
+    let mut stats = conn
+        .query_row(
+            &sql,
+            params![range.from_ms, range.to_ms, min as f64, max as f64],
+            |row| {
+                let count: i64 = row.get(0)?;
+                let mean: Option<f64> = row.get(1)?;
+                let mean_sq: Option<f64> = row.get(4)?;
+                let in_range: Option<i64> = row.get(5)?;
+                Ok(Stats {
+                    count: count as u64,
+                    mean,
+                    arithmetic_mean: mean,
+                    min: row.get(2)?,
+                    max: row.get(3)?,
+                    stddev: stddev(mean, mean_sq),
+                    in_range_pct: match (target, in_range) {
+                        (Some(_), Some(n)) if count > 0 => Some(n as f64 * 100. / count as f64),
+                        _ => None,
+                    },
+                    coverage: 0.,
+                    weighting: "samples",
+                })
+            },
+        )
+        .map_err(history::query_error)?;
+
+    let timed = time_weighted::summary(conn, name, range.from_ms, range.to_ms, max_hold_ms, target)
+        .map_err(history::query_error)?;
+    stats.coverage = (timed.covered_ms / (range.to_ms - range.from_ms) as f64).min(1.);
+    if timed.mean.is_some() {
+        stats.mean = timed.mean;
+        stats.stddev = stddev(timed.mean, timed.mean_sq);
+        stats.in_range_pct = target.map(|_| timed.in_range_ms * 100. / timed.covered_ms);
+        stats.weighting = "time";
+    }
+    Ok(stats)
+}
+
+fn stddev(mean: Option<f64>, mean_sq: Option<f64>) -> Option<f64> {
+    // Rounding can take the variance just below 0.
+    mean.zip(mean_sq).map(|(m, sq)| (sq - m * m).max(0.).sqrt())
+}
+
+fn delta(a: Option<f64>, b: Option<f64>) -> Option<f64> {
+    a.zip(b).map(|(a, b)| b - a)
+}
+
+/// Per-sensor mean, min, max, standard deviation, time in range and coverage, for
+/// `range_a` and `range_b`, and B minus A. The ranges can't overlap, and each needs samples.
+#[get("/compare?<range_a>&<range_b>&<tz>")]
+pub fn view_compare(
+    _viewer: Viewer,
+    range_a: String,
+    range_b: String,
+    tz: Option<String>,
+) -> Result<content::Json<String>, ErrorResponse> {
+    let zone = tz::from_query(tz.as_deref())?;
+    let now_ms = Utc::now().timestamp_millis();
+    let a = parse_range(&range_a, "range_a", now_ms, zone)?;
+    let b = parse_range(&range_b, "range_b", now_ms, zone)?;
+
+    let describe = |r: Range| {
+        format!(
+            "{} to {}",
+            tz::format(r.from_ms, zone),
+            tz::format(r.to_ms, zone)
+        )
+    };
+    if a.from_ms < b.to_ms && b.from_ms < a.to_ms {
+        return Err(api::error(
+            Status::BadRequest,
+            &format!(
+                "The ranges overlap: `range_a` is {}, and `range_b` is {}",
+                describe(a),
+                describe(b)
+            ),
+        ));
+    }
+
+    let conn = history::open_reader()?;
+    let cfg = config::get();
+    let probe = cfg.ec.probe;
+    let max_hold_ms = history::gap_ms(&cfg);
+
+    let mut sensors = Map::new();
+    let (mut count_a, mut count_b) = (0, 0);
+    for name in registry::ids() {
+        let target = cfg.status.targets.get(name, probe);
+        let stats_a = stats(&conn, name, a, target, max_hold_ms)?;
+        let stats_b = stats(&conn, name, b, target, max_hold_ms)?;
+        count_a += stats_a.count;
+        count_b += stats_b.count;
+
+        let comparison = SensorComparison {
+            delta: Delta {
+                mean: delta(stats_a.mean, stats_b.mean),
+                min: delta(stats_a.min, stats_b.min),
+                max: delta(stats_a.max, stats_b.max),
+                stddev: delta(stats_a.stddev, stats_b.stddev),
+                in_range_pct: delta(stats_a.in_range_pct, stats_b.in_range_pct),
+            },
+            a: stats_a,
+            b: stats_b,
+        };
+        sensors.insert(name.into(), serde_json::to_value(comparison).unwrap());
+    }
+
+    for (name, range, count) in [("range_a", a, count_a), ("range_b", b, count_b)] {
+        if count == 0 {
+            return Err(api::error(
+                Status::BadRequest,
+                &format!("`{}`, {}, has no samples", name, describe(range)),
+            ));
+        }
+    }
+
+    let mut result = Map::new();
+    for (name, range) in [("range_a", a), ("range_b", b)] {
+        let mut r = Map::new();
+        r.insert("from".into(), tz::format(range.from_ms, zone).into());
+        r.insert("to".into(), tz::format(range.to_ms, zone).into());
+        result.insert(name.into(), Value::Object(r));
+    }
+    result.insert("tz".into(), zone.name().into());
+    result.insert("sensors".into(), Value::Object(sensors));
+
+    Ok(content::Json(Value::Object(result).to_string()))
+}
@@ -0,0 +1,1276 @@
+//! App configuration, loaded from `water-mon.toml` in the working directory. Every
+//! setting has a default, so the file, and any section or key in it, is optional.
+
+use std::{collections::BTreeMap, fs, io, path::Path, sync::RwLock};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    events::Severity,
+    registry::{self, KeyNaming, Kind, SensorMap},
+};
+
+pub const CONFIG_PATH: &str = "water-mon.toml";
+
+static CONFIG: RwLock<Option<AppConfig>> = RwLock::new(None);
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LocaleConfig {
+    /// A language tag, eg `de-DE` or `en-US`, for numbers and units in human-facing text.
+    /// JSON is unaffected.
+    pub language: String,
+}
+
+impl Default for LocaleConfig {
+    fn default() -> Self {
+        Self {
+            language: "en-GB".into(),
+        }
+    }
+}
+
+/// Decimal places readings are shown to, wherever they leave the app. History keeps
+/// them as measured.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PrecisionConfig {
+    /// Off to show readings as measured.
+    pub round: bool,
+    /// Decimal places by sensor id, eg `pH = 3`, for those that shouldn't have their
+    /// default.
+    pub decimals: BTreeMap<String, u8>,
+}
+
+impl Default for PrecisionConfig {
+    fn default() -> Self {
+        Self {
+            round: true,
+            decimals: BTreeMap::new(),
+        }
+    }
+}
+
+/// Probes that swing for a while after power-up, like ORP, are stabilizing after the
+/// Water Monitor connects: not an error, but kept out of alerts and stats.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct WarmupConfig {
+    pub enabled: bool,
+    /// Policies by sensor id, eg `ORP`, for those that shouldn't have their default.
+    /// Only ORP warms up by default.
+    pub sensors: BTreeMap<String, WarmupPolicy>,
+}
+
+impl Default for WarmupConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            sensors: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub struct WarmupPolicy {
+    /// Stabilizing for at most this long after connecting; 0 for no warm-up.
+    pub max_secs: u32,
+    /// Settled sooner, once readings over the last `window_secs` have a standard
+    /// deviation below this, in the sensor's unit, eg mV. If unset, always `max_secs`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_std_dev: Option<f32>,
+    #[serde(default = "default_window_secs")]
+    pub window_secs: u32,
+}
+
+fn default_window_secs() -> u32 {
+    60
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct InstanceConfig {
+    /// A name for people, eg `Back pond`. Defaults to the hostname.
+    pub name: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AppConfig {
+    pub instance: InstanceConfig,
+    pub server: ServerConfig,
+    pub access_log: AccessLogConfig,
+    pub watchdog: WatchdogConfig,
+    pub reliability: ReliabilityConfig,
+    pub resources: ResourcesConfig,
+    pub polling: PollingConfig,
+    pub serial: SerialConfig,
+    pub modbus: ModbusConfig,
+    pub snmp: SnmpConfig,
+    pub coap: CoapConfig,
+    pub metrics: MetricsConfig,
+    pub history: HistoryConfig,
+    pub auth: AuthConfig,
+    pub flight_controller: FlightControllerConfig,
+    pub status: StatusConfig,
+    pub score: ScoreConfig,
+    pub time: TimeConfig,
+    pub locale: LocaleConfig,
+    pub precision: PrecisionConfig,
+    pub warmup: WarmupConfig,
+    pub ec: EcConfig,
+    pub flow: FlowConfig,
+    pub indicator: IndicatorConfig,
+    pub validation_hook: ValidationHookConfig,
+    /// Other Water Monitors to read from, for `channels`.
+    pub devices: Vec<DeviceConfig>,
+    /// Readings merged across devices, eg two monitors in one pond.
+    pub channels: Vec<ChannelConfig>,
+    /// HTTP endpoints to push readings to.
+    pub exporters: Vec<ExporterConfig>,
+    /// Binary inputs, eg float switches.
+    pub inputs: Vec<InputConfig>,
+    /// Alerts on combinations of readings and trends.
+    pub alerts: Vec<AlertRule>,
+    pub notify: NotifyConfig,
+    pub signing: SigningConfig,
+    pub updates: UpdatesConfig,
+}
+
+/// Which IP versions to listen on, when no specific `address` is set.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IpVersion {
+    Ipv4,
+    /// Listening on `::`. Whether this also accepts IPv4 depends on the OS: Linux does
+    /// by default, unless `net.ipv6.bindv6only` is set.
+    Ipv6,
+    /// Listening on `::`, and on `0.0.0.0` too if the OS keeps them separate.
+    Dual,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// Listen on this address only. If unset, listen on all interfaces, per `ip_version`.
+    pub address: Option<String>,
+    pub ip_version: IpVersion,
+    /// 80 means default, ie users can just go to localhost.
+    pub port: u16,
+    /// Also listen on this unix domain socket, eg for a reverse proxy.
+    pub unix_socket: Option<String>,
+    /// Permissions of the socket file, in octal.
+    pub unix_socket_mode: String,
+    /// Serve TCP clients. If false, and `unix_socket` is set, TCP only listens on
+    /// localhost, as the socket's backend.
+    pub tcp: bool,
+    /// Reverse proxies, as addresses or networks like `10.0.0.0/8`, whose
+    /// `X-Forwarded-*` headers say who the client is, and where it connected.
+    pub trusted_proxies: Vec<String>,
+    /// The web page's files. If unset, `WATER_MON_STATIC_DIR`, or else `static` next to
+    /// the executable, or in the working directory.
+    pub static_dir: Option<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            address: None,
+            ip_version: IpVersion::Ipv4,
+            port: 80,
+            unix_socket: None,
+            unix_socket_mode: "660".into(),
+            tcp: true,
+            trusted_proxies: Vec::new(),
+            static_dir: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AccessLogConfig {
+    /// Log every request to a file, and track the slowest ones.
+    pub enabled: bool,
+    pub path: String,
+    /// Rotate the log once it exceeds this size, in kB.
+    pub max_size_kb: u64,
+    /// How many rotated files to keep, in addition to the current one.
+    pub keep_files: u32,
+}
+
+impl ServerConfig {
+    /// The address to bind our TCP listener to.
+    pub fn bind_address(&self) -> &str {
+        match (&self.address, self.ip_version) {
+            (Some(addr), _) => addr,
+            (None, IpVersion::Ipv4) => "0.0.0.0",
+            (None, _) => "::",
+        }
+    }
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: "access.log".into(),
+            max_size_kb: 1_024,
+            keep_files: 5,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct WatchdogConfig {
+    /// Reopen the serial port after repeated failures.
+    pub enabled: bool,
+    /// Consecutive failed polls before we close, rediscover, and reopen the port.
+    pub max_consecutive_failures: u32,
+    /// Raise an alert once the Water Monitor has been unreachable this long, in minutes.
+    pub unreachable_alert_mins: u32,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_consecutive_failures: 5,
+            unreachable_alert_mins: 10,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ReliabilityConfig {
+    /// Windows to report at `/api/reliability`, as durations like `1h` or `7d`.
+    pub windows: Vec<String>,
+    /// Raise an alert when fewer than this percentage of the last day's poll cycles
+    /// gave fully valid readings. Unset for no alert.
+    pub alert_below_pct: Option<f32>,
+    /// Cycles the last day needs before alerting, so a fresh start doesn't.
+    pub alert_min_cycles: u32,
+}
+
+impl Default for ReliabilityConfig {
+    fn default() -> Self {
+        Self {
+            windows: vec!["1h".into(), "24h".into(), "7d".into()],
+            alert_below_pct: Some(95.),
+            alert_min_cycles: 1_000,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ResourcesConfig {
+    /// Stop storing history while the disk holding it has less free than this. Unset to
+    /// never stop.
+    pub min_free_disk_mb: Option<u64>,
+}
+
+impl Default for ResourcesConfig {
+    fn default() -> Self {
+        Self {
+            min_free_disk_mb: Some(100),
+        }
+    }
+}
+
+/// Order of the two registers holding each float.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WordOrder {
+    /// The high word in the lower register; the Modbus convention.
+    HighFirst,
+    /// The low word in the lower register, as some PLCs expect.
+    LowFirst,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SerialConfig {
+    /// The Water Monitor's port, eg `/dev/ttyUSB0` or `COM3`. If unset, it's found by its
+    /// USB serial number.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PollingConfig {
+    /// Poll slower while no clients are watching. Off by default.
+    pub adaptive: bool,
+    /// Clients count as gone after this long without a request, in seconds.
+    pub idle_after_secs: u32,
+    /// Seconds between polls while they're gone.
+    pub idle_interval_secs: u32,
+    /// Readings to take back to back each poll. Each sensor's median is recorded.
+    pub samples_per_cycle: u32,
+    /// Keep the latest cycle's samples, for `/api/debug/samples`.
+    pub keep_cycle_samples: bool,
+}
+
+impl Default for PollingConfig {
+    fn default() -> Self {
+        Self {
+            adaptive: false,
+            idle_after_secs: 5 * 60,
+            idle_interval_secs: 30,
+            samples_per_cycle: 1,
+            keep_cycle_samples: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ModbusConfig {
+    /// Serve readings over Modbus TCP, as read-only holding registers.
+    pub enabled: bool,
+    pub port: u16,
+    pub word_order: WordOrder,
+}
+
+impl Default for ModbusConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: 502,
+            word_order: WordOrder::HighFirst,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SnmpConfig {
+    /// Serve readings to SNMP v2c pollers. Read-only.
+    pub enabled: bool,
+    pub port: u16,
+    pub community: String,
+    /// Where our objects live. The default is Net-SNMP's experimental subtree; set your
+    /// own private enterprise OID here if you have one. Must be under 1.3.6.1.4.1.
+    pub oid_base: String,
+}
+
+impl Default for SnmpConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: 161,
+            community: "public".into(),
+            oid_base: "1.3.6.1.4.1.8072.9999.9999.1".into(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CoapFormat {
+    Cbor,
+    Json,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CoapConfig {
+    /// Serve readings to CoAP clients, eg battery-powered gateways. Read-only.
+    pub enabled: bool,
+    pub port: u16,
+    /// The payload format for requests without an `Accept` option.
+    pub format: CoapFormat,
+}
+
+impl Default for CoapConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: 5683,
+            format: CoapFormat::Cbor,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricsProtocol {
+    /// Gauges over UDP.
+    Statsd,
+    /// Graphite's plaintext protocol, over TCP.
+    Graphite,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct MetricsConfig {
+    /// Push each sensor's reading, and serial counters, to StatsD or Graphite.
+    pub enabled: bool,
+    pub protocol: MetricsProtocol,
+    /// `host:port`. Defaults to port 8125 on this machine for StatsD, and 2003 for
+    /// Graphite.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+    pub interval_secs: u32,
+    /// Before every metric name, eg `water_mon.pH`.
+    pub prefix: String,
+    /// With StatsD and `[[devices]]`, tag metrics with their device, DogStatsD style,
+    /// rather than putting it in the name.
+    pub tags: bool,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            protocol: MetricsProtocol::Statsd,
+            address: None,
+            interval_secs: 10,
+            prefix: "water_mon".into(),
+            tags: false,
+        }
+    }
+}
+
+impl MetricsConfig {
+    pub fn address(&self) -> String {
+        self.address.clone().unwrap_or_else(|| match self.protocol {
+            MetricsProtocol::Statsd => "127.0.0.1:8125".into(),
+            MetricsProtocol::Graphite => "127.0.0.1:2003".into(),
+        })
+    }
+}
+
+/// Checking for a newer release. Off by default, so nothing leaves the network unasked.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct UpdatesConfig {
+    /// Check `url` daily, and say when there's a newer release. Nothing is installed.
+    pub check: bool,
+    /// A plain HTTP URL returning JSON with the latest release's `tag_name`, as GitHub's
+    /// releases API does, or `version`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct HistoryConfig {
+    /// Store every successful reading, for `/api/history`.
+    pub enabled: bool,
+    /// The SQLite database.
+    pub path: String,
+    /// Keep raw samples this long, then roll them up into 1-minute rows.
+    pub keep_raw_hours: u32,
+    /// Keep 1-minute rows this long, then roll them up into 1-hour rows.
+    pub keep_1m_days: u32,
+    /// Delete rows older than this, from every tier. Unset keeps history forever.
+    pub max_age_days: Option<u32>,
+    /// Delete the oldest rows while the database uses more than this. Unset for no limit.
+    pub max_size_mb: Option<u64>,
+    /// Report stretches without samples longer than this many sampling intervals as
+    /// gaps.
+    pub gap_factor: f32,
+    /// With `?interpolate=true`, fill gaps up to this long, in seconds.
+    pub interpolate_max_secs: u32,
+    pub plausible: PlausibleRanges,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            path: "history.sqlite3".into(),
+            keep_raw_hours: 48,
+            keep_1m_days: 30,
+            max_age_days: None,
+            max_size_mb: None,
+            gap_factor: 5.,
+            interpolate_max_secs: 5 * 60,
+            plausible: Default::default(),
+        }
+    }
+}
+
+/// `[min, max]` by sensor id, eg `pH = [0, 14]`. Stored values outside these are flagged
+/// when verifying history.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct PlausibleRanges(pub SensorMap<[f32; 2]>);
+
+impl Default for PlausibleRanges {
+    fn default() -> Self {
+        Self(SensorMap::empty())
+    }
+}
+
+impl PlausibleRanges {
+    /// The range for a sensor, by its id in the registry. Sensors without a setting
+    /// use the registry's range, or for conductivity, the EC probe's.
+    pub fn get(&self, sensor: &str, probe: CellConstant) -> [f32; 2] {
+        if let Some(range) = self.0.get(sensor) {
+            return range;
+        }
+        match registry::get(sensor) {
+            Some(s) if s.kind == Kind::Conductivity => probe.plausible(),
+            Some(s) => s.plausible,
+            None => [f32::MIN, f32::MAX],
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct FlightControllerConfig {
+    /// Talk to a flight controller on the serial port, using its framed protocol. Off by
+    /// default, since a Water Monitor doesn't understand these requests. Needs the
+    /// `flight-controller` feature.
+    pub enabled: bool,
+    /// How often to request the control channels, in ms.
+    pub controls_refresh_ms: u32,
+    /// How often to request position and attitude, in ms.
+    pub params_refresh_ms: u32,
+    /// How long to wait for the flight controller to acknowledge a command, in ms.
+    pub ack_timeout_ms: u32,
+    /// How often to set the flight controller's clock to ours, in minutes, as well as on
+    /// connecting. 0 syncs on connecting only.
+    pub clock_sync_mins: u32,
+    /// Warn when a sync finds its clock off by more than this, in ms.
+    pub max_clock_offset_ms: u32,
+}
+
+impl Default for FlightControllerConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            controls_refresh_ms: 500,
+            params_refresh_ms: 500,
+            ack_timeout_ms: 1_000,
+            clock_sync_mins: 60,
+            max_clock_offset_ms: 1_000,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct StatusConfig {
+    /// Readings are `stale` once the last successful one is this many seconds old.
+    pub stale_secs: u32,
+    /// How far outside its target range a reading is `warn` rather than `critical`, as
+    /// a fraction of the range's width.
+    pub warn_margin: f32,
+    pub targets: TargetRanges,
+}
+
+impl Default for StatusConfig {
+    fn default() -> Self {
+        Self {
+            stale_secs: 10,
+            warn_margin: 0.25,
+            targets: Default::default(),
+        }
+    }
+}
+
+/// The water-quality score, from each sensor's status.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ScoreConfig {
+    /// Each sensor's share of the score, relative to the others, by id. Sensors left out,
+    /// or without a reading, don't count.
+    pub weights: BTreeMap<String, f32>,
+    pub penalties: ScorePenalties,
+}
+
+impl Default for ScoreConfig {
+    fn default() -> Self {
+        let weights = [("T", 1.), ("pH", 3.), ("ORP", 3.), ("ec", 1.), ("DO", 2.)];
+        Self {
+            weights: weights.iter().map(|(s, w)| (s.to_string(), *w)).collect(),
+            penalties: Default::default(),
+        }
+    }
+}
+
+/// How much of its share a sensor loses in each status, from 0 to 1.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ScorePenalties {
+    pub warn: f32,
+    pub critical: f32,
+    pub error: f32,
+    pub stale: f32,
+    pub no_flow: f32,
+    pub stabilizing: f32,
+}
+
+impl Default for ScorePenalties {
+    fn default() -> Self {
+        Self {
+            warn: 0.4,
+            critical: 1.,
+            error: 1.,
+            stale: 0.5,
+            no_flow: 0.5,
+            stabilizing: 0.5,
+        }
+    }
+}
+
+/// `[min, max]` for each sensor, for status. Sensors without a target are `ok` whenever
+/// they have a reading. The defaults suit a chlorinated pool.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TargetRanges {
+    /// °C
+    pub T: Option<[f32; 2]>,
+    pub pH: Option<[f32; 2]>,
+    /// mV
+    pub ORP: Option<[f32; 2]>,
+    /// µS/cm. Defaults to the EC probe's target, if it has one.
+    pub ec: Option<[f32; 2]>,
+    /// mg/L
+    pub DO: Option<[f32; 2]>,
+    /// L/min
+    pub flow: Option<[f32; 2]>,
+}
+
+impl Default for TargetRanges {
+    fn default() -> Self {
+        Self {
+            T: None,
+            pH: Some([7.2, 7.8]),
+            ORP: Some([650., 800.]),
+            ec: None,
+            DO: None,
+            flow: None,
+        }
+    }
+}
+
+impl TargetRanges {
+    /// The range for a sensor, by its id in the registry. Sensors without a setting
+    /// have no target.
+    pub fn get(&self, sensor: &str, probe: CellConstant) -> Option<[f32; 2]> {
+        match sensor {
+            "T" => self.T,
+            "pH" => self.pH,
+            "ORP" => self.ORP,
+            "ec" => self.ec.or_else(|| probe.target()),
+            "DO" => self.DO,
+            "flow" => self.flow,
+            _ => None,
+        }
+    }
+
+    /// A sensor's setting, by its id in the registry; `None` for an unknown sensor.
+    pub fn get_mut(&mut self, sensor: &str) -> Option<&mut Option<[f32; 2]>> {
+        match sensor {
+            "T" => Some(&mut self.T),
+            "pH" => Some(&mut self.pH),
+            "ORP" => Some(&mut self.ORP),
+            "ec" => Some(&mut self.ec),
+            "DO" => Some(&mut self.DO),
+            "flow" => Some(&mut self.flow),
+            _ => None,
+        }
+    }
+}
+
+/// An EC probe's cell constant, K, in 1/cm. The Water Monitor's conductivity assumes
+/// K = 1; other probes read low or high by their K.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum CellConstant {
+    /// For pure water, eg from reverse osmosis.
+    #[serde(rename = "k0.1")]
+    K0_1,
+    /// For fresh water, eg hydroponics.
+    #[serde(rename = "k1")]
+    K1,
+    /// For salt water, eg a salt-chlorinated pool.
+    #[serde(rename = "k10")]
+    K10,
+}
+
+impl CellConstant {
+    /// What to multiply the Water Monitor's conductivity by.
+    pub fn factor(&self) -> f32 {
+        match self {
+            Self::K0_1 => 0.1,
+            Self::K1 => 1.,
+            Self::K10 => 10.,
+        }
+    }
+
+    /// The probe's measuring range, in µS/cm.
+    pub fn plausible(&self) -> [f32; 2] {
+        match self {
+            Self::K0_1 => [0., 2_000.],
+            Self::K1 => [0., 20_000.],
+            Self::K10 => [0., 200_000.],
+        }
+    }
+
+    /// A target for the water the probe suits, in µS/cm. Fresh water varies too much
+    /// for one.
+    pub fn target(&self) -> Option<[f32; 2]> {
+        match self {
+            Self::K0_1 => Some([0., 50.]),
+            Self::K1 => None,
+            Self::K10 => Some([6_000., 8_000.]),
+        }
+    }
+
+    /// The unit to show values in; `display_scale` converts them from µS/cm.
+    pub fn display_unit(&self) -> &'static str {
+        match self {
+            Self::K10 => "mS/cm",
+            _ => "µS/cm",
+        }
+    }
+
+    pub fn display_scale(&self) -> f32 {
+        match self {
+            Self::K10 => 0.001,
+            _ => 1.,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct EcConfig {
+    pub probe: CellConstant,
+}
+
+impl Default for EcConfig {
+    fn default() -> Self {
+        Self {
+            probe: CellConstant::K1,
+        }
+    }
+}
+
+/// The Water Monitor's onboard RGB LED and buzzer, showing the water's status at the
+/// equipment pad. Off by default, since only newer firmware has the command.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct IndicatorConfig {
+    pub enabled: bool,
+    /// Sound the buzzer while the indicator's red.
+    pub buzzer: bool,
+    /// The longest `POST /api/device/indicator` can set it by hand for, before it goes
+    /// back to following alerts.
+    pub max_override_mins: u32,
+}
+
+impl Default for IndicatorConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            buzzer: false,
+            max_override_mins: 24 * 60,
+        }
+    }
+}
+
+/// A pulse-output flow sensor, eg a hall-effect one on the return line, on one of the
+/// Pi's GPIO pins. Needs a build with `--features gpio`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct FlowConfig {
+    pub enabled: bool,
+    /// Its Linux GPIO number.
+    pub pin: u32,
+    /// From the sensor's datasheet, eg 450 for a YF-S201.
+    pub pulses_per_liter: f32,
+    /// The flow rate is averaged over this long.
+    pub window_secs: u32,
+    /// Below this, in L/min, there's no flow, and `gates`' readings don't mean much.
+    pub min_lpm: f32,
+    /// Sensors whose status is `no_flow`, and whose alert conditions aren't met, while
+    /// there's no flow.
+    pub gates: Vec<String>,
+}
+
+impl Default for FlowConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            pin: 0,
+            pulses_per_liter: 450.,
+            window_secs: 10,
+            min_lpm: 1.,
+            gates: vec!["ORP".into(), "pH".into()],
+        }
+    }
+}
+
+/// An external service that vets each cycle's readings before they're used, eg a lab's
+/// QC service. Off unless `url` is set.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ValidationHookConfig {
+    /// Only plain HTTP is supported, eg `http://127.0.0.1:8090/qc`.
+    pub url: Option<String>,
+    /// How long a poll cycle waits for its reply, in ms.
+    pub timeout_ms: u32,
+    /// What happens to readings if it times out or fails.
+    pub on_failure: FailPolicy,
+}
+
+impl Default for ValidationHookConfig {
+    fn default() -> Self {
+        Self {
+            url: None,
+            timeout_ms: 250,
+            on_failure: FailPolicy::Open,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FailPolicy {
+    /// Use the readings as they are, unvetted.
+    Open,
+    /// Reject them all.
+    Closed,
+}
+
+/// Another Water Monitor, running this app, or a sender that pushes its readings.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DeviceConfig {
+    /// How channels refer to it. `local` is this one.
+    pub name: String,
+    /// Its app, eg `http://192.168.1.20`. Only plain HTTP is supported. Unset for a
+    /// device that pushes its readings to `/api/ingest/<name>` instead.
+    #[serde(default)]
+    pub url: Option<String>,
+    /// For a device that pushes its readings, what it sends as its bearer token.
+    #[serde(default)]
+    pub token: Option<String>,
+    /// Seconds between fetches of its readings, or, for one that pushes, between its
+    /// pushes.
+    #[serde(default = "default_device_interval_secs")]
+    pub interval_secs: u32,
+    /// Of devices due to be fetched at once, higher priorities go first.
+    #[serde(default)]
+    pub priority: i32,
+}
+
+fn default_device_interval_secs() -> u32 {
+    5
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MergeMethod {
+    /// The weighted mean of the sources.
+    #[default]
+    Weighted,
+    /// The median of the sources, ignoring weights. Better with 3 or more, since it
+    /// ignores one that reads wrong.
+    Median,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ChannelConfig {
+    pub name: String,
+    /// The sensor it measures, for its unit and target.
+    pub sensor: String,
+    #[serde(default)]
+    pub merge: MergeMethod,
+    pub sources: Vec<ChannelSource>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ChannelSource {
+    /// A name from `devices`, or `local`.
+    pub device: String,
+    /// Its sensor, if not the channel's.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sensor: Option<String>,
+    #[serde(default = "default_weight")]
+    pub weight: f32,
+}
+
+fn default_weight() -> f32 {
+    1.
+}
+
+/// An HTTP endpoint to push readings to, eg Splunk's HEC, or a serverless function.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ExporterConfig {
+    pub name: String,
+    /// Only plain HTTP is supported.
+    pub url: String,
+    /// `POST`, `PUT` or `PATCH`.
+    #[serde(default = "default_method")]
+    pub method: String,
+    #[serde(default = "default_content_type")]
+    pub content_type: String,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Per reading, with placeholders like `{{pH}}` and `{{ts_rfc3339}}`.
+    pub body: String,
+    /// Send the readings taken over this many seconds in one request, rather than each
+    /// as it's taken.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub batch_secs: Option<u32>,
+    /// Around and between readings' bodies, in a batch.
+    #[serde(default)]
+    pub batch_prefix: String,
+    #[serde(default = "default_batch_separator")]
+    pub batch_separator: String,
+    #[serde(default)]
+    pub batch_suffix: String,
+    /// Times to retry a failed request before dropping its readings.
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+    /// How `{{readings}}` keys sensors: their ids, eg `pH`, or `snake_case`, eg `ph`.
+    #[serde(default)]
+    pub key_naming: KeyNaming,
+    /// Sign requests with `signing.secret`.
+    #[serde(default)]
+    pub sign: bool,
+}
+
+fn default_method() -> String {
+    "POST".into()
+}
+
+fn default_content_type() -> String {
+    "application/json".into()
+}
+
+fn default_batch_separator() -> String {
+    "\n".into()
+}
+
+fn default_retries() -> u32 {
+    3
+}
+
+/// A binary input, eg a float switch, on one of the Pi's GPIO pins. Needs a build with
+/// `--features gpio`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct InputConfig {
+    /// As in readings, and alert rules, eg `sump_low`.
+    pub name: String,
+    /// Its Linux GPIO number, eg 17 for BCM 17.
+    pub pin: u32,
+    /// Active while the pin is low, rather than high, eg for a switch to ground.
+    #[serde(default)]
+    pub inverted: bool,
+    /// How long a change must hold before it counts, for a switch that bounces.
+    #[serde(default = "default_debounce_ms")]
+    pub debounce_ms: u32,
+}
+
+fn default_debounce_ms() -> u32 {
+    50
+}
+
+/// Where events are sent, by their category and severity.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct NotifyConfig {
+    /// Channels for events no route matches.
+    pub default: Vec<String>,
+    pub channels: Vec<NotifyChannel>,
+    /// The first that matches an event decides its channels.
+    pub routes: Vec<NotifyRoute>,
+}
+
+/// For signing outbound requests, so receivers can check they came from us.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SigningConfig {
+    /// Shared with receivers. Exporters and notification channels with `sign` set need
+    /// it.
+    pub secret: Option<String>,
+    /// How old a signed request's timestamp can be, in either direction, before
+    /// `/api/notify/verify` calls it a replay. Receivers should use the same window.
+    pub max_age_secs: u32,
+}
+
+impl Default for SigningConfig {
+    fn default() -> Self {
+        Self {
+            secret: None,
+            max_age_secs: 300,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NotifierKind {
+    /// The event as JSON, posted to `url`.
+    #[default]
+    Webhook,
+    /// An ntfy server, at `url`, publishing to `topic`, with `token` if it needs one.
+    Ntfy,
+    /// A Gotify server, at `url`, with an application's `token`.
+    Gotify,
+    /// Pushover, through a relay at `url`, with an application's `token` and a `user` key.
+    Pushover,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct NotifyChannel {
+    pub name: String,
+    #[serde(default)]
+    pub kind: NotifierKind,
+    /// Only plain HTTP is supported.
+    pub url: String,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Sign deliveries with `signing.secret`.
+    #[serde(default)]
+    pub sign: bool,
+    /// Collect events for this many minutes, and send them as one digest. Critical
+    /// events are sent at once regardless.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest_mins: Option<u32>,
+    /// For `ntfy`, `gotify` and `pushover`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    /// For `ntfy`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+    /// For `pushover`: the user or group key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// Where a preset's notifications link to. Default: the first URL the app is
+    /// advertised at.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub click_url: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct NotifyRoute {
+    /// The event's source, eg `watchdog` or `alerts`. Any, if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub category: Option<String>,
+    /// Any, if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub severity: Option<Severity>,
+    /// None, to send matching events nowhere.
+    pub channels: Vec<String>,
+}
+
+/// An alert that fires while its condition holds, eg ORP below 600 while pH is above 7.8.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AlertRule {
+    pub name: String,
+    /// A device that pushes its readings, to test them instead of this one's. Such a
+    /// rule can't test inputs, the score or baselines, which are this device's.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+    /// Only fire between these local times, eg `["22:00", "06:00"]`, which spans
+    /// midnight.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub between: Option<[String; 2]>,
+    /// Notification channels for its events, instead of those `[notify]` routes them to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notify: Option<Vec<String>>,
+    /// Only fire once the condition has held this long. Default: 0.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub for_mins: Option<u32>,
+    /// Send its events at once, even to channels with digests. Default: false.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub critical: Option<bool>,
+    pub when: AlertCondition,
+}
+
+/// One of: `all` or `any` of other conditions; a sensor `below` or `above` a value; a
+/// sensor `rising` or `falling` by at least, or `stable` within, so much per hour, over
+/// the last `window_mins`; a sensor outside its `baseline`, learned from history; or a
+/// binary `input` being `active`, or not.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct AlertCondition {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sensor: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub input: Option<String>,
+    /// For an `input`. Default: true.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub active: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub below: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub above: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rising: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub falling: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stable: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub baseline: Option<AlertBaseline>,
+    /// The unit of a sensor condition's values, and a baseline's `low` and `high`, eg
+    /// `°F`. Default: the sensor's unit in the registry, eg `°C`; the API's is the
+    /// locale's.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
+    /// For trends. Default: 15.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub window_mins: Option<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub all: Vec<AlertCondition>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub any: Vec<AlertCondition>,
+}
+
+/// A sensor's normal band, from the `low_pct` to the `high_pct` percentile of the last
+/// `days` of history, widened on each side by `margin_pct` of its width. It's relearned
+/// nightly; `low` and `high` override its edges.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct AlertBaseline {
+    pub days: u32,
+    pub low_pct: f32,
+    pub high_pct: f32,
+    pub margin_pct: f32,
+    /// Until history covers this many days, the band is still being learned, and only
+    /// `low` and `high` apply.
+    pub min_days: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub low: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub high: Option<f32>,
+}
+
+impl Default for AlertBaseline {
+    fn default() -> Self {
+        Self {
+            days: 14,
+            low_pct: 5.,
+            high_pct: 95.,
+            margin_pct: 10.,
+            min_days: 3,
+            low: None,
+            high: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TimeConfig {
+    /// The IANA name of the local timezone, eg `Europe/London`, for human-facing times.
+    /// Times are stored in UTC regardless.
+    pub timezone: String,
+}
+
+impl Default for TimeConfig {
+    fn default() -> Self {
+        Self {
+            timezone: "UTC".into(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AuthConfig {
+    /// Required as `Authorization: Bearer <token>` on admin routes, like restoring a
+    /// backup, unless logged in with the password. Admin routes are disabled until this
+    /// or the password is set.
+    pub admin_token: Option<String>,
+    /// An argon2 hash of the password for `/api/login`. Set with
+    /// `water-mon-app set-password`.
+    pub password_hash: Option<String>,
+    /// Encrypts and signs session cookies. Set with the password.
+    pub session_key: Option<String>,
+    /// A session ends after this long without a request.
+    pub session_idle_mins: u32,
+    /// Readings, history and stats need admin, or a token with their scope, from
+    /// `/api/tokens`, rather than being open to anyone.
+    pub private: bool,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            admin_token: None,
+            password_hash: None,
+            session_key: None,
+            session_idle_mins: 24 * 60,
+            private: false,
+        }
+    }
+}
+
+impl AppConfig {
+    /// Load config from a TOML file. A missing file gives the default config.
+    pub fn load(path: &Path) -> Result<Self, io::Error> {
+        match fs::read_to_string(path) {
+            Ok(s) => toml::from_str(&s).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Problem parsing `{}`: {}", path.display(), e),
+                )
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Replace one section of the config file, leaving the rest of the file as it is, and
+/// make the result the active config.
+pub fn set_section<T: Serialize>(name: &str, section: &T) -> Result<AppConfig, io::Error> {
+    let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+
+    let text = match fs::read_to_string(CONFIG_PATH) {
+        Ok(t) => t,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+    let mut value: toml::Value = toml::from_str(&text).map_err(|e| invalid(e.to_string()))?;
+    let section = toml::Value::try_from(section).map_err(|e| invalid(e.to_string()))?;
+    if let Some(table) = value.as_table_mut() {
+        table.insert(name.into(), section);
+    }
+
+    let text = toml::to_string(&value).map_err(|e| invalid(e.to_string()))?;
+    let config: AppConfig = toml::from_str(&text).map_err(|e| invalid(e.to_string()))?;
+    fs::write(CONFIG_PATH, text)?;
+    set(config.clone());
+
+    Ok(config)
+}
+
+/// Set the active config.
+pub fn set(config: AppConfig) {
+    *CONFIG.write().unwrap() = Some(config);
+}
+
+/// The active config.
+pub fn get() -> AppConfig {
+    CONFIG.read().unwrap().clone().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plausible_ranges_default_to_the_registry() {
+        let cfg: HistoryConfig = toml::from_str("[plausible]\npH = [4.0, 10.0]").unwrap();
+        let probe = CellConstant::K1;
+        assert_eq!(cfg.plausible.get("pH", probe), [4., 10.]);
+        assert_eq!(cfg.plausible.get("T", probe), [-10., 80.]);
+        assert_eq!(cfg.plausible.get("ec", probe), probe.plausible());
+        assert_eq!(cfg.plausible.get("flow", probe), [0., 1_000.]);
+
+        assert!(toml::from_str::<HistoryConfig>("[plausible]\nsalinity = [0.0, 40.0]").is_err());
+    }
+}
@@ -0,0 +1,109 @@
+//! QR codes of the dashboard's URL, so users can scan one to open the app on their
+//! phone instead of typing an IP address.
+
+use std::io::Cursor;
+
+use qrcode::{render::svg, Color, QrCode};
+use rocket::{
+    http::{ContentType, Status},
+    response::{self, Responder, Response},
+    Request,
+};
+
+use crate::{
+    api::{self, ErrorResponse},
+    config, net, png,
+    proxy::Client,
+};
+
+/// The content only changes if our address does, with a different `?url=`, or via a
+/// proxy, with the host the client connected to.
+const CACHE_CONTROL: &str = "public, max-age=86400";
+
+/// Pixels per QR module in the PNG.
+const PNG_SCALE: usize = 8;
+/// Modules of blank border around the code, as the QR spec requires.
+const QUIET_ZONE: usize = 4;
+
+pub struct QrImage {
+    content_type: ContentType,
+    body: Vec<u8>,
+}
+
+impl<'r> Responder<'r> for QrImage {
+    fn respond_to(self, _req: &Request) -> response::Result<'r> {
+        Response::build()
+            .header(self.content_type)
+            .raw_header("Cache-Control", CACHE_CONTROL)
+            .raw_header("Vary", "X-Forwarded-Host, X-Forwarded-Proto")
+            .sized_body(Cursor::new(self.body))
+            .ok()
+    }
+}
+
+/// Encode `url`, or else the URL the client connected to, via a trusted proxy, or
+/// else our best-guess LAN URL.
+fn qr_code(url: Option<String>, client: Client) -> Result<QrCode, ErrorResponse> {
+    let url = match url.or(client.base_url) {
+        Some(u) => u,
+        None => net::advertised_urls(&config::get().server)
+            .into_iter()
+            .next()
+            .ok_or_else(|| {
+                api::error(
+                    Status::ServiceUnavailable,
+                    "Problem finding this computer's network address; pass `?url=`",
+                )
+            })?,
+    };
+
+    QrCode::new(url.as_bytes()).map_err(|e| {
+        api::error(
+            Status::BadRequest,
+            &format!("Problem encoding the URL: {}", e),
+        )
+    })
+}
+
+#[get("/connect/qr.svg?<url>")]
+pub fn view_qr_svg(url: Option<String>, client: Client) -> Result<QrImage, ErrorResponse> {
+    let code = qr_code(url, client)?;
+    let svg = code
+        .render::<svg::Color>()
+        .min_dimensions(256, 256)
+        .dark_color(svg::Color("#000000"))
+        .light_color(svg::Color("#ffffff"))
+        .build();
+
+    Ok(QrImage {
+        content_type: ContentType::SVG,
+        body: svg.into_bytes(),
+    })
+}
+
+#[get("/connect/qr.png?<url>")]
+pub fn view_qr_png(url: Option<String>, client: Client) -> Result<QrImage, ErrorResponse> {
+    let code = qr_code(url, client)?;
+    let colors = code.to_colors();
+    let modules = code.width();
+    let size = (modules + 2 * QUIET_ZONE) * PNG_SCALE;
+
+    let mut pixels = vec![0xff; size * size];
+    for (i, color) in colors.iter().enumerate() {
+        if *color != Color::Dark {
+            continue;
+        }
+        let x0 = (i % modules + QUIET_ZONE) * PNG_SCALE;
+        let y0 = (i / modules + QUIET_ZONE) * PNG_SCALE;
+        for y in y0..y0 + PNG_SCALE {
+            for px in &mut pixels[y * size + x0..y * size + x0 + PNG_SCALE] {
+                *px = 0;
+            }
+        }
+    }
+
+    Ok(QrImage {
+        content_type: ContentType::PNG,
+        body: png::encode_gray(size as u32, size as u32, &pixels),
+    })
+}
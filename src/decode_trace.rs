@@ -0,0 +1,414 @@
+//! `water-mon-app decode-trace FILE`: replaying captured serial exchanges offline, through
+//! the decoder the poller uses, so a decode bug a user sees can be reproduced from the
+//! file they send. It takes the trace from `POST /api/readings/refresh?debug=true`, the
+//! whole response or its `trace`, or `serial.json` from a support bundle, and prints, for
+//! each exchange, the message type, whether the response was complete, each sensor's
+//! value or error, and a summary. Readings responses have no CRC, so there's no check to
+//! report. `load` and `decode` read and replay such a file for the tests' fixture, or
+//! anything else that needs real frames.
+
+use std::{fs, path::Path};
+
+use serde_json::Value;
+
+use crate::{
+    registry::REGISTRY, serial_stats, Readings, SensorError, EXTENDED_READINGS_REQUEST,
+    EXTENDED_READINGS_SIZE, INDICATOR_REQUEST, OK_BIT, READINGS_REQUEST, READINGS_SIZE,
+};
+
+/// A request and its response, as captured.
+pub struct Captured {
+    pub request: Vec<u8>,
+    pub response: Vec<u8>,
+    /// If the capture cut the response short, as traces do past 256 bytes.
+    pub truncated: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MessageType {
+    Readings,
+    ExtendedReadings,
+    Indicator,
+    Unknown,
+}
+
+/// How a sensor's slot decoded.
+pub struct Slot {
+    pub sensor: &'static str,
+    pub offset: usize,
+    pub status: u8,
+    pub value: Result<f32, SensorError>,
+    /// If a value is within the registry's plausible range.
+    pub plausible: bool,
+}
+
+/// How an exchange decodes.
+pub struct Decoded {
+    pub message_type: MessageType,
+    /// The response's expected length, for readings and indicator requests.
+    pub expected_len: Option<usize>,
+    /// If the response was as long as expected, so the poller would've used it.
+    pub complete: bool,
+    /// Each sensor's slot, for a complete readings response.
+    pub slots: Vec<Slot>,
+    /// Why it wouldn't be used, if it wouldn't.
+    pub error: Option<String>,
+}
+
+fn parse_hex(hex: &str) -> Result<Vec<u8>, String> {
+    hex.split_whitespace()
+        .map(|b| u8::from_str_radix(b, 16).map_err(|_| format!("`{}` isn't a hex byte", b)))
+        .collect()
+}
+
+/// Read captured exchanges from a trace or support bundle's JSON.
+pub fn load(json: &str) -> Result<Vec<Captured>, String> {
+    let value: Value = serde_json::from_str(json).map_err(|e| format!("Not JSON: {}", e))?;
+    let exchanges = [
+        &value["trace"]["transactions"],
+        &value["transactions"],
+        &value["frames"],
+        &value,
+    ]
+    .into_iter()
+    .find_map(Value::as_array)
+    .ok_or("No exchanges found: expected a trace's `transactions`, or `frames`")?;
+
+    exchanges
+        .iter()
+        .enumerate()
+        .map(|(i, e)| {
+            let field = |name| {
+                let hex = e[name]
+                    .as_str()
+                    .ok_or_else(|| format!("Exchange {} has no `{}`", i + 1, name))?;
+                parse_hex(hex).map_err(|err| format!("Exchange {}: {}", i + 1, err))
+            };
+            Ok(Captured {
+                request: field("request")?,
+                response: field("response")?,
+                truncated: e["truncated"].as_bool().unwrap_or(false),
+            })
+        })
+        .collect()
+}
+
+/// Read captured exchanges from a file.
+pub fn load_file(path: &Path) -> Result<Vec<Captured>, String> {
+    let json = fs::read_to_string(path)
+        .map_err(|e| format!("Problem reading `{}`: {}", path.display(), e))?;
+    load(&json).map_err(|e| format!("`{}`: {}", path.display(), e))
+}
+
+/// Decode an exchange as the poller would.
+pub fn decode(captured: &Captured) -> Decoded {
+    let request = captured.request.as_slice();
+    let (message_type, expected_len) = if request == READINGS_REQUEST {
+        (MessageType::Readings, Some(READINGS_SIZE))
+    } else if request == EXTENDED_READINGS_REQUEST {
+        (MessageType::ExtendedReadings, Some(EXTENDED_READINGS_SIZE))
+    } else if request.len() == 4 && request[..3] == INDICATOR_REQUEST {
+        (MessageType::Indicator, Some(1))
+    } else {
+        (MessageType::Unknown, None)
+    };
+    let len = captured.response.len();
+
+    let mut decoded = Decoded {
+        message_type,
+        expected_len,
+        complete: expected_len.is_some_and(|n| len >= n),
+        slots: Vec::new(),
+        error: None,
+    };
+    match (message_type, expected_len) {
+        (MessageType::Unknown, _) => {
+            decoded.error = Some("Not a request the app sends".into());
+        }
+        (_, Some(n)) if len < n && captured.truncated => {
+            decoded.error = Some(format!("Cut short by the capture, at {} bytes", len));
+        }
+        (_, Some(n)) if len < n => {
+            decoded.error = Some(format!("Incomplete response: {} of {} bytes", len, n));
+        }
+        (MessageType::Indicator, _) => {
+            if captured.response[0] != request[3] {
+                decoded.error = Some(format!(
+                    "Not echoed: sent {:02x}, got {:02x}, so taken as firmware without an \
+                     indicator",
+                    request[3], captured.response[0]
+                ));
+            }
+        }
+        (_, Some(n)) => {
+            let response = &captured.response[..n];
+            let readings = Readings::from_bytes(response);
+            decoded.slots = REGISTRY
+                .iter()
+                .filter_map(|sensor| {
+                    let offset = sensor.offset?;
+                    let value = readings.get(sensor.id)?;
+                    let [min, max] = sensor.plausible;
+                    Some(Slot {
+                        sensor: sensor.id,
+                        offset,
+                        status: response.get(offset).copied().unwrap_or(0),
+                        value,
+                        plausible: value.is_ok_and(|v| v >= min && v <= max),
+                    })
+                })
+                .collect();
+            if len > n {
+                decoded.error = Some(format!(
+                    "{} bytes more than expected, which would offset the next response",
+                    len - n
+                ));
+            }
+        }
+        (_, None) => (),
+    }
+    decoded
+}
+
+fn describe(slot: &Slot) -> String {
+    match slot.value {
+        Ok(v) if slot.plausible => format!("{}", v),
+        Ok(v) => format!("{} (implausible)", v),
+        Err(e) => format!("{} (status byte {})", e, slot.status),
+    }
+}
+
+/// What a trace's exchanges add up to.
+#[derive(Debug, Default, PartialEq)]
+pub struct Summary {
+    pub exchanges: usize,
+    /// Complete responses to requests the app sends.
+    pub complete: usize,
+    /// Slots with a status other than OK.
+    pub sensor_errors: usize,
+    /// OK slots with values outside the registry's plausible range.
+    pub implausible: usize,
+}
+
+impl Summary {
+    pub fn add(&mut self, decoded: &Decoded) {
+        self.exchanges += 1;
+        if decoded.complete && decoded.message_type != MessageType::Unknown {
+            self.complete += 1;
+        }
+        for slot in decoded.slots.iter() {
+            if slot.status != OK_BIT {
+                self.sensor_errors += 1;
+            } else if !slot.plausible {
+                self.implausible += 1;
+            }
+        }
+    }
+}
+
+/// Print how each exchange in a trace file decodes, and a summary.
+pub fn run(path: &Path) -> Result<(), String> {
+    let captured = load_file(path)?;
+    let mut summary = Summary::default();
+    for (i, c) in captured.iter().enumerate() {
+        let decoded = decode(c);
+        println!("Exchange {}: {:?}", i + 1, decoded.message_type);
+        println!("  Request:  {}", serial_stats::hex(&c.request));
+        println!("  Response: {}", serial_stats::hex(&c.response));
+        match decoded.expected_len {
+            Some(n) => println!("  Length:   {} of {} bytes", c.response.len(), n),
+            None => println!("  Length:   {} bytes", c.response.len()),
+        }
+        println!("  CRC:      none; readings responses don't have one");
+        for slot in decoded.slots.iter() {
+            println!(
+                "  {:<5} at {:>2}: {}",
+                slot.sensor,
+                slot.offset,
+                describe(slot)
+            );
+        }
+        if let Some(error) = &decoded.error {
+            println!("  Problem:  {}", error);
+        }
+        summary.add(&decoded);
+    }
+
+    println!();
+    println!(
+        "{} exchanges: {} complete, {} incomplete or unknown. {} sensor errors; {} \
+         implausible values.",
+        summary.exchanges,
+        summary.complete,
+        summary.exchanges - summary.complete,
+        summary.sensor_errors,
+        summary.implausible
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A traced cycle from a Water Monitor with dissolved oxygen: a complete response, one
+    /// cut short, and one with a probe disconnected and a status from newer firmware.
+    const FIXTURE: &str = r#"{"trace": {"transactions": [
+        {"attempt": 1, "request": "64 96 c9",
+         "response": "01 41 bc 00 00 01 40 e8 00 00 01 43 e1 00 00 01 3f 80 00 00 01 41 00 00 00",
+         "outcome": "Ok"},
+        {"attempt": 2, "request": "64 96 c9",
+         "response": "01 41 bc 00 00 01 40 e8 00 00 01 43 e1",
+         "outcome": "Timeout"},
+        {"attempt": 3, "request": "64 96 c9",
+         "response": "01 41 bc 00 00 01 40 e8 00 00 02 00 00 00 00 01 3f 80 00 00 09 00 00 00 00",
+         "outcome": "Ok"}
+    ]}}"#;
+
+    /// Replay `FIXTURE`, and check it decodes as captured.
+    #[test]
+    fn the_fixture_decodes_as_captured() {
+        let captured = load(FIXTURE).unwrap();
+        let decoded: Vec<_> = captured.iter().map(decode).collect();
+        let value =
+            |d: &Decoded, sensor| d.slots.iter().find(|s| s.sensor == sensor).map(|s| s.value);
+
+        let expect = |ok: bool, what: &str| assert!(ok, "The trace fixture's {}", what);
+        expect(decoded.len() == 3, "exchanges didn't all load");
+        if let [first, second, third] = &decoded[..] {
+            expect(
+                first.message_type == MessageType::ExtendedReadings && first.complete,
+                "first exchange wasn't a complete extended readings response",
+            );
+            expect(
+                value(first, "T") == Some(Ok(23.5)) && value(first, "DO") == Some(Ok(8.)),
+                "first exchange didn't decode T as 23.5 and DO as 8",
+            );
+            expect(
+                !second.complete && second.slots.is_empty() && second.error.is_some(),
+                "second exchange, cut short, wasn't taken as incomplete",
+            );
+            expect(
+                value(third, "ORP") == Some(Err(SensorError::ProbeDisconnected))
+                    && value(third, "DO") == Some(Err(SensorError::Unknown(9))),
+                "third exchange didn't decode ORP's and DO's errors",
+            );
+        }
+    }
+
+    fn exchange(request: &str, response: &str, truncated: bool) -> Captured {
+        Captured {
+            request: parse_hex(request).unwrap(),
+            response: parse_hex(response).unwrap(),
+            truncated,
+        }
+    }
+
+    #[test]
+    fn requests_are_told_apart() {
+        let readings = decode(&exchange(
+            "64 96 c8",
+            "01 41 bc 00 00 01 40 e8 00 00 01 43 e1 00 00 01 3f 80 00 00",
+            false,
+        ));
+        assert_eq!(readings.message_type, MessageType::Readings);
+        assert_eq!(readings.expected_len, Some(READINGS_SIZE));
+        assert!(readings.complete && readings.error.is_none());
+        assert_eq!(readings.slots.len(), 4);
+
+        let echoed = decode(&exchange("64 96 d2 02", "02", false));
+        assert_eq!(echoed.message_type, MessageType::Indicator);
+        assert!(echoed.complete && echoed.error.is_none());
+        let old_firmware = decode(&exchange("64 96 d2 02", "00", false));
+        assert!(old_firmware.error.unwrap().starts_with("Not echoed"));
+
+        let unknown = decode(&exchange("01 02 03", "01 02 03", false));
+        assert_eq!(unknown.message_type, MessageType::Unknown);
+        assert_eq!(unknown.expected_len, None);
+        assert!(!unknown.complete && unknown.slots.is_empty());
+        assert_eq!(
+            unknown.error.as_deref(),
+            Some("Not a request the app sends")
+        );
+    }
+
+    /// Responses the poller wouldn't use say why.
+    #[test]
+    fn bad_lengths_are_explained() {
+        let error = |response, truncated| decode(&exchange("64 96 c8", response, truncated)).error;
+        assert_eq!(
+            error("01 41 bc", false).as_deref(),
+            Some("Incomplete response: 3 of 20 bytes")
+        );
+        assert_eq!(
+            error("01 41 bc", true).as_deref(),
+            Some("Cut short by the capture, at 3 bytes")
+        );
+
+        let overlong = decode(&exchange(
+            "64 96 c8",
+            "01 41 bc 00 00 01 40 e8 00 00 01 43 e1 00 00 01 3f 80 00 00 01 41",
+            false,
+        ));
+        assert!(overlong.complete);
+        assert_eq!(overlong.slots.len(), 4);
+        assert_eq!(
+            overlong.error.as_deref(),
+            Some("2 bytes more than expected, which would offset the next response")
+        );
+    }
+
+    #[test]
+    fn traces_load_from_each_format() {
+        let exchanges = r#"[{"request": "64 96 c8", "response": "01 41", "truncated": true}]"#;
+        for json in [
+            exchanges.to_owned(),
+            format!(r#"{{"transactions": {}}}"#, exchanges),
+            format!(r#"{{"frames": {}}}"#, exchanges),
+        ] {
+            let captured = load(&json).unwrap();
+            assert_eq!(captured.len(), 1, "{}", json);
+            assert_eq!(captured[0].request, READINGS_REQUEST);
+            assert_eq!(captured[0].response, [0x01, 0x41]);
+            assert!(captured[0].truncated);
+        }
+
+        assert!(load("{").err().unwrap().starts_with("Not JSON"));
+        assert!(load("{}").err().unwrap().starts_with("No exchanges found"));
+        assert_eq!(
+            load(r#"[{"request": "64 96 c8"}]"#).err().as_deref(),
+            Some("Exchange 1 has no `response`")
+        );
+        assert_eq!(
+            load(r#"[{"request": "64 96 c8", "response": "01"}, {"request": "64 zz", "response": ""}]"#)
+                .err()
+                .as_deref(),
+            Some("Exchange 2: `zz` isn't a hex byte")
+        );
+    }
+
+    #[test]
+    fn the_summary_counts_problems() {
+        let mut summary = Summary::default();
+        for captured in load(FIXTURE).unwrap().iter() {
+            summary.add(&decode(captured));
+        }
+        // T at 200 °C.
+        summary.add(&decode(&exchange(
+            "64 96 c8",
+            "01 43 48 00 00 01 40 e8 00 00 01 43 e1 00 00 01 3f 80 00 00",
+            false,
+        )));
+        summary.add(&decode(&exchange("01 02 03", "", false)));
+
+        assert_eq!(
+            summary,
+            Summary {
+                exchanges: 5,
+                complete: 3,
+                sensor_errors: 2,
+                implausible: 1,
+            }
+        );
+    }
+}
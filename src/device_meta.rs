@@ -0,0 +1,218 @@
+//! Each device's label, location, color and sort order, for a UI showing several:
+//! `PUT /api/devices/<name>/meta` sets them, for `local`, this one, or a `[[devices]]`
+//! entry, and they're kept in `device-meta.json`, in the working directory. They're in
+//! `/api/devices`, history responses, StatsD tags with `metrics.tags`, and notifications,
+//! whose titles use this device's label, eg "Reef Tank: pH high". A device removed from
+//! the config keeps its metadata, archived, at `/api/devices/archived`, until
+//! `DELETE /api/devices/<name>` purges it. Other devices' readings aren't kept in
+//! history, so metadata is all there is to archive.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, Read},
+    sync::Mutex,
+};
+
+use rocket::{http::Status, response::content, Data};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::{self, ErrorResponse},
+    auth::Admin,
+    config::{self, AppConfig},
+    events::{self, Severity},
+};
+
+const PATH: &str = "device-meta.json";
+
+/// This device's name.
+pub const LOCAL: &str = "local";
+
+const MAX_LABEL_LEN: usize = 64;
+
+const MAX_LOCATION_LEN: usize = 128;
+
+/// By device name; `None` until read from `PATH`.
+static META: Mutex<Option<BTreeMap<String, DeviceMeta>>> = Mutex::new(None);
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct DeviceMeta {
+    /// Eg `Reef Tank`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// Eg `Garage, left rack`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+    /// `#rrggbb`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub color: Option<String>,
+    /// Lower first, then as configured.
+    #[serde(default)]
+    pub sort_order: i32,
+}
+
+#[derive(Serialize)]
+struct Archived {
+    name: String,
+    #[serde(flatten)]
+    meta: DeviceMeta,
+}
+
+fn load() -> BTreeMap<String, DeviceMeta> {
+    let meta = match fs::read_to_string(PATH) {
+        Ok(s) => serde_json::from_str(&s).map_err(|e| e.to_string()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e.to_string()),
+    };
+    meta.unwrap_or_else(|e| {
+        events::record(
+            Severity::Warning,
+            "devices",
+            format!("Problem reading device metadata from `{}`: {}", PATH, e),
+        );
+        BTreeMap::new()
+    })
+}
+
+fn save(meta: &BTreeMap<String, DeviceMeta>) -> Result<(), io::Error> {
+    let tmp = format!("{}.tmp", PATH);
+    fs::write(&tmp, serde_json::to_string(meta).unwrap())?;
+    fs::rename(&tmp, PATH)
+}
+
+fn with_meta<T>(f: impl FnOnce(&mut BTreeMap<String, DeviceMeta>) -> T) -> T {
+    let mut meta = META.lock().unwrap();
+    f(meta.get_or_insert_with(load))
+}
+
+/// A device's metadata; the defaults if it has none.
+pub fn get(name: &str) -> DeviceMeta {
+    with_meta(|meta| meta.get(name).cloned().unwrap_or_default())
+}
+
+/// A device's label, if it has one.
+pub fn label(name: &str) -> Option<String> {
+    get(name).label
+}
+
+/// If `name` is this device, or one in `[[devices]]`.
+fn configured(cfg: &AppConfig, name: &str) -> bool {
+    name == LOCAL || cfg.devices.iter().any(|d| d.name == name)
+}
+
+fn validate(meta: &DeviceMeta) -> Result<(), String> {
+    let text = [
+        ("label", &meta.label, MAX_LABEL_LEN),
+        ("location", &meta.location, MAX_LOCATION_LEN),
+    ];
+    for (field, value, max) in text {
+        if let Some(value) = value {
+            if value.trim().is_empty() || value.chars().count() > max {
+                return Err(format!("`{}` must be 1 to {} characters", field, max));
+            }
+            if value.chars().any(char::is_control) {
+                return Err(format!("`{}` can't have control characters", field));
+            }
+        }
+    }
+    if let Some(color) = &meta.color {
+        let hex = color.strip_prefix('#').unwrap_or_default();
+        if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("`color` must be like `#1e90ff`; got `{}`", color));
+        }
+    }
+    Ok(())
+}
+
+/// Set a device's label, location, color and sort order, eg
+/// `{"label": "Reef Tank", "location": "Living room", "color": "#1e90ff", "sort_order": 1}`.
+/// Fields left out are cleared.
+#[put("/devices/<name>/meta", data = "<data>")]
+pub fn set_meta(
+    _admin: Admin,
+    name: String,
+    data: Data,
+) -> Result<content::Json<String>, ErrorResponse> {
+    if !configured(&config::get(), &name) {
+        return Err(api::error(
+            Status::NotFound,
+            &format!("No device named `{}`; `local` is this one", name),
+        ));
+    }
+    let mut body = String::new();
+    data.open()
+        .take(16 * 1_024)
+        .read_to_string(&mut body)
+        .map_err(|e| api::error(Status::BadRequest, &e.to_string()))?;
+    let mut meta: DeviceMeta = serde_json::from_str(&body)
+        .map_err(|e| api::error(Status::BadRequest, &format!("Invalid metadata: {}", e)))?;
+    validate(&meta).map_err(|e| api::error(Status::BadRequest, &e))?;
+    meta.color = meta.color.map(|c| c.to_ascii_lowercase());
+
+    with_meta(|all| {
+        let previous = all.insert(name.clone(), meta.clone());
+        save(all).map_err(|e| {
+            match previous {
+                Some(p) => all.insert(name.clone(), p),
+                None => all.remove(&name),
+            };
+            api::error(
+                Status::InternalServerError,
+                &format!("Problem saving device metadata to `{}`: {}", PATH, e),
+            )
+        })
+    })?;
+    Ok(content::Json(serde_json::to_string(&meta).unwrap()))
+}
+
+/// Metadata of devices no longer in the config.
+#[get("/devices/archived")]
+pub fn view_archived() -> content::Json<String> {
+    let cfg = config::get();
+    let archived: Vec<_> = with_meta(|meta| {
+        meta.iter()
+            .filter(|(name, _)| !configured(&cfg, name))
+            .map(|(name, meta)| Archived {
+                name: name.clone(),
+                meta: meta.clone(),
+            })
+            .collect()
+    });
+    content::Json(serde_json::to_string(&archived).unwrap())
+}
+
+/// Purge an archived device's metadata. A device still in the config can't be purged.
+#[delete("/devices/<name>")]
+pub fn purge(_admin: Admin, name: String) -> Result<content::Json<String>, ErrorResponse> {
+    if configured(&config::get(), &name) {
+        return Err(api::error(
+            Status::Conflict,
+            &format!(
+                "`{}` is still configured; remove it from the config first",
+                name
+            ),
+        ));
+    }
+    with_meta(|meta| {
+        let removed = meta.remove(&name).ok_or_else(|| {
+            api::error(
+                Status::NotFound,
+                &format!("No archived device named `{}`", name),
+            )
+        })?;
+        save(meta).map_err(|e| {
+            meta.insert(name.clone(), removed);
+            api::error(
+                Status::InternalServerError,
+                &format!("Problem saving device metadata to `{}`: {}", PATH, e),
+            )
+        })
+    })?;
+    events::record(
+        Severity::Info,
+        "devices",
+        format!("Purged the archived device `{}`.", name),
+    );
+    Ok(view_archived())
+}
@@ -0,0 +1,207 @@
+//! A sensor's distribution over a window of history: a histogram, and percentiles, eg
+//! for picking alert thresholds. Compacted rows count as their average, weighted by
+//! how many samples went into it, so over ranges older than `keep_raw_hours`, the
+//! distribution is of 1-minute or 1-hour averages.
+
+use chrono::Utc;
+use rocket::{http::Status, response::content};
+use rusqlite::params;
+use serde::Serialize;
+
+use crate::{
+    api::{self, ErrorResponse},
+    config,
+    history::{self, AGGREGATE_TIERS},
+    registry,
+    tokens::Viewer,
+    tz,
+};
+
+const DEFAULT_HOURS: u32 = 24;
+const MAX_HOURS: u32 = 366 * 24;
+
+const DEFAULT_BINS: u32 = 20;
+const MAX_BINS: u32 = 1_000;
+
+const PERCENTILES: [(&str, f64); 5] = [
+    ("p5", 0.05),
+    ("p25", 0.25),
+    ("p50", 0.5),
+    ("p75", 0.75),
+    ("p95", 0.95),
+];
+
+#[derive(Serialize)]
+struct Distribution {
+    sensor: &'static str,
+    unit: &'static str,
+    from: String,
+    to: String,
+    /// Valid samples.
+    count: u64,
+    /// Samples without a valid reading, or taken during maintenance or while stabilizing.
+    excluded: u64,
+    /// `bins + 1` edges; bin `i` is from `edges[i]`, up to `edges[i + 1]`, which the last
+    /// bin includes.
+    edges: Vec<f64>,
+    counts: Vec<u64>,
+    /// Samples below the first edge, and above the last.
+    below: u64,
+    above: u64,
+    /// `None` without samples.
+    percentiles: Option<serde_json::Map<String, serde_json::Value>>,
+}
+
+/// A query for every valid value of `sensor` from `?1` up to `?2`, as `v`, with how
+/// many samples it stands for, as `w`. Samples taken during maintenance, or while it
+/// was stabilizing, are left out.
+pub fn weighted_values(sensor: &str) -> String {
+    let mut values = vec![format!(
+        "SELECT {0} AS v, 1 AS w FROM samples \
+         WHERE time >= ?1 AND time < ?2 AND {0} IS NOT NULL AND NOT maintenance AND {1}",
+        sensor,
+        history::settled(sensor)
+    )];
+    for (table, _) in AGGREGATE_TIERS.iter() {
+        values.push(format!(
+            "SELECT {0}_avg, {0}_n FROM {1} WHERE time >= ?1 AND time < ?2 AND {0}_n > 0",
+            sensor, table
+        ));
+    }
+    values.join(" UNION ALL ")
+}
+
+/// The distribution of `sensor` over the last `hours`, with `bins` equal bins from `min`
+/// to `max`, defaulting to the sensor's plausible range.
+#[get("/distribution?<sensor>&<hours>&<bins>&<min>&<max>")]
+pub fn view_distribution(
+    _viewer: Viewer,
+    sensor: String,
+    hours: Option<u32>,
+    bins: Option<u32>,
+    min: Option<f64>,
+    max: Option<f64>,
+) -> Result<content::Json<String>, ErrorResponse> {
+    // Case-insensitive, so `ph` works in a hand-typed URL.
+    let def = registry::REGISTRY
+        .iter()
+        .find(|s| s.id.eq_ignore_ascii_case(&sensor))
+        .ok_or_else(|| {
+            let ids: Vec<_> = registry::ids().collect();
+            api::error(
+                Status::BadRequest,
+                &format!(
+                    "`sensor` must be one of {}; got `{}`",
+                    ids.join(", "),
+                    sensor
+                ),
+            )
+        })?;
+    let hours = hours.unwrap_or(DEFAULT_HOURS);
+    if hours == 0 || hours > MAX_HOURS {
+        return Err(api::error(
+            Status::BadRequest,
+            &format!("`hours` must be from 1 to {}", MAX_HOURS),
+        ));
+    }
+    let bins = bins.unwrap_or(DEFAULT_BINS);
+    if bins == 0 || bins > MAX_BINS {
+        return Err(api::error(
+            Status::BadRequest,
+            &format!("`bins` must be from 1 to {}", MAX_BINS),
+        ));
+    }
+
+    let cfg = config::get();
+    let [plausible_min, plausible_max] = cfg.history.plausible.get(def.id, cfg.ec.probe);
+    let (min, max) = (
+        min.unwrap_or(plausible_min as f64),
+        max.unwrap_or(plausible_max as f64),
+    );
+    if !min.is_finite() || !max.is_finite() || min >= max {
+        return Err(api::error(Status::BadRequest, "`min` must be below `max`"));
+    }
+
+    let to_ms = Utc::now().timestamp_millis();
+    let from_ms = to_ms - hours as i64 * 3_600_000;
+    let conn = history::open_reader()?;
+    let name = def.id;
+
+    let values = weighted_values(name);
+    let mut excluded = vec![format!(
+        "SELECT COUNT(*) - COUNT(CASE WHEN NOT maintenance AND {1} THEN {0} END) AS e \
+         FROM samples WHERE time >= ?1 AND time < ?2",
+        name,
+        history::settled(name)
+    )];
+    for (table, _) in AGGREGATE_TIERS.iter() {
+        excluded.push(format!(
+            "SELECT SUM(count - {0}_n) FROM {1} WHERE time >= ?1 AND time < ?2",
+            name, table
+        ));
+    }
+
+    let (count, excluded): (i64, i64) = conn
+        .query_row(
+            &format!(
+                "SELECT (SELECT COALESCE(SUM(w), 0) FROM ({})), \
+                 (SELECT COALESCE(SUM(e), 0) FROM ({}))",
+                values,
+                excluded.join(" UNION ALL ")
+            ),
+            params![from_ms, to_ms],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .map_err(history::query_error)?;
+
+    // One pass over the values in order, filling bins and finding percentiles.
+    let width = (max - min) / bins as f64;
+    let mut counts = vec![0; bins as usize];
+    let (mut below, mut above) = (0, 0);
+    let mut percentiles = serde_json::Map::new();
+    let mut next = 0;
+    let mut seen = 0;
+
+    let mut stmt = conn
+        .prepare(&format!("SELECT v, w FROM ({}) ORDER BY v", values))
+        .map_err(history::query_error)?;
+    let mut rows = stmt
+        .query(params![from_ms, to_ms])
+        .map_err(history::query_error)?;
+    while let Some(row) = rows.next().map_err(history::query_error)? {
+        let v: f64 = row.get(0).map_err(history::query_error)?;
+        let w: i64 = row.get(1).map_err(history::query_error)?;
+
+        if v < min {
+            below += w as u64;
+        } else if v > max {
+            above += w as u64;
+        } else {
+            let bin = (((v - min) / width) as usize).min(bins as usize - 1);
+            counts[bin] += w as u64;
+        }
+
+        seen += w;
+        while next < PERCENTILES.len() && seen as f64 >= PERCENTILES[next].1 * count as f64 {
+            percentiles.insert(PERCENTILES[next].0.into(), v.into());
+            next += 1;
+        }
+    }
+
+    let zone = tz::configured();
+    let result = Distribution {
+        sensor: name,
+        unit: def.unit,
+        from: tz::format(from_ms, zone),
+        to: tz::format(to_ms, zone),
+        count: count as u64,
+        excluded: excluded as u64,
+        edges: (0..=bins).map(|i| min + i as f64 * width).collect(),
+        counts,
+        below,
+        above,
+        percentiles: if count > 0 { Some(percentiles) } else { None },
+    };
+
+    Ok(content::Json(serde_json::to_string(&result).unwrap()))
+}
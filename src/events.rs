@@ -0,0 +1,186 @@
+//! A log of notable events, eg the device disconnecting, kept in memory and printed
+//! to the console.
+
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+use rocket::response::content;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    memory::{Buffer, Policy},
+    notify, request_id,
+    tokens::Viewer,
+};
+
+/// How many events we keep; older ones are dropped first.
+const MAX_EVENTS: usize = 1_000;
+
+pub const BUFFER: Buffer = Buffer {
+    name: "events",
+    cap: MAX_EVENTS,
+    policy: Policy::DropOldest,
+    about: "Recent events, for `/api/events`",
+};
+
+static EVENTS: Mutex<VecDeque<Event>> = Mutex::new(VecDeque::new());
+
+/// Where to also write events, eg when running as a service without a console.
+static LOG_FILE: Mutex<Option<File>> = Mutex::new(None);
+
+/// Print events as single lines with syslog priority prefixes, which journald parses.
+static JOURNALD_FORMAT: AtomicBool = AtomicBool::new(false);
+
+/// In increasing order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+pub enum Severity {
+    #[serde(alias = "info")]
+    Info,
+    #[serde(alias = "warning")]
+    Warning,
+    /// Something that needs the user's attention.
+    #[serde(alias = "alert")]
+    Alert,
+}
+
+impl Severity {
+    /// The syslog priority journald should log this with.
+    fn syslog_priority(&self) -> u8 {
+        match self {
+            Self::Info => 6,
+            Self::Warning => 4,
+            Self::Alert => 1,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Event {
+    /// RFC 3339, UTC.
+    pub time: String,
+    pub severity: Severity,
+    /// The part of the app that raised this, eg "poller".
+    pub source: &'static str,
+    pub message: String,
+    /// The alert rule that raised this, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rule: Option<String>,
+    /// The API request being handled when this was raised, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+pub fn set_journald_format(enabled: bool) {
+    JOURNALD_FORMAT.store(enabled, Ordering::Relaxed);
+}
+
+/// Append events to a file, in addition to printing them.
+pub fn set_log_file(path: &Path) -> Result<(), io::Error> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    *LOG_FILE.lock().unwrap() = Some(file);
+    Ok(())
+}
+
+/// Record an event, print it, and send it to its notification channels.
+pub fn record(severity: Severity, source: &'static str, message: impl Into<String>) {
+    push(Event {
+        time: chrono::Utc::now().to_rfc3339(),
+        severity,
+        source,
+        message: message.into(),
+        rule: None,
+        request_id: request_id::current(),
+    });
+}
+
+/// Record an event raised by an alert rule, whose `notify` overrides the routes.
+pub fn record_rule(severity: Severity, rule: &str, message: impl Into<String>) {
+    push(Event {
+        time: chrono::Utc::now().to_rfc3339(),
+        severity,
+        source: "alerts",
+        message: message.into(),
+        rule: Some(rule.into()),
+        request_id: request_id::current(),
+    });
+}
+
+fn push(event: Event) {
+    let request = event
+        .request_id
+        .as_ref()
+        .map(|id| format!(" (request {})", id))
+        .unwrap_or_default();
+    if JOURNALD_FORMAT.load(Ordering::Relaxed) {
+        println!(
+            "<{}>{}: {}{}",
+            event.severity.syslog_priority(),
+            event.source,
+            event.message.replace('\n', " \\n "),
+            request
+        );
+    } else {
+        println!(
+            "[{:?}] {}: {}{}",
+            event.severity, event.source, event.message, request
+        );
+    }
+
+    if let Some(file) = LOG_FILE.lock().unwrap().as_mut() {
+        let _ = writeln!(
+            file,
+            "{} [{:?}] {}: {}{}",
+            event.time, event.severity, event.source, event.message, request
+        );
+    }
+
+    notify::queue(&event);
+
+    let mut events = EVENTS.lock().unwrap();
+    if events.len() == MAX_EVENTS {
+        events.pop_front();
+        BUFFER.dropped(1);
+    }
+    events.push_back(event);
+    BUFFER.note(events.len());
+}
+
+/// The most recent events, newest first.
+pub fn recent(limit: usize) -> Vec<Event> {
+    EVENTS
+        .lock()
+        .unwrap()
+        .iter()
+        .rev()
+        .take(limit)
+        .cloned()
+        .collect()
+}
+
+/// The most recent events, newest first; only those raised handling `request_id`, if
+/// given.
+#[get("/events?<limit>&<request_id>")]
+pub fn view_events(
+    _viewer: Viewer,
+    limit: Option<usize>,
+    request_id: Option<String>,
+) -> content::Json<String> {
+    let events: Vec<_> = EVENTS
+        .lock()
+        .unwrap()
+        .iter()
+        .rev()
+        .filter(|e| request_id.is_none() || e.request_id == request_id)
+        .take(limit.unwrap_or(100))
+        .cloned()
+        .collect();
+    content::Json(serde_json::to_string(&events).unwrap())
+}
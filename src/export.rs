@@ -0,0 +1,289 @@
+//! History as a Parquet file, for analysis in pandas and the like, with typed columns
+//! rather than JSON's strings: `time` as a UTC timestamp, `count`, the samples each row
+//! stands for, and a nullable float per sensor. Like `/api/history`, compacted ranges
+//! come from the aggregate tiers, with their averages as values. The file is written to
+//! a temp file a row group at a time, then streamed, so memory stays bounded for any
+//! range.
+//!
+//! Or as CSV, with the same columns, and a row for each outage, so a stretch without
+//! samples is labeled rather than missing: its `time` is when it started, `count` is 0,
+//! and `outage_end` and `outage_cause` are set, eg to `app_down`.
+//!
+//! Either can be of a device that pushes its readings, with `device`. Its outages aren't
+//! tracked, so its CSV has none.
+
+use std::{
+    env,
+    fs::{self, File, OpenOptions},
+    io::{BufWriter, Seek, SeekFrom, Write},
+};
+
+use chrono::Utc;
+use rocket::{
+    http::{ContentType, Status},
+    response::{self, Responder, Response},
+    Request,
+};
+use rusqlite::params;
+
+use crate::{
+    api::{self, ErrorResponse},
+    config,
+    device_meta::LOCAL,
+    history, outages,
+    parquet::{Field, Kind, Values, Writer},
+    registry,
+    tokens::Viewer,
+    tz,
+};
+
+/// Rows per row group; about 2 MB of values in memory.
+const ROW_GROUP_ROWS: usize = 64 * 1_024;
+
+pub struct ExportFile {
+    file: File,
+    content_type: ContentType,
+    /// Its extension.
+    extension: &'static str,
+}
+
+impl<'r> Responder<'r> for ExportFile {
+    fn respond_to(self, _req: &Request) -> response::Result<'r> {
+        Response::build()
+            .header(self.content_type)
+            .raw_header(
+                "Content-Disposition",
+                format!(
+                    "attachment; filename=\"water-mon-history.{}\"",
+                    self.extension
+                ),
+            )
+            .streamed_body(self.file)
+            .ok()
+    }
+}
+
+fn export_error(e: impl std::fmt::Display) -> ErrorResponse {
+    api::error(
+        Status::InternalServerError,
+        &format!("Problem exporting history: {}", e),
+    )
+}
+
+fn empty_columns() -> Vec<Values> {
+    let mut columns = vec![
+        Values::TimestampMillis(Vec::new()),
+        Values::Int32(Vec::new()),
+    ];
+    columns.extend(registry::ids().map(|_| Values::NullableFloat(Vec::new())));
+    columns
+}
+
+/// `from` and `to`, in ms, defaulting to all of history. Times without an offset are in
+/// `tz`, defaulting to `time.timezone`.
+fn range(
+    from: Option<String>,
+    to: Option<String>,
+    tz: Option<String>,
+) -> Result<(i64, i64), ErrorResponse> {
+    let zone = tz::from_query(tz.as_deref())?;
+    let from_ms = match from {
+        Some(f) => history::parse_time(&f, "from", zone)?.timestamp_millis(),
+        None => 0,
+    };
+    let to_ms = match to {
+        Some(t) => history::parse_time(&t, "to", zone)?.timestamp_millis(),
+        None => Utc::now().timestamp_millis() + 1,
+    };
+    if from_ms >= to_ms {
+        return Err(api::error(Status::BadRequest, "`from` must be before `to`"));
+    }
+    Ok((from_ms, to_ms))
+}
+
+/// Each of `device`'s rows, from every tier, by time: its time, count, and a value per
+/// sensor.
+fn rows_sql(device: &str) -> String {
+    let columns: Vec<String> = registry::ids()
+        .map(|name| format!("{0}_sum / {0}_n", name))
+        .collect();
+    format!(
+        "SELECT time, count, {} FROM ({}) ORDER BY time",
+        columns.join(", "),
+        history::device_tiers(device)
+    )
+}
+
+/// A new temp file, to write an export to.
+fn temp_file(extension: &str) -> Result<File, ErrorResponse> {
+    let path = env::temp_dir().join(format!(
+        "water-mon-export-{:016x}.{}",
+        rand::random::<u64>(),
+        extension
+    ));
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .map_err(export_error)?;
+    // On unix, the file lives on until it's closed, after the response. Elsewhere, an
+    // open file can't be removed, so it's left in the temp dir.
+    let _ = fs::remove_file(&path);
+    Ok(file)
+}
+
+/// History between `from` and `to`, defaulting to all of it, as Parquet. Times without
+/// an offset are in `tz`, defaulting to `time.timezone`; those in the file are UTC.
+#[get("/export.parquet?<from>&<to>&<tz>&<device>")]
+pub fn export_parquet(
+    _viewer: Viewer,
+    from: Option<String>,
+    to: Option<String>,
+    tz: Option<String>,
+    device: Option<String>,
+) -> Result<ExportFile, ErrorResponse> {
+    let device = history::history_device(device, &config::get())?;
+    let (from_ms, to_ms) = range(from, to, tz)?;
+    let conn = history::open_reader()?;
+    let mut stmt = conn
+        .prepare(&rows_sql(&device))
+        .map_err(history::query_error)?;
+    let mut rows = stmt
+        .query(params![from_ms, to_ms])
+        .map_err(history::query_error)?;
+    let file = temp_file("parquet")?;
+
+    let mut fields = vec![
+        Field {
+            name: "time".into(),
+            kind: Kind::TimestampMillis,
+        },
+        Field {
+            name: "count".into(),
+            kind: Kind::Int32,
+        },
+    ];
+    fields.extend(registry::ids().map(|name| Field {
+        name: name.into(),
+        kind: Kind::NullableFloat,
+    }));
+    let created_by = format!("water-mon-app version {}", env!("CARGO_PKG_VERSION"));
+    let mut writer =
+        Writer::new(BufWriter::new(file), fields, &created_by).map_err(export_error)?;
+
+    let mut columns = empty_columns();
+    let mut buffered = 0;
+    while let Some(row) = rows.next().map_err(history::query_error)? {
+        for (i, column) in columns.iter_mut().enumerate() {
+            match column {
+                Values::TimestampMillis(v) => v.push(row.get(i).map_err(history::query_error)?),
+                Values::Int32(v) => v.push(row.get(i).map_err(history::query_error)?),
+                Values::NullableFloat(v) => v.push(
+                    row.get::<_, Option<f64>>(i)
+                        .map_err(history::query_error)?
+                        .map(|v| v as f32),
+                ),
+            }
+        }
+        buffered += 1;
+        if buffered == ROW_GROUP_ROWS {
+            writer.write_row_group(&columns).map_err(export_error)?;
+            columns = empty_columns();
+            buffered = 0;
+        }
+    }
+    writer.write_row_group(&columns).map_err(export_error)?;
+
+    let mut file = writer
+        .finish()
+        .and_then(|w| w.into_inner().map_err(|e| e.into_error()))
+        .map_err(export_error)?;
+    file.seek(SeekFrom::Start(0)).map_err(export_error)?;
+
+    Ok(ExportFile {
+        file,
+        content_type: ContentType::new("application", "vnd.apache.parquet"),
+        extension: "parquet",
+    })
+}
+
+fn outage_row(outage: &outages::Outage) -> String {
+    let empty = [""; registry::COUNT];
+    format!(
+        "{},0,{},{},{}",
+        history::format_time(outage.start),
+        empty.join(","),
+        history::format_time(outage.end),
+        outage.cause
+    )
+}
+
+/// History between `from` and `to`, defaulting to all of it, as CSV, with a row for each
+/// outage overlapping it. Times without an offset are in `tz`, defaulting to
+/// `time.timezone`; those in the file are UTC.
+#[get("/export.csv?<from>&<to>&<tz>&<device>")]
+pub fn export_csv(
+    _viewer: Viewer,
+    from: Option<String>,
+    to: Option<String>,
+    tz: Option<String>,
+    device: Option<String>,
+) -> Result<ExportFile, ErrorResponse> {
+    let device = history::history_device(device, &config::get())?;
+    let (from_ms, to_ms) = range(from, to, tz)?;
+    let conn = history::open_reader()?;
+    let outages = if device == LOCAL {
+        outages::between(&conn, from_ms, to_ms).map_err(history::query_error)?
+    } else {
+        Vec::new()
+    };
+    let mut outages = outages.iter().peekable();
+    let mut stmt = conn
+        .prepare(&rows_sql(&device))
+        .map_err(history::query_error)?;
+    let mut rows = stmt
+        .query(params![from_ms, to_ms])
+        .map_err(history::query_error)?;
+
+    let mut out = BufWriter::new(temp_file("csv")?);
+    let header: Vec<_> = ["time", "count"]
+        .into_iter()
+        .chain(registry::ids())
+        .chain(["outage_end", "outage_cause"])
+        .collect();
+    writeln!(out, "{}", header.join(",")).map_err(export_error)?;
+
+    while let Some(row) = rows.next().map_err(history::query_error)? {
+        let time: i64 = row.get(0).map_err(history::query_error)?;
+        while let Some(outage) = outages.next_if(|o| o.start <= time) {
+            writeln!(out, "{}", outage_row(outage)).map_err(export_error)?;
+        }
+        let count: i64 = row.get(1).map_err(history::query_error)?;
+        let mut values = Vec::with_capacity(registry::COUNT);
+        for i in 0..registry::COUNT {
+            let value: Option<f64> = row.get(i + 2).map_err(history::query_error)?;
+            values.push(value.map(|v| v.to_string()).unwrap_or_default());
+        }
+        writeln!(
+            out,
+            "{},{},{},,",
+            history::format_time(time),
+            count,
+            values.join(",")
+        )
+        .map_err(export_error)?;
+    }
+    for outage in outages {
+        writeln!(out, "{}", outage_row(outage)).map_err(export_error)?;
+    }
+
+    let mut file = out.into_inner().map_err(|e| export_error(e.into_error()))?;
+    file.seek(SeekFrom::Start(0)).map_err(export_error)?;
+
+    Ok(ExportFile {
+        file,
+        content_type: ContentType::CSV,
+        extension: "csv",
+    })
+}
@@ -0,0 +1,514 @@
+//! Pushing readings to any HTTP endpoint, for ingestion services without their own
+//! integration: Splunk's HEC, ThingsBoard, a serverless function. Each `[[exporters]]`
+//! entry has a URL, method, headers and a body template, filled in per reading, and
+//! sends each reading as it's taken, or those of every `batch_secs` in one request.
+//! Failed requests are retried, then their readings are dropped and counted, so an
+//! endpoint that's down doesn't grow the queue without limit.
+//!
+//! Placeholders are `{{name}}`: a sensor id, case-insensitively, for its value;
+//! `ts_rfc3339`, `ts_ms` and `ts` for the reading's time, in UTC; `seq`, for its
+//! sequence number; `device_id` and `device_name`, for this instance; and `readings`,
+//! for them all as a JSON object, keyed per the exporter's `key_naming`. A sensor in
+//! error gives `null`, or its fallback, as in `{{pH|-1}}`. Sensors' snake case keys,
+//! eg `{{temperature}}`, work too.
+//!
+//! Delivery is at least once: a retried request may have got through already, so each
+//! request's `Idempotency-Key` header, the instance ID and its readings' sequence
+//! numbers, stays the same across retries. Readings dropped, after their retries or
+//! from a full queue, are counted, and show as gaps in `seq`.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io,
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use rocket::{http::Status, response::content};
+use serde::Serialize;
+
+use crate::{
+    api::{self, ErrorResponse},
+    auth::Admin,
+    cache,
+    config::{self, AppConfig, ExporterConfig},
+    events::{self, Severity},
+    instance,
+    memory::{Buffer, Policy},
+    net::{self, HttpResponse},
+    precision,
+    registry::{KeyNaming, REGISTRY},
+    signing, Readings,
+};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Readings queued per exporter; older ones are dropped.
+const MAX_QUEUED: usize = 1_000;
+
+pub const BUFFER: Buffer = Buffer {
+    name: "exporters.queue",
+    cap: MAX_QUEUED,
+    policy: Policy::DropOldest,
+    about: "Readings waiting to be sent, per exporter; each exporter counts its drops",
+};
+
+const METHODS: &[&str] = &["POST", "PUT", "PATCH"];
+
+/// The longest wait between retries.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+#[derive(Clone)]
+struct Sample {
+    time: DateTime<Utc>,
+    seq: u64,
+    readings: Readings,
+}
+
+#[derive(Default)]
+struct Queue {
+    samples: VecDeque<Sample>,
+    /// When the oldest queued reading was taken, for batching.
+    batch_start: Option<Instant>,
+    /// Readings sent.
+    sent: u64,
+    /// Requests that failed, after retries.
+    failures: u64,
+    /// Readings dropped, from failed requests or a full queue.
+    dropped: u64,
+    last_error: Option<String>,
+    /// If the last request failed; we warn once per run of failures.
+    failing: bool,
+}
+
+/// By exporter name.
+static QUEUES: Mutex<BTreeMap<String, Queue>> = Mutex::new(BTreeMap::new());
+
+#[derive(Serialize)]
+pub struct ExporterStatus {
+    pub name: String,
+    pub queued: usize,
+    pub sent: u64,
+    pub failures: u64,
+    pub dropped: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct TestResult {
+    /// What we sent.
+    request_body: String,
+    response: HttpResponse,
+}
+
+fn invalid_input(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+/// A placeholder's value, or an error for an unknown one.
+fn placeholder(p: &str, naming: KeyNaming, sample: &Sample) -> Result<String, String> {
+    let (name, fallback) = match p.split_once('|') {
+        Some((n, f)) => (n.trim(), Some(f.trim())),
+        None => (p, None),
+    };
+
+    let sensor = REGISTRY
+        .iter()
+        .find(|s| s.id.eq_ignore_ascii_case(name) || s.key == name);
+    if let Some(id) = sensor.map(|s| s.id) {
+        return Ok(match sample.readings.get(id) {
+            Some(Ok(v)) if v.is_finite() => v.to_string(),
+            _ => fallback.unwrap_or("null").to_owned(),
+        });
+    }
+    if fallback.is_some() {
+        return Err(format!(
+            "`{{{{{}}}}}` has a fallback, but only sensors can",
+            p
+        ));
+    }
+    Ok(match name {
+        "readings" => serde_json::to_string(&sample.readings.named(naming)).unwrap(),
+        "ts_rfc3339" => sample.time.to_rfc3339_opts(SecondsFormat::Millis, true),
+        "ts_ms" => sample.time.timestamp_millis().to_string(),
+        "ts" => sample.time.timestamp().to_string(),
+        "seq" => sample.seq.to_string(),
+        "device_id" => instance::id(),
+        "device_name" => instance::name(),
+        _ => return Err(format!("Unknown placeholder `{{{{{}}}}}`", name)),
+    })
+}
+
+/// Fill in an exporter's body template's placeholders.
+fn render(exporter: &ExporterConfig, sample: &Sample) -> Result<String, String> {
+    let mut out = String::with_capacity(exporter.body.len());
+    let mut rest = exporter.body.as_str();
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after
+            .find("}}")
+            .ok_or_else(|| "A `{{` without its `}}`".to_owned())?;
+        out.push_str(&placeholder(
+            after[..end].trim(),
+            exporter.key_naming,
+            sample,
+        )?);
+        rest = &after[end + 2..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Checks each exporter's URL, method, headers and body template.
+pub fn check(cfg: &AppConfig) -> Result<(), io::Error> {
+    let sample = Sample {
+        time: Utc::now(),
+        seq: 0,
+        readings: Readings::default(),
+    };
+
+    for (i, exporter) in cfg.exporters.iter().enumerate() {
+        let name = &exporter.name;
+        let invalid = |msg: String| {
+            Err(invalid_input(format!(
+                "Config error: Exporter `{}`: {}",
+                name, msg
+            )))
+        };
+
+        if name.is_empty() {
+            return Err(invalid_input(
+                "Config error: An exporter has no name".into(),
+            ));
+        }
+        if cfg.exporters[..i].iter().any(|e| &e.name == name) {
+            return Err(invalid_input(format!(
+                "Config error: There's more than one exporter named `{}`",
+                name
+            )));
+        }
+        if let Err(e) = net::split_url(&exporter.url) {
+            return invalid(e);
+        }
+        if !METHODS.contains(&exporter.method.as_str()) {
+            return invalid(format!(
+                "`method` must be one of {}; got `{}`",
+                METHODS.join(", "),
+                exporter.method
+            ));
+        }
+        let mut header_values = exporter.headers.values().chain([&exporter.content_type]);
+        if exporter
+            .headers
+            .keys()
+            .any(|k| k.is_empty() || !k.bytes().all(|b| b.is_ascii_graphic() && b != b':'))
+            || header_values.any(|v| v.contains(['\r', '\n']))
+        {
+            return invalid(
+                "Header names must be printable ASCII, without colons, and values on one line"
+                    .into(),
+            );
+        }
+        if let Err(e) = render(exporter, &sample) {
+            return invalid(format!("In `body`: {}", e));
+        }
+        if exporter.batch_secs == Some(0) {
+            return invalid("`batch_secs` must be at least 1".into());
+        }
+    }
+    Ok(())
+}
+
+/// Queue a successful reading, with its sequence number, for each exporter. Called by
+/// the poller.
+pub fn record(readings: &Readings, seq: u64) {
+    let cfg = config::get();
+    let exporters = cfg.exporters;
+    if exporters.is_empty() {
+        return;
+    }
+    let sample = Sample {
+        time: Utc::now(),
+        seq,
+        readings: precision::rounded(readings, &cfg.precision),
+    };
+
+    let mut queues = QUEUES.lock().unwrap();
+    for exporter in exporters.iter() {
+        let queue = queues.entry(exporter.name.clone()).or_default();
+        if queue.samples.len() >= MAX_QUEUED {
+            queue.samples.pop_front();
+            queue.dropped += 1;
+            BUFFER.dropped(1);
+        }
+        queue.samples.push_back(sample.clone());
+        queue.batch_start.get_or_insert_with(Instant::now);
+    }
+    BUFFER.note(longest(&queues));
+}
+
+fn longest(queues: &BTreeMap<String, Queue>) -> usize {
+    queues.values().map(|q| q.samples.len()).max().unwrap_or(0)
+}
+
+/// The `Idempotency-Key` for a request with these readings.
+fn idempotency_key(samples: &[Sample]) -> String {
+    match (samples.first(), samples.last()) {
+        (Some(first), Some(last)) if first.seq != last.seq => {
+            format!("{}-{}-{}", instance::id(), first.seq, last.seq)
+        }
+        (Some(s), _) => format!("{}-{}", instance::id(), s.seq),
+        _ => instance::id(),
+    }
+}
+
+/// Send a request, once, and read the reply.
+fn request(exporter: &ExporterConfig, body: &str, key: &str) -> Result<HttpResponse, io::Error> {
+    let signed = if exporter.sign {
+        signing::headers(body)
+    } else {
+        None
+    };
+    let mut headers = vec![
+        ("Content-Type", exporter.content_type.as_str()),
+        ("Idempotency-Key", key),
+    ];
+    headers.extend(
+        exporter
+            .headers
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str())),
+    );
+    headers.extend(signed.iter().flatten().map(|(k, v)| (*k, v.as_str())));
+    net::http_request(&exporter.method, &exporter.url, &headers, body, TIMEOUT)
+}
+
+/// Send a request, retrying failures that may pass: network errors, server errors, and
+/// rate limiting.
+fn send(exporter: &ExporterConfig, body: &str, key: &str) -> Result<(), String> {
+    let mut backoff = Duration::from_secs(1);
+    let mut attempt = 0;
+    loop {
+        let (error, retryable) = match request(exporter, body, key) {
+            Ok(r) if (200..300).contains(&r.status) => return Ok(()),
+            Ok(r) => (
+                format!("Got `{}`", r.status_line),
+                r.status >= 500 || r.status == 429,
+            ),
+            Err(e) => (e.to_string(), true),
+        };
+        if !retryable || attempt >= exporter.retries {
+            return Err(error);
+        }
+        attempt += 1;
+        thread::sleep(backoff);
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Update an exporter's counts after a request, and warn at the start of a run of
+/// failures.
+fn on_sent(name: &str, readings: usize, result: Result<(), String>) {
+    let mut queues = QUEUES.lock().unwrap();
+    let queue = match queues.get_mut(name) {
+        Some(q) => q,
+        None => return,
+    };
+
+    match result {
+        Ok(()) => {
+            queue.sent += readings as u64;
+            if queue.failing {
+                events::record(
+                    Severity::Info,
+                    "exporters",
+                    format!("Exporter `{}` is sending again.", name),
+                );
+            }
+            queue.failing = false;
+            queue.last_error = None;
+        }
+        Err(e) => {
+            queue.failures += 1;
+            queue.dropped += readings as u64;
+            if !queue.failing {
+                events::record(
+                    Severity::Warning,
+                    "exporters",
+                    format!(
+                        "Problem sending to exporter `{}`: {}. Its readings are dropped until \
+                         it works again.",
+                        name, e
+                    ),
+                );
+            }
+            queue.failing = true;
+            queue.last_error = Some(e);
+        }
+    }
+}
+
+/// Send an exporter's queued readings, if they're due.
+fn flush(exporter: &ExporterConfig) {
+    let samples: Vec<_> = {
+        let mut queues = QUEUES.lock().unwrap();
+        let queue = match queues.get_mut(&exporter.name) {
+            Some(q) => q,
+            None => return,
+        };
+        let due = match (exporter.batch_secs, queue.batch_start) {
+            (_, None) => false,
+            (None, Some(_)) => true,
+            (Some(secs), Some(start)) => start.elapsed() >= Duration::from_secs(secs as u64),
+        };
+        if !due {
+            return;
+        }
+        queue.batch_start = None;
+        let samples = queue.samples.drain(..).collect();
+        BUFFER.note(longest(&queues));
+        samples
+    };
+
+    // Templates are checked with the config, so rendering doesn't fail here.
+    if exporter.batch_secs.is_some() {
+        let bodies: Vec<_> = samples
+            .iter()
+            .filter_map(|s| render(exporter, s).ok())
+            .collect();
+        let body = format!(
+            "{}{}{}",
+            exporter.batch_prefix,
+            bodies.join(&exporter.batch_separator),
+            exporter.batch_suffix
+        );
+        let key = idempotency_key(&samples);
+        on_sent(&exporter.name, samples.len(), send(exporter, &body, &key));
+    } else {
+        for sample in samples.iter() {
+            if let Ok(body) = render(exporter, sample) {
+                let key = idempotency_key(std::slice::from_ref(sample));
+                on_sent(&exporter.name, 1, send(exporter, &body, &key));
+            }
+        }
+    }
+}
+
+/// Send queued readings, forever; run this on its own thread. Exporters take turns, so
+/// one that's slow to answer delays the others.
+pub fn run() {
+    loop {
+        let exporters = config::get().exporters;
+        QUEUES
+            .lock()
+            .unwrap()
+            .retain(|name, _| exporters.iter().any(|e| &e.name == name));
+
+        for exporter in exporters.iter() {
+            flush(exporter);
+        }
+        thread::sleep(CHECK_INTERVAL);
+    }
+}
+
+/// Each exporter's counts, for `/api/health`.
+pub fn status() -> Vec<ExporterStatus> {
+    let queues = QUEUES.lock().unwrap();
+    config::get()
+        .exporters
+        .into_iter()
+        .map(|e| {
+            let queue = queues.get(&e.name);
+            ExporterStatus {
+                queued: queue.map_or(0, |q| q.samples.len()),
+                sent: queue.map_or(0, |q| q.sent),
+                failures: queue.map_or(0, |q| q.failures),
+                dropped: queue.map_or(0, |q| q.dropped),
+                last_error: queue.and_then(|q| q.last_error.clone()),
+                name: e.name,
+            }
+        })
+        .collect()
+}
+
+/// Send one request with the latest readings, without retries, and return what the
+/// endpoint replied, for setting an exporter up. Its counts aren't affected.
+#[post("/exporters/<name>/test")]
+pub fn test_exporter(_admin: Admin, name: String) -> Result<content::Json<String>, ErrorResponse> {
+    let exporter = config::get()
+        .exporters
+        .into_iter()
+        .find(|e| e.name == name)
+        .ok_or_else(|| api::error(Status::NotFound, &format!("No exporter named `{}`", name)))?;
+
+    let snapshot = cache::latest();
+    let sample = Sample {
+        time: snapshot.time,
+        seq: snapshot.seq.unwrap_or(0),
+        readings: precision::rounded(&snapshot.readings, &config::get().precision),
+    };
+    let body = render(&exporter, &sample)
+        .map_err(|e| api::error(Status::BadRequest, &format!("In `body`: {}", e)))?;
+    let request_body = if exporter.batch_secs.is_some() {
+        format!("{}{}{}", exporter.batch_prefix, body, exporter.batch_suffix)
+    } else {
+        body
+    };
+
+    let key = idempotency_key(std::slice::from_ref(&sample));
+    let response = request(&exporter, &request_body, &key).map_err(|e| {
+        api::error(
+            Status::BadGateway,
+            &format!("Problem sending to `{}`: {}", exporter.url, e),
+        )
+    })?;
+    let result = TestResult {
+        request_body,
+        response,
+    };
+    Ok(content::Json(serde_json::to_string(&result).unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(seq: u64) -> Sample {
+        let mut readings = Readings::empty();
+        readings.set("T", Some(Ok(21.5)));
+        Sample {
+            time: Utc::now(),
+            seq,
+            readings,
+        }
+    }
+
+    /// Retries of a request send the same key, and each batch's is its own.
+    #[test]
+    fn requests_are_keyed_by_their_readings() {
+        let id = instance::id();
+        assert_eq!(idempotency_key(&[sample(7)]), format!("{}-7", id));
+        let batch = [sample(7), sample(8), sample(9)];
+        assert_eq!(idempotency_key(&batch), format!("{}-7-9", id));
+        assert_eq!(idempotency_key(&batch), idempotency_key(&batch));
+
+        let exporter: ExporterConfig = toml::from_str(
+            r#"
+            name = "sink"
+            url = "http://localhost:9000"
+            body = '{"seq": {{seq}}, "device": "{{device_id}}", "T": {{T}}}'
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            render(&exporter, &sample(8)).unwrap(),
+            format!(r#"{{"seq": 8, "device": "{}", "T": 21.5}}"#, id)
+        );
+    }
+}
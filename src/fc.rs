@@ -0,0 +1,828 @@
+//! The framed serial protocol of the flight controller, and the transactions we make with
+//! it. Message types and payloads are copy+pasted from `quadcopter::protocols::usb`.
+//!
+//! A frame is the message type, the payload length, the payload, then a CRC-8 of
+//! everything before it. Most requests from the PC have empty payloads.
+
+use std::{
+    convert::{TryFrom, TryInto},
+    io::{self, Read},
+    sync::{mpsc, Mutex},
+    time::{Duration, Instant},
+};
+
+use num_enum::TryFromPrimitive;
+use rocket::{http::Status, response::content, Data};
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::{
+    api::{self, ErrorResponse},
+    auth::Admin,
+    config,
+    interlock::{self, MOTOR_DIRS},
+    WaterMonitor, READ_TIMEOUT, REFRESH_INTERVAL,
+};
+
+mod buffer;
+mod clock;
+mod dispatch;
+pub mod firmware;
+
+use dispatch::{Dispatcher, Request};
+
+const CRC_POLY: u8 = 0xab;
+static CRC_LUT: [u8; 256] = crc_lut(CRC_POLY);
+
+pub const PARAMS_SIZE: usize = 76; // + message type, payload len, and crc.
+pub const CONTROLS_SIZE: usize = 18; // + message type, payload len, and crc.
+/// Start index and count, then `buffer::CHUNK_SAMPLES` samples: a device time in ms, and
+/// readings in the Water Monitor's format.
+pub const BUFFER_CHUNK_SIZE: usize = 5 + buffer::CHUNK_SAMPLES * buffer::SAMPLE_SIZE;
+
+const MAX_PAYLOAD_SIZE: usize = BUFFER_CHUNK_SIZE; // For BufferChunk.
+const MAX_PACKET_SIZE: usize = MAX_PAYLOAD_SIZE + 3; // + message type, payload len, and crc.
+
+/// The latest control channels, and when we got them.
+static CONTROLS: Mutex<Option<ChannelData>> = Mutex::new(None);
+static LAST_CONTROLS_UPDATE: Mutex<Option<Instant>> = Mutex::new(None);
+/// The latest params, and when we got them.
+static PARAMS: Mutex<Option<Params>> = Mutex::new(None);
+static LAST_ATTITUDE_UPDATE: Mutex<Option<Instant>> = Mutex::new(None);
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, TryFromPrimitive)]
+#[repr(u8)]
+/// Repr is how this type is passed as serial.
+pub enum MsgType {
+    /// Transmit from FC
+    Params = 0,
+    SetMotorDirs = 1,
+    /// Receive to FC
+    ReqParams = 2,
+    /// Acknowledgement, eg in response to setting something.
+    Ack = 3,
+    /// Controls data (From FC)
+    Controls = 4,
+    /// Request controls data. (From PC)
+    ReqControls = 5,
+    /// Request the FC's clock. (From PC)
+    ReqTime = 6,
+    /// The FC's clock, as ms since the Unix epoch, in UTC. (From FC)
+    Time = 7,
+    /// Set the FC's clock, in the same format. (From PC)
+    SetTime = 8,
+    /// Request how many samples the FC buffered while we weren't polling. (From PC)
+    ReqBufferCount = 9,
+    /// The number of buffered samples, as a u32. (From FC)
+    BufferCount = 10,
+    /// Request buffered samples, from a u32 index, oldest first. (From PC)
+    ReqBufferChunk = 11,
+    /// Up to `buffer::CHUNK_SAMPLES` buffered samples. (From FC)
+    BufferChunk = 12,
+    /// Drop this many buffered samples, as a u32, oldest first. (From PC)
+    ClearBuffer = 13,
+    /// Request the FC's model, hardware revision and firmware version. (From PC)
+    ReqDeviceInfo = 14,
+    /// In the format of a firmware image's header. (From FC)
+    DeviceInfo = 15,
+    /// Restart into the USB DFU bootloader, after acknowledging. (From PC)
+    EnterBootloader = 16,
+}
+
+impl MsgType {
+    pub fn payload_size(&self) -> usize {
+        match self {
+            Self::Params => PARAMS_SIZE,
+            Self::SetMotorDirs => 1, // Packed bits: motors 1-4, R-L. True = CW.
+            Self::ReqParams => 0,
+            Self::Ack => 0,
+            Self::Controls => CONTROLS_SIZE,
+            Self::ReqControls => 0,
+            Self::ReqTime => 0,
+            Self::Time => 8,
+            Self::SetTime => 8,
+            Self::ReqBufferCount => 0,
+            Self::BufferCount => 4,
+            Self::ReqBufferChunk => 4,
+            Self::BufferChunk => BUFFER_CHUNK_SIZE,
+            Self::ClearBuffer => 4,
+            Self::ReqDeviceInfo => 0,
+            Self::DeviceInfo => firmware::DEVICE_INFO_SIZE,
+            Self::EnterBootloader => 0,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    /// Fewer bytes than the frame's header says it has.
+    Incomplete,
+    UnknownType(u8),
+    /// The declared payload length doesn't match the message type's.
+    WrongLength {
+        msg_type: MsgType,
+        len: usize,
+    },
+    Crc,
+}
+
+impl DecodeError {
+    pub fn to_io(&self) -> io::Error {
+        let msg = match self {
+            Self::Incomplete => "Incomplete frame from the flight controller".to_owned(),
+            Self::UnknownType(t) => {
+                format!("Unknown message type {} from the flight controller", t)
+            }
+            Self::WrongLength { msg_type, len } => format!(
+                "{:?} frame from the flight controller has a {} byte payload; expected {}",
+                msg_type,
+                len,
+                msg_type.payload_size()
+            ),
+            Self::Crc => "Frame from the flight controller failed its CRC check".to_owned(),
+        };
+        io::Error::new(io::ErrorKind::InvalidData, msg)
+    }
+}
+
+pub struct Packet {
+    message_type: MsgType,
+    payload_size: usize,
+    payload: [u8; MAX_PAYLOAD_SIZE], // todo?
+    crc: u8,
+}
+
+impl Packet {
+    pub fn new(message_type: MsgType, payload: &[u8]) -> Self {
+        let mut p = [0; MAX_PAYLOAD_SIZE];
+        p[..payload.len()].copy_from_slice(payload);
+
+        let mut result = Self {
+            message_type,
+            payload_size: payload.len(),
+            payload: p,
+            crc: 0,
+        };
+        result.crc = calc_crc(&result.to_bytes()[..payload.len() + 2]);
+        result
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.payload[..self.payload_size]
+    }
+
+    /// The framed packet, ready to send.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut result = Vec::with_capacity(self.payload_size + 3);
+        result.push(self.message_type as u8);
+        result.push(self.payload_size as u8);
+        result.extend_from_slice(self.payload());
+        result.push(self.crc);
+        result
+    }
+
+    /// Decode a frame from the start of `buf`.
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, DecodeError> {
+        if buf.len() < 3 {
+            return Err(DecodeError::Incomplete);
+        }
+
+        let message_type =
+            MsgType::try_from(buf[0]).map_err(|_| DecodeError::UnknownType(buf[0]))?;
+        let len = buf[1] as usize;
+        if len != message_type.payload_size() {
+            return Err(DecodeError::WrongLength {
+                msg_type: message_type,
+                len,
+            });
+        }
+        if buf.len() < len + 3 {
+            return Err(DecodeError::Incomplete);
+        }
+
+        if calc_crc(&buf[..len + 2]) != buf[len + 2] {
+            return Err(DecodeError::Crc);
+        }
+
+        let mut payload = [0; MAX_PAYLOAD_SIZE];
+        payload[..len].copy_from_slice(&buf[2..len + 2]);
+        Ok(Self {
+            message_type,
+            payload_size: len,
+            payload,
+            crc: buf[len + 2],
+        })
+    }
+}
+
+const fn crc_lut(poly: u8) -> [u8; 256] {
+    let mut lut = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u8;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = (crc << 1) ^ if crc & 0x80 != 0 { poly } else { 0 };
+            bit += 1;
+        }
+        lut[i] = crc;
+        i += 1;
+    }
+    lut
+}
+
+/// CRC-8, matching `quadcopter::util::calc_crc`.
+fn calc_crc(data: &[u8]) -> u8 {
+    data.iter().fold(0, |crc, b| CRC_LUT[(crc ^ b) as usize])
+}
+
+// Byte encodings match the firmware's `#[repr(u8)]`s. Defaults are implemented by hand:
+// `num_enum` takes a `#[default]` variant as the one unknown bytes convert to, which
+// would hide them from `Switch`.
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, TryFromPrimitive)]
+#[repr(u8)]
+pub enum ArmStatus {
+    Disarmed = 0,
+    Armed = 1,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, TryFromPrimitive)]
+#[repr(u8)]
+pub enum InputModeSwitch {
+    /// Sticks command rotation rates.
+    Acro = 0,
+    /// Sticks command an attitude.
+    Attitude = 1,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, TryFromPrimitive)]
+#[repr(u8)]
+pub enum AltHoldSwitch {
+    Disabled = 0,
+    /// Holding altitude above ground level.
+    #[serde(rename = "AGL")]
+    EnabledAgl = 1,
+    /// Holding altitude above mean sea level.
+    #[serde(rename = "MSL")]
+    EnabledMsl = 2,
+}
+
+impl Default for ArmStatus {
+    fn default() -> Self {
+        Self::Disarmed
+    }
+}
+
+impl Default for InputModeSwitch {
+    fn default() -> Self {
+        Self::Acro
+    }
+}
+
+impl Default for AltHoldSwitch {
+    fn default() -> Self {
+        Self::Disabled
+    }
+}
+
+/// A switch position, or its raw value if it's one we don't know, eg from newer
+/// firmware. Unknown positions serialize as eg `"Unknown(3)"`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Switch<T> {
+    Known(T),
+    Unknown(u8),
+}
+
+impl<T: TryFrom<u8>> From<u8> for Switch<T> {
+    fn from(b: u8) -> Self {
+        T::try_from(b).map_or(Self::Unknown(b), Self::Known)
+    }
+}
+
+impl<T: Default> Default for Switch<T> {
+    fn default() -> Self {
+        Self::Known(T::default())
+    }
+}
+
+impl<T: Serialize> Serialize for Switch<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Self::Known(v) => v.serialize(serializer),
+            Self::Unknown(b) => serializer.collect_str(&format_args!("Unknown({})", b)),
+        }
+    }
+}
+
+/// The big-endian float at `offset` in a fixed-size payload, whose layout puts one there.
+fn float_at<const N: usize>(p: &[u8; N], offset: usize) -> f32 {
+    f32::from_be_bytes([p[offset], p[offset + 1], p[offset + 2], p[offset + 3]])
+}
+
+/// Represents channel data in our end-use format.
+#[derive(Clone, Default, Serialize)]
+pub struct ChannelData {
+    /// Aileron, -1. to 1.
+    pub roll: f32,
+    /// Elevator, -1. to 1.
+    pub pitch: f32,
+    /// Throttle, 0. to 1., or -1. to 1. depending on if stick auto-centers.
+    pub throttle: f32,
+    /// Rudder, -1. to 1.
+    pub yaw: f32,
+    pub arm_status: Switch<ArmStatus>,
+    pub input_mode: Switch<InputModeSwitch>,
+    pub alt_hold: Switch<AltHoldSwitch>,
+    // todo: Auto-recover commanded, auto-TO/land/RTB, obstacle avoidance etc.
+}
+
+impl From<&[u8; CONTROLS_SIZE]> for ChannelData {
+    /// 4 f32s in the order we have defined in the struct, then the switches: the arm
+    /// status byte, then a byte with the input mode in its low nibble, and altitude hold
+    /// in its high one.
+    fn from(p: &[u8; CONTROLS_SIZE]) -> Self {
+        ChannelData {
+            roll: float_at(p, 0),
+            pitch: float_at(p, 4),
+            throttle: float_at(p, 8),
+            yaw: float_at(p, 12),
+            arm_status: p[16].into(),
+            input_mode: (p[17] & 0x0f).into(),
+            alt_hold: (p[17] >> 4).into(),
+        }
+    }
+}
+
+/// Represents a first-order status of the drone. todo: What grid/reference are we using?
+#[derive(Clone, Default, Serialize)]
+pub struct Params {
+    // todo: Do we want to use this full struct, or store multiple (3+) instantaneous ones?
+    pub s_x: f32,
+    pub s_y: f32,
+    // Note that we only need to specify MSL vs AGL for position; velocity and accel should
+    // be equiv for them.
+    pub s_z_msl: f32,
+    pub s_z_agl: f32,
+
+    pub s_pitch: f32,
+    pub s_roll: f32,
+    pub s_yaw: f32,
+
+    // Velocity
+    pub v_x: f32,
+    pub v_y: f32,
+    pub v_z: f32,
+
+    pub v_pitch: f32,
+    pub v_roll: f32,
+    pub v_yaw: f32,
+
+    // Acceleration
+    pub a_x: f32,
+    pub a_y: f32,
+    pub a_z: f32,
+
+    pub a_pitch: f32,
+    pub a_roll: f32,
+    pub a_yaw: f32,
+}
+
+// Code in this section is a reverse of buffer <--> struct conversion in `usb_cfg`.
+
+impl From<&[u8; PARAMS_SIZE]> for Params {
+    /// 19 f32s x 4 = 76. In the order we have defined in the struct.
+    fn from(p: &[u8; PARAMS_SIZE]) -> Self {
+        let f = |i: usize| float_at(p, i * 4);
+
+        Params {
+            s_x: f(0),
+            s_y: f(1),
+            s_z_msl: f(2),
+            s_z_agl: f(3),
+
+            s_pitch: f(4),
+            s_roll: f(5),
+            s_yaw: f(6),
+
+            v_x: f(7),
+            v_y: f(8),
+            v_z: f(9),
+
+            v_pitch: f(10),
+            v_roll: f(11),
+            v_yaw: f(12),
+
+            a_x: f(13),
+            a_y: f(14),
+            a_z: f(15),
+
+            a_pitch: f(16),
+            a_roll: f(17),
+            a_yaw: f(18),
+        }
+    }
+}
+
+// End code reversed from `quadcopter`.
+
+enum CommandError {
+    NotConnected,
+    /// The queue to the flight controller is full.
+    Busy,
+    /// No `Ack` in time.
+    Timeout,
+    /// A garbled response.
+    Nack(io::Error),
+    Io(io::Error),
+}
+
+impl CommandError {
+    fn response(&self) -> ErrorResponse {
+        match self {
+            Self::NotConnected => api::error(
+                Status::ServiceUnavailable,
+                "The flight controller isn't connected",
+            ),
+            Self::Busy => api::error(
+                Status::ServiceUnavailable,
+                "Too many commands are waiting for the flight controller; try again shortly",
+            ),
+            Self::Timeout => api::error(
+                Status::GatewayTimeout,
+                "The flight controller didn't acknowledge the command in time",
+            ),
+            Self::Nack(e) => api::error(
+                Status::BadGateway,
+                &format!(
+                    "The flight controller didn't acknowledge the command: {}",
+                    e
+                ),
+            ),
+            Self::Io(e) => api::error(
+                Status::InternalServerError,
+                &format!("Problem sending the command: {}", e),
+            ),
+        }
+    }
+}
+
+/// Queue a command for the poller, and wait for its `Ack`.
+fn send_command(packet: Packet) -> Result<(), ErrorResponse> {
+    let cfg = config::get().flight_controller;
+    let timeout = Duration::from_millis(cfg.ack_timeout_ms as u64);
+
+    // The poller picks requests up once per cycle, after readings. If it hasn't by the
+    // time we stop waiting, it's dropped, rather than sent after we've reported failure.
+    let wait = timeout + Duration::from_millis(2 * REFRESH_INTERVAL as u64 + 1_000);
+    let (tx, rx) = mpsc::channel();
+    dispatch::enqueue(
+        Request {
+            packet,
+            response: MsgType::Ack,
+            timeout,
+            reply: Some(tx),
+        },
+        Some(Instant::now() + wait - timeout),
+    )
+    .map_err(|_| CommandError::Busy.response())?;
+
+    let result = match rx.recv_timeout(wait) {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) => Err(match e.kind() {
+            io::ErrorKind::NotConnected => CommandError::NotConnected,
+            io::ErrorKind::TimedOut => CommandError::Timeout,
+            io::ErrorKind::InvalidData => CommandError::Nack(e),
+            _ => CommandError::Io(e),
+        }),
+        Err(_) => Err(CommandError::Timeout),
+    };
+    result.map_err(|e| e.response())
+}
+
+/// The dispatcher, and the channel its handlers send control channels and params on.
+struct Link {
+    dispatcher: Dispatcher,
+    updates: mpsc::Receiver<Packet>,
+    /// If the port was open last cycle.
+    connected: bool,
+}
+
+static LINK: Mutex<Option<Link>> = Mutex::new(None);
+
+impl Link {
+    fn new() -> Self {
+        let (tx, updates) = mpsc::channel();
+        let mut dispatcher = Dispatcher::default();
+        dispatcher.register(MsgType::Controls, tx.clone());
+        dispatcher.register(MsgType::Params, tx);
+        Self {
+            dispatcher,
+            updates,
+            connected: false,
+        }
+    }
+
+    /// Cache the control channels and params the dispatcher has routed to us.
+    fn cache_updates(&self) {
+        for packet in self.updates.try_iter() {
+            match packet.message_type {
+                MsgType::Controls => {
+                    let payload: &[u8; CONTROLS_SIZE] = packet.payload().try_into().unwrap();
+                    *CONTROLS.lock().unwrap() = Some(payload.into());
+                    *LAST_CONTROLS_UPDATE.lock().unwrap() = Some(Instant::now());
+                }
+                MsgType::Params => {
+                    let payload: &[u8; PARAMS_SIZE] = packet.payload().try_into().unwrap();
+                    *PARAMS.lock().unwrap() = Some(payload.into());
+                    *LAST_ATTITUDE_UPDATE.lock().unwrap() = Some(Instant::now());
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+fn due(last_update: &Mutex<Option<Instant>>, interval_ms: u32) -> bool {
+    let interval = Duration::from_millis(interval_ms as u64);
+    last_update
+        .lock()
+        .unwrap()
+        .is_none_or(|t| t.elapsed() >= interval)
+}
+
+/// If the samples the flight controller buffered, if any, are all imported since it
+/// connected.
+pub(crate) fn buffer_settled() -> bool {
+    buffer::settled()
+}
+
+/// Request whichever of the control channels and params are due, then send every queued
+/// request. Called by the poller, which owns the port, so none of these interleave with
+/// a readings request, or each other.
+pub(crate) fn refresh(monitor: Option<&mut WaterMonitor>) {
+    let cfg = config::get().flight_controller;
+    let mut link = LINK.lock().unwrap();
+    let link = link.get_or_insert_with(Link::new);
+
+    let wm = match monitor {
+        Some(wm) if cfg.enabled => wm,
+        _ => {
+            link.connected = false;
+            link.dispatcher.fail_all(
+                io::ErrorKind::NotConnected,
+                "The flight controller isn't connected",
+            );
+            return;
+        }
+    };
+
+    // Polling is paused while it runs, and the device restarts.
+    if firmware::update_if_queued(&mut link.dispatcher, wm, &cfg) {
+        link.connected = false;
+        return;
+    }
+
+    if !link.connected {
+        link.connected = true;
+        clock::reset();
+        buffer::reset();
+    }
+    clock::sync_if_due(&mut link.dispatcher, wm, &cfg);
+    buffer::import_if_due(&mut link.dispatcher, wm, &cfg);
+
+    // Commands go first. A failure leaves the last values cached; the routes report
+    // their age.
+    let timeout = READ_TIMEOUT;
+    if due(&LAST_CONTROLS_UPDATE, cfg.controls_refresh_ms) {
+        // If the queue's full, the next cycle asks again.
+        let _ = dispatch::enqueue(
+            Request {
+                packet: Packet::new(MsgType::ReqControls, &[]),
+                response: MsgType::Controls,
+                timeout,
+                reply: None,
+            },
+            None,
+        );
+    }
+    if due(&LAST_ATTITUDE_UPDATE, cfg.params_refresh_ms) {
+        let _ = dispatch::enqueue(
+            Request {
+                packet: Packet::new(MsgType::ReqParams, &[]),
+                response: MsgType::Params,
+                timeout,
+                reply: None,
+            },
+            None,
+        );
+    }
+
+    link.dispatcher.run(wm);
+    link.cache_updates();
+}
+
+fn disabled() -> ErrorResponse {
+    api::error(
+        Status::NotFound,
+        "Flight controller support is disabled; see `flight_controller.enabled`",
+    )
+}
+
+#[derive(Serialize)]
+struct Cached<T> {
+    #[serde(flatten)]
+    value: T,
+    seconds_old: f32,
+}
+
+/// A cached value from the flight controller, with its age.
+fn view_cached<T: Clone + Serialize>(
+    value: &Mutex<Option<T>>,
+    last_update: &Mutex<Option<Instant>>,
+    name: &str,
+) -> Result<content::Json<String>, ErrorResponse> {
+    if !config::get().flight_controller.enabled {
+        return Err(disabled());
+    }
+
+    let value = value.lock().unwrap().clone();
+    let updated = *last_update.lock().unwrap();
+    match (value, updated) {
+        (Some(value), Some(t)) => Ok(content::Json(
+            serde_json::to_string(&Cached {
+                value,
+                seconds_old: t.elapsed().as_secs_f32(),
+            })
+            .unwrap(),
+        )),
+        _ => Err(api::error(
+            Status::ServiceUnavailable,
+            &format!("No {} from the flight controller yet", name),
+        )),
+    }
+}
+
+/// The latest control channels and switch positions from the flight controller.
+#[get("/controls")]
+pub fn view_controls() -> Result<content::Json<String>, ErrorResponse> {
+    view_cached(&CONTROLS, &LAST_CONTROLS_UPDATE, "controls")
+}
+
+/// The latest position, attitude, and their rates, from the flight controller.
+#[get("/params")]
+pub fn view_params() -> Result<content::Json<String>, ErrorResponse> {
+    view_cached(&PARAMS, &LAST_ATTITUDE_UPDATE, "params")
+}
+
+/// What we know about the flight controller itself: how its clock compares to ours, and
+/// the state of the latest firmware update.
+#[get("/device")]
+pub fn view_device() -> Result<content::Json<String>, ErrorResponse> {
+    if !config::get().flight_controller.enabled {
+        return Err(disabled());
+    }
+    Ok(content::Json(
+        serde_json::json!({ "clock": clock::info(), "firmware": firmware::status() }).to_string(),
+    ))
+}
+
+#[derive(Deserialize)]
+struct MotorDirs {
+    /// Motors 1 to 4; true for clockwise.
+    clockwise: [bool; 4],
+}
+
+/// Set the motors' spin directions. Waits for the flight controller to acknowledge.
+#[post("/motors/dirs", data = "<data>")]
+pub fn set_motor_dirs(_admin: Admin, data: Data) -> Result<content::Json<String>, ErrorResponse> {
+    if !config::get().flight_controller.enabled {
+        return Err(disabled());
+    }
+
+    let mut body = String::new();
+    data.open()
+        .take(1_024)
+        .read_to_string(&mut body)
+        .map_err(|e| api::error(Status::BadRequest, &e.to_string()))?;
+    let dirs: MotorDirs = serde_json::from_str(&body).map_err(|e| {
+        api::error(
+            Status::BadRequest,
+            &format!(
+                "Expected eg `{{\"clockwise\": [true, false, true, false]}}`: {}",
+                e
+            ),
+        )
+    })?;
+
+    interlock::permit(&MOTOR_DIRS).map_err(|blocked| interlock::denial(&MOTOR_DIRS, &blocked))?;
+    // Motor 1 in the lowest bit.
+    let packed = dirs
+        .clockwise
+        .iter()
+        .enumerate()
+        .fold(0, |acc, (i, cw)| acc | ((*cw as u8) << i));
+    send_command(Packet::new(MsgType::SetMotorDirs, &[packed]))?;
+
+    Ok(content::Json(
+        serde_json::json!({ "clockwise": dirs.clockwise }).to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The axes, then the switch bytes.
+    fn controls(axes: [f32; 4], arm: u8, modes: u8) -> [u8; CONTROLS_SIZE] {
+        let mut p = [0; CONTROLS_SIZE];
+        for (i, axis) in axes.iter().enumerate() {
+            p[i * 4..i * 4 + 4].copy_from_slice(&axis.to_be_bytes());
+        }
+        p[16] = arm;
+        p[17] = modes;
+        p
+    }
+
+    #[test]
+    fn controls_decode_from_their_payload() {
+        let c = ChannelData::from(&controls([-0.5, 0.25, 0.75, -1.], 1, 0x21));
+        assert_eq!(
+            [c.roll, c.pitch, c.throttle, c.yaw],
+            [-0.5, 0.25, 0.75, -1.]
+        );
+        assert_eq!(c.arm_status, Switch::Known(ArmStatus::Armed));
+        // Input mode in the low nibble, altitude hold in the high one.
+        assert_eq!(c.input_mode, Switch::Known(InputModeSwitch::Attitude));
+        assert_eq!(c.alt_hold, Switch::Known(AltHoldSwitch::EnabledMsl));
+
+        let c = ChannelData::from(&controls([0.; 4], 0, 0x10));
+        assert_eq!(c.arm_status, Switch::Known(ArmStatus::Disarmed));
+        assert_eq!(c.input_mode, Switch::Known(InputModeSwitch::Acro));
+        assert_eq!(c.alt_hold, Switch::Known(AltHoldSwitch::EnabledAgl));
+
+        assert_eq!(
+            serde_json::to_value(ChannelData::from(&controls([0.; 4], 1, 0x21))).unwrap(),
+            serde_json::json!({
+                "roll": 0., "pitch": 0., "throttle": 0., "yaw": 0.,
+                "arm_status": "Armed", "input_mode": "Attitude", "alt_hold": "MSL",
+            })
+        );
+    }
+
+    /// Each variant is its firmware byte, and serializes by name.
+    fn round_trips<T>(variants: &[(T, u8, &str)])
+    where
+        T: Copy + std::fmt::Debug + PartialEq + Serialize + TryFrom<u8>,
+    {
+        for (variant, byte, name) in variants {
+            assert_eq!(Switch::<T>::from(*byte), Switch::Known(*variant));
+            assert_eq!(
+                serde_json::to_value(Switch::Known(*variant)).unwrap(),
+                *name
+            );
+        }
+    }
+
+    #[test]
+    fn switches_round_trip() {
+        round_trips(&[
+            (ArmStatus::Disarmed, 0, "Disarmed"),
+            (ArmStatus::Armed, 1, "Armed"),
+        ]);
+        round_trips(&[
+            (InputModeSwitch::Acro, 0, "Acro"),
+            (InputModeSwitch::Attitude, 1, "Attitude"),
+        ]);
+        round_trips(&[
+            (AltHoldSwitch::Disabled, 0, "Disabled"),
+            (AltHoldSwitch::EnabledAgl, 1, "AGL"),
+            (AltHoldSwitch::EnabledMsl, 2, "MSL"),
+        ]);
+
+        assert_eq!(
+            Switch::<ArmStatus>::default(),
+            Switch::Known(ArmStatus::Disarmed)
+        );
+        assert_eq!(
+            Switch::<InputModeSwitch>::default(),
+            Switch::Known(InputModeSwitch::Acro)
+        );
+        assert_eq!(
+            Switch::<AltHoldSwitch>::default(),
+            Switch::Known(AltHoldSwitch::Disabled)
+        );
+    }
+
+    /// Positions from newer firmware are kept, rather than failing the packet.
+    #[test]
+    fn unknown_switch_positions_are_kept() {
+        assert_eq!(Switch::<ArmStatus>::from(2), Switch::Unknown(2));
+        assert_eq!(Switch::<AltHoldSwitch>::from(3), Switch::Unknown(3));
+
+        let c = ChannelData::from(&controls([0.; 4], 7, 0xf2));
+        assert_eq!(c.arm_status, Switch::Unknown(7));
+        assert_eq!(c.input_mode, Switch::Unknown(2));
+        assert_eq!(c.alt_hold, Switch::Unknown(15));
+        assert_eq!(serde_json::to_value(c.alt_hold).unwrap(), "Unknown(15)");
+    }
+}
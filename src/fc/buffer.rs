@@ -0,0 +1,250 @@
+//! Imports the samples the flight controller buffers while we aren't polling it, eg while
+//! the Pi is down, into history. On connect, once its clock is synced, we ask how many
+//! it holds, then download them a chunk per polling cycle, so readings carry on between
+//! chunks. Its timestamps are converted with the clock offset found at that sync. The
+//! device only drops them once we have them all; if the link drops part-way, the next
+//! connection picks up where this one stopped.
+
+use std::{convert::TryInto, io, sync::Mutex, time::Duration};
+
+use super::{
+    clock,
+    dispatch::{Dispatcher, Request},
+    MsgType, Packet,
+};
+use crate::{
+    config::{self, FlightControllerConfig},
+    events::{self, Severity},
+    history, sensors, Readings, WaterMonitor, READ_TIMEOUT,
+};
+
+/// Samples per `BufferChunk`.
+pub const CHUNK_SAMPLES: usize = 8;
+/// A device time in ms since the epoch, then readings.
+pub const SAMPLE_SIZE: usize = 8 + 20;
+
+/// Report progress every this many samples.
+const PROGRESS_INTERVAL: u32 = 1_000;
+
+struct Download {
+    /// How many samples the device holds.
+    total: u32,
+    /// The next to request.
+    next: u32,
+    /// Device time minus ours, when its buffered samples were stamped.
+    offset_ms: i64,
+    /// How many we stored, and skipped as already stored.
+    stored: usize,
+    skipped: usize,
+}
+
+struct State {
+    /// Kept across connections, to resume.
+    download: Option<Download>,
+    /// If we've checked the device's buffer since it connected.
+    checked: bool,
+    /// If the last attempt failed, so we only report the first of a run of failures.
+    failing: bool,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    download: None,
+    checked: false,
+    failing: false,
+});
+
+/// Check the buffer next time, eg because the device just connected.
+pub fn reset() {
+    STATE.lock().unwrap().checked = false;
+}
+
+/// If we've checked the device's buffer since it connected, and imported all it held.
+pub fn settled() -> bool {
+    let state = STATE.lock().unwrap();
+    state.checked && state.download.is_none()
+}
+
+/// Download the next chunk of buffered samples, if there are any.
+pub(crate) fn import_if_due(
+    dispatcher: &mut Dispatcher,
+    wm: &mut WaterMonitor,
+    cfg: &FlightControllerConfig,
+) {
+    let mut state = STATE.lock().unwrap();
+    // Without history, there's nowhere to put them; leave them on the device.
+    if !config::get().history.enabled || (state.checked && state.download.is_none()) {
+        return;
+    }
+    // The offset is only known, and only applies to what's buffered, after this
+    // connection's first sync.
+    let offset_ms = match clock::offset_since_reset() {
+        Some(o) => o,
+        None => return,
+    };
+
+    let result = step(&mut state, dispatcher, wm, cfg, offset_ms);
+    if let Err(e) = &result {
+        if !state.failing {
+            events::record(
+                Severity::Warning,
+                "buffer",
+                format!(
+                    "Problem importing the flight controller's buffered samples: {}",
+                    e
+                ),
+            );
+        }
+    }
+    state.failing = result.is_err();
+}
+
+fn step(
+    state: &mut State,
+    dispatcher: &mut Dispatcher,
+    wm: &mut WaterMonitor,
+    cfg: &FlightControllerConfig,
+    offset_ms: i64,
+) -> Result<(), io::Error> {
+    if !state.checked {
+        let response = dispatcher.request(
+            wm,
+            &Request {
+                packet: Packet::new(MsgType::ReqBufferCount, &[]),
+                response: MsgType::BufferCount,
+                timeout: READ_TIMEOUT,
+                reply: None,
+            },
+        )?;
+        let total = u32::from_be_bytes(response.payload().try_into().unwrap());
+        state.checked = true;
+
+        state.download = match state.download.take() {
+            _ if total == 0 => None,
+            // It still holds what we were downloading, plus anything it's buffered
+            // since, which was stamped with the clock we set.
+            Some(d) if d.next <= total => {
+                events::record(
+                    Severity::Info,
+                    "buffer",
+                    format!(
+                        "Resuming the import of the flight controller's {} buffered samples, \
+                         from {}",
+                        d.total, d.next
+                    ),
+                );
+                Some(d)
+            }
+            // New, or it's lost what we were downloading, eg by restarting.
+            _ => {
+                events::record(
+                    Severity::Info,
+                    "buffer",
+                    format!(
+                        "Importing {} samples the flight controller buffered while we weren't \
+                         polling",
+                        total
+                    ),
+                );
+                Some(Download {
+                    total,
+                    next: 0,
+                    offset_ms,
+                    stored: 0,
+                    skipped: 0,
+                })
+            }
+        };
+        return Ok(());
+    }
+
+    let download = match state.download.as_mut() {
+        Some(d) => d,
+        None => return Ok(()),
+    };
+
+    if download.next < download.total {
+        let samples = request_chunk(dispatcher, wm, download)?;
+        let stored = history::import(&samples)?;
+        download.stored += stored;
+        download.skipped += samples.len() - stored;
+
+        let before = download.next;
+        download.next += samples.len() as u32;
+        if download.next < download.total
+            && download.next / PROGRESS_INTERVAL > before / PROGRESS_INTERVAL
+        {
+            events::record(
+                Severity::Info,
+                "buffer",
+                format!(
+                    "Imported {} of the flight controller's {} buffered samples",
+                    download.next, download.total
+                ),
+            );
+        }
+        return Ok(());
+    }
+
+    // We have them all, so the device can drop them.
+    dispatcher.request(
+        wm,
+        &Request {
+            packet: Packet::new(MsgType::ClearBuffer, &download.total.to_be_bytes()),
+            response: MsgType::Ack,
+            timeout: Duration::from_millis(cfg.ack_timeout_ms as u64),
+            reply: None,
+        },
+    )?;
+    events::record(
+        Severity::Info,
+        "buffer",
+        format!(
+            "Imported {} samples from the flight controller's buffer; skipped {} already \
+             stored",
+            download.stored, download.skipped
+        ),
+    );
+    state.download = None;
+    Ok(())
+}
+
+fn request_chunk(
+    dispatcher: &mut Dispatcher,
+    wm: &mut WaterMonitor,
+    download: &Download,
+) -> Result<Vec<(i64, Readings)>, io::Error> {
+    let response = dispatcher.request(
+        wm,
+        &Request {
+            packet: Packet::new(MsgType::ReqBufferChunk, &download.next.to_be_bytes()),
+            response: MsgType::BufferChunk,
+            timeout: READ_TIMEOUT,
+            reply: None,
+        },
+    )?;
+    let payload = response.payload();
+
+    let start = u32::from_be_bytes(payload[0..4].try_into().unwrap());
+    let count = payload[4] as usize;
+    let remaining = (download.total - download.next) as usize;
+    if start != download.next || count == 0 || count > CHUNK_SAMPLES.min(remaining) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Asked the flight controller for buffered samples from {}, and got {} from {}",
+                download.next, count, start
+            ),
+        ));
+    }
+
+    let probe = config::get().ec.probe;
+    Ok(payload[5..5 + count * SAMPLE_SIZE]
+        .chunks(SAMPLE_SIZE)
+        .map(|s| {
+            let device_ms = u64::from_be_bytes(s[0..8].try_into().unwrap()) as i64;
+            let mut readings = Readings::from_bytes(&s[8..]);
+            sensors::scale(&mut readings, probe);
+            (device_ms - download.offset_ms, readings)
+        })
+        .collect())
+}
@@ -0,0 +1,175 @@
+//! Keeps the device's clock, which timestamps the samples it buffers, in step with ours.
+//! On connect, and every `flight_controller.clock_sync_mins`, we read its clock, note how
+//! far off it is, then set it to ours. The offset found at each sync, over the time since
+//! the last one, is its drift rate.
+
+use std::{
+    convert::TryInto,
+    io,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use serde::Serialize;
+
+use super::{
+    dispatch::{Dispatcher, Request},
+    MsgType, Packet,
+};
+use crate::{
+    config::FlightControllerConfig,
+    events::{self, Severity},
+    WaterMonitor, READ_TIMEOUT,
+};
+
+/// After a failed sync, wait this long before trying again.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Serialize)]
+pub struct ClockInfo {
+    pub last_sync: String,
+    /// How far ahead of ours the device's clock was, before we set it, in ms.
+    pub offset_ms: i64,
+    pub round_trip_ms: f32,
+    /// How fast the device's clock gains (or, if negative, loses) time on ours, in parts
+    /// per million. Known from the second sync.
+    pub drift_ppm: Option<f64>,
+    pub syncs: u64,
+}
+
+struct State {
+    info: Option<ClockInfo>,
+    /// When we last set the device's clock.
+    synced: Option<Instant>,
+    /// When we last tried, successfully or not.
+    attempted: Option<Instant>,
+    /// If the last attempt failed, so we only report the first of a run of failures.
+    failing: bool,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    info: None,
+    synced: None,
+    attempted: None,
+    failing: false,
+});
+
+/// The latest sync, for `/api/device`.
+pub fn info() -> Option<ClockInfo> {
+    STATE.lock().unwrap().info.clone()
+}
+
+/// The offset found by the first sync since `reset`, once there's been one.
+pub fn offset_since_reset() -> Option<i64> {
+    let state = STATE.lock().unwrap();
+    state.synced.and(state.info.as_ref()).map(|i| i.offset_ms)
+}
+
+/// Sync next time, eg because the device just connected, and may have restarted.
+pub fn reset() {
+    let mut state = STATE.lock().unwrap();
+    state.synced = None;
+    state.attempted = None;
+}
+
+fn due(state: &State, cfg: &FlightControllerConfig) -> bool {
+    let interval = Duration::from_secs(cfg.clock_sync_mins as u64 * 60);
+    match (state.synced, state.attempted) {
+        (_, Some(a)) if a.elapsed() < RETRY_INTERVAL => false,
+        (Some(s), _) => cfg.clock_sync_mins > 0 && s.elapsed() >= interval,
+        (None, _) => true,
+    }
+}
+
+/// Sync the device's clock, if it's due.
+pub(crate) fn sync_if_due(
+    dispatcher: &mut Dispatcher,
+    wm: &mut WaterMonitor,
+    cfg: &FlightControllerConfig,
+) {
+    if !due(&STATE.lock().unwrap(), cfg) {
+        return;
+    }
+    STATE.lock().unwrap().attempted = Some(Instant::now());
+
+    let result = sync(dispatcher, wm, cfg);
+    let mut state = STATE.lock().unwrap();
+    if let Err(e) = &result {
+        if !state.failing {
+            events::record(
+                Severity::Warning,
+                "clock",
+                format!("Problem syncing the flight controller's clock: {}", e),
+            );
+        }
+    }
+    state.failing = result.is_err();
+}
+
+fn sync(
+    dispatcher: &mut Dispatcher,
+    wm: &mut WaterMonitor,
+    cfg: &FlightControllerConfig,
+) -> Result<(), io::Error> {
+    let sent = Utc::now().timestamp_millis();
+    let start = Instant::now();
+    let response = dispatcher.request(
+        wm,
+        &Request {
+            packet: Packet::new(MsgType::ReqTime, &[]),
+            response: MsgType::Time,
+            timeout: READ_TIMEOUT,
+            reply: None,
+        },
+    )?;
+    let round_trip = start.elapsed();
+
+    // Assume the device read its clock halfway through the round trip.
+    let device_ms = u64::from_be_bytes(response.payload().try_into().unwrap()) as i64;
+    let offset_ms = device_ms - (sent + round_trip.as_millis() as i64 / 2);
+
+    let now = Utc::now();
+    dispatcher.request(
+        wm,
+        &Request {
+            packet: Packet::new(
+                MsgType::SetTime,
+                &(now.timestamp_millis() as u64).to_be_bytes(),
+            ),
+            response: MsgType::Ack,
+            timeout: Duration::from_millis(cfg.ack_timeout_ms as u64),
+            reply: None,
+        },
+    )?;
+
+    let mut state = STATE.lock().unwrap();
+    let drift_ppm = state
+        .synced
+        .map(|t| offset_ms as f64 / t.elapsed().as_millis() as f64 * 1e6);
+    let syncs = state.info.as_ref().map(|i| i.syncs).unwrap_or(0) + 1;
+    state.synced = Some(Instant::now());
+    state.info = Some(ClockInfo {
+        last_sync: now.to_rfc3339(),
+        offset_ms,
+        round_trip_ms: round_trip.as_secs_f32() * 1_000.,
+        drift_ppm,
+        syncs,
+    });
+    drop(state);
+
+    // Before the first sync, the clock may never have been set.
+    if drift_ppm.is_some() && offset_ms.unsigned_abs() > cfg.max_clock_offset_ms as u64 {
+        events::record(
+            Severity::Warning,
+            "clock",
+            format!(
+                "The flight controller's clock drifted {} ms from ours since the last sync; \
+                 it's been reset. Limit: {} ms.",
+                offset_ms, cfg.max_clock_offset_ms
+            ),
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,317 @@
+//! The one reader of the flight controller's frames. Requests are queued, and sent one at
+//! a time; incoming bytes are framed by their type and length, checked, and routed to
+//! the request waiting for them, or else to the handler registered for their type. A
+//! late response to a timed-out request still reaches its handler instead of confusing
+//! the next transaction.
+//!
+//! The queue is bounded, so while the link is wedged, eg by a bad cable, commands are
+//! refused rather than piling up. A queued request whose caller has stopped waiting is
+//! dropped unsent.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, Read, Write},
+    sync::{mpsc, Mutex},
+    time::{Duration, Instant},
+};
+
+use super::{DecodeError, MsgType, Packet, MAX_PACKET_SIZE};
+use crate::{
+    serial_stats::{self, Outcome},
+    WaterMonitor,
+};
+
+/// Requests waiting for the poller's next cycle, with when their callers stop waiting.
+static QUEUE: Mutex<VecDeque<(Request, Option<Instant>)>> = Mutex::new(VecDeque::new());
+
+/// The poller queues 2 requests a cycle, leaving the rest for commands.
+const MAX_QUEUED: usize = 8;
+
+pub struct Request {
+    pub packet: Packet,
+    /// The message type that answers this request.
+    pub response: MsgType,
+    pub timeout: Duration,
+    /// Where to send the response, or the failure. Without one, the response goes to the
+    /// handler for its type.
+    pub reply: Option<mpsc::Sender<Result<Packet, io::Error>>>,
+}
+
+/// Queue a request, to be sent on the poller's next cycle, unless it's still queued at
+/// `deadline`. Fails if the queue is full.
+pub fn enqueue(request: Request, deadline: Option<Instant>) -> Result<(), io::Error> {
+    let mut queue = QUEUE.lock().unwrap();
+    if queue.len() >= MAX_QUEUED {
+        serial_stats::record_queue_rejected();
+        return Err(io::Error::new(
+            io::ErrorKind::WouldBlock,
+            "Too many requests are waiting for the flight controller",
+        ));
+    }
+    queue.push_back((request, deadline));
+    serial_stats::record_queue_depth(queue.len());
+    Ok(())
+}
+
+/// Splits a byte stream into frames. There's no start-of-frame marker, so a frame
+/// boundary is wherever a known message type, its payload length, and a matching CRC
+/// line up. After a dropped byte, or connecting mid-frame, bytes are discarded one at a
+/// time until they do, which finds the next frame intact.
+#[derive(Default)]
+pub struct Framer {
+    buf: Vec<u8>,
+    /// If we're discarding bytes to find the next frame.
+    resyncing: bool,
+    /// Times we lost the frame boundary.
+    pub resyncs: u64,
+    /// Bytes discarded to find it again.
+    pub skipped: u64,
+    pub crc_failures: u64,
+}
+
+impl Framer {
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// The next complete frame, if there is one.
+    pub fn next_frame(&mut self) -> Option<Packet> {
+        loop {
+            match Packet::from_bytes(&self.buf) {
+                Ok(p) => {
+                    self.buf.drain(..p.payload().len() + 3);
+                    self.resyncing = false;
+                    return Some(p);
+                }
+                Err(DecodeError::Incomplete) => return None,
+                Err(e) => {
+                    if let DecodeError::Crc = e {
+                        self.crc_failures += 1;
+                    }
+                    // A CRC failure may be a header that only looked valid, eg a payload
+                    // byte that matches a message type, so don't trust its length
+                    // either; slide along a byte.
+                    self.buf.remove(0);
+                    self.skipped += 1;
+                    if !self.resyncing {
+                        self.resyncing = true;
+                        self.resyncs += 1;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Dispatcher {
+    framer: Framer,
+    handlers: HashMap<MsgType, mpsc::Sender<Packet>>,
+}
+
+impl Dispatcher {
+    /// Send frames of `msg_type` that no request is waiting for to `handler`.
+    pub fn register(&mut self, msg_type: MsgType, handler: mpsc::Sender<Packet>) {
+        self.handlers.insert(msg_type, handler);
+    }
+
+    /// Send every queued request, one at a time.
+    pub(crate) fn run(&mut self, wm: &mut WaterMonitor) {
+        let requests: Vec<_> = QUEUE.lock().unwrap().drain(..).collect();
+        serial_stats::record_queue_depth(0);
+        for (request, deadline) in requests {
+            if deadline.is_some_and(|d| Instant::now() >= d) {
+                serial_stats::record_queue_expired();
+                continue;
+            }
+            let result = self.transact(wm, &request);
+            match (request.reply, result) {
+                (Some(reply), result) => {
+                    let _ = reply.send(result);
+                }
+                (None, Ok(p)) => self.dispatch(p),
+                (None, Err(_)) => (),
+            }
+        }
+    }
+
+    /// Send a request now, ahead of the queue, and return its response. For the poller's
+    /// own requests that need the result straight away.
+    pub(crate) fn request(
+        &mut self,
+        wm: &mut WaterMonitor,
+        request: &Request,
+    ) -> Result<Packet, io::Error> {
+        self.transact(wm, request)
+    }
+
+    /// Fail every queued request; eg when there's no port to send them on.
+    pub fn fail_all(&mut self, kind: io::ErrorKind, msg: &str) {
+        let requests: Vec<_> = QUEUE.lock().unwrap().drain(..).collect();
+        serial_stats::record_queue_depth(0);
+        for (request, _) in requests {
+            if let Some(reply) = request.reply {
+                let _ = reply.send(Err(io::Error::new(kind, msg)));
+            }
+        }
+    }
+
+    fn dispatch(&self, packet: Packet) {
+        if let Some(handler) = self.handlers.get(&packet.message_type) {
+            let _ = handler.send(packet);
+        }
+    }
+
+    /// Route frames already received, eg late responses from an earlier transaction.
+    fn read_pending(&mut self, wm: &mut WaterMonitor) -> Result<usize, io::Error> {
+        let pending = wm.ser.bytes_to_read()? as usize;
+        let mut buf = vec![0; pending];
+        wm.ser.read_exact(&mut buf)?;
+        self.framer.push(&buf);
+
+        while let Some(p) = self.framer.next_frame() {
+            self.dispatch(p);
+        }
+        Ok(pending)
+    }
+
+    fn transact(&mut self, wm: &mut WaterMonitor, request: &Request) -> Result<Packet, io::Error> {
+        let start = Instant::now();
+        let (resyncs, skipped) = (self.framer.resyncs, self.framer.skipped);
+        let tx_buf = request.packet.to_bytes();
+        let crc_failures = self.framer.crc_failures;
+        let mut bytes_read = 0;
+
+        let result = (|| {
+            bytes_read += self.read_pending(wm)?;
+            wm.ser.write_all(&tx_buf)?;
+
+            let deadline = start + request.timeout;
+            let mut buf = [0; MAX_PACKET_SIZE];
+            loop {
+                while let Some(p) = self.framer.next_frame() {
+                    if p.message_type == request.response {
+                        return Ok(p);
+                    }
+                    self.dispatch(p);
+                }
+
+                if Instant::now() >= deadline {
+                    return Err(if self.framer.crc_failures > crc_failures {
+                        DecodeError::Crc.to_io()
+                    } else {
+                        io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            format!("No {:?} from the flight controller", request.response),
+                        )
+                    });
+                }
+
+                match wm.ser.read(&mut buf) {
+                    Ok(n) => {
+                        self.framer.push(&buf[..n]);
+                        bytes_read += n;
+                    }
+                    Err(e)
+                        if e.kind() == io::ErrorKind::TimedOut
+                            || e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        })();
+
+        let outcome = match &result {
+            Ok(_) => Outcome::Ok,
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => Outcome::Timeout,
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => Outcome::CrcFailure,
+            Err(_) => Outcome::IoError,
+        };
+        serial_stats::record(start.elapsed(), tx_buf.len(), bytes_read, outcome);
+        serial_stats::record_resyncs(self.framer.resyncs - resyncs, self.framer.skipped - skipped);
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::iter;
+
+    use proptest::{collection::vec, prelude::*, sample::select};
+
+    use super::*;
+    use crate::fc::CONTROLS_SIZE;
+
+    /// A frame, of any message type, with any payload.
+    fn frame() -> impl Strategy<Value = Vec<u8>> {
+        let types: Vec<_> = (0..=u8::MAX)
+            .filter_map(|b| MsgType::try_from(b).ok())
+            .collect();
+        select(types).prop_flat_map(|t| {
+            vec(any::<u8>(), t.payload_size()).prop_map(move |p| Packet::new(t, &p).to_bytes())
+        })
+    }
+
+    /// Every frame after the one a byte was dropped from, or connected in the middle of,
+    /// is found.
+    #[test]
+    fn the_framer_recovers_within_a_frame() {
+        let frames: Vec<_> = (0..4u8)
+            .map(|i| Packet::new(MsgType::Controls, &[i * 16 + 1; CONTROLS_SIZE]).to_bytes())
+            .collect();
+        let decode = |stream: &[u8]| {
+            let mut framer = Framer::default();
+            framer.push(stream);
+            let found: Vec<_> = iter::from_fn(|| framer.next_frame())
+                .map(|p| p.to_bytes())
+                .collect();
+            (found, framer.resyncs)
+        };
+
+        let mut dropped = frames.concat();
+        dropped.remove(7);
+        assert_eq!(decode(&dropped), (frames[1..].to_vec(), 1));
+
+        let joined = frames.concat();
+        assert_eq!(decode(&joined[5..]), (frames[1..].to_vec(), 1));
+    }
+
+    proptest! {
+        #[test]
+        fn any_bytes_are_framed_or_skipped(chunks in vec(vec(any::<u8>(), 0..64), 0..8)) {
+            let mut framer = Framer::default();
+            let mut total = 0;
+            let mut framed = 0;
+            for chunk in chunks {
+                total += chunk.len();
+                framer.push(&chunk);
+                while let Some(p) = framer.next_frame() {
+                    framed += p.payload().len() + 3;
+                }
+            }
+            prop_assert!(framed + framer.skipped as usize <= total);
+        }
+
+        #[test]
+        fn frames_are_found_however_they_arrive(
+            frames in vec(frame(), 1..6),
+            cuts in vec(any::<usize>(), 0..8),
+        ) {
+            let stream = frames.concat();
+            let mut cuts: Vec<_> = cuts.iter().map(|c| c % (stream.len() + 1)).collect();
+            cuts.extend([0, stream.len()]);
+            cuts.sort();
+
+            let mut framer = Framer::default();
+            let mut found = Vec::new();
+            for cut in cuts.windows(2) {
+                framer.push(&stream[cut[0]..cut[1]]);
+                while let Some(p) = framer.next_frame() {
+                    found.push(p.to_bytes());
+                }
+            }
+            prop_assert_eq!(found, frames);
+            prop_assert_eq!(framer.skipped, 0);
+        }
+    }
+}
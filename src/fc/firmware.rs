@@ -0,0 +1,476 @@
+//! Firmware updates for the flight controller, from the web UI. An uploaded image is
+//! checked, then handed to the poller, which owns the port: it checks the image is for
+//! this device, tells it to enter its USB DFU bootloader, and waits, with polling paused,
+//! for it to come back running the new firmware. Flashing is done with `dfu-util` while
+//! we wait; the status reports the command to run.
+//!
+//! Images start with a 32-byte header:
+//!
+//! | Bytes | |
+//! |---|---|
+//! | 0-3 | `AFW1` |
+//! | 4-19 | Model, in ASCII, padded with NULs |
+//! | 20 | Hardware revision |
+//! | 21-23 | Version: major, minor, patch |
+//! | 24-27 | Image length, as a big-endian u32 |
+//! | 28-31 | CRC-32 of the image, as a big-endian u32 |
+
+use std::{
+    convert::TryInto,
+    env, fs,
+    io::{self, Read},
+    mem,
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use rocket::{http::Status, response::content, Data};
+use serde::Serialize;
+
+use super::{
+    disabled,
+    dispatch::{Dispatcher, Request},
+    MsgType, Packet,
+};
+use crate::{
+    api::{self, ErrorResponse},
+    auth::Admin,
+    config::{self, FlightControllerConfig},
+    events::{self, Severity},
+    interlock::{self, FIRMWARE_UPDATE},
+    png, systemd, WaterMonitor, READ_TIMEOUT,
+};
+
+const MAGIC: &[u8; 4] = b"AFW1";
+const HEADER_SIZE: usize = 32;
+/// Larger than any flight controller's flash.
+const MAX_IMAGE_SIZE: usize = 2 * 1_024 * 1_024;
+
+/// Model, hardware revision, then version, as in the image header.
+pub const DEVICE_INFO_SIZE: usize = 16 + 1 + 3;
+
+/// How long the device has to leave serial for its bootloader.
+const BOOTLOADER_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long we wait for it to be flashed, and come back.
+const DFU_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    Idle,
+    /// Checked, and waiting for the poller.
+    Queued,
+    /// Told to enter its bootloader; waiting for it to leave serial.
+    EnteringBootloader,
+    /// In its bootloader, to be flashed with `dfu-util`.
+    Dfu,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+impl Stage {
+    fn in_progress(&self) -> bool {
+        matches!(self, Self::Queued | Self::EnteringBootloader | Self::Dfu)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ImageInfo {
+    pub model: String,
+    pub hardware_revision: u8,
+    pub version: String,
+    pub size: usize,
+}
+
+#[derive(Clone, Serialize)]
+pub struct FirmwareStatus {
+    pub stage: Stage,
+    /// The latest upload.
+    pub image: Option<ImageInfo>,
+    /// What to run to flash it, while in DFU mode.
+    pub command: Option<String>,
+    pub message: String,
+    pub updated: Option<String>,
+}
+
+struct State {
+    status: FirmwareStatus,
+    cancel: bool,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    status: FirmwareStatus {
+        stage: Stage::Idle,
+        image: None,
+        command: None,
+        message: String::new(),
+        updated: None,
+    },
+    cancel: false,
+});
+
+pub fn status() -> FirmwareStatus {
+    STATE.lock().unwrap().status.clone()
+}
+
+/// Move to `stage`, and record it as an event.
+fn set_stage(stage: Stage, message: String) {
+    set_stage_locked(&mut STATE.lock().unwrap(), stage, message);
+}
+
+fn set_stage_locked(state: &mut State, stage: Stage, message: String) {
+    let severity = match stage {
+        Stage::Failed => Severity::Warning,
+        _ => Severity::Info,
+    };
+    events::record(severity, "firmware", message.clone());
+
+    state.status.stage = stage;
+    state.status.message = message;
+    state.status.updated = Some(Utc::now().to_rfc3339());
+    if stage != Stage::Dfu {
+        state.status.command = None;
+    }
+}
+
+fn image_path() -> String {
+    env::temp_dir()
+        .join("water-mon-firmware.bin")
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn version_string(v: &[u8]) -> String {
+    format!("{}.{}.{}", v[0], v[1], v[2])
+}
+
+/// Model, hardware revision, and version, in the header's format.
+fn parse_info(buf: &[u8]) -> (String, u8, String) {
+    let model = String::from_utf8_lossy(&buf[..16])
+        .trim_end_matches('\0')
+        .to_owned();
+    (model, buf[16], version_string(&buf[17..20]))
+}
+
+/// Check an image's header and checksum. Returns its description, and the image
+/// without its header.
+fn verify(upload: &[u8]) -> Result<(ImageInfo, &[u8]), String> {
+    if upload.len() < HEADER_SIZE || &upload[..4] != MAGIC {
+        return Err("This isn't a firmware image; it has no `AFW1` header".to_owned());
+    }
+    let (header, image) = upload.split_at(HEADER_SIZE);
+
+    let len = u32::from_be_bytes(header[24..28].try_into().unwrap()) as usize;
+    if len != image.len() {
+        return Err(format!(
+            "The header says the image is {} bytes, but it's {}; is it truncated?",
+            len,
+            image.len()
+        ));
+    }
+    let crc = u32::from_be_bytes(header[28..32].try_into().unwrap());
+    if png::crc32(image) != crc {
+        return Err("The image failed its checksum; it may be corrupt".to_owned());
+    }
+
+    let (model, hardware_revision, version) = parse_info(&header[4..24]);
+    Ok((
+        ImageInfo {
+            model,
+            hardware_revision,
+            version,
+            size: image.len(),
+        },
+        image,
+    ))
+}
+
+/// The state of the latest firmware update.
+#[get("/device/firmware")]
+pub fn view_firmware() -> Result<content::Json<String>, ErrorResponse> {
+    if !config::get().flight_controller.enabled {
+        return Err(disabled());
+    }
+    Ok(content::Json(serde_json::to_string(&status()).unwrap()))
+}
+
+/// Upload a firmware image, and start updating the flight controller with it. Returns
+/// once it's checked; follow progress at `GET /api/device/firmware`.
+#[post("/device/firmware", data = "<data>")]
+pub fn update_firmware(_admin: Admin, data: Data) -> Result<content::Json<String>, ErrorResponse> {
+    if !config::get().flight_controller.enabled {
+        return Err(disabled());
+    }
+    if STATE.lock().unwrap().status.stage.in_progress() {
+        return Err(api::error(
+            Status::Conflict,
+            "A firmware update is already in progress",
+        ));
+    }
+    interlock::permit(&FIRMWARE_UPDATE)
+        .map_err(|blocked| interlock::denial(&FIRMWARE_UPDATE, &blocked))?;
+
+    let mut upload = Vec::new();
+    data.open()
+        .take((HEADER_SIZE + MAX_IMAGE_SIZE + 1) as u64)
+        .read_to_end(&mut upload)
+        .map_err(|e| api::error(Status::BadRequest, &e.to_string()))?;
+    if upload.len() > HEADER_SIZE + MAX_IMAGE_SIZE {
+        return Err(api::error(
+            Status::PayloadTooLarge,
+            &format!(
+                "Firmware images over {} MiB aren't supported",
+                MAX_IMAGE_SIZE >> 20
+            ),
+        ));
+    }
+
+    let (info, image) = verify(&upload).map_err(|e| {
+        events::record(
+            Severity::Warning,
+            "firmware",
+            format!("Rejected a firmware upload: {}", e),
+        );
+        api::error(Status::BadRequest, &e)
+    })?;
+    let mut state = STATE.lock().unwrap();
+    // Checked again, in case another upload got here first.
+    if state.status.stage.in_progress() {
+        return Err(api::error(
+            Status::Conflict,
+            "A firmware update is already in progress",
+        ));
+    }
+    fs::write(image_path(), image).map_err(|e| {
+        api::error(
+            Status::InternalServerError,
+            &format!("Problem saving the firmware image: {}", e),
+        )
+    })?;
+    state.status.image = Some(info.clone());
+    state.cancel = false;
+    set_stage_locked(
+        &mut state,
+        Stage::Queued,
+        format!(
+            "Firmware {} for {} rev {} ({} bytes) passed its checks; waiting to pause polling",
+            info.version, info.model, info.hardware_revision, info.size
+        ),
+    );
+    drop(state);
+
+    Ok(content::Json(serde_json::to_string(&status()).unwrap()))
+}
+
+/// Stop waiting for the flight controller to be flashed, and resume polling.
+#[delete("/device/firmware")]
+pub fn cancel_firmware(_admin: Admin) -> Result<content::Json<String>, ErrorResponse> {
+    if !config::get().flight_controller.enabled {
+        return Err(disabled());
+    }
+    let mut state = STATE.lock().unwrap();
+    if !state.status.stage.in_progress() {
+        return Err(api::error(
+            Status::Conflict,
+            "There's no firmware update in progress",
+        ));
+    }
+    if state.status.stage == Stage::Queued {
+        set_stage_locked(
+            &mut state,
+            Stage::Cancelled,
+            "Cancelled the firmware update before it started".to_owned(),
+        );
+    } else {
+        state.cancel = true;
+    }
+    drop(state);
+
+    Ok(content::Json(serde_json::to_string(&status()).unwrap()))
+}
+
+fn cancelled() -> bool {
+    mem::take(&mut STATE.lock().unwrap().cancel)
+}
+
+/// Run a queued update. Called by the poller, which polls nothing else until it's done,
+/// failed, or cancelled. Returns if there was one, since the device will have restarted.
+pub(crate) fn update_if_queued(
+    dispatcher: &mut Dispatcher,
+    wm: &mut WaterMonitor,
+    cfg: &FlightControllerConfig,
+) -> bool {
+    let image = {
+        let mut state = STATE.lock().unwrap();
+        let image = match (&state.status.image, state.status.stage) {
+            (Some(image), Stage::Queued) => image.clone(),
+            _ => return false,
+        };
+        set_stage_locked(
+            &mut state,
+            Stage::EnteringBootloader,
+            "Polling paused. Checking the firmware is for this flight controller".to_owned(),
+        );
+        image
+    };
+
+    if let Err((stage, message)) = update(dispatcher, wm, cfg, &image) {
+        set_stage(stage, message);
+    }
+    true
+}
+
+fn device_info(
+    dispatcher: &mut Dispatcher,
+    wm: &mut WaterMonitor,
+) -> Result<(String, u8, String), io::Error> {
+    let response = dispatcher.request(
+        wm,
+        &Request {
+            packet: Packet::new(MsgType::ReqDeviceInfo, &[]),
+            response: MsgType::DeviceInfo,
+            timeout: READ_TIMEOUT,
+            reply: None,
+        },
+    )?;
+    Ok(parse_info(response.payload()))
+}
+
+/// Wait until the device's port is present, or absent, pinging systemd's watchdog
+/// meanwhile. Returns the port if it's present.
+fn wait_for_port(
+    present: bool,
+    timeout: Duration,
+) -> Result<Option<WaterMonitor>, (Stage, String)> {
+    let start = Instant::now();
+    loop {
+        if cancelled() {
+            return Err((
+                Stage::Cancelled,
+                "Cancelled the firmware update; resuming polling".to_owned(),
+            ));
+        }
+        match WaterMonitor::new() {
+            Ok(port) if present => return Ok(Some(port)),
+            Err(_) if !present => return Ok(None),
+            _ => (),
+        }
+        if start.elapsed() >= timeout {
+            return Err((
+                Stage::Failed,
+                format!(
+                    "The flight controller didn't {} within {} s; resuming polling",
+                    if present {
+                        "come back after flashing"
+                    } else {
+                        "enter its bootloader"
+                    },
+                    timeout.as_secs()
+                ),
+            ));
+        }
+        systemd::ping_watchdog();
+        thread::sleep(CHECK_INTERVAL);
+    }
+}
+
+fn update(
+    dispatcher: &mut Dispatcher,
+    wm: &mut WaterMonitor,
+    cfg: &FlightControllerConfig,
+    image: &ImageInfo,
+) -> Result<(), (Stage, String)> {
+    let failed = |e: io::Error| {
+        (
+            Stage::Failed,
+            format!("Problem updating the flight controller's firmware: {}", e),
+        )
+    };
+
+    let (model, rev, version) = device_info(dispatcher, wm).map_err(failed)?;
+    if model != image.model || rev != image.hardware_revision {
+        return Err((
+            Stage::Failed,
+            format!(
+                "The firmware is for {} rev {}, but this flight controller is {} rev {}",
+                image.model, image.hardware_revision, model, rev
+            ),
+        ));
+    }
+
+    if cancelled() {
+        return Err((
+            Stage::Cancelled,
+            "Cancelled the firmware update; resuming polling".to_owned(),
+        ));
+    }
+    // Maintenance or a lockout may have started since the upload.
+    if let Err(blocked) = interlock::permit(&FIRMWARE_UPDATE) {
+        return Err((
+            Stage::Failed,
+            format!(
+                "{}; resuming polling",
+                interlock::denial_message(&FIRMWARE_UPDATE, &blocked)
+            ),
+        ));
+    }
+    set_stage(
+        Stage::EnteringBootloader,
+        format!(
+            "Telling the flight controller, running {}, to enter its bootloader",
+            version
+        ),
+    );
+    dispatcher
+        .request(
+            wm,
+            &Request {
+                packet: Packet::new(MsgType::EnterBootloader, &[]),
+                response: MsgType::Ack,
+                timeout: Duration::from_millis(cfg.ack_timeout_ms as u64),
+                reply: None,
+            },
+        )
+        .map_err(failed)?;
+    wait_for_port(false, BOOTLOADER_TIMEOUT)?;
+
+    let command = format!("dfu-util -a 0 -s 0x08000000:leave -D {}", image_path());
+    set_stage(
+        Stage::Dfu,
+        format!(
+            "The flight controller is in DFU mode. Flash it with `{}`; polling resumes once \
+             it's back",
+            command
+        ),
+    );
+    STATE.lock().unwrap().status.command = Some(command);
+
+    if let Some(port) = wait_for_port(true, DFU_TIMEOUT)? {
+        *wm = port;
+    }
+    // It may not be ready to answer straight away.
+    thread::sleep(CHECK_INTERVAL);
+    let (_, _, version) = device_info(dispatcher, wm).map_err(failed)?;
+    if version == image.version {
+        set_stage(
+            Stage::Done,
+            format!(
+                "The flight controller is back, running {}; polling resumed",
+                version
+            ),
+        );
+        Ok(())
+    } else {
+        Err((
+            Stage::Failed,
+            format!(
+                "The flight controller is back, but running {}, not {}; polling resumed",
+                version, image.version
+            ),
+        ))
+    }
+}
@@ -0,0 +1,408 @@
+//! Flow, from a pulse-output flow sensor on the return line, so a dead pump shows: with
+//! no flow past the probes, ORP and pH don't reflect the pool. Pulses are counted on one
+//! of the Pi's GPIO pins, woken by the kernel on each rising edge, on their own thread.
+//! Each poll cycle turns the count into a rate, the `flow` reading, averaged over
+//! `window_secs`. Below `min_lpm`, `gates`' readings are `no_flow`, and alert conditions
+//! on them aren't met. The volume per local day is kept in history's `flow_volume`.
+//! Needs a build with `--features gpio`.
+
+use std::{
+    collections::VecDeque,
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use chrono::{NaiveDate, Utc};
+use rocket::{http::Status, response::content};
+use rusqlite::{params, OptionalExtension};
+use serde::Serialize;
+
+use crate::{
+    api::{self, ErrorResponse},
+    config::{self, AppConfig, FlowConfig},
+    events::{self, Severity},
+    history,
+    memory::{Buffer, Policy},
+    registry,
+    tokens::Viewer,
+    tz, Readings, SensorError,
+};
+
+/// How often to check if flow has been enabled, or its pin changed.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long to wait before setting up the pin again, after a failure.
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often today's volume is saved to history.
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+const MAX_WINDOW_SECS: u32 = 3_600;
+
+/// An hour's, polling every 200 ms, and then some.
+const MAX_COUNTS: usize = 20_000;
+
+pub const BUFFER: Buffer = Buffer {
+    name: "flow.counts",
+    cap: MAX_COUNTS,
+    policy: Policy::DropOldest,
+    about: "Pulse counts over `flow.window_secs`, for the flow rate",
+};
+
+/// Days listed by `/api/flow`.
+const MAX_DAYS: i64 = 31;
+
+/// Rising edges counted since startup.
+static PULSES: AtomicU64 = AtomicU64::new(0);
+
+/// Why pulses can't be counted, if they can't.
+static COUNT_ERROR: Mutex<Option<String>> = Mutex::new(None);
+
+static STATE: Mutex<State> = Mutex::new(State {
+    counts: VecDeque::new(),
+    rate: None,
+    last_pulses: 0,
+    date: None,
+    liters: 0.,
+    saved: None,
+});
+
+struct State {
+    /// Pulse counts, each poll cycle, over the last `window_secs`.
+    counts: VecDeque<(Instant, u64)>,
+    /// In L/min; `None` until a cycle after the first.
+    rate: Option<f32>,
+    last_pulses: u64,
+    /// Local; `None` until the first cycle.
+    date: Option<NaiveDate>,
+    /// Today's volume.
+    liters: f64,
+    saved: Option<Instant>,
+}
+
+#[derive(Serialize)]
+struct DayVolume {
+    date: String,
+    liters: f64,
+}
+
+#[derive(Serialize)]
+struct FlowStatus {
+    /// In L/min.
+    rate: Option<f32>,
+    no_flow: bool,
+    today_liters: f64,
+    /// From history, most recent first, including today as last saved.
+    days: Vec<DayVolume>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Wait for rising edges on `pin`, counting each, until the config changes.
+#[cfg(feature = "gpio")]
+fn count(cfg: &FlowConfig) -> Result<(), io::Error> {
+    use std::{
+        cmp,
+        fs::{self, File},
+        io::{Read, Seek, SeekFrom},
+        os::unix::io::AsRawFd,
+        path::Path,
+    };
+
+    let dir = format!("/sys/class/gpio/gpio{}", cfg.pin);
+    if !Path::new(&dir).exists() {
+        fs::write("/sys/class/gpio/export", cfg.pin.to_string())?;
+    }
+    fs::write(format!("{}/direction", dir), "in")?;
+    fs::write(format!("{}/edge", dir), "rising")?;
+
+    let mut value = File::open(format!("{}/value", dir))?;
+    let mut buf = String::new();
+    // Clear the edge that's pending from opening it.
+    value.read_to_string(&mut buf)?;
+    *COUNT_ERROR.lock().unwrap() = None;
+
+    let mut checked = Instant::now();
+    loop {
+        let mut fd = libc::pollfd {
+            fd: value.as_raw_fd(),
+            events: libc::POLLPRI | libc::POLLERR,
+            revents: 0,
+        };
+        let ready = unsafe { libc::poll(&mut fd, 1, CHECK_INTERVAL.as_millis() as i32) };
+        match ready.cmp(&0) {
+            cmp::Ordering::Less => {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::Interrupted {
+                    return Err(e);
+                }
+            }
+            cmp::Ordering::Greater => {
+                value.seek(SeekFrom::Start(0))?;
+                buf.clear();
+                value.read_to_string(&mut buf)?;
+                PULSES.fetch_add(1, Ordering::Relaxed);
+            }
+            cmp::Ordering::Equal => (),
+        }
+
+        if checked.elapsed() >= CHECK_INTERVAL {
+            checked = Instant::now();
+            let current = config::get().flow;
+            if !current.enabled || current.pin != cfg.pin {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "gpio"))]
+fn count(_cfg: &FlowConfig) -> Result<(), io::Error> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "This build doesn't support GPIO",
+    ))
+}
+
+/// Check the flow sensor's settings, and that this build can count its pulses.
+pub fn check(cfg: &AppConfig) -> Result<(), io::Error> {
+    let invalid = |msg: String| {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Config error: {}", msg),
+        ))
+    };
+    let flow = &cfg.flow;
+    if !flow.enabled {
+        return Ok(());
+    }
+    if !cfg!(feature = "gpio") {
+        return invalid("`flow.enabled` needs a build with `--features gpio`".into());
+    }
+    if flow.pulses_per_liter <= 0. {
+        return invalid("`flow.pulses_per_liter` must be above 0".into());
+    }
+    if flow.window_secs == 0 || flow.window_secs > MAX_WINDOW_SECS {
+        return invalid(format!(
+            "`flow.window_secs` must be from 1 to {}",
+            MAX_WINDOW_SECS
+        ));
+    }
+    if flow.min_lpm < 0. {
+        return invalid("`flow.min_lpm` can't be negative".into());
+    }
+    if let Some(sensor) = flow
+        .gates
+        .iter()
+        .find(|s| s.as_str() == "flow" || registry::get(s).is_none())
+    {
+        return invalid(format!("`flow.gates` can't include `{}`", sensor));
+    }
+    if cfg.inputs.iter().any(|i| i.pin == flow.pin) {
+        return invalid(format!(
+            "Pin {} is used by both `flow` and an input",
+            flow.pin
+        ));
+    }
+    Ok(())
+}
+
+/// Count pulses, forever; run this on its own thread.
+pub fn run() {
+    loop {
+        let cfg = config::get().flow;
+        if !cfg.enabled {
+            *COUNT_ERROR.lock().unwrap() = None;
+            thread::sleep(CHECK_INTERVAL);
+            continue;
+        }
+        if let Err(e) = count(&cfg) {
+            // Warn once per run of failures.
+            let failing = COUNT_ERROR.lock().unwrap().replace(e.to_string()).is_some();
+            if !failing {
+                events::record(
+                    Severity::Warning,
+                    "flow",
+                    format!(
+                        "Problem counting the flow sensor's pulses on pin {}: {}",
+                        cfg.pin, e
+                    ),
+                );
+            }
+            thread::sleep(RETRY_INTERVAL);
+        }
+    }
+}
+
+fn save(date: NaiveDate, liters: f64) {
+    if !config::get().history.enabled {
+        return;
+    }
+    let result = history::write(|conn| {
+        conn.execute(
+            "INSERT INTO flow_volume (date, liters) VALUES (?1, ?2) \
+             ON CONFLICT (date) DO UPDATE SET liters = excluded.liters",
+            params![date.to_string(), liters],
+        )
+    });
+    if let Err(e) = result {
+        events::record(
+            Severity::Warning,
+            "flow",
+            format!("Problem saving the day's flow volume: {}", e),
+        );
+    }
+}
+
+/// Today's volume as last saved, so a restart carries on from it.
+fn saved_liters(date: NaiveDate) -> f64 {
+    history::open_reader()
+        .ok()
+        .and_then(|conn| {
+            conn.query_row(
+                "SELECT liters FROM flow_volume WHERE date = ?1",
+                params![date.to_string()],
+                |row| row.get(0),
+            )
+            .optional()
+            .ok()
+            .flatten()
+        })
+        .unwrap_or(0.)
+}
+
+/// Add the flow rate to readings, and total the day's volume. Called by the poller,
+/// each cycle with readings.
+pub fn apply(readings: &mut Readings) {
+    let cfg = config::get().flow;
+    if !cfg.enabled {
+        // So enabling it again starts a fresh window.
+        let mut state = STATE.lock().unwrap();
+        state.counts.clear();
+        state.rate = None;
+        return;
+    }
+    if COUNT_ERROR.lock().unwrap().is_some() {
+        readings.set("flow", Some(Err(SensorError::NotConnected)));
+        return;
+    }
+
+    let now = Instant::now();
+    let pulses = PULSES.load(Ordering::Relaxed);
+    let today = Utc::now()
+        .with_timezone(&tz::configured())
+        .naive_local()
+        .date();
+    let window = Duration::from_secs(cfg.window_secs as u64);
+
+    let mut finished = None;
+    let rate = {
+        let mut state = STATE.lock().unwrap();
+        let liters = (pulses - state.last_pulses) as f64 / cfg.pulses_per_liter as f64;
+        state.last_pulses = pulses;
+        match state.date {
+            Some(date) if date == today => state.liters += liters,
+            Some(date) => {
+                finished = Some((date, state.liters));
+                state.liters = liters;
+            }
+            None => state.liters = saved_liters(today) + liters,
+        }
+        state.date = Some(today);
+
+        // Keep one count from before the window, to measure it from.
+        if state.counts.len() == MAX_COUNTS {
+            state.counts.pop_front();
+            BUFFER.dropped(1);
+        }
+        state.counts.push_back((now, pulses));
+        while state.counts.len() > 2 && now - state.counts[1].0 >= window {
+            state.counts.pop_front();
+        }
+        BUFFER.note(state.counts.len());
+        let (start, start_pulses) = state.counts[0];
+        let mins = (now - start).as_secs_f32() / 60.;
+        state.rate =
+            (mins > 0.).then(|| (pulses - start_pulses) as f32 / cfg.pulses_per_liter / mins);
+        state.rate
+    };
+
+    readings.set("flow", Some(rate.ok_or(SensorError::NotStabilized)));
+
+    // Outside the lock, since the disk can be slow.
+    if let Some((date, liters)) = finished {
+        save(date, liters);
+    }
+    let save_due = {
+        let mut state = STATE.lock().unwrap();
+        let due = state.saved.is_none_or(|t| t.elapsed() >= SAVE_INTERVAL);
+        if due {
+            state.saved = Some(now);
+        }
+        due.then_some(state.liters)
+    };
+    if let Some(liters) = save_due {
+        save(today, liters);
+    }
+}
+
+/// If the flow sensor shows no flow. Not while its rate is unknown.
+pub fn no_flow(cfg: &FlowConfig) -> bool {
+    cfg.enabled
+        && COUNT_ERROR.lock().unwrap().is_none()
+        && STATE.lock().unwrap().rate.is_some_and(|r| r < cfg.min_lpm)
+}
+
+/// If a sensor's readings don't mean much now, for lack of flow. Takes the config, since
+/// it's checked per sensor and per alert condition, where getting it each time adds up.
+pub fn gated(cfg: &FlowConfig, sensor: &str) -> bool {
+    cfg.gates.iter().any(|s| s == sensor) && no_flow(cfg)
+}
+
+/// The flow rate, and volumes per day.
+#[get("/flow")]
+pub fn view_flow(_viewer: Viewer) -> Result<content::Json<String>, ErrorResponse> {
+    let cfg = config::get().flow;
+    if !cfg.enabled {
+        return Err(api::error(
+            Status::NotFound,
+            "No flow sensor; set `flow.enabled`",
+        ));
+    }
+
+    let days = history::open_reader()
+        .ok()
+        .and_then(|conn| {
+            let mut stmt = conn
+                .prepare("SELECT date, liters FROM flow_volume ORDER BY date DESC LIMIT ?1")
+                .ok()?;
+            let rows = stmt
+                .query_map(params![MAX_DAYS], |row| {
+                    Ok(DayVolume {
+                        date: row.get(0)?,
+                        liters: row.get(1)?,
+                    })
+                })
+                .ok()?;
+            rows.collect::<Result<Vec<_>, _>>().ok()
+        })
+        .unwrap_or_default();
+
+    let (rate, today_liters) = {
+        let state = STATE.lock().unwrap();
+        (state.rate, state.liters)
+    };
+    let result = FlowStatus {
+        rate,
+        no_flow: no_flow(&cfg),
+        today_liters,
+        days,
+        error: COUNT_ERROR.lock().unwrap().clone(),
+    };
+    Ok(content::Json(serde_json::to_string(&result).unwrap()))
+}
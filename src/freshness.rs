@@ -0,0 +1,81 @@
+//! When each sensor last had a fresh reading. Probes settle at different rates: EC can
+//! report not stabilized in a cycle where the others read fine. So a sensor that's not
+//! stabilized keeps its last reading, rather than going to error for the cycle, and each
+//! sensor is stale once its own last reading is older than `status.stale_secs`, however
+//! recent the cycle. Alert rules don't test stale sensors. Readings list each sensor's
+//! last update, as `updated_at`.
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use serde::{Serialize, Serializer};
+
+use crate::{
+    config::StatusConfig,
+    history,
+    registry::{self, SensorMap},
+    Readings, SensorError,
+};
+
+#[derive(Clone, Copy)]
+struct Fresh {
+    value: f32,
+    at: Instant,
+    /// The same, in ms since the epoch.
+    at_ms: i64,
+}
+
+static LAST: Mutex<SensorMap<Fresh>> = Mutex::new(SensorMap::empty());
+
+/// When a sensor was last updated, serialized as an RFC 3339 time.
+#[derive(Clone, Copy)]
+pub struct Updated(i64);
+
+impl Serialize for Updated {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&history::format_time(self.0))
+    }
+}
+
+/// Note each fresh reading, and give sensors that aren't stabilized their last one.
+/// Called by the poller, for each cycle's readings.
+pub fn apply(readings: &mut Readings) {
+    let mut last = LAST.lock().unwrap();
+    let (now, now_ms) = (Instant::now(), Utc::now().timestamp_millis());
+    for id in registry::ids() {
+        match readings.get(id) {
+            Some(Ok(value)) => last.set(
+                id,
+                Some(Fresh {
+                    value,
+                    at: now,
+                    at_ms: now_ms,
+                }),
+            ),
+            Some(Err(SensorError::NotStabilized)) => {
+                if let Some(fresh) = last.get(id) {
+                    readings.set(id, Some(Ok(fresh.value)));
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+/// How long since a sensor's last fresh reading.
+pub fn age(id: &str) -> Option<Duration> {
+    LAST.lock().unwrap().get(id).map(|f| f.at.elapsed())
+}
+
+/// If a sensor's last fresh reading is too old to trust.
+pub fn stale(id: &str, cfg: &StatusConfig) -> bool {
+    age(id).is_some_and(|age| age.as_secs_f32() > cfg.stale_secs as f32)
+}
+
+/// When each sensor with a reading was last updated.
+pub fn updated_at() -> SensorMap<Updated> {
+    LAST.lock().unwrap().map(|_, f| Updated(f.at_ms))
+}
@@ -0,0 +1,21 @@
+//! What the fuzz targets in `fuzz/` call: the decoders for bytes from a cable, which
+//! must take anything without panicking. Like `bench`, it's only a way in; not an API.
+
+use crate::Readings;
+
+/// Decode `data` as a readings response, and what that encodes to again.
+pub fn readings(data: &[u8]) {
+    let readings = Readings::from_bytes(data);
+    Readings::from_bytes(&readings.to_bytes());
+    let _ = crate::bytes_to_float(data);
+}
+
+/// Decode `data` as a flight controller's frame. One that decodes re-encodes to one
+/// that does too.
+#[cfg(feature = "flight-controller")]
+pub fn fc_packet(data: &[u8]) {
+    use crate::fc::Packet;
+    if let Ok(packet) = Packet::from_bytes(data) {
+        assert!(Packet::from_bytes(&packet.to_bytes()).is_ok());
+    }
+}
@@ -0,0 +1,80 @@
+//! A summary of the app's health, at `/api/health`.
+
+use rocket::response::content;
+use serde::Serialize;
+
+use crate::{
+    activity::{self, PollingStatus},
+    config,
+    exporters::{self, ExporterStatus},
+    instance::{self, Instance},
+    interlock::{self, InterlockStatus},
+    maintenance::{self, MaintenanceStatus},
+    metrics::{self, MetricsStatus},
+    net,
+    poller::{self, PollerStatus},
+    reliability,
+    supervisor::{self, ComponentStatus},
+    system::{self, GuardStatus},
+    systemd,
+    verify::{self, VerifyReport},
+    version,
+};
+
+#[derive(Serialize)]
+pub struct Health {
+    pub version: &'static str,
+    /// A newer release, eg `v1.2.0`, with `updates.check`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub update_available: Option<String>,
+    pub instance: Instance,
+    /// URLs other devices on the network can likely open the app at.
+    pub addresses: Vec<String>,
+    /// The sockets systemd passed, with socket activation.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub activated_sockets: Vec<String>,
+    pub poller: PollerStatus,
+    /// Whether the poller is running fast, or slowed down while no clients are watching.
+    pub polling: PollingStatus,
+    /// Background components, and how often they've been restarted after a panic.
+    pub components: Vec<ComponentStatus>,
+    /// The latest history check: the quick one at startup, or a later `/api/storage/verify`.
+    pub history_check: Option<VerifyReport>,
+    /// The percentage of the last day's poll cycles with fully valid readings; `None`
+    /// without history, or cycles.
+    pub reliability_24h_pct: Option<f32>,
+    /// If history is paused for lack of disk space.
+    pub resources: GuardStatus,
+    /// Readings sent, and failed requests, per exporter.
+    pub exporters: Vec<ExporterStatus>,
+    /// StatsD or Graphite pushes.
+    pub metrics: MetricsStatus,
+    /// `None` unless in maintenance.
+    pub maintenance: Option<MaintenanceStatus>,
+    /// What blocks actuators.
+    pub interlock: InterlockStatus,
+}
+
+#[get("/health")]
+pub fn view_health() -> content::Json<String> {
+    let health = Health {
+        version: env!("CARGO_PKG_VERSION"),
+        update_available: version::update_available(),
+        instance: instance::get(),
+        addresses: net::advertised_urls(&config::get().server),
+        activated_sockets: systemd::activation()
+            .map(|a| a.addresses.iter().map(|a| a.to_string()).collect())
+            .unwrap_or_default(),
+        poller: poller::status(),
+        polling: activity::status(),
+        components: supervisor::status(),
+        history_check: verify::last_check(),
+        reliability_24h_pct: reliability::last_day().and_then(|d| d.valid_pct),
+        resources: system::guard_status(),
+        exporters: exporters::status(),
+        metrics: metrics::status(),
+        maintenance: maintenance::status(),
+        interlock: interlock::status(),
+    };
+    content::Json(serde_json::to_string(&health).unwrap())
+}
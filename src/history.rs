@@ -0,0 +1,1268 @@
+//! Readings history, stored in SQLite. The poller hands each successful reading to a
+//! writer thread, which inserts them in batches so the poller never waits on the disk.
+//! Queries can return raw samples, or group them into fixed time buckets in SQL.
+//!
+//! Older samples are rolled up into 1-minute, then 1-hour, aggregate rows by the
+//! compactor. Queries read all tiers, so callers don't need to know which holds what.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io, iter,
+    sync::{
+        mpsc::{self, Receiver, SyncSender},
+        Mutex, MutexGuard,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use rocket::{http::Status, response::content};
+use rusqlite::{params, Connection, OpenFlags, ToSql};
+use serde_json::{json, Map, Value};
+
+use crate::{
+    api::{self, ErrorResponse},
+    config::{self, AppConfig, HistoryConfig},
+    device_meta,
+    events::{self, Severity},
+    maintenance,
+    memory::{Buffer, Policy},
+    outages, registry, system,
+    tokens::Viewer,
+    tz, warmup, Readings, REFRESH_INTERVAL,
+};
+
+/// Samples waiting to be written. If the disk stalls for longer than this holds,
+/// newer samples are dropped rather than blocking the poller.
+const QUEUE_SIZE: usize = 4_096;
+
+pub const BUFFER: Buffer = Buffer {
+    name: "history.writes",
+    cap: QUEUE_SIZE,
+    policy: Policy::DropNewest,
+    about: "Samples waiting to be written to the history database",
+};
+
+/// How long the writer collects samples before inserting them in one transaction.
+const BATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Max raw samples per query; longer ranges need a bucket.
+const MAX_SAMPLES: usize = 10_000;
+/// Max buckets per query.
+const MAX_BUCKETS: i64 = 10_000;
+
+/// Default query range, when `from` isn't given.
+const DEFAULT_RANGE: Duration = Duration::from_secs(60 * 60);
+
+/// Imported samples this close to a stored one are taken to be the same reading.
+const IMPORT_TOLERANCE: Duration = Duration::from_secs(1);
+
+/// Stored as `PRAGMA user_version`. Bump this when changing the schema, and migrate
+/// older databases, and backups, in `open`.
+pub const SCHEMA_VERSION: i32 = 9;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS samples (
+    -- Milliseconds since the Unix epoch.
+    time INTEGER NOT NULL,
+    -- NULL if the reading was an error.
+    T REAL,
+    pH REAL,
+    ORP REAL,
+    ec REAL,
+    -- 1 if taken during maintenance, which leaves it out of stats and compaction.
+    maintenance INTEGER NOT NULL DEFAULT 0,
+    -- A bit per sensor, in registry order, set if it was stabilizing after power-up,
+    -- which leaves its reading out of stats and compaction.
+    stabilizing INTEGER NOT NULL DEFAULT 0
+    -- Then a column per sensor added since, by `add_sensor_columns`.
+);
+CREATE INDEX IF NOT EXISTS samples_time ON samples (time);
+
+-- Aggregates, by bucket start time. `*_n` is the number of valid samples.
+CREATE TABLE IF NOT EXISTS samples_1m (
+    time INTEGER PRIMARY KEY,
+    count INTEGER NOT NULL,
+    T_avg REAL, T_min REAL, T_max REAL, T_n INTEGER NOT NULL,
+    pH_avg REAL, pH_min REAL, pH_max REAL, pH_n INTEGER NOT NULL,
+    ORP_avg REAL, ORP_min REAL, ORP_max REAL, ORP_n INTEGER NOT NULL,
+    ec_avg REAL, ec_min REAL, ec_max REAL, ec_n INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS samples_1h (
+    time INTEGER PRIMARY KEY,
+    count INTEGER NOT NULL,
+    T_avg REAL, T_min REAL, T_max REAL, T_n INTEGER NOT NULL,
+    pH_avg REAL, pH_min REAL, pH_max REAL, pH_n INTEGER NOT NULL,
+    ORP_avg REAL, ORP_min REAL, ORP_max REAL, ORP_n INTEGER NOT NULL,
+    ec_avg REAL, ec_min REAL, ec_max REAL, ec_n INTEGER NOT NULL
+);
+
+-- Rows that failed verification, moved out of their tables rather than deleted.
+CREATE TABLE IF NOT EXISTS quarantine (
+    -- When quarantined, in ms since the Unix epoch.
+    quarantined INTEGER NOT NULL,
+    source TEXT NOT NULL,
+    reason TEXT NOT NULL,
+    time INTEGER,
+    -- The original row, as a JSON object.
+    row TEXT NOT NULL
+);
+
+-- Poll cycles per minute, by outcome, for `/api/reliability`. `cycles` counts every
+-- cycle; the rest are `valid`, a failure, or `sensor_<id>` and `error_<kind>` for each
+-- sensor in error.
+CREATE TABLE IF NOT EXISTS reliability (
+    time INTEGER NOT NULL,
+    cause TEXT NOT NULL,
+    count INTEGER NOT NULL,
+    PRIMARY KEY (time, cause)
+);
+
+-- Maintenance windows, with what was done, by `/api/maintenance/start`.
+CREATE TABLE IF NOT EXISTS maintenance (
+    start INTEGER NOT NULL,
+    -- When it was stopped, or will expire.
+    end INTEGER NOT NULL,
+    note TEXT NOT NULL
+);
+
+-- Binary inputs' changes of state, eg a float switch's. The first row after startup is
+-- the state it was found in.
+CREATE TABLE IF NOT EXISTS input_changes (
+    time INTEGER NOT NULL,
+    input TEXT NOT NULL,
+    active INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS input_changes_time ON input_changes (input, time);
+
+-- The flow sensor's volume per local day, eg `2024-06-01`, in liters.
+CREATE TABLE IF NOT EXISTS flow_volume (
+    date TEXT PRIMARY KEY,
+    liters REAL NOT NULL
+);
+
+-- The time of the last sample stored per device, `local` for this one, so the stretch
+-- since can be checked for an outage after a restart.
+CREATE TABLE IF NOT EXISTS last_stored (
+    device TEXT PRIMARY KEY,
+    time INTEGER NOT NULL
+);
+
+-- Readings pushed by other devices, eg an ESP32 by a well, at the time each was taken,
+-- with a column per sensor, added by `add_sensor_columns`. They aren't compacted.
+CREATE TABLE IF NOT EXISTS pushed_samples (
+    device TEXT NOT NULL,
+    time INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS pushed_samples_time ON pushed_samples (device, time);
+
+-- Stretches without samples that buffered samples didn't fill, by cause, eg
+-- `app_down`; see `outages`.
+CREATE TABLE IF NOT EXISTS outages (
+    start INTEGER NOT NULL,
+    end INTEGER NOT NULL,
+    cause TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS outages_start ON outages (start);
+
+-- Stretches that may be outages, until buffered samples have had a chance to fill
+-- them. `end` is null until the device is read again.
+CREATE TABLE IF NOT EXISTS pending_outages (
+    start INTEGER NOT NULL,
+    end INTEGER,
+    cause TEXT NOT NULL
+);
+";
+
+/// Aggregate tables, finest first, with their bucket lengths in ms.
+pub const AGGREGATE_TIERS: [(&str, i64); 2] = [("samples_1m", 60_000), ("samples_1h", 3_600_000)];
+
+static SENDER: Mutex<Option<SyncSender<Sample>>> = Mutex::new(None);
+
+/// Held while writing to the database, so restoring a backup can stop writers.
+static WRITE_LOCK: Mutex<()> = Mutex::new(());
+
+struct Sample {
+    /// A device that pushed its readings; `None` for this one.
+    device: Option<String>,
+    time: i64,
+    /// By sensor, in registry order; `None` for errors, and sensors without readings.
+    values: [Option<f32>; registry::COUNT],
+    maintenance: bool,
+    /// `warmup::mask`.
+    stabilizing: i64,
+}
+
+/// Open the database and start the writer, if history is enabled.
+pub fn start(cfg: &HistoryConfig) -> Result<(), io::Error> {
+    if !cfg.enabled {
+        return Ok(());
+    }
+
+    let conn = open(&cfg.path)?;
+    outages::start(&conn).map_err(|e| db_error(&cfg.path, e))?;
+    let (tx, rx) = mpsc::sync_channel(QUEUE_SIZE);
+    *SENDER.lock().unwrap() = Some(tx);
+
+    thread::Builder::new()
+        .name("history writer".into())
+        .spawn(move || write_samples(conn, rx))?;
+
+    Ok(())
+}
+
+pub fn db_error(path: &str, e: rusqlite::Error) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Other,
+        format!("Problem with the history database `{}`: {}", path, e),
+    )
+}
+
+/// Open the database for writing, creating it if needed.
+pub fn open(path: &str) -> Result<Connection, io::Error> {
+    let conn = Connection::open(path).map_err(|e| db_error(path, e))?;
+    // WAL lets queries run while the writer inserts. Writers wait on each other.
+    conn.query_row("PRAGMA journal_mode = WAL", [], |_| Ok(()))
+        .and_then(|_| conn.busy_timeout(Duration::from_secs(10)))
+        .and_then(|_| conn.execute_batch(SCHEMA))
+        .map_err(|e| db_error(path, e))?;
+
+    match schema_version(&conn).map_err(|e| db_error(path, e))? {
+        // New, from before we versioned the schema, from before the quarantine table,
+        // which `SCHEMA` has now created, from before dissolved oxygen, which
+        // `add_sensor_columns` adds, from before the reliability table, which `SCHEMA`
+        // has also created, from before maintenance, which needs a column, from before
+        // the input changes table, which `SCHEMA` has created, from before probe
+        // warm-up, which needs a column, from before outages, whose tables `SCHEMA` has
+        // also created, or from before pushed samples, whose table it has too.
+        0..=8 => {
+            let migrate = || -> Result<(), rusqlite::Error> {
+                for column in ["maintenance", "stabilizing"] {
+                    if !has_column(&conn, "samples", column)? {
+                        conn.execute_batch(&format!(
+                            "ALTER TABLE samples ADD COLUMN {} INTEGER NOT NULL DEFAULT 0",
+                            column
+                        ))?;
+                    }
+                }
+                conn.pragma_update(None, "user_version", SCHEMA_VERSION)
+            };
+            migrate().map_err(|e| db_error(path, e))?
+        }
+        SCHEMA_VERSION => (),
+        v => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "The history database `{}` has schema version {}, from a newer version of \
+                     this app; this version uses {}.",
+                    path, v, SCHEMA_VERSION
+                ),
+            ))
+        }
+    }
+    add_sensor_columns(&conn).map_err(|e| db_error(path, e))?;
+
+    Ok(conn)
+}
+
+pub fn has_column(conn: &Connection, table: &str, column: &str) -> Result<bool, rusqlite::Error> {
+    conn.query_row(
+        "SELECT COUNT(*) FROM pragma_table_info(?1) WHERE name = ?2",
+        params![table, column],
+        |row| row.get::<_, i64>(0),
+    )
+    .map(|n| n > 0)
+}
+
+/// Add columns for sensors in the registry that tables don't have yet. New sensors
+/// don't need a schema version, since older versions ignore columns they don't know.
+fn add_sensor_columns(conn: &Connection) -> Result<(), rusqlite::Error> {
+    let has_column = |table: &str, column: &str| has_column(conn, table, column);
+
+    for name in registry::ids() {
+        for table in ["samples", "pushed_samples"] {
+            if !has_column(table, name)? {
+                conn.execute_batch(&format!("ALTER TABLE {} ADD COLUMN {} REAL", table, name))?;
+            }
+        }
+        for (table, _) in AGGREGATE_TIERS.iter() {
+            if !has_column(table, &format!("{}_n", name))? {
+                conn.execute_batch(&format!(
+                    "ALTER TABLE {0} ADD COLUMN {1}_avg REAL; \
+                     ALTER TABLE {0} ADD COLUMN {1}_min REAL; \
+                     ALTER TABLE {0} ADD COLUMN {1}_max REAL; \
+                     ALTER TABLE {0} ADD COLUMN {1}_n INTEGER NOT NULL DEFAULT 0;",
+                    table, name
+                ))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn schema_version(conn: &Connection) -> Result<i32, rusqlite::Error> {
+    conn.query_row("PRAGMA user_version", [], |row| row.get(0))
+}
+
+/// Run a write on its own connection, for rows outside the writer's batches, like
+/// maintenance windows. Fails if history is disabled.
+pub fn write<T>(f: impl FnOnce(&Connection) -> Result<T, rusqlite::Error>) -> Result<T, io::Error> {
+    let cfg = config::get().history;
+    if !cfg.enabled {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "History is disabled",
+        ));
+    }
+    let conn = open(&cfg.path)?;
+    let _lock = lock_writes();
+    f(&conn).map_err(|e| db_error(&cfg.path, e))
+}
+
+/// Stop other writers until the guard is dropped.
+pub fn lock_writes() -> MutexGuard<'static, ()> {
+    WRITE_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Open the database for a query.
+pub fn open_reader() -> Result<Connection, ErrorResponse> {
+    let cfg = config::get().history;
+    if !cfg.enabled {
+        return Err(api::error(Status::NotFound, "History is disabled"));
+    }
+
+    let conn = Connection::open_with_flags(&cfg.path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .and_then(|c| c.busy_timeout(Duration::from_secs(5)).map(|_| c))
+        .map_err(query_error)?;
+    Ok(conn)
+}
+
+pub fn query_error(e: rusqlite::Error) -> ErrorResponse {
+    api::error(
+        Status::InternalServerError,
+        &format!("Problem querying history: {}", e),
+    )
+}
+
+fn values(readings: &Readings) -> [Option<f32>; registry::COUNT] {
+    readings.values().map(|r| r.and_then(|r| r.ok()))
+}
+
+/// `time`, then a column per sensor.
+fn insert_columns() -> (String, String) {
+    let columns: Vec<_> = iter::once("time").chain(registry::ids()).collect();
+    let placeholders: Vec<_> = (1..=columns.len()).map(|i| format!("?{}", i)).collect();
+    (columns.join(", "), placeholders.join(", "))
+}
+
+/// Queue a successful reading to be stored, unless the disk is nearly full. Called by
+/// the poller.
+pub fn record(readings: &Readings) {
+    if system::history_paused() {
+        system::skip_sample();
+        return;
+    }
+    queue(Sample {
+        device: None,
+        time: Utc::now().timestamp_millis(),
+        values: values(readings),
+        maintenance: maintenance::active(),
+        stabilizing: warmup::mask(),
+    });
+}
+
+/// Queue readings `device` pushed, taken at `time_ms`, to be stored, unless the disk is
+/// nearly full. Called by `ingest`.
+pub fn record_pushed(device: &str, time_ms: i64, readings: &Readings) {
+    if system::history_paused() {
+        system::skip_sample();
+        return;
+    }
+    queue(Sample {
+        device: Some(device.to_owned()),
+        time: time_ms,
+        values: values(readings),
+        maintenance: false,
+        stabilizing: 0,
+    });
+}
+
+fn queue(sample: Sample) {
+    if let Some(tx) = SENDER.lock().unwrap().as_ref() {
+        match tx.try_send(sample) {
+            Ok(()) => BUFFER.added(),
+            Err(_) => BUFFER.dropped(1),
+        }
+    }
+}
+
+/// Store readings taken while we weren't polling, eg buffered by the flight controller,
+/// with their times in ms since the epoch. Skips any that overlap what's stored: a raw
+/// sample within `IMPORT_TOLERANCE`, or an aggregate row for its bucket. Returns how
+/// many were stored.
+pub fn import(readings: &[(i64, Readings)]) -> Result<usize, io::Error> {
+    import_into(&config::get().history.path, readings)
+}
+
+/// `import`, into the database at `path`.
+pub fn import_into(path: &str, readings: &[(i64, Readings)]) -> Result<usize, io::Error> {
+    let mut conn = open(path)?;
+    let _lock = lock_writes();
+
+    let result = (|| -> Result<usize, rusqlite::Error> {
+        let tx = conn.transaction()?;
+        let mut stored = 0;
+        {
+            let (columns, placeholders) = insert_columns();
+            let tolerance = format!("?{}", registry::COUNT + 2);
+            let mut stmt = tx.prepare_cached(&format!(
+                "INSERT INTO samples ({0}) SELECT {1}
+                 WHERE NOT EXISTS (SELECT 1 FROM samples WHERE time BETWEEN ?1 - {2} AND ?1 + {2})
+                 AND NOT EXISTS (SELECT 1 FROM samples_1m WHERE time = ?1 - ?1 % 60000)
+                 AND NOT EXISTS (SELECT 1 FROM samples_1h WHERE time = ?1 - ?1 % 3600000)",
+                columns, placeholders, tolerance
+            ))?;
+            let tolerance = IMPORT_TOLERANCE.as_millis() as i64;
+            for (time, r) in readings {
+                let values = values(r);
+                let mut params: Vec<&dyn ToSql> = vec![time];
+                params.extend(values.iter().map(|v| v as &dyn ToSql));
+                params.push(&tolerance);
+                stored += stmt.execute(&params[..])?;
+            }
+        }
+        tx.commit()?;
+        Ok(stored)
+    })();
+    result.map_err(|e| db_error(path, e))
+}
+
+fn write_samples(mut conn: Connection, rx: Receiver<Sample>) {
+    // Only report the first failure in a run, so a full disk doesn't flood the log.
+    let mut failing = false;
+
+    while let Ok(first) = rx.recv() {
+        BUFFER.removed();
+        let mut batch = vec![first];
+        let deadline = Instant::now() + BATCH_INTERVAL;
+        while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+            match rx.recv_timeout(remaining) {
+                Ok(s) => {
+                    BUFFER.removed();
+                    batch.push(s);
+                }
+                Err(_) => break,
+            }
+        }
+
+        let result = {
+            let _lock = lock_writes();
+            insert(&mut conn, &batch)
+        };
+        match result {
+            Ok(()) => failing = false,
+            Err(e) => {
+                if !failing {
+                    events::record(
+                        Severity::Warning,
+                        "history",
+                        format!("Problem saving readings history: {}", e),
+                    );
+                }
+                failing = true;
+            }
+        }
+    }
+}
+
+fn insert(conn: &mut Connection, batch: &[Sample]) -> Result<(), rusqlite::Error> {
+    let tx = conn.transaction()?;
+    {
+        let (columns, placeholders) = insert_columns();
+        let mut local = tx.prepare_cached(&format!(
+            "INSERT INTO samples ({}, maintenance, stabilizing) VALUES ({}, ?{}, ?{})",
+            columns,
+            placeholders,
+            registry::COUNT + 2,
+            registry::COUNT + 3
+        ))?;
+        let mut pushed = tx.prepare_cached(&format!(
+            "INSERT INTO pushed_samples ({}, device) VALUES ({}, ?{})",
+            columns,
+            placeholders,
+            registry::COUNT + 2
+        ))?;
+        let mut last_stored = BTreeMap::new();
+        for s in batch {
+            let mut params: Vec<&dyn ToSql> = vec![&s.time];
+            params.extend(s.values.iter().map(|v| v as &dyn ToSql));
+            match &s.device {
+                None => {
+                    params.push(&s.maintenance);
+                    params.push(&s.stabilizing);
+                    local.execute(&params[..])?;
+                }
+                Some(device) => {
+                    params.push(device);
+                    pushed.execute(&params[..])?;
+                }
+            }
+            let device = s.device.as_deref().unwrap_or(device_meta::LOCAL);
+            let last = last_stored.entry(device).or_insert(s.time);
+            *last = s.time.max(*last);
+        }
+        for (device, last) in last_stored {
+            tx.execute(
+                "INSERT INTO last_stored (device, time) VALUES (?1, ?2) \
+                 ON CONFLICT (device) DO UPDATE SET time = MAX(time, excluded.time)",
+                params![device, last],
+            )?;
+        }
+    }
+    tx.commit()
+}
+
+/// Parse a duration like `200ms`, `30s`, `5m`, `1h`, or `7d`, into milliseconds.
+pub fn parse_duration(s: &str) -> Option<i64> {
+    let split = s.find(|c: char| !c.is_ascii_digit())?;
+    let (num, unit) = s.split_at(split);
+    let num: i64 = num.parse().ok()?;
+
+    let unit_ms = match unit {
+        "ms" => 1,
+        "s" => 1_000,
+        "m" => 60_000,
+        "h" => 3_600_000,
+        "d" => 86_400_000,
+        _ => return None,
+    };
+
+    num.checked_mul(unit_ms).filter(|ms| *ms > 0)
+}
+
+pub fn parse_time(s: &str, name: &str, zone: Tz) -> Result<DateTime<Utc>, ErrorResponse> {
+    tz::parse_time(s, zone).ok_or_else(|| {
+        api::error(
+            Status::BadRequest,
+            &format!(
+                "`{}` must be an RFC 3339 time, eg `2021-06-01T12:00:00Z`, a local time, eg \
+                 `2021-06-01T12:00:00`, or a date; got `{}`, which isn't one, or is a local \
+                 time skipped by a clock change",
+                name, s
+            ),
+        )
+    })
+}
+
+pub fn format_time(ms: i64) -> String {
+    Utc.timestamp_millis_opt(ms).unwrap().to_rfc3339()
+}
+
+/// Which statistics to return per bucket.
+#[derive(Clone, Copy, PartialEq)]
+enum Agg {
+    All,
+    Avg,
+    Min,
+    Max,
+}
+
+impl Agg {
+    fn parse(s: Option<&str>) -> Result<Self, ErrorResponse> {
+        match s {
+            None | Some("all") => Ok(Self::All),
+            Some("avg") => Ok(Self::Avg),
+            Some("min") => Ok(Self::Min),
+            Some("max") => Ok(Self::Max),
+            Some(s) => Err(api::error(
+                Status::BadRequest,
+                &format!("`agg` must be `avg`, `min`, `max` or `all`; got `{}`", s),
+            )),
+        }
+    }
+
+    fn includes(&self, other: Self) -> bool {
+        *self == Self::All || *self == other
+    }
+}
+
+/// How samples and buckets are laid out: an object per sample or bucket, or an array
+/// per field, as charting libraries like uPlot take them. Columns are about half the
+/// size, and give times as `ts`, in seconds since the epoch, rather than in a zone.
+#[derive(Clone, Copy, PartialEq)]
+enum Shape {
+    Rows(Tz),
+    Columns,
+}
+
+impl Shape {
+    fn parse(s: Option<&str>, zone: Tz) -> Result<Self, ErrorResponse> {
+        match s {
+            None | Some("rows") => Ok(Self::Rows(zone)),
+            Some("columns") => Ok(Self::Columns),
+            Some(s) => Err(api::error(
+                Status::BadRequest,
+                &format!("`shape` must be `rows` or `columns`; got `{}`", s),
+            )),
+        }
+    }
+
+    /// A sample's or bucket's time, and its key: `key` for rows, or `ts`.
+    fn time(&self, key: &'static str, ms: i64) -> (&'static str, Value) {
+        match self {
+            Self::Rows(zone) => (key, json!(tz::format(ms, *zone))),
+            Self::Columns => ("ts", json!(ms as f64 / 1_000.)),
+        }
+    }
+
+    fn lay_out(&self, rows: Vec<Value>) -> Value {
+        match self {
+            Self::Rows(_) => Value::Array(rows),
+            Self::Columns => {
+                let mut result = columns(&rows);
+                // So an empty range still has every column.
+                for key in iter::once("ts").chain(registry::ids()) {
+                    result.entry(key).or_insert_with(|| json!([]));
+                }
+                Value::Object(result)
+            }
+        }
+    }
+}
+
+/// `rows`, which are objects, as an array per key. Flags only present when true, like
+/// `maintenance`, are false where they're missing, and other values are null. Objects,
+/// like bucket stats, are made columns in turn.
+fn columns(rows: &[Value]) -> Map<String, Value> {
+    let keys: BTreeSet<&String> = rows
+        .iter()
+        .filter_map(Value::as_object)
+        .flat_map(|r| r.keys())
+        .collect();
+    keys.into_iter()
+        .map(|key| {
+            let column: Vec<Value> = rows
+                .iter()
+                .map(|r| r.get(key).cloned().unwrap_or(Value::Null))
+                .collect();
+            let value = if column.iter().any(Value::is_object) {
+                Value::Object(columns(&column))
+            } else if column.iter().any(Value::is_boolean) {
+                column
+                    .iter()
+                    .map(|v| json!(v.as_bool().unwrap_or(false)))
+                    .collect()
+            } else {
+                Value::Array(column)
+            };
+            (key.clone(), value)
+        })
+        .collect()
+}
+
+/// Samples between `from` and `to`, defaulting to the last hour. With a `bucket`
+/// duration, samples are grouped into buckets of that length, each with the `agg`
+/// statistics (`avg`, `min`, `max`, or `all`, the default) per sensor; `interpolate`
+/// fills empty buckets in short gaps. Times without an offset, and those returned, are
+/// in `tz`, defaulting to `time.timezone`. Either way, stretches without samples are
+/// listed as `gaps`. With `shape=columns`, samples or buckets are an array per field.
+/// With `device`, a device that pushes its readings, they're its samples instead.
+#[get("/history?<from>&<to>&<bucket>&<agg>&<interpolate>&<tz>&<shape>&<device>")]
+#[allow(clippy::too_many_arguments)]
+pub fn view_history(
+    _viewer: Viewer,
+    from: Option<String>,
+    to: Option<String>,
+    bucket: Option<String>,
+    agg: Option<String>,
+    interpolate: Option<bool>,
+    tz: Option<String>,
+    shape: Option<String>,
+    device: Option<String>,
+) -> Result<content::Json<String>, ErrorResponse> {
+    let cfg = config::get();
+    let device = history_device(device, &cfg)?;
+    let zone = tz::from_query(tz.as_deref())?;
+    let to = match to {
+        Some(t) => parse_time(&t, "to", zone)?,
+        None => Utc::now(),
+    };
+    let from = match from {
+        Some(f) => parse_time(&f, "from", zone)?,
+        None => to - chrono::Duration::from_std(DEFAULT_RANGE).unwrap(),
+    };
+    if from >= to {
+        return Err(api::error(Status::BadRequest, "`from` must be before `to`"));
+    }
+    let (from_ms, to_ms) = (from.timestamp_millis(), to.timestamp_millis());
+
+    let agg = Agg::parse(agg.as_deref())?;
+    let shape = Shape::parse(shape.as_deref(), zone)?;
+    let interpolate_ms = interpolate
+        .unwrap_or(false)
+        .then(|| cfg.history.interpolate_max_secs as i64 * 1_000);
+
+    let mut result = json!({
+        "from": tz::format(from_ms, zone),
+        "to": tz::format(to_ms, zone),
+        "tz": zone.name(),
+        "gaps": gaps(&device, from_ms, to_ms, device_gap_ms(&cfg, &device), zone)?,
+        "device": device_meta::get(&device),
+    });
+
+    match bucket {
+        None => {
+            result["samples"] = shape.lay_out(samples(&device, from_ms, to_ms, shape)?);
+        }
+        Some(b) => {
+            let bucket_ms = parse_duration(&b).ok_or_else(|| {
+                api::error(
+                    Status::BadRequest,
+                    &format!(
+                        "`bucket` must be a duration like `30s`, `5m` or `1h`; got `{}`",
+                        b
+                    ),
+                )
+            })?;
+
+            let count = (to_ms - from_ms + bucket_ms - 1) / bucket_ms;
+            if count > MAX_BUCKETS {
+                return Err(api::error(
+                    Status::BadRequest,
+                    &format!(
+                        "That's {} buckets; the limit is {}. Use larger buckets, or a shorter range.",
+                        count, MAX_BUCKETS
+                    ),
+                ));
+            }
+
+            result["bucket_secs"] = json!(bucket_ms as f64 / 1_000.);
+            result["buckets"] = shape.lay_out(buckets(
+                &device,
+                from_ms,
+                to_ms,
+                bucket_ms,
+                count,
+                agg,
+                interpolate_ms,
+                shape,
+            )?);
+        }
+    }
+
+    Ok(content::Json(result.to_string()))
+}
+
+/// A condition on raw samples: that `sensor` wasn't stabilizing.
+pub fn settled(sensor: &str) -> String {
+    let bit = registry::index(sensor).map_or(0, |i| 1_i64 << i);
+    format!("stabilizing & {} = 0", bit)
+}
+
+/// Rows from every tier between `?1` and `?2`, in a common shape: `time`, `count`,
+/// `maintenance`, `stabilizing`, and per sensor, eg `T_sum`, `T_n`, `T_min` and
+/// `T_max`. Raw samples are rows with a count of 1. Tiers don't overlap, since
+/// compaction moves rows between them, and aggregates never include maintenance, or
+/// sensors stabilizing.
+pub fn all_tiers() -> String {
+    tiers(false, device_meta::LOCAL)
+}
+
+/// `all_tiers`, for `device`: this one, or one that pushes its readings, whose rows are
+/// its raw samples.
+pub fn device_tiers(device: &str) -> String {
+    tiers(false, device)
+}
+
+/// A string as an SQL literal.
+fn sql_text(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+/// `device_tiers`, or with `only_settled`, without readings from sensors that were
+/// stabilizing, for stats.
+fn tiers(only_settled: bool, device: &str) -> String {
+    let local = device == device_meta::LOCAL;
+    let mut raw = vec![
+        "time".to_owned(),
+        "1 AS count".to_owned(),
+        "maintenance".to_owned(),
+        "stabilizing".to_owned(),
+    ];
+    let mut aggregate = vec![
+        "time".to_owned(),
+        "count".to_owned(),
+        "0".to_owned(),
+        "0".to_owned(),
+    ];
+    for name in registry::ids() {
+        let value = if only_settled && local {
+            format!("CASE WHEN {} THEN {} END", settled(name), name)
+        } else {
+            name.to_owned()
+        };
+        raw.push(format!(
+            "{1} AS {0}_sum, {1} IS NOT NULL AS {0}_n, {1} AS {0}_min, {1} AS {0}_max",
+            name, value
+        ));
+        aggregate.push(format!(
+            "{0}_avg * {0}_n AS {0}_sum, {0}_n, {0}_min, {0}_max",
+            name
+        ));
+    }
+
+    if !local {
+        // Pushed samples aren't taken during maintenance, or while stabilizing.
+        raw[2] = "0 AS maintenance".into();
+        raw[3] = "0 AS stabilizing".into();
+        return format!(
+            "SELECT {} FROM pushed_samples WHERE device = {} AND time >= ?1 AND time < ?2",
+            raw.join(", "),
+            sql_text(device)
+        );
+    }
+    let mut selects = vec![format!(
+        "SELECT {} FROM samples WHERE time >= ?1 AND time < ?2",
+        raw.join(", ")
+    )];
+    for (table, _) in AGGREGATE_TIERS.iter() {
+        selects.push(format!(
+            "SELECT {} FROM {} WHERE time >= ?1 AND time < ?2",
+            aggregate.join(", "),
+            table
+        ));
+    }
+    selects.join(" UNION ALL ")
+}
+
+/// Samples in the range. Where raw samples have been compacted, the aggregate rows
+/// stand in for them, with their averages as values. Those taken during maintenance
+/// have `maintenance` set, and those with sensors stabilizing list them as
+/// `stabilizing`.
+fn samples(
+    device: &str,
+    from_ms: i64,
+    to_ms: i64,
+    shape: Shape,
+) -> Result<Vec<Value>, ErrorResponse> {
+    let conn = open_reader()?;
+
+    let columns: Vec<String> = registry::ids()
+        .map(|name| format!("{0}_sum / {0}_n", name))
+        .collect();
+    let sql = format!(
+        "SELECT time, maintenance, stabilizing, {} FROM ({}) ORDER BY time LIMIT ?3",
+        columns.join(", "),
+        device_tiers(device)
+    );
+    let mut stmt = conn.prepare(&sql).map_err(query_error)?;
+
+    let rows = stmt
+        .query_map(params![from_ms, to_ms, MAX_SAMPLES as i64 + 1], |row| {
+            let mut sample = Map::new();
+            let (key, time) = shape.time("time", row.get(0)?);
+            sample.insert(key.into(), time);
+            if row.get(1)? {
+                sample.insert("maintenance".into(), json!(true));
+            }
+            let stabilizing: i64 = row.get(2)?;
+            if stabilizing != 0 {
+                let ids: Vec<_> = registry::ids()
+                    .enumerate()
+                    .filter(|(i, _)| stabilizing & 1 << i != 0)
+                    .map(|(_, id)| id)
+                    .collect();
+                sample.insert("stabilizing".into(), json!(ids));
+            }
+            for (i, name) in registry::ids().enumerate() {
+                sample.insert(name.into(), json!(row.get::<_, Option<f64>>(i + 3)?));
+            }
+            Ok(Value::Object(sample))
+        })
+        .map_err(query_error)?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(query_error)?;
+
+    if rows.len() > MAX_SAMPLES {
+        return Err(api::error(
+            Status::BadRequest,
+            &format!(
+                "There are more than {} samples in that range. Use `bucket`, or a shorter range.",
+                MAX_SAMPLES
+            ),
+        ));
+    }
+    Ok(rows)
+}
+
+/// Buckets covering `from_ms` to `to_ms`, with stats computed in SQL. Aggregate rows
+/// count towards the bucket their start time is in; samples taken during maintenance,
+/// and readings from sensors stabilizing, don't count.
+#[allow(clippy::too_many_arguments)]
+fn buckets(
+    device: &str,
+    from_ms: i64,
+    to_ms: i64,
+    bucket_ms: i64,
+    count: i64,
+    agg: Agg,
+    interpolate_ms: Option<i64>,
+    shape: Shape,
+) -> Result<Vec<Value>, ErrorResponse> {
+    let conn = open_reader()?;
+
+    let mut columns = vec!["(time - ?1) / ?3".to_owned(), "SUM(count)".to_owned()];
+    for name in registry::ids() {
+        // Dividing by a zero count gives NULL.
+        columns.push(format!(
+            "SUM({0}_sum) / SUM({0}_n), MIN({0}_min), MAX({0}_max)",
+            name
+        ));
+    }
+    let sql = format!(
+        "SELECT {} FROM ({}) WHERE NOT maintenance GROUP BY 1 ORDER BY 1",
+        columns.join(", "),
+        tiers(true, device)
+    );
+
+    let mut stmt = conn.prepare(&sql).map_err(query_error)?;
+    let rows = stmt
+        .query_map(params![from_ms, to_ms, bucket_ms], |row| {
+            let index: i64 = row.get(0)?;
+            let count: i64 = row.get(1)?;
+            let mut stats = Vec::with_capacity(registry::COUNT);
+            for i in 0..registry::COUNT {
+                let col = 2 + i * 3;
+                stats.push([
+                    row.get::<_, Option<f64>>(col)?,
+                    row.get::<_, Option<f64>>(col + 1)?,
+                    row.get::<_, Option<f64>>(col + 2)?,
+                ]);
+            }
+            Ok((index, count, stats))
+        })
+        .map_err(query_error)?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(query_error)?;
+
+    // Fill in every bucket, including ones without samples.
+    let mut rows = rows.into_iter().peekable();
+    let mut filled: Vec<Option<Bucket>> = (0..count)
+        .map(|i| match rows.peek() {
+            Some((index, _, _)) if *index == i => rows.next().map(|(_, c, stats)| (c, stats)),
+            _ => None,
+        })
+        .collect();
+    let interpolated = match interpolate_ms {
+        Some(max_ms) => interpolate(&mut filled, bucket_ms, max_ms),
+        None => vec![false; filled.len()],
+    };
+
+    let mut result = Vec::with_capacity(count as usize);
+    for (i, row) in filled.iter().enumerate() {
+        let mut bucket = Map::new();
+        let (key, start) = shape.time("start", from_ms + i as i64 * bucket_ms);
+        bucket.insert(key.into(), start);
+        bucket.insert(
+            "count".into(),
+            json!(row.as_ref().map(|r| r.0).unwrap_or(0)),
+        );
+        if interpolated[i] {
+            bucket.insert("interpolated".into(), json!(true));
+        }
+
+        for (s, name) in registry::ids().enumerate() {
+            let [avg, min, max] = row.as_ref().map(|r| r.1[s]).unwrap_or([None; 3]);
+            let mut stats = Map::new();
+            if agg.includes(Agg::Avg) {
+                stats.insert("avg".into(), json!(avg));
+            }
+            if agg.includes(Agg::Min) {
+                stats.insert("min".into(), json!(min));
+            }
+            if agg.includes(Agg::Max) {
+                stats.insert("max".into(), json!(max));
+            }
+            bucket.insert(name.into(), Value::Object(stats));
+        }
+
+        result.push(Value::Object(bucket));
+    }
+
+    Ok(result)
+}
+
+/// A bucket's sample count, and `[avg, min, max]` per sensor.
+type Bucket = (i64, Vec<[Option<f64>; 3]>);
+
+/// Fill runs of empty buckets lasting up to `max_ms`, between two with samples, in a
+/// straight line between them, per statistic. Returns which buckets were filled.
+fn interpolate(buckets: &mut [Option<Bucket>], bucket_ms: i64, max_ms: i64) -> Vec<bool> {
+    let mut filled = vec![false; buckets.len()];
+    let mut prev: Option<usize> = None;
+
+    for i in 0..buckets.len() {
+        let after = match &buckets[i] {
+            Some(b) => b.1.clone(),
+            None => continue,
+        };
+        if let Some(p) = prev {
+            let empty = (i - p - 1) as i64;
+            if empty > 0 && empty * bucket_ms <= max_ms {
+                let before = buckets[p].as_ref().unwrap().1.clone();
+                for (j, bucket) in buckets.iter_mut().enumerate().take(i).skip(p + 1) {
+                    let f = (j - p) as f64 / (i - p) as f64;
+                    let stats = before
+                        .iter()
+                        .zip(after.iter())
+                        .map(|(b, a)| {
+                            [0, 1, 2].map(|k| match (b[k], a[k]) {
+                                (Some(b), Some(a)) => Some(b + (a - b) * f),
+                                _ => None,
+                            })
+                        })
+                        .collect();
+                    *bucket = Some((0, stats));
+                    filled[j] = true;
+                }
+            }
+        }
+        prev = Some(i);
+    }
+    filled
+}
+
+/// How long without samples counts as a gap: `history.gap_factor` times the longest
+/// time between polls.
+pub fn gap_ms(cfg: &AppConfig) -> i64 {
+    let interval_ms = if cfg.polling.adaptive {
+        cfg.polling.idle_interval_secs as i64 * 1_000
+    } else {
+        REFRESH_INTERVAL as i64
+    };
+    (cfg.history.gap_factor as f64 * interval_ms as f64) as i64
+}
+
+/// `gap_ms`, for `device`: `history.gap_factor` times its interval between pushes, for
+/// one that pushes its readings.
+fn device_gap_ms(cfg: &AppConfig, device: &str) -> i64 {
+    match cfg.devices.iter().find(|d| d.name == device) {
+        Some(d) => (cfg.history.gap_factor as f64 * d.interval_secs as f64 * 1_000.) as i64,
+        None => gap_ms(cfg),
+    }
+}
+
+/// Whose history a `device` query parameter asks for: this device's, by default, or
+/// one's that pushes its readings. Devices that are fetched from keep their own.
+pub fn history_device(device: Option<String>, cfg: &AppConfig) -> Result<String, ErrorResponse> {
+    match device {
+        None => Ok(device_meta::LOCAL.to_owned()),
+        Some(d) if d == device_meta::LOCAL => Ok(d),
+        Some(d) if cfg.devices.iter().any(|c| c.name == d && c.url.is_none()) => Ok(d),
+        Some(d) => Err(api::error(
+            Status::NotFound,
+            &format!(
+                "There's no history for `{}`: only for `{}`, and devices that push their readings",
+                d,
+                device_meta::LOCAL
+            ),
+        )),
+    }
+}
+
+/// Stretches between `from_ms` and `to_ms` without samples, longer than `min_ms`, for
+/// responses. An aggregate row covers its whole bucket.
+fn gaps(
+    device: &str,
+    from_ms: i64,
+    to_ms: i64,
+    min_ms: i64,
+    zone: Tz,
+) -> Result<Vec<Value>, ErrorResponse> {
+    let conn = open_reader()?;
+    let spans = gap_spans(&conn, device, from_ms, to_ms, min_ms).map_err(query_error)?;
+    Ok(spans
+        .into_iter()
+        .map(|(start, end)| {
+            json!({
+                "start": tz::format(start, zone),
+                "end": tz::format(end, zone),
+                "secs": (end - start) as f64 / 1_000.,
+            })
+        })
+        .collect())
+}
+
+/// Each stretch's start and end, in ms since the epoch, up to `MAX_SAMPLES` of them.
+pub fn gap_spans(
+    conn: &Connection,
+    device: &str,
+    from_ms: i64,
+    to_ms: i64,
+    min_ms: i64,
+) -> Result<Vec<(i64, i64)>, rusqlite::Error> {
+    // Each row's start and end, between ones marking the ends of the range. A gap ends
+    // at a row starting after every earlier row has ended.
+    let mut spans = vec!["SELECT ?1 AS time, ?1 AS span_end".to_owned()];
+    if device == device_meta::LOCAL {
+        spans.push("SELECT time, time FROM samples WHERE time >= ?1 AND time < ?2".into());
+        for (table, bucket_ms) in AGGREGATE_TIERS.iter() {
+            spans.push(format!(
+                "SELECT time, time + {1} FROM {0} WHERE time > ?1 - {1} AND time < ?2",
+                table, bucket_ms
+            ));
+        }
+    } else {
+        spans.push(format!(
+            "SELECT time, time FROM pushed_samples \
+             WHERE device = {} AND time >= ?1 AND time < ?2",
+            sql_text(device)
+        ));
+    }
+    spans.push("SELECT ?2, ?2".to_owned());
+
+    let sql = format!(
+        "SELECT start, time FROM \
+         (SELECT time, MAX(span_end) OVER \
+             (ORDER BY time ROWS BETWEEN UNBOUNDED PRECEDING AND 1 PRECEDING) AS start \
+          FROM ({})) \
+         WHERE time - start > ?3 ORDER BY time LIMIT ?4",
+        spans.join(" UNION ALL ")
+    );
+    let mut stmt = conn.prepare(&sql)?;
+    let rows = stmt
+        .query_map(params![from_ms, to_ms, min_ms, MAX_SAMPLES as i64], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })?
+        .collect::<Result<_, _>>()?;
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Laying out rows as columns keeps every value in its place.
+    #[test]
+    fn rows_lay_out_as_columns() {
+        let rows = vec![
+            json!({ "time": 0, "maintenance": true, "T": 20.5, "pH": null }),
+            json!({ "time": 1, "T": null, "pH": 7.2 }),
+            json!({ "time": 2, "T": { "avg": 21., "max": 22. }, "pH": 7.4 }),
+        ];
+        let expected = json!({
+            "time": [0, 1, 2],
+            "maintenance": [true, false, false],
+            "T": { "avg": [null, null, 21.], "max": [null, null, 22.] },
+            "pH": [null, 7.2, 7.4],
+        });
+        assert_eq!(Value::Object(columns(&rows)), expected);
+
+        let empty = Shape::Columns.lay_out(Vec::new());
+        for key in iter::once("ts").chain(registry::ids()) {
+            assert_eq!(empty[key], json!([]), "{}", key);
+        }
+    }
+
+    fn sample(device: Option<&str>, time: i64, t: f32) -> Sample {
+        let mut values = [None; registry::COUNT];
+        values[registry::index("T").unwrap()] = Some(t);
+        Sample {
+            device: device.map(str::to_owned),
+            time,
+            values,
+            maintenance: false,
+            stabilizing: 0,
+        }
+    }
+
+    #[test]
+    fn pushed_samples_are_kept_apart() {
+        let mut conn = open(":memory:").unwrap();
+        insert(
+            &mut conn,
+            &[
+                sample(None, 1_000, 20.),
+                sample(Some("well"), 2_000, 11.5),
+                sample(Some("it's"), 3_000, 12.),
+                sample(Some("well"), 60_000, 11.),
+            ],
+        )
+        .unwrap();
+
+        let rows = |device: &str| -> Vec<(i64, f64)> {
+            let sql = format!(
+                "SELECT time, T_sum FROM ({}) ORDER BY time",
+                device_tiers(device)
+            );
+            let mut stmt = conn.prepare(&sql).unwrap();
+            stmt.query_map(params![0, i64::MAX], |row| Ok((row.get(0)?, row.get(1)?)))
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap()
+        };
+        assert_eq!(rows(device_meta::LOCAL), [(1_000, 20.)]);
+        assert_eq!(rows("well"), [(2_000, 11.5), (60_000, 11.)]);
+        assert_eq!(rows("it's"), [(3_000, 12.)]);
+
+        let gaps = gap_spans(&conn, "well", 0, 100_000, 30_000).unwrap();
+        assert_eq!(gaps, [(2_000, 60_000), (60_000, 100_000)]);
+
+        let last: i64 = conn
+            .query_row(
+                "SELECT time FROM last_stored WHERE device = 'well'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(last, 60_000);
+    }
+
+    #[test]
+    fn history_is_of_this_device_or_a_pushing_one() {
+        let cfg: AppConfig = toml::from_str(
+            r#"
+            [[devices]]
+            name = "north"
+            url = "http://192.168.1.21"
+            [[devices]]
+            name = "well"
+            token = "secret"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(history_device(None, &cfg).unwrap(), device_meta::LOCAL);
+        assert_eq!(history_device(Some("well".into()), &cfg).unwrap(), "well");
+        let e = history_device(Some("north".into()), &cfg).err().unwrap();
+        assert_eq!(e.0, Status::NotFound);
+    }
+
+    /// Two outages, of 3 and 40 minutes, in samples a minute apart: both are gaps, and
+    /// only the short one is interpolated.
+    #[test]
+    fn outages_are_gaps_and_short_ones_interpolate() {
+        const MIN: i64 = 60_000;
+        let minutes: Vec<i64> = (0..=10).chain(13..=20).chain(60..=70).collect();
+        let mut conn = open(":memory:").unwrap();
+        let batch: Vec<_> = minutes
+            .iter()
+            .map(|m| sample(None, m * MIN, *m as f32))
+            .collect();
+        insert(&mut conn, &batch).unwrap();
+
+        let gaps = gap_spans(&conn, device_meta::LOCAL, 0, 70 * MIN, 2 * MIN).unwrap();
+        assert_eq!(gaps, [(10 * MIN, 13 * MIN), (20 * MIN, 60 * MIN)]);
+
+        let mut buckets: Vec<Option<Bucket>> = (0..=70)
+            .map(|m| minutes.contains(&m).then(|| (1, vec![[Some(m as f64); 3]])))
+            .collect();
+        let filled = interpolate(&mut buckets, MIN, 5 * MIN);
+        let filled: Vec<_> = (0..=70).filter(|m| filled[*m as usize]).collect();
+        assert_eq!(filled, [11, 12]);
+        assert_eq!(buckets[11], Some((0, vec![[Some(11.); 3]])));
+        assert_eq!(buckets[30], None);
+    }
+}
@@ -0,0 +1,303 @@
+//! The Water Monitor's onboard RGB LED and buzzer, so someone at the equipment pad can
+//! see the water's status without a phone. With `indicator.enabled`, the poller sets it
+//! whenever it changes: red while an alert is active or a reading is critical, blinking
+//! blue while readings are stale, amber for a warning or an error, and green otherwise.
+//! The buzzer sounds with red if `indicator.buzzer` is set. `POST /api/device/indicator`
+//! sets it by hand for a while, eg to tell monitors apart, and then it follows alerts
+//! again. Only newer firmware has the command: if the Water Monitor doesn't echo it, it
+//! isn't sent again until the Water Monitor reconnects. The buzzer, and setting it by
+//! hand, are subject to the interlock.
+
+use std::{
+    io::{self, Read},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use rocket::{http::Status, response::content, Data};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    activity, alerts,
+    api::{self, ErrorResponse},
+    auth::Admin,
+    cache,
+    config::{self, AppConfig},
+    events::{self, Severity},
+    history,
+    interlock::{self, BUZZER, INDICATOR_OVERRIDE},
+    status::{self, SensorStatus},
+    WaterMonitor,
+};
+
+const DEFAULT_OVERRIDE_MINS: u32 = 15;
+
+static SHARED: Mutex<Shared> = Mutex::new(Shared {
+    manual: None,
+    sent: None,
+    supported: None,
+});
+
+struct Shared {
+    manual: Option<Manual>,
+    /// What the Water Monitor was last set to, since it connected.
+    sent: Option<State>,
+    /// `None` until it's been tried since the Water Monitor connected.
+    supported: Option<bool>,
+}
+
+struct Manual {
+    state: State,
+    until: Instant,
+    until_ms: i64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Color {
+    Off,
+    Green,
+    Amber,
+    Red,
+    Blue,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Pattern {
+    #[default]
+    Solid,
+    Blink,
+    Pulse,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+struct State {
+    color: Color,
+    #[serde(default)]
+    pattern: Pattern,
+    #[serde(default)]
+    buzzer: bool,
+}
+
+impl State {
+    const fn solid(color: Color) -> Self {
+        Self {
+            color,
+            pattern: Pattern::Solid,
+            buzzer: false,
+        }
+    }
+
+    /// As sent to the Water Monitor: the color in bits 0 to 3, the pattern in 4 to 6,
+    /// and the buzzer in 7.
+    fn byte(&self) -> u8 {
+        self.color as u8 | (self.pattern as u8) << 4 | (self.buzzer as u8) << 7
+    }
+}
+
+/// A `POST /api/device/indicator` body.
+#[derive(Deserialize)]
+struct Request {
+    /// Follow alerts again.
+    #[serde(default)]
+    auto: bool,
+    #[serde(flatten)]
+    state: Option<State>,
+    /// How long to keep it; default 15.
+    minutes: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct IndicatorStatus {
+    enabled: bool,
+    /// `None` until it's been tried since the Water Monitor connected.
+    supported: Option<bool>,
+    /// `auto`, following alerts, or `manual`.
+    mode: &'static str,
+    /// What it shows, or will from the next poll cycle.
+    state: State,
+    /// When a manual setting ends.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    until: Option<String>,
+}
+
+/// Check `[indicator]`.
+pub fn check(cfg: &AppConfig) -> Result<(), io::Error> {
+    if cfg.indicator.max_override_mins == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Config error: `indicator.max_override_mins` must be above 0",
+        ));
+    }
+    Ok(())
+}
+
+/// What alerts and statuses call for.
+fn automatic(cfg: &AppConfig) -> State {
+    let statuses = status::classify(&cache::latest().readings, &cfg.status);
+    let any = |status| statuses.iter().any(|(_, s)| s == status);
+
+    if !alerts::active().is_empty() || any(SensorStatus::Critical) {
+        State {
+            buzzer: cfg.indicator.buzzer,
+            ..State::solid(Color::Red)
+        }
+    } else if any(SensorStatus::Stale) {
+        State {
+            pattern: Pattern::Blink,
+            ..State::solid(Color::Blue)
+        }
+    } else if any(SensorStatus::Warn) || any(SensorStatus::Error) || any(SensorStatus::Rejected) {
+        State::solid(Color::Amber)
+    } else {
+        State::solid(Color::Green)
+    }
+}
+
+/// The manual setting, if there is one, ending it if it's run out.
+fn manual() -> Option<(State, i64)> {
+    let mut shared = SHARED.lock().unwrap();
+    match &shared.manual {
+        Some(m) if Instant::now() < m.until => Some((m.state, m.until_ms)),
+        Some(_) => {
+            shared.manual = None;
+            drop(shared);
+            events::record(
+                Severity::Info,
+                "indicator",
+                "The indicator's manual setting has ended; it follows alerts again",
+            );
+            None
+        }
+        None => None,
+    }
+}
+
+/// Forget what the Water Monitor was set to. Called by the poller when it connects.
+pub fn reset() {
+    let mut shared = SHARED.lock().unwrap();
+    shared.sent = None;
+    shared.supported = None;
+}
+
+/// Set the indicator, if what it should show has changed. Called by the poller after
+/// each cycle, with the Water Monitor if it's connected. Turns it off once, if
+/// `indicator.enabled` is unset.
+pub(crate) fn refresh(monitor: Option<&mut WaterMonitor>) {
+    let Some(wm) = monitor else {
+        return;
+    };
+    let cfg = config::get();
+    let mut state = match manual() {
+        Some((state, _)) => state,
+        None => automatic(&cfg),
+    };
+    if state.buzzer && interlock::permit(&BUZZER).is_err() {
+        state.buzzer = false;
+    }
+
+    let (sent, supported) = {
+        let shared = SHARED.lock().unwrap();
+        (shared.sent, shared.supported)
+    };
+    let state = match (cfg.indicator.enabled, sent) {
+        (true, _) => state,
+        (false, Some(sent)) if sent.color != Color::Off => State::solid(Color::Off),
+        (false, _) => return,
+    };
+    if supported == Some(false) || sent == Some(state) {
+        return;
+    }
+
+    // Outside the lock, so a slow port doesn't hold up `/api/device/indicator`.
+    let result = wm.set_indicator(state.byte());
+    let mut shared = SHARED.lock().unwrap();
+    match result {
+        Ok(()) => {
+            shared.supported = Some(true);
+            shared.sent = Some(state);
+        }
+        Err(e) if e.kind() == io::ErrorKind::Unsupported => {
+            shared.supported = Some(false);
+            drop(shared);
+            events::record(Severity::Warning, "indicator", e.to_string());
+        }
+        // Tried again next cycle.
+        Err(_) => (),
+    }
+}
+
+fn status() -> IndicatorStatus {
+    let cfg = config::get();
+    let manual = manual();
+    let shared = SHARED.lock().unwrap();
+    IndicatorStatus {
+        enabled: cfg.indicator.enabled,
+        supported: shared.supported,
+        mode: if manual.is_some() { "manual" } else { "auto" },
+        state: manual.map_or_else(|| automatic(&cfg), |(state, _)| state),
+        until: manual.map(|(_, until_ms)| history::format_time(until_ms)),
+    }
+}
+
+/// What the indicator shows, and if it's set by hand.
+#[get("/device/indicator")]
+pub fn view_indicator() -> content::Json<String> {
+    content::Json(serde_json::to_string(&status()).unwrap())
+}
+
+/// Set the indicator by hand, eg `{"color": "blue", "pattern": "blink", "minutes": 5}`,
+/// for up to `indicator.max_override_mins`; or `{"auto": true}` to follow alerts again.
+#[post("/device/indicator", data = "<data>")]
+pub fn set_indicator(_admin: Admin, data: Data) -> Result<content::Json<String>, ErrorResponse> {
+    let mut body = String::new();
+    data.open()
+        .take(16 * 1_024)
+        .read_to_string(&mut body)
+        .map_err(|e| api::error(Status::BadRequest, &e.to_string()))?;
+    let request: Request = serde_json::from_str(&body)
+        .map_err(|e| api::error(Status::BadRequest, &format!("Invalid indicator: {}", e)))?;
+
+    let cfg = config::get().indicator;
+    if !cfg.enabled {
+        return Err(api::error(
+            Status::NotFound,
+            "The indicator is off; set `indicator.enabled`",
+        ));
+    }
+    let manual = match (request.auto, request.state) {
+        (true, _) => None,
+        (false, Some(state)) => {
+            interlock::permit(&INDICATOR_OVERRIDE)
+                .map_err(|blocked| interlock::denial(&INDICATOR_OVERRIDE, &blocked))?;
+            let minutes = request.minutes.unwrap_or(DEFAULT_OVERRIDE_MINS);
+            if minutes == 0 || minutes > cfg.max_override_mins {
+                return Err(api::error(
+                    Status::BadRequest,
+                    &format!(
+                        "`minutes` must be from 1 to {}, per `indicator.max_override_mins`",
+                        cfg.max_override_mins
+                    ),
+                ));
+            }
+            let duration = Duration::from_secs(minutes as u64 * 60);
+            Some(Manual {
+                state,
+                until: Instant::now() + duration,
+                until_ms: Utc::now().timestamp_millis() + duration.as_millis() as i64,
+            })
+        }
+        (false, None) => {
+            return Err(api::error(
+                Status::BadRequest,
+                "Give a `color`, or `auto: true` to follow alerts again",
+            ))
+        }
+    };
+    SHARED.lock().unwrap().manual = manual;
+    // So it shows straight away.
+    activity::wake();
+    Ok(content::Json(serde_json::to_string(&status()).unwrap()))
+}
@@ -0,0 +1,195 @@
+//! Readings pushed by senders that can't run this app, like an ESP32 on a remote well.
+//! Each is a `[[devices]]` entry with a `token` instead of a `url`, which posts to
+//! `/api/ingest/<name>` with `Authorization: Bearer <token>`:
+//!
+//! ```json
+//! { "time": "2021-06-01T12:00:00Z", "readings": { "T": 11.5, "pH": 7.4 } }
+//! ```
+//!
+//! `time` is optional, and defaults to when the push arrives. Sensors are by id or snake
+//! case key; one without a reading is left out, and a null is rejected, as are values
+//! outside `history.plausible`. Pushed readings are used as fetched devices' are, in
+//! channels and metrics, and as this one's are, in history, with `device`, and alert
+//! rules on the device. Pushing the latest readings again is ignored; older ones are
+//! rejected with a 409.
+
+use std::{collections::BTreeMap, io::Read};
+
+use chrono::Utc;
+use rocket::{
+    http::Status,
+    request::{self, FromRequest},
+    response::content,
+    Data, Outcome, Request,
+};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    alerts,
+    api::{self, ErrorResponse},
+    auth,
+    channels::{self, PushError},
+    config::{self, AppConfig},
+    history, registry, tz, Readings,
+};
+
+/// How far ahead of our clock a push's `time` can be.
+const MAX_CLOCK_SKEW_MS: i64 = 5 * 60 * 1_000;
+
+/// The bearer token a request was sent with, if any.
+pub struct Bearer(Option<String>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for Bearer {
+    type Error = ();
+
+    fn from_request(req: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let token = req
+            .headers()
+            .get_one("Authorization")
+            .and_then(|h| h.strip_prefix("Bearer "))
+            .map(str::to_owned);
+        Outcome::Success(Self(token))
+    }
+}
+
+#[derive(Deserialize)]
+struct Push {
+    #[serde(default)]
+    time: Option<String>,
+    readings: BTreeMap<String, Option<f32>>,
+}
+
+/// A push's readings, by sensor, if they're all known sensors, with plausible values.
+fn pushed_readings(
+    pushed: &BTreeMap<String, Option<f32>>,
+    cfg: &AppConfig,
+) -> Result<Readings, ErrorResponse> {
+    let mut readings = Readings::empty();
+    for (name, value) in pushed.iter() {
+        let sensor = registry::REGISTRY
+            .iter()
+            .find(|s| s.id == name || s.key == name)
+            .ok_or_else(|| api::error(Status::BadRequest, &format!("Unknown sensor `{}`", name)))?;
+        let value = value.ok_or_else(|| {
+            api::error(
+                Status::BadRequest,
+                &format!(
+                    "`readings.{}` is null; leave out sensors without a reading",
+                    name
+                ),
+            )
+        })?;
+        let [min, max] = cfg.history.plausible.get(sensor.id, cfg.ec.probe);
+        if !(min..=max).contains(&value) {
+            return Err(api::error(
+                Status::BadRequest,
+                &format!(
+                    "{} is {}, outside its plausible range, {} to {}",
+                    sensor.id, value, min, max
+                ),
+            ));
+        }
+        readings.set(sensor.id, Some(Ok(value)));
+    }
+    Ok(readings)
+}
+
+/// Take a device's pushed readings, if its token matches. Returns if they were a
+/// duplicate of the latest.
+#[post("/ingest/<device>", data = "<data>")]
+pub fn ingest(
+    device: String,
+    bearer: Bearer,
+    data: Data,
+) -> Result<content::Json<String>, ErrorResponse> {
+    let cfg = config::get();
+    let not_found = || {
+        api::error(
+            Status::NotFound,
+            &format!("No device `{}` pushes its readings", device),
+        )
+    };
+    let token = cfg
+        .devices
+        .iter()
+        .find(|d| d.name == device && d.url.is_none())
+        .and_then(|d| d.token.as_deref())
+        .ok_or_else(not_found)?;
+    if !bearer
+        .0
+        .is_some_and(|given| auth::constant_time_eq(given.as_bytes(), token.as_bytes()))
+    {
+        return Err(api::error(
+            Status::Unauthorized,
+            "Missing or wrong token for this device",
+        ));
+    }
+
+    let mut body = String::new();
+    data.open()
+        .take(16 * 1_024)
+        .read_to_string(&mut body)
+        .map_err(|e| api::error(Status::BadRequest, &e.to_string()))?;
+    let push: Push = serde_json::from_str(&body)
+        .map_err(|e| api::error(Status::BadRequest, &format!("Invalid readings: {}", e)))?;
+
+    let now_ms = Utc::now().timestamp_millis();
+    let time_ms = match &push.time {
+        Some(t) => history::parse_time(t, "time", tz::configured())?.timestamp_millis(),
+        None => now_ms,
+    };
+    if time_ms > now_ms + MAX_CLOCK_SKEW_MS {
+        return Err(api::error(
+            Status::BadRequest,
+            "`time` is in the future; check the device's clock",
+        ));
+    }
+
+    let readings = pushed_readings(&push.readings, &cfg)?;
+    let values = readings
+        .iter()
+        .filter_map(|(s, r)| r.ok().map(|v| (s.id.to_owned(), v)))
+        .collect();
+
+    match channels::push(&device, time_ms, values) {
+        Ok(stored) => {
+            if stored {
+                history::record_pushed(&device, time_ms, &readings);
+                alerts::evaluate(&device, &readings);
+            }
+            Ok(content::Json(json!({ "duplicate": !stored }).to_string()))
+        }
+        Err(PushError::UnknownDevice) => Err(not_found()),
+        Err(PushError::OutOfOrder(latest)) => Err(api::error(
+            Status::Conflict,
+            &format!(
+                "These readings are older than the latest pushed, from {}",
+                history::format_time(latest)
+            ),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pushed_readings_are_checked() {
+        let cfg = AppConfig::default();
+        let pushed = |json: &str| pushed_readings(&serde_json::from_str(json).unwrap(), &cfg);
+
+        let readings = pushed(r#"{ "T": 11.5, "ph": 7.4 }"#).unwrap();
+        assert_eq!(readings.get("T"), Some(Ok(11.5)));
+        assert_eq!(readings.get("pH"), Some(Ok(7.4)));
+        assert_eq!(readings.get("ORP"), None);
+
+        let e = pushed(r#"{ "T": 11.5, "ORP": null }"#).err().unwrap();
+        assert_eq!(e.0, Status::BadRequest);
+        assert!(e.1 .0.contains("readings.ORP"));
+
+        assert!(pushed(r#"{ "T": 500 }"#).is_err());
+        assert!(pushed(r#"{ "salinity": 35 }"#).is_err());
+    }
+}
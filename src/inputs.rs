@@ -0,0 +1,326 @@
+//! Binary inputs, like a sump's float switch, on the Pi's GPIO pins, so "water level
+//! low" shows in readings and alert rules alongside the sensors. Pins are read through
+//! Linux's sysfs every few milliseconds on their own thread, and debounced: a change
+//! counts once it's held for `debounce_ms`. History keeps each change of state, rather
+//! than a row per sample, in `input_changes`. Needs a build with `--features gpio`.
+
+use std::{
+    collections::BTreeMap,
+    io,
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use rocket::{http::Status, response::content};
+use rusqlite::params;
+use serde::Serialize;
+
+use crate::{
+    api::{self, ErrorResponse},
+    config::{self, AppConfig, InputConfig},
+    events::{self, Severity},
+    history,
+    tokens::Viewer,
+    tz,
+};
+
+const READ_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How often to check for inputs added to the config, while there are none.
+const IDLE_INTERVAL: Duration = Duration::from_secs(1);
+
+const MAX_DEBOUNCE_MS: u32 = 10_000;
+
+/// Changes listed per query.
+const MAX_CHANGES: i64 = 10_000;
+
+/// By input name.
+static STATES: Mutex<BTreeMap<String, State>> = Mutex::new(BTreeMap::new());
+
+struct State {
+    /// `None` until the first debounced read.
+    active: Option<bool>,
+    since_ms: Option<i64>,
+    /// The latest read, before debouncing, and since when.
+    raw: Option<bool>,
+    raw_since: Instant,
+    error: Option<String>,
+    /// Of the config it was read with, so a changed pin starts over.
+    pin: u32,
+}
+
+#[derive(Serialize)]
+pub struct InputState {
+    pub name: String,
+    pub pin: u32,
+    /// `None` until its first read.
+    pub active: Option<bool>,
+    /// When it last changed, or was first read.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub since: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Change {
+    time: String,
+    active: bool,
+}
+
+#[cfg(feature = "gpio")]
+fn read_pin(pin: u32) -> Result<bool, io::Error> {
+    use std::{fs, path::Path};
+
+    let dir = format!("/sys/class/gpio/gpio{}", pin);
+    if !Path::new(&dir).exists() {
+        fs::write("/sys/class/gpio/export", pin.to_string())?;
+        fs::write(format!("{}/direction", dir), "in")?;
+    }
+    match fs::read_to_string(format!("{}/value", dir))?.trim() {
+        "0" => Ok(false),
+        "1" => Ok(true),
+        v => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unexpected GPIO value `{}`", v),
+        )),
+    }
+}
+
+#[cfg(not(feature = "gpio"))]
+fn read_pin(_pin: u32) -> Result<bool, io::Error> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "This build doesn't support GPIO",
+    ))
+}
+
+/// Check inputs have distinct names, that work as JSON keys and in URLs, and that this
+/// build can read them.
+pub fn check(cfg: &AppConfig) -> Result<(), io::Error> {
+    let invalid = |msg: String| {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Config error: {}", msg),
+        ))
+    };
+    if !cfg.inputs.is_empty() && !cfg!(feature = "gpio") {
+        return invalid("`[[inputs]]` needs a build with `--features gpio`".into());
+    }
+    for (i, input) in cfg.inputs.iter().enumerate() {
+        let valid_name = !input.name.is_empty()
+            && input
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if !valid_name {
+            return invalid(format!(
+                "Input names must be letters, digits, `_` and `-`; got `{}`",
+                input.name
+            ));
+        }
+        if cfg.inputs[..i].iter().any(|x| x.name == input.name) {
+            return invalid(format!(
+                "There's more than one input named `{}`",
+                input.name
+            ));
+        }
+        if cfg.inputs[..i].iter().any(|x| x.pin == input.pin) {
+            return invalid(format!("More than one input uses pin {}", input.pin));
+        }
+        if input.debounce_ms > MAX_DEBOUNCE_MS {
+            return invalid(format!(
+                "Input `{}`: `debounce_ms` must be at most {}",
+                input.name, MAX_DEBOUNCE_MS
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Note a change in history, if it's enabled.
+fn record_change(name: &str, time_ms: i64, active: bool) {
+    if !config::get().history.enabled {
+        return;
+    }
+    let result = history::write(|conn| {
+        conn.execute(
+            "INSERT INTO input_changes (time, input, active) VALUES (?1, ?2, ?3)",
+            params![time_ms, name, active],
+        )
+    });
+    if let Err(e) = result {
+        events::record(
+            Severity::Warning,
+            "inputs",
+            format!("Problem saving input `{}`'s change: {}", name, e),
+        );
+    }
+}
+
+/// Read an input, and debounce it. Returns a change of its state.
+fn read(input: &InputConfig, state: &mut State, now: Instant) -> Option<bool> {
+    let raw = match read_pin(input.pin) {
+        Ok(high) => high != input.inverted,
+        Err(e) => {
+            state.error = Some(e.to_string());
+            state.raw = None;
+            return None;
+        }
+    };
+    state.error = None;
+    if state.raw != Some(raw) {
+        state.raw = Some(raw);
+        state.raw_since = now;
+    }
+    let held = now - state.raw_since >= Duration::from_millis(input.debounce_ms as u64);
+    (held && state.active != Some(raw)).then_some(raw)
+}
+
+/// Read inputs, forever; run this on its own thread.
+pub fn run() {
+    loop {
+        let inputs = config::get().inputs;
+        let now = Instant::now();
+        let mut changes = Vec::new();
+        {
+            let mut states = STATES.lock().unwrap();
+            states.retain(|name, s| inputs.iter().any(|i| &i.name == name && i.pin == s.pin));
+            for input in inputs.iter() {
+                let state = states.entry(input.name.clone()).or_insert_with(|| State {
+                    active: None,
+                    since_ms: None,
+                    raw: None,
+                    raw_since: now,
+                    error: None,
+                    pin: input.pin,
+                });
+                if let Some(active) = read(input, state, now) {
+                    let first = state.active.is_none();
+                    let time_ms = Utc::now().timestamp_millis();
+                    state.active = Some(active);
+                    state.since_ms = Some(time_ms);
+                    changes.push((input.name.clone(), time_ms, active, first));
+                }
+            }
+        }
+
+        // Outside the lock, since the disk can be slow.
+        for (name, time_ms, active, first) in changes {
+            record_change(&name, time_ms, active);
+            if !first {
+                events::record(
+                    Severity::Info,
+                    "inputs",
+                    format!(
+                        "Input `{}` is now {}.",
+                        name,
+                        if active { "active" } else { "inactive" }
+                    ),
+                );
+            }
+        }
+        thread::sleep(if inputs.is_empty() {
+            IDLE_INTERVAL
+        } else {
+            READ_INTERVAL
+        });
+    }
+}
+
+/// An input's debounced state; `None` if it's unknown, or not read yet.
+pub fn active(name: &str) -> Option<bool> {
+    STATES.lock().unwrap().get(name).and_then(|s| s.active)
+}
+
+/// Each input's known state, for readings.
+pub fn values() -> BTreeMap<String, bool> {
+    STATES
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(|(name, s)| s.active.map(|a| (name.clone(), a)))
+        .collect()
+}
+
+/// Each configured input's state.
+#[get("/inputs")]
+pub fn view_inputs(_viewer: Viewer) -> content::Json<String> {
+    let states = STATES.lock().unwrap();
+    let zone = tz::configured();
+    let result: Vec<_> = config::get()
+        .inputs
+        .into_iter()
+        .map(|input| {
+            let state = states.get(&input.name);
+            InputState {
+                pin: input.pin,
+                active: state.and_then(|s| s.active),
+                since: state
+                    .and_then(|s| s.since_ms)
+                    .map(|ms| tz::format(ms, zone)),
+                error: state.and_then(|s| s.error.clone()),
+                name: input.name,
+            }
+        })
+        .collect();
+    content::Json(serde_json::to_string(&result).unwrap())
+}
+
+/// An input's changes of state between `from` and `to`, defaulting to the last day.
+#[get("/inputs/<name>/changes?<from>&<to>&<tz>")]
+pub fn view_changes(
+    _viewer: Viewer,
+    name: String,
+    from: Option<String>,
+    to: Option<String>,
+    tz: Option<String>,
+) -> Result<content::Json<String>, ErrorResponse> {
+    if !config::get().inputs.iter().any(|i| i.name == name) {
+        return Err(api::error(
+            Status::NotFound,
+            &format!("No input named `{}`", name),
+        ));
+    }
+    let zone = tz::from_query(tz.as_deref())?;
+    let to = match to {
+        Some(t) => history::parse_time(&t, "to", zone)?,
+        None => Utc::now(),
+    };
+    let from = match from {
+        Some(f) => history::parse_time(&f, "from", zone)?,
+        None => to - chrono::Duration::days(1),
+    };
+    if from >= to {
+        return Err(api::error(Status::BadRequest, "`from` must be before `to`"));
+    }
+
+    let conn = history::open_reader()?;
+    let mut stmt = conn
+        .prepare(
+            "SELECT time, active FROM input_changes \
+             WHERE input = ?1 AND time >= ?2 AND time < ?3 ORDER BY time LIMIT ?4",
+        )
+        .map_err(history::query_error)?;
+    let changes = stmt
+        .query_map(
+            params![
+                name,
+                from.timestamp_millis(),
+                to.timestamp_millis(),
+                MAX_CHANGES
+            ],
+            |row| {
+                Ok(Change {
+                    time: tz::format(row.get(0)?, zone),
+                    active: row.get(1)?,
+                })
+            },
+        )
+        .map_err(history::query_error)?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(history::query_error)?;
+    Ok(content::Json(serde_json::to_string(&changes).unwrap()))
+}
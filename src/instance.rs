@@ -0,0 +1,132 @@
+//! This app's identity, for telling instances apart on the network: a UUID made on first
+//! run and kept in `instance-id` in the working directory, and a friendly name from
+//! `instance.name`, or else the hostname. The ID isn't in the config file, so a backup
+//! restored onto another device doesn't make it a copy of this one. Every response has
+//! it as `X-Instance-Id`, so other instances reading our readings can tell who we are.
+
+use std::{env, fmt::Write, fs, io, sync::Mutex};
+
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    Request, Response,
+};
+use serde::Serialize;
+
+use crate::config;
+
+pub const ID_PATH: &str = "instance-id";
+
+pub const HEADER: &str = "X-Instance-Id";
+
+static ID: Mutex<Option<String>> = Mutex::new(None);
+
+#[derive(Serialize)]
+pub struct Instance {
+    pub id: String,
+    pub name: String,
+}
+
+/// A random (version 4) UUID.
+fn new_id() -> String {
+    let mut b: [u8; 16] = rand::random();
+    b[6] = (b[6] & 0x0f) | 0x40;
+    b[8] = (b[8] & 0x3f) | 0x80;
+    let hex = b.iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{:02x}", b);
+        s
+    });
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+pub fn valid_id(id: &str) -> bool {
+    id.len() == 36
+        && id.char_indices().all(|(i, c)| match i {
+            8 | 13 | 18 | 23 => c == '-',
+            _ => c.is_ascii_hexdigit(),
+        })
+}
+
+/// Load the ID, making it on first run. Call at startup.
+pub fn init() -> Result<(), io::Error> {
+    let id = match fs::read_to_string(ID_PATH) {
+        Ok(s) => {
+            let id = s.trim().to_lowercase();
+            if !valid_id(&id) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "`{}` isn't a UUID; delete it to make a new one, if this app has no \
+                         other instances that knew it",
+                        ID_PATH
+                    ),
+                ));
+            }
+            id
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let id = new_id();
+            fs::write(ID_PATH, format!("{}\n", id)).map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("Problem saving the instance ID to `{}`: {}", ID_PATH, e),
+                )
+            })?;
+            id
+        }
+        Err(e) => return Err(e),
+    };
+    *ID.lock().unwrap() = Some(id);
+    Ok(())
+}
+
+pub fn id() -> String {
+    ID.lock().unwrap().clone().unwrap_or_default()
+}
+
+fn hostname() -> Option<String> {
+    env::var("HOSTNAME")
+        .or_else(|_| env::var("COMPUTERNAME"))
+        .ok()
+        .or_else(|| fs::read_to_string("/etc/hostname").ok())
+        .map(|h| h.trim().to_owned())
+        .filter(|h| !h.is_empty())
+}
+
+/// `instance.name`, or else the hostname.
+pub fn name() -> String {
+    config::get()
+        .instance
+        .name
+        .or_else(hostname)
+        .unwrap_or_else(|| "Water Monitor".into())
+}
+
+pub fn get() -> Instance {
+    Instance {
+        id: id(),
+        name: name(),
+    }
+}
+
+/// Sets `X-Instance-Id` on every response.
+pub struct InstanceHeader;
+
+impl Fairing for InstanceHeader {
+    fn info(&self) -> Info {
+        Info {
+            name: "Instance ID header",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, _request: &Request, response: &mut Response) {
+        response.set_raw_header(HEADER, id());
+    }
+}
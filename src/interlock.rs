@@ -0,0 +1,419 @@
+//! The safety interlock that everything that actuates consults first, so their checks
+//! can't drift apart. It tracks the conditions that make acting unsafe: a sensor error,
+//! stale readings, maintenance, no flow, and a manual lockout, from
+//! `POST /api/interlock/lockout`, which lasts until it's released, across restarts.
+//! Each actuator lists the conditions that block it, here; every one is blocked while
+//! the interlock's own inputs are unavailable, eg before the first reading, or if the
+//! lockout file can't be read. Changes to the conditions, and denials, are events; a
+//! denial is noted once per run of the same conditions. The state is on `/api/health`.
+//!
+//! The actuators are the device indicator's buzzer and manual setting, and, with flight
+//! controller support, setting its motor directions and restarting it to update its
+//! firmware.
+
+use std::{collections::BTreeMap, fs, io, sync::Mutex};
+
+use chrono::Utc;
+use rocket::{http::Status, response::content};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::{self, ErrorResponse},
+    auth::Admin,
+    cache, config,
+    events::{self, Severity},
+    flow, maintenance, poller,
+    status::{self, SensorStatus},
+    tz,
+};
+
+pub const SOURCE: &str = "interlock";
+
+/// The manual lockout, so it survives a restart.
+const LOCKOUT_PATH: &str = "interlock-lockout.json";
+
+/// Sounded with red, for an alert.
+pub const BUZZER: Actuator = Actuator {
+    name: "indicator.buzzer",
+    blocked_by: &[Condition::Maintenance, Condition::Lockout],
+};
+
+/// Setting the indicator by hand, with `POST /api/device/indicator`.
+pub const INDICATOR_OVERRIDE: Actuator = Actuator {
+    name: "indicator.override",
+    blocked_by: &[Condition::Lockout],
+};
+
+/// Setting the flight controller's motor directions, with `POST /api/motors/dirs`.
+#[cfg(feature = "flight-controller")]
+pub const MOTOR_DIRS: Actuator = Actuator {
+    name: "fc.motor_dirs",
+    blocked_by: &[Condition::Maintenance, Condition::Lockout],
+};
+
+/// Restarting the flight controller into its bootloader, for a firmware update from
+/// `POST /api/device/firmware`. Checked on upload, and again before the restart.
+#[cfg(feature = "flight-controller")]
+pub const FIRMWARE_UPDATE: Actuator = Actuator {
+    name: "fc.firmware_update",
+    blocked_by: &[Condition::Maintenance, Condition::Lockout],
+};
+
+/// Every actuator, for `/api/interlock`.
+const ACTUATORS: &[&Actuator] = &[
+    &BUZZER,
+    &INDICATOR_OVERRIDE,
+    #[cfg(feature = "flight-controller")]
+    &MOTOR_DIRS,
+    #[cfg(feature = "flight-controller")]
+    &FIRMWARE_UPDATE,
+];
+
+static SHARED: Mutex<Shared> = Mutex::new(Shared {
+    loaded: false,
+    lockout: None,
+    conditions: Vec::new(),
+    since_ms: None,
+    denied: BTreeMap::new(),
+});
+
+struct Shared {
+    /// If `lockout` has been read from `LOCKOUT_PATH`.
+    loaded: bool,
+    lockout: Option<Lockout>,
+    /// As of the last evaluation.
+    conditions: Vec<Condition>,
+    /// When they last changed.
+    since_ms: Option<i64>,
+    /// The conditions each actuator was last denied for, so a denial is noted once.
+    denied: BTreeMap<&'static str, Vec<Condition>>,
+}
+
+/// What makes acting unsafe.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Condition {
+    /// The interlock can't tell if it's safe; blocks everything.
+    Unavailable,
+    SensorError,
+    StaleData,
+    Maintenance,
+    NoFlow,
+    Lockout,
+}
+
+/// Something that acts on the equipment.
+pub struct Actuator {
+    pub name: &'static str,
+    /// Besides `Unavailable`.
+    pub blocked_by: &'static [Condition],
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Lockout {
+    since_ms: i64,
+    note: String,
+}
+
+#[derive(Serialize)]
+struct LockoutStatus {
+    since: String,
+    note: String,
+}
+
+#[derive(Serialize)]
+struct ActuatorStatus {
+    name: &'static str,
+    /// The conditions blocking it now.
+    blocked_by: Vec<Condition>,
+}
+
+/// For `/api/health`.
+#[derive(Serialize)]
+pub struct InterlockStatus {
+    /// If any condition holds.
+    engaged: bool,
+    conditions: Vec<Condition>,
+    /// When they last changed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    since: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lockout: Option<LockoutStatus>,
+}
+
+#[derive(Serialize)]
+struct InterlockReport {
+    #[serde(flatten)]
+    status: InterlockStatus,
+    actuators: Vec<ActuatorStatus>,
+}
+
+impl Condition {
+    fn describe(&self) -> &'static str {
+        match self {
+            Self::Unavailable => "unavailable inputs",
+            Self::SensorError => "a sensor error",
+            Self::StaleData => "stale readings",
+            Self::Maintenance => "maintenance",
+            Self::NoFlow => "no flow",
+            Self::Lockout => "the manual lockout",
+        }
+    }
+}
+
+fn listed(conditions: &[Condition]) -> String {
+    conditions
+        .iter()
+        .map(Condition::describe)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// The lockout from before a restart. One that can't be read is taken as a lockout.
+fn load_lockout() -> Option<Lockout> {
+    let unreadable = |e: String| {
+        events::record(
+            Severity::Warning,
+            SOURCE,
+            format!(
+                "Problem reading the lockout from `{}`, so actuators are locked out until \
+                 it's released: {}",
+                LOCKOUT_PATH, e
+            ),
+        );
+        Some(Lockout {
+            since_ms: Utc::now().timestamp_millis(),
+            note: format!("`{}` couldn't be read", LOCKOUT_PATH),
+        })
+    };
+    match fs::read_to_string(LOCKOUT_PATH) {
+        Ok(s) => match serde_json::from_str(&s) {
+            Ok(lockout) => Some(lockout),
+            Err(e) => unreadable(e.to_string()),
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => unreadable(e.to_string()),
+    }
+}
+
+fn save_lockout(lockout: &Option<Lockout>) -> Result<(), io::Error> {
+    match lockout {
+        Some(lockout) => {
+            let tmp = format!("{}.tmp", LOCKOUT_PATH);
+            fs::write(&tmp, serde_json::to_string(lockout).unwrap())
+                .and_then(|()| fs::rename(&tmp, LOCKOUT_PATH))
+        }
+        None => match fs::remove_file(LOCKOUT_PATH) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        },
+    }
+}
+
+/// The conditions that hold now, and the lockout, noting when they change.
+fn evaluate() -> (Vec<Condition>, Option<Lockout>) {
+    // Read before taking the lock: these record events, and might take it themselves.
+    let in_maintenance = maintenance::active();
+    let (needs_load, lockout) = match SHARED.lock() {
+        Ok(shared) => (!shared.loaded, shared.lockout.clone()),
+        Err(_) => return (vec![Condition::Unavailable], None),
+    };
+    let lockout = if needs_load { load_lockout() } else { lockout };
+
+    let cfg = config::get();
+    let statuses = status::classify(&cache::latest().readings, &cfg.status);
+    let any = |status| statuses.iter().any(|(_, s)| s == status);
+    let mut conditions = Vec::new();
+    if poller::status().seconds_since_success.is_none() || statuses.iter().next().is_none() {
+        conditions.push(Condition::Unavailable);
+    }
+    if any(SensorStatus::Error) || any(SensorStatus::Rejected) {
+        conditions.push(Condition::SensorError);
+    }
+    if any(SensorStatus::Stale) {
+        conditions.push(Condition::StaleData);
+    }
+    if in_maintenance {
+        conditions.push(Condition::Maintenance);
+    }
+    if flow::no_flow(&cfg.flow) {
+        conditions.push(Condition::NoFlow);
+    }
+    if lockout.is_some() {
+        conditions.push(Condition::Lockout);
+    }
+
+    let changed = {
+        let mut shared = match SHARED.lock() {
+            Ok(shared) => shared,
+            Err(_) => return (vec![Condition::Unavailable], lockout),
+        };
+        if needs_load && !shared.loaded {
+            shared.loaded = true;
+            shared.lockout = lockout.clone();
+        }
+        let changed = shared.since_ms.is_none() || shared.conditions != conditions;
+        if changed {
+            shared.conditions = conditions.clone();
+            shared.since_ms = Some(Utc::now().timestamp_millis());
+        }
+        changed
+    };
+    if changed {
+        if conditions.is_empty() {
+            events::record(Severity::Info, SOURCE, "The interlock is clear.");
+        } else {
+            events::record(
+                Severity::Info,
+                SOURCE,
+                format!("The interlock is engaged, for {}.", listed(&conditions)),
+            );
+        }
+    }
+    (conditions, lockout)
+}
+
+fn blocking(actuator: &Actuator, conditions: &[Condition]) -> Vec<Condition> {
+    conditions
+        .iter()
+        .copied()
+        .filter(|c| *c == Condition::Unavailable || actuator.blocked_by.contains(c))
+        .collect()
+}
+
+/// Re-evaluate the conditions, noting any change. Called by the poller after each cycle.
+pub fn refresh() {
+    evaluate();
+}
+
+/// If `actuator` may act now; if not, the conditions blocking it. Denials are events,
+/// once per run of the same conditions.
+pub fn permit(actuator: &Actuator) -> Result<(), Vec<Condition>> {
+    let blocked = blocking(actuator, &evaluate().0);
+    let note = {
+        let mut shared = match SHARED.lock() {
+            Ok(shared) => shared,
+            Err(_) => return Err(vec![Condition::Unavailable]),
+        };
+        if blocked.is_empty() {
+            shared.denied.remove(actuator.name);
+            false
+        } else {
+            shared.denied.insert(actuator.name, blocked.clone()) != Some(blocked.clone())
+        }
+    };
+    if note {
+        events::record(
+            Severity::Warning,
+            SOURCE,
+            format!(
+                "The interlock blocked `{}`, for {}.",
+                actuator.name,
+                listed(&blocked)
+            ),
+        );
+    }
+    if blocked.is_empty() {
+        Ok(())
+    } else {
+        Err(blocked)
+    }
+}
+
+/// Why an actuation was blocked, for an API response.
+pub fn denial(actuator: &Actuator, blocked: &[Condition]) -> ErrorResponse {
+    api::error(Status::Conflict, &denial_message(actuator, blocked))
+}
+
+pub fn denial_message(actuator: &Actuator, blocked: &[Condition]) -> String {
+    format!(
+        "The interlock blocks `{}`, for {}",
+        actuator.name,
+        listed(blocked)
+    )
+}
+
+pub fn status() -> InterlockStatus {
+    let (conditions, lockout) = evaluate();
+    let since_ms = SHARED.lock().ok().and_then(|s| s.since_ms);
+    let zone = tz::configured();
+    InterlockStatus {
+        engaged: !conditions.is_empty(),
+        conditions,
+        since: since_ms.map(|ms| tz::format(ms, zone)),
+        lockout: lockout.map(|l| LockoutStatus {
+            since: tz::format(l.since_ms, zone),
+            note: l.note,
+        }),
+    }
+}
+
+fn response() -> content::Json<String> {
+    let status = status();
+    let actuators = ACTUATORS
+        .iter()
+        .map(|a| ActuatorStatus {
+            name: a.name,
+            blocked_by: blocking(a, &status.conditions),
+        })
+        .collect();
+    let report = InterlockReport { status, actuators };
+    content::Json(serde_json::to_string(&report).unwrap())
+}
+
+/// The conditions that hold, the lockout, and what each actuator is blocked by.
+#[get("/interlock")]
+pub fn view_interlock() -> content::Json<String> {
+    response()
+}
+
+/// Lock out every actuator that honors it, until released, noting `note`.
+#[post("/interlock/lockout?<note>")]
+pub fn lock_out(
+    _admin: Admin,
+    note: Option<String>,
+) -> Result<content::Json<String>, ErrorResponse> {
+    let (_, current) = evaluate();
+    if let Some(current) = current {
+        return Err(api::error(
+            Status::Conflict,
+            &format!("Already locked out: {}", current.note),
+        ));
+    }
+    let lockout = Some(Lockout {
+        since_ms: Utc::now().timestamp_millis(),
+        note: note.unwrap_or_else(|| "Locked out".into()),
+    });
+    save_lockout(&lockout).map_err(|e| {
+        api::error(
+            Status::InternalServerError,
+            &format!("Problem saving the lockout to `{}`: {}", LOCKOUT_PATH, e),
+        )
+    })?;
+    let note = lockout.as_ref().map(|l| l.note.clone()).unwrap_or_default();
+    SHARED.lock().unwrap().lockout = lockout;
+    events::record(
+        Severity::Info,
+        SOURCE,
+        format!("Actuators are locked out: {}.", note),
+    );
+    Ok(response())
+}
+
+/// Release the manual lockout.
+#[post("/interlock/release")]
+pub fn release(_admin: Admin) -> Result<content::Json<String>, ErrorResponse> {
+    if evaluate().1.is_none() {
+        return Err(api::error(Status::Conflict, "Not locked out"));
+    }
+    // Still locked out if the file can't be removed, so a restart doesn't lock out again.
+    save_lockout(&None).map_err(|e| {
+        api::error(
+            Status::InternalServerError,
+            &format!("Problem removing `{}`: {}", LOCKOUT_PATH, e),
+        )
+    })?;
+    SHARED.lock().unwrap().lockout = None;
+    events::record(Severity::Info, SOURCE, "The lockout is released.");
+    Ok(response())
+}
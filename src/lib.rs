@@ -0,0 +1,1679 @@
+#![feature(proc_macro_hygiene, decl_macro)]
+#![allow(non_snake_case)]
+
+#[macro_use]
+extern crate rocket;
+
+use rocket::{
+    config::{Config, Environment, LoggingLevel},
+    fairing::AdHoc,
+    response::content,
+    Request, Rocket, Route,
+};
+
+use serde::Serialize;
+
+use std::{
+    collections::BTreeMap,
+    env, fmt, io,
+    net::Ipv4Addr,
+    path::Path,
+    process,
+    thread,
+    time::{Duration, Instant},
+};
+
+use serialport::{self, ClearBuffer, SerialPortInfo, SerialPortType};
+
+mod access_log;
+mod activity;
+mod alerts;
+mod api;
+mod auth;
+mod backup;
+mod baseline;
+mod basic;
+#[doc(hidden)]
+pub mod bench;
+mod cache;
+mod changes;
+mod channels;
+mod cli;
+mod coap;
+mod compaction;
+mod compare;
+mod config;
+mod connect;
+mod decode_trace;
+mod device_meta;
+mod distribution;
+mod events;
+mod export;
+mod exporters;
+mod font;
+#[cfg(feature = "flight-controller")]
+mod fc;
+mod flow;
+mod freshness;
+#[doc(hidden)]
+pub mod fuzz;
+mod health;
+mod history;
+mod indicator;
+mod ingest;
+mod inputs;
+mod instance;
+mod interlock;
+mod locale;
+mod maintenance;
+mod memory;
+mod metrics;
+mod migrate;
+mod modbus;
+mod net;
+mod notify;
+mod notify_presets;
+mod outages;
+mod parquet;
+mod png;
+mod poller;
+mod precision;
+mod proxy;
+mod registry;
+mod reload;
+mod reliability;
+mod request_id;
+mod retention;
+mod score;
+mod selfcheck;
+mod selftest;
+mod sensors;
+mod sequence;
+mod serial_stats;
+mod serial_trace;
+mod session;
+mod setup;
+mod signing;
+mod simulate;
+mod snapshot;
+mod snmp;
+mod spa;
+mod spec;
+mod status;
+mod supervisor;
+mod support;
+mod system;
+mod systemd;
+mod time_weighted;
+mod tokens;
+mod tz;
+mod units;
+mod unix_socket;
+mod validate;
+mod validation_hook;
+mod verify;
+mod version;
+mod warmup;
+mod win_service;
+
+use access_log::AccessLog;
+use api::{ApiVersion, Deprecated};
+use cli::{Args, Command, LogFormat};
+use config::{AppConfig, IpVersion, ServerConfig};
+use registry::{KeyNaming, Named, SensorMap, REGISTRY};
+use serial_stats::Outcome;
+use tokens::Viewer;
+
+const REFRESH_INTERVAL: u32 = 200; // Time between querying the FC for readings in ms.
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+// todo: Baud cfg?
+
+
+
+// pub enum SerialError {};
+
+/// Convert bytes to a float, from the first 4, big-endian. Fewer than 4 are an error,
+/// rather than a panic, since they come off a cable.
+/// Adapted from `water_monitor::util`
+pub fn bytes_to_float(bytes: &[u8]) -> Result<f32, io::Error> {
+    match bytes.get(..4) {
+        Some(&[a, b, c, d]) => Ok(f32::from_be_bytes([a, b, c, d])),
+        _ => Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("A float takes 4 bytes; got {}", bytes.len()),
+        )),
+    }
+}
+
+/// Indicates the first byte of a reading is valid; anything else is an error flagged by
+/// the Water Monitor firmware.
+const OK_BIT: u8 = 1;
+
+const READINGS_REQUEST: [u8; 3] = [100, 150, 200]; // todo: Don't hard code it like this.
+/// Asks for an extended set, with dissolved oxygen. Hardware without it doesn't answer
+/// with one.
+const EXTENDED_READINGS_REQUEST: [u8; 3] = [100, 150, 201];
+/// Then a state byte, which firmware with an indicator echoes back.
+const INDICATOR_REQUEST: [u8; 3] = [100, 150, 210];
+const READINGS_SIZE: usize = 20;
+const EXTENDED_READINGS_SIZE: usize = 25;
+
+/// Why a sensor has no reading. The Water Monitor firmware gives most of these as a
+/// reading's status byte.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SensorError {
+    /// The Water Monitor flagged this measurement as invalid, without saying why. Status 0.
+    BadMeasurement,
+    /// We haven't been able to take a reading from the Water Monitor.
+    NotConnected,
+    /// Status 2: an open circuit at the probe.
+    ProbeDisconnected,
+    /// Status 3: the signal is outside the ADC's range.
+    OutOfRange,
+    /// Status 4: the probe hasn't settled yet, eg after power-up.
+    NotStabilized,
+    /// Status 5: the Water Monitor couldn't reach the sensor's front end, over I2C.
+    FrontendFault,
+    /// The validation hook rejected the reading, or failed, with `on_failure = "closed"`.
+    Rejected,
+    /// A status we don't know, eg from newer firmware.
+    Unknown(u8),
+}
+
+/// `SensorError::code`s, for protocol docs.
+pub const SENSOR_ERROR_CODES: &str = "0: ok, 1: bad measurement, 2: not connected, 3: probe \
+    disconnected, 4: out of range, 5: not stabilized, 6: front end fault, 7: rejected, 256 + \
+    n: unknown status n.";
+
+impl SensorError {
+    /// From a reading's status byte, other than `OK_BIT`.
+    fn from_status(status: u8) -> Self {
+        match status {
+            0 => Self::BadMeasurement,
+            2 => Self::ProbeDisconnected,
+            3 => Self::OutOfRange,
+            4 => Self::NotStabilized,
+            5 => Self::FrontendFault,
+            s => Self::Unknown(s),
+        }
+    }
+
+    /// The status byte the firmware sends for this error. Errors it doesn't send are 0.
+    fn status(&self) -> u8 {
+        match self {
+            Self::BadMeasurement | Self::NotConnected | Self::Rejected => 0,
+            Self::ProbeDisconnected => 2,
+            Self::OutOfRange => 3,
+            Self::NotStabilized => 4,
+            Self::FrontendFault => 5,
+            Self::Unknown(s) => *s,
+        }
+    }
+
+    /// A numeric code, for protocols without strings. 0 is reserved for no error.
+    pub fn code(&self) -> u16 {
+        match self {
+            Self::BadMeasurement => 1,
+            Self::NotConnected => 2,
+            Self::ProbeDisconnected => 3,
+            Self::OutOfRange => 4,
+            Self::NotStabilized => 5,
+            Self::FrontendFault => 6,
+            Self::Rejected => 7,
+            Self::Unknown(s) => 0x100 | *s as u16,
+        }
+    }
+
+    /// What to do about it.
+    pub fn hint(&self) -> &'static str {
+        match self {
+            Self::BadMeasurement => {
+                "The Water Monitor flagged the reading as invalid; if it persists, check the \
+                 probe and its cable"
+            }
+            Self::NotConnected => "No reading from the Water Monitor; check its USB cable",
+            Self::ProbeDisconnected => "The probe may be disconnected; check the BNC connector",
+            Self::OutOfRange => {
+                "The signal is outside the measurable range; check the probe is in water, and \
+                 calibrated"
+            }
+            Self::NotStabilized => "The probe is still settling; readings should start shortly",
+            Self::FrontendFault => {
+                "The Water Monitor can't reach this sensor's circuitry; power-cycle it, and \
+                 contact support if it persists"
+            }
+            Self::Rejected => {
+                "The validation hook rejected this reading; `/api/validation-hook` has why"
+            }
+            Self::Unknown(_) => {
+                "The Water Monitor reported an error this app doesn't know; updating the app \
+                 may explain it"
+            }
+        }
+    }
+}
+
+impl fmt::Display for SensorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Unknown(s) => write!(f, "Unknown({})", s),
+            e => write!(f, "{:?}", e),
+        }
+    }
+}
+
+/// As a string, eg `ProbeDisconnected`, or `Unknown(9)`.
+impl Serialize for SensorError {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// A sensor's error, with its code and a hint, for readings responses.
+#[derive(Clone, Copy, Serialize)]
+struct ErrorDetail {
+    error: SensorError,
+    code: u16,
+    hint: &'static str,
+}
+
+/// The JSON body of API error responses.
+#[derive(Serialize)]
+pub struct ApiError {
+    pub error: String,
+    /// Of the request that failed, to find it in the event log.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+impl ApiError {
+    pub fn json(msg: &str) -> String {
+        serde_json::to_string(&Self {
+            error: msg.into(),
+            request_id: request_id::current(),
+        })
+        .unwrap()
+    }
+}
+
+/// The latest value of each sensor, or why there isn't one.
+pub type Readings = SensorMap<Result<f32, SensorError>>;
+
+impl Readings {
+    /// A sensor's reading. Sensors the hardware doesn't have aren't connected.
+    pub fn reading(&self, id: &str) -> Result<f32, SensorError> {
+        self.get(id).unwrap_or(Err(SensorError::NotConnected))
+    }
+
+    /// Read a 20-byte set, or a 25-byte extended one, with dissolved oxygen. Each
+    /// reading is 5 bytes: 1 for ok/error, the other 4 for a float, at its sensor's
+    /// offset. Copy+pasted from drivers.
+    pub fn from_bytes(buf: &[u8]) -> Self {
+        let mut result = SensorMap::empty();
+        for sensor in REGISTRY.iter() {
+            let Some(offset) = sensor.offset else {
+                continue;
+            };
+            let value = match buf.get(offset..offset + 5) {
+                Some(slot) if slot[0] == OK_BIT => {
+                    Some(bytes_to_float(&slot[1..]).map_err(|_| SensorError::BadMeasurement))
+                }
+                // These errors are identified in the Water Monitor firmware, and
+                // passed explicitly with the error code to indicate this.
+                Some(slot) => Some(Err(SensorError::from_status(slot[0]))),
+                None if sensor.required => Some(Err(SensorError::BadMeasurement)),
+                None => None,
+            };
+            result.set(sensor.id, value);
+        }
+        result
+    }
+
+    /// The set `from_bytes` reads, long enough for every sensor with a reading. Errors
+    /// are sent as their status byte, and a zeroed float.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let in_frame = || self.iter().filter_map(|(s, r)| s.offset.map(|i| (i, r)));
+        let len = in_frame().map(|(i, _)| i + 5).max().unwrap_or(0);
+        let mut result = vec![0; len];
+        for (i, reading) in in_frame() {
+            match reading {
+                Ok(v) => {
+                    result[i] = OK_BIT;
+                    result[i + 1..i + 5].copy_from_slice(&v.to_be_bytes());
+                }
+                Err(e) => result[i] = e.status(),
+            }
+        }
+        result
+    }
+}
+
+impl Default for Readings {
+    /// The required sensors, not connected.
+    fn default() -> Self {
+        let mut result = SensorMap::empty();
+        for sensor in REGISTRY.iter().filter(|s| s.required) {
+            result.set(sensor.id, Some(Err(SensorError::NotConnected)));
+        }
+        result
+    }
+}
+
+/// The Water Monitor's port, found by its USB serial number.
+fn find_port(ports: &[SerialPortInfo]) -> Option<&SerialPortInfo> {
+    ports.iter().find(|port| match &port.port_type {
+        SerialPortType::UsbPort(info) => info.serial_number.as_deref() == Some("WM"),
+        _ => false,
+    })
+}
+
+/// Where the poller gets readings.
+pub enum ReadingsSource {
+    /// The Water Monitor, on a serial port.
+    Device,
+    /// A scenario, replayed by a simulated Water Monitor.
+    Scenario(simulate::Scenario),
+}
+
+/// This mirrors that in the Python driver
+struct WaterMonitor {
+    ser: Box<dyn serialport::SerialPort>,
+    /// If it sends extended readings, with dissolved oxygen.
+    extended: bool,
+}
+
+impl WaterMonitor {
+    pub fn new() -> Result<Self, io::Error> {
+        if let Some(port) = simulate::open() {
+            let mut result = Self {
+                ser: port?,
+                extended: false,
+            };
+            result.negotiate();
+            return Ok(result);
+        }
+        let configured = config::get().serial.port;
+        let found = || {
+            serialport::available_ports()
+                .ok()
+                .and_then(|ports| find_port(&ports).map(|p| p.port_name.clone()))
+        };
+        if let Some(port_name) = configured.or_else(found) {
+            let mut result = Self {
+                ser: serialport::new(&port_name, 9_600)
+                    .timeout(READ_TIMEOUT)
+                    .open()?,
+                extended: false,
+            };
+            result.negotiate();
+            return Ok(result);
+        }
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "Can't get readings from the Water Monitor.",
+        ))
+    }
+
+    /// Find out if it sends extended readings. Older firmware either ignores the request,
+    /// which costs a read timeout, or answers with a standard set.
+    fn negotiate(&mut self) {
+        let mut rx_buf = [0; EXTENDED_READINGS_SIZE];
+        self.extended = self
+            .transact(&EXTENDED_READINGS_REQUEST, &mut rx_buf, None)
+            .is_ok();
+        let _ = self.ser.clear(ClearBuffer::Input);
+    }
+
+    pub fn read_all(&mut self) -> Result<Readings, io::Error> {
+        let (xmit_buf, len) = if self.extended {
+            (&EXTENDED_READINGS_REQUEST, EXTENDED_READINGS_SIZE)
+        } else {
+            (&READINGS_REQUEST, READINGS_SIZE)
+        };
+        let mut rx_buf = [0; EXTENDED_READINGS_SIZE];
+        let rx_buf = &mut rx_buf[..len];
+
+        // Readings aren't framed, so bytes left from an earlier, timed-out, response
+        // would offset this one, and every one after it.
+        let stale = self.ser.bytes_to_read().unwrap_or(0);
+        if stale > 0 {
+            self.ser.clear(ClearBuffer::Input)?;
+            serial_stats::record_resyncs(1, stale as u64);
+        }
+
+        let tracing = serial_trace::recording();
+        let mut timings = serial_trace::Timings::default();
+        let start = Instant::now();
+        let result = self.transact(xmit_buf, rx_buf, tracing.then_some(&mut timings));
+
+        let (bytes_read, outcome) = match &result {
+            Ok(n) => (*n, Outcome::Ok),
+            Err((n, e)) if e.kind() == io::ErrorKind::TimedOut => (*n, Outcome::Timeout),
+            Err((n, _)) => (*n, Outcome::IoError),
+        };
+        serial_stats::record(start.elapsed(), xmit_buf.len(), bytes_read, outcome);
+        serial_stats::record_frame(xmit_buf, &rx_buf[..bytes_read], outcome);
+        if tracing {
+            timings.read = start.elapsed().saturating_sub(timings.write + timings.flush);
+            serial_trace::record(
+                stale as usize,
+                xmit_buf,
+                &rx_buf[..bytes_read],
+                timings,
+                outcome,
+                result.as_ref().err().map(|(_, e)| e.to_string()),
+            );
+        }
+
+        result.map_err(|(_, e)| e)?;
+        Ok(Readings::from_bytes(rx_buf))
+    }
+
+    /// Set the onboard LED and buzzer to `state`, as the `indicator` module encodes it.
+    /// Fails with `Unsupported` if the firmware doesn't echo it back.
+    pub fn set_indicator(&mut self, state: u8) -> Result<(), io::Error> {
+        let [a, b, c] = INDICATOR_REQUEST;
+        let request = [a, b, c, state];
+        let mut rx_buf = [0; 1];
+        let start = Instant::now();
+        let result = self.transact(&request, &mut rx_buf, None);
+        let (bytes_read, outcome) = match &result {
+            Ok(n) => (*n, Outcome::Ok),
+            Err((n, e)) if e.kind() == io::ErrorKind::TimedOut => (*n, Outcome::Timeout),
+            Err((n, _)) => (*n, Outcome::IoError),
+        };
+        serial_stats::record(start.elapsed(), request.len(), bytes_read, outcome);
+
+        // Older firmware may answer with a readings set, or nothing.
+        let _ = self.ser.clear(ClearBuffer::Input);
+        match result {
+            Ok(_) if rx_buf[0] == state => Ok(()),
+            Err((_, e)) if e.kind() != io::ErrorKind::TimedOut => Err(e),
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "The Water Monitor's firmware doesn't have an indicator",
+            )),
+        }
+    }
+
+    /// Write a request, then fill `rx_buf` with the response. Returns the number of
+    /// bytes read, including on failure. With `timings`, for a trace, waits for the
+    /// request to be sent before reading, timing each.
+    fn transact(
+        &mut self,
+        tx_buf: &[u8],
+        rx_buf: &mut [u8],
+        timings: Option<&mut serial_trace::Timings>,
+    ) -> Result<usize, (usize, io::Error)> {
+        let start = Instant::now();
+        self.ser.write_all(tx_buf).map_err(|e| (0, e))?;
+        if let Some(timings) = timings {
+            timings.write = start.elapsed();
+            let sent = Instant::now();
+            self.ser.flush().map_err(|e| (0, e))?;
+            timings.flush = sent.elapsed();
+        }
+
+        let mut bytes_read = 0;
+        while bytes_read < rx_buf.len() {
+            match self.ser.read(&mut rx_buf[bytes_read..]) {
+                Ok(0) => {
+                    return Err((
+                        bytes_read,
+                        io::Error::new(io::ErrorKind::TimedOut, "Incomplete response"),
+                    ))
+                }
+                Ok(n) => bytes_read += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err((bytes_read, e)),
+            }
+        }
+        Ok(bytes_read)
+    }
+
+    /// Close the serial port
+    pub fn close(&mut self) {}
+}
+
+/// The parts of a readings response that only depend on the readings, keyed per the
+/// API version. The cache serializes them when the readings are published.
+#[derive(Serialize)]
+struct MeasuredResponse<'a> {
+    #[serde(flatten)]
+    readings: Named<'a, Result<f32, SensorError>>,
+    errors: Named<'a, ErrorDetail>,
+    /// The readings' sequence number; `None` after a failed poll cycle.
+    seq: Option<u64>,
+}
+
+/// The rest: each sensor's status, and what can change between poll cycles.
+#[derive(Serialize)]
+struct ReadingsResponse<'a> {
+    status: Named<'a, status::SensorStatus>,
+    /// When each sensor last had a fresh reading.
+    updated_at: Named<'a, freshness::Updated>,
+    /// Sensors warming up after power-up, whose readings don't count yet.
+    stabilizing: Named<'a, warmup::Stabilizing>,
+    /// Flags the validation hook attached, by sensor.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    quality: BTreeMap<&'static str, validation_hook::Quality>,
+    /// Only while in maintenance.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    maintenance: Option<maintenance::MaintenanceStatus>,
+    /// Binary inputs' states, by name.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    inputs: BTreeMap<String, bool>,
+    instance_id: String,
+}
+
+/// The measured part of a readings response, as a JSON object.
+fn measured_json(readings: &Readings, seq: Option<u64>, naming: KeyNaming) -> String {
+    let mut errors = SensorMap::empty();
+    for (sensor, reading) in readings.iter() {
+        if let Err(error) = reading {
+            errors.set(
+                sensor.id,
+                Some(ErrorDetail {
+                    error,
+                    code: error.code(),
+                    hint: error.hint(),
+                }),
+            );
+        }
+    }
+    serde_json::to_string(&MeasuredResponse {
+        readings: readings.named(naming),
+        errors: errors.named(naming),
+        seq,
+    })
+    .unwrap()
+}
+
+/// Get readings over JSON, which we've cached. `/api/v1` keys sensors in snake case;
+/// the unversioned route keeps their ids.
+fn readings(version: ApiVersion) -> content::Json<String> {
+    let naming = match version {
+        ApiVersion::Unversioned => KeyNaming::Legacy,
+        ApiVersion::V1 => KeyNaming::SnakeCase,
+    };
+    let snapshot = cache::latest();
+    let status = status::classify(&snapshot.readings, &config::get().status);
+    let rest = serde_json::to_string(&ReadingsResponse {
+        status: status.named(naming),
+        updated_at: freshness::updated_at().named(naming),
+        stabilizing: warmup::status().named(naming),
+        quality: validation_hook::quality()
+            .into_iter()
+            .filter_map(|(id, q)| Some((registry::get(id)?.json_key(naming), q)))
+            .collect(),
+        maintenance: maintenance::status(),
+        inputs: inputs::values(),
+        instance_id: instance::id(),
+    });
+    content::Json(match rest {
+        Ok(rest) => cache::join(snapshot.json(naming), &rest),
+        Err(_) => ApiError::json("Problem taking readings"),
+    })
+}
+
+#[get("/readings")]
+fn view_readings(_viewer: Viewer) -> Deprecated<content::Json<String>> {
+    Deprecated {
+        inner: readings(ApiVersion::Unversioned),
+        successor: "/api/v1/readings",
+    }
+}
+
+/// Routes under `/api/v1`.
+mod v1 {
+    use super::*;
+
+    #[get("/readings")]
+    pub fn view_readings(_viewer: Viewer) -> content::Json<String> {
+        readings(ApiVersion::V1)
+    }
+}
+
+/// Routes under `/api/v1`, as well as `/api`, whose responses are the same in both.
+fn stable_routes() -> Vec<Route> {
+    routes![
+        history::view_history,
+        export::export_parquet,
+        export::export_csv,
+        distribution::view_distribution,
+        compare::view_compare,
+        changes::view_changes,
+        reliability::view_reliability,
+        locale::view_readings_text,
+        channels::view_channels,
+        channels::view_devices,
+        sensors::view_sensors,
+        score::view_score,
+        alerts::view_alerts,
+        events::view_events,
+        flow::view_flow,
+        inputs::view_inputs,
+        inputs::view_changes,
+        health::view_health,
+        ingest::ingest
+    ]
+}
+
+/// The latest cached readings.
+fn latest_readings() -> Readings {
+    cache::latest().readings.clone()
+}
+
+/// The OpenAPI description of this API.
+#[get("/spec.json")]
+fn view_spec() -> content::Json<String> {
+    content::Json(spec::spec().to_string())
+}
+
+/// Interactive API docs.
+#[get("/docs")]
+fn view_docs() -> content::Html<&'static str> {
+    content::Html(spec::DOCS_PAGE)
+}
+
+#[catch(404)]
+fn not_found(req: &Request) -> content::Json<String> {
+    content::Json(ApiError::json(&format!("No resource at `{}`", req.uri().path())))
+}
+
+#[catch(401)]
+fn unauthorized() -> content::Json<String> {
+    content::Json(ApiError::json(
+        "Missing or wrong admin token (`Authorization: Bearer <token>`)",
+    ))
+}
+
+#[catch(403)]
+fn forbidden() -> content::Json<String> {
+    content::Json(ApiError::json(
+        "Admin routes are disabled until `auth.admin_token` or a password is set",
+    ))
+}
+
+#[catch(500)]
+fn internal_error() -> content::Json<String> {
+    content::Json(ApiError::json("Internal server error"))
+}
+
+/// Run the command line given: the server, by default. The binary is just this, so the
+/// benches can link the rest.
+pub fn main() {
+    let args = Args::parse(env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("{}\n\n{}", e, cli::USAGE);
+        process::exit(2);
+    });
+
+    match args.command {
+        Command::Run => (),
+        Command::Help => {
+            print!("{}", cli::USAGE);
+            return;
+        }
+        Command::InstallService { path, socket_port } => {
+            let path = path.unwrap_or_else(|| systemd::DEFAULT_UNIT_PATH.into());
+            if let Err(e) = systemd::install_service(&path, socket_port) {
+                eprintln!("Problem writing `{}`: {}", path.display(), e);
+                process::exit(1);
+            }
+            return;
+        }
+        Command::Service(cmd) => {
+            if let Err(e) = win_service::handle(cmd) {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+            return;
+        }
+        Command::CheckConfig { path } => {
+            if !validate::check_config(path.as_deref()) {
+                process::exit(1);
+            }
+            return;
+        }
+        Command::SetPassword => {
+            let result = AppConfig::load(Path::new(config::CONFIG_PATH)).and_then(|cfg| {
+                config::set(cfg);
+                session::set_password()
+            });
+            if let Err(e) = result {
+                eprintln!("Problem setting the password: {}", e);
+                process::exit(1);
+            }
+            return;
+        }
+        Command::SupportBundle { path } => {
+            match support::write(path) {
+                Ok(path) => println!("Wrote `{}`.", path.display()),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    process::exit(1);
+                }
+            }
+            return;
+        }
+        Command::Migrate { command, options } => {
+            let result = AppConfig::load(Path::new(config::CONFIG_PATH))
+                .map_err(|e| e.to_string())
+                .and_then(|cfg| {
+                    config::set(cfg);
+                    migrate::run(&command, &options)
+                });
+            if let Err(e) = result {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+            return;
+        }
+        Command::DecodeTrace { path } => {
+            if let Err(e) = decode_trace::run(&path) {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+            return;
+        }
+    }
+
+    events::set_journald_format(args.log_format == LogFormat::Journald);
+    let source = match &args.simulate_scenario {
+        Some(path) => match simulate::load_file(path) {
+            Ok(scenario) => ReadingsSource::Scenario(scenario),
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        },
+        None => ReadingsSource::Device,
+    };
+
+    run_server(source);
+}
+
+/// Where the server listens for TCP clients. Without them, or with systemd's sockets,
+/// it's only localhost, as their backend.
+fn listen_address(server: &ServerConfig) -> &str {
+    if systemd::activation().is_none() && (server.tcp || server.unix_socket.is_none()) {
+        server.bind_address()
+    } else {
+        "127.0.0.1"
+    }
+}
+
+/// The port the server listens on: `server.port`, or with socket activation, a free one.
+fn listen_port(server: &ServerConfig) -> u16 {
+    systemd::activation().map_or(server.port, |a| a.backend_port)
+}
+
+/// Load config, start the poller, and serve the app. Blocks until the server exits.
+fn run_server(source: ReadingsSource) {
+    let app_config =
+        AppConfig::load(Path::new(config::CONFIG_PATH)).expect("Problem loading the config file");
+    config::set(app_config.clone());
+
+    if let Err(e) = validate::check(&app_config) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+    if let Err(e) = instance::init() {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+    if let Err(e) = sequence::init() {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+    if let Err(e) = systemd::adopt_sockets() {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+
+    let server = &app_config.server;
+    selfcheck::resolve_static_dir(server);
+    if !selfcheck::run(listen_address(server), listen_port(server)) {
+        process::exit(1);
+    }
+    let first_run = setup::detect();
+
+    if let Err(e) = history::start(&app_config.history) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+
+    supervisor::install_panic_hook();
+    supervisor::spawn("poller", poller::run);
+    // Even without devices, since they can be added without a restart.
+    supervisor::spawn("device reader", channels::run);
+    supervisor::spawn("config watcher", reload::run);
+    supervisor::spawn("resource guard", system::run);
+    // Like the device reader, even without exporters.
+    supervisor::spawn("exporters", exporters::run);
+    supervisor::spawn("metrics", metrics::run);
+    supervisor::spawn("notifier", notify::run);
+    supervisor::spawn("inputs", inputs::run);
+    supervisor::spawn("flow", flow::run);
+    // Idle unless `updates.check` is set.
+    supervisor::spawn("update check", version::run);
+    if app_config.history.enabled {
+        supervisor::spawn("history compactor", compaction::run);
+        supervisor::spawn("reliability", reliability::run);
+        supervisor::spawn("alert baselines", baseline::run);
+        thread::Builder::new()
+            .name("history check".into())
+            .spawn(verify::startup)
+            .expect("Problem spawning a thread");
+    }
+
+    if let Err(e) = unix_socket::start(server) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+    if let Err(e) = modbus::start(&app_config.modbus) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+    if let Err(e) = snmp::start(&app_config.snmp) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+    if let Err(e) = coap::start(&app_config.coap) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+
+    let activation = systemd::activation();
+    if server.tcp || activation.is_some() {
+        println!(
+            "The AnyLeaf Water Monitor app launched. You can connect by opening `{}` in a \
+        web browser on this computer, or from another device on this network, like your phone, at:\n",
+            net::url(
+                Ipv4Addr::LOCALHOST.into(),
+                activation.map_or(server.port, |a| a.port())
+            )
+        );
+        let urls = net::advertised_urls(server);
+        if urls.is_empty() {
+            println!("    (Problem finding this computer's network address)");
+        }
+        for url in urls {
+            println!("    {}", url);
+        }
+        println!();
+    }
+    if let Some(banner) = systemd::banner() {
+        println!("{}", banner);
+    }
+    if let Some(banner) = unix_socket::banner(server) {
+        println!("{}", banner);
+    }
+    if first_run {
+        println!(
+            "First run: there's no `{}`, so the app is in setup mode. Set it up from the web \
+             page, or see `/api/setup/status` and `POST /api/setup`; it carries on without a \
+             restart.\n",
+            config::CONFIG_PATH
+        );
+    }
+
+    build_rocket(&app_config, source).launch();
+}
+
+/// The app, with its routes and fairings, serving readings from `source`. Shared by the
+/// server and anything else that needs the whole app, eg a local client.
+fn build_rocket(app_config: &AppConfig, source: ReadingsSource) -> Rocket {
+    if let ReadingsSource::Scenario(scenario) = source {
+        simulate::start(scenario);
+    }
+    let server = &app_config.server;
+
+    let mut config = Config::build(Environment::Staging)
+        .address(listen_address(server))
+        .port(listen_port(server))
+        .log_level(LoggingLevel::Critical); // Don't show the user the connections.
+    if let Some(key) = app_config.auth.session_key.clone().or_else(setup::session_key) {
+        config = config.secret_key(key.as_str());
+    }
+    let config = config
+        .finalize()
+        .expect("Problem setting up our custom config");
+
+    selfcheck::resolve_static_dir(server);
+    let mut rocket = rocket::custom(config)
+        .mount(
+            "/",
+            routes![
+                spa::view_index,
+                spa::view_file,
+                basic::view_basic,
+                session::view_login_page
+            ],
+        )
+        .mount(
+            "/api",
+            routes![
+                view_readings,
+                view_spec,
+                view_docs,
+                access_log::view_slow_requests,
+                serial_stats::view_serial_stats,
+                serial_stats::reset_serial_stats,
+                memory::view_memory,
+                selftest::run_selftest,
+                setup::view_setup,
+                setup::set_up,
+                simulate::view_simulation,
+                simulate::load_scenario,
+                simulate::stop_scenario,
+                selfcheck::view_selfcheck,
+                poller::view_cycle_samples,
+                poller::refresh_readings,
+                indicator::view_indicator,
+                indicator::set_indicator,
+                interlock::view_interlock,
+                validation_hook::view_status,
+                interlock::lock_out,
+                interlock::release,
+                system::view_system,
+                connect::view_qr_svg,
+                connect::view_qr_png,
+                modbus::view_map,
+                snmp::view_mib,
+                status::view_targets,
+                device_meta::set_meta,
+                device_meta::view_archived,
+                device_meta::purge,
+                status::set_targets,
+                alerts::view_rules,
+                alerts::add_rule,
+                alerts::delete_rule,
+                notify::view_routes,
+                notify::test_delivery,
+                notify::verify_signature,
+                maintenance::view_maintenance,
+                maintenance::start,
+                maintenance::stop,
+                sensors::view_ec,
+                sensors::set_ec,
+                snapshot::view_snapshot,
+                retention::view_storage,
+                verify::verify_storage,
+                session::login,
+                session::logout,
+                session::view_session,
+                backup::view_backup,
+                backup::restore,
+                reload::reload_config,
+                exporters::test_exporter,
+                support::view_support_bundle,
+                version::view_version,
+                tokens::view_tokens,
+                tokens::create_token,
+                tokens::delete_token
+            ],
+        )
+        .mount("/api", stable_routes())
+        .mount(
+            ApiVersion::V1.base(),
+            [routes![v1::view_readings], stable_routes()].concat(),
+        )
+        .register(catchers![not_found, unauthorized, forbidden, internal_error])
+        .attach(request_id::RequestIds)
+        .attach(activity::ActivityTracker)
+        .attach(spa::CacheHeaders)
+        .attach(instance::InstanceHeader)
+        .attach(AdHoc::on_launch("systemd", |_| systemd::on_launch()));
+
+    #[cfg(feature = "flight-controller")]
+    {
+        rocket = rocket.mount(
+            "/api",
+            routes![
+                fc::view_controls,
+                fc::view_params,
+                fc::view_device,
+                fc::set_motor_dirs,
+                fc::firmware::view_firmware,
+                fc::firmware::update_firmware,
+                fc::firmware::cancel_firmware
+            ],
+        );
+    }
+
+    if systemd::activation().is_some() {
+        rocket = rocket.attach(AdHoc::on_launch("socket activation", |_| {
+            systemd::forward_activated()
+        }));
+    } else if server.tcp && server.address.is_none() && server.ip_version == IpVersion::Dual {
+        let port = server.port;
+        rocket = rocket.attach(AdHoc::on_launch("ipv4 listener", move |_| {
+            net::add_ipv4_listener(port)
+        }));
+    }
+
+    if app_config.access_log.enabled {
+        match AccessLog::new(&app_config.access_log) {
+            Ok(log) => rocket = rocket.attach(log),
+            Err(e) => println!("Problem opening the access log; it's disabled: {}", e),
+        }
+    }
+
+    spec::check_routes(&rocket);
+    rocket
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        io::{Read, Write},
+        net::TcpListener,
+        sync::{Mutex, MutexGuard, Once},
+    };
+
+    use proptest::{collection::vec, prelude::*};
+    use rocket::{
+        http::{Header, Status},
+        local::{Client, LocalResponse},
+    };
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    /// A scenario holding every required sensor in range.
+    const IN_RANGE: &str = r#"
+        name = "in range"
+        [[segments]]
+        secs = 60
+        values = { T = 21.5, pH = 7.25, ORP = 650, ec = 800 }
+    "#;
+
+    /// The app, over a local client, reading from a simulated Water Monitor playing
+    /// `scenario`, with `config` as its config file. The tests share the config, cache
+    /// and simulator, so they take turns. They run in a directory of their own, for what
+    /// the app keeps in its working directory, like `sequence`.
+    fn app(config: &str, scenario: &str) -> (MutexGuard<'static, ()>, Client) {
+        static TURN: Mutex<()> = Mutex::new(());
+        static WORKING_DIR: Once = Once::new();
+        let turn = TURN.lock().unwrap_or_else(|e| e.into_inner());
+        WORKING_DIR.call_once(|| {
+            let dir = env::temp_dir().join(format!("water-mon-{}", process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            env::set_current_dir(dir).unwrap();
+        });
+        let cfg: AppConfig = toml::from_str(config).unwrap();
+        config::set(cfg.clone());
+        let source = ReadingsSource::Scenario(toml::from_str(scenario).unwrap());
+        (turn, Client::new(build_rocket(&cfg, source)).unwrap())
+    }
+
+    fn json(response: &mut LocalResponse) -> Value {
+        serde_json::from_str(&response.body_string().unwrap()).unwrap()
+    }
+
+    /// A history database of its own, removed when it's dropped.
+    struct TempHistory(String);
+
+    impl TempHistory {
+        fn new(name: &str) -> Self {
+            let path = env::temp_dir().join(format!("water-mon-{}-{}.db", process::id(), name));
+            Self(path.to_string_lossy().into_owned())
+        }
+
+        fn config(&self) -> String {
+            format!("[history]\nenabled = true\npath = '{}'", self.0)
+        }
+    }
+
+    impl Drop for TempHistory {
+        fn drop(&mut self) {
+            for suffix in ["", "-wal", "-shm"] {
+                let _ = fs::remove_file(format!("{}{}", self.0, suffix));
+            }
+        }
+    }
+
+    #[test]
+    fn readings_come_from_the_source() {
+        let (_turn, client) = app("", IN_RANGE);
+        poller::poll_once();
+
+        let mut response = client.get("/api/readings").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.headers().get_one("Deprecation"), Some("true"));
+        let body = json(&mut response);
+        assert_eq!(body["T"]["Ok"], 21.5);
+        assert_eq!(body["pH"]["Ok"], 7.25);
+        assert_eq!(body["status"]["T"], "ok");
+        // Connecting powers the probes, and ORP takes a while to settle.
+        assert_eq!(body["status"]["ORP"], "stabilizing");
+        assert!(body["seq"].is_u64());
+
+        let body = json(&mut client.get("/api/v1/readings").dispatch());
+        assert_eq!(body["temperature"]["Ok"], 21.5);
+    }
+
+    #[test]
+    fn an_unplugged_device_has_no_readings() {
+        let (_turn, client) = app(
+            "",
+            "name = \"unplugged\"\n[[segments]]\nsecs = 60\ndisconnected = true",
+        );
+        poller::poll_once();
+
+        let mut response = client.get("/api/readings").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body = json(&mut response);
+        assert!(body["seq"].is_null());
+        assert_eq!(body["T"]["Err"], "NotConnected");
+        assert!(body["errors"]["T"]["code"].is_u64());
+    }
+
+    #[test]
+    fn injected_errors_reach_the_readings() {
+        let (_turn, client) = app(
+            "",
+            r#"
+            name = "probe fault"
+            [[segments]]
+            secs = 60
+            values = { T = 21.5 }
+            errors = { pH = "ProbeDisconnected" }
+            "#,
+        );
+        poller::poll_once();
+
+        let body = json(&mut client.get("/api/readings").dispatch());
+        assert_eq!(body["T"]["Ok"], 21.5);
+        assert_eq!(body["pH"]["Err"], "ProbeDisconnected");
+        assert_eq!(body["status"]["pH"], "error");
+        assert!(body["seq"].is_u64());
+    }
+
+    /// Values replaced by their type, so responses compare by shape.
+    fn shape(v: &Value) -> Value {
+        match v {
+            Value::Object(o) => o.iter().map(|(k, v)| (k.clone(), shape(v))).collect(),
+            Value::Array(a) => a.iter().map(shape).collect(),
+            Value::Number(_) => json!("number"),
+            Value::String(_) => json!("string"),
+            v => v.clone(),
+        }
+    }
+
+    /// Pins both key namings of the readings response, so neither drifts.
+    #[test]
+    fn readings_keep_their_shape() {
+        // A sensor keeps its last update through errors. Give EC one, so what earlier
+        // tests read doesn't matter.
+        let (turn, _client) = app("", IN_RANGE);
+        poller::poll_once();
+        drop(turn);
+        let (_turn, client) = app(
+            "",
+            r#"
+            name = "ec out of range"
+            [[segments]]
+            secs = 60
+            values = { T = 21.5, pH = 7.25, ORP = 650 }
+            errors = { ec = "OutOfRange" }
+            "#,
+        );
+        poller::poll_once();
+        let error = json!({ "error": "string", "code": "number", "hint": "string" });
+
+        let legacy = json(&mut client.get("/api/readings").dispatch());
+        assert_eq!(
+            shape(&legacy),
+            json!({
+                "T": { "Ok": "number" },
+                "pH": { "Ok": "number" },
+                "ORP": { "Ok": "number" },
+                "ec": { "Err": "string" },
+                "errors": { "ec": error },
+                "seq": "number",
+                "status": { "T": "string", "pH": "string", "ORP": "string", "ec": "string" },
+                "updated_at": { "T": "string", "pH": "string", "ORP": "string", "ec": "string" },
+                "stabilizing": { "ORP": { "elapsed_secs": "number", "remaining_secs": "number" } },
+                "instance_id": "string",
+            })
+        );
+
+        let v1 = json(&mut client.get("/api/v1/readings").dispatch());
+        assert_eq!(
+            shape(&v1),
+            json!({
+                "temperature": { "Ok": "number" },
+                "ph": { "Ok": "number" },
+                "orp": { "Ok": "number" },
+                "ec": { "Err": "string" },
+                "errors": { "ec": error },
+                "seq": "number",
+                "status": { "temperature": "string", "ph": "string", "orp": "string", "ec": "string" },
+                "updated_at": {
+                    "temperature": "string",
+                    "ph": "string",
+                    "orp": "string",
+                    "ec": "string",
+                },
+                "stabilizing": { "orp": { "elapsed_secs": "number", "remaining_secs": "number" } },
+                "instance_id": "string",
+            })
+        );
+    }
+
+    /// A long run against the simulator fills the buffers that grow per poll cycle to
+    /// their caps, and no further: past that, nothing held grows.
+    #[test]
+    fn buffers_stay_within_their_caps() {
+        let (_turn, client) = app("[history]\nenabled = false", IN_RANGE);
+        let buffers = || {
+            let report = json(&mut client.get("/api/debug/memory").dispatch());
+            let buffers = report["buffers"].as_array().unwrap().clone();
+            for b in buffers.iter() {
+                assert!(b["high_water"].as_u64() <= b["cap"].as_u64(), "{}", b);
+            }
+            buffers
+        };
+        let window_cap = serial_stats::WINDOW_BUFFER.cap;
+        for _ in 0..window_cap + 1_000 {
+            poller::poll_once();
+        }
+        let filled = buffers();
+        let window = filled
+            .iter()
+            .find(|b| b["name"] == "serial.window")
+            .unwrap();
+        assert_eq!(window["len"], window_cap);
+        assert!(window["dropped"].as_u64().unwrap() > 0);
+
+        for _ in 0..5_000 {
+            poller::poll_once();
+        }
+        // Trend samples are kept by time, not by count, so the run can add one.
+        for (before, after) in filled.iter().zip(buffers().iter()) {
+            if after["name"] == "alerts.samples" {
+                continue;
+            }
+            assert!(after["len"].as_u64() <= before["len"].as_u64(), "{}", after);
+        }
+    }
+
+    /// A preset channel can be test-fired alone, and is sent what its service takes.
+    #[test]
+    fn presets_can_be_test_fired() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(2)))
+                .unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 4_096];
+            while !String::from_utf8_lossy(&request).contains("}") {
+                let n = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n{}")
+                .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let cfg = format!(
+            "[history]\nenabled = false\n[auth]\nadmin_token = 'admin'\n\
+             [[notify.channels]]\nname = 'gotify'\nkind = 'gotify'\n\
+             url = 'http://127.0.0.1:{}'\ntoken = 'tk_123'",
+            port
+        );
+        let (_turn, client) = app(&cfg, IN_RANGE);
+        let mut response = client
+            .post("/api/notify/test?channel=gotify")
+            .header(Header::new("Authorization", "Bearer admin"))
+            .body(r#"{ "category": "alerts", "severity": "alert", "rule": "High ORP" }"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let deliveries = json(&mut response);
+        assert_eq!(deliveries[0]["channel"], "gotify");
+        assert_eq!(deliveries[0]["delivered"], true, "{}", deliveries);
+
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /message "), "{}", request);
+        assert!(request.contains("X-Gotify-Key: tk_123"), "{}", request);
+        assert!(request.contains(r#""priority":8"#), "{}", request);
+    }
+
+    #[test]
+    fn old_readings_are_stale() {
+        let (_turn, client) = app("[status]\nstale_secs = 0", IN_RANGE);
+        poller::poll_once();
+
+        let body = json(&mut client.get("/api/readings").dispatch());
+        assert_eq!(body["T"]["Ok"], 21.5);
+        assert_eq!(body["status"]["T"], "stale");
+        assert!(body["updated_at"]["T"].is_string());
+    }
+
+    #[test]
+    fn history_and_its_stats() {
+        let db = TempHistory::new("stats");
+        let (_turn, client) = app(&db.config(), IN_RANGE);
+        let now = chrono::Utc::now().timestamp_millis();
+        history::write(|conn| {
+            for i in 0..10 {
+                conn.execute(
+                    "INSERT INTO samples (time, T) VALUES (?1, ?2)",
+                    rusqlite::params![now - (10 - i) * 60_000, 20. + i as f64],
+                )?;
+            }
+            Ok(())
+        })
+        .unwrap();
+
+        let mut response = client.get("/api/history").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body = json(&mut response);
+        assert_eq!(body["samples"].as_array().unwrap().len(), 10);
+        assert_eq!(body["samples"][9]["T"], 29.);
+
+        let body = json(&mut client.get("/api/history?bucket=1h&agg=avg").dispatch());
+        let buckets = body["buckets"].as_array().unwrap();
+        let averages: Vec<_> = buckets
+            .iter()
+            .filter_map(|b| b["T"]["avg"].as_f64())
+            .collect();
+        assert!(!averages.is_empty());
+
+        let mut response = client
+            .get("/api/distribution?sensor=T&hours=1&bins=10&min=20&max=30")
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body = json(&mut response);
+        assert_eq!(body["count"], 10);
+        assert_eq!(body["counts"].as_array().unwrap().len(), 10);
+        assert_eq!(body["percentiles"]["p50"], 24.);
+    }
+
+    /// A sensor that's only a registry entry, the tests' turbidity, at offset 25, is
+    /// decoded from its slot, and served, stored and alerted on like the others.
+    #[test]
+    fn a_registered_sensor_is_used_everywhere() {
+        let db = TempHistory::new("turbidity");
+        let cfg = format!(
+            "{}\n[[alerts]]\nname = 'Cloudy'\nwhen = {{ sensor = 'NTU', above = 5 }}",
+            db.config()
+        );
+        let (_turn, client) = app(&cfg, IN_RANGE);
+
+        let mut sent = Readings::default();
+        for (id, value) in [("T", 21.5), ("pH", 7.25), ("ORP", 650.), ("ec", 800.)] {
+            sent.set(id, Some(Ok(value)));
+        }
+        sent.set("NTU", Some(Ok(12.5)));
+        let frame = sent.to_bytes();
+        assert_eq!(frame.len(), 30);
+        assert_eq!(frame[25], OK_BIT);
+        let readings = Readings::from_bytes(&frame);
+        assert_eq!(readings.get("NTU"), Some(Ok(12.5)));
+        assert_eq!(Readings::from_bytes(&frame[..25]).get("NTU"), None);
+
+        cache::publish(readings.clone(), Some(1));
+        let body = json(&mut client.get("/api/readings").dispatch());
+        assert_eq!(body["NTU"]["Ok"], 12.5);
+        let body = json(&mut client.get("/api/v1/readings").dispatch());
+        assert_eq!(body["turbidity"]["Ok"], 12.5);
+
+        let now = chrono::Utc::now().timestamp_millis();
+        history::import(&[(now - 60_000, readings.clone())]).unwrap();
+        let body = json(&mut client.get("/api/history").dispatch());
+        assert_eq!(body["samples"][0]["NTU"], 12.5);
+
+        alerts::evaluate(device_meta::LOCAL, &readings);
+        let body = json(&mut client.get("/api/alerts").dispatch());
+        assert_eq!(body[0]["name"], "Cloudy");
+        assert_eq!(body[0]["active"], true, "{}", body);
+    }
+
+    /// A silent Water Monitor, for the watchdog to reopen after 2 failed cycles,
+    /// with ORP warming up for a second.
+    const SILENT: &str = r#"
+        name = "silent"
+        [[segments]]
+        secs = 60
+        values = { T = 21.5, pH = 7.25, ORP = 650, ec = 800 }
+        silent = true
+    "#;
+    const REOPENED: &str = "[history]\nenabled = false\n[watchdog]\nmax_consecutive_failures = 2\n\
+                            [warmup.sensors.ORP]\nmax_secs = 1\n[indicator]\nenabled = true";
+
+    /// A connection to `SILENT` that's failed once, after a successful poll, so the
+    /// next cycle trips the watchdog, with the turn.
+    fn reopening() -> (MutexGuard<'static, ()>, Client, Option<WaterMonitor>) {
+        let (turn, _client) = app(REOPENED, IN_RANGE);
+        poller::poll_once();
+        drop(turn);
+        let (turn, client) = app(REOPENED, SILENT);
+        let mut monitor = None;
+        poller::poll_on(&mut monitor);
+        assert!(monitor.is_some());
+        assert_eq!(poller::status().consecutive_failures, 1);
+        (turn, client, monitor)
+    }
+
+    #[test]
+    fn a_reopened_port_restarts_warm_up() {
+        let (_turn, _client, mut monitor) = reopening();
+        assert!(warmup::stabilizing("ORP"));
+        let trips = poller::status().watchdog_trips;
+
+        thread::sleep(Duration::from_millis(1_100));
+        let mut readings = Readings::default();
+        readings.set("ORP", Some(Ok(650.)));
+        warmup::update(&readings);
+        assert!(!warmup::stabilizing("ORP"));
+
+        poller::poll_on(&mut monitor);
+        assert_eq!(poller::status().watchdog_trips, trips + 1);
+        assert!(warmup::stabilizing("ORP"));
+    }
+
+    /// The simulator doesn't echo the indicator, so once it's reopened, it's found to be
+    /// unsupported again.
+    #[test]
+    fn a_reopened_port_resets_the_indicator() {
+        let (_turn, _client, mut monitor) = reopening();
+        poller::poll_on(&mut monitor);
+        let recent = events::recent(100);
+        let trip = recent.iter().position(|e| e.source == "watchdog").unwrap();
+        assert!(recent[..trip].iter().any(|e| e.source == "indicator"));
+    }
+
+    /// The self-test needs admin, and times the next request to the Water Monitor.
+    #[test]
+    fn the_selftest_needs_admin() {
+        let (_turn, client) = app(
+            "[history]\nenabled = false\n[auth]\nadmin_token = 'admin'",
+            IN_RANGE,
+        );
+        assert_eq!(
+            client.post("/api/debug/selftest").dispatch().status(),
+            Status::Unauthorized
+        );
+
+        poller::poll_once();
+        let poll = thread::spawn(|| {
+            thread::sleep(Duration::from_millis(100));
+            poller::poll_once();
+        });
+        let mut response = client
+            .post("/api/debug/selftest")
+            .header(Header::new("Authorization", "Bearer admin"))
+            .dispatch();
+        poll.join().unwrap();
+        assert_eq!(response.status(), Status::Ok);
+        let report = json(&mut response);
+        assert_eq!(report["pass"], true, "{}", report);
+        assert_eq!(report["round_trip"]["failures"], json!([]));
+        assert_eq!(report["link"]["responded"], true);
+        assert!(report["link"]["latency_ms"].is_number());
+    }
+
+    /// With `auth.private`, a token limited to devices reads only their history: a
+    /// pushing device's, with `?device=`, and this one's, without.
+    #[test]
+    fn tokens_read_only_their_devices_history() {
+        let db = TempHistory::new("tokens");
+        let cfg = format!(
+            "{}\n[auth]\nprivate = true\nadmin_token = 'admin'\n\
+             [[devices]]\nname = 'well'\ntoken = 'secret'",
+            db.config()
+        );
+        let (_turn, client) = app(&cfg, IN_RANGE);
+        let token = |devices: &str| {
+            let mut response = client
+                .post("/api/tokens")
+                .header(Header::new("Authorization", "Bearer admin"))
+                .body(format!(
+                    r#"{{ "name": "pool service", "scopes": ["history"], "devices": [{}] }}"#,
+                    devices
+                ))
+                .dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            json(&mut response)["token"].as_str().unwrap().to_owned()
+        };
+        let (well, local) = (token(r#""well""#), token(r#""local""#));
+
+        let now = chrono::Utc::now().timestamp_millis();
+        history::write(|conn| {
+            conn.execute(
+                "INSERT INTO samples (time, T) VALUES (?1, 21.5)",
+                rusqlite::params![now - 60_000],
+            )?;
+            conn.execute(
+                "INSERT INTO pushed_samples (device, time, T) VALUES ('well', ?1, 11.5)",
+                rusqlite::params![now - 60_000],
+            )
+        })
+        .unwrap();
+
+        let history = |query: &str| client.get(format!("/api/history?{}", query)).dispatch();
+        let mut response = history(&format!("device=well&token={}", well));
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(json(&mut response)["samples"][0]["T"], 11.5);
+        assert_eq!(
+            history(&format!("token={}", well)).status(),
+            Status::Unauthorized
+        );
+
+        let mut response = history(&format!("token={}", local));
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(json(&mut response)["samples"][0]["T"], 21.5);
+        assert_eq!(
+            history(&format!("device=well&token={}", local)).status(),
+            Status::Unauthorized
+        );
+    }
+
+    #[test]
+    fn errors_are_json() {
+        let (turn, client) = app("[history]\nenabled = false", IN_RANGE);
+        let error = |path: &str, status: Status| {
+            let mut response = client.get(path).dispatch();
+            let body = json(&mut response);
+            assert_eq!(response.status(), status, "{}: {}", path, body);
+            body["error"].as_str().unwrap().to_owned()
+        };
+
+        assert!(error("/api/nowhere", Status::NotFound).contains("/api/nowhere"));
+        assert!(
+            error("/api/distribution?sensor=salinity", Status::BadRequest).contains("`sensor`")
+        );
+        assert_eq!(
+            error("/api/history", Status::NotFound),
+            "History is disabled"
+        );
+        error("/api/tokens", Status::Forbidden);
+
+        let db = TempHistory::new("errors");
+        drop(turn);
+        let (_turn, client) = app(&db.config(), IN_RANGE);
+        let mut response = client.get("/api/history?from=yesterday").dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        assert!(json(&mut response)["error"]
+            .as_str()
+            .unwrap()
+            .contains("`from`"));
+    }
+
+    #[test]
+    fn the_web_page_is_a_single_page_app() {
+        let dir = env::temp_dir().join(format!("water-mon-{}-static", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("index.html"), "<html>app</html>").unwrap();
+        fs::write(dir.join("app.3f9a1c2b.js"), "app()").unwrap();
+        let cfg = format!(
+            "[history]\nenabled = false\n[server]\nstatic_dir = '{}'",
+            dir.display()
+        );
+        let (_turn, client) = app(&cfg, IN_RANGE);
+        let get = |path: &str| {
+            let mut response = client.get(path).dispatch();
+            let cache = response
+                .headers()
+                .get_one("Cache-Control")
+                .map(str::to_owned);
+            (response.status(), cache, response.body_string())
+        };
+
+        let (status, cache, body) = get("/history/week");
+        assert_eq!(status, Status::Ok);
+        assert_eq!(cache.as_deref(), Some("no-cache"));
+        assert_eq!(body.as_deref(), Some("<html>app</html>"));
+
+        let (status, cache, _) = get("/app.3f9a1c2b.js");
+        assert_eq!(status, Status::Ok);
+        assert_eq!(
+            cache.as_deref(),
+            Some("public, max-age=31536000, immutable")
+        );
+
+        assert_eq!(get("/app.0000000a.js").0, Status::NotFound);
+        let (status, cache, body) = get("/api/history/week");
+        assert_eq!(status, Status::NotFound);
+        assert_eq!(cache, None);
+        assert!(body.unwrap().contains("\"error\""));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    proptest! {
+        #[test]
+        fn floats_are_their_first_4_bytes(bytes in vec(any::<u8>(), 0..8)) {
+            match bytes_to_float(&bytes) {
+                Ok(v) => prop_assert_eq!(
+                    v.to_bits(),
+                    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+                ),
+                Err(_) => prop_assert!(bytes.len() < 4),
+            }
+        }
+
+        /// Required sensors cut off are errors, rather than a panic or a made-up value.
+        #[test]
+        fn any_bytes_decode_as_readings(buf in vec(any::<u8>(), 0..32)) {
+            let readings = Readings::from_bytes(&buf);
+            for sensor in REGISTRY.iter().filter(|s| s.required) {
+                let offset = sensor.offset.unwrap();
+                if buf.len() < offset + 5 {
+                    prop_assert_eq!(
+                        readings.get(sensor.id),
+                        Some(Err(SensorError::BadMeasurement))
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,150 @@
+//! The locale, for numbers and units in human-facing text: the plain-text readings, and
+//! event messages. It's a language tag, eg `de-DE`; the language picks the decimal
+//! separator, and the region, the temperature unit. JSON is unaffected: numbers there
+//! are always plain JSON numbers, in the registry's units.
+
+use std::io;
+
+use rocket::{http::Status, response::content};
+
+use crate::{
+    api::{self, ErrorResponse},
+    config::{self, LocaleConfig, PrecisionConfig},
+    precision,
+    registry::{Kind, SensorDef},
+    status,
+    tokens::Viewer,
+};
+
+/// Languages writing `1,5` rather than `1.5`.
+const DECIMAL_COMMA: [&str; 30] = [
+    "af", "bg", "ca", "cs", "da", "de", "el", "es", "et", "eu", "fi", "fr", "gl", "hr", "hu", "id",
+    "is", "it", "lt", "lv", "nb", "nl", "nn", "no", "pl", "pt", "ro", "ru", "sk", "sv",
+];
+
+/// Regions using °F.
+const FAHRENHEIT: [&str; 5] = ["US", "BS", "BZ", "KY", "LR"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Locale {
+    decimal_comma: bool,
+    fahrenheit: bool,
+}
+
+pub fn parse(tag: &str) -> Result<Locale, String> {
+    let invalid = || format!("`{}` isn't a language tag, like `en-GB` or `de-DE`", tag);
+
+    let mut parts = tag.split(['-', '_']);
+    let language = parts.next().unwrap_or_default().to_ascii_lowercase();
+    if !(2..=3).contains(&language.len()) || !language.bytes().all(|b| b.is_ascii_alphabetic()) {
+        return Err(invalid());
+    }
+    // Skip a script, eg `Latn`.
+    let region = parts
+        .find(|p| p.len() != 4)
+        .map(|p| p.to_ascii_uppercase())
+        .unwrap_or_default();
+    if !region.is_empty() && region.len() != 2 && region.len() != 3 {
+        return Err(invalid());
+    }
+
+    Ok(Locale {
+        decimal_comma: DECIMAL_COMMA.contains(&language.as_str()),
+        fahrenheit: FAHRENHEIT.contains(&region.as_str()),
+    })
+}
+
+/// Check the configured locale at startup.
+pub fn check(cfg: &LocaleConfig) -> Result<(), io::Error> {
+    parse(&cfg.language)
+        .map(|_| ())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("Config error: {}", e)))
+}
+
+/// The configured locale.
+pub fn configured() -> Locale {
+    parse(&config::get().locale.language).unwrap_or(Locale {
+        decimal_comma: false,
+        fahrenheit: false,
+    })
+}
+
+/// The locale from a `?locale=` override, or else the configured one.
+pub fn from_query(locale: Option<&str>) -> Result<Locale, ErrorResponse> {
+    match locale {
+        Some(tag) => parse(tag).map_err(|e| api::error(Status::BadRequest, &e)),
+        None => Ok(configured()),
+    }
+}
+
+impl Locale {
+    pub fn number(&self, value: f64, decimals: usize) -> String {
+        let s = format!("{:.*}", decimals, value);
+        if self.decimal_comma {
+            s.replace('.', ",")
+        } else {
+            s
+        }
+    }
+
+    /// The unit to show a sensor's readings in.
+    pub fn unit(&self, sensor: &SensorDef) -> &'static str {
+        if sensor.kind == Kind::Temperature && self.fahrenheit {
+            "°F"
+        } else {
+            sensor.unit
+        }
+    }
+
+    /// A reading with its unit, eg `24,5 °C`, to its sensor's precision.
+    pub fn reading(&self, sensor: &SensorDef, value: f32, cfg: &PrecisionConfig) -> String {
+        let unit = self.unit(sensor);
+        let value = if unit == "°F" {
+            value * 1.8 + 32.
+        } else {
+            value
+        };
+        let number = match precision::decimals(sensor, cfg) {
+            Some(decimals) => self.number(precision::round(value, decimals) as f64, decimals),
+            // As written in JSON.
+            None => {
+                let written = value.to_string();
+                let decimals = written.split_once('.').map_or(0, |(_, f)| f.len());
+                self.number(value as f64, decimals)
+            }
+        };
+        // pH is a scale, not a unit.
+        if sensor.kind == Kind::Ph {
+            number
+        } else {
+            format!("{} {}", number, unit)
+        }
+    }
+}
+
+/// The latest readings as text, one sensor per line, in the locale.
+#[get("/readings/text?<locale>")]
+pub fn view_readings_text(
+    _viewer: Viewer,
+    locale: Option<String>,
+) -> Result<content::Plain<String>, ErrorResponse> {
+    let locale = from_query(locale.as_deref())?;
+    let readings = crate::latest_readings();
+    let cfg = config::get();
+    let statuses = status::classify(&readings, &cfg.status);
+
+    let mut text = String::new();
+    for (sensor, reading) in readings.iter() {
+        let value = match reading {
+            Ok(v) => locale.reading(sensor, v, &cfg.precision),
+            Err(_) => "-".into(),
+        };
+        let status = statuses
+            .get(sensor.id)
+            .and_then(|s| serde_json::to_value(s).ok())
+            .and_then(|s| s.as_str().map(str::to_owned))
+            .unwrap_or_default();
+        text.push_str(&format!("{:<4} {:>14}  {}\n", sensor.id, value, status));
+    }
+    Ok(content::Plain(text))
+}
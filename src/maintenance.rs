@@ -0,0 +1,234 @@
+//! Maintenance mode, for servicing probes: while it's on, alert rules aren't evaluated,
+//! notifications aren't sent, and samples are flagged in history, so stats, compaction
+//! and baselines leave them out. It always has an end, so it can't be left on by
+//! mistake. Each window is noted in history's `maintenance` table, with what was done.
+
+use std::sync::Mutex;
+
+use chrono::Utc;
+use rocket::{http::Status, response::content};
+use rusqlite::{params, Connection};
+use serde::Serialize;
+
+use crate::{
+    api::{self, ErrorResponse},
+    auth::Admin,
+    config,
+    events::{self, Severity},
+    history, tz,
+};
+
+/// Events about maintenance itself are still delivered.
+pub const SOURCE: &str = "maintenance";
+
+const DEFAULT_DURATION: &str = "30m";
+
+const MAX_DURATION_MS: i64 = 24 * 3_600_000;
+
+/// Past windows listed by `/api/maintenance`.
+const MAX_WINDOWS: i64 = 50;
+
+static WINDOW: Mutex<Option<Window>> = Mutex::new(None);
+
+struct Window {
+    start_ms: i64,
+    end_ms: i64,
+    note: String,
+    /// Its row in the `maintenance` table, if history is enabled.
+    row: Option<i64>,
+}
+
+/// For `/api/health`, and readings.
+#[derive(Clone, Serialize)]
+pub struct MaintenanceStatus {
+    pub since: String,
+    pub until: String,
+    pub remaining_secs: u64,
+    pub note: String,
+}
+
+#[derive(Serialize)]
+struct PastWindow {
+    start: String,
+    end: String,
+    note: String,
+}
+
+#[derive(Serialize)]
+struct Maintenance {
+    /// `None` unless in maintenance.
+    current: Option<MaintenanceStatus>,
+    /// Most recent first, from history.
+    windows: Vec<PastWindow>,
+}
+
+/// The current window, if any. One that's expired is ended here, so the poller, which
+/// checks each cycle, ends it on time.
+pub fn status() -> Option<MaintenanceStatus> {
+    let now = Utc::now().timestamp_millis();
+    let expired = {
+        let mut window = WINDOW.lock().unwrap();
+        match window.as_ref() {
+            Some(w) if now < w.end_ms => {
+                let zone = tz::configured();
+                return Some(MaintenanceStatus {
+                    since: tz::format(w.start_ms, zone),
+                    until: tz::format(w.end_ms, zone),
+                    remaining_secs: ((w.end_ms - now) / 1_000) as u64,
+                    note: w.note.clone(),
+                });
+            }
+            Some(_) => window.take(),
+            None => None,
+        }
+    };
+    // Outside the lock, since recording an event checks if we're in maintenance.
+    if expired.is_some() {
+        events::record(
+            Severity::Info,
+            SOURCE,
+            "Maintenance has expired; alerts and notifications are back on.",
+        );
+    }
+    None
+}
+
+pub fn active() -> bool {
+    status().is_some()
+}
+
+/// Run a write on history, if it's enabled. Failures are warned about, but don't stop
+/// maintenance starting or stopping.
+fn write_history<T>(f: impl FnOnce(&Connection) -> Result<T, rusqlite::Error>) -> Option<T> {
+    if !config::get().history.enabled {
+        return None;
+    }
+    match history::write(f) {
+        Ok(v) => Some(v),
+        Err(e) => {
+            events::record(
+                Severity::Warning,
+                SOURCE,
+                format!("Problem noting maintenance in history: {}", e),
+            );
+            None
+        }
+    }
+}
+
+fn response() -> content::Json<String> {
+    let windows = history::open_reader()
+        .ok()
+        .and_then(|conn| {
+            let zone = tz::configured();
+            let mut stmt = conn
+                .prepare("SELECT start, end, note FROM maintenance ORDER BY start DESC LIMIT ?1")
+                .ok()?;
+            let rows = stmt
+                .query_map(params![MAX_WINDOWS], |row| {
+                    Ok(PastWindow {
+                        start: tz::format(row.get(0)?, zone),
+                        end: tz::format(row.get(1)?, zone),
+                        note: row.get(2)?,
+                    })
+                })
+                .ok()?;
+            rows.collect::<Result<Vec<_>, _>>().ok()
+        })
+        .unwrap_or_default();
+
+    let result = Maintenance {
+        current: status(),
+        windows,
+    };
+    content::Json(serde_json::to_string(&result).unwrap())
+}
+
+/// If we're in maintenance, until when, and past windows.
+#[get("/maintenance")]
+pub fn view_maintenance() -> content::Json<String> {
+    response()
+}
+
+/// Start maintenance for `duration`, eg `30m`, the default, up to a day, noting `note`
+/// in history.
+#[post("/maintenance/start?<duration>&<note>")]
+pub fn start(
+    _admin: Admin,
+    duration: Option<String>,
+    note: Option<String>,
+) -> Result<content::Json<String>, ErrorResponse> {
+    let duration = duration.unwrap_or_else(|| DEFAULT_DURATION.into());
+    let duration_ms = history::parse_duration(&duration)
+        .filter(|ms| *ms <= MAX_DURATION_MS)
+        .ok_or_else(|| {
+            api::error(
+                Status::BadRequest,
+                &format!(
+                    "`duration` must be a duration up to a day, like `30m` or `2h`; got `{}`",
+                    duration
+                ),
+            )
+        })?;
+    if let Some(current) = status() {
+        return Err(api::error(
+            Status::Conflict,
+            &format!(
+                "Already in maintenance, until {}; stop it first",
+                current.until
+            ),
+        ));
+    }
+
+    let start_ms = Utc::now().timestamp_millis();
+    let end_ms = start_ms + duration_ms;
+    let note = note.unwrap_or_else(|| "Maintenance".into());
+    let row = write_history(|conn| {
+        conn.execute(
+            "INSERT INTO maintenance (start, end, note) VALUES (?1, ?2, ?3)",
+            params![start_ms, end_ms, note],
+        )?;
+        Ok(conn.last_insert_rowid())
+    });
+    *WINDOW.lock().unwrap() = Some(Window {
+        start_ms,
+        end_ms,
+        note: note.clone(),
+        row,
+    });
+    events::record(
+        Severity::Info,
+        SOURCE,
+        format!(
+            "Maintenance started, for {}: {}. Alerts and notifications are off until {}.",
+            duration,
+            note,
+            tz::format(end_ms, tz::configured())
+        ),
+    );
+    Ok(response())
+}
+
+/// End maintenance early.
+#[post("/maintenance/stop")]
+pub fn stop(_admin: Admin) -> Result<content::Json<String>, ErrorResponse> {
+    if !active() {
+        return Err(api::error(Status::Conflict, "Not in maintenance"));
+    }
+    let window = WINDOW.lock().unwrap().take();
+    if let Some(Window { row: Some(row), .. }) = window {
+        let end_ms = Utc::now().timestamp_millis();
+        write_history(|conn| {
+            conn.execute(
+                "UPDATE maintenance SET end = ?1 WHERE rowid = ?2",
+                params![end_ms, row],
+            )
+        });
+    }
+    events::record(
+        Severity::Info,
+        SOURCE,
+        "Maintenance stopped; alerts and notifications are back on.",
+    );
+    Ok(response())
+}
@@ -0,0 +1,161 @@
+//! What's held in memory, at `GET /api/debug/memory`: each in-process buffer's length
+//! against its cap, its high-water mark, and how many entries it's dropped, with the
+//! process's resident size. Every buffer that grows with time or traffic has a cap, and
+//! a policy for when it's hit: logs and windows drop their oldest entry, queues to a
+//! stuck writer drop the newest, and CoAP observers past the cap are turned away.
+//! Run the simulator for a while and watch this to see memory stay flat.
+
+use std::{collections::BTreeMap, fs, sync::Mutex};
+
+use rocket::response::content;
+use serde::Serialize;
+
+use crate::{
+    access_log, alerts, channels, coap, events, exporters, flow, history, notify, serial_stats,
+    serial_trace, simulate, snapshot, warmup,
+};
+
+static USAGE: Mutex<BTreeMap<&'static str, Usage>> = Mutex::new(BTreeMap::new());
+
+/// Every buffer, for the report.
+const BUFFERS: [&Buffer; 17] = [
+    &access_log::RECENT_BUFFER,
+    &access_log::WRITE_BUFFER,
+    &alerts::BUFFER,
+    &channels::BUFFER,
+    &coap::BUFFER,
+    &events::BUFFER,
+    &exporters::BUFFER,
+    &flow::BUFFER,
+    &history::BUFFER,
+    &notify::QUEUE_BUFFER,
+    &notify::DIGEST_BUFFER,
+    &serial_stats::WINDOW_BUFFER,
+    &serial_stats::FRAMES_BUFFER,
+    &serial_trace::BUFFER,
+    &simulate::BUFFER,
+    &snapshot::BUFFER,
+    &warmup::BUFFER,
+];
+
+/// What happens to an entry that would take a buffer past its cap.
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Policy {
+    /// The oldest entry is dropped to make room.
+    DropOldest,
+    /// The new entry is dropped.
+    DropNewest,
+    /// The new entry, eg a CoAP observer, is turned away, and told so.
+    Refuse,
+}
+
+/// An in-process buffer, and its cap.
+pub struct Buffer {
+    pub name: &'static str,
+    /// Entries; for a buffer kept per exporter, channel or sensor, in each one.
+    pub cap: usize,
+    pub policy: Policy,
+    pub about: &'static str,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Usage {
+    len: usize,
+    high_water: usize,
+    dropped: u64,
+}
+
+#[derive(Serialize)]
+struct BufferReport {
+    name: &'static str,
+    about: &'static str,
+    /// For a buffer kept per exporter, channel or sensor, the longest.
+    len: usize,
+    cap: usize,
+    /// The longest it's been since startup.
+    high_water: usize,
+    /// Entries dropped, or turned away, at the cap since startup.
+    dropped: u64,
+    policy: Policy,
+}
+
+#[derive(Serialize)]
+struct MemoryReport {
+    /// The process's resident set, from `/proc/self/status`; `None` elsewhere.
+    rss_bytes: Option<u64>,
+    /// The most it's been.
+    peak_rss_bytes: Option<u64>,
+    buffers: Vec<BufferReport>,
+}
+
+impl Buffer {
+    fn update(&self, f: impl FnOnce(&mut Usage)) {
+        let mut usage = USAGE.lock().unwrap();
+        let usage = usage.entry(self.name).or_default();
+        f(usage);
+        usage.high_water = usage.high_water.max(usage.len);
+    }
+
+    /// Note the buffer's length, after it's changed.
+    pub fn note(&self, len: usize) {
+        self.update(|u| u.len = len);
+    }
+
+    /// Note an entry added to a queue whose length can't be read, eg a channel.
+    pub fn added(&self) {
+        self.update(|u| u.len += 1);
+    }
+
+    /// Note an entry taken from a queue whose length can't be read.
+    pub fn removed(&self) {
+        self.update(|u| u.len = u.len.saturating_sub(1));
+    }
+
+    /// Count entries dropped, or turned away, at the cap.
+    pub fn dropped(&self, n: u64) {
+        self.update(|u| u.dropped += n);
+    }
+}
+
+/// A field of `/proc/self/status`, in bytes.
+fn status_bytes(status: &str, name: &str) -> Option<u64> {
+    let line = status.lines().find(|l| l.starts_with(name))?;
+    let kb: u64 = line[name.len()..]
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()?;
+    Some(kb * 1_024)
+}
+
+/// Each in-process buffer's length, cap, high-water mark and drops, and the process's
+/// resident size.
+#[get("/debug/memory")]
+pub fn view_memory() -> content::Json<String> {
+    let status = fs::read_to_string("/proc/self/status").unwrap_or_default();
+    let usage = USAGE.lock().unwrap().clone();
+    let buffers = BUFFERS
+        .iter()
+        .map(|b| {
+            let u = usage.get(b.name).copied().unwrap_or_default();
+            BufferReport {
+                name: b.name,
+                about: b.about,
+                len: u.len,
+                cap: b.cap,
+                high_water: u.high_water,
+                dropped: u.dropped,
+                policy: b.policy,
+            }
+        })
+        .collect();
+
+    let report = MemoryReport {
+        rss_bytes: status_bytes(&status, "VmRSS:"),
+        peak_rss_bytes: status_bytes(&status, "VmHWM:"),
+        buffers,
+    };
+    content::Json(serde_json::to_string(&report).unwrap())
+}
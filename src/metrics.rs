@@ -0,0 +1,289 @@
+//! Pushing gauges to StatsD, over UDP, or Graphite's plaintext protocol, over TCP, for
+//! setups without Prometheus: each sensor's latest usable reading, and the serial link's
+//! counters, every `metrics.interval_secs`. With `[[devices]]`, each device's readings
+//! are under its name, eg `water_mon.north.pH`, or tagged with it, for StatsD with
+//! `tags`, with its label too, if it has one. Pushing runs on its own thread, so a slow or missing server never holds up
+//! polling; failures are only counted, with a warning at most hourly.
+
+use std::{
+    fmt::Write as _,
+    io::{self, Write},
+    net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use serde::Serialize;
+
+use crate::{
+    channels,
+    config::{self, AppConfig, MetricsConfig, MetricsProtocol},
+    device_meta,
+    events::{self, Severity},
+    precision, score, serial_stats,
+};
+
+const TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Keeps StatsD datagrams under a typical MTU.
+const MAX_DATAGRAM: usize = 1_432;
+
+const WARN_EVERY: Duration = Duration::from_secs(60 * 60);
+
+static STATE: Mutex<State> = Mutex::new(State::new());
+
+struct State {
+    pushes: u64,
+    failures: u64,
+    last_error: Option<String>,
+    last_warning: Option<Instant>,
+}
+
+impl State {
+    const fn new() -> Self {
+        Self {
+            pushes: 0,
+            failures: 0,
+            last_error: None,
+            last_warning: None,
+        }
+    }
+}
+
+/// For `/api/health`.
+#[derive(Serialize)]
+pub struct MetricsStatus {
+    /// Successful pushes, and failed ones.
+    pub pushes: u64,
+    pub failures: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+struct Metric {
+    name: String,
+    value: f64,
+    device: Option<String>,
+    /// The device's label, when it's tagged.
+    label: Option<String>,
+}
+
+/// A name part, with anything but letters, digits, `_` and `-` replaced, since dots
+/// separate parts, and spaces separate fields.
+fn sanitize(part: &str) -> String {
+    part.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn metric_name(prefix: &str, parts: &[&str]) -> String {
+    let prefix = prefix.trim_matches('.');
+    let mut name = prefix.to_owned();
+    for part in parts {
+        if !name.is_empty() {
+            name.push('.');
+        }
+        name.push_str(&sanitize(part));
+    }
+    name
+}
+
+fn metrics(cfg: &MetricsConfig) -> Vec<Metric> {
+    let devices = channels::device_values();
+    let rounding = config::get().precision;
+    // The device only goes in the name when there's more than one.
+    let by_device = devices.len() > 1;
+    let tagged = by_device && cfg.tags && cfg.protocol == MetricsProtocol::Statsd;
+
+    let mut result = Vec::new();
+    for (device, values) in devices.iter() {
+        for (sensor, value) in values.iter() {
+            let name = if by_device && !tagged {
+                metric_name(&cfg.prefix, &[device, sensor])
+            } else {
+                metric_name(&cfg.prefix, &[sensor])
+            };
+            result.push(Metric {
+                name,
+                value: precision::value(sensor, *value, &rounding) as f64,
+                device: tagged.then(|| device.clone()),
+                label: tagged.then(|| device_meta::label(device)).flatten(),
+            });
+        }
+    }
+
+    if let Some(score) = score::current(&config::get(), &crate::latest_readings()).score {
+        result.push(Metric {
+            name: metric_name(&cfg.prefix, &["score"]),
+            value: score as f64,
+            device: None,
+            label: None,
+        });
+    }
+
+    let serial = serial_stats::report();
+    let counters = [
+        ("transactions", Some(serial.transactions as f64)),
+        ("timeouts", Some(serial.timeouts as f64)),
+        ("crc_failures", Some(serial.crc_failures as f64)),
+        ("io_errors", Some(serial.io_errors as f64)),
+        ("resyncs", Some(serial.resyncs as f64)),
+        ("success_rate", serial.window_success_rate.map(f64::from)),
+        ("latency_p95_ms", serial.latency_p95_ms.map(f64::from)),
+    ];
+    for (counter, value) in counters {
+        if let Some(value) = value {
+            result.push(Metric {
+                name: metric_name(&cfg.prefix, &["serial", counter]),
+                value,
+                device: None,
+                label: None,
+            });
+        }
+    }
+    result
+}
+
+fn resolve(address: &str) -> Result<SocketAddr, io::Error> {
+    address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No address for the host"))
+}
+
+fn send_statsd(address: SocketAddr, metrics: &[Metric]) -> Result<(), io::Error> {
+    let local: SocketAddr = if address.is_ipv4() {
+        "0.0.0.0:0".parse().unwrap()
+    } else {
+        "[::]:0".parse().unwrap()
+    };
+    let socket = UdpSocket::bind(local)?;
+    socket.set_write_timeout(Some(TIMEOUT))?;
+
+    let mut datagram = String::new();
+    for metric in metrics {
+        let mut line = format!("{}:{}|g", metric.name, metric.value);
+        if let Some(device) = &metric.device {
+            let _ = write!(line, "|#device:{}", sanitize(device));
+            if let Some(label) = &metric.label {
+                let _ = write!(line, ",label:{}", sanitize(label));
+            }
+        }
+        if !datagram.is_empty() && datagram.len() + 1 + line.len() > MAX_DATAGRAM {
+            socket.send_to(datagram.as_bytes(), address)?;
+            datagram.clear();
+        }
+        if !datagram.is_empty() {
+            datagram.push('\n');
+        }
+        datagram.push_str(&line);
+    }
+    if !datagram.is_empty() {
+        socket.send_to(datagram.as_bytes(), address)?;
+    }
+    Ok(())
+}
+
+fn send_graphite(address: SocketAddr, metrics: &[Metric]) -> Result<(), io::Error> {
+    let time = Utc::now().timestamp();
+    let mut lines = String::new();
+    for metric in metrics {
+        let _ = writeln!(lines, "{} {} {}", metric.name, metric.value, time);
+    }
+
+    let mut stream = TcpStream::connect_timeout(&address, TIMEOUT)?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    stream.write_all(lines.as_bytes())
+}
+
+fn push(cfg: &MetricsConfig) -> Result<(), io::Error> {
+    let metrics = metrics(cfg);
+    if metrics.is_empty() {
+        return Ok(());
+    }
+    let address = resolve(&cfg.address())?;
+    match cfg.protocol {
+        MetricsProtocol::Statsd => send_statsd(address, &metrics),
+        MetricsProtocol::Graphite => send_graphite(address, &metrics),
+    }
+}
+
+/// Check that the address is a `host:port`, and the interval isn't 0.
+pub fn check(cfg: &AppConfig) -> Result<(), io::Error> {
+    let metrics = &cfg.metrics;
+    let invalid = |msg: String| {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Config error: {}", msg),
+        ))
+    };
+
+    if let Some(address) = &metrics.address {
+        let valid = address
+            .rsplit_once(':')
+            .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok());
+        if !valid {
+            return invalid(format!(
+                "`metrics.address` must be a `host:port`, eg \"graphite.local:2003\"; got \"{}\"",
+                address
+            ));
+        }
+    }
+    if metrics.interval_secs == 0 {
+        return invalid("`metrics.interval_secs` must be at least 1".into());
+    }
+    Ok(())
+}
+
+/// Push metrics, forever, while enabled; run this on its own thread.
+pub fn run() {
+    loop {
+        let cfg = config::get().metrics;
+        if cfg.enabled {
+            let result = push(&cfg);
+            let mut state = STATE.lock().unwrap();
+            match result {
+                Ok(()) => {
+                    state.pushes += 1;
+                    state.last_error = None;
+                }
+                Err(e) => {
+                    state.failures += 1;
+                    state.last_error = Some(e.to_string());
+                    if state.last_warning.is_none_or(|t| t.elapsed() >= WARN_EVERY) {
+                        state.last_warning = Some(Instant::now());
+                        events::record(
+                            Severity::Warning,
+                            "metrics",
+                            format!(
+                                "Problem pushing metrics to {}: {}. {} pushes have failed so \
+                                 far; this is only reported hourly.",
+                                cfg.address(),
+                                e,
+                                state.failures
+                            ),
+                        );
+                    }
+                }
+            }
+        }
+        thread::sleep(Duration::from_secs(cfg.interval_secs.max(1) as u64));
+    }
+}
+
+pub fn status() -> MetricsStatus {
+    let state = STATE.lock().unwrap();
+    MetricsStatus {
+        pushes: state.pushes,
+        failures: state.failures,
+        last_error: state.last_error.clone(),
+    }
+}
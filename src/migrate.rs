@@ -0,0 +1,539 @@
+//! `water-mon-app migrate`: moving readings history in and out of the database. `import`
+//! reads a CSV or NDJSON log, `export` writes a range of history as either, and `copy`
+//! merges one history database into another. History holds one device's readings, so
+//! its samples are told apart by time: imports skip readings within a second of one
+//! already stored, as the flight controller's buffered readings do, and copies skip
+//! rows stored at the same time, or overlapping an aggregate row. So running either
+//! again changes nothing.
+
+use std::{
+    collections::BTreeSet,
+    fs,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use chrono::Utc;
+use rusqlite::params;
+use serde_json::{json, Map, Value};
+
+use crate::{
+    cli::{DataFormat, MigrateCommand, MigrateOptions},
+    config::{self, AppConfig},
+    history,
+    registry::{self, SensorDef},
+    tz, Readings,
+};
+
+/// Problems listed in a summary; the rest are only counted.
+const MAX_PROBLEMS: usize = 10;
+
+/// Quarantined rows' `source`.
+const SOURCE: &str = "migrate";
+
+/// Times below this are taken as seconds since the epoch, and others as milliseconds.
+/// It's in the year 5138 as seconds, and in 1973 as milliseconds.
+const SECONDS_BELOW: f64 = 1e11;
+
+/// What became of an import's rows.
+#[derive(Default)]
+struct Summary {
+    rows: usize,
+    imported: usize,
+    duplicates: usize,
+    invalid: usize,
+    implausible: usize,
+    /// Descriptions of the first `MAX_PROBLEMS` invalid or implausible rows.
+    problems: Vec<String>,
+    ignored_columns: BTreeSet<String>,
+}
+
+impl Summary {
+    fn problem(&mut self, line: usize, msg: String) {
+        if self.problems.len() < MAX_PROBLEMS {
+            self.problems.push(format!("Line {}: {}", line, msg));
+        }
+    }
+}
+
+/// Run a migrate command, printing what it did. Errors are for the user.
+pub fn run(command: &MigrateCommand, options: &MigrateOptions) -> Result<(), String> {
+    let cfg = config::get();
+    let db = options
+        .db
+        .as_ref()
+        .map_or_else(|| cfg.history.path.clone(), |p| p.display().to_string());
+
+    match command {
+        MigrateCommand::Import { path } => import(&cfg, path, &db, options),
+        MigrateCommand::Export => export(&db, options),
+        MigrateCommand::Copy { from, to } => copy(from, to),
+    }
+}
+
+/// Fields of a CSV line. Quoted fields can have commas and `""` quotes, but not line
+/// breaks.
+fn split_csv(line: &str) -> Result<Vec<String>, String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut chars = line.chars().peekable();
+    let mut quoted = false;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' if quoted => quoted = false,
+            '"' if field.is_empty() => quoted = true,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    if quoted {
+        return Err("It has an unclosed quote".into());
+    }
+    fields.push(field);
+    Ok(fields)
+}
+
+/// A CSV field as JSON: empty is null, and numbers are numbers.
+fn csv_value(field: &str) -> Value {
+    let field = field.trim();
+    if field.is_empty() {
+        return Value::Null;
+    }
+    match field.parse::<f64>() {
+        Ok(n) if n.is_finite() => json!(n),
+        _ => json!(field),
+    }
+}
+
+/// A row of the file, as an object, or why it isn't one.
+type Record = Result<Map<String, Value>, String>;
+
+/// Each row of the file, by line number.
+fn records(text: &str, format: DataFormat) -> Vec<(usize, Record)> {
+    let mut lines = text
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim_end_matches('\r')))
+        .filter(|(_, line)| !line.trim().is_empty());
+
+    match format {
+        DataFormat::Ndjson => lines
+            .map(|(n, line)| {
+                let record = match serde_json::from_str(line) {
+                    Ok(Value::Object(record)) => Ok(record),
+                    Ok(_) => Err("It isn't a JSON object".into()),
+                    Err(e) => Err(format!("Invalid JSON: {}", e)),
+                };
+                (n, record)
+            })
+            .collect(),
+        DataFormat::Csv => {
+            let header = match lines.next() {
+                Some((n, line)) => match split_csv(line) {
+                    Ok(h) => h,
+                    Err(e) => return vec![(n, Err(format!("{}, in the header", e)))],
+                },
+                None => return Vec::new(),
+            };
+            lines
+                .map(|(n, line)| {
+                    let record = split_csv(line).and_then(|fields| {
+                        if fields.len() != header.len() {
+                            return Err(format!(
+                                "It has {} fields, and the header {}",
+                                fields.len(),
+                                header.len()
+                            ));
+                        }
+                        Ok(header
+                            .iter()
+                            .map(|h| h.trim().to_owned())
+                            .zip(fields.iter().map(|f| csv_value(f)))
+                            .collect())
+                    });
+                    (n, record)
+                })
+                .collect()
+        }
+    }
+}
+
+/// A time, as RFC 3339, a local time, with a `T` or space, or a date, or seconds or
+/// milliseconds since the epoch, as ms since the epoch.
+fn parse_time(value: &Value, zone: chrono_tz::Tz) -> Option<i64> {
+    match value {
+        Value::Number(n) => {
+            let n = n.as_f64()?;
+            Some(if n.abs() < SECONDS_BELOW {
+                (n * 1_000.) as i64
+            } else {
+                n as i64
+            })
+        }
+        Value::String(s) => tz::parse_time(s, zone)
+            .or_else(|| tz::parse_time(&s.replacen(' ', "T", 1), zone))
+            .map(|t| t.timestamp_millis()),
+        _ => None,
+    }
+}
+
+/// The sensor a column has: by `--map`, or else if it's named for a sensor's id or key.
+fn sensor_for(column: &str, map: &[(String, String)]) -> Option<&'static SensorDef> {
+    match map.iter().find(|(c, _)| c == column) {
+        Some((_, sensor)) => registry::get(sensor),
+        None => registry::REGISTRY
+            .iter()
+            .find(|s| s.id == column || s.key == column),
+    }
+}
+
+/// Quarantine an implausible row, unless it's already been.
+fn quarantine(
+    conn: &rusqlite::Connection,
+    time: i64,
+    reason: &str,
+    row: &Value,
+) -> Result<(), rusqlite::Error> {
+    let row = row.to_string();
+    conn.execute(
+        "INSERT INTO quarantine (quarantined, source, reason, time, row) SELECT ?1, ?2, ?3, ?4, ?5 \
+         WHERE NOT EXISTS (SELECT 1 FROM quarantine WHERE source = ?2 AND time = ?4 AND row = ?5)",
+        params![Utc::now().timestamp_millis(), SOURCE, reason, time, row],
+    )?;
+    Ok(())
+}
+
+fn import(cfg: &AppConfig, path: &Path, db: &str, options: &MigrateOptions) -> Result<(), String> {
+    if let Some((_, sensor)) = options.map.iter().find(|(_, s)| registry::get(s).is_none()) {
+        return Err(format!(
+            "`--map` has an unknown sensor, `{}`; it's one of {}",
+            sensor,
+            registry::ids().collect::<Vec<_>>().join(", ")
+        ));
+    }
+    let format = match options.format {
+        Some(f) => f,
+        None => match path.extension().and_then(|e| e.to_str()) {
+            Some("csv") => DataFormat::Csv,
+            Some("ndjson" | "jsonl") => DataFormat::Ndjson,
+            _ => return Err("Give the file's `--format`, `csv` or `ndjson`".into()),
+        },
+    };
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Problem reading `{}`: {}", path.display(), e))?;
+    let time_column = options.time_column.as_deref().unwrap_or("time");
+    let zone = tz::configured();
+
+    let mut summary = Summary::default();
+    let mut readings = Vec::new();
+    let mut implausible = Vec::new();
+
+    for (line, record) in records(&text, format) {
+        summary.rows += 1;
+        let record = match record {
+            Ok(r) => r,
+            Err(e) => {
+                summary.invalid += 1;
+                summary.problem(line, e);
+                continue;
+            }
+        };
+
+        let row = (|| {
+            let time = record
+                .get(time_column)
+                .ok_or_else(|| format!("It has no `{}`", time_column))?;
+            let time = parse_time(time, zone)
+                .ok_or_else(|| format!("`{}` isn't a time", time.to_string().trim_matches('"')))?;
+
+            let mut r = Readings::empty();
+            let mut values = Map::new();
+            let mut reason = None;
+            for (column, value) in record.iter().filter(|(c, _)| *c != time_column) {
+                let sensor = match sensor_for(column, &options.map) {
+                    Some(s) => s,
+                    None => {
+                        summary.ignored_columns.insert(column.clone());
+                        continue;
+                    }
+                };
+                let value = match value {
+                    Value::Null => continue,
+                    Value::Number(n) => n.as_f64().unwrap() as f32,
+                    Value::String(s) if s.eq_ignore_ascii_case("nan") || s.is_empty() => continue,
+                    v => return Err(format!("`{}` isn't a number, for {}", v, sensor.id)),
+                };
+                let [min, max] = cfg.history.plausible.get(sensor.id, cfg.ec.probe);
+                if !(min..=max).contains(&value) && reason.is_none() {
+                    reason = Some(format!(
+                        "{} is {}, outside its plausible range, {} to {}",
+                        sensor.id, value, min, max
+                    ));
+                }
+                r.set(sensor.id, Some(Ok(value)));
+                values.insert(sensor.id.into(), json!(value));
+            }
+            if values.is_empty() {
+                return Err("It has no readings".into());
+            }
+            Ok((time, r, values, reason))
+        })();
+
+        match row {
+            Ok((time, r, _, None)) => readings.push((time, r)),
+            Ok((time, _, mut values, Some(reason))) => {
+                summary.implausible += 1;
+                summary.problem(line, reason.clone());
+                values.insert("time".into(), json!(time));
+                implausible.push((time, reason, Value::Object(values)));
+            }
+            Err(e) => {
+                summary.invalid += 1;
+                summary.problem(line, e);
+            }
+        }
+    }
+
+    summary.imported = history::import_into(db, &readings).map_err(|e| e.to_string())?;
+    summary.duplicates = readings.len() - summary.imported;
+    if options.quarantine && !implausible.is_empty() {
+        let conn = history::open(db).map_err(|e| e.to_string())?;
+        for (time, reason, row) in implausible.iter() {
+            quarantine(&conn, *time, reason, row)
+                .map_err(|e| history::db_error(db, e).to_string())?;
+        }
+    }
+
+    println!("Read {} rows from `{}`:", summary.rows, path.display());
+    println!("    {} imported into `{}`", summary.imported, db);
+    println!(
+        "    {} skipped, as history already has them",
+        summary.duplicates
+    );
+    println!("    {} skipped, as they're invalid", summary.invalid);
+    println!(
+        "    {} with implausible values, {}",
+        summary.implausible,
+        if options.quarantine {
+            "put in the `quarantine` table"
+        } else {
+            "skipped; `--quarantine` keeps them"
+        }
+    );
+    if !summary.ignored_columns.is_empty() {
+        let columns: Vec<_> = summary.ignored_columns.into_iter().collect();
+        println!(
+            "Ignored columns without a sensor, which `--map` can give: {}",
+            columns.join(", ")
+        );
+    }
+    for problem in summary.problems.iter() {
+        println!("{}", problem);
+    }
+    let more = summary.invalid + summary.implausible - summary.problems.len();
+    if more > 0 {
+        println!("And {} more.", more);
+    }
+    Ok(())
+}
+
+fn export(db: &str, options: &MigrateOptions) -> Result<(), String> {
+    if !Path::new(db).exists() {
+        return Err(format!("There's no history database at `{}`", db));
+    }
+    let zone = tz::configured();
+    let time = |t: &Option<String>, name: &str, default: i64| match t {
+        Some(t) => tz::parse_time(t, zone)
+            .map(|t| t.timestamp_millis())
+            .ok_or_else(|| format!("`--{}` isn't a time: `{}`", name, t)),
+        None => Ok(default),
+    };
+    let from_ms = time(&options.from, "from", i64::MIN)?;
+    let to_ms = time(&options.to, "to", i64::MAX)?;
+
+    let format = options.format.unwrap_or(DataFormat::Csv);
+    let mut out = BufWriter::new(io::stdout().lock());
+    let count = write_rows(db, from_ms, to_ms, format, &mut out)?;
+    out.flush().map_err(|e| format!("Problem writing: {}", e))?;
+    eprintln!("Exported {} rows from `{}`.", count, db);
+    Ok(())
+}
+
+/// Write the rows of history between two times, in ms, to `out`, returning how many.
+pub fn write_rows(
+    db: &str,
+    from_ms: i64,
+    to_ms: i64,
+    format: DataFormat,
+    out: &mut impl Write,
+) -> Result<usize, String> {
+    let conn = history::open(db).map_err(|e| e.to_string())?;
+    let columns: Vec<String> = registry::ids()
+        .map(|name| format!("{0}_sum / {0}_n", name))
+        .collect();
+    let sql = format!(
+        "SELECT time, count, {} FROM ({}) ORDER BY time",
+        columns.join(", "),
+        history::all_tiers()
+    );
+    let db_error = |e| history::db_error(db, e).to_string();
+    let mut stmt = conn.prepare(&sql).map_err(db_error)?;
+    let mut rows = stmt.query(params![from_ms, to_ms]).map_err(db_error)?;
+
+    let write_error = |e: io::Error| format!("Problem writing: {}", e);
+    if format == DataFormat::Csv {
+        let header: Vec<_> = ["time", "count"]
+            .into_iter()
+            .chain(registry::ids())
+            .collect();
+        writeln!(out, "{}", header.join(",")).map_err(write_error)?;
+    }
+
+    let mut count = 0;
+    while let Some(row) = rows.next().map_err(db_error)? {
+        let time = history::format_time(row.get(0).map_err(db_error)?);
+        let samples: i64 = row.get(1).map_err(db_error)?;
+        let mut values = Vec::with_capacity(registry::COUNT);
+        for i in 0..registry::COUNT {
+            values.push(row.get::<_, Option<f64>>(i + 2).map_err(db_error)?);
+        }
+
+        match format {
+            DataFormat::Csv => {
+                let values: Vec<_> = values
+                    .iter()
+                    .map(|v| v.map(|v| v.to_string()).unwrap_or_default())
+                    .collect();
+                writeln!(out, "{},{},{}", time, samples, values.join(","))
+            }
+            DataFormat::Ndjson => {
+                let mut record = Map::new();
+                record.insert("time".into(), json!(time));
+                record.insert("count".into(), json!(samples));
+                for (id, v) in registry::ids().zip(values) {
+                    record.insert(id.into(), json!(v));
+                }
+                writeln!(out, "{}", Value::Object(record))
+            }
+        }
+        .map_err(write_error)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// A condition that a row `s`, of a tier with rows `src_ms` long, overlaps one in
+/// `main.<table>`, with rows `dst_ms` long. Raw samples are a moment, so only overlap
+/// raw samples at the same time.
+fn overlaps(table: &str, dst_ms: i64, src_ms: i64) -> String {
+    format!(
+        "EXISTS (SELECT 1 FROM main.{} m WHERE m.time < s.time + {} AND s.time < m.time + {})",
+        table,
+        src_ms.max(1),
+        dst_ms.max(1)
+    )
+}
+
+fn copy(from: &Path, to: &Path) -> Result<(), String> {
+    if !from.exists() {
+        return Err(format!(
+            "There's no history database at `{}`",
+            from.display()
+        ));
+    }
+    if fs::canonicalize(from).ok() == fs::canonicalize(to).ok() {
+        return Err("That's the same database".into());
+    }
+    let to_name = to.display().to_string();
+    let conn = history::open(&to_name).map_err(|e| e.to_string())?;
+    let db_error = |e| history::db_error(&to_name, e).to_string();
+    conn.execute(
+        "ATTACH DATABASE ?1 AS src",
+        params![from.display().to_string()],
+    )
+    .map_err(db_error)?;
+
+    let tiers: Vec<(&str, i64)> = [("samples", 0)]
+        .into_iter()
+        .chain(history::AGGREGATE_TIERS)
+        .collect();
+    let tx = conn.unchecked_transaction().map_err(db_error)?;
+    let mut copied = Vec::new();
+    let mut skipped = 0;
+    for (table, bucket_ms) in tiers.iter() {
+        let in_source: i64 = tx
+            .query_row(
+                "SELECT COUNT(*) FROM src.sqlite_master WHERE type = 'table' AND name = ?1",
+                params![table],
+                |row| row.get(0),
+            )
+            .map_err(db_error)?;
+        if in_source == 0 {
+            copied.push(0);
+            continue;
+        }
+
+        // Sensors' columns the source doesn't have, from before them, are left empty.
+        let columns: Vec<String> = {
+            let mut stmt = tx
+                .prepare("SELECT name FROM pragma_table_info(?1, 'src')")
+                .map_err(db_error)?;
+            let names = stmt
+                .query_map(params![table], |row| row.get(0))
+                .map_err(db_error)?
+                .collect::<Result<Vec<String>, _>>()
+                .map_err(db_error)?;
+            let mut columns = Vec::new();
+            for name in names {
+                if history::has_column(&tx, table, &name).map_err(db_error)? {
+                    columns.push(name);
+                }
+            }
+            columns
+        };
+
+        let conditions: Vec<String> = tiers
+            .iter()
+            .map(|(other, other_ms)| format!("NOT {}", overlaps(other, *other_ms, *bucket_ms)))
+            .collect();
+        let columns = columns.join(", ");
+        copied.push(
+            tx.execute(
+                &format!(
+                    "INSERT INTO main.{0} ({1}) SELECT {1} FROM src.{0} s WHERE {2}",
+                    table,
+                    columns,
+                    conditions.join(" AND ")
+                ),
+                [],
+            )
+            .map_err(db_error)?,
+        );
+        let total: i64 = tx
+            .query_row(&format!("SELECT COUNT(*) FROM src.{}", table), [], |row| {
+                row.get(0)
+            })
+            .map_err(db_error)?;
+        skipped += total as usize - copied.last().unwrap();
+    }
+    tx.commit().map_err(db_error)?;
+    conn.execute("DETACH DATABASE src", []).map_err(db_error)?;
+
+    println!(
+        "Copied {} samples, {} 1-minute rows and {} 1-hour rows from `{}` to `{}`; skipped {} \
+         that `{}` already has.",
+        copied[0],
+        copied[1],
+        copied[2],
+        from.display(),
+        to_name,
+        skipped,
+        to_name
+    );
+    Ok(())
+}
@@ -0,0 +1,360 @@
+//! A read-only Modbus TCP server, for PLCs and other automation that only speaks
+//! Modbus. Requests are answered from the cached readings, so they never touch the
+//! serial port.
+//!
+//! Each reading is an IEEE-754 float across two registers, followed by status
+//! registers: each sensor's `SensorError` code, the age of the data, and each sensor's
+//! status against its target range. The same map is served as both holding registers
+//! (function 3) and input registers (function 4), since PLCs differ in which they expect. The map is described at `/api/modbus/map`.
+
+use std::{
+    io::{self, Read, Write},
+    net::{TcpListener, TcpStream},
+    thread,
+    time::Duration,
+};
+
+use rocket::response::content;
+use serde::Serialize;
+
+use crate::{
+    activity,
+    config::{self, ModbusConfig, WordOrder},
+    events::{self, Severity},
+    poller,
+    status::{self, SensorStatus},
+    Readings, SensorError,
+};
+
+const READ_HOLDING_REGISTERS: u8 = 0x03;
+const READ_INPUT_REGISTERS: u8 = 0x04;
+
+const ILLEGAL_FUNCTION: u8 = 0x01;
+const ILLEGAL_DATA_ADDRESS: u8 = 0x02;
+const ILLEGAL_DATA_VALUE: u8 = 0x03;
+
+/// The most registers one request may read, per the Modbus spec.
+const MAX_READ_COUNT: u16 = 125;
+
+/// Drop clients that stay idle this long.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Data age reported when we've never had a successful reading.
+const AGE_UNKNOWN: u16 = u16::MAX;
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum RegisterType {
+    Float32,
+    Uint16,
+}
+
+#[derive(Serialize)]
+struct Register {
+    address: u16,
+    name: &'static str,
+    #[serde(rename = "type")]
+    type_: RegisterType,
+    description: &'static str,
+}
+
+const REGISTERS: &[Register] = &[
+    Register {
+        address: 0,
+        name: "temperature",
+        type_: RegisterType::Float32,
+        description: "Temperature, in °C. NaN if unavailable.",
+    },
+    Register {
+        address: 2,
+        name: "ph",
+        type_: RegisterType::Float32,
+        description: "pH. NaN if unavailable.",
+    },
+    Register {
+        address: 4,
+        name: "orp",
+        type_: RegisterType::Float32,
+        description: "ORP, in mV. NaN if unavailable.",
+    },
+    Register {
+        address: 6,
+        name: "ec",
+        type_: RegisterType::Float32,
+        description: "Conductivity, in S/cm. NaN if unavailable.",
+    },
+    Register {
+        address: 8,
+        name: "temperature_status",
+        type_: RegisterType::Uint16,
+        description: crate::SENSOR_ERROR_CODES,
+    },
+    Register {
+        address: 9,
+        name: "ph_status",
+        type_: RegisterType::Uint16,
+        description: crate::SENSOR_ERROR_CODES,
+    },
+    Register {
+        address: 10,
+        name: "orp_status",
+        type_: RegisterType::Uint16,
+        description: crate::SENSOR_ERROR_CODES,
+    },
+    Register {
+        address: 11,
+        name: "ec_status",
+        type_: RegisterType::Uint16,
+        description: crate::SENSOR_ERROR_CODES,
+    },
+    Register {
+        address: 12,
+        name: "data_age",
+        type_: RegisterType::Uint16,
+        description: "Seconds since the last successful reading. 65535 if never, or longer.",
+    },
+    Register {
+        address: 13,
+        name: "temperature_level",
+        type_: RegisterType::Uint16,
+        description:
+            "Temperature against its target. 0: ok, 1: warn, 2: critical, 3: error, 4: stale, 5: no flow, 6: stabilizing, 7: rejected.",
+    },
+    Register {
+        address: 14,
+        name: "ph_level",
+        type_: RegisterType::Uint16,
+        description: "pH against its target. 0: ok, 1: warn, 2: critical, 3: error, 4: stale, 5: no flow, 6: stabilizing, 7: rejected.",
+    },
+    Register {
+        address: 15,
+        name: "orp_level",
+        type_: RegisterType::Uint16,
+        description: "ORP against its target. 0: ok, 1: warn, 2: critical, 3: error, 4: stale, 5: no flow, 6: stabilizing, 7: rejected.",
+    },
+    Register {
+        address: 16,
+        name: "ec_level",
+        type_: RegisterType::Uint16,
+        description:
+            "Conductivity against its target. 0: ok, 1: warn, 2: critical, 3: error, 4: stale, 5: no flow, 6: stabilizing, 7: rejected.",
+    },
+];
+
+/// Number of registers in the map.
+const MAP_SIZE: u16 = 17;
+
+/// Fill in the register map.
+fn registers(
+    readings: &Readings,
+    age: Option<f32>,
+    word_order: WordOrder,
+) -> [u16; MAP_SIZE as usize] {
+    let mut result = [0; MAP_SIZE as usize];
+
+    let levels = status::classify(readings, &config::get().status);
+    // A fixed layout: sensors added since aren't in the map.
+    for (i, id) in ["T", "pH", "ORP", "ec"].iter().enumerate() {
+        let reading = readings.reading(id);
+        let bits = reading.unwrap_or(f32::NAN).to_bits();
+        let (high, low) = ((bits >> 16) as u16, bits as u16);
+        let (first, second) = match word_order {
+            WordOrder::HighFirst => (high, low),
+            WordOrder::LowFirst => (low, high),
+        };
+        result[i * 2] = first;
+        result[i * 2 + 1] = second;
+
+        result[8 + i] = reading.err().as_ref().map(SensorError::code).unwrap_or(0);
+        result[13 + i] = levels.get(id).unwrap_or(SensorStatus::Error).code();
+    }
+
+    result[12] = match age {
+        Some(a) if a < AGE_UNKNOWN as f32 => a as u16,
+        _ => AGE_UNKNOWN,
+    };
+
+    result
+}
+
+/// Bind the Modbus port and start serving, if enabled.
+pub fn start(cfg: &ModbusConfig) -> Result<(), io::Error> {
+    if !cfg.enabled {
+        return Ok(());
+    }
+
+    let listener = TcpListener::bind(("0.0.0.0", cfg.port)).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Problem listening for Modbus on port {}: {}", cfg.port, e),
+        )
+    })?;
+    let word_order = cfg.word_order;
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(s) => {
+                    thread::spawn(move || {
+                        if let Err(e) = serve(s, word_order) {
+                            // Disconnecting, or going idle, is expected.
+                            if !matches!(
+                                e.kind(),
+                                io::ErrorKind::UnexpectedEof
+                                    | io::ErrorKind::WouldBlock
+                                    | io::ErrorKind::TimedOut
+                            ) {
+                                events::record(
+                                    Severity::Warning,
+                                    "modbus",
+                                    format!("Problem serving a Modbus client: {}", e),
+                                );
+                            }
+                        }
+                    });
+                }
+                Err(e) => events::record(
+                    Severity::Warning,
+                    "modbus",
+                    format!("Problem accepting a Modbus connection: {}", e),
+                ),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Answer requests from one client until it disconnects.
+fn serve(mut stream: TcpStream, word_order: WordOrder) -> Result<(), io::Error> {
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+
+    loop {
+        // MBAP header: transaction ID, protocol ID, length, unit ID.
+        let mut header = [0; 7];
+        stream.read_exact(&mut header)?;
+
+        let protocol = u16::from_be_bytes([header[2], header[3]]);
+        let len = u16::from_be_bytes([header[4], header[5]]) as usize;
+        if protocol != 0 || !(2..=254).contains(&len) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Invalid Modbus TCP header",
+            ));
+        }
+
+        let mut pdu = vec![0; len - 1];
+        stream.read_exact(&mut pdu)?;
+
+        activity::touch();
+        let response = respond(&pdu, word_order);
+
+        let mut frame = Vec::with_capacity(7 + response.len());
+        frame.extend_from_slice(&header[0..4]);
+        frame.extend_from_slice(&(response.len() as u16 + 1).to_be_bytes());
+        frame.push(header[6]);
+        frame.extend_from_slice(&response);
+        stream.write_all(&frame)?;
+    }
+}
+
+/// The response PDU for a request PDU.
+fn respond(pdu: &[u8], word_order: WordOrder) -> Vec<u8> {
+    // Without a function code, there's nothing to answer but an exception.
+    let Some(&function) = pdu.first() else {
+        return vec![0x80, ILLEGAL_FUNCTION];
+    };
+    let exception = |code| vec![function | 0x80, code];
+
+    match function {
+        READ_HOLDING_REGISTERS | READ_INPUT_REGISTERS => {
+            if pdu.len() != 5 {
+                return exception(ILLEGAL_DATA_VALUE);
+            }
+            let start = u16::from_be_bytes([pdu[1], pdu[2]]);
+            let count = u16::from_be_bytes([pdu[3], pdu[4]]);
+
+            if count == 0 || count > MAX_READ_COUNT {
+                return exception(ILLEGAL_DATA_VALUE);
+            }
+            if start as u32 + count as u32 > MAP_SIZE as u32 {
+                return exception(ILLEGAL_DATA_ADDRESS);
+            }
+
+            let status = poller::status();
+            let registers = registers(
+                &crate::latest_readings(),
+                status.seconds_since_success,
+                word_order,
+            );
+
+            let mut result = vec![function, (count * 2) as u8];
+            for reg in &registers[start as usize..(start + count) as usize] {
+                result.extend_from_slice(&reg.to_be_bytes());
+            }
+            result
+        }
+        // The map is read-only, so writes, and anything else, are unsupported.
+        _ => exception(ILLEGAL_FUNCTION),
+    }
+}
+
+#[derive(Serialize)]
+struct RegisterMap {
+    enabled: bool,
+    port: u16,
+    word_order: WordOrder,
+    /// Function codes that read the map.
+    functions: &'static [u8],
+    /// Addresses are 0-based, as sent on the wire. Some PLCs number holding registers
+    /// from 40001 instead.
+    registers: &'static [Register],
+}
+
+#[get("/modbus/map")]
+pub fn view_map() -> content::Json<String> {
+    let cfg = config::get().modbus;
+    let map = RegisterMap {
+        enabled: cfg.enabled,
+        port: cfg.port,
+        word_order: cfg.word_order,
+        functions: &[READ_HOLDING_REGISTERS, READ_INPUT_REGISTERS],
+        registers: REGISTERS,
+    };
+    content::Json(serde_json::to_string(&map).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::{collection::vec, prelude::*, sample::select};
+
+    use super::*;
+
+    proptest! {
+        /// Every request gets a response: its registers, or an exception.
+        #[test]
+        fn any_pdu_is_answered(pdu in vec(any::<u8>(), 0..12)) {
+            let response = respond(&pdu, WordOrder::HighFirst);
+            prop_assert!(response.len() >= 2);
+            if response[0] & 0x80 == 0 {
+                prop_assert_eq!(response[1] as usize, response.len() - 2);
+            }
+        }
+
+        #[test]
+        fn reads_in_the_map_are_answered(
+            function in select(vec![READ_HOLDING_REGISTERS, READ_INPUT_REGISTERS]),
+            start in 0..MAP_SIZE,
+            count in 1..=MAP_SIZE,
+        ) {
+            let count = count.min(MAP_SIZE - start);
+            let mut pdu = vec![function];
+            pdu.extend(start.to_be_bytes());
+            pdu.extend(count.to_be_bytes());
+
+            let response = respond(&pdu, WordOrder::LowFirst);
+            prop_assert_eq!(response[0], function);
+            prop_assert_eq!(response.len(), 2 + count as usize * 2);
+        }
+    }
+}
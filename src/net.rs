@@ -0,0 +1,368 @@
+//! Network helpers: finding the addresses other devices can reach us at, forwarding
+//! connections to our TCP listener, and sending plain HTTP requests.
+
+use std::{
+    io::{self, Read, Write},
+    net::{
+        IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs,
+    },
+    thread,
+    time::Duration,
+};
+
+use serde::Serialize;
+
+use crate::{
+    config::{IpVersion, ServerConfig},
+    events::{self, Severity},
+    systemd,
+};
+
+/// Keep this much of an HTTP response.
+const MAX_RESPONSE_SIZE: u64 = 64 * 1_024;
+
+/// Interfaces that are usually virtual, and not reachable from the LAN: container
+/// bridges, VM networks, and VPN tunnels.
+const VIRTUAL_IFACE_PREFIXES: &[&str] = &[
+    "docker",
+    "br-",
+    "veth",
+    "virbr",
+    "vmnet",
+    "vboxnet",
+    "tun",
+    "tap",
+    "wg",
+    "zt",
+    "utun",
+    "tailscale",
+];
+
+/// An address on one of this machine's interfaces.
+#[derive(Clone, Debug)]
+pub struct InterfaceAddr {
+    pub name: String,
+    pub ip: IpAddr,
+}
+
+/// All addresses on local, non-loopback interfaces that are up.
+#[cfg(unix)]
+pub fn interface_addrs() -> Vec<InterfaceAddr> {
+    use std::ffi::CStr;
+
+    let mut result = Vec::new();
+    let mut addrs: *mut libc::ifaddrs = std::ptr::null_mut();
+
+    unsafe {
+        if libc::getifaddrs(&mut addrs) != 0 {
+            return result;
+        }
+
+        let mut cur = addrs;
+        while let Some(ifa) = cur.as_ref() {
+            cur = ifa.ifa_next;
+
+            let flags = ifa.ifa_flags as libc::c_int;
+            if ifa.ifa_addr.is_null()
+                || flags & libc::IFF_UP == 0
+                || flags & libc::IFF_LOOPBACK != 0
+            {
+                continue;
+            }
+
+            let ip = match (*ifa.ifa_addr).sa_family as libc::c_int {
+                libc::AF_INET => {
+                    let sa = &*(ifa.ifa_addr as *const libc::sockaddr_in);
+                    IpAddr::V4(Ipv4Addr::from(u32::from_be(sa.sin_addr.s_addr)))
+                }
+                libc::AF_INET6 => {
+                    let sa = &*(ifa.ifa_addr as *const libc::sockaddr_in6);
+                    IpAddr::V6(Ipv6Addr::from(sa.sin6_addr.s6_addr))
+                }
+                _ => continue,
+            };
+
+            result.push(InterfaceAddr {
+                name: CStr::from_ptr(ifa.ifa_name).to_string_lossy().into_owned(),
+                ip,
+            });
+        }
+
+        libc::freeifaddrs(addrs);
+    }
+
+    result
+}
+
+/// Without `getifaddrs`, fall back to a single best guess.
+#[cfg(not(unix))]
+pub fn interface_addrs() -> Vec<InterfaceAddr> {
+    local_ipaddress::get()
+        .and_then(|ip| ip.parse().ok())
+        .map(|ip| {
+            vec![InterfaceAddr {
+                name: String::new(),
+                ip,
+            }]
+        })
+        .unwrap_or_default()
+}
+
+/// If other devices on the LAN can likely reach us at this address.
+fn is_lan_reachable(addr: &InterfaceAddr) -> bool {
+    if VIRTUAL_IFACE_PREFIXES
+        .iter()
+        .any(|p| addr.name.starts_with(p))
+    {
+        return false;
+    }
+
+    match addr.ip {
+        IpAddr::V4(ip) => !ip.is_loopback() && !ip.is_link_local() && !ip.is_unspecified(),
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            // Link-local addresses need a zone ID, which browsers don't accept.
+            !ip.is_loopback() && !ip.is_unspecified() && first & 0xffc0 != 0xfe80
+        }
+    }
+}
+
+/// URLs other devices on the network can likely open the app at, best guesses first:
+/// private IPv4 ranges, then other IPv4, then IPv6.
+pub fn lan_urls(port: u16, include_v4: bool, include_v6: bool) -> Vec<String> {
+    let mut addrs: Vec<IpAddr> = interface_addrs()
+        .into_iter()
+        .filter(is_lan_reachable)
+        .map(|a| a.ip)
+        .filter(|ip| (ip.is_ipv4() && include_v4) || (ip.is_ipv6() && include_v6))
+        .collect();
+
+    addrs.sort_by_key(|ip| match ip {
+        IpAddr::V4(v4) if v4.is_private() => 0,
+        IpAddr::V4(_) => 1,
+        IpAddr::V6(_) => 2,
+    });
+    addrs.dedup();
+
+    addrs.iter().map(|ip| url(*ip, port)).collect()
+}
+
+/// URLs other devices can open the app at, given how we're listening.
+pub fn advertised_urls(cfg: &ServerConfig) -> Vec<String> {
+    if let Some(activation) = systemd::activation() {
+        let mut urls: Vec<_> = activation
+            .addresses
+            .iter()
+            .flat_map(|a| match a.ip() {
+                ip if !ip.is_unspecified() => vec![url(ip, a.port())],
+                IpAddr::V4(_) => lan_urls(a.port(), true, false),
+                IpAddr::V6(_) => lan_urls(a.port(), true, true),
+            })
+            .collect();
+        urls.dedup();
+        return urls;
+    }
+    if !cfg.tcp {
+        return Vec::new();
+    }
+
+    match cfg.address.as_ref().and_then(|a| a.parse::<IpAddr>().ok()) {
+        Some(ip) if !ip.is_unspecified() => vec![url(ip, cfg.port)],
+        Some(IpAddr::V4(_)) => lan_urls(cfg.port, true, false),
+        Some(IpAddr::V6(_)) => lan_urls(cfg.port, true, true),
+        None => lan_urls(
+            cfg.port,
+            cfg.ip_version != IpVersion::Ipv6,
+            cfg.ip_version != IpVersion::Ipv4,
+        ),
+    }
+}
+
+/// A URL for an address, with brackets for IPv6 literals, and the port unless it's 80.
+pub fn url(ip: IpAddr, port: u16) -> String {
+    let host = match ip {
+        IpAddr::V4(v4) => v4.to_string(),
+        IpAddr::V6(v6) => format!("[{}]", v6),
+    };
+
+    if port == 80 {
+        format!("http://{}", host)
+    } else {
+        format!("http://{}:{}", host, port)
+    }
+}
+
+/// A plain HTTP URL's `host:port`, with port 80 if it has none, and its path, `/` if it
+/// has none.
+pub fn split_url(url: &str) -> Result<(String, String), String> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| format!("`{}` must start with `http://`", url))?;
+    let (host, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    if host.is_empty() {
+        return Err(format!("`{}` has no host", url));
+    }
+    // Without a port, the last colon is in an IPv6 address, if any.
+    let has_port = host
+        .rsplit_once(':')
+        .is_some_and(|(h, p)| p.parse::<u16>().is_ok() && (!h.contains(':') || h.ends_with(']')));
+    let address = if has_port {
+        host.to_owned()
+    } else {
+        format!("{}:80", host)
+    };
+    Ok((address, path.to_owned()))
+}
+
+/// What an HTTP server replied.
+#[derive(Serialize)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub status_line: String,
+    /// As text, cut off at 64 KiB.
+    pub body: String,
+}
+
+/// Send a request to a plain HTTP URL, once, and read the reply.
+pub fn http_request(
+    method: &str,
+    url: &str,
+    headers: &[(&str, &str)],
+    body: &str,
+    timeout: Duration,
+) -> Result<HttpResponse, io::Error> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_owned());
+
+    let (address, path) =
+        split_url(url).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let socket_addr = address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No address for the host"))?;
+
+    let mut stream = TcpStream::connect_timeout(&socket_addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    let mut head = format!(
+        "{} {} HTTP/1.0\r\nHost: {}\r\nContent-Length: {}\r\n",
+        method,
+        path,
+        address,
+        body.len()
+    );
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(body.as_bytes())?;
+
+    let mut response = Vec::new();
+    stream.take(MAX_RESPONSE_SIZE).read_to_end(&mut response)?;
+    let response = String::from_utf8_lossy(&response);
+    let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+    let status_line = head.lines().next().unwrap_or_default();
+    let status = status_line
+        .split(' ')
+        .nth(1)
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| invalid("Not an HTTP response"))?;
+
+    Ok(HttpResponse {
+        status,
+        status_line: status_line.to_owned(),
+        body: body.to_owned(),
+    })
+}
+
+/// A stream we can forward: readable and writable from separate threads.
+pub trait Duplex: Read + Write + Send + Sized + 'static {
+    fn try_clone(&self) -> Result<Self, io::Error>;
+    fn shutdown_write(&self);
+}
+
+impl Duplex for TcpStream {
+    fn try_clone(&self) -> Result<Self, io::Error> {
+        TcpStream::try_clone(self)
+    }
+
+    fn shutdown_write(&self) {
+        let _ = self.shutdown(Shutdown::Write);
+    }
+}
+
+#[cfg(unix)]
+impl Duplex for std::os::unix::net::UnixStream {
+    fn try_clone(&self) -> Result<Self, io::Error> {
+        std::os::unix::net::UnixStream::try_clone(self)
+    }
+
+    fn shutdown_write(&self) {
+        let _ = self.shutdown(Shutdown::Write);
+    }
+}
+
+/// Copy bytes both ways between a client and our TCP listener, until both sides close.
+pub fn forward<C: Duplex>(client: C, backend: SocketAddr) -> Result<(), io::Error> {
+    let server = TcpStream::connect(backend)?;
+
+    let mut client_read = client.try_clone()?;
+    let mut server_write = server.try_clone()?;
+    let upstream = thread::spawn(move || {
+        let _ = io::copy(&mut client_read, &mut server_write);
+        server_write.shutdown_write();
+    });
+
+    let mut server_read = server;
+    let mut client_write = client;
+    let _ = io::copy(&mut server_read, &mut client_write);
+    client_write.shutdown_write();
+
+    let _ = upstream.join();
+    Ok(())
+}
+
+/// Forward a newly accepted connection on its own thread. `source` names the listener
+/// in event messages.
+pub fn forward_accepted<C: Duplex>(
+    stream: Result<C, io::Error>,
+    backend: SocketAddr,
+    source: &'static str,
+) {
+    match stream {
+        Ok(s) => {
+            thread::spawn(move || {
+                if let Err(e) = forward(s, backend) {
+                    events::record(
+                        Severity::Warning,
+                        source,
+                        format!("Problem forwarding a connection: {}", e),
+                    );
+                }
+            });
+        }
+        Err(e) => events::record(
+            Severity::Warning,
+            source,
+            format!("Problem accepting a connection: {}", e),
+        ),
+    }
+}
+
+/// For dual-stack binding, once we're listening on `[::]:port`: if the OS keeps IPv6
+/// sockets IPv6-only (eg Windows), `0.0.0.0:port` is still free, so listen there too
+/// and forward to our IPv6 listener. Otherwise IPv4 is already covered.
+pub fn add_ipv4_listener(port: u16) {
+    if let Ok(listener) = TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)) {
+        let backend = SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), port);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                forward_accepted(stream, backend, "ipv4 listener");
+            }
+        });
+    }
+}
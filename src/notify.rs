@@ -0,0 +1,864 @@
+//! Sending events to notification channels, so each kind of event reaches the right
+//! place: the device going offline to a pager, minor drift only to a log. `[notify]`
+//! routes map an event's category, its source, and severity to the channels it goes
+//! to; the first matching route wins, and `default` covers the rest. An alert rule's
+//! `notify` overrides the routes for its events. Delivery is on its own thread, so
+//! raising an event never waits on the network; failed deliveries are warned about
+//! once per run of failures, as `notify` events, which aren't routed themselves. In
+//! maintenance, only events about maintenance are sent.
+//!
+//! A channel with `digest_mins` collects its events for that long, from the first, and
+//! sends them as one digest, listing the alert rules that fired, are still active, and
+//! cleared. Critical events skip the digest: those of rules marked `critical`, and other
+//! alerts. Collected events are saved in `notify-digests.json`, in the working
+//! directory, so a restart mid-window doesn't lose them. Each event is in the event log
+//! as it happens, and each digest sent is too.
+//!
+//! Besides webhooks, channels can be ntfy, Gotify or Pushover, formatted for each by
+//! `notify_presets`.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    fs,
+    io::{self, Read},
+    sync::Mutex,
+    thread,
+    time::Duration,
+};
+
+use rocket::{http::Status, response::content, Data};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    alerts,
+    api::{self, ErrorResponse},
+    auth::Admin,
+    config::{self, AppConfig, NotifierKind, NotifyChannel, NotifyConfig, NotifyRoute},
+    device_meta::{self, DeviceMeta},
+    events::{self, Event, Severity},
+    history,
+    instance::{self, Instance},
+    maintenance,
+    memory::{Buffer, Policy},
+    net,
+    notify_presets::{self, Notice},
+    signing,
+};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Events waiting to be delivered; older ones are dropped.
+const MAX_QUEUED: usize = 100;
+
+/// Events collected for digests, by channel.
+const DIGESTS_PATH: &str = "notify-digests.json";
+
+/// Events per digest; older ones are dropped.
+const MAX_DIGESTED: usize = 500;
+
+pub const QUEUE_BUFFER: Buffer = Buffer {
+    name: "notify.queue",
+    cap: MAX_QUEUED,
+    policy: Policy::DropOldest,
+    about: "Events waiting to be routed to notification channels",
+};
+
+pub const DIGEST_BUFFER: Buffer = Buffer {
+    name: "notify.digest",
+    cap: MAX_DIGESTED,
+    policy: Policy::DropOldest,
+    about: "Events collected for a channel's digest, per channel",
+};
+
+/// The longest a digest can collect for: a day.
+const MAX_DIGEST_MINS: u32 = 1_440;
+
+/// Our own failures' source, which isn't routed, so failing channels can't loop.
+const SOURCE: &str = "notify";
+
+/// Event sources, for listing the effective routes. Routes can name others.
+const CATEGORIES: &[&str] = &[
+    "alerts",
+    "backup",
+    "buffer",
+    "channels",
+    "clock",
+    "coap",
+    "compaction",
+    "config",
+    "exporters",
+    "firmware",
+    "flow",
+    "history",
+    "inputs",
+    "interlock",
+    "maintenance",
+    "metrics",
+    "modbus",
+    "poller",
+    "reliability",
+    "resources",
+    "retention",
+    "sequence",
+    "service",
+    "setup",
+    "simulate",
+    "snmp",
+    "supervisor",
+    "verify",
+    "watchdog",
+];
+
+const SEVERITIES: [Severity; 3] = [Severity::Info, Severity::Warning, Severity::Alert];
+
+static QUEUE: Mutex<VecDeque<Event>> = Mutex::new(VecDeque::new());
+
+/// Channels whose last delivery failed.
+static FAILING: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+/// By channel.
+static DIGESTS: Mutex<BTreeMap<String, Digest>> = Mutex::new(BTreeMap::new());
+
+/// What a webhook is sent, as JSON.
+#[derive(Serialize)]
+struct Payload<'a> {
+    time: &'a str,
+    severity: Severity,
+    category: &'a str,
+    message: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rule: Option<&'a str>,
+    instance: Instance,
+    /// This device's label, location and color.
+    device: DeviceMeta,
+}
+
+/// An event, as collected for a digest.
+#[derive(Clone, Deserialize, Serialize)]
+struct Digested {
+    time: String,
+    severity: Severity,
+    category: String,
+    message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rule: Option<String>,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+struct Digest {
+    /// When its first event was collected, in ms since the epoch.
+    started_ms: i64,
+    events: Vec<Digested>,
+    /// Over `MAX_DIGESTED`.
+    #[serde(default)]
+    dropped: usize,
+}
+
+/// What a webhook is sent for a digest: an event, summing up those collected, with them.
+#[derive(Serialize)]
+struct DigestPayload<'a> {
+    time: &'a str,
+    /// The most severe of its events'.
+    severity: Severity,
+    category: &'static str,
+    message: &'a str,
+    /// When it started collecting, RFC 3339.
+    since: String,
+    /// Alert rules that fired while it collected.
+    new: Vec<&'a str>,
+    /// Alert rules routed to the channel that are active as it's sent.
+    active: Vec<String>,
+    /// Alert rules that cleared while it collected.
+    cleared: Vec<&'a str>,
+    events: &'a [Digested],
+    dropped: usize,
+    instance: Instance,
+    device: DeviceMeta,
+}
+
+#[derive(Serialize)]
+struct ChannelInfo {
+    name: String,
+    kind: NotifierKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    digest_mins: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct RuleRoute {
+    rule: String,
+    channels: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct EffectiveRoute {
+    category: String,
+    severity: Severity,
+    channels: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct Routes {
+    channels: Vec<ChannelInfo>,
+    default: Vec<String>,
+    routes: Vec<NotifyRoute>,
+    /// Alert rules with their own channels.
+    rules: Vec<RuleRoute>,
+    /// Where each known category's events go, at each severity.
+    effective: Vec<EffectiveRoute>,
+}
+
+#[derive(Deserialize)]
+struct TestEvent {
+    category: String,
+    severity: Severity,
+    #[serde(default = "default_test_message")]
+    message: String,
+    /// An alert rule, to test its `notify`.
+    #[serde(default)]
+    rule: Option<String>,
+}
+
+fn default_test_message() -> String {
+    "A test notification.".into()
+}
+
+/// A signed request, as a receiver got it.
+#[derive(Deserialize)]
+struct SignedRequest {
+    /// The raw body.
+    body: String,
+    /// `X-Signature-Timestamp`.
+    timestamp: i64,
+    /// `X-Signature`.
+    signature: String,
+}
+
+#[derive(Serialize)]
+struct Verification {
+    /// If the signature matches the body and timestamp.
+    valid: bool,
+    /// How old the timestamp is, in seconds; negative if it's ahead of our clock.
+    age_secs: i64,
+    /// If it's within `signing.max_age_secs`.
+    fresh: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hint: Option<&'static str>,
+}
+
+#[derive(Serialize)]
+struct Delivery {
+    channel: String,
+    /// `None` for a dry run.
+    delivered: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// An event's channels: its rule's `notify`, if set, or else the first matching
+/// route's, or else the default.
+fn channels_for(
+    cfg: &AppConfig,
+    category: &str,
+    severity: Severity,
+    rule: Option<&str>,
+) -> Vec<String> {
+    let rule_channels = rule
+        .and_then(|name| cfg.alerts.iter().find(|r| r.name == name))
+        .and_then(|r| r.notify.clone());
+    if let Some(channels) = rule_channels {
+        return channels;
+    }
+
+    let notify = &cfg.notify;
+    notify
+        .routes
+        .iter()
+        .find(|r| {
+            r.category.as_ref().is_none_or(|c| c == category)
+                && r.severity.is_none_or(|s| s == severity)
+        })
+        .map_or_else(|| notify.default.clone(), |r| r.channels.clone())
+}
+
+/// Check that channels are valid, and routes only name known ones.
+pub fn check(cfg: &AppConfig) -> Result<(), io::Error> {
+    let invalid = |msg: String| {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Config error: {}", msg),
+        ))
+    };
+    let notify = &cfg.notify;
+
+    for (i, channel) in notify.channels.iter().enumerate() {
+        if channel.name.is_empty() {
+            return invalid("A notification channel has no name".into());
+        }
+        if notify.channels[..i].iter().any(|c| c.name == channel.name) {
+            return invalid(format!(
+                "There's more than one notification channel named `{}`",
+                channel.name
+            ));
+        }
+        if let Err(e) = net::split_url(&channel.url) {
+            return invalid(format!("Notification channel `{}`: {}", channel.name, e));
+        }
+        if channel
+            .digest_mins
+            .is_some_and(|m| m == 0 || m > MAX_DIGEST_MINS)
+        {
+            return invalid(format!(
+                "Notification channel `{}`: `digest_mins` must be from 1 to {}",
+                channel.name, MAX_DIGEST_MINS
+            ));
+        }
+        if channel
+            .headers
+            .iter()
+            .any(|(k, v)| k.is_empty() || k.contains([':', '\r', '\n']) || v.contains(['\r', '\n']))
+        {
+            return invalid(format!(
+                "Notification channel `{}` has an invalid header",
+                channel.name
+            ));
+        }
+        let needs = match channel.kind {
+            NotifierKind::Webhook => &[][..],
+            NotifierKind::Ntfy => &["topic"],
+            NotifierKind::Gotify => &["token"],
+            NotifierKind::Pushover => &["token", "user"],
+        };
+        for field in needs {
+            let value = match *field {
+                "topic" => &channel.topic,
+                "token" => &channel.token,
+                _ => &channel.user,
+            };
+            if value.as_deref().is_none_or(str::is_empty) {
+                return invalid(format!(
+                    "Notification channel `{}` needs a `{}`",
+                    channel.name, field
+                ));
+            }
+        }
+        if channel.kind != NotifierKind::Webhook && (channel.sign || !channel.headers.is_empty()) {
+            return invalid(format!(
+                "Notification channel `{}`: `sign` and `headers` are for webhooks",
+                channel.name
+            ));
+        }
+        // Opened by the phone, so it can be HTTPS.
+        if channel
+            .click_url
+            .as_deref()
+            .is_some_and(|u| !u.starts_with("http://") && !u.starts_with("https://"))
+        {
+            return invalid(format!(
+                "Notification channel `{}`: `click_url` must be an HTTP or HTTPS URL",
+                channel.name
+            ));
+        }
+    }
+
+    let named = notify
+        .routes
+        .iter()
+        .map(|r| ("a route", &r.channels))
+        .chain(Some(("`notify.default`", &notify.default)))
+        .chain(
+            cfg.alerts
+                .iter()
+                .filter_map(|r| r.notify.as_ref().map(|n| ("an alert rule", n))),
+        );
+    for (place, channels) in named {
+        if let Some(unknown) = channels
+            .iter()
+            .find(|name| !notify.channels.iter().any(|c| &&c.name == name))
+        {
+            return invalid(format!(
+                "{} names an unknown notification channel, `{}`",
+                place, unknown
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Queue an event for delivery. Called for every event.
+pub fn queue(event: &Event) {
+    if event.source == SOURCE || (event.source != maintenance::SOURCE && maintenance::active()) {
+        return;
+    }
+    let mut queue = QUEUE.lock().unwrap();
+    if queue.len() >= MAX_QUEUED {
+        queue.pop_front();
+        QUEUE_BUFFER.dropped(1);
+    }
+    queue.push_back(event.clone());
+    QUEUE_BUFFER.note(queue.len());
+}
+
+/// Send `payload` to a webhook, or `notice` to a preset.
+fn deliver(
+    channel: &NotifyChannel,
+    payload: &impl Serialize,
+    notice: &Notice,
+) -> Result<(), String> {
+    match channel.kind {
+        NotifierKind::Webhook => {
+            let body = serde_json::to_string(payload).unwrap();
+            let signed = if channel.sign {
+                signing::headers(&body)
+            } else {
+                None
+            };
+            let mut headers = vec![("Content-Type", "application/json")];
+            headers.extend(
+                channel
+                    .headers
+                    .iter()
+                    .map(|(k, v)| (k.as_str(), v.as_str())),
+            );
+            headers.extend(signed.iter().flatten().map(|(k, v)| (*k, v.as_str())));
+            match net::http_request("POST", &channel.url, &headers, &body, TIMEOUT) {
+                Ok(r) if (200..300).contains(&r.status) => Ok(()),
+                Ok(r) => Err(format!("Got `{}`", r.status_line)),
+                Err(e) => Err(e.to_string()),
+            }
+        }
+        NotifierKind::Ntfy | NotifierKind::Gotify | NotifierKind::Pushover => {
+            notify_presets::send(channel, notice, TIMEOUT)
+        }
+    }
+}
+
+/// Warn when a channel starts failing, and note when it recovers.
+fn on_delivered(channel: &str, result: &Result<(), String>) {
+    let mut failing = FAILING.lock().unwrap();
+    match result {
+        Ok(()) => {
+            if failing.remove(channel) {
+                events::record(
+                    Severity::Info,
+                    SOURCE,
+                    format!("Notification channel `{}` is delivering again.", channel),
+                );
+            }
+        }
+        Err(e) => {
+            if failing.insert(channel.to_owned()) {
+                events::record(
+                    Severity::Warning,
+                    SOURCE,
+                    format!(
+                        "Problem delivering to notification channel `{}`: {}",
+                        channel, e
+                    ),
+                );
+            }
+        }
+    }
+}
+
+/// If an event skips digests: its alert rule is `critical`, or it's an alert that isn't
+/// a rule's.
+fn critical(cfg: &AppConfig, event: &Event) -> bool {
+    match &event.rule {
+        Some(name) => cfg
+            .alerts
+            .iter()
+            .any(|r| &r.name == name && r.critical == Some(true)),
+        None => event.severity == Severity::Alert,
+    }
+}
+
+fn save_digests(digests: &BTreeMap<String, Digest>) {
+    let tmp = format!("{}.tmp", DIGESTS_PATH);
+    let result = fs::write(&tmp, serde_json::to_string(digests).unwrap())
+        .and_then(|()| fs::rename(&tmp, DIGESTS_PATH));
+    if let Err(e) = result {
+        events::record(
+            Severity::Warning,
+            SOURCE,
+            format!(
+                "Problem saving digests to `{}`: {}. A restart would lose their events.",
+                DIGESTS_PATH, e
+            ),
+        );
+    }
+}
+
+/// Pick up digests from before a restart.
+fn load_digests() {
+    let digests = match fs::read_to_string(DIGESTS_PATH) {
+        Ok(s) => serde_json::from_str(&s).map_err(|e| e.to_string()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return,
+        Err(e) => Err(e.to_string()),
+    };
+    match digests {
+        Ok(digests) => *DIGESTS.lock().unwrap() = digests,
+        Err(e) => events::record(
+            Severity::Warning,
+            SOURCE,
+            format!(
+                "Problem reading digests from `{}`, so their events are lost: {}",
+                DIGESTS_PATH, e
+            ),
+        ),
+    }
+}
+
+/// Add an event to a channel's digest, starting one if there isn't one.
+fn collect(channel: &str, event: &Event) {
+    let mut digests = DIGESTS.lock().unwrap();
+    let digest = digests.entry(channel.to_owned()).or_insert_with(|| Digest {
+        started_ms: chrono::Utc::now().timestamp_millis(),
+        ..Default::default()
+    });
+    if digest.events.len() >= MAX_DIGESTED {
+        digest.events.remove(0);
+        digest.dropped += 1;
+        DIGEST_BUFFER.dropped(1);
+    }
+    digest.events.push(Digested {
+        time: event.time.clone(),
+        severity: event.severity,
+        category: event.source.into(),
+        message: event.message.clone(),
+        rule: event.rule.clone(),
+    });
+    DIGEST_BUFFER.note(longest(&digests));
+    save_digests(&digests);
+}
+
+fn longest(digests: &BTreeMap<String, Digest>) -> usize {
+    digests.values().map(|d| d.events.len()).max().unwrap_or(0)
+}
+
+/// Rules with events of `severity` in a digest, in order, once each.
+fn rules(digest: &Digest, severity: Severity) -> Vec<&str> {
+    let mut rules = Vec::new();
+    for event in digest.events.iter().filter(|e| e.severity == severity) {
+        if let Some(rule) = event.rule.as_deref() {
+            if !rules.contains(&rule) {
+                rules.push(rule);
+            }
+        }
+    }
+    rules
+}
+
+fn listed(rules: &[impl AsRef<str>]) -> String {
+    if rules.is_empty() {
+        return "none".into();
+    }
+    let quoted: Vec<_> = rules.iter().map(|r| format!("`{}`", r.as_ref())).collect();
+    quoted.join(", ")
+}
+
+/// Send a digest to its channel, and note it in the event log.
+fn send_digest(cfg: &AppConfig, channel: &NotifyChannel, digest: &Digest) {
+    let new = rules(digest, Severity::Alert);
+    let cleared = rules(digest, Severity::Info);
+    let active: Vec<_> = alerts::active()
+        .into_iter()
+        .filter(|rule| {
+            channels_for(cfg, "alerts", Severity::Alert, Some(rule)).contains(&channel.name)
+        })
+        .collect();
+    let count = digest.events.len() + digest.dropped;
+    let since = history::format_time(digest.started_ms);
+    let message = format!(
+        "{} events since {}. New alerts: {}. Still active: {}. Cleared: {}.",
+        count,
+        since,
+        listed(&new),
+        listed(&active),
+        listed(&cleared)
+    );
+
+    let time = chrono::Utc::now().to_rfc3339();
+    let payload = DigestPayload {
+        time: &time,
+        severity: digest
+            .events
+            .iter()
+            .map(|e| e.severity)
+            .max()
+            .unwrap_or(Severity::Info),
+        category: "digest",
+        message: &message,
+        since,
+        new,
+        active,
+        cleared,
+        events: &digest.events,
+        dropped: digest.dropped,
+        instance: instance::get(),
+        device: device_meta::get(device_meta::LOCAL),
+    };
+    let notice = Notice::new(payload.severity, "digest", None, &message);
+    let result = deliver(channel, &payload, &notice);
+    if result.is_ok() {
+        events::record(
+            Severity::Info,
+            SOURCE,
+            format!(
+                "Sent notification channel `{}` a digest of {} events.",
+                channel.name, count
+            ),
+        );
+    }
+    on_delivered(&channel.name, &result);
+}
+
+/// Send digests whose windows are over, or whose channels no longer collect them. Those
+/// of channels that are gone are dropped.
+fn send_due_digests() {
+    if DIGESTS.lock().unwrap().is_empty() {
+        return;
+    }
+    let cfg = config::get();
+    let now_ms = chrono::Utc::now().timestamp_millis();
+    let due: Vec<_> = {
+        let mut digests = DIGESTS.lock().unwrap();
+        let names: Vec<_> = digests
+            .iter()
+            .filter(|(name, digest)| {
+                let window = cfg
+                    .notify
+                    .channels
+                    .iter()
+                    .find(|c| &&c.name == name)
+                    .and_then(|c| c.digest_mins);
+                window.is_none_or(|mins| now_ms - digest.started_ms >= mins as i64 * 60_000)
+            })
+            .map(|(name, _)| name.clone())
+            .collect();
+        if names.is_empty() {
+            return;
+        }
+        let due = names
+            .iter()
+            .filter_map(|name| digests.remove_entry(name))
+            .collect();
+        DIGEST_BUFFER.note(longest(&digests));
+        save_digests(&digests);
+        due
+    };
+
+    for (name, digest) in due {
+        if let Some(channel) = cfg.notify.channels.iter().find(|c| c.name == name) {
+            send_digest(&cfg, channel, &digest);
+        }
+    }
+}
+
+/// Deliver queued events, forever; run this on its own thread.
+pub fn run() {
+    load_digests();
+    loop {
+        let events: Vec<_> = QUEUE.lock().unwrap().drain(..).collect();
+        QUEUE_BUFFER.note(0);
+        if !events.is_empty() {
+            let cfg = config::get();
+            for event in events.iter() {
+                let payload = Payload {
+                    time: &event.time,
+                    severity: event.severity,
+                    category: event.source,
+                    message: &event.message,
+                    rule: event.rule.as_deref(),
+                    instance: instance::get(),
+                    device: device_meta::get(device_meta::LOCAL),
+                };
+                let notice = Notice::new(
+                    event.severity,
+                    event.source,
+                    event.rule.as_deref(),
+                    &event.message,
+                );
+                let critical = critical(&cfg, event);
+                for name in channels_for(&cfg, event.source, event.severity, event.rule.as_deref())
+                {
+                    match cfg.notify.channels.iter().find(|c| c.name == name) {
+                        Some(channel) if channel.digest_mins.is_some() && !critical => {
+                            collect(&name, event);
+                        }
+                        Some(channel) => {
+                            let result = deliver(channel, &payload, &notice);
+                            on_delivered(&name, &result);
+                        }
+                        None => (),
+                    }
+                }
+            }
+        }
+        send_due_digests();
+        thread::sleep(CHECK_INTERVAL);
+    }
+}
+
+/// The channels, routes and alert rule overrides, and where each category's events go.
+#[get("/notify/routes")]
+pub fn view_routes() -> content::Json<String> {
+    let cfg = config::get();
+    let NotifyConfig {
+        default,
+        channels,
+        routes,
+    } = cfg.notify.clone();
+
+    let mut categories: BTreeSet<&str> = CATEGORIES.iter().copied().collect();
+    categories.extend(routes.iter().filter_map(|r| r.category.as_deref()));
+    let effective = categories
+        .iter()
+        .flat_map(|category| {
+            SEVERITIES.iter().map(|severity| EffectiveRoute {
+                category: (*category).into(),
+                severity: *severity,
+                channels: channels_for(&cfg, category, *severity, None),
+            })
+        })
+        .collect();
+
+    let result = Routes {
+        channels: channels
+            .into_iter()
+            .map(|c| ChannelInfo {
+                name: c.name,
+                kind: c.kind,
+                digest_mins: c.digest_mins,
+            })
+            .collect(),
+        rules: cfg
+            .alerts
+            .iter()
+            .filter_map(|r| {
+                r.notify.clone().map(|channels| RuleRoute {
+                    rule: r.name.clone(),
+                    channels,
+                })
+            })
+            .collect(),
+        default,
+        routes,
+        effective,
+    };
+    content::Json(serde_json::to_string(&result).unwrap())
+}
+
+/// Route a synthetic event, and deliver it to its channels unless `dry_run`, returning
+/// each channel, and if delivery worked; or, with `channel`, deliver it to that channel
+/// alone, eg to test-fire a preset. Failures here aren't warned about.
+#[post("/notify/test?<dry_run>&<channel>", data = "<data>")]
+pub fn test_delivery(
+    _admin: Admin,
+    dry_run: Option<bool>,
+    channel: Option<String>,
+    data: Data,
+) -> Result<content::Json<String>, ErrorResponse> {
+    let mut body = String::new();
+    data.open()
+        .take(16 * 1_024)
+        .read_to_string(&mut body)
+        .map_err(|e| api::error(Status::BadRequest, &e.to_string()))?;
+    let event: TestEvent = serde_json::from_str(&body)
+        .map_err(|e| api::error(Status::BadRequest, &format!("Invalid event: {}", e)))?;
+
+    let cfg = config::get();
+    let time = chrono::Utc::now().to_rfc3339();
+    let payload = Payload {
+        time: &time,
+        severity: event.severity,
+        category: &event.category,
+        message: &event.message,
+        rule: event.rule.as_deref(),
+        instance: instance::get(),
+        device: device_meta::get(device_meta::LOCAL),
+    };
+    let notice = Notice::new(
+        event.severity,
+        &event.category,
+        event.rule.as_deref(),
+        &event.message,
+    );
+    let names = match channel {
+        Some(name) if cfg.notify.channels.iter().any(|c| c.name == name) => vec![name],
+        Some(name) => {
+            return Err(api::error(
+                Status::NotFound,
+                &format!("There's no notification channel named `{}`", name),
+            ))
+        }
+        None => channels_for(&cfg, &event.category, event.severity, event.rule.as_deref()),
+    };
+    let deliveries: Vec<_> = names
+        .into_iter()
+        .map(|name| {
+            let result = match cfg.notify.channels.iter().find(|c| c.name == name) {
+                _ if dry_run == Some(true) => None,
+                Some(channel) => Some(deliver(channel, &payload, &notice)),
+                None => Some(Err("No such channel".into())),
+            };
+            Delivery {
+                channel: name,
+                delivered: result.as_ref().map(Result::is_ok),
+                error: result.and_then(Result::err),
+            }
+        })
+        .collect();
+    Ok(content::Json(serde_json::to_string(&deliveries).unwrap()))
+}
+
+/// Check a signature as a receiver would, for debugging one: if it matches the body and
+/// timestamp, and if the timestamp is recent enough not to be a replay.
+#[post("/notify/verify", data = "<data>")]
+pub fn verify_signature(_admin: Admin, data: Data) -> Result<content::Json<String>, ErrorResponse> {
+    let mut body = String::new();
+    data.open()
+        .take(64 * 1_024)
+        .read_to_string(&mut body)
+        .map_err(|e| api::error(Status::BadRequest, &e.to_string()))?;
+    let request: SignedRequest = serde_json::from_str(&body)
+        .map_err(|e| api::error(Status::BadRequest, &format!("Invalid request: {}", e)))?;
+
+    let signing = config::get().signing;
+    let secret = signing.secret.ok_or_else(|| {
+        api::error(
+            Status::Conflict,
+            "`signing.secret` isn't set, so nothing is signed",
+        )
+    })?;
+    let valid = signing::verify(
+        &secret,
+        request.timestamp,
+        &request.body,
+        &request.signature,
+    );
+    let age_secs = chrono::Utc::now().timestamp() - request.timestamp;
+    let fresh = age_secs.unsigned_abs() <= signing.max_age_secs as u64;
+    let hint = if !request.signature.trim().starts_with("sha256=") {
+        Some("The signature should start with `sha256=`, as in `X-Signature`.")
+    } else if !valid {
+        Some(
+            "Sign the raw body exactly as received, before parsing it, after the timestamp and \
+             a `.`, with the same secret.",
+        )
+    } else if !fresh {
+        Some("Check the clocks: a receiver would reject this as a possible replay.")
+    } else {
+        None
+    };
+
+    let result = Verification {
+        valid,
+        age_secs,
+        fresh,
+        hint,
+    };
+    Ok(content::Json(serde_json::to_string(&result).unwrap()))
+}
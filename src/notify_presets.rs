@@ -0,0 +1,476 @@
+//! Notification channels for ntfy, Gotify and Pushover, so a channel needs only a base
+//! URL and its topic or tokens: each service gets a title naming the instance and the
+//! alert rule or category, a priority from the event's severity, and a link back to the
+//! dashboard, in its own format. The link is `click_url`, or the first URL the app is
+//! advertised at. Channels only speak plain HTTP, so Pushover, whose API is HTTPS only,
+//! is reached through a relay, eg a reverse proxy to `https://api.pushover.net`.
+//!
+//! The tests send a notice to each preset at a mock server, on a loopback port, that
+//! replies as the service does, and compare what it got with the requests recorded
+//! there, so a change to what we send shows up before a service starts turning it away.
+
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+use crate::{
+    config::{self, NotifierKind, NotifyChannel},
+    device_meta,
+    events::Severity,
+    instance, net,
+};
+
+/// What a preset is sent, in whatever form its service takes.
+pub struct Notice<'a> {
+    pub severity: Severity,
+    pub title: String,
+    pub message: &'a str,
+}
+
+impl<'a> Notice<'a> {
+    /// Titled with the device's label, or else the instance's name, and the alert rule if
+    /// there is one, or else the category.
+    pub fn new(severity: Severity, category: &str, rule: Option<&str>, message: &'a str) -> Self {
+        let name = device_meta::label(device_meta::LOCAL).unwrap_or_else(|| instance::get().name);
+        Self {
+            severity,
+            title: format!("{}: {}", name, rule.unwrap_or(category)),
+            message,
+        }
+    }
+}
+
+/// A request to a preset's service.
+struct PresetRequest {
+    url: String,
+    headers: Vec<(&'static str, String)>,
+    body: Value,
+}
+
+/// ntfy's priorities run from 1, min, to 5, urgent; 3 is its default.
+fn ntfy_priority(severity: Severity) -> u8 {
+    match severity {
+        Severity::Info => 3,
+        Severity::Warning => 4,
+        Severity::Alert => 5,
+    }
+}
+
+/// Emoji shortcodes, shown before the title.
+fn ntfy_tag(severity: Severity) -> &'static str {
+    match severity {
+        Severity::Info => "information_source",
+        Severity::Warning => "warning",
+        Severity::Alert => "rotating_light",
+    }
+}
+
+/// Gotify's Android app pops up a notification from 4, and sounds one from 8.
+fn gotify_priority(severity: Severity) -> u8 {
+    match severity {
+        Severity::Info => 2,
+        Severity::Warning => 5,
+        Severity::Alert => 8,
+    }
+}
+
+/// Pushover's -1 is quiet, 0 normal, and 1 bypasses quiet hours. 2 needs a receipt to be
+/// acknowledged, so isn't used.
+fn pushover_priority(severity: Severity) -> i8 {
+    match severity {
+        Severity::Info => -1,
+        Severity::Warning => 0,
+        Severity::Alert => 1,
+    }
+}
+
+fn with_path(base: &str, path: &str) -> String {
+    format!("{}{}", base.trim_end_matches('/'), path)
+}
+
+/// What a preset channel is sent for `notice`, linking to `click`. `None` for a webhook.
+fn request(channel: &NotifyChannel, notice: &Notice, click: Option<&str>) -> Option<PresetRequest> {
+    let token = channel.token.as_deref().unwrap_or_default();
+    let mut headers = vec![("Content-Type", "application/json".to_owned())];
+    let (url, body) = match channel.kind {
+        NotifierKind::Webhook => return None,
+        // Published as JSON to the server's root, not the topic's URL, which takes the
+        // message as plain text.
+        NotifierKind::Ntfy => {
+            if !token.is_empty() {
+                headers.push(("Authorization", format!("Bearer {}", token)));
+            }
+            let mut body = json!({
+                "topic": channel.topic.as_deref().unwrap_or_default(),
+                "title": notice.title,
+                "message": notice.message,
+                "priority": ntfy_priority(notice.severity),
+                "tags": [ntfy_tag(notice.severity)],
+            });
+            if let Some(click) = click {
+                body["click"] = click.into();
+            }
+            (channel.url.clone(), body)
+        }
+        NotifierKind::Gotify => {
+            headers.push(("X-Gotify-Key", token.to_owned()));
+            let mut body = json!({
+                "title": notice.title,
+                "message": notice.message,
+                "priority": gotify_priority(notice.severity),
+            });
+            if let Some(click) = click {
+                body["extras"] = json!({ "client::notification": { "click": { "url": click } } });
+            }
+            (with_path(&channel.url, "/message"), body)
+        }
+        NotifierKind::Pushover => {
+            let mut body = json!({
+                "token": token,
+                "user": channel.user.as_deref().unwrap_or_default(),
+                "title": notice.title,
+                "message": notice.message,
+                "priority": pushover_priority(notice.severity),
+            });
+            if let Some(click) = click {
+                body["url"] = click.into();
+                body["url_title"] = "Open the dashboard".into();
+            }
+            (with_path(&channel.url, "/1/messages.json"), body)
+        }
+    };
+    Some(PresetRequest { url, headers, body })
+}
+
+/// Where a channel's notifications link to.
+fn click_url(channel: &NotifyChannel) -> Option<String> {
+    channel.click_url.clone().or_else(|| {
+        net::advertised_urls(&config::get().server)
+            .into_iter()
+            .next()
+    })
+}
+
+/// The service's reason for turning a notice away, from its reply: ntfy's `error`,
+/// Gotify's `errorDescription`, or Pushover's `errors`.
+fn reason(body: &str) -> Option<String> {
+    let reply: Value = serde_json::from_str(body).ok()?;
+    let reason = match &reply["errors"] {
+        Value::Array(errors) => errors
+            .iter()
+            .filter_map(Value::as_str)
+            .collect::<Vec<_>>()
+            .join("; "),
+        _ => reply["errorDescription"]
+            .as_str()
+            .or_else(|| reply["error"].as_str())?
+            .to_owned(),
+    };
+    Some(reason)
+}
+
+fn send_to(
+    channel: &NotifyChannel,
+    notice: &Notice,
+    click: Option<&str>,
+    timeout: Duration,
+) -> Result<(), String> {
+    let request = request(channel, notice, click)
+        .ok_or_else(|| format!("`{}` isn't a preset channel", channel.name))?;
+    let headers: Vec<_> = request
+        .headers
+        .iter()
+        .map(|(k, v)| (*k, v.as_str()))
+        .collect();
+    let body = request.body.to_string();
+    match net::http_request("POST", &request.url, &headers, &body, timeout) {
+        // Pushover also says so in the body.
+        Ok(r) if (200..300).contains(&r.status) && reason(&r.body).is_none() => Ok(()),
+        Ok(r) => Err(match reason(&r.body) {
+            Some(reason) => format!("Got `{}`: {}", r.status_line, reason),
+            None => format!("Got `{}`", r.status_line),
+        }),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Send `notice` to a preset channel.
+pub fn send(channel: &NotifyChannel, notice: &Notice, timeout: Duration) -> Result<(), String> {
+    send_to(channel, notice, click_url(channel).as_deref(), timeout)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{self, Read, Write},
+        net::{Ipv4Addr, TcpListener},
+        thread,
+        time::Instant,
+    };
+
+    use super::*;
+
+    /// For the mock server.
+    const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+    /// A recorded exchange with a preset's service: what it should be sent, and its reply.
+    struct Exchange {
+        channel: NotifyChannel,
+        severity: Severity,
+        path: &'static str,
+        /// Headers it should get, besides `Host` and `Content-Length`.
+        headers: &'static [(&'static str, &'static str)],
+        body: Value,
+        reply: &'static str,
+        /// How the reply should be taken.
+        delivered: Result<(), &'static str>,
+    }
+
+    fn exchanges() -> Vec<Exchange> {
+        let channel = |kind| NotifyChannel {
+            name: "preset".into(),
+            kind,
+            url: String::new(),
+            token: Some("tk_123".into()),
+            ..Default::default()
+        };
+        vec![
+            Exchange {
+                channel: NotifyChannel {
+                    topic: Some("pool".into()),
+                    ..channel(NotifierKind::Ntfy)
+                },
+                severity: Severity::Alert,
+                path: "/",
+                headers: &[
+                    ("Content-Type", "application/json"),
+                    ("Authorization", "Bearer tk_123"),
+                ],
+                body: json!({
+                    "topic": "pool",
+                    "title": "Pool: High ORP",
+                    "message": "ORP is 910 mV",
+                    "priority": 5,
+                    "tags": ["rotating_light"],
+                    "click": "http://pool.local:8000",
+                }),
+                reply: "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\r\n\
+                        {\"id\":\"hwQ2YpKdmg\",\"time\":1760400000,\"event\":\"message\",\"topic\":\"pool\"}",
+                delivered: Ok(()),
+            },
+            Exchange {
+                channel: channel(NotifierKind::Gotify),
+                severity: Severity::Warning,
+                path: "/message",
+                headers: &[
+                    ("Content-Type", "application/json"),
+                    ("X-Gotify-Key", "tk_123"),
+                ],
+                body: json!({
+                    "title": "Pool: High ORP",
+                    "message": "ORP is 910 mV",
+                    "priority": 5,
+                    "extras": {
+                        "client::notification": { "click": { "url": "http://pool.local:8000" } }
+                    },
+                }),
+                reply: "HTTP/1.1 401 Unauthorized\r\nContent-Type: application/json\r\n\r\n\
+                        {\"error\":\"Unauthorized\",\"errorCode\":401,\
+                        \"errorDescription\":\"you need to provide a valid access token\"}",
+                delivered: Err("Got `HTTP/1.1 401 Unauthorized`: you need to provide a valid access token"),
+            },
+            Exchange {
+                channel: NotifyChannel {
+                    user: Some("uk_456".into()),
+                    ..channel(NotifierKind::Pushover)
+                },
+                severity: Severity::Info,
+                path: "/1/messages.json",
+                headers: &[("Content-Type", "application/json")],
+                body: json!({
+                    "token": "tk_123",
+                    "user": "uk_456",
+                    "title": "Pool: High ORP",
+                    "message": "ORP is 910 mV",
+                    "priority": -1,
+                    "url": "http://pool.local:8000",
+                    "url_title": "Open the dashboard",
+                }),
+                reply: "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\r\n\
+                        {\"status\":1,\"request\":\"5042853c-402d-4a18-abcb-168734a801de\"}",
+                delivered: Ok(()),
+            },
+        ]
+    }
+
+    /// Accept one request, reply with `reply`, and return the request as sent.
+    fn mock(listener: TcpListener, reply: &'static str) -> Result<String, String> {
+        // Not waiting forever, if nothing's sent.
+        listener.set_nonblocking(true).map_err(|e| e.to_string())?;
+        let start = Instant::now();
+        let mut stream = loop {
+            match listener.accept() {
+                Ok((stream, _)) => break stream,
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock && start.elapsed() < CHECK_TIMEOUT =>
+                {
+                    thread::sleep(Duration::from_millis(10))
+                }
+                Err(e) => return Err(e.to_string()),
+            }
+        };
+        stream.set_nonblocking(false).map_err(|e| e.to_string())?;
+        stream
+            .set_read_timeout(Some(CHECK_TIMEOUT))
+            .map_err(|e| e.to_string())?;
+        let mut request = Vec::new();
+        let mut buf = [0; 4_096];
+        // Until the head, and as much body as it says.
+        loop {
+            let n = stream.read(&mut buf).map_err(|e| e.to_string())?;
+            if n == 0 {
+                break;
+            }
+            request.extend_from_slice(&buf[..n]);
+            let text = String::from_utf8_lossy(&request);
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let len = head
+                    .lines()
+                    .find_map(|l| l.strip_prefix("Content-Length: "))
+                    .and_then(|l| l.trim().parse().ok())
+                    .unwrap_or(0);
+                if body.len() >= len {
+                    break;
+                }
+            }
+        }
+        stream
+            .write_all(reply.as_bytes())
+            .map_err(|e| e.to_string())?;
+        Ok(String::from_utf8_lossy(&request).into_owned())
+    }
+
+    /// Compare a request as sent with what was recorded.
+    fn compare(exchange: &Exchange, sent: &str) -> Vec<String> {
+        let kind = exchange.channel.kind;
+        let (head, body) = sent.split_once("\r\n\r\n").unwrap_or((sent, ""));
+        let mut lines = head.lines();
+        let mut failures = Vec::new();
+
+        let request_line = lines.next().unwrap_or_default();
+        let expected_line = format!("POST {} HTTP/1.0", exchange.path);
+        if request_line != expected_line {
+            failures.push(format!(
+                "{:?} was sent `{}`, not `{}`",
+                kind, request_line, expected_line
+            ));
+        }
+        let headers: Vec<_> = lines
+            .filter_map(|l| l.split_once(": "))
+            .filter(|(k, _)| *k != "Host" && *k != "Content-Length")
+            .collect();
+        if headers != exchange.headers {
+            failures.push(format!(
+                "{:?} was sent headers {:?}, not {:?}",
+                kind, headers, exchange.headers
+            ));
+        }
+        match serde_json::from_str::<Value>(body) {
+            Ok(body) if body == exchange.body => (),
+            _ => failures.push(format!(
+                "{:?} was sent `{}`, not `{}`",
+                kind, body, exchange.body
+            )),
+        }
+        failures
+    }
+
+    /// Send a notice to each preset at a mock of its service, and compare what it got, and
+    /// how its reply was taken, with the recorded exchange.
+    #[test]
+    fn presets_send_as_recorded() {
+        for exchange in exchanges() {
+            let kind = exchange.channel.kind;
+            let failures = check_exchange(exchange)
+                .unwrap_or_else(|e| vec![format!("Problem checking the {:?} preset: {}", kind, e)]);
+            assert!(failures.is_empty(), "{:#?}", failures);
+        }
+    }
+
+    /// Each severity maps to a priority each service treats differently, and a notice
+    /// without a link to follow has none.
+    #[test]
+    fn severities_map_to_priorities() {
+        let channel = |kind| NotifyChannel {
+            name: "preset".into(),
+            kind,
+            url: "https://push.example.com/".into(),
+            ..Default::default()
+        };
+        let sent = |kind, severity| request(&channel(kind), &notice(severity), None).unwrap();
+        let severities = [Severity::Info, Severity::Warning, Severity::Alert];
+        let priorities = |kind| -> Vec<Value> {
+            severities
+                .iter()
+                .map(|s| sent(kind, *s).body["priority"].clone())
+                .collect()
+        };
+        assert_eq!(
+            priorities(NotifierKind::Ntfy),
+            [json!(3), json!(4), json!(5)]
+        );
+        assert_eq!(
+            priorities(NotifierKind::Gotify),
+            [json!(2), json!(5), json!(8)]
+        );
+        assert_eq!(
+            priorities(NotifierKind::Pushover),
+            [json!(-1), json!(0), json!(1)]
+        );
+
+        let gotify = sent(NotifierKind::Gotify, Severity::Info);
+        assert_eq!(gotify.url, "https://push.example.com/message");
+        assert!(gotify.body.get("extras").is_none());
+        assert!(sent(NotifierKind::Ntfy, Severity::Info)
+            .body
+            .get("click")
+            .is_none());
+        let webhook = channel(NotifierKind::Webhook);
+        assert!(request(&webhook, &notice(Severity::Alert), None).is_none());
+    }
+
+    fn notice(severity: Severity) -> Notice<'static> {
+        Notice {
+            severity,
+            title: "Pool: High ORP".into(),
+            message: "ORP is 910 mV",
+        }
+    }
+
+    fn check_exchange(mut exchange: Exchange) -> Result<Vec<String>, String> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).map_err(|e| e.to_string())?;
+        let port = listener.local_addr().map_err(|e| e.to_string())?.port();
+        exchange.channel.url = format!("http://127.0.0.1:{}", port);
+
+        let reply = exchange.reply;
+        let server = thread::spawn(move || mock(listener, reply));
+        let delivered = send_to(
+            &exchange.channel,
+            &notice(exchange.severity),
+            Some("http://pool.local:8000"),
+            CHECK_TIMEOUT,
+        );
+        let sent = server
+            .join()
+            .map_err(|_| "The mock server panicked".to_owned())??;
+
+        let mut failures = compare(&exchange, &sent);
+        if delivered.as_ref().map(|_| ()).map_err(String::as_str) != exchange.delivered {
+            failures.push(format!(
+                "{:?}'s reply was taken as {:?}, not {:?}",
+                exchange.channel.kind, delivered, exchange.delivered
+            ));
+        }
+        Ok(failures)
+    }
+}
@@ -0,0 +1,291 @@
+//! Outages: stretches of history without samples that nothing could fill, recorded so
+//! `/api/export.csv` has a row for each, rather than a silent hole. History keeps the
+//! time of the last sample it stored, so on startup, the stretch since then, while the
+//! app wasn't running, may be one; so may a run of failed polls longer than a gap, once
+//! the Water Monitor answers again, and the stretch history was paused for a full disk,
+//! once it resumes. A flight controller's samples, buffered while we
+//! weren't polling, are imported when it reconnects, which can fill them. Once that's
+//! done, or `SETTLE_MINS` after, the stretches still without samples are recorded, with
+//! their cause. Candidates are kept in the database until then, so a restart before
+//! they're settled doesn't lose them.
+//!
+//! History only holds this device's readings, so these are its outages.
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::{
+    config,
+    device_meta::LOCAL,
+    events::{self, Severity},
+    history,
+};
+
+/// How long after the device is read again to wait for its buffered samples, before
+/// recording what they haven't filled.
+const SETTLE_MINS: u64 = 15;
+
+static STATE: Mutex<State> = Mutex::new(State {
+    pending: false,
+    closed_at: None,
+});
+
+struct State {
+    /// If there are candidates in `pending_outages`.
+    pending: bool,
+    /// When the last open candidate was closed, by a successful poll.
+    closed_at: Option<Instant>,
+}
+
+/// Why there are no samples.
+#[derive(Clone, Copy)]
+pub enum Cause {
+    /// The app wasn't running, and the device had no buffered samples for the time.
+    AppDown,
+    /// The app was running, but couldn't read the Water Monitor.
+    DeviceUnreachable,
+    /// History was paused, with the disk under `resources.min_free_disk_mb`.
+    StorageFull,
+}
+
+impl Cause {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::AppDown => "app_down",
+            Self::DeviceUnreachable => "device_unreachable",
+            Self::StorageFull => "storage_full",
+        }
+    }
+}
+
+/// Note the stretch since the last stored sample as a candidate, and close any left open
+/// by the last run. Called once history's database is open, at startup.
+pub fn start(conn: &Connection) -> Result<(), rusqlite::Error> {
+    let now = Utc::now().timestamp_millis();
+    conn.execute(
+        "UPDATE pending_outages SET end = ?1 WHERE end IS NULL",
+        params![now],
+    )?;
+    // Databases from before `last_stored` have their latest sample instead.
+    let last: Option<i64> = match conn
+        .query_row(
+            "SELECT time FROM last_stored WHERE device = ?1",
+            params![LOCAL],
+            |row| row.get(0),
+        )
+        .optional()?
+    {
+        Some(t) => Some(t),
+        None => conn.query_row("SELECT MAX(time) FROM samples", [], |row| row.get(0))?,
+    };
+    if let Some(last) = last {
+        conn.execute(
+            "INSERT INTO pending_outages (start, end, cause) VALUES (?1, NULL, ?2)",
+            params![last, Cause::AppDown.as_str()],
+        )?;
+    }
+    let pending: i64 =
+        conn.query_row("SELECT COUNT(*) FROM pending_outages", [], |row| row.get(0))?;
+    STATE.lock().unwrap().pending = pending > 0;
+    Ok(())
+}
+
+/// Note a run of failed polls, from `since_ms` to now, as a candidate. Called by the
+/// poller, when the Water Monitor answers again after one longer than a gap.
+pub fn unreachable(since_ms: i64) {
+    candidate(since_ms, Cause::DeviceUnreachable);
+}
+
+/// Note the stretch history was paused for a full disk, from `since_ms` to now, as a
+/// candidate. Called by the disk space guard, when history resumes.
+pub fn storage_full(since_ms: i64) {
+    candidate(since_ms, Cause::StorageFull);
+}
+
+fn candidate(since_ms: i64, cause: Cause) {
+    let now = Utc::now().timestamp_millis();
+    let result = history::write(|conn| {
+        conn.execute(
+            "INSERT INTO pending_outages (start, end, cause) VALUES (?1, ?2, ?3)",
+            params![since_ms, now, cause.as_str()],
+        )
+    });
+    match result {
+        // `refresh` closes it, so buffered samples get a cycle to be checked for.
+        Ok(_) => {
+            let mut state = STATE.lock().unwrap();
+            state.pending = true;
+            state.closed_at = None;
+        }
+        Err(e) if config::get().history.enabled => problem(&e.to_string()),
+        // Without history, there's nothing to have a hole in.
+        Err(_) => (),
+    }
+}
+
+/// If the flight controller's buffered samples, if it has any, are all imported.
+fn buffer_settled() -> bool {
+    #[cfg(feature = "flight-controller")]
+    if config::get().flight_controller.enabled {
+        return crate::fc::buffer_settled();
+    }
+    true
+}
+
+/// Close open candidates, and on a later cycle, once buffered samples have been
+/// imported, record what they haven't filled as outages. Called by the poller after
+/// each successful cycle; without candidates, it returns straight away.
+pub fn refresh() {
+    let closed_at = {
+        let state = STATE.lock().unwrap();
+        if !state.pending {
+            return;
+        }
+        state.closed_at
+    };
+    let closed_at = match closed_at {
+        Some(t) => t,
+        None => {
+            let now = Utc::now().timestamp_millis();
+            let result = history::write(|conn| {
+                conn.execute(
+                    "UPDATE pending_outages SET end = ?1 WHERE end IS NULL",
+                    params![now],
+                )
+            });
+            match result {
+                Ok(_) => STATE.lock().unwrap().closed_at = Some(Instant::now()),
+                Err(e) => problem(&e.to_string()),
+            }
+            return;
+        }
+    };
+    if !buffer_settled() && closed_at.elapsed() < Duration::from_secs(SETTLE_MINS * 60) {
+        return;
+    }
+
+    let min_ms = history::gap_ms(&config::get());
+    match history::write(|conn| settle(conn, min_ms)) {
+        Ok(outages) => {
+            let mut state = STATE.lock().unwrap();
+            state.pending = false;
+            state.closed_at = None;
+            drop(state);
+            if !outages.is_empty() {
+                let total_ms: i64 = outages.iter().map(|(start, end)| end - start).sum();
+                events::record(
+                    Severity::Info,
+                    "history",
+                    format!(
+                        "Recorded {} outages in history, {:.1} minutes in all, that no \
+                         buffered samples filled.",
+                        outages.len(),
+                        total_ms as f64 / 60_000.
+                    ),
+                );
+            }
+        }
+        Err(e) => problem(&e.to_string()),
+    }
+}
+
+/// Record the stretches of closed candidates without samples, longer than `min_ms`, as
+/// outages, and drop the candidates. Overlapping candidates are merged, keeping the
+/// earliest's cause. Returns the outages.
+fn settle(conn: &Connection, min_ms: i64) -> Result<Vec<(i64, i64)>, rusqlite::Error> {
+    let tx = conn.unchecked_transaction()?;
+    let candidates = tx
+        .prepare(
+            "SELECT start, end, cause FROM pending_outages WHERE end IS NOT NULL ORDER BY start",
+        )?
+        .query_map([], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?, row.get(2)?))
+        })?
+        .collect::<Result<Vec<(i64, i64, String)>, _>>()?;
+
+    let mut merged: Vec<(i64, i64, String)> = Vec::new();
+    for (start, end, cause) in candidates {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end, cause)),
+        }
+    }
+
+    let mut outages = Vec::new();
+    for (start, end, cause) in merged {
+        if end <= start {
+            continue;
+        }
+        for (gap_start, gap_end) in history::gap_spans(&tx, LOCAL, start, end, min_ms)? {
+            tx.execute(
+                "INSERT INTO outages (start, end, cause) VALUES (?1, ?2, ?3)",
+                params![gap_start, gap_end, cause],
+            )?;
+            outages.push((gap_start, gap_end));
+        }
+    }
+    tx.execute("DELETE FROM pending_outages WHERE end IS NOT NULL", [])?;
+    tx.commit()?;
+    Ok(outages)
+}
+
+fn problem(e: &str) {
+    events::record(
+        Severity::Warning,
+        "history",
+        format!("Problem recording an outage in history: {}", e),
+    );
+}
+
+/// An outage, for exports: its start and end, in ms since the epoch, and cause.
+pub struct Outage {
+    pub start: i64,
+    pub end: i64,
+    pub cause: String,
+}
+
+/// Outages overlapping `from_ms` to `to_ms`, by start.
+pub fn between(
+    conn: &Connection,
+    from_ms: i64,
+    to_ms: i64,
+) -> Result<Vec<Outage>, rusqlite::Error> {
+    let mut stmt = conn.prepare(
+        "SELECT start, end, cause FROM outages WHERE start < ?2 AND end > ?1 ORDER BY start",
+    )?;
+    let outages = stmt
+        .query_map(params![from_ms, to_ms], |row| {
+            Ok(Outage {
+                start: row.get(0)?,
+                end: row.get(1)?,
+                cause: row.get(2)?,
+            })
+        })?
+        .collect::<Result<_, _>>()?;
+    Ok(outages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_paused_stretch_is_an_outage() {
+        let conn = history::open(":memory:").unwrap();
+        conn.execute(
+            "INSERT INTO pending_outages (start, end, cause) VALUES (?1, ?2, ?3)",
+            params![0, 600_000, Cause::StorageFull.as_str()],
+        )
+        .unwrap();
+
+        assert_eq!(settle(&conn, 60_000).unwrap(), [(0, 600_000)]);
+        let outages = between(&conn, 0, 600_000).unwrap();
+        assert_eq!(outages.len(), 1);
+        assert_eq!(outages[0].cause, "storage_full");
+    }
+}
@@ -0,0 +1,383 @@
+//! A minimal Parquet writer, for exporting history to pandas and the like: flat schemas
+//! of required `INT32`, millisecond-timestamp `INT64`, and nullable `FLOAT` columns,
+//! PLAIN-encoded and uncompressed, one page per column per row group. Rows are written a
+//! row group at a time, so memory is bounded by the row group, not the file. Metadata is
+//! Thrift's compact protocol, per `parquet.thrift`.
+
+use std::io::{self, Write};
+
+const MAGIC: &[u8] = b"PAR1";
+
+// Thrift compact protocol types.
+const TRUE: u8 = 1;
+const FALSE: u8 = 2;
+const I32: u8 = 5;
+const I64: u8 = 6;
+const BINARY: u8 = 8;
+const LIST: u8 = 9;
+const STRUCT: u8 = 12;
+
+// Parquet enums.
+const TYPE_INT32: i32 = 1;
+const TYPE_INT64: i32 = 2;
+const TYPE_FLOAT: i32 = 4;
+const REQUIRED: i32 = 0;
+const OPTIONAL: i32 = 1;
+const CONVERTED_TIMESTAMP_MILLIS: i32 = 9;
+const ENCODING_PLAIN: i32 = 0;
+const ENCODING_RLE: i32 = 3;
+const UNCOMPRESSED: i32 = 0;
+const DATA_PAGE: i32 = 0;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Kind {
+    /// Milliseconds since the Unix epoch, in UTC.
+    TimestampMillis,
+    Int32,
+    NullableFloat,
+}
+
+pub struct Field {
+    pub name: String,
+    pub kind: Kind,
+}
+
+/// A row group's values for one column, matching its field's kind.
+pub enum Values {
+    TimestampMillis(Vec<i64>),
+    Int32(Vec<i32>),
+    NullableFloat(Vec<Option<f32>>),
+}
+
+impl Values {
+    fn kind(&self) -> Kind {
+        match self {
+            Self::TimestampMillis(_) => Kind::TimestampMillis,
+            Self::Int32(_) => Kind::Int32,
+            Self::NullableFloat(_) => Kind::NullableFloat,
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Self::TimestampMillis(v) => v.len(),
+            Self::Int32(v) => v.len(),
+            Self::NullableFloat(v) => v.len(),
+        }
+    }
+
+    /// Definition levels, if nullable, then the non-null values.
+    fn page(&self) -> Vec<u8> {
+        let mut page = Vec::new();
+        match self {
+            Self::TimestampMillis(v) => {
+                v.iter().for_each(|v| page.extend(v.to_le_bytes()));
+            }
+            Self::Int32(v) => {
+                v.iter().for_each(|v| page.extend(v.to_le_bytes()));
+            }
+            Self::NullableFloat(v) => {
+                let levels = definition_levels(v.iter().map(Option::is_some));
+                page.extend((levels.len() as u32).to_le_bytes());
+                page.extend(levels);
+                v.iter()
+                    .flatten()
+                    .for_each(|v| page.extend(v.to_le_bytes()));
+            }
+        }
+        page
+    }
+}
+
+/// Levels of 0 or 1, as one bit-packed run of the RLE/bit-packing hybrid.
+fn definition_levels(defined: impl ExactSizeIterator<Item = bool>) -> Vec<u8> {
+    let groups = defined.len().div_ceil(8);
+    let mut out = Vec::new();
+    varint(&mut out, (groups as u64) << 1 | 1);
+    let mut bits = vec![0; groups];
+    for (i, d) in defined.enumerate() {
+        if d {
+            bits[i / 8] |= 1 << (i % 8);
+        }
+    }
+    out.extend(bits);
+    out
+}
+
+fn varint(out: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        out.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+fn zigzag(v: i64) -> u64 {
+    ((v << 1) ^ (v >> 63)) as u64
+}
+
+/// Writes a struct in Thrift's compact protocol. Fields must be written in id order.
+struct Compact {
+    out: Vec<u8>,
+    /// The last field id written, per nested struct.
+    last_ids: Vec<i16>,
+}
+
+impl Compact {
+    fn new() -> Self {
+        Self {
+            out: Vec::new(),
+            last_ids: vec![0],
+        }
+    }
+
+    fn field(&mut self, id: i16, kind: u8) {
+        let last = self.last_ids.last_mut().unwrap();
+        match id - *last {
+            delta @ 1..=15 => self.out.push((delta as u8) << 4 | kind),
+            _ => {
+                self.out.push(kind);
+                varint(&mut self.out, zigzag(id as i64));
+            }
+        }
+        *last = id;
+    }
+
+    fn i32(&mut self, id: i16, v: i32) {
+        self.field(id, I32);
+        varint(&mut self.out, zigzag(v as i64));
+    }
+
+    fn i64(&mut self, id: i16, v: i64) {
+        self.field(id, I64);
+        varint(&mut self.out, zigzag(v));
+    }
+
+    fn bool(&mut self, id: i16, v: bool) {
+        self.field(id, if v { TRUE } else { FALSE });
+    }
+
+    fn string(&mut self, id: i16, s: &str) {
+        self.field(id, BINARY);
+        varint(&mut self.out, s.len() as u64);
+        self.out.extend(s.as_bytes());
+    }
+
+    fn begin_struct(&mut self, id: i16) {
+        self.field(id, STRUCT);
+        self.last_ids.push(0);
+    }
+
+    /// Ends a struct field, a struct in a list, or the top-level struct.
+    fn end_struct(&mut self) {
+        self.out.push(0);
+        self.last_ids.pop();
+    }
+
+    fn list(&mut self, id: i16, kind: u8, len: usize) {
+        self.field(id, LIST);
+        if len < 15 {
+            self.out.push((len as u8) << 4 | kind);
+        } else {
+            self.out.push(0xf0 | kind);
+            varint(&mut self.out, len as u64);
+        }
+    }
+
+    /// A struct in a list; end it with `end_struct`.
+    fn begin_element(&mut self) {
+        self.last_ids.push(0);
+    }
+
+    fn i32_element(&mut self, v: i32) {
+        varint(&mut self.out, zigzag(v as i64));
+    }
+
+    fn string_element(&mut self, s: &str) {
+        varint(&mut self.out, s.len() as u64);
+        self.out.extend(s.as_bytes());
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.out.push(0);
+        self.out
+    }
+}
+
+struct ChunkMeta {
+    kind: Kind,
+    num_values: usize,
+    /// Of the page header.
+    offset: u64,
+    /// The page header and page.
+    size: u64,
+}
+
+struct RowGroupMeta {
+    columns: Vec<ChunkMeta>,
+    rows: usize,
+}
+
+pub struct Writer<W: Write> {
+    out: W,
+    offset: u64,
+    fields: Vec<Field>,
+    row_groups: Vec<RowGroupMeta>,
+    created_by: String,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg.to_owned())
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(mut out: W, fields: Vec<Field>, created_by: &str) -> Result<Self, io::Error> {
+        out.write_all(MAGIC)?;
+        Ok(Self {
+            out,
+            offset: MAGIC.len() as u64,
+            fields,
+            row_groups: Vec::new(),
+            created_by: created_by.into(),
+        })
+    }
+
+    /// Write a row group: a column of values per field, all the same length.
+    pub fn write_row_group(&mut self, columns: &[Values]) -> Result<(), io::Error> {
+        if columns.len() != self.fields.len()
+            || columns
+                .iter()
+                .zip(&self.fields)
+                .any(|(c, f)| c.kind() != f.kind)
+        {
+            return Err(invalid("Columns don't match the schema"));
+        }
+        let rows = columns.first().map_or(0, Values::len);
+        if columns.iter().any(|c| c.len() != rows) {
+            return Err(invalid("Columns have different lengths"));
+        }
+        if rows == 0 {
+            return Ok(());
+        }
+
+        let mut chunks = Vec::with_capacity(columns.len());
+        for column in columns {
+            let page = column.page();
+
+            let mut header = Compact::new();
+            header.i32(1, DATA_PAGE);
+            header.i32(2, page.len() as i32);
+            header.i32(3, page.len() as i32);
+            header.begin_struct(5);
+            header.i32(1, rows as i32);
+            header.i32(2, ENCODING_PLAIN);
+            header.i32(3, ENCODING_RLE);
+            header.i32(4, ENCODING_RLE);
+            header.end_struct();
+            let header = header.finish();
+
+            self.out.write_all(&header)?;
+            self.out.write_all(&page)?;
+            let size = (header.len() + page.len()) as u64;
+            chunks.push(ChunkMeta {
+                kind: column.kind(),
+                num_values: rows,
+                offset: self.offset,
+                size,
+            });
+            self.offset += size;
+        }
+
+        self.row_groups.push(RowGroupMeta {
+            columns: chunks,
+            rows,
+        });
+        Ok(())
+    }
+
+    fn schema(&self, meta: &mut Compact) {
+        meta.list(2, STRUCT, self.fields.len() + 1);
+        meta.begin_element();
+        meta.string(4, "schema");
+        meta.i32(5, self.fields.len() as i32);
+        meta.end_struct();
+
+        for field in self.fields.iter() {
+            meta.begin_element();
+            let (physical, repetition) = match field.kind {
+                Kind::TimestampMillis => (TYPE_INT64, REQUIRED),
+                Kind::Int32 => (TYPE_INT32, REQUIRED),
+                Kind::NullableFloat => (TYPE_FLOAT, OPTIONAL),
+            };
+            meta.i32(1, physical);
+            meta.i32(3, repetition);
+            meta.string(4, &field.name);
+            if field.kind == Kind::TimestampMillis {
+                meta.i32(6, CONVERTED_TIMESTAMP_MILLIS);
+                // `LogicalType.TIMESTAMP`, adjusted to UTC, in `MILLIS`.
+                meta.begin_struct(10);
+                meta.begin_struct(8);
+                meta.bool(1, true);
+                meta.begin_struct(2);
+                meta.begin_struct(1);
+                meta.end_struct();
+                meta.end_struct();
+                meta.end_struct();
+                meta.end_struct();
+            }
+            meta.end_struct();
+        }
+    }
+
+    /// Write the footer, and return the output.
+    pub fn finish(mut self) -> Result<W, io::Error> {
+        let mut meta = Compact::new();
+        meta.i32(1, 1);
+        self.schema(&mut meta);
+        let num_rows: usize = self.row_groups.iter().map(|g| g.rows).sum();
+        meta.i64(3, num_rows as i64);
+
+        meta.list(4, STRUCT, self.row_groups.len());
+        for group in self.row_groups.iter() {
+            meta.begin_element();
+            meta.list(1, STRUCT, group.columns.len());
+            for (chunk, field) in group.columns.iter().zip(&self.fields) {
+                meta.begin_element();
+                meta.i64(2, chunk.offset as i64);
+                meta.begin_struct(3);
+                meta.i32(
+                    1,
+                    match chunk.kind {
+                        Kind::TimestampMillis => TYPE_INT64,
+                        Kind::Int32 => TYPE_INT32,
+                        Kind::NullableFloat => TYPE_FLOAT,
+                    },
+                );
+                meta.list(2, I32, 2);
+                meta.i32_element(ENCODING_PLAIN);
+                meta.i32_element(ENCODING_RLE);
+                meta.list(3, BINARY, 1);
+                meta.string_element(&field.name);
+                meta.i32(4, UNCOMPRESSED);
+                meta.i64(5, chunk.num_values as i64);
+                meta.i64(6, chunk.size as i64);
+                meta.i64(7, chunk.size as i64);
+                meta.i64(9, chunk.offset as i64);
+                meta.end_struct();
+                meta.end_struct();
+            }
+            let size: u64 = group.columns.iter().map(|c| c.size).sum();
+            meta.i64(2, size as i64);
+            meta.i64(3, group.rows as i64);
+            meta.end_struct();
+        }
+        meta.string(6, &self.created_by);
+        let meta = meta.finish();
+
+        self.out.write_all(&meta)?;
+        self.out.write_all(&(meta.len() as u32).to_le_bytes())?;
+        self.out.write_all(MAGIC)?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
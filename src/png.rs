@@ -0,0 +1,98 @@
+//! A minimal PNG encoder, for the few images we serve. It writes uncompressed
+//! (stored) deflate blocks, which is plenty for small images and avoids a dependency.
+
+/// Encode an 8-bit grayscale image, given one byte per pixel, row by row.
+pub fn encode_gray(width: u32, height: u32, pixels: &[u8]) -> Vec<u8> {
+    // Color type 0: grayscale.
+    encode(width, height, 0, &[], pixels)
+}
+
+/// Encode an image of up to 256 colors, given one palette index per pixel, row by row.
+pub fn encode_indexed(width: u32, height: u32, palette: &[[u8; 3]], pixels: &[u8]) -> Vec<u8> {
+    assert!(!palette.is_empty() && palette.len() <= 256);
+    // Color type 3: indexed.
+    encode(width, height, 3, palette, pixels)
+}
+
+fn encode(width: u32, height: u32, color_type: u8, palette: &[[u8; 3]], pixels: &[u8]) -> Vec<u8> {
+    assert_eq!(pixels.len(), width as usize * height as usize);
+
+    // Each row starts with its filter type; 0 is none.
+    let mut raw = Vec::with_capacity(pixels.len() + height as usize);
+    for row in pixels.chunks(width as usize) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend_from_slice(&width.to_be_bytes());
+    ihdr.extend_from_slice(&height.to_be_bytes());
+    // Bit depth 8, the color type, default compression, filter, no interlace.
+    ihdr.extend_from_slice(&[8, color_type, 0, 0, 0]);
+
+    let mut result = vec![0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+    write_chunk(&mut result, b"IHDR", &ihdr);
+    if !palette.is_empty() {
+        write_chunk(&mut result, b"PLTE", &palette.concat());
+    }
+    write_chunk(&mut result, b"IDAT", &zlib_stored(&raw));
+    write_chunk(&mut result, b"IEND", &[]);
+    result
+}
+
+fn write_chunk(buf: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = buf.len();
+    buf.extend_from_slice(kind);
+    buf.extend_from_slice(data);
+    let crc = crc32(&buf[start..]);
+    buf.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// A zlib stream of stored deflate blocks.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    const MAX_BLOCK: usize = 65_535;
+
+    let mut result = Vec::with_capacity(data.len() + data.len() / MAX_BLOCK * 5 + 11);
+    // Deflate with a 32k window, no preset dictionary, fastest compression.
+    result.extend_from_slice(&[0x78, 0x01]);
+
+    let mut blocks = data.chunks(MAX_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        result.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let len = block.len() as u16;
+        result.push(last as u8);
+        result.extend_from_slice(&len.to_le_bytes());
+        result.extend_from_slice(&(!len).to_le_bytes());
+        result.extend_from_slice(block);
+    }
+
+    result.extend_from_slice(&adler32(data).to_be_bytes());
+    result
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffff_u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    const MOD: u32 = 65_521;
+
+    let (mut a, mut b) = (1_u32, 0_u32);
+    for &byte in data {
+        a = (a + byte as u32) % MOD;
+        b = (b + a) % MOD;
+    }
+    (b << 16) | a
+}
@@ -0,0 +1,495 @@
+//! Polls the Water Monitor in the background, and caches its readings. Requesting the
+//! readings directly from the frontend could result in conflicts, where multiple
+//! frontends are requesting readings from the WM directly in too short an interval.
+//!
+//! Includes a watchdog: occasionally the port gets stuck with every read timing out,
+//! which reopening it fixes.
+
+use std::{
+    io,
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use rocket::{http::Status, response::content};
+use serde::Serialize;
+use serde_json::json;
+
+use crate::{
+    activity, alerts,
+    api::{self, ApiVersion, ErrorResponse},
+    auth::Admin,
+    cache, changes, coap,
+    config::{self, PollingConfig, WatchdogConfig},
+    device_meta,
+    events::{self, Severity},
+    exporters, flow, freshness, history, indicator, interlock, outages, registry, reliability,
+    sensors, sequence, serial_trace, simulate, systemd,
+    tokens::Viewer,
+    validation_hook, warmup, Readings, SensorError, WaterMonitor, REFRESH_INTERVAL,
+};
+
+/// How long a refresh waits for the poller's next cycle.
+const REFRESH_TIMEOUT: Duration = Duration::from_secs(10);
+
+static STATE: Mutex<PollerState> = Mutex::new(PollerState::new());
+
+struct PollerState {
+    connected: bool,
+    consecutive_failures: u32,
+    last_success: Option<Instant>,
+    /// When the current run of failures started.
+    failing_since: Option<Instant>,
+    /// If we've raised the unreachable alert for the current run of failures.
+    alerted: bool,
+    watchdog_trips: u64,
+    failed_reopens: u64,
+    last_read: Option<ReadTiming>,
+    /// If the last cycle took longer than the refresh interval.
+    overrunning: bool,
+}
+
+/// One cycle's samples, and the readings we took from them.
+#[derive(Serialize)]
+struct Cycle {
+    samples: Vec<Readings>,
+    result: Readings,
+}
+
+static LAST_CYCLE: Mutex<Option<Cycle>> = Mutex::new(None);
+
+/// The latest readings request: when it finished, how long it took, and if it worked.
+#[derive(Clone, Copy)]
+pub struct ReadTiming {
+    pub finished: Instant,
+    pub duration: Duration,
+    pub ok: bool,
+}
+
+impl PollerState {
+    const fn new() -> Self {
+        Self {
+            connected: false,
+            consecutive_failures: 0,
+            last_success: None,
+            failing_since: None,
+            alerted: false,
+            watchdog_trips: 0,
+            failed_reopens: 0,
+            last_read: None,
+            overrunning: false,
+        }
+    }
+}
+
+/// Poller and watchdog status, for `/api/health`.
+#[derive(Serialize)]
+pub struct PollerStatus {
+    /// If the serial port to the Water Monitor is open.
+    pub connected: bool,
+    pub consecutive_failures: u32,
+    pub seconds_since_success: Option<f32>,
+    /// Times the watchdog has closed and reopened the port.
+    pub watchdog_trips: u64,
+    /// Watchdog reopen attempts that didn't find the device.
+    pub failed_reopens: u64,
+}
+
+pub fn status() -> PollerStatus {
+    let state = STATE.lock().unwrap();
+    PollerStatus {
+        connected: state.connected,
+        consecutive_failures: state.consecutive_failures,
+        seconds_since_success: state.last_success.map(|t| t.elapsed().as_secs_f32()),
+        watchdog_trips: state.watchdog_trips,
+        failed_reopens: state.failed_reopens,
+    }
+}
+
+/// Wait up to `timeout` for the next readings request to finish, and return its timing.
+/// `None` if there wasn't one, eg because the Water Monitor isn't connected.
+pub fn next_read(timeout: Duration) -> Option<ReadTiming> {
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        if let Some(read) = STATE.lock().unwrap().last_read {
+            if read.finished > start {
+                return Some(read);
+            }
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    None
+}
+
+/// Poll forever; run this on its own thread.
+pub fn run() {
+    let mut monitor = None;
+
+    loop {
+        let cycle_start = Instant::now();
+        poll(&mut monitor, &config::get().watchdog);
+
+        // Slower while no clients are watching.
+        let (interval, _) = activity::interval();
+        if let Some(remaining) = interval.checked_sub(cycle_start.elapsed()) {
+            activity::wait(remaining);
+        }
+    }
+}
+
+/// Run a cycle now, as `run` does, with a fresh connection. For the app's tests, which
+/// poll a simulated Water Monitor.
+#[cfg(test)]
+pub(crate) fn poll_once() {
+    poll(&mut None, &config::get().watchdog);
+}
+
+/// Run a cycle now, as `run` does, on `monitor`'s connection, which the watchdog may
+/// reopen. For the app's tests.
+#[cfg(test)]
+pub(crate) fn poll_on(monitor: &mut Option<WaterMonitor>) {
+    poll(monitor, &config::get().watchdog);
+}
+
+/// Open the Water Monitor's port. Connecting powers the probes, so they warm up again,
+/// and it may have been swapped for one with different firmware, so the indicator's
+/// tried again.
+fn connect(monitor: &mut Option<WaterMonitor>) {
+    *monitor = WaterMonitor::new().ok();
+    if monitor.is_some() {
+        warmup::restart();
+        indicator::reset();
+    }
+}
+
+fn poll(monitor: &mut Option<WaterMonitor>, watchdog: &WatchdogConfig) {
+    serial_trace::begin();
+    if simulate::take_switched() {
+        *monitor = None;
+    }
+    if monitor.is_none() {
+        connect(monitor);
+    }
+
+    let cfg = config::get();
+    let result = match monitor.as_mut() {
+        Some(wm) => read_cycle(wm, &cfg.polling),
+        None => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "Can't find the Water Monitor.",
+        )),
+    };
+    reliability::record(&result);
+    let error = result.as_ref().err().map(|e| e.to_string());
+
+    match result {
+        Ok(mut readings) => {
+            sensors::apply(&mut readings);
+            flow::apply(&mut readings);
+            if cfg.validation_hook.url.is_some() {
+                validation_hook::apply(&mut readings, &cfg.validation_hook);
+            }
+            warmup::update(&readings);
+            // History keeps what was measured; the rest see settling sensors' last
+            // readings.
+            history::record(&readings);
+            freshness::apply(&mut readings);
+            let seq = sequence::next();
+            exporters::record(&readings, seq);
+            alerts::evaluate(device_meta::LOCAL, &readings);
+            changes::record(seq, &readings, &cfg);
+            cache::publish(readings, Some(seq));
+            on_success();
+            outages::refresh();
+        }
+        Err(e) => {
+            cache::publish(Readings::default(), None);
+            if cfg.validation_hook.url.is_some() {
+                validation_hook::clear();
+            }
+
+            // Anything other than a timeout usually means the device is gone; drop the
+            // port so we rediscover it, possibly under a new name, next cycle.
+            if e.kind() != io::ErrorKind::TimedOut {
+                *monitor = None;
+            }
+            on_failure(monitor, watchdog);
+        }
+    }
+    serial_trace::finish(error);
+    coap::notify();
+    interlock::refresh();
+    indicator::refresh(monitor.as_mut());
+
+    #[cfg(feature = "flight-controller")]
+    crate::fc::refresh(monitor.as_mut());
+
+    systemd::on_poll(monitor.is_some());
+}
+
+/// The median of the valid values, or the last error if there are none.
+pub(crate) fn median(
+    values: impl Iterator<Item = Result<f32, SensorError>>,
+) -> Result<f32, SensorError> {
+    let mut valid = Vec::new();
+    let mut error = SensorError::BadMeasurement;
+    for v in values {
+        match v {
+            Ok(v) if !v.is_nan() => valid.push(v),
+            Ok(_) => (),
+            Err(e) => error = e,
+        }
+    }
+    if valid.is_empty() {
+        return Err(error);
+    }
+
+    valid.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let mid = valid.len() / 2;
+    Ok(if valid.len() % 2 == 0 {
+        (valid[mid - 1] + valid[mid]) / 2.
+    } else {
+        valid[mid]
+    })
+}
+
+/// Take `samples_per_cycle` readings back to back, and return each sensor's median,
+/// which filters out spikes, eg from pump noise on EC. Samples a sensor flags as errors
+/// don't count towards its median.
+fn read_cycle(wm: &mut WaterMonitor, cfg: &PollingConfig) -> Result<Readings, io::Error> {
+    let cycle_start = Instant::now();
+    let mut samples = Vec::new();
+
+    for _ in 0..cfg.samples_per_cycle.max(1) {
+        let start = Instant::now();
+        let result = wm.read_all();
+        STATE.lock().unwrap().last_read = Some(ReadTiming {
+            finished: Instant::now(),
+            duration: start.elapsed(),
+            ok: result.is_ok(),
+        });
+
+        match result {
+            Ok(r) => samples.push(r),
+            // Keep what we have; a failure usually means the rest would fail too.
+            Err(e) if samples.is_empty() => return Err(e),
+            Err(_) => break,
+        }
+    }
+
+    let readings = if samples.len() == 1 {
+        samples[0].clone()
+    } else {
+        let mut result = Readings::empty();
+        for id in registry::ids() {
+            if samples.iter().any(|r| r.get(id).is_some()) {
+                result.set(id, Some(median(samples.iter().filter_map(|r| r.get(id)))));
+            }
+        }
+        result
+    };
+
+    check_cycle_time(cycle_start.elapsed(), samples.len());
+    *LAST_CYCLE.lock().unwrap() = if cfg.keep_cycle_samples {
+        Some(Cycle {
+            samples,
+            result: readings.clone(),
+        })
+    } else {
+        None
+    };
+
+    Ok(readings)
+}
+
+/// Warn, once per run of them, about cycles that take longer than the refresh interval.
+fn check_cycle_time(elapsed: Duration, samples: usize) {
+    let interval = Duration::from_millis(REFRESH_INTERVAL as u64);
+    let was_overrunning = {
+        let mut state = STATE.lock().unwrap();
+        std::mem::replace(&mut state.overrunning, elapsed > interval)
+    };
+
+    if elapsed > interval && !was_overrunning {
+        events::record(
+            Severity::Warning,
+            "poller",
+            format!(
+                "Taking {} samples took {} ms, longer than the {} ms refresh interval. \
+                 Consider lowering `polling.samples_per_cycle`.",
+                samples,
+                elapsed.as_millis(),
+                REFRESH_INTERVAL
+            ),
+        );
+    }
+}
+
+/// The raw samples from the latest cycle, and their medians, if
+/// `polling.keep_cycle_samples` is set.
+#[get("/debug/samples")]
+pub fn view_cycle_samples(_viewer: Viewer) -> Result<content::Json<String>, ErrorResponse> {
+    match &*LAST_CYCLE.lock().unwrap() {
+        Some(cycle) => Ok(content::Json(serde_json::to_string(cycle).unwrap())),
+        None => Err(api::error(
+            Status::NotFound,
+            "No samples kept; set `polling.keep_cycle_samples`, and wait for a cycle",
+        )),
+    }
+}
+
+/// Wait up to `timeout` for readings published after `after`.
+fn published_since(after: DateTime<Utc>, timeout: Duration) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        if cache::latest().time > after {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    false
+}
+
+/// Poll now, and return the readings. With `debug`, also a trace of the cycle's serial
+/// transactions: their bytes, timings, and how each sensor's slot decoded.
+#[post("/readings/refresh?<debug>")]
+pub fn refresh_readings(
+    _admin: Admin,
+    debug: Option<bool>,
+) -> Result<content::Json<String>, ErrorResponse> {
+    let debug = debug.unwrap_or(false);
+    if debug && !serial_trace::arm() {
+        return Err(api::error(
+            Status::Conflict,
+            "A traced refresh is already running",
+        ));
+    }
+    let requested = Utc::now();
+    activity::wake();
+
+    let timed_out = || {
+        api::error(
+            Status::ServiceUnavailable,
+            "The poller didn't finish a cycle in time",
+        )
+    };
+    let trace = if debug {
+        Some(serial_trace::take(REFRESH_TIMEOUT).ok_or_else(timed_out)?)
+    } else if published_since(requested, REFRESH_TIMEOUT) {
+        None
+    } else {
+        return Err(timed_out());
+    };
+
+    let readings = crate::readings(ApiVersion::Unversioned).0;
+    Ok(content::Json(match trace {
+        Some(trace) => cache::join(
+            &readings,
+            &serde_json::to_string(&json!({ "trace": trace })).unwrap(),
+        ),
+        None => readings,
+    }))
+}
+
+fn on_success() {
+    let (recovered, unreachable_for) = {
+        let mut state = STATE.lock().unwrap();
+        // A run of failures long enough to leave a gap in history may be an outage.
+        let unreachable_for = match (state.failing_since, state.last_success) {
+            (Some(_), Some(last)) => Some(last.elapsed()),
+            _ => None,
+        };
+        let recovered = state.alerted;
+
+        state.connected = true;
+        state.consecutive_failures = 0;
+        state.last_success = Some(Instant::now());
+        state.failing_since = None;
+        state.alerted = false;
+        (recovered, unreachable_for)
+    };
+
+    if recovered {
+        events::record(
+            Severity::Info,
+            "poller",
+            "The Water Monitor is reachable again.",
+        );
+    }
+
+    if let Some(elapsed) = unreachable_for {
+        let elapsed_ms = elapsed.as_millis() as i64;
+        if elapsed_ms > history::gap_ms(&config::get()) {
+            outages::unreachable(Utc::now().timestamp_millis() - elapsed_ms);
+        }
+    }
+}
+
+/// Note a failed cycle, and have the watchdog reopen the port if it's time. The state
+/// isn't held while reopening, which is slow serial I/O, nor while recording events, so
+/// `status` callers, like `/api/health`, the interlock and Modbus, don't wait on them.
+fn on_failure(monitor: &mut Option<WaterMonitor>, watchdog: &WatchdogConfig) {
+    let now = Instant::now();
+
+    let (trip, consecutive_failures, failing_since) = {
+        let mut state = STATE.lock().unwrap();
+        state.consecutive_failures += 1;
+        let failing_since = *state.failing_since.get_or_insert(now);
+        let trip = watchdog.enabled
+            && watchdog.max_consecutive_failures > 0
+            && state.consecutive_failures % watchdog.max_consecutive_failures == 0;
+        if trip {
+            state.watchdog_trips += 1;
+        }
+        (trip, state.consecutive_failures, failing_since)
+    };
+
+    if trip {
+        // Close, rediscover, and reopen.
+        if let Some(mut wm) = monitor.take() {
+            wm.close();
+        }
+        connect(monitor);
+
+        if monitor.is_none() {
+            STATE.lock().unwrap().failed_reopens += 1;
+        }
+
+        // Only log the first trip in a run of failures, so a stuck or unplugged device
+        // doesn't flood the event log.
+        if consecutive_failures == watchdog.max_consecutive_failures {
+            let msg = if monitor.is_some() {
+                format!(
+                    "Reopened the serial port after {} consecutive failed reads.",
+                    consecutive_failures
+                )
+            } else {
+                "Tried to reopen the serial port, but can't find the Water Monitor.".to_owned()
+            };
+            events::record(Severity::Warning, "watchdog", msg);
+        }
+    }
+
+    let alert_after = Duration::from_secs(watchdog.unreachable_alert_mins as u64 * 60);
+    let alert = {
+        let mut state = STATE.lock().unwrap();
+        state.connected = monitor.is_some();
+        let alert = watchdog.enabled && !state.alerted && now - failing_since >= alert_after;
+        if alert {
+            state.alerted = true;
+        }
+        alert
+    };
+    if alert {
+        events::record(
+            Severity::Alert,
+            "watchdog",
+            format!(
+                "Water Monitor unreachable for {} minutes.",
+                watchdog.unreachable_alert_mins
+            ),
+        );
+    }
+}
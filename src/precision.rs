@@ -0,0 +1,103 @@
+//! Rounding readings to a fixed number of decimal places wherever they leave the app, so
+//! every consumer shows the same value: the readings API, exporters, metrics, CoAP,
+//! channels, alerts, and text, like the basic dashboard. Each sensor has a default, which
+//! `[precision] decimals` overrides. Ties round to even, on the reading as written, so
+//! 7.425 is 7.42 and 7.435 is 7.44, and long-term averages of rounded readings aren't
+//! biased upwards. History, and queries and exports of it, keep readings as measured.
+//! `round = false` turns rounding off.
+
+use std::io;
+
+use crate::{
+    config::{AppConfig, PrecisionConfig},
+    registry::{self, SensorDef},
+    Readings,
+};
+
+/// More than an f32 has.
+const MAX_DECIMALS: u8 = 6;
+
+/// Check `[precision]`.
+pub fn check(cfg: &AppConfig) -> Result<(), io::Error> {
+    let invalid = |msg: String| {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Config error: {}", msg),
+        ))
+    };
+    for (sensor, decimals) in cfg.precision.decimals.iter() {
+        if registry::get(sensor).is_none() {
+            return invalid(format!(
+                "`precision.decimals` has an unknown sensor, `{}`",
+                sensor
+            ));
+        }
+        if *decimals > MAX_DECIMALS {
+            return invalid(format!(
+                "`precision.decimals.{}` can be at most {}",
+                sensor, MAX_DECIMALS
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// The decimal places to show a sensor's readings to; `None` if they aren't rounded.
+pub fn decimals(sensor: &SensorDef, cfg: &PrecisionConfig) -> Option<usize> {
+    cfg.round.then(|| {
+        cfg.decimals
+            .get(sensor.id)
+            .map_or(sensor.decimals, |d| *d as usize)
+    })
+}
+
+/// `value` to `decimals` places, rounding ties to even. Rounds its shortest decimal
+/// form, which is how it's written everywhere, rather than the binary value: the f32
+/// nearest 7.425 is a little above it, so would otherwise round up.
+pub fn round(value: f32, decimals: usize) -> f32 {
+    if !value.is_finite() {
+        return value;
+    }
+    let written = value.abs().to_string();
+    let (whole, fraction) = written.split_once('.').unwrap_or((&written, ""));
+    if fraction.len() <= decimals {
+        return value;
+    }
+    let (kept, dropped) = fraction.split_at(decimals);
+
+    // With digits past `decimals`, there are few enough before them to fit.
+    let mut n: u64 = format!("{}{}", whole, kept).parse().unwrap_or(0);
+    let up = match dropped.as_bytes() {
+        // Shortest forms have no trailing zeros, so a lone 5 is a tie.
+        [b'5'] => n % 2 == 1,
+        [first, ..] => *first >= b'5',
+        [] => false,
+    };
+    n += up as u64;
+    if n == 0 {
+        // Not -0.
+        return 0.;
+    }
+
+    let rounded = (n as f64 / 10_f64.powi(decimals as i32)) as f32;
+    rounded.copysign(value)
+}
+
+/// A reading of `sensor`, as it's shown.
+pub fn value(sensor: &str, value: f32, cfg: &PrecisionConfig) -> f32 {
+    match registry::get(sensor).and_then(|s| decimals(s, cfg)) {
+        Some(decimals) => round(value, decimals),
+        None => value,
+    }
+}
+
+/// Readings, as they're shown.
+pub fn rounded(readings: &Readings, cfg: &PrecisionConfig) -> Readings {
+    let mut result = readings.clone();
+    for (sensor, reading) in readings.iter() {
+        if let Ok(v) = reading {
+            result.set(sensor.id, Some(Ok(value(sensor.id, v, cfg))));
+        }
+    }
+    result
+}
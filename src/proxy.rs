@@ -0,0 +1,239 @@
+//! Running behind a reverse proxy, like nginx or Traefik. Requests from a peer in
+//! `server.trusted_proxies` can say who the client is, with `X-Forwarded-For`, and
+//! where it connected, with `X-Forwarded-Proto` and `X-Forwarded-Host`. From any other
+//! peer, those headers are ignored, so a client can't claim to be someone else.
+
+use std::{io, net::IpAddr};
+
+use rocket::{
+    request::{self, FromRequest},
+    Outcome, Request,
+};
+
+use crate::config::{self, ServerConfig};
+
+/// An address, or a network in CIDR notation, eg `10.0.0.0/8`.
+struct Network {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+fn parse_network(s: &str) -> Result<Network, String> {
+    let invalid = || {
+        format!(
+            "`{}` in `server.trusted_proxies` isn't an address or network, like \
+             `127.0.0.1` or `10.0.0.0/8`",
+            s
+        )
+    };
+
+    let (addr, prefix) = match s.split_once('/') {
+        Some((a, p)) => (a, Some(p.parse::<u8>().map_err(|_| invalid())?)),
+        None => (s, None),
+    };
+    let addr: IpAddr = addr.trim().parse().map_err(|_| invalid())?;
+    let max = if addr.is_ipv4() { 32 } else { 128 };
+    let prefix = prefix.unwrap_or(max);
+    if prefix > max {
+        return Err(invalid());
+    }
+    Ok(Network { addr, prefix })
+}
+
+impl Network {
+    fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 clients of a dual-stack listener show up as eg `::ffff:127.0.0.1`.
+        match (self.addr.to_canonical(), ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Check `server.trusted_proxies` at startup.
+pub fn check(cfg: &ServerConfig) -> Result<(), io::Error> {
+    for proxy in cfg.trusted_proxies.iter() {
+        parse_network(proxy).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("Config error: {}", e))
+        })?;
+    }
+    Ok(())
+}
+
+fn trusted(networks: &[Network], ip: IpAddr) -> bool {
+    networks.iter().any(|n| n.contains(ip))
+}
+
+/// The header's values, in order, across repeats of it.
+fn values<'a>(req: &'a Request, header: &str) -> Vec<&'a str> {
+    req.headers()
+        .get(header)
+        .flat_map(|h| h.split(','))
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
+/// Who made a request, and where they connected, as a request guard; never fails.
+pub struct Client {
+    /// The client's address: from `X-Forwarded-For` via trusted proxies, or else the
+    /// peer's.
+    pub ip: Option<IpAddr>,
+    /// The URL the client connected to, eg `https://pond.example.com`, if a trusted
+    /// proxy said.
+    pub base_url: Option<String>,
+}
+
+impl Client {
+    pub fn from(req: &Request) -> Self {
+        let networks: Vec<_> = config::get()
+            .server
+            .trusted_proxies
+            .iter()
+            .filter_map(|p| parse_network(p).ok())
+            .collect();
+        Self::via(req, &networks)
+    }
+
+    /// Who made a request, trusting proxies in `networks`.
+    fn via(req: &Request, networks: &[Network]) -> Self {
+        let peer = req.remote().map(|a| a.ip());
+        let peer = match peer {
+            Some(p) if trusted(networks, p) => p,
+            _ => {
+                return Self {
+                    ip: peer,
+                    base_url: None,
+                }
+            }
+        };
+
+        // Each proxy appends who it heard from, so walk back from the nearest, past
+        // trusted ones. Anything further left is the client's to write, so can't be
+        // trusted.
+        let mut ip = peer;
+        for hop in values(req, "X-Forwarded-For").iter().rev() {
+            match hop.parse::<IpAddr>() {
+                Ok(hop) => {
+                    ip = hop;
+                    if !trusted(networks, hop) {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+
+        // The first proxy is the one the client connected to.
+        let proto = values(req, "X-Forwarded-Proto").first().copied();
+        let host = values(req, "X-Forwarded-Host").first().copied();
+        let valid_host = |h: &str| {
+            h.bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"-.:[]".contains(&b))
+        };
+        let base_url = match (proto, host) {
+            (Some(proto @ ("http" | "https")), Some(host)) if valid_host(host) => {
+                Some(format!("{}://{}", proto, host))
+            }
+            (None, Some(host)) if valid_host(host) => Some(format!("http://{}", host)),
+            _ => None,
+        };
+
+        Self {
+            ip: Some(ip),
+            base_url,
+        }
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Client {
+    type Error = ();
+
+    fn from_request(req: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        Outcome::Success(Self::from(req))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::{config::Environment, http::Header, local};
+
+    use super::*;
+
+    /// Who a request from `peer`, with `headers`, says it's from, through a proxy at
+    /// `10.0.0.2`.
+    fn client(
+        peer: &str,
+        headers: &[(&'static str, &'static str)],
+    ) -> (Option<String>, Option<String>) {
+        let rocket = rocket::custom(rocket::Config::new(Environment::Development));
+        let client = local::Client::untracked(rocket).unwrap();
+        let mut req = client
+            .get("/")
+            .remote(format!("{}:4000", peer).parse().unwrap());
+        for (name, value) in headers {
+            req.add_header(Header::new(*name, *value));
+        }
+        let networks = [parse_network("10.0.0.2").unwrap()];
+        let c = Client::via(req.inner(), &networks);
+        (c.ip.map(|ip| ip.to_string()), c.base_url)
+    }
+
+    #[test]
+    fn trusted_proxies_say_who_the_client_is() {
+        let forwarded = [
+            ("X-Forwarded-For", "203.0.113.5"),
+            ("X-Forwarded-Proto", "https"),
+            ("X-Forwarded-Host", "pond.example.com"),
+        ];
+        assert_eq!(
+            client("10.0.0.2", &forwarded),
+            (
+                Some("203.0.113.5".into()),
+                Some("https://pond.example.com".into())
+            )
+        );
+        assert_eq!(client("10.0.0.2", &[]), (Some("10.0.0.2".into()), None));
+    }
+
+    /// A client can't claim another address, or host, directly or through the proxy.
+    #[test]
+    fn forwarded_headers_cant_be_spoofed() {
+        let spoofed = [
+            ("X-Forwarded-For", "127.0.0.1"),
+            ("X-Forwarded-Host", "evil.example.com"),
+        ];
+        assert_eq!(
+            client("198.51.100.7", &spoofed),
+            (Some("198.51.100.7".into()), None)
+        );
+
+        // Through the proxy, which appends who it heard from, the client's own entry is
+        // further left, so ignored.
+        let appended = [("X-Forwarded-For", "127.0.0.1, 198.51.100.7")];
+        assert_eq!(
+            client("10.0.0.2", &appended).0.as_deref(),
+            Some("198.51.100.7")
+        );
+
+        let bad_host = [("X-Forwarded-Host", "pond.example.com/<script>")];
+        assert_eq!(client("10.0.0.2", &bad_host).1, None);
+    }
+
+    #[test]
+    fn networks_contain_their_addresses() {
+        let net = parse_network("10.0.0.0/8").unwrap();
+        assert!(net.contains("10.20.30.40".parse().unwrap()));
+        assert!(net.contains("::ffff:10.1.1.1".parse().unwrap()));
+        assert!(!net.contains("11.0.0.1".parse().unwrap()));
+        assert!(parse_network("10.0.0.0/33").is_err());
+        assert!(parse_network("pond").is_err());
+    }
+}
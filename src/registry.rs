@@ -0,0 +1,250 @@
+//! The sensors we know of, and a map from each to a value, which is how readings, and
+//! everything derived from them, are held. History columns, statuses and sensor
+//! metadata are all driven by `REGISTRY`, so adding a channel is an entry here, plus its
+//! slot in the readings frame, or wherever else it's measured.
+//!
+//! Maps serialize as JSON objects in registry order, leaving out sensors without a
+//! value. Every Water Monitor has the required sensors, so their keys are always there,
+//! as in the original four-field shape. They're keyed by id, eg `T` and `pH`, or with
+//! `named`, per `KeyNaming`, eg `temperature` and `ph`. In config, they're tables keyed
+//! by id.
+
+use std::collections::BTreeMap;
+
+use serde::{
+    de::{self, Deserializer},
+    ser::SerializeMap,
+    Deserialize, Serialize, Serializer,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    Temperature,
+    Ph,
+    Orp,
+    Conductivity,
+    DissolvedOxygen,
+    Flow,
+    #[cfg(test)]
+    Turbidity,
+}
+
+/// How maps are keyed in JSON.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyNaming {
+    /// Sensor ids, eg `T` and `pH`, as in the unversioned API.
+    #[default]
+    Legacy,
+    /// Eg `temperature` and `ph`, as in `/api/v1`.
+    SnakeCase,
+}
+
+pub struct SensorDef {
+    /// As used in JSON, history columns and config keys.
+    pub id: &'static str,
+    /// Its key in JSON in snake case, as in `/api/v1`.
+    pub key: &'static str,
+    pub kind: Kind,
+    /// Its short name, for people.
+    pub label: &'static str,
+    pub unit: &'static str,
+    /// The decimal places its readings are shown to, unless `[precision]` says.
+    pub decimals: usize,
+    /// Where its 5 bytes start in the Water Monitor's readings frame; `None` for those
+    /// the app measures itself.
+    pub offset: Option<usize>,
+    /// If every Water Monitor has it. Others are left out of readings from hardware
+    /// without them.
+    pub required: bool,
+    /// What's plausible, where the config doesn't say.
+    pub plausible: [f32; 2],
+}
+
+pub static REGISTRY: [SensorDef; COUNT] = [
+    SensorDef {
+        id: "T",
+        key: "temperature",
+        kind: Kind::Temperature,
+        label: "Temp",
+        unit: "°C",
+        decimals: 1,
+        offset: Some(0),
+        required: true,
+        plausible: [-10., 80.],
+    },
+    SensorDef {
+        id: "pH",
+        key: "ph",
+        kind: Kind::Ph,
+        label: "pH",
+        unit: "pH",
+        decimals: 2,
+        offset: Some(5),
+        required: true,
+        plausible: [0., 14.],
+    },
+    SensorDef {
+        id: "ORP",
+        key: "orp",
+        kind: Kind::Orp,
+        label: "ORP",
+        unit: "mV",
+        decimals: 0,
+        offset: Some(10),
+        required: true,
+        plausible: [-2_000., 2_000.],
+    },
+    SensorDef {
+        id: "ec",
+        key: "ec",
+        kind: Kind::Conductivity,
+        label: "EC",
+        unit: "µS/cm",
+        decimals: 0,
+        offset: Some(15),
+        required: true,
+        plausible: [0., 200_000.],
+    },
+    SensorDef {
+        id: "DO",
+        key: "do",
+        kind: Kind::DissolvedOxygen,
+        label: "DO",
+        unit: "mg/L",
+        decimals: 2,
+        offset: Some(20),
+        required: false,
+        plausible: [0., 20.],
+    },
+    // Counted from a flow sensor's pulses, by `flow`.
+    SensorDef {
+        id: "flow",
+        key: "flow",
+        kind: Kind::Flow,
+        label: "Flow",
+        unit: "L/min",
+        decimals: 1,
+        offset: None,
+        required: false,
+        plausible: [0., 1_000.],
+    },
+    // Only in tests, which check a new sensor needs nothing but its entry.
+    #[cfg(test)]
+    SensorDef {
+        id: "NTU",
+        key: "turbidity",
+        kind: Kind::Turbidity,
+        label: "Turbidity",
+        unit: "NTU",
+        decimals: 1,
+        offset: Some(25),
+        required: false,
+        plausible: [0., 4_000.],
+    },
+];
+
+pub const COUNT: usize = if cfg!(test) { 7 } else { 6 };
+
+/// Sensor ids, in registry order.
+pub fn ids() -> impl Iterator<Item = &'static str> {
+    REGISTRY.iter().map(|s| s.id)
+}
+
+pub fn index(id: &str) -> Option<usize> {
+    REGISTRY.iter().position(|s| s.id == id)
+}
+
+pub fn get(id: &str) -> Option<&'static SensorDef> {
+    index(id).map(|i| &REGISTRY[i])
+}
+
+/// A value per sensor, for those that have one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SensorMap<T: Copy>([Option<T>; COUNT]);
+
+impl<T: Copy> SensorMap<T> {
+    pub const fn empty() -> Self {
+        Self([None; COUNT])
+    }
+
+    pub fn get(&self, id: &str) -> Option<T> {
+        index(id).and_then(|i| self.0[i])
+    }
+
+    /// Panics for an id that isn't in the registry.
+    pub fn set(&mut self, id: &str, value: Option<T>) {
+        let i = index(id).unwrap_or_else(|| panic!("No sensor `{}`", id));
+        self.0[i] = value;
+    }
+
+    /// Sensors with values, in registry order.
+    pub fn iter(&self) -> impl Iterator<Item = (&'static SensorDef, T)> + '_ {
+        REGISTRY
+            .iter()
+            .zip(self.0.iter())
+            .filter_map(|(s, v)| v.map(|v| (s, v)))
+    }
+
+    /// The value for each sensor, in registry order, including those without one.
+    pub fn values(&self) -> [Option<T>; COUNT] {
+        self.0
+    }
+
+    pub fn map<U: Copy>(&self, mut f: impl FnMut(&'static SensorDef, T) -> U) -> SensorMap<U> {
+        let mut result = SensorMap::empty();
+        for (i, (s, v)) in REGISTRY.iter().zip(self.0.iter()).enumerate() {
+            result.0[i] = v.map(|v| f(s, v));
+        }
+        result
+    }
+}
+
+impl SensorDef {
+    /// Its key in JSON, per `naming`.
+    pub fn json_key(&self, naming: KeyNaming) -> &'static str {
+        match naming {
+            KeyNaming::Legacy => self.id,
+            KeyNaming::SnakeCase => self.key,
+        }
+    }
+}
+
+/// A map, serialized with keys per its naming.
+pub struct Named<'a, T: Copy>(&'a SensorMap<T>, KeyNaming);
+
+impl<T: Copy> SensorMap<T> {
+    /// This map, to serialize with keys per `naming`.
+    pub fn named(&self, naming: KeyNaming) -> Named<'_, T> {
+        Named(self, naming)
+    }
+}
+
+impl<T: Copy + Serialize> Serialize for Named<'_, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.iter().count()))?;
+        for (sensor, value) in self.0.iter() {
+            map.serialize_entry(sensor.json_key(self.1), &value)?;
+        }
+        map.end()
+    }
+}
+
+impl<T: Copy + Serialize> Serialize for SensorMap<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.named(KeyNaming::Legacy).serialize(serializer)
+    }
+}
+
+impl<'de, T: Copy + Deserialize<'de>> Deserialize<'de> for SensorMap<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut result = Self::empty();
+        for (id, value) in BTreeMap::<String, T>::deserialize(deserializer)? {
+            let i =
+                index(&id).ok_or_else(|| de::Error::custom(format!("unknown sensor `{}`", id)))?;
+            result.0[i] = Some(value);
+        }
+        Ok(result)
+    }
+}
@@ -0,0 +1,271 @@
+//! How reliably polling gets fully valid readings, over windows like the last hour, day
+//! and week, broken down by why cycles failed. Counts are kept per minute, and written to
+//! the history database each minute, so restarts don't reset them. A slow decline, eg
+//! from a corroding USB connector, raises an alert before the link fails outright. A
+//! sensor that's only not stabilized yet, like EC settling, is counted, but doesn't make
+//! its cycle invalid.
+
+use std::{collections::BTreeMap, io, sync::Mutex, thread, time::Duration};
+
+use chrono::Utc;
+use rocket::{http::Status, response::content};
+use rusqlite::{params, Connection};
+use serde::Serialize;
+
+use crate::{
+    api::{self, ErrorResponse},
+    config,
+    events::{self, Severity},
+    history, locale,
+    tokens::Viewer,
+    Readings, SensorError,
+};
+
+/// How often to write counts to the database, and check the alert.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Rows older than this are deleted; longer windows can't be reported.
+const KEEP_MS: i64 = 31 * 24 * 60 * 60 * 1_000;
+
+const DAY_MS: i64 = 24 * 60 * 60 * 1_000;
+
+/// Each cycles' outcomes, by minute, not yet written.
+static PENDING: Mutex<BTreeMap<(i64, String), u64>> = Mutex::new(BTreeMap::new());
+
+/// If we've raised the alert for the current run of low reliability.
+static ALERTED: Mutex<bool> = Mutex::new(false);
+
+#[derive(Default, Serialize)]
+pub struct Window {
+    window: String,
+    cycles: u64,
+    valid: u64,
+    /// `None` without cycles.
+    pub valid_pct: Option<f32>,
+    /// Not finding the Water Monitor.
+    no_device: u64,
+    timeout: u64,
+    /// A garbled response.
+    crc: u64,
+    io_error: u64,
+    /// Cycles with an error from each sensor, which may be several per cycle.
+    sensor_errors: BTreeMap<String, u64>,
+    /// Sensor errors by kind, eg `ProbeDisconnected`.
+    error_kinds: BTreeMap<String, u64>,
+}
+
+/// Count a poll cycle's outcome.
+pub fn record(result: &Result<Readings, io::Error>) {
+    if !config::get().history.enabled {
+        return;
+    }
+
+    let mut causes = vec!["cycles".to_owned()];
+    match result {
+        Ok(readings) => {
+            let errors: Vec<_> = readings
+                .iter()
+                .filter_map(|(sensor, r)| r.err().map(|e| (sensor, e)))
+                .collect();
+            if errors.iter().all(|(_, e)| *e == SensorError::NotStabilized) {
+                causes.push("valid".into());
+            }
+            for (sensor, error) in errors {
+                causes.push(format!("sensor_{}", sensor.id));
+                causes.push(format!("error_{}", error));
+            }
+        }
+        Err(e) => causes.push(
+            match e.kind() {
+                io::ErrorKind::NotFound => "no_device",
+                io::ErrorKind::TimedOut => "timeout",
+                io::ErrorKind::InvalidData => "crc",
+                _ => "io_error",
+            }
+            .into(),
+        ),
+    }
+
+    let now = Utc::now().timestamp_millis();
+    let minute = now - now % 60_000;
+    let mut pending = PENDING.lock().unwrap();
+    for cause in causes {
+        *pending.entry((minute, cause)).or_default() += 1;
+    }
+}
+
+/// Write counts to the database, and check the alert, forever; run this on its own
+/// thread.
+pub fn run() {
+    let mut failing = false;
+    loop {
+        thread::sleep(FLUSH_INTERVAL);
+
+        match flush() {
+            Ok(()) => failing = false,
+            Err(e) => {
+                if !failing {
+                    events::record(
+                        Severity::Warning,
+                        "reliability",
+                        format!("Problem saving reliability counts: {}", e),
+                    );
+                }
+                failing = true;
+            }
+        }
+        check_alert();
+    }
+}
+
+fn flush() -> Result<(), io::Error> {
+    let pending = std::mem::take(&mut *PENDING.lock().unwrap());
+    let cfg = config::get().history;
+    let mut conn = history::open(&cfg.path)?;
+    let _lock = history::lock_writes();
+
+    let result = (|| -> Result<(), rusqlite::Error> {
+        let tx = conn.transaction()?;
+        {
+            let mut stmt = tx.prepare_cached(
+                "INSERT INTO reliability (time, cause, count) VALUES (?1, ?2, ?3) \
+                 ON CONFLICT (time, cause) DO UPDATE SET count = count + excluded.count",
+            )?;
+            for ((time, cause), count) in pending.iter() {
+                stmt.execute(params![time, cause, *count as i64])?;
+            }
+        }
+        tx.execute(
+            "DELETE FROM reliability WHERE time < ?1",
+            params![Utc::now().timestamp_millis() - KEEP_MS],
+        )?;
+        tx.commit()
+    })();
+
+    result.map_err(|e| {
+        // Keep them for next time.
+        let mut current = PENDING.lock().unwrap();
+        for (key, count) in pending {
+            *current.entry(key).or_default() += count;
+        }
+        io::Error::new(io::ErrorKind::Other, e.to_string())
+    })
+}
+
+/// Counts since `from_ms`, stored and pending.
+fn window(conn: &Connection, name: &str, from_ms: i64) -> Result<Window, rusqlite::Error> {
+    let mut counts: BTreeMap<String, u64> = BTreeMap::new();
+
+    let mut stmt = conn.prepare_cached(
+        "SELECT cause, SUM(count) FROM reliability WHERE time >= ?1 GROUP BY cause",
+    )?;
+    let rows = stmt.query_map(params![from_ms], |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+    })?;
+    for row in rows {
+        let (cause, count) = row?;
+        *counts.entry(cause).or_default() += count as u64;
+    }
+    for ((time, cause), count) in PENDING.lock().unwrap().iter() {
+        if *time >= from_ms {
+            *counts.entry(cause.clone()).or_default() += count;
+        }
+    }
+
+    let mut result = Window {
+        window: name.to_owned(),
+        ..Default::default()
+    };
+    for (cause, count) in counts {
+        match cause.as_str() {
+            "cycles" => result.cycles = count,
+            "valid" => result.valid = count,
+            "no_device" => result.no_device = count,
+            "timeout" => result.timeout = count,
+            "crc" => result.crc = count,
+            "io_error" => result.io_error = count,
+            c => {
+                if let Some(sensor) = c.strip_prefix("sensor_") {
+                    result.sensor_errors.insert(sensor.to_owned(), count);
+                } else if let Some(kind) = c.strip_prefix("error_") {
+                    result.error_kinds.insert(kind.to_owned(), count);
+                }
+            }
+        }
+    }
+    if result.cycles > 0 {
+        result.valid_pct = Some(result.valid as f32 * 100. / result.cycles as f32);
+    }
+    Ok(result)
+}
+
+/// The last day's figures, eg for `/api/health`. `None` if history is disabled, or
+/// can't be read.
+pub fn last_day() -> Option<Window> {
+    let conn = history::open_reader().ok()?;
+    window(&conn, "24h", Utc::now().timestamp_millis() - DAY_MS).ok()
+}
+
+fn check_alert() {
+    let cfg = config::get().reliability;
+    let threshold = match cfg.alert_below_pct {
+        Some(t) => t,
+        None => return,
+    };
+    let day = match last_day() {
+        Some(d) if d.cycles >= cfg.alert_min_cycles as u64 => d,
+        _ => return,
+    };
+    let pct = day.valid_pct.unwrap_or(0.);
+
+    let locale = locale::configured();
+    let mut alerted = ALERTED.lock().unwrap();
+    if pct < threshold && !*alerted {
+        *alerted = true;
+        events::record(
+            Severity::Alert,
+            "reliability",
+            format!(
+                "Only {}% of the last day's poll cycles gave valid readings, below {}%. \
+                 Check the USB cable and connector; `/api/reliability` has the causes.",
+                locale.number(pct as f64, 1),
+                locale.number(threshold as f64, 1)
+            ),
+        );
+    } else if pct >= threshold && *alerted {
+        *alerted = false;
+        events::record(
+            Severity::Info,
+            "reliability",
+            format!(
+                "{}% of the last day's poll cycles gave valid readings, back above {}%.",
+                locale.number(pct as f64, 1),
+                locale.number(threshold as f64, 1)
+            ),
+        );
+    }
+}
+
+/// Each of `reliability.windows`: the percentage of poll cycles with fully valid
+/// readings, and what went wrong with the rest.
+#[get("/reliability")]
+pub fn view_reliability(_viewer: Viewer) -> Result<content::Json<String>, ErrorResponse> {
+    let conn = history::open_reader()?;
+    let now = Utc::now().timestamp_millis();
+
+    let mut windows = Vec::new();
+    for name in config::get().reliability.windows.iter() {
+        let ms = history::parse_duration(name).ok_or_else(|| {
+            api::error(
+                Status::InternalServerError,
+                &format!(
+                    "`reliability.windows` has `{}`; use durations like `1h` or `7d`",
+                    name
+                ),
+            )
+        })?;
+        windows.push(window(&conn, name, now - ms).map_err(history::query_error)?);
+    }
+
+    Ok(content::Json(serde_json::to_string(&windows).unwrap()))
+}
@@ -0,0 +1,212 @@
+//! Picking up edits to `water-mon.toml` without a restart. A watcher checks the file's
+//! modification time every few seconds, and `POST /api/config/reload` reloads on demand.
+//! The whole file is parsed and checked before anything changes, so a mistake leaves the
+//! previous config in effect, with a warning event. Most settings, like intervals,
+//! targets and units, are read as they're used, so apply straight away. Those only read
+//! at startup, like the listening address, keep their running values until a restart.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs, io,
+    path::Path,
+    sync::Mutex,
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use rocket::{http::Status, response::content};
+use serde::Serialize;
+
+use crate::{
+    api::{self, ErrorResponse},
+    auth::Admin,
+    config::{self, AppConfig, CONFIG_PATH},
+    events::{self, Severity},
+    validate,
+};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Settings only read at startup, as dotted paths. Each covers the settings under it.
+const NEEDS_RESTART: &[&str] = &[
+    "server.address",
+    "server.ip_version",
+    "server.port",
+    "server.unix_socket",
+    "server.unix_socket_mode",
+    "server.tcp",
+    "access_log",
+    "modbus",
+    "snmp",
+    "coap",
+    "history.enabled",
+    "history.path",
+    // Rocket's secret key, for session cookies.
+    "auth.session_key",
+];
+
+/// The config file's modification time when it was last loaded. Held while reloading,
+/// so the watcher and the API don't reload at once.
+static LOADED: Mutex<Option<SystemTime>> = Mutex::new(None);
+
+#[derive(Serialize)]
+pub struct Reload {
+    /// Settings that changed, and now apply.
+    pub applied: Vec<String>,
+    /// Settings that changed, but keep their running values until a restart.
+    pub needs_restart: Vec<String>,
+}
+
+fn modified() -> Option<SystemTime> {
+    fs::metadata(CONFIG_PATH).and_then(|m| m.modified()).ok()
+}
+
+fn invalid(e: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+/// `value`'s settings, by dotted path. Arrays, like `devices`, count as one setting.
+fn flatten(path: &str, value: &toml::Value, into: &mut BTreeMap<String, toml::Value>) {
+    match value {
+        toml::Value::Table(table) => {
+            for (key, v) in table {
+                let path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                flatten(&path, v, into);
+            }
+        }
+        v => {
+            into.insert(path.into(), v.clone());
+        }
+    }
+}
+
+fn lookup<'a>(value: &'a toml::Value, path: &str) -> Option<&'a toml::Value> {
+    path.split('.').try_fold(value, |v, key| v.get(key))
+}
+
+/// Set the setting at `path`, or remove it for `None`.
+fn replace(value: &mut toml::Value, path: &str, with: Option<toml::Value>) {
+    let (parent, key) = match path.rsplit_once('.') {
+        Some((parent, key)) => (Some(parent), key),
+        None => (None, path),
+    };
+    let parent = match parent {
+        Some(p) => p.split('.').try_fold(value, |v, key| v.get_mut(key)),
+        None => Some(value),
+    };
+    if let Some(toml::Value::Table(table)) = parent {
+        match with {
+            Some(w) => table.insert(key.into(), w),
+            None => table.remove(key),
+        };
+    }
+}
+
+fn needs_restart(path: &str) -> bool {
+    NEEDS_RESTART.iter().any(|p| {
+        path == *p
+            || path
+                .strip_prefix(p)
+                .is_some_and(|rest| rest.starts_with('.'))
+    })
+}
+
+fn apply() -> Result<Reload, io::Error> {
+    let new = AppConfig::load(Path::new(CONFIG_PATH))?;
+    validate::check(&new)?;
+
+    // Compared in full, with defaults, so removing a setting counts as changing it.
+    let old = toml::Value::try_from(config::get()).map_err(invalid)?;
+    let mut new = toml::Value::try_from(new).map_err(invalid)?;
+    let (mut old_settings, mut new_settings) = (BTreeMap::new(), BTreeMap::new());
+    flatten("", &old, &mut old_settings);
+    flatten("", &new, &mut new_settings);
+
+    let changed: BTreeSet<_> = old_settings
+        .keys()
+        .chain(new_settings.keys())
+        .filter(|k| old_settings.get(*k) != new_settings.get(*k))
+        .cloned()
+        .collect();
+    let (needs_restart, applied) = changed.into_iter().partition(|k| needs_restart(k));
+
+    for path in NEEDS_RESTART {
+        replace(&mut new, path, lookup(&old, path).cloned());
+    }
+    config::set(new.try_into().map_err(invalid)?);
+
+    Ok(Reload {
+        applied,
+        needs_restart,
+    })
+}
+
+/// Load the config file, and apply what changed. On an error, nothing changes.
+pub fn reload() -> Result<Reload, io::Error> {
+    let mut loaded = LOADED.lock().unwrap();
+    // Before reading, so a save while we do is picked up next time.
+    *loaded = modified();
+
+    let result = apply();
+    match &result {
+        Ok(reload) => {
+            if !reload.applied.is_empty() {
+                events::record(
+                    Severity::Info,
+                    "config",
+                    format!("Applied config changes: {}", reload.applied.join(", ")),
+                );
+            }
+            if !reload.needs_restart.is_empty() {
+                events::record(
+                    Severity::Warning,
+                    "config",
+                    format!(
+                        "Changes to {} take effect after a restart",
+                        reload.needs_restart.join(", ")
+                    ),
+                );
+            }
+        }
+        Err(e) => events::record(
+            Severity::Warning,
+            "config",
+            format!(
+                "Problem reloading `{}`, so the previous config stays in effect: {}",
+                CONFIG_PATH, e
+            ),
+        ),
+    }
+    result
+}
+
+/// Reload the config whenever the file changes, forever; run this on its own thread.
+pub fn run() {
+    *LOADED.lock().unwrap() = modified();
+
+    loop {
+        thread::sleep(CHECK_INTERVAL);
+        let changed = *LOADED.lock().unwrap() != modified();
+        if changed {
+            // Errors are recorded as events.
+            let _ = reload();
+        }
+    }
+}
+
+/// Reload `water-mon.toml` now, as when it changes.
+#[post("/config/reload")]
+pub fn reload_config(_admin: Admin) -> Result<content::Json<String>, ErrorResponse> {
+    let reload = reload().map_err(|e| {
+        let status = match e.kind() {
+            io::ErrorKind::InvalidData | io::ErrorKind::InvalidInput => Status::BadRequest,
+            _ => Status::InternalServerError,
+        };
+        api::error(status, &format!("Problem reloading the config: {}", e))
+    })?;
+    Ok(content::Json(serde_json::to_string(&reload).unwrap()))
+}
@@ -0,0 +1,76 @@
+//! A short ID for each API request, so an error a user reports can be matched to the
+//! log. It's a proxy's `X-Request-Id`, if that looks sane, or else one we make. It's
+//! returned in the `X-Request-Id` header and in JSON error bodies, and attached to events
+//! raised while handling the request, including serial errors it causes, so
+//! `/api/events?request_id=` traces one failure end to end. Rocket handles each request
+//! on one thread, so while it's handled the ID is kept in a thread local.
+
+use std::cell::RefCell;
+
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    Data, Request, Response,
+};
+
+const HEADER: &str = "X-Request-Id";
+
+/// The longest incoming ID we accept.
+const MAX_LEN: usize = 64;
+
+thread_local! {
+    /// The ID of the request this thread is handling, if any.
+    static CURRENT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Stored in request-local cache when a request arrives.
+pub struct RequestId(pub Option<String>);
+
+/// The ID of the request being handled on this thread, if any.
+pub fn current() -> Option<String> {
+    CURRENT.with(|c| c.borrow().clone())
+}
+
+/// If an incoming ID is safe to log and echo back.
+fn valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_LEN
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(&b))
+}
+
+/// 8 hex digits; enough to tell apart requests in the event log.
+fn new_id() -> String {
+    format!("{:08x}", rand::random::<u32>())
+}
+
+/// Assigns each API request its ID, and returns it in `X-Request-Id`.
+pub struct RequestIds;
+
+impl Fairing for RequestIds {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request IDs",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        let id = request.uri().path().starts_with("/api").then(|| {
+            request
+                .headers()
+                .get_one(HEADER)
+                .filter(|id| valid(id))
+                .map_or_else(new_id, str::to_owned)
+        });
+        CURRENT.with(|c| *c.borrow_mut() = id.clone());
+        request.local_cache(|| RequestId(id));
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        CURRENT.with(|c| *c.borrow_mut() = None);
+        if let Some(id) = &request.local_cache(|| RequestId(None)).0 {
+            response.set_raw_header(HEADER, id.clone());
+        }
+    }
+}
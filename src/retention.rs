@@ -0,0 +1,307 @@
+//! Bounds how much history is kept: rows older than `max_age_days` are deleted, and if
+//! the database is still over `max_size_mb`, the oldest rows go next. Compaction rolls
+//! raw samples up long before either limit applies, so summaries outlive raw data.
+//!
+//! Deletes happen in small chunks, each holding the write lock only briefly, so the
+//! history writer never waits long.
+
+use std::{fs, sync::Mutex, thread, time::Duration};
+
+use chrono::Utc;
+use rocket::response::content;
+use rusqlite::{params, Connection};
+use serde::Serialize;
+use serde_json::json;
+
+use crate::{
+    api::ErrorResponse,
+    config::{self, HistoryConfig},
+    events::{self, Severity},
+    history::{self, AGGREGATE_TIERS},
+    locale,
+};
+
+/// Rows per delete transaction.
+const CHUNK_ROWS: u32 = 5_000;
+
+/// Pause between chunks, so the history writer gets a turn.
+const CHUNK_PAUSE: Duration = Duration::from_millis(50);
+
+/// Every table of samples: the tiers, finest first, then devices' pushed readings.
+const TABLES: [&str; 4] = [
+    "samples",
+    AGGREGATE_TIERS[0].0,
+    AGGREGATE_TIERS[1].0,
+    "pushed_samples",
+];
+
+static LAST_PRUNE: Mutex<Option<PruneReport>> = Mutex::new(None);
+
+#[derive(Clone, Serialize)]
+pub struct TierRows {
+    tier: &'static str,
+    rows: u64,
+}
+
+#[derive(Clone, Serialize)]
+pub struct PruneReport {
+    time: String,
+    dry_run: bool,
+    /// Rows deleted for being older than `max_age_days`, or that would be.
+    by_age: Vec<TierRows>,
+    /// Rows deleted to get under `max_size_mb`, or an estimate of how many would be.
+    by_size: Vec<TierRows>,
+    used_bytes_before: u64,
+    /// Estimated, for a dry run.
+    used_bytes_after: u64,
+}
+
+impl PruneReport {
+    fn deleted(&self) -> u64 {
+        self.by_age
+            .iter()
+            .chain(&self.by_size)
+            .map(|t| t.rows)
+            .sum()
+    }
+}
+
+fn tier_rows(rows: [u64; TABLES.len()]) -> Vec<TierRows> {
+    TABLES
+        .iter()
+        .zip(rows.iter())
+        .filter(|(_, n)| **n > 0)
+        .map(|(tier, rows)| TierRows { tier, rows: *rows })
+        .collect()
+}
+
+/// Bytes in pages holding data. Deleting rows frees pages for reuse rather than
+/// shrinking the file, so this is what the size limit applies to.
+fn used_bytes(conn: &Connection) -> Result<u64, rusqlite::Error> {
+    let pragma = |name: &str| -> Result<i64, rusqlite::Error> {
+        conn.query_row(&format!("PRAGMA {}", name), [], |row| row.get(0))
+    };
+    Ok(((pragma("page_count")? - pragma("freelist_count")?) * pragma("page_size")?) as u64)
+}
+
+fn row_count(conn: &Connection, table: &str) -> Result<u64, rusqlite::Error> {
+    conn.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| {
+        row.get::<_, i64>(0)
+    })
+    .map(|n| n as u64)
+}
+
+fn oldest(conn: &Connection, table: &str) -> Result<Option<i64>, rusqlite::Error> {
+    conn.query_row(&format!("SELECT MIN(time) FROM {}", table), [], |row| {
+        row.get(0)
+    })
+}
+
+/// Delete up to `CHUNK_ROWS` of the oldest rows before `cutoff`.
+fn delete_chunk(conn: &Connection, table: &str, cutoff: i64) -> Result<u64, rusqlite::Error> {
+    let _lock = history::lock_writes();
+    conn.execute(
+        &format!(
+            "DELETE FROM {0} WHERE rowid IN \
+             (SELECT rowid FROM {0} WHERE time < ?1 ORDER BY time LIMIT ?2)",
+            table
+        ),
+        params![cutoff, CHUNK_ROWS],
+    )
+    .map(|n| n as u64)
+}
+
+/// Apply the retention limits. With `dry_run`, nothing is deleted, and the report
+/// says what would be.
+pub fn prune(
+    conn: &Connection,
+    cfg: &HistoryConfig,
+    dry_run: bool,
+) -> Result<PruneReport, rusqlite::Error> {
+    let used_before = used_bytes(conn)?;
+    let mut by_age = [0; TABLES.len()];
+    let mut by_size = [0; TABLES.len()];
+
+    if let Some(days) = cfg.max_age_days {
+        let cutoff = Utc::now().timestamp_millis() - days as i64 * 24 * 60 * 60 * 1_000;
+
+        for (i, table) in TABLES.iter().enumerate() {
+            if dry_run {
+                by_age[i] = conn.query_row(
+                    &format!("SELECT COUNT(*) FROM {} WHERE time < ?1", table),
+                    params![cutoff],
+                    |row| row.get::<_, i64>(0),
+                )? as u64;
+                continue;
+            }
+
+            loop {
+                let n = delete_chunk(conn, table, cutoff)?;
+                by_age[i] += n;
+                if n == 0 {
+                    break;
+                }
+                thread::sleep(CHUNK_PAUSE);
+            }
+        }
+    }
+
+    let max_bytes = cfg.max_size_mb.map(|mb| mb * 1_024 * 1_024);
+
+    let used_after = match max_bytes {
+        Some(max) if dry_run => estimate_size_prune(conn, max, used_before, &by_age, &mut by_size)?,
+        Some(max) => {
+            let mut used = used_bytes(conn)?;
+            while used > max {
+                // The table with the oldest row, preferring the finer on a tie.
+                let mut next: Option<(usize, i64)> = None;
+                for (i, table) in TABLES.iter().enumerate() {
+                    if let Some(t) = oldest(conn, table)? {
+                        if next.is_none_or(|(_, oldest)| t < oldest) {
+                            next = Some((i, t));
+                        }
+                    }
+                }
+
+                let i = match next {
+                    Some((i, _)) => i,
+                    None => break,
+                };
+                by_size[i] += delete_chunk(conn, TABLES[i], i64::MAX)?;
+                used = used_bytes(conn)?;
+                thread::sleep(CHUNK_PAUSE);
+            }
+            used
+        }
+        None if dry_run => used_before,
+        None => used_bytes(conn)?,
+    };
+
+    Ok(PruneReport {
+        time: Utc::now().to_rfc3339(),
+        dry_run,
+        by_age: tier_rows(by_age),
+        by_size: tier_rows(by_size),
+        used_bytes_before: used_before,
+        used_bytes_after: used_after,
+    })
+}
+
+/// Estimate the rows deleting oldest-first would take to get under `max` bytes, from the
+/// average row size, after the age limit's deletions. Returns the estimated size after.
+fn estimate_size_prune(
+    conn: &Connection,
+    max: u64,
+    used: u64,
+    by_age: &[u64; TABLES.len()],
+    by_size: &mut [u64; TABLES.len()],
+) -> Result<u64, rusqlite::Error> {
+    let mut remaining = [0; TABLES.len()];
+    for (i, table) in TABLES.iter().enumerate() {
+        remaining[i] = row_count(conn, table)? - by_age[i];
+    }
+
+    let total_rows: u64 = remaining.iter().sum::<u64>() + by_age.iter().sum::<u64>();
+    if total_rows == 0 {
+        return Ok(used);
+    }
+    let bytes_per_row = used as f64 / total_rows as f64;
+    let used = used - (by_age.iter().sum::<u64>() as f64 * bytes_per_row) as u64;
+    if used <= max {
+        return Ok(used);
+    }
+
+    let mut to_delete = ((used - max) as f64 / bytes_per_row).ceil() as u64;
+
+    // Tiers hold mostly separate time ranges, so take whole tiers, oldest first.
+    let mut order: Vec<(usize, i64)> = Vec::new();
+    for (i, table) in TABLES.iter().enumerate() {
+        if let Some(t) = oldest(conn, table)? {
+            order.push((i, t));
+        }
+    }
+    order.sort_by_key(|(i, t)| (*t, *i));
+
+    for (i, _) in order {
+        let n = to_delete.min(remaining[i]);
+        by_size[i] = n;
+        to_delete -= n;
+    }
+
+    let deleted: u64 = by_size.iter().sum();
+    Ok(used.saturating_sub((deleted as f64 * bytes_per_row) as u64))
+}
+
+/// Prune, and keep the report for `/api/storage`. Called by the compactor after each
+/// run.
+pub fn run(cfg: &HistoryConfig) -> Result<(), Box<dyn std::error::Error>> {
+    if cfg.max_age_days.is_none() && cfg.max_size_mb.is_none() {
+        return Ok(());
+    }
+
+    let conn = history::open(&cfg.path)?;
+    let report = prune(&conn, cfg, false)?;
+
+    let deleted = report.deleted();
+    if deleted > 0 {
+        events::record(
+            Severity::Info,
+            "retention",
+            format!(
+                "Pruned {} history rows; the database now uses {} MiB.",
+                deleted,
+                locale::configured().number(report.used_bytes_after as f64 / 1_048_576., 1)
+            ),
+        );
+    }
+    *LAST_PRUNE.lock().unwrap() = Some(report);
+
+    Ok(())
+}
+
+/// Disk usage, rows per tier, and the last prune. With `dry_run=true`, also what
+/// pruning now would delete.
+#[get("/storage?<dry_run>")]
+pub fn view_storage(dry_run: Option<bool>) -> Result<content::Json<String>, ErrorResponse> {
+    let cfg = config::get().history;
+    let conn = history::open_reader()?;
+
+    let file_bytes: u64 = ["", "-wal"]
+        .iter()
+        .filter_map(|suffix| fs::metadata(format!("{}{}", cfg.path, suffix)).ok())
+        .map(|m| m.len())
+        .sum();
+
+    let mut tiers = Vec::new();
+    for table in TABLES.iter() {
+        let (rows, oldest, newest): (i64, Option<i64>, Option<i64>) = conn
+            .query_row(
+                &format!("SELECT COUNT(*), MIN(time), MAX(time) FROM {}", table),
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .map_err(history::query_error)?;
+        tiers.push(json!({
+            "name": table,
+            "rows": rows,
+            "oldest": oldest.map(history::format_time),
+            "newest": newest.map(history::format_time),
+        }));
+    }
+
+    let mut result = json!({
+        "path": cfg.path,
+        "file_bytes": file_bytes,
+        "used_bytes": used_bytes(&conn).map_err(history::query_error)?,
+        "max_bytes": cfg.max_size_mb.map(|mb| mb * 1_024 * 1_024),
+        "max_age_days": cfg.max_age_days,
+        "tiers": tiers,
+        "last_prune": LAST_PRUNE.lock().unwrap().clone(),
+    });
+
+    if dry_run == Some(true) {
+        result["dry_run"] = json!(prune(&conn, &cfg, true).map_err(history::query_error)?);
+    }
+
+    Ok(content::Json(result.to_string()))
+}
@@ -0,0 +1,381 @@
+//! A water-quality score from 0 to 100, for people who just want one number, and a
+//! color. Each sensor with a `[score]` weight has that share of it, and loses its
+//! status's `penalties` fraction of the share: for being outside its target range, in
+//! error, stale, or without flow. Points are rounded per sensor, so the components add
+//! up to the score. It's at `/api/score`, pushed with the metrics, and alert rules can
+//! test it, as `sensor = "score"`.
+
+use std::io;
+
+use rocket::response::content;
+use serde::Serialize;
+
+use crate::{
+    config::{self, AppConfig, ScoreConfig, ScorePenalties},
+    registry::{self, SensorDef},
+    status::{self, SensorStatus},
+    tokens::Viewer,
+    Readings,
+};
+
+/// What alert conditions call the score.
+pub const SENSOR: &str = "score";
+
+/// The lowest good score, and fair one.
+const GOOD: u8 = 80;
+const FAIR: u8 = 50;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Rating {
+    Good,
+    Fair,
+    Poor,
+}
+
+impl Rating {
+    fn of(score: u8) -> Self {
+        if score >= GOOD {
+            Self::Good
+        } else if score >= FAIR {
+            Self::Fair
+        } else {
+            Self::Poor
+        }
+    }
+
+    /// As the basic dashboard colors statuses.
+    pub fn color(&self) -> &'static str {
+        match self {
+            Self::Good => "#1e8e3e",
+            Self::Fair => "#c78a00",
+            Self::Poor => "#c62828",
+        }
+    }
+}
+
+/// A sensor's status, for scoring.
+pub struct Input {
+    pub sensor: &'static SensorDef,
+    pub status: SensorStatus,
+    /// If it's outside its range, whether it's below it.
+    pub below: bool,
+}
+
+#[derive(Serialize)]
+pub struct Component {
+    pub sensor: &'static str,
+    pub status: SensorStatus,
+    /// Its share of the score.
+    pub weight_pct: f32,
+    /// Taken off the score, so 0 or negative.
+    pub points: i32,
+    /// Eg `ORP below range`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Score {
+    /// `None` if no weighted sensor has a reading.
+    pub score: Option<u8>,
+    pub rating: Option<Rating>,
+    pub color: Option<&'static str>,
+    pub components: Vec<Component>,
+}
+
+/// Check `[score]`.
+pub fn check(cfg: &AppConfig) -> Result<(), io::Error> {
+    let invalid = |msg: String| {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Config error: {}", msg),
+        ))
+    };
+    for (sensor, weight) in cfg.score.weights.iter() {
+        if registry::get(sensor).is_none() {
+            return invalid(format!(
+                "`score.weights` has an unknown sensor, `{}`",
+                sensor
+            ));
+        }
+        if !weight.is_finite() || *weight < 0. {
+            return invalid(format!("`score.weights.{}` can't be negative", sensor));
+        }
+    }
+    let p = &cfg.score.penalties;
+    let penalties = [
+        ("warn", p.warn),
+        ("critical", p.critical),
+        ("error", p.error),
+        ("stale", p.stale),
+        ("no_flow", p.no_flow),
+        ("stabilizing", p.stabilizing),
+    ];
+    for (name, penalty) in penalties {
+        if !(0. ..=1.).contains(&penalty) {
+            return invalid(format!("`score.penalties.{}` must be from 0 to 1", name));
+        }
+    }
+    Ok(())
+}
+
+fn penalty(status: SensorStatus, p: &ScorePenalties) -> f32 {
+    match status {
+        SensorStatus::Ok => 0.,
+        SensorStatus::Warn => p.warn,
+        SensorStatus::Critical => p.critical,
+        SensorStatus::Error | SensorStatus::Rejected => p.error,
+        SensorStatus::Stale => p.stale,
+        SensorStatus::NoFlow => p.no_flow,
+        SensorStatus::Stabilizing => p.stabilizing,
+    }
+}
+
+fn reason(input: &Input) -> Option<String> {
+    let label = input.sensor.label;
+    let side = if input.below { "below" } else { "above" };
+    match input.status {
+        SensorStatus::Ok => None,
+        SensorStatus::Warn => Some(format!("{} slightly {} range", label, side)),
+        SensorStatus::Critical => Some(format!("{} {} range", label, side)),
+        SensorStatus::Error => Some(format!("{} has no valid reading", label)),
+        SensorStatus::Stale => Some(format!("{} reading is stale", label)),
+        SensorStatus::NoFlow => Some(format!("no flow past the {} probe", label)),
+        SensorStatus::Stabilizing => Some(format!("{} probe is stabilizing", label)),
+        SensorStatus::Rejected => Some(format!("{} reading was rejected", label)),
+    }
+}
+
+/// The score for these statuses. Deterministic: the same inputs and config always give
+/// the same score and components.
+pub fn compute(inputs: &[Input], cfg: &ScoreConfig) -> Score {
+    let weight = |i: &Input| cfg.weights.get(i.sensor.id).copied().unwrap_or(0.);
+    let weighted: Vec<_> = inputs.iter().filter(|i| weight(i) > 0.).collect();
+    let total: f32 = weighted.iter().map(|i| weight(i)).sum();
+
+    let components: Vec<_> = weighted
+        .iter()
+        .map(|i| {
+            let share = weight(i) / total;
+            Component {
+                sensor: i.sensor.id,
+                status: i.status,
+                weight_pct: (share * 1_000.).round() / 10.,
+                points: -(share * penalty(i.status, &cfg.penalties) * 100.).round() as i32,
+                reason: reason(i),
+            }
+        })
+        .collect();
+
+    let score = (!components.is_empty())
+        .then(|| (100 + components.iter().map(|c| c.points).sum::<i32>()).clamp(0, 100) as u8);
+    let rating = score.map(Rating::of);
+    Score {
+        score,
+        rating,
+        color: rating.map(|r| r.color()),
+        components,
+    }
+}
+
+/// The score for `readings`, which are the latest.
+pub fn current(cfg: &AppConfig, readings: &Readings) -> Score {
+    let inputs: Vec<_> = status::classify(readings, &cfg.status)
+        .iter()
+        .map(|(sensor, status)| {
+            let target = cfg.status.targets.get(sensor.id, cfg.ec.probe);
+            Input {
+                sensor,
+                status,
+                below: match (readings.reading(sensor.id), target) {
+                    (Ok(v), Some([min, _])) => v < min,
+                    _ => false,
+                },
+            }
+        })
+        .collect();
+    compute(&inputs, &cfg.score)
+}
+
+/// The water-quality score for the latest readings, and what each sensor took off it.
+#[get("/score")]
+pub fn view_score(_viewer: Viewer) -> content::Json<String> {
+    let score = current(&config::get(), &crate::latest_readings());
+    content::Json(serde_json::to_string(&score).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A case, with the default config.
+    struct Scenario {
+        name: &'static str,
+        /// Each sensor's status, and if it's below its range.
+        statuses: &'static [(&'static str, SensorStatus, bool)],
+        score: Option<u8>,
+    }
+
+    const SCENARIOS: &[Scenario] = &[
+        Scenario {
+            name: "all in range",
+            statuses: &[
+                ("T", SensorStatus::Ok, false),
+                ("pH", SensorStatus::Ok, false),
+                ("ORP", SensorStatus::Ok, false),
+                ("ec", SensorStatus::Ok, false),
+            ],
+            score: Some(100),
+        },
+        Scenario {
+            name: "ORP well below range",
+            statuses: &[
+                ("T", SensorStatus::Ok, false),
+                ("pH", SensorStatus::Ok, false),
+                ("ORP", SensorStatus::Critical, true),
+                ("ec", SensorStatus::Ok, false),
+            ],
+            score: Some(62),
+        },
+        Scenario {
+            name: "pH slightly above range",
+            statuses: &[
+                ("T", SensorStatus::Ok, false),
+                ("pH", SensorStatus::Warn, false),
+                ("ORP", SensorStatus::Ok, false),
+                ("ec", SensorStatus::Ok, false),
+            ],
+            score: Some(85),
+        },
+        Scenario {
+            name: "temperature slightly off, and EC in error",
+            statuses: &[
+                ("T", SensorStatus::Warn, true),
+                ("pH", SensorStatus::Ok, false),
+                ("ORP", SensorStatus::Ok, false),
+                ("ec", SensorStatus::Error, false),
+            ],
+            score: Some(82),
+        },
+        Scenario {
+            name: "every reading stale",
+            statuses: &[
+                ("T", SensorStatus::Stale, false),
+                ("pH", SensorStatus::Stale, false),
+                ("ORP", SensorStatus::Stale, false),
+                ("ec", SensorStatus::Stale, false),
+            ],
+            score: Some(50),
+        },
+        Scenario {
+            name: "no flow past pH and ORP",
+            statuses: &[
+                ("T", SensorStatus::Ok, false),
+                ("pH", SensorStatus::NoFlow, false),
+                ("ORP", SensorStatus::NoFlow, false),
+                ("ec", SensorStatus::Ok, false),
+            ],
+            score: Some(62),
+        },
+        Scenario {
+            name: "disconnected",
+            statuses: &[
+                ("T", SensorStatus::Error, false),
+                ("pH", SensorStatus::Error, false),
+                ("ORP", SensorStatus::Error, false),
+                ("ec", SensorStatus::Error, false),
+            ],
+            score: Some(0),
+        },
+        Scenario {
+            name: "pH well above range, with dissolved oxygen in range",
+            statuses: &[
+                ("T", SensorStatus::Ok, false),
+                ("pH", SensorStatus::Critical, false),
+                ("ORP", SensorStatus::Ok, false),
+                ("ec", SensorStatus::Ok, false),
+                ("DO", SensorStatus::Ok, false),
+            ],
+            score: Some(70),
+        },
+        Scenario {
+            name: "only unweighted sensors",
+            statuses: &[("flow", SensorStatus::Ok, false)],
+            score: None,
+        },
+    ];
+
+    #[test]
+    fn scenarios_score_as_expected() {
+        let cfg = ScoreConfig::default();
+        for scenario in SCENARIOS {
+            let inputs: Vec<_> = scenario
+                .statuses
+                .iter()
+                .map(|(id, status, below)| Input {
+                    sensor: registry::get(id).unwrap(),
+                    status: *status,
+                    below: *below,
+                })
+                .collect();
+            assert_eq!(
+                compute(&inputs, &cfg).score,
+                scenario.score,
+                "{}",
+                scenario.name
+            );
+        }
+    }
+
+    /// Each component says what it took off, and why, and they add up to the score.
+    #[test]
+    fn components_explain_the_score() {
+        let input = |id, status, below| Input {
+            sensor: registry::get(id).unwrap(),
+            status,
+            below,
+        };
+        let inputs = [
+            input("T", SensorStatus::Ok, false),
+            input("pH", SensorStatus::Warn, false),
+            input("ORP", SensorStatus::Critical, true),
+            input("ec", SensorStatus::Stale, false),
+            input("flow", SensorStatus::NoFlow, false),
+        ];
+        let score = compute(&inputs, &ScoreConfig::default());
+
+        let components: Vec<_> = score
+            .components
+            .iter()
+            .map(|c| (c.sensor, c.weight_pct, c.points, c.reason.as_deref()))
+            .collect();
+        assert_eq!(
+            components,
+            [
+                ("T", 12.5, 0, None),
+                ("pH", 37.5, -15, Some("pH slightly above range")),
+                ("ORP", 37.5, -38, Some("ORP below range")),
+                ("ec", 12.5, -6, Some("EC reading is stale")),
+            ]
+        );
+        assert_eq!(score.score, Some(41));
+        assert_eq!(score.rating, Some(Rating::Poor));
+        assert_eq!(score.color, Some(Rating::Poor.color()));
+
+        let again = compute(&inputs, &ScoreConfig::default());
+        assert_eq!(
+            serde_json::to_string(&again).unwrap(),
+            serde_json::to_string(&score).unwrap()
+        );
+    }
+
+    #[test]
+    fn weights_and_penalties_are_checked() {
+        let config = |toml: &str| check(&toml::from_str(toml).unwrap());
+        assert!(config("[score.weights]\npH = 2").is_ok());
+        assert!(config("[score.weights]\nsalinity = 1").is_err());
+        assert!(config("[score.weights]\npH = -1").is_err());
+        assert!(config("[score.penalties]\nstale = 1.5").is_err());
+    }
+}
@@ -0,0 +1,282 @@
+//! Checks at startup for the usual reasons a new install doesn't work: the web page's
+//! files missing, the port taken or needing root, serial ports unreadable, and no Water
+//! Monitor plugged in. Results go to the console as a table, with a fix for each
+//! problem, and are kept for `/api/selfcheck`. Only a port we can't listen on stops the
+//! server; the rest are warnings, since eg the Water Monitor is found whenever it's
+//! plugged in.
+
+use std::{env, io, net::TcpListener, path::PathBuf, sync::Mutex};
+
+use chrono::Utc;
+use rocket::response::content;
+use serde::Serialize;
+
+use crate::{config::ServerConfig, history};
+
+/// The web page's files' folder, by default, next to the executable or in the working
+/// directory.
+pub const STATIC_DIR: &str = "static";
+
+/// Overrides where the web page's files are, unless `server.static_dir` is set.
+const STATIC_DIR_VAR: &str = "WATER_MON_STATIC_DIR";
+
+static RESULTS: Mutex<Option<Report>> = Mutex::new(None);
+
+/// Where the web page's files are, once found.
+static STATIC_PATH: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Outcome {
+    Pass,
+    /// A problem, but the server can run.
+    Warn,
+    /// The server can't run.
+    Fail,
+}
+
+#[derive(Clone, Serialize)]
+struct Check {
+    name: &'static str,
+    outcome: Outcome,
+    detail: String,
+    /// What to do about it, for warnings and failures.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fix: Option<String>,
+}
+
+#[derive(Clone, Serialize)]
+struct Report {
+    checked: String,
+    checks: Vec<Check>,
+}
+
+fn check(name: &'static str, outcome: Outcome, detail: String, fix: Option<String>) -> Check {
+    Check {
+        name,
+        outcome,
+        detail,
+        fix,
+    }
+}
+
+/// Find the web page's files: `server.static_dir` or `WATER_MON_STATIC_DIR`, as given;
+/// or else `static` next to the executable, if it has an `index.html`, since the app is
+/// often launched from another folder; or else `static` in the working directory.
+pub fn resolve_static_dir(server: &ServerConfig) -> PathBuf {
+    let path = server
+        .static_dir
+        .as_ref()
+        .map(PathBuf::from)
+        .or_else(|| env::var_os(STATIC_DIR_VAR).map(PathBuf::from))
+        .or_else(|| {
+            let dir = env::current_exe().ok()?.parent()?.join(STATIC_DIR);
+            dir.join("index.html").is_file().then_some(dir)
+        })
+        .unwrap_or_else(|| PathBuf::from(STATIC_DIR));
+    *STATIC_PATH.lock().unwrap() = Some(path.clone());
+    path
+}
+
+/// Where the web page's files are, as found at startup.
+pub fn static_dir() -> PathBuf {
+    STATIC_PATH
+        .lock()
+        .unwrap()
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(STATIC_DIR))
+}
+
+fn static_files() -> Check {
+    let index = static_dir().join("index.html");
+    if index.is_file() {
+        check(
+            "web page",
+            Outcome::Pass,
+            format!("Found `{}`", index.display()),
+            None,
+        )
+    } else {
+        let cwd = std::env::current_dir()
+            .map(|d| d.display().to_string())
+            .unwrap_or_else(|_| "?".into());
+        check(
+            "web page",
+            Outcome::Warn,
+            format!("No `{}` in `{}`", index.display(), cwd),
+            Some(format!(
+                "Put the web page's files in `{}` next to the app, or set `server.static_dir` \
+                 or `{}`. Until then, the API works, and `/` shows a basic status page",
+                STATIC_DIR, STATIC_DIR_VAR
+            )),
+        )
+    }
+}
+
+fn port(address: &str, port: u16) -> Check {
+    let name = "port";
+    // Released straight away, for the server to take.
+    match TcpListener::bind((address, port)) {
+        Ok(_) => check(name, Outcome::Pass, format!("Can listen on {}", port), None),
+        Err(e) => {
+            let fix = match e.kind() {
+                io::ErrorKind::PermissionDenied => format!(
+                    "Ports below 1024 need root. Set `server.port` to eg 8080, or on Linux, \
+                     allow it with `sudo setcap cap_net_bind_service=+ep {}`, or let systemd \
+                     bind it, with `install-service --socket`",
+                    std::env::current_exe()
+                        .map(|p| p.display().to_string())
+                        .unwrap_or_else(|_| "<this program>".into())
+                ),
+                io::ErrorKind::AddrInUse => format!(
+                    "Another program is using port {}; stop it, or set `server.port`",
+                    port
+                ),
+                io::ErrorKind::AddrNotAvailable => format!(
+                    "`{}` isn't an address of this computer; fix `server.address`",
+                    address
+                ),
+                _ => "Check `server.address` and `server.port`".into(),
+            };
+            check(
+                name,
+                Outcome::Fail,
+                format!("Can't listen on {} port {}: {}", address, port, e),
+                Some(fix),
+            )
+        }
+    }
+}
+
+#[cfg(unix)]
+fn readable(path: &str) -> bool {
+    use std::ffi::CString;
+
+    match CString::new(path) {
+        Ok(p) => unsafe { libc::access(p.as_ptr(), libc::R_OK | libc::W_OK) == 0 },
+        Err(_) => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn readable(_path: &str) -> bool {
+    // Windows doesn't restrict ports by user.
+    true
+}
+
+/// Serial ports, and whether we can open them, then the Water Monitor itself.
+fn serial() -> Vec<Check> {
+    let ports = match serialport::available_ports() {
+        Ok(p) => p,
+        Err(e) => {
+            return vec![check(
+                "serial ports",
+                Outcome::Warn,
+                format!("Problem listing serial ports: {}", e),
+                Some("Check that this user can see `/dev` or the Device Manager".into()),
+            )]
+        }
+    };
+
+    let names: Vec<_> = ports.iter().map(|p| p.port_name.as_str()).collect();
+    let unreadable: Vec<_> = names.iter().filter(|n| !readable(n)).collect();
+    let mut result = vec![if ports.is_empty() {
+        check(
+            "serial ports",
+            Outcome::Warn,
+            "No serial ports found".into(),
+            Some("Plug the Water Monitor in by USB".into()),
+        )
+    } else if !unreadable.is_empty() {
+        check(
+            "serial ports",
+            Outcome::Warn,
+            format!(
+                "No permission to open {}",
+                unreadable
+                    .iter()
+                    .map(|n| format!("`{}`", n))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Some(
+                "Add this user to the `dialout` group, with `sudo usermod -aG dialout $USER`, \
+                 then log out and back in"
+                    .into(),
+            ),
+        )
+    } else {
+        check(
+            "serial ports",
+            Outcome::Pass,
+            format!("Found {}", names.join(", ")),
+            None,
+        )
+    }];
+
+    result.push(match crate::find_port(&ports) {
+        Some(p) => check(
+            "water monitor",
+            Outcome::Pass,
+            format!("Found on `{}`", p.port_name),
+            None,
+        ),
+        None => check(
+            "water monitor",
+            Outcome::Warn,
+            "Not plugged in".into(),
+            Some("Plug it in by USB; it's found whenever it's connected, without a restart".into()),
+        ),
+    });
+    result
+}
+
+fn checks(address: &str, tcp_port: u16) -> Vec<Check> {
+    let mut checks = vec![static_files(), port(address, tcp_port)];
+    checks.extend(serial());
+    checks
+}
+
+/// Run every check, print the results, and keep them. Returns false if the server can't
+/// run.
+pub fn run(address: &str, tcp_port: u16) -> bool {
+    let checks = checks(address, tcp_port);
+
+    println!("Startup checks:");
+    for c in checks.iter() {
+        let outcome = match c.outcome {
+            Outcome::Pass => "ok  ",
+            Outcome::Warn => "WARN",
+            Outcome::Fail => "FAIL",
+        };
+        println!("    {}  {:<14} {}", outcome, c.name, c.detail);
+        if let Some(fix) = &c.fix {
+            println!("          {:<14} Fix: {}", "", fix);
+        }
+    }
+    println!();
+
+    let ok = checks.iter().all(|c| c.outcome != Outcome::Fail);
+    *RESULTS.lock().unwrap() = Some(Report {
+        checked: history::format_time(Utc::now().timestamp_millis()),
+        checks,
+    });
+    ok
+}
+
+/// The results of the checks at startup, as JSON; or if the server hasn't run them, of
+/// running them now, for a support bundle.
+pub fn results(address: &str, tcp_port: u16) -> String {
+    let kept = RESULTS.lock().unwrap().clone();
+    let report = kept.unwrap_or_else(|| Report {
+        checked: history::format_time(Utc::now().timestamp_millis()),
+        checks: checks(address, tcp_port),
+    });
+    serde_json::to_string_pretty(&report).unwrap()
+}
+
+/// The results of the checks at startup.
+#[get("/selfcheck")]
+pub fn view_selfcheck() -> content::Json<String> {
+    content::Json(serde_json::to_string(&*RESULTS.lock().unwrap()).unwrap())
+}
@@ -0,0 +1,188 @@
+//! A self-test of the readings encoding, and of the serial link if the Water Monitor is
+//! connected. Catches byte order and offset mistakes in `Readings::{from_bytes,
+//! to_bytes}`. The rest of the app's checks are its unit tests.
+
+use std::time::Duration;
+
+use rocket::response::content;
+use serde::Serialize;
+
+use crate::{auth::Admin, poller, registry, Readings, SensorError};
+
+/// Values that tend to expose byte order and offset mistakes.
+const VALUES: [f32; 10] = [
+    0.,
+    -0.,
+    1.5,
+    -40.,
+    7.25,
+    1_000_000.,
+    f32::MIN_POSITIVE,
+    f32::MAX,
+    f32::INFINITY,
+    f32::NAN,
+];
+
+/// Longer than a polling cycle, including its timeouts.
+const READ_WAIT: Duration = Duration::from_secs(2);
+
+#[derive(Serialize)]
+struct RoundTrip {
+    cases: usize,
+    /// Descriptions of the cases that didn't survive encoding and decoding.
+    failures: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct Link {
+    /// If a readings request finished while we waited.
+    responded: bool,
+    ok: bool,
+    latency_ms: Option<f32>,
+}
+
+#[derive(Serialize)]
+struct SelfTestReport {
+    pass: bool,
+    round_trip: RoundTrip,
+    /// `None` if the Water Monitor isn't connected.
+    link: Option<Link>,
+}
+
+/// Bitwise, so NaNs and signed zeros compare as sent. Errors decode from their status
+/// byte, so those the firmware doesn't send, like `NotConnected`, decode as
+/// `BadMeasurement`.
+fn same(sent: &Result<f32, SensorError>, got: &Result<f32, SensorError>) -> bool {
+    match (sent, got) {
+        (Ok(a), Ok(b)) => a.to_bits() == b.to_bits(),
+        (Err(a), Err(b)) => SensorError::from_status(a.status()) == *b,
+        _ => false,
+    }
+}
+
+/// The required sensors, in registry order, and dissolved oxygen.
+fn readings(
+    required: &[Result<f32, SensorError>],
+    oxygen: Option<Result<f32, SensorError>>,
+) -> Readings {
+    let mut result = Readings::empty();
+    for (id, r) in registry::ids().zip(required.iter()) {
+        result.set(id, Some(*r));
+    }
+    result.set("DO", oxygen);
+    result
+}
+
+fn round_trip() -> RoundTrip {
+    let mut cases = Vec::new();
+    // Each value in each position, with the others different, to catch mixed-up offsets.
+    for (i, v) in VALUES.iter().enumerate() {
+        let next = |n: usize| Ok(VALUES[(i + n) % VALUES.len()]);
+        cases.push(readings(&[Ok(*v), next(1), next(2), next(3)], None));
+        cases.push(readings(
+            &[
+                next(3),
+                Ok(*v),
+                Err(SensorError::BadMeasurement),
+                Err(SensorError::NotConnected),
+            ],
+            Some(next(1)),
+        ));
+        cases.push(readings(
+            &[next(1), next(2), next(3), next(4)],
+            Some(Ok(*v)),
+        ));
+    }
+    cases.push(Readings::default());
+    let mut r = Readings::default();
+    r.set("DO", Some(Err(SensorError::BadMeasurement)));
+    cases.push(r);
+    cases.push(readings(
+        &[
+            Err(SensorError::ProbeDisconnected),
+            Err(SensorError::OutOfRange),
+            Err(SensorError::NotStabilized),
+            Err(SensorError::FrontendFault),
+        ],
+        Some(Err(SensorError::Unknown(9))),
+    ));
+
+    let failures = cases
+        .iter()
+        .filter_map(|sent| {
+            let bytes = sent.to_bytes();
+            let got = Readings::from_bytes(&bytes);
+            let ok = registry::ids().all(|id| match (sent.get(id), got.get(id)) {
+                (Some(a), Some(b)) => same(&a, &b),
+                (None, None) => true,
+                _ => false,
+            });
+            if ok {
+                None
+            } else {
+                Some(format!(
+                    "Sent {:?}; encoded as {:?}; decoded as {:?}",
+                    sent, bytes, got
+                ))
+            }
+        })
+        .collect();
+
+    RoundTrip {
+        cases: cases.len(),
+        failures,
+    }
+}
+
+fn link() -> Option<Link> {
+    if !poller::status().connected {
+        return None;
+    }
+
+    Some(match poller::next_read(READ_WAIT) {
+        Some(read) => Link {
+            responded: true,
+            ok: read.ok,
+            latency_ms: Some(read.duration.as_secs_f32() * 1_000.),
+        },
+        None => Link {
+            responded: false,
+            ok: false,
+            latency_ms: None,
+        },
+    })
+}
+
+/// Round-trip readings through the encoder and decoder, and time the poller's next
+/// request to the Water Monitor, if it's connected.
+#[post("/debug/selftest")]
+pub fn run_selftest(_admin: Admin) -> content::Json<String> {
+    let round_trip = round_trip();
+    let link = link();
+    let pass = round_trip.failures.is_empty() && link.as_ref().is_none_or(|l| l.ok);
+
+    content::Json(
+        serde_json::to_string(&SelfTestReport {
+            pass,
+            round_trip,
+            link,
+        })
+        .unwrap(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn readings_round_trip() {
+        let round_trip = round_trip();
+        assert_eq!(round_trip.cases, 33);
+        assert!(
+            round_trip.failures.is_empty(),
+            "{}",
+            round_trip.failures.join("\n")
+        );
+    }
+}
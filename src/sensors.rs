@@ -0,0 +1,119 @@
+//! What each sensor measures, and in what units, for clients to label readings with.
+//! Dissolved oxygen is listed even if the hardware doesn't have it; its readings don't.
+//! Also the EC probe's cell constant: the Water Monitor can't be told it, so we scale
+//! its conductivity here, keeping the unscaled value for `/api/sensors`.
+
+use std::{io::Read, sync::Mutex};
+
+use rocket::{http::Status, response::content, Data};
+use serde::Serialize;
+
+use crate::{
+    api::{self, ErrorResponse},
+    auth::Admin,
+    config::{self, CellConstant, EcConfig},
+    registry::{Kind, REGISTRY},
+    tokens::Viewer,
+    Readings, SensorError,
+};
+
+/// The latest conductivity, before scaling for the probe.
+static RAW_EC: Mutex<Option<Result<f32, SensorError>>> = Mutex::new(None);
+
+#[derive(Serialize)]
+struct Sensor {
+    name: &'static str,
+    kind: Kind,
+    unit: &'static str,
+    /// The unit to show values in; multiply them by `display_scale` first.
+    display_unit: &'static str,
+    display_scale: f32,
+    plausible: [f32; 2],
+    target: Option<[f32; 2]>,
+    /// The EC probe's cell constant.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    probe: Option<CellConstant>,
+    /// The latest conductivity as the Water Monitor reported it, before scaling for the
+    /// probe.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    raw: Option<Result<f32, SensorError>>,
+}
+
+/// Scale the conductivity in readings from the Water Monitor for the probe.
+pub fn scale(readings: &mut Readings, probe: CellConstant) {
+    let ec = readings.get("ec").map(|r| r.map(|v| v * probe.factor()));
+    readings.set("ec", ec);
+}
+
+/// Scale the poller's latest readings, keeping the unscaled conductivity.
+pub fn apply(readings: &mut Readings) {
+    *RAW_EC.lock().unwrap() = readings.get("ec");
+    scale(readings, config::get().ec.probe);
+}
+
+/// Each sensor's units, plausible range and target.
+#[get("/sensors")]
+pub fn view_sensors(_viewer: Viewer) -> content::Json<String> {
+    let config = config::get();
+    let probe = config.ec.probe;
+
+    let sensors: Vec<_> = REGISTRY
+        .iter()
+        .map(|sensor| {
+            let name = sensor.id;
+            let ec = name == "ec";
+            Sensor {
+                name,
+                kind: sensor.kind,
+                unit: sensor.unit,
+                display_unit: if ec {
+                    probe.display_unit()
+                } else {
+                    sensor.unit
+                },
+                display_scale: if ec { probe.display_scale() } else { 1. },
+                plausible: config.history.plausible.get(name, probe),
+                target: config.status.targets.get(name, probe),
+                probe: if ec { Some(probe) } else { None },
+                raw: if ec { *RAW_EC.lock().unwrap() } else { None },
+            }
+        })
+        .collect();
+
+    content::Json(serde_json::to_string(&sensors).unwrap())
+}
+
+/// The active `[ec]` settings.
+#[get("/sensors/ec")]
+pub fn view_ec() -> content::Json<String> {
+    content::Json(serde_json::to_string(&config::get().ec).unwrap())
+}
+
+/// Replace the `[ec]` settings, in the config file too. Takes effect from the next
+/// reading; stored history isn't rescaled.
+#[put("/sensors/ec", data = "<data>")]
+pub fn set_ec(_admin: Admin, data: Data) -> Result<content::Json<String>, ErrorResponse> {
+    let mut body = String::new();
+    data.open()
+        .take(1_024)
+        .read_to_string(&mut body)
+        .map_err(|e| api::error(Status::BadRequest, &e.to_string()))?;
+    let ec: EcConfig = serde_json::from_str(&body).map_err(|e| {
+        api::error(
+            Status::BadRequest,
+            &format!(
+                "Expected eg `{{\"probe\": \"k10\"}}`, with `k0.1`, `k1` or `k10`: {}",
+                e
+            ),
+        )
+    })?;
+
+    let config = config::set_section("ec", &ec).map_err(|e| {
+        api::error(
+            Status::InternalServerError,
+            &format!("Problem saving the config: {}", e),
+        )
+    })?;
+
+    Ok(content::Json(serde_json::to_string(&config.ec).unwrap()))
+}
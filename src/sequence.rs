@@ -0,0 +1,130 @@
+//! Sequence numbers for readings, so consumers can order them, drop duplicates, and
+//! spot losses. Each successful reading gets the next number, and it goes out with the
+//! instance ID wherever the reading does: the readings response, and exporters' `seq`
+//! and `device_id` placeholders. Numbers are reserved in `sequence`, in the working
+//! directory, a block at a time, so they keep increasing across restarts without a
+//! write per reading; a restart can skip the rest of a block, so a jump right after one
+//! isn't a loss.
+
+use std::{fs, io, sync::Mutex};
+
+use crate::events::{self, Severity};
+
+pub const PATH: &str = "sequence";
+
+/// Numbers reserved per write.
+const BLOCK: u64 = 1_000;
+
+static STATE: Mutex<State> = Mutex::new(State::new(1));
+
+struct State {
+    next: u64,
+    /// Numbers below this are covered by the file.
+    reserved: u64,
+    /// If the last reservation failed; we warn once per run of failures.
+    failing: bool,
+}
+
+impl State {
+    const fn new(next: u64) -> Self {
+        Self {
+            next,
+            reserved: next,
+            failing: false,
+        }
+    }
+
+    /// The next number, reserving a block in `path` first if it's needed.
+    fn take(&mut self, path: &str) -> u64 {
+        if self.next >= self.reserved {
+            let reserved = self.next + BLOCK;
+            match save(path, reserved) {
+                Ok(()) => {
+                    self.reserved = reserved;
+                    self.failing = false;
+                }
+                Err(e) => {
+                    if !self.failing {
+                        events::record(
+                            Severity::Warning,
+                            "sequence",
+                            format!(
+                                "Problem saving the sequence number to `{}`: {}. After a \
+                                 restart, numbers may repeat.",
+                                path, e
+                            ),
+                        );
+                    }
+                    self.failing = true;
+                }
+            }
+        }
+        let seq = self.next;
+        self.next += 1;
+        seq
+    }
+}
+
+fn save(path: &str, reserved: u64) -> Result<(), io::Error> {
+    let tmp = format!("{}.tmp", path);
+    fs::write(&tmp, format!("{}\n", reserved))?;
+    fs::rename(&tmp, path)
+}
+
+/// Where the last run left off: the number after its last reservation, or 1.
+fn load(path: &str) -> Result<u64, io::Error> {
+    match fs::read_to_string(path) {
+        Ok(s) => s.trim().parse().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "`{}` isn't a sequence number; fix it, or delete it to start from 1, if \
+                     no consumer will mistake the restart for duplicates",
+                    path
+                ),
+            )
+        }),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(1),
+        Err(e) => Err(e),
+    }
+}
+
+/// Continue from the last reservation. Call at startup.
+pub fn init() -> Result<(), io::Error> {
+    *STATE.lock().unwrap() = State::new(load(PATH)?);
+    Ok(())
+}
+
+/// The next number, for a successful reading, which is now the latest. Called by the
+/// poller.
+pub fn next() -> u64 {
+    STATE.lock().unwrap().take(PATH)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    /// Numbers keep increasing across restarts, and a restart skips the rest of its
+    /// block.
+    #[test]
+    fn numbers_increase_across_restarts() {
+        let path = env::temp_dir().join(format!("water-mon-{}-sequence", process::id()));
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+
+        let mut run = State::new(load(path).unwrap());
+        let first: Vec<_> = (0..3).map(|_| run.take(path)).collect();
+        assert_eq!(first, [1, 2, 3]);
+
+        let mut run = State::new(load(path).unwrap());
+        assert_eq!(run.take(path), 1 + BLOCK);
+        assert_eq!(run.take(path), 2 + BLOCK);
+
+        fs::write(path, "seven").unwrap();
+        assert_eq!(load(path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,315 @@
+//! Timing and outcome of each serial transaction with the Water Monitor, for diagnosing
+//! flaky cables and hubs.
+
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use rocket::response::content;
+use serde::Serialize;
+
+use crate::{
+    auth::Admin,
+    memory::{Buffer, Policy},
+};
+
+/// Transactions older than this drop out of the rolling window.
+const WINDOW: Duration = Duration::from_secs(15 * 60);
+
+/// Cap on the rolling window, in case we're polled much faster than expected.
+const MAX_WINDOW_LEN: usize = 20_000;
+
+/// Readings exchanges kept, bytes and all, for support bundles.
+const MAX_FRAMES: usize = 10;
+
+pub const WINDOW_BUFFER: Buffer = Buffer {
+    name: "serial.window",
+    cap: MAX_WINDOW_LEN,
+    policy: Policy::DropOldest,
+    about: "Serial transactions over the last 15 minutes, for latency and success rate",
+};
+
+pub const FRAMES_BUFFER: Buffer = Buffer {
+    name: "serial.frames",
+    cap: MAX_FRAMES,
+    policy: Policy::DropOldest,
+    about: "The last readings exchanges' bytes, for support bundles",
+};
+
+static STATS: Mutex<Stats> = Mutex::new(Stats::new());
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum Outcome {
+    Ok,
+    /// No response, or an incomplete one, before the port's timeout.
+    Timeout,
+    /// The response failed its CRC check.
+    CrcFailure,
+    /// Any other IO error.
+    IoError,
+}
+
+struct Transaction {
+    time: Instant,
+    duration: Duration,
+    outcome: Outcome,
+}
+
+/// A readings request and the response to it, as sent and received.
+#[derive(Clone, Serialize)]
+pub struct Frame {
+    time: String,
+    outcome: Outcome,
+    /// Hex.
+    request: String,
+    /// Hex; as much as arrived.
+    response: String,
+}
+
+struct Stats {
+    /// When these stats were last reset.
+    since: Option<Instant>,
+    window: VecDeque<Transaction>,
+    frames: VecDeque<Frame>,
+    // Counts since the last reset.
+    transactions: u64,
+    bytes_written: u64,
+    bytes_read: u64,
+    timeouts: u64,
+    crc_failures: u64,
+    io_errors: u64,
+    resyncs: u64,
+    resync_bytes_skipped: u64,
+    /// Requests waiting for the flight controller now, and the most since the reset.
+    queue_depth: usize,
+    queue_max_depth: usize,
+    queue_rejected: u64,
+    queue_expired: u64,
+}
+
+impl Stats {
+    const fn new() -> Self {
+        Self {
+            since: None,
+            window: VecDeque::new(),
+            frames: VecDeque::new(),
+            transactions: 0,
+            bytes_written: 0,
+            bytes_read: 0,
+            timeouts: 0,
+            crc_failures: 0,
+            io_errors: 0,
+            resyncs: 0,
+            resync_bytes_skipped: 0,
+            queue_depth: 0,
+            queue_max_depth: 0,
+            queue_rejected: 0,
+            queue_expired: 0,
+        }
+    }
+
+    fn prune(&mut self, now: Instant) {
+        while let Some(t) = self.window.front() {
+            if now - t.time > WINDOW || self.window.len() > MAX_WINDOW_LEN {
+                self.window.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+}
+
+/// Record a completed (or failed) transaction.
+pub fn record(duration: Duration, bytes_written: usize, bytes_read: usize, outcome: Outcome) {
+    let now = Instant::now();
+    let mut stats = STATS.lock().unwrap();
+
+    if stats.since.is_none() {
+        stats.since = Some(now);
+    }
+
+    stats.transactions += 1;
+    stats.bytes_written += bytes_written as u64;
+    stats.bytes_read += bytes_read as u64;
+    match outcome {
+        Outcome::Ok => (),
+        Outcome::Timeout => stats.timeouts += 1,
+        Outcome::CrcFailure => stats.crc_failures += 1,
+        Outcome::IoError => stats.io_errors += 1,
+    }
+
+    if stats.window.len() == MAX_WINDOW_LEN {
+        stats.window.pop_front();
+        WINDOW_BUFFER.dropped(1);
+    }
+    stats.window.push_back(Transaction {
+        time: now,
+        duration,
+        outcome,
+    });
+    stats.prune(now);
+    WINDOW_BUFFER.note(stats.window.len());
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Keep a readings exchange's bytes, dropping the oldest past `MAX_FRAMES`.
+pub fn record_frame(request: &[u8], response: &[u8], outcome: Outcome) {
+    let mut stats = STATS.lock().unwrap();
+    if stats.frames.len() == MAX_FRAMES {
+        stats.frames.pop_front();
+    }
+    stats.frames.push_back(Frame {
+        time: Utc::now().to_rfc3339(),
+        outcome,
+        request: hex(request),
+        response: hex(response),
+    });
+    FRAMES_BUFFER.note(stats.frames.len());
+}
+
+/// The last readings exchanges, oldest first.
+pub fn frames() -> Vec<Frame> {
+    STATS.lock().unwrap().frames.iter().cloned().collect()
+}
+
+/// Record losing the boundary between responses `resyncs` times, and the bytes skipped
+/// to find it again.
+pub fn record_resyncs(resyncs: u64, bytes_skipped: u64) {
+    if resyncs == 0 && bytes_skipped == 0 {
+        return;
+    }
+    let mut stats = STATS.lock().unwrap();
+    stats.resyncs += resyncs;
+    stats.resync_bytes_skipped += bytes_skipped;
+}
+
+/// Record how many requests are queued for the flight controller.
+#[cfg(feature = "flight-controller")]
+pub fn record_queue_depth(depth: usize) {
+    let mut stats = STATS.lock().unwrap();
+    stats.queue_depth = depth;
+    stats.queue_max_depth = stats.queue_max_depth.max(depth);
+}
+
+/// Record refusing a request because the queue was full.
+#[cfg(feature = "flight-controller")]
+pub fn record_queue_rejected() {
+    STATS.lock().unwrap().queue_rejected += 1;
+}
+
+/// Record dropping a request its caller stopped waiting for before it was sent.
+#[cfg(feature = "flight-controller")]
+pub fn record_queue_expired() {
+    STATS.lock().unwrap().queue_expired += 1;
+}
+
+/// Clear all stats, eg before and after swapping a cable. The queue's depth is kept.
+pub fn reset() {
+    let mut stats = STATS.lock().unwrap();
+    let depth = stats.queue_depth;
+    *stats = Stats::new();
+    stats.queue_depth = depth;
+    WINDOW_BUFFER.note(0);
+    FRAMES_BUFFER.note(0);
+}
+
+#[derive(Serialize)]
+pub struct SerialReport {
+    /// Seconds since the stats were reset, or since the first transaction.
+    pub seconds_recorded: f32,
+    pub transactions: u64,
+    pub bytes_written: u64,
+    pub bytes_read: u64,
+    pub timeouts: u64,
+    pub crc_failures: u64,
+    pub io_errors: u64,
+    /// Times the response stream got out of step, eg from a dropped byte.
+    pub resyncs: u64,
+    /// Bytes discarded to get back in step.
+    pub resync_bytes_skipped: u64,
+    /// Requests queued for the flight controller now, and the most queued at once.
+    pub queue_depth: usize,
+    pub queue_max_depth: usize,
+    /// Requests refused because the queue was full.
+    pub queue_rejected: u64,
+    /// Requests dropped unsent, since their callers had stopped waiting.
+    pub queue_expired: u64,
+    /// Transactions in the last 15 minutes.
+    pub window_transactions: usize,
+    /// Fraction of transactions in the last 15 minutes that succeeded.
+    pub window_success_rate: Option<f32>,
+    /// Transaction latency percentiles over the last 15 minutes, in ms.
+    pub latency_p50_ms: Option<f32>,
+    pub latency_p95_ms: Option<f32>,
+    pub latency_p99_ms: Option<f32>,
+}
+
+/// Nearest-rank percentile of sorted values.
+fn percentile(sorted: &[Duration], p: f32) -> Option<f32> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = ((p / 100. * sorted.len() as f32).ceil() as usize).clamp(1, sorted.len());
+    Some(sorted[rank - 1].as_secs_f32() * 1_000.)
+}
+
+pub fn report() -> SerialReport {
+    let now = Instant::now();
+    let mut stats = STATS.lock().unwrap();
+    stats.prune(now);
+
+    let mut durations: Vec<Duration> = stats.window.iter().map(|t| t.duration).collect();
+    durations.sort();
+
+    let successes = stats
+        .window
+        .iter()
+        .filter(|t| t.outcome == Outcome::Ok)
+        .count();
+
+    SerialReport {
+        seconds_recorded: stats.since.map(|s| (now - s).as_secs_f32()).unwrap_or(0.),
+        transactions: stats.transactions,
+        bytes_written: stats.bytes_written,
+        bytes_read: stats.bytes_read,
+        timeouts: stats.timeouts,
+        crc_failures: stats.crc_failures,
+        io_errors: stats.io_errors,
+        resyncs: stats.resyncs,
+        resync_bytes_skipped: stats.resync_bytes_skipped,
+        queue_depth: stats.queue_depth,
+        queue_max_depth: stats.queue_max_depth,
+        queue_rejected: stats.queue_rejected,
+        queue_expired: stats.queue_expired,
+        window_transactions: stats.window.len(),
+        window_success_rate: if stats.window.is_empty() {
+            None
+        } else {
+            Some(successes as f32 / stats.window.len() as f32)
+        },
+        latency_p50_ms: percentile(&durations, 50.),
+        latency_p95_ms: percentile(&durations, 95.),
+        latency_p99_ms: percentile(&durations, 99.),
+    }
+}
+
+#[get("/debug/serial")]
+pub fn view_serial_stats() -> content::Json<String> {
+    content::Json(serde_json::to_string(&report()).unwrap())
+}
+
+#[post("/debug/serial/reset")]
+pub fn reset_serial_stats(_admin: Admin) -> content::Json<String> {
+    reset();
+    content::Json(serde_json::to_string(&report()).unwrap())
+}
@@ -0,0 +1,224 @@
+//! Traces of a poll cycle's serial transactions, for
+//! `POST /api/readings/refresh?debug=true`: when a refresh gives an odd value, what went
+//! over the wire for it. The route arms a trace; the poller's next cycle records each
+//! readings transaction into it, with its bytes, timings, and how each sensor's slot
+//! decoded; and the route takes it once the cycle's done. Nothing's recorded, or timed
+//! apart, unless a trace is armed. Readings responses have no CRC, so there's no check
+//! to report; the bytes are there instead.
+
+use std::{
+    mem,
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::{
+    memory::{Buffer, Policy},
+    registry::REGISTRY,
+    serial_stats::{self, Outcome},
+    Readings, SensorError,
+};
+
+/// Transactions per trace; more are counted, not kept.
+const MAX_TRANSACTIONS: usize = 32;
+
+/// Response bytes kept per transaction.
+const MAX_BYTES: usize = 256;
+
+pub const BUFFER: Buffer = Buffer {
+    name: "serial_trace.transactions",
+    cap: MAX_TRANSACTIONS,
+    policy: Policy::DropNewest,
+    about: "A traced poll cycle's transactions, counted in `omitted` past the cap",
+};
+
+static STATE: Mutex<State> = Mutex::new(State::Idle);
+
+enum State {
+    Idle,
+    /// Waiting for the next cycle to start.
+    Armed,
+    Recording(Trace),
+    Done(Trace),
+}
+
+/// How long each part of a transaction took.
+#[derive(Clone, Copy, Default)]
+pub struct Timings {
+    pub write: Duration,
+    /// Waiting for the request to be sent.
+    pub flush: Duration,
+    /// Waiting for, and reading, the response.
+    pub read: Duration,
+}
+
+#[derive(Serialize)]
+pub struct Trace {
+    transactions: Vec<Transaction>,
+    /// Transactions past the limit, left out.
+    omitted: usize,
+    /// Why the cycle failed, if it did.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    duration_ms: f32,
+    #[serde(skip)]
+    start: Instant,
+}
+
+#[derive(Serialize)]
+struct Transaction {
+    /// From 1. A cycle requests readings `polling.samples_per_cycle` times, and stops
+    /// at a failure once it has one.
+    attempt: usize,
+    /// Left over from an earlier response, and cleared before the request.
+    skipped_bytes: usize,
+    /// Hex.
+    request: String,
+    /// Hex; as much as arrived.
+    response: String,
+    /// If `response` was cut short.
+    truncated: bool,
+    write_us: f32,
+    flush_us: f32,
+    read_us: f32,
+    outcome: Outcome,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    /// Each sensor's slot, as decoded, for a complete response.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    fields: Vec<Field>,
+}
+
+#[derive(Serialize)]
+struct Field {
+    sensor: &'static str,
+    offset: usize,
+    /// Hex: a status byte, then a big-endian f32.
+    bytes: String,
+    value: Result<f32, SensorError>,
+}
+
+fn micros(d: Duration) -> f32 {
+    d.as_secs_f32() * 1_000_000.
+}
+
+/// How each sensor's slot in a response decodes.
+fn fields(response: &[u8]) -> Vec<Field> {
+    let readings = Readings::from_bytes(response);
+    REGISTRY
+        .iter()
+        .filter_map(|sensor| {
+            let offset = sensor.offset?;
+            let slot = response.get(offset..offset + 5)?;
+            Some(Field {
+                sensor: sensor.id,
+                offset,
+                bytes: serial_stats::hex(slot),
+                value: readings.get(sensor.id)?,
+            })
+        })
+        .collect()
+}
+
+/// Arm a trace of the next poll cycle. False if one's already armed.
+pub fn arm() -> bool {
+    let mut state = STATE.lock().unwrap();
+    if !matches!(*state, State::Idle) {
+        return false;
+    }
+    *state = State::Armed;
+    true
+}
+
+/// Start recording, if a trace is armed. Called by the poller as a cycle starts.
+pub fn begin() {
+    let mut state = STATE.lock().unwrap();
+    if matches!(*state, State::Armed) {
+        *state = State::Recording(Trace {
+            transactions: Vec::new(),
+            omitted: 0,
+            error: None,
+            duration_ms: 0.,
+            start: Instant::now(),
+        });
+        BUFFER.note(0);
+    }
+}
+
+/// If this cycle's transactions are being traced.
+pub fn recording() -> bool {
+    matches!(*STATE.lock().unwrap(), State::Recording(_))
+}
+
+/// Add a readings transaction to the trace being recorded.
+pub fn record(
+    skipped_bytes: usize,
+    request: &[u8],
+    response: &[u8],
+    timings: Timings,
+    outcome: Outcome,
+    error: Option<String>,
+) {
+    let mut state = STATE.lock().unwrap();
+    let State::Recording(trace) = &mut *state else {
+        return;
+    };
+    if trace.transactions.len() == MAX_TRANSACTIONS {
+        trace.omitted += 1;
+        BUFFER.dropped(1);
+        return;
+    }
+    trace.transactions.push(Transaction {
+        attempt: trace.transactions.len() + 1,
+        skipped_bytes,
+        request: serial_stats::hex(request),
+        response: serial_stats::hex(&response[..response.len().min(MAX_BYTES)]),
+        truncated: response.len() > MAX_BYTES,
+        write_us: micros(timings.write),
+        flush_us: micros(timings.flush),
+        read_us: micros(timings.read),
+        outcome,
+        error,
+        fields: if outcome == Outcome::Ok {
+            fields(response)
+        } else {
+            Vec::new()
+        },
+    });
+    BUFFER.note(trace.transactions.len());
+}
+
+/// Finish the trace being recorded, with the cycle's error, if any. Called by the poller
+/// once the cycle's readings are published.
+pub fn finish(error: Option<String>) {
+    let mut state = STATE.lock().unwrap();
+    *state = match mem::replace(&mut *state, State::Idle) {
+        State::Recording(mut trace) => {
+            trace.error = error;
+            trace.duration_ms = trace.start.elapsed().as_secs_f32() * 1_000.;
+            State::Done(trace)
+        }
+        other => other,
+    };
+}
+
+/// Wait up to `timeout` for the armed trace's cycle to finish, and take its trace.
+/// `None`, disarming it, if the cycle didn't finish in time.
+pub fn take(timeout: Duration) -> Option<Trace> {
+    let start = Instant::now();
+    loop {
+        {
+            let mut state = STATE.lock().unwrap();
+            match mem::replace(&mut *state, State::Idle) {
+                State::Done(trace) => return Some(trace),
+                // Left idle.
+                _ if start.elapsed() >= timeout => return None,
+                other => *state = other,
+            }
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
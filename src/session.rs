@@ -0,0 +1,312 @@
+//! Logging in with a password, as an alternative to the admin token, eg for a household
+//! sharing the web page. The password is kept as an argon2 hash, in
+//! `auth.password_hash`, set with `water-mon-app set-password`. Logging in sets an
+//! HttpOnly cookie holding the time of the session's last request, encrypted and signed
+//! with `auth.session_key`, so sessions survive restarts without a session store. Each
+//! request renews it; one idle for longer than `auth.session_idle_mins` has expired.
+//! Setting a new password makes a new key, which ends every session.
+
+use std::io::{self, BufRead, Read, Write};
+
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use chrono::Utc;
+use rocket::{
+    http::{Cookie, Cookies, SameSite, Status},
+    request::{self, FromRequest},
+    response::content,
+    Data, Outcome, Request,
+};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    api::{self, ErrorResponse},
+    config::{self, AuthConfig},
+};
+
+pub const COOKIE: &str = "session";
+
+/// Clocks on the same machine; allows for small adjustments.
+const MAX_CLOCK_SKEW_SECS: i64 = 60;
+
+#[derive(Deserialize)]
+struct Login {
+    password: String,
+}
+
+/// An argon2 hash of `password`, for `auth.password_hash`.
+pub fn hash(password: &str) -> Result<String, String> {
+    let salt = SaltString::encode_b64(&rand::random::<[u8; 16]>()).map_err(|e| e.to_string())?;
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|h| h.to_string())
+        .map_err(|e| e.to_string())
+}
+
+fn verify(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|h| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &h)
+            .is_ok()
+    })
+}
+
+/// A new key for session cookies, base64-encoded, as Rocket takes it.
+pub fn new_key() -> String {
+    base64::encode(rand::random::<[u8; 32]>())
+}
+
+/// If logging in with a password is set up.
+pub fn enabled(cfg: &AuthConfig) -> bool {
+    cfg.password_hash.is_some()
+}
+
+/// Check the password settings at startup.
+pub fn check(cfg: &AuthConfig) -> Result<(), io::Error> {
+    let invalid = |msg: &str| {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Config error: {}", msg),
+        ))
+    };
+
+    if let Some(hash) = &cfg.password_hash {
+        if PasswordHash::new(hash).is_err() {
+            return invalid(
+                "`auth.password_hash` isn't an argon2 hash; set it with \
+                 `water-mon-app set-password`",
+            );
+        }
+        if cfg.session_key.is_none() {
+            return invalid(
+                "`auth.password_hash` needs `auth.session_key`; set both with \
+                 `water-mon-app set-password`",
+            );
+        }
+    }
+    Ok(())
+}
+
+fn cookie(last_request: i64) -> Cookie<'static> {
+    Cookie::build(COOKIE, last_request.to_string())
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        // The app is usually served over plain HTTP, on the LAN.
+        .secure(false)
+        .finish()
+}
+
+/// If the request has a session that hasn't expired. Renews it.
+pub fn valid(req: &Request) -> bool {
+    let cfg = config::get().auth;
+    if !enabled(&cfg) {
+        return false;
+    }
+
+    let mut cookies = req.cookies();
+    let last_request: i64 = match cookies
+        .get_private(COOKIE)
+        .and_then(|c| c.value().parse().ok())
+    {
+        Some(t) => t,
+        None => return false,
+    };
+    let now = Utc::now().timestamp();
+    if now - last_request > cfg.session_idle_mins as i64 * 60
+        || last_request > now + MAX_CLOCK_SKEW_SECS
+    {
+        cookies.remove_private(Cookie::named(COOKIE));
+        return false;
+    }
+
+    cookies.add_private(cookie(now));
+    true
+}
+
+/// Start a session, given `{"password": ...}`.
+#[post("/login", data = "<data>")]
+pub fn login(mut cookies: Cookies, data: Data) -> Result<content::Json<String>, ErrorResponse> {
+    let cfg = config::get().auth;
+    let hash = cfg.password_hash.ok_or_else(|| {
+        api::error(
+            Status::Forbidden,
+            "Logging in is disabled until a password is set, with `water-mon-app set-password`",
+        )
+    })?;
+
+    let mut body = String::new();
+    data.open()
+        .take(4 * 1_024)
+        .read_to_string(&mut body)
+        .map_err(|e| api::error(Status::BadRequest, &e.to_string()))?;
+    let login: Login = serde_json::from_str(&body)
+        .map_err(|e| api::error(Status::BadRequest, &format!("Invalid login: {}", e)))?;
+
+    if !verify(&login.password, &hash) {
+        return Err(api::error(Status::Unauthorized, "Wrong password"));
+    }
+    cookies.add_private(cookie(Utc::now().timestamp()));
+
+    Ok(content::Json(json!({ "logged_in": true }).to_string()))
+}
+
+/// End the session, if any.
+#[post("/logout")]
+pub fn logout(mut cookies: Cookies) -> content::Json<String> {
+    cookies.remove_private(Cookie::named(COOKIE));
+    content::Json(json!({ "logged_in": false }).to_string())
+}
+
+/// A request guard for whether the request has a session; never fails.
+pub struct Session(bool);
+
+impl<'a, 'r> FromRequest<'a, 'r> for Session {
+    type Error = ();
+
+    fn from_request(req: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        Outcome::Success(Session(valid(req)))
+    }
+}
+
+/// If logging in is set up, and whether this request has a session.
+#[get("/session")]
+pub fn view_session(session: Session) -> content::Json<String> {
+    let cfg = config::get().auth;
+    content::Json(
+        json!({
+            "password_set": enabled(&cfg),
+            "logged_in": session.0,
+            "idle_mins": cfg.session_idle_mins,
+        })
+        .to_string(),
+    )
+}
+
+/// Turns off echo on stdin until dropped, if it's a terminal.
+#[cfg(unix)]
+struct NoEcho(Option<libc::termios>);
+
+#[cfg(unix)]
+impl NoEcho {
+    fn new() -> Self {
+        use std::{io::IsTerminal, os::unix::io::AsRawFd};
+
+        let stdin = io::stdin();
+        if !stdin.is_terminal() {
+            return Self(None);
+        }
+        let fd = stdin.as_raw_fd();
+        unsafe {
+            let mut term: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(fd, &mut term) != 0 {
+                return Self(None);
+            }
+            let original = term;
+            // Still echo the newline, so the next prompt starts on its own line.
+            term.c_lflag &= !libc::ECHO;
+            term.c_lflag |= libc::ECHONL;
+            if libc::tcsetattr(fd, libc::TCSANOW, &term) != 0 {
+                return Self(None);
+            }
+            Self(Some(original))
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for NoEcho {
+    fn drop(&mut self) {
+        use std::os::unix::io::AsRawFd;
+
+        if let Some(original) = &self.0 {
+            unsafe {
+                libc::tcsetattr(io::stdin().as_raw_fd(), libc::TCSANOW, original);
+            }
+        }
+    }
+}
+
+/// `water-mon-app set-password`: read a new password from stdin, and save its hash,
+/// and a new session key, to the config file. From a terminal, it isn't echoed, on
+/// Unix; piped in, it's the first two lines.
+pub fn set_password() -> Result<(), io::Error> {
+    let stdin = io::stdin();
+    #[cfg(unix)]
+    let _no_echo = NoEcho::new();
+    let mut lines = stdin.lock().lines();
+    let mut prompt = |msg: &str| -> Result<String, io::Error> {
+        eprint!("{}", msg);
+        io::stderr().flush()?;
+        lines
+            .next()
+            .unwrap_or_else(|| Err(io::ErrorKind::UnexpectedEof.into()))
+    };
+
+    let password = prompt("New password: ")?;
+    if password.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The password can't be empty",
+        ));
+    }
+    if prompt("Again: ")? != password {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The passwords don't match",
+        ));
+    }
+
+    let mut auth = config::get().auth;
+    auth.password_hash =
+        Some(hash(&password).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?);
+    auth.session_key = Some(new_key());
+    config::set_section("auth", &auth)?;
+
+    eprintln!(
+        "Saved to `{}`; restart the app for it to take effect. Any sessions have ended.",
+        config::CONFIG_PATH
+    );
+    Ok(())
+}
+
+/// A page for logging in, for before the web page has its own.
+#[get("/login")]
+pub fn view_login_page() -> content::Html<&'static str> {
+    content::Html(LOGIN_PAGE)
+}
+
+const LOGIN_PAGE: &str = r##"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="UTF-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1">
+    <title>Log in - AnyLeaf Water Monitor</title>
+</head>
+<body>
+    <form id="login">
+        <label>Password <input type="password" name="password" autofocus></label>
+        <button type="submit">Log in</button>
+        <p id="error"></p>
+    </form>
+    <script>
+        document.getElementById("login").addEventListener("submit", async e => {
+            e.preventDefault();
+            const response = await fetch("/api/login", {
+                method: "POST",
+                headers: { "Content-Type": "application/json" },
+                body: JSON.stringify({ password: e.target.password.value }),
+            });
+            if (response.ok) {
+                window.location = "/";
+            } else {
+                document.getElementById("error").textContent = (await response.json()).error;
+            }
+        });
+    </script>
+</body>
+</html>
+"##;
@@ -0,0 +1,244 @@
+//! First-run setup. With no config file, the app starts in setup mode: it runs with the
+//! defaults, and `/api/setup` lets the web page, or curl, pick the Water Monitor's
+//! serial port from those found, the locale, for units, a profile of target ranges,
+//! and a password, then writes the config file. The app carries on with it, without a
+//! restart. Once there's a config file, the app never starts in setup mode, and the
+//! setup routes 404.
+
+use std::{
+    fs,
+    io::Read,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+use rocket::{http::Status, response::content, Data};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use serialport::SerialPortType;
+
+use crate::{
+    api::{self, ErrorResponse},
+    config::{self, AppConfig, AuthConfig, LocaleConfig, SerialConfig, TargetRanges},
+    events::{self, Severity},
+    locale, session, validate,
+};
+
+const SOURCE: &str = "setup";
+
+/// Profiles of target ranges, for `profile`.
+const PROFILES: [&str; 4] = ["pool", "spa", "aquarium", "hydroponics"];
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// The session key Rocket is started with in setup mode, so a password set during
+/// setup works straight away.
+static SESSION_KEY: Mutex<Option<String>> = Mutex::new(None);
+
+#[derive(Serialize)]
+struct Port {
+    name: String,
+    /// If it's the Water Monitor, by its USB serial number.
+    water_monitor: bool,
+}
+
+#[derive(Serialize)]
+struct SetupStatus {
+    setup: bool,
+    ports: Vec<Port>,
+    profiles: [&'static str; 4],
+    /// The locale used if none is given.
+    default_locale: String,
+}
+
+#[derive(Deserialize)]
+struct Setup {
+    /// If unset, the Water Monitor is found by its USB serial number.
+    #[serde(default)]
+    port: Option<String>,
+    #[serde(default)]
+    locale: Option<String>,
+    #[serde(default)]
+    profile: Option<String>,
+    #[serde(default)]
+    password: Option<String>,
+}
+
+/// A profile's target ranges.
+fn profile(name: &str) -> Option<TargetRanges> {
+    let defaults = TargetRanges::default();
+    Some(match name {
+        "pool" => defaults,
+        "spa" => TargetRanges {
+            T: Some([36., 40.]),
+            ..defaults
+        },
+        "aquarium" => TargetRanges {
+            T: Some([24., 28.]),
+            pH: Some([6.8, 7.6]),
+            ORP: None,
+            ..defaults
+        },
+        "hydroponics" => TargetRanges {
+            T: Some([18., 24.]),
+            pH: Some([5.5, 6.5]),
+            ORP: None,
+            ec: Some([1_200., 2_400.]),
+            ..defaults
+        },
+        _ => return None,
+    })
+}
+
+/// Enter setup mode if there's no config file. Called at startup.
+pub fn detect() -> bool {
+    if Path::new(config::CONFIG_PATH).exists() {
+        return false;
+    }
+    *SESSION_KEY.lock().unwrap() = Some(session::new_key());
+    ACTIVE.store(true, Ordering::Relaxed);
+    true
+}
+
+pub fn active() -> bool {
+    ACTIVE.load(Ordering::Relaxed)
+}
+
+/// The session key for Rocket, in setup mode.
+pub fn session_key() -> Option<String> {
+    SESSION_KEY.lock().unwrap().clone()
+}
+
+fn not_in_setup() -> ErrorResponse {
+    api::error(
+        Status::NotFound,
+        "Not in setup mode; the app is already set up",
+    )
+}
+
+/// The config file's text for `setup`, and the config it gives.
+fn initial_config(setup: &Setup) -> Result<(String, AppConfig), String> {
+    let mut table = toml::value::Table::new();
+    let mut insert = |name: &str, section: Result<toml::Value, toml::ser::Error>| {
+        section
+            .map(|s| {
+                table.insert(name.into(), s);
+            })
+            .map_err(|e| e.to_string())
+    };
+
+    if let Some(port) = &setup.port {
+        if port.trim().is_empty() {
+            return Err("`port` can't be empty; leave it out to find the Water Monitor".into());
+        }
+        insert(
+            "serial",
+            toml::Value::try_from(SerialConfig {
+                port: Some(port.clone()),
+            }),
+        )?;
+    }
+    if let Some(language) = &setup.locale {
+        locale::parse(language)?;
+        insert(
+            "locale",
+            toml::Value::try_from(LocaleConfig {
+                language: language.clone(),
+            }),
+        )?;
+    }
+    if let Some(name) = &setup.profile {
+        let targets = profile(name).ok_or_else(|| {
+            format!(
+                "Unknown profile `{}`; it's one of {}",
+                name,
+                PROFILES.join(", ")
+            )
+        })?;
+        let mut status = config::get().status;
+        status.targets = targets;
+        insert("status", toml::Value::try_from(status))?;
+    }
+    if let Some(password) = &setup.password {
+        if password.is_empty() {
+            return Err("The password can't be empty; leave it out for none".into());
+        }
+        let auth = AuthConfig {
+            password_hash: Some(session::hash(password)?),
+            session_key: session_key(),
+            ..AuthConfig::default()
+        };
+        insert("auth", toml::Value::try_from(auth))?;
+    }
+
+    let text = toml::to_string(&toml::Value::Table(table)).map_err(|e| e.to_string())?;
+    let config: AppConfig = toml::from_str(&text).map_err(|e| e.to_string())?;
+    validate::check(&config).map_err(|e| e.to_string())?;
+    Ok((text, config))
+}
+
+/// If the app is in setup mode, and what it can be set up with.
+#[get("/setup/status")]
+pub fn view_setup() -> Result<content::Json<String>, ErrorResponse> {
+    if !active() {
+        return Err(not_in_setup());
+    }
+    let ports = serialport::available_ports()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|p| Port {
+            water_monitor: matches!(
+                &p.port_type,
+                SerialPortType::UsbPort(info) if info.serial_number.as_deref() == Some("WM")
+            ),
+            name: p.port_name,
+        })
+        .collect();
+    let result = SetupStatus {
+        setup: true,
+        ports,
+        profiles: PROFILES,
+        default_locale: LocaleConfig::default().language,
+    };
+    Ok(content::Json(serde_json::to_string(&result).unwrap()))
+}
+
+/// Write the config file, with the serial port, locale, profile and password, each
+/// optional, and leave setup mode. Only in setup mode, for which there's no password
+/// yet.
+#[post("/setup", data = "<data>")]
+pub fn set_up(data: Data) -> Result<content::Json<String>, ErrorResponse> {
+    if !active() {
+        return Err(not_in_setup());
+    }
+    let mut body = String::new();
+    data.open()
+        .take(16 * 1_024)
+        .read_to_string(&mut body)
+        .map_err(|e| api::error(Status::BadRequest, &e.to_string()))?;
+    let setup: Setup = serde_json::from_str(&body)
+        .map_err(|e| api::error(Status::BadRequest, &format!("Invalid setup: {}", e)))?;
+    let (text, config) = initial_config(&setup).map_err(|e| api::error(Status::BadRequest, &e))?;
+
+    // Another request may have finished setup meanwhile.
+    if !ACTIVE.swap(false, Ordering::Relaxed) || Path::new(config::CONFIG_PATH).exists() {
+        return Err(not_in_setup());
+    }
+    if let Err(e) = fs::write(config::CONFIG_PATH, text) {
+        ACTIVE.store(true, Ordering::Relaxed);
+        return Err(api::error(
+            Status::InternalServerError,
+            &format!("Problem writing the config file: {}", e),
+        ));
+    }
+    config::set(config);
+    events::record(
+        Severity::Info,
+        SOURCE,
+        format!("Setup is done; saved `{}`.", config::CONFIG_PATH),
+    );
+    Ok(content::Json(json!({ "setup": false }).to_string()))
+}
@@ -0,0 +1,89 @@
+//! Signing outbound requests, so a receiver on the public internet can check they came
+//! from this monitor. Exporters and notification channels with `sign` set send two
+//! headers: `X-Signature-Timestamp`, the Unix time in seconds, and `X-Signature`,
+//! `sha256=` and the hex HMAC-SHA256 of the timestamp, a `.` and the body, keyed with
+//! `signing.secret`. Each retry is signed afresh. Receivers should compute it over the
+//! raw body, compare in constant time, and reject timestamps more than
+//! `signing.max_age_secs` from their clock, so a captured request can't be replayed
+//! later. `POST /api/notify/verify` checks a signature, for debugging a receiver.
+
+use std::{fmt::Write, io};
+
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::{
+    auth,
+    config::{self, AppConfig},
+};
+
+pub const SIGNATURE_HEADER: &str = "X-Signature";
+pub const TIMESTAMP_HEADER: &str = "X-Signature-Timestamp";
+
+/// Check that anything signed has a secret to sign with.
+pub fn check(cfg: &AppConfig) -> Result<(), io::Error> {
+    let invalid = |msg: String| {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Config error: {}", msg),
+        ))
+    };
+    if cfg.signing.secret.as_ref().is_some_and(|s| s.is_empty()) {
+        return invalid("`signing.secret` is empty".into());
+    }
+    if cfg.signing.secret.is_none() {
+        let signed = cfg
+            .exporters
+            .iter()
+            .filter(|e| e.sign)
+            .map(|e| format!("Exporter `{}`", e.name))
+            .chain(
+                cfg.notify
+                    .channels
+                    .iter()
+                    .filter(|c| c.sign)
+                    .map(|c| format!("Notification channel `{}`", c.name)),
+            )
+            .next();
+        if let Some(signed) = signed {
+            return invalid(format!(
+                "{} is signed, but `signing.secret` isn't set",
+                signed
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// `sha256=` and the signature of `timestamp` and `body`.
+pub fn signature(secret: &str, timestamp: i64, body: &str) -> String {
+    // HMAC takes keys of any length.
+    let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes()).unwrap();
+    mac.input(format!("{}.", timestamp).as_bytes());
+    mac.input(body.as_bytes());
+    let hex = mac.result().code().iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{:02x}", b);
+        s
+    });
+    format!("sha256={}", hex)
+}
+
+/// The timestamp and signature headers for a request with `body`, signed now. `None`
+/// without a secret.
+pub fn headers(body: &str) -> Option<[(&'static str, String); 2]> {
+    let secret = config::get().signing.secret?;
+    let timestamp = Utc::now().timestamp();
+    Some([
+        (TIMESTAMP_HEADER, timestamp.to_string()),
+        (SIGNATURE_HEADER, signature(&secret, timestamp, body)),
+    ])
+}
+
+/// If `signature` is the signature of `timestamp` and `body`.
+pub fn verify(secret: &str, timestamp: i64, body: &str, signature: &str) -> bool {
+    auth::constant_time_eq(
+        self::signature(secret, timestamp, body).as_bytes(),
+        signature.trim().to_ascii_lowercase().as_bytes(),
+    )
+}
@@ -0,0 +1,587 @@
+//! A simulated Water Monitor, replaying a scripted scenario, so the chain from the serial
+//! link to alerts, notifications and history can be tested deterministically, without
+//! hardware. A scenario is a timeline of segments, each holding or ramping sensors'
+//! values, and injecting faults: sensor errors, the device unplugged, unanswered
+//! requests, and responses cut short or corrupted. While one is loaded, with
+//! `--simulate-scenario=FILE` or `POST /api/simulate/scenario`, the poller talks to a
+//! simulated serial port instead of the device, so resyncs, reconnection and the
+//! watchdog work as they would with the real thing. Readings from it are recorded like
+//! real ones.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs,
+    io::{self, Read},
+    path::Path,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use rocket::{http::Status, response::content, Data};
+use serde::{Deserialize, Serialize};
+use serialport::{ClearBuffer, DataBits, FlowControl, Parity, SerialPort, StopBits};
+
+use crate::{
+    api::{self, ErrorResponse},
+    auth::Admin,
+    events::{self, Severity},
+    memory::{Buffer, Policy},
+    registry, Readings, SensorError, EXTENDED_READINGS_REQUEST, OK_BIT, READINGS_REQUEST,
+    READINGS_SIZE, READ_TIMEOUT,
+};
+
+const SOURCE: &str = "simulate";
+
+/// Where sensors start, before a segment sets them.
+const DEFAULTS: [(&str, f32); 5] = [
+    ("T", 25.),
+    ("pH", 7.4),
+    ("ORP", 700.),
+    ("ec", 1_000.),
+    ("DO", 8.),
+];
+
+const ERROR_NAMES: [(&str, SensorError); 5] = [
+    ("BadMeasurement", SensorError::BadMeasurement),
+    ("ProbeDisconnected", SensorError::ProbeDisconnected),
+    ("OutOfRange", SensorError::OutOfRange),
+    ("NotStabilized", SensorError::NotStabilized),
+    ("FrontendFault", SensorError::FrontendFault),
+];
+
+/// Like a UART's receive buffer, which overruns, rather than grows, if it isn't read.
+const MAX_RX: usize = 4_096;
+
+pub const BUFFER: Buffer = Buffer {
+    name: "simulate.rx",
+    cap: MAX_RX,
+    policy: Policy::DropNewest,
+    about: "Bytes the simulated Water Monitor has sent, not yet read",
+};
+
+static STATE: Mutex<Option<Running>> = Mutex::new(None);
+
+/// If the poller should drop its port, since a scenario was loaded or stopped.
+static SWITCHED: Mutex<bool> = Mutex::new(false);
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Scenario {
+    #[serde(default)]
+    pub name: String,
+    /// Start over after the last segment, rather than holding it.
+    #[serde(default)]
+    pub repeat: bool,
+    /// Answer requests for extended readings, with dissolved oxygen, as newer hardware
+    /// does.
+    #[serde(default)]
+    pub extended: bool,
+    pub segments: Vec<Segment>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Segment {
+    pub secs: f32,
+    /// Sensors' values through the segment, by id. Sensors it doesn't set keep their
+    /// value from the segment before.
+    pub values: BTreeMap<String, f32>,
+    /// `[from, to]`, in a straight line over the segment.
+    pub ramps: BTreeMap<String, [f32; 2]>,
+    /// Sensors' errors, eg `ProbeDisconnected`, or another status byte, eg `9`.
+    pub errors: BTreeMap<String, String>,
+    /// The device can't be found, as if unplugged.
+    pub disconnected: bool,
+    /// Requests aren't answered.
+    pub silent: bool,
+    /// Responses lose their second half.
+    pub short_reads: bool,
+    /// Responses have bits flipped, as line noise would. Readings have no checksum, so
+    /// this shows what gets through.
+    pub corrupt: bool,
+}
+
+struct Running {
+    scenario: Scenario,
+    started: Instant,
+    /// Requests answered.
+    responses: u64,
+}
+
+#[derive(Serialize)]
+struct SimulationStatus {
+    name: String,
+    elapsed_secs: f32,
+    /// The segment playing, from 0.
+    segment: usize,
+    /// Past the last segment, without `repeat`; it's held.
+    done: bool,
+    responses: u64,
+}
+
+/// The segment playing `elapsed` into a scenario, how far through it, from 0 to 1, and
+/// if the scenario's done.
+fn position(scenario: &Scenario, elapsed: Duration) -> (usize, f32, bool) {
+    let total: f32 = scenario.segments.iter().map(|s| s.secs).sum();
+    let mut t = elapsed.as_secs_f32();
+    if t >= total {
+        if !scenario.repeat {
+            return (scenario.segments.len() - 1, 1., true);
+        }
+        t %= total;
+    }
+    for (i, segment) in scenario.segments.iter().enumerate() {
+        if t < segment.secs {
+            return (i, t / segment.secs, false);
+        }
+        t -= segment.secs;
+    }
+    (scenario.segments.len() - 1, 1., false)
+}
+
+fn parse_error(name: &str) -> Option<SensorError> {
+    ERROR_NAMES
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, e)| *e)
+        .or_else(|| {
+            name.parse()
+                .ok()
+                .filter(|s| *s != OK_BIT)
+                .map(SensorError::from_status)
+        })
+}
+
+/// The readings a scenario gives `elapsed` into it.
+fn readings(scenario: &Scenario, elapsed: Duration) -> Readings {
+    let (current, progress, _) = position(scenario, elapsed);
+    let mut levels: BTreeMap<&str, f32> = DEFAULTS.iter().copied().collect();
+    for (i, segment) in scenario.segments[..=current].iter().enumerate() {
+        let progress = if i == current { progress } else { 1. };
+        for (id, v) in segment.values.iter() {
+            levels.insert(id, *v);
+        }
+        for (id, [from, to]) in segment.ramps.iter() {
+            levels.insert(id, from + (to - from) * progress);
+        }
+    }
+
+    let mut result = Readings::empty();
+    for (id, v) in levels {
+        if id != "DO" || scenario.extended {
+            result.set(id, Some(Ok(v)));
+        }
+    }
+    for (id, name) in scenario.segments[current].errors.iter() {
+        if let Some(error) = parse_error(name) {
+            result.set(id, Some(Err(error)));
+        }
+    }
+    result
+}
+
+/// Check a scenario only sets sensors in the readings frame, with valid errors.
+fn check(scenario: &Scenario) -> Result<(), String> {
+    if scenario.segments.is_empty() {
+        return Err("A scenario needs at least one segment".into());
+    }
+    for (i, segment) in scenario.segments.iter().enumerate() {
+        if !(segment.secs > 0. && segment.secs.is_finite()) {
+            return Err(format!("Segment {}: `secs` must be above 0", i));
+        }
+        let sensors = segment
+            .values
+            .keys()
+            .chain(segment.ramps.keys())
+            .chain(segment.errors.keys());
+        for id in sensors {
+            if registry::get(id).is_none_or(|s| s.offset.is_none()) {
+                return Err(format!(
+                    "Segment {}: `{}` isn't a sensor in the Water Monitor's readings",
+                    i, id
+                ));
+            }
+        }
+        if let Some(name) = segment.errors.values().find(|n| parse_error(n).is_none()) {
+            return Err(format!(
+                "Segment {}: unknown error `{}`; use one of {}, or a status byte",
+                i,
+                name,
+                ERROR_NAMES
+                    .iter()
+                    .map(|(n, _)| format!("`{}`", n))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Read a scenario as JSON, or otherwise TOML.
+fn parse(text: &str) -> Result<Scenario, String> {
+    let scenario: Scenario = if text.trim_start().starts_with('{') {
+        serde_json::from_str(text).map_err(|e| e.to_string())?
+    } else {
+        toml::from_str(text).map_err(|e| e.to_string())?
+    };
+    check(&scenario)?;
+    Ok(scenario)
+}
+
+/// Start replaying a scenario, from its start.
+pub fn start(scenario: Scenario) {
+    events::record(
+        Severity::Warning,
+        SOURCE,
+        format!(
+            "Simulating the Water Monitor with scenario `{}`; readings aren't real until \
+             it's stopped.",
+            scenario.name
+        ),
+    );
+    *STATE.lock().unwrap() = Some(Running {
+        scenario,
+        started: Instant::now(),
+        responses: 0,
+    });
+    *SWITCHED.lock().unwrap() = true;
+}
+
+/// Read a scenario from a file, for `--simulate-scenario`.
+pub fn load_file(path: &Path) -> Result<Scenario, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Problem reading `{}`: {}", path.display(), e))?;
+    parse(&text).map_err(|e| format!("Invalid scenario: {}", e))
+}
+
+/// If the poller should reopen its port, since a scenario started or stopped.
+pub fn take_switched() -> bool {
+    std::mem::take(&mut *SWITCHED.lock().unwrap())
+}
+
+/// The simulated port, if a scenario is loaded, or why it can't be opened.
+pub fn open() -> Option<Result<Box<dyn SerialPort>, io::Error>> {
+    let state = STATE.lock().unwrap();
+    let running = state.as_ref()?;
+    let (segment, _, _) = position(&running.scenario, running.started.elapsed());
+    Some(if running.scenario.segments[segment].disconnected {
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "Can't find the Water Monitor.",
+        ))
+    } else {
+        Ok(Box::new(Port {
+            rx: Mutex::new(VecDeque::new()),
+            timeout: READ_TIMEOUT,
+        }))
+    })
+}
+
+/// Answers readings requests from the scenario playing.
+struct Port {
+    rx: Mutex<VecDeque<u8>>,
+    timeout: Duration,
+}
+
+fn unplugged() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "The device was disconnected")
+}
+
+impl io::Write for Port {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = STATE.lock().unwrap();
+        let running = match state.as_mut() {
+            Some(r) => r,
+            None => return Err(unplugged()),
+        };
+        let elapsed = running.started.elapsed();
+        let (current, _, _) = position(&running.scenario, elapsed);
+        let segment = &running.scenario.segments[current];
+        if segment.disconnected {
+            return Err(unplugged());
+        }
+
+        let extended = buf == EXTENDED_READINGS_REQUEST;
+        // Like older firmware, without dissolved oxygen, this ignores the extended request.
+        if segment.silent || !(buf == READINGS_REQUEST || extended && running.scenario.extended) {
+            return Ok(buf.len());
+        }
+
+        let mut frame = readings(&running.scenario, elapsed).to_bytes();
+        if !extended {
+            frame.truncate(READINGS_SIZE);
+        }
+        if segment.corrupt {
+            // Deterministic, but a different byte each time.
+            let i = (running.responses as usize * 7) % frame.len();
+            frame[i] ^= 0x5a;
+        }
+        if segment.short_reads {
+            frame.truncate(frame.len() / 2);
+        }
+        running.responses += 1;
+        let mut rx = self.rx.lock().unwrap();
+        let room = MAX_RX - rx.len();
+        if frame.len() > room {
+            BUFFER.dropped((frame.len() - room) as u64);
+        }
+        rx.extend(frame.into_iter().take(room));
+        BUFFER.note(rx.len());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl io::Read for Port {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut rx = self.rx.lock().unwrap();
+        if rx.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Operation timed out",
+            ));
+        }
+        let n = buf.len().min(rx.len());
+        for (b, v) in buf.iter_mut().zip(rx.drain(..n)) {
+            *b = v;
+        }
+        BUFFER.note(rx.len());
+        Ok(n)
+    }
+}
+
+impl SerialPort for Port {
+    fn name(&self) -> Option<String> {
+        Some("simulated".into())
+    }
+
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        Ok(9_600)
+    }
+
+    fn data_bits(&self) -> serialport::Result<DataBits> {
+        Ok(DataBits::Eight)
+    }
+
+    fn flow_control(&self) -> serialport::Result<FlowControl> {
+        Ok(FlowControl::None)
+    }
+
+    fn parity(&self) -> serialport::Result<Parity> {
+        Ok(Parity::None)
+    }
+
+    fn stop_bits(&self) -> serialport::Result<StopBits> {
+        Ok(StopBits::One)
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_baud_rate(&mut self, _baud_rate: u32) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_data_bits(&mut self, _data_bits: DataBits) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_flow_control(&mut self, _flow_control: FlowControl) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_parity(&mut self, _parity: Parity) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_stop_bits(&mut self, _stop_bits: StopBits) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn write_request_to_send(&mut self, _level: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn write_data_terminal_ready(&mut self, _level: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        Ok(false)
+    }
+
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn bytes_to_read(&self) -> serialport::Result<u32> {
+        Ok(self.rx.lock().unwrap().len() as u32)
+    }
+
+    fn bytes_to_write(&self) -> serialport::Result<u32> {
+        Ok(0)
+    }
+
+    fn clear(&self, buffer_to_clear: ClearBuffer) -> serialport::Result<()> {
+        if buffer_to_clear != ClearBuffer::Output {
+            self.rx.lock().unwrap().clear();
+            BUFFER.note(0);
+        }
+        Ok(())
+    }
+
+    fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
+        Err(serialport::Error::new(
+            serialport::ErrorKind::Unknown,
+            "The simulated port can't be cloned",
+        ))
+    }
+
+    fn set_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn clear_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+}
+
+fn status() -> Option<SimulationStatus> {
+    let state = STATE.lock().unwrap();
+    let running = state.as_ref()?;
+    let elapsed = running.started.elapsed();
+    let (segment, _, done) = position(&running.scenario, elapsed);
+    Some(SimulationStatus {
+        name: running.scenario.name.clone(),
+        elapsed_secs: elapsed.as_secs_f32(),
+        segment,
+        done,
+        responses: running.responses,
+    })
+}
+
+fn response() -> Result<content::Json<String>, ErrorResponse> {
+    match status() {
+        Some(s) => Ok(content::Json(serde_json::to_string(&s).unwrap())),
+        None => Err(api::error(Status::NotFound, "No scenario is loaded")),
+    }
+}
+
+/// The scenario playing, and where it's up to.
+#[get("/simulate")]
+pub fn view_simulation() -> Result<content::Json<String>, ErrorResponse> {
+    response()
+}
+
+/// Replace the Water Monitor with a scenario, as JSON or TOML, from its start.
+#[post("/simulate/scenario", data = "<data>")]
+pub fn load_scenario(_admin: Admin, data: Data) -> Result<content::Json<String>, ErrorResponse> {
+    let mut body = String::new();
+    data.open()
+        .take(256 * 1_024)
+        .read_to_string(&mut body)
+        .map_err(|e| api::error(Status::BadRequest, &e.to_string()))?;
+    let scenario = parse(&body)
+        .map_err(|e| api::error(Status::BadRequest, &format!("Invalid scenario: {}", e)))?;
+    start(scenario);
+    response()
+}
+
+/// Stop the scenario, and go back to the Water Monitor. Returns where it was up to.
+#[delete("/simulate/scenario")]
+pub fn stop_scenario(_admin: Admin) -> Result<content::Json<String>, ErrorResponse> {
+    let last = response();
+    let stopped = STATE.lock().unwrap().take();
+    match stopped {
+        Some(running) => {
+            *SWITCHED.lock().unwrap() = true;
+            events::record(
+                Severity::Info,
+                SOURCE,
+                format!(
+                    "Stopped scenario `{}`; back to the Water Monitor.",
+                    running.scenario.name
+                ),
+            );
+            last
+        }
+        None => Err(api::error(Status::NotFound, "No scenario is loaded")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RAMP: &str = r#"
+        name = "ramp"
+        repeat = true
+        [[segments]]
+        secs = 10
+        values = { T = 20 }
+        [[segments]]
+        secs = 20
+        ramps = { pH = [7.0, 8.0] }
+        errors = { ORP = "ProbeDisconnected", ec = "9" }
+    "#;
+
+    #[test]
+    fn segments_play_in_order() {
+        let scenario = parse(RAMP).unwrap();
+        let at = |secs: u64| readings(&scenario, Duration::from_secs(secs));
+
+        assert_eq!(position(&scenario, Duration::from_secs(5)), (0, 0.5, false));
+        assert_eq!(at(5).get("T"), Some(Ok(20.)));
+        assert_eq!(at(5).get("pH"), Some(Ok(7.4)));
+        assert_eq!(at(5).get("ORP"), Some(Ok(700.)));
+        // Only extended scenarios send dissolved oxygen.
+        assert_eq!(at(5).get("DO"), None);
+
+        // The temperature holds, as pH ramps, with the segment's errors.
+        assert_eq!(at(20).get("T"), Some(Ok(20.)));
+        assert_eq!(at(20).get("pH"), Some(Ok(7.5)));
+        assert_eq!(at(20).get("ORP"), Some(Err(SensorError::ProbeDisconnected)));
+        assert_eq!(at(20).get("ec"), Some(Err(SensorError::from_status(9))));
+
+        // And it repeats.
+        assert_eq!(
+            position(&scenario, Duration::from_secs(35)),
+            (0, 0.5, false)
+        );
+        let mut held = scenario.clone();
+        held.repeat = false;
+        assert_eq!(position(&held, Duration::from_secs(35)), (1, 1., true));
+    }
+
+    #[test]
+    fn scenarios_are_checked() {
+        let json = r#"{ "name": "json", "segments": [{ "secs": 5, "disconnected": true }] }"#;
+        assert!(parse(json).unwrap().segments[0].disconnected);
+
+        let invalid = |text: &str, expected: &str| {
+            let e = parse(text).unwrap_err();
+            assert!(e.contains(expected), "{}", e);
+        };
+        invalid(r#"{ "segments": [] }"#, "at least one segment");
+        invalid("[[segments]]\nsecs = 0", "`secs`");
+        invalid("[[segments]]\nsecs = 1\nvalues = { flow = 2 }", "`flow`");
+        invalid("[[segments]]\nsecs = 1\nerrors = { T = \"Wet\" }", "`Wet`");
+    }
+}
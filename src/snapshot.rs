@@ -0,0 +1,300 @@
+//! A rendered PNG of the latest readings, for displays that only show images, like a
+//! smart mirror or an e-ink frame. Each sensor gets a row, marked with its status color,
+//! and sensors in error show a dash and the error; a footer says when readings are
+//! from, or that they're stale. The layout scales to the requested size. Renders are
+//! kept until what they'd show changes, so fetches between polls don't re-render.
+
+use std::{collections::VecDeque, sync::Mutex};
+
+use chrono::{Duration, Utc};
+use rocket::{
+    http::{ContentType, Status},
+    response::content::Content,
+};
+
+use crate::{
+    api::{self, ErrorResponse},
+    config, font, locale,
+    memory::{Buffer, Policy},
+    png, poller,
+    status::{self, SensorStatus},
+    tokens::Viewer,
+    tz,
+};
+
+const DEFAULT_WIDTH: u32 = 600;
+const DEFAULT_HEIGHT: u32 = 300;
+const MIN_SIZE: u32 = 64;
+const MAX_SIZE: u32 = 2_000;
+
+/// Renders kept, eg for a few displays of different sizes.
+const CACHE_SIZE: usize = 4;
+
+pub const BUFFER: Buffer = Buffer {
+    name: "snapshot.renders",
+    cap: CACHE_SIZE,
+    policy: Policy::DropOldest,
+    about: "Recently rendered snapshot PNGs",
+};
+
+// Palette indices.
+const BACKGROUND: u8 = 0;
+const TEXT: u8 = 1;
+const MUTED: u8 = 2;
+const OK: u8 = 3;
+const WARN: u8 = 4;
+const CRITICAL: u8 = 5;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Theme {
+    Light,
+    Dark,
+}
+
+impl Theme {
+    fn palette(&self) -> [[u8; 3]; 6] {
+        match self {
+            Self::Light => [
+                [0xff, 0xff, 0xff],
+                [0x11, 0x11, 0x11],
+                [0x77, 0x77, 0x77],
+                [0x1e, 0x8e, 0x3e],
+                [0xc7, 0x8a, 0x00],
+                [0xc6, 0x28, 0x28],
+            ],
+            Self::Dark => [
+                [0x11, 0x11, 0x11],
+                [0xee, 0xee, 0xee],
+                [0x88, 0x88, 0x88],
+                [0x4c, 0xc4, 0x5c],
+                [0xf0, 0xb4, 0x29],
+                [0xef, 0x53, 0x50],
+            ],
+        }
+    }
+}
+
+/// What a render shows.
+#[derive(Clone, PartialEq)]
+struct Scene {
+    rows: Vec<Row>,
+    footer: String,
+    stale: bool,
+}
+
+#[derive(Clone, PartialEq)]
+struct Row {
+    label: &'static str,
+    value: String,
+    /// The error, for sensors without a reading.
+    error: Option<String>,
+    color: u8,
+}
+
+struct Render {
+    width: u32,
+    height: u32,
+    theme: Theme,
+    scene: Scene,
+    png: Vec<u8>,
+}
+
+static CACHE: Mutex<VecDeque<Render>> = Mutex::new(VecDeque::new());
+
+/// Eg `45 s`, `12 min` or `3 h`.
+pub fn age(secs: f32) -> String {
+    let secs = secs as u64;
+    if secs < 120 {
+        format!("{} s", secs)
+    } else if secs < 120 * 60 {
+        format!("{} min", secs / 60)
+    } else {
+        format!("{} h", secs / 3_600)
+    }
+}
+
+fn scene() -> Scene {
+    let cfg = config::get();
+    let readings = crate::latest_readings();
+    let statuses = status::classify(&readings, &cfg.status);
+    let locale = locale::configured();
+
+    let rows = readings
+        .iter()
+        .map(|(sensor, reading)| {
+            let color = match statuses.get(sensor.id).unwrap_or(SensorStatus::Error) {
+                SensorStatus::Ok => OK,
+                SensorStatus::Warn => WARN,
+                SensorStatus::Critical => CRITICAL,
+                SensorStatus::Error
+                | SensorStatus::Stale
+                | SensorStatus::NoFlow
+                | SensorStatus::Stabilizing
+                | SensorStatus::Rejected => MUTED,
+            };
+            let (value, error) = match reading {
+                Ok(v) => (locale.reading(sensor, v, &cfg.precision), None),
+                Err(e) => ("-".into(), Some(e.to_string())),
+            };
+            Row {
+                label: sensor.label,
+                value,
+                error,
+                color,
+            }
+        })
+        .collect();
+
+    let (footer, stale) = match poller::status().seconds_since_success {
+        None => ("No readings yet".into(), true),
+        Some(s) if s > cfg.status.stale_secs as f32 => {
+            (format!("Stale: last reading {} ago", age(s)), true)
+        }
+        Some(s) => {
+            let at = Utc::now() - Duration::milliseconds((s * 1_000.) as i64);
+            let at = at.with_timezone(&tz::configured());
+            (format!("Updated {}", at.format("%H:%M")), false)
+        }
+    };
+
+    Scene {
+        rows,
+        footer,
+        stale,
+    }
+}
+
+/// The largest scale `text` fits in `width` and `height` at, at least 1.
+fn fit(text: &str, width: usize, height: usize) -> usize {
+    let by_width = width
+        .checked_div(font::width(text, 1))
+        .unwrap_or(usize::MAX);
+    (height / font::GLYPH_HEIGHT).min(by_width).max(1)
+}
+
+fn fill(pixels: &mut [u8], image_width: usize, x: usize, y: usize, size: usize, color: u8) {
+    for row in pixels.chunks_mut(image_width).skip(y).take(size) {
+        for px in row.iter_mut().skip(x).take(size) {
+            *px = color;
+        }
+    }
+}
+
+fn render(scene: &Scene, width: u32, height: u32, theme: Theme) -> Vec<u8> {
+    let (w, h) = (width as usize, height as usize);
+    let mut pixels = vec![BACKGROUND; w * h];
+
+    let pad = (w.min(h) / 25).max(2);
+    let inner = h.saturating_sub(2 * pad);
+    // The footer gets 2/5 of a row.
+    let rows = scene.rows.len().max(1);
+    let row_h = inner * 5 / (5 * rows + 2);
+    let footer_h = inner - row_h * rows;
+
+    let dot = (row_h / 3).min(w / 20).max(2);
+    let label_x = 2 * pad + dot;
+    let label_w = w.saturating_sub(label_x + pad) * 3 / 10;
+    let value_x = label_x + label_w + pad;
+    let value_w = w.saturating_sub(value_x + pad);
+    let right = w.saturating_sub(pad);
+
+    for (i, row) in scene.rows.iter().enumerate() {
+        let top = pad + i * row_h;
+        fill(
+            &mut pixels,
+            w,
+            pad,
+            top + row_h.saturating_sub(dot) / 2,
+            dot,
+            row.color,
+        );
+
+        let scale = fit(row.label, label_w, row_h * 6 / 10);
+        let y = top + row_h.saturating_sub(font::GLYPH_HEIGHT * scale) / 2;
+        font::draw(&mut pixels, w, label_x, y, row.label, scale, TEXT);
+
+        match &row.error {
+            None => {
+                let scale = fit(&row.value, value_w, row_h * 7 / 10);
+                let x = right.saturating_sub(font::width(&row.value, scale));
+                let y = top + row_h.saturating_sub(font::GLYPH_HEIGHT * scale) / 2;
+                font::draw(&mut pixels, w, x, y, &row.value, scale, TEXT);
+            }
+            Some(error) => {
+                // The dash above, and the error below, in the same space.
+                let value_scale = fit(&row.value, value_w, row_h * 5 / 10);
+                let error_scale = fit(error, value_w, row_h * 3 / 10);
+                let used = font::GLYPH_HEIGHT * (value_scale + error_scale) + error_scale * 2;
+                let y = top + row_h.saturating_sub(used) / 2;
+                let x = right.saturating_sub(font::width(&row.value, value_scale));
+                font::draw(&mut pixels, w, x, y, &row.value, value_scale, TEXT);
+
+                let y = y + font::GLYPH_HEIGHT * value_scale + error_scale * 2;
+                let x = right.saturating_sub(font::width(error, error_scale));
+                font::draw(&mut pixels, w, x, y, error, error_scale, MUTED);
+            }
+        }
+    }
+
+    let scale = fit(&scene.footer, w.saturating_sub(2 * pad), footer_h * 6 / 10);
+    let y = pad + row_h * rows + footer_h.saturating_sub(font::GLYPH_HEIGHT * scale) / 2;
+    let color = if scene.stale { WARN } else { MUTED };
+    font::draw(&mut pixels, w, pad, y, &scene.footer, scale, color);
+
+    png::encode_indexed(width, height, &theme.palette(), &pixels)
+}
+
+/// The latest readings as a `width` by `height` PNG, with a `light` or `dark` theme.
+#[get("/snapshot.png?<width>&<height>&<theme>")]
+pub fn view_snapshot(
+    _viewer: Viewer,
+    width: Option<u32>,
+    height: Option<u32>,
+    theme: Option<String>,
+) -> Result<Content<Vec<u8>>, ErrorResponse> {
+    let width = width.unwrap_or(DEFAULT_WIDTH);
+    let height = height.unwrap_or(DEFAULT_HEIGHT);
+    for (name, size) in [("width", width), ("height", height)] {
+        if !(MIN_SIZE..=MAX_SIZE).contains(&size) {
+            return Err(api::error(
+                Status::BadRequest,
+                &format!("`{}` must be from {} to {}", name, MIN_SIZE, MAX_SIZE),
+            ));
+        }
+    }
+    let theme = match theme.as_deref() {
+        None | Some("light") => Theme::Light,
+        Some("dark") => Theme::Dark,
+        Some(t) => {
+            return Err(api::error(
+                Status::BadRequest,
+                &format!("`theme` must be `light` or `dark`; got `{}`", t),
+            ))
+        }
+    };
+
+    let scene = scene();
+    let mut cache = CACHE.lock().unwrap();
+    if let Some(r) = cache
+        .iter()
+        .find(|r| r.width == width && r.height == height && r.theme == theme && r.scene == scene)
+    {
+        return Ok(Content(ContentType::PNG, r.png.clone()));
+    }
+
+    let png = render(&scene, width, height, theme);
+    // Older renders of this size and theme won't be shown again.
+    cache.retain(|r| !(r.width == width && r.height == height && r.theme == theme));
+    if cache.len() == CACHE_SIZE {
+        cache.pop_back();
+    }
+    cache.push_front(Render {
+        width,
+        height,
+        theme,
+        scene,
+        png: png.clone(),
+    });
+    BUFFER.note(cache.len());
+    Ok(Content(ContentType::PNG, png))
+}
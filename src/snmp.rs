@@ -0,0 +1,612 @@
+//! A read-only SNMP v2c agent, for facility monitoring systems. It answers GET,
+//! GETNEXT and GETBULK from the cached readings; SET is refused. The objects are
+//! scalars under `snmp.oid_base`, with readings scaled to integers. The MIB describing
+//! them is served at `/api/snmp/mib`.
+
+use std::{io, net::UdpSocket, thread};
+
+use rocket::{http::Status, response::content};
+
+use crate::{
+    activity,
+    api::{self, ErrorResponse},
+    config::{self, SnmpConfig},
+    events::{self, Severity},
+    poller, SensorError,
+};
+
+/// `iso.org.dod.internet.private.enterprises`
+const ENTERPRISES: &[u32] = &[1, 3, 6, 1, 4, 1];
+
+/// SNMP v2c, as encoded in messages.
+const VERSION_2C: i64 = 1;
+
+const INTEGER: u8 = 0x02;
+const OCTET_STRING: u8 = 0x04;
+const OBJECT_IDENTIFIER: u8 = 0x06;
+const SEQUENCE: u8 = 0x30;
+
+const GET_REQUEST: u8 = 0xa0;
+const GET_NEXT_REQUEST: u8 = 0xa1;
+const RESPONSE: u8 = 0xa2;
+const SET_REQUEST: u8 = 0xa3;
+const GET_BULK_REQUEST: u8 = 0xa5;
+
+const NO_SUCH_OBJECT: u8 = 0x80;
+const NO_SUCH_INSTANCE: u8 = 0x81;
+const END_OF_MIB_VIEW: u8 = 0x82;
+
+const ERROR_NOT_WRITABLE: i64 = 17;
+
+/// Caps GETBULK's `max-repetitions`; walking everything takes fewer than this.
+const MAX_REPETITIONS: usize = 32;
+
+struct Object {
+    arc: u32,
+    name: &'static str,
+    syntax: &'static str,
+    description: &'static str,
+}
+
+/// Our objects, in OID order. Each is a scalar, ie its one instance is `.0`.
+const OBJECTS: &[Object] = &[
+    Object {
+        arc: 1,
+        name: "wmTemperature",
+        syntax: "Integer32",
+        description: "Temperature in hundredths of a degree C, ie degrees C x 100. 0 if \
+                      unavailable; see wmTemperatureStatus.",
+    },
+    Object {
+        arc: 2,
+        name: "wmPh",
+        syntax: "Integer32",
+        description: "pH x 100. 0 if unavailable; see wmPhStatus.",
+    },
+    Object {
+        arc: 3,
+        name: "wmOrp",
+        syntax: "Integer32",
+        description: "ORP in mV. 0 if unavailable; see wmOrpStatus.",
+    },
+    Object {
+        arc: 4,
+        name: "wmEc",
+        syntax: "Integer32",
+        description: "Conductivity in uS/cm. 0 if unavailable; see wmEcStatus.",
+    },
+    Object {
+        arc: 5,
+        name: "wmDataAge",
+        syntax: "Integer32",
+        description: "Seconds since the last successful reading. -1 if there hasn't been one.",
+    },
+    Object {
+        arc: 6,
+        name: "wmConnected",
+        syntax: "TruthValue",
+        description: "If the app has the Water Monitor's serial port open.",
+    },
+    Object {
+        arc: 7,
+        name: "wmTemperatureStatus",
+        syntax: "Integer32",
+        description: crate::SENSOR_ERROR_CODES,
+    },
+    Object {
+        arc: 8,
+        name: "wmPhStatus",
+        syntax: "Integer32",
+        description: crate::SENSOR_ERROR_CODES,
+    },
+    Object {
+        arc: 9,
+        name: "wmOrpStatus",
+        syntax: "Integer32",
+        description: crate::SENSOR_ERROR_CODES,
+    },
+    Object {
+        arc: 10,
+        name: "wmEcStatus",
+        syntax: "Integer32",
+        description: crate::SENSOR_ERROR_CODES,
+    },
+];
+
+/// A value in a variable binding.
+#[derive(Clone)]
+enum Value {
+    Integer(i64),
+    /// Anything else, exactly as encoded in a request.
+    Raw(Vec<u8>),
+    Exception(u8),
+}
+
+impl Value {
+    fn encode(&self) -> Vec<u8> {
+        match self {
+            Self::Integer(v) => tlv(INTEGER, &encode_int(*v)),
+            Self::Raw(r) => r.clone(),
+            Self::Exception(tag) => tlv(*tag, &[]),
+        }
+    }
+}
+
+/// Parse a dotted OID, and check it's under `enterprises`.
+pub fn oid_base(cfg: &SnmpConfig) -> Result<Vec<u32>, io::Error> {
+    let oid: Option<Vec<u32>> = cfg
+        .oid_base
+        .trim_start_matches('.')
+        .split('.')
+        .map(|arc| arc.parse().ok())
+        .collect();
+
+    match oid {
+        Some(oid) if oid.len() > ENTERPRISES.len() && oid.starts_with(ENTERPRISES) => Ok(oid),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Config error: `snmp.oid_base` must be an OID under 1.3.6.1.4.1; got \"{}\".",
+                cfg.oid_base
+            ),
+        )),
+    }
+}
+
+/// The current value of each object, by full OID, in OID order.
+fn snapshot(base: &[u32]) -> Vec<(Vec<u32>, Value)> {
+    let readings = crate::latest_readings();
+    let status = poller::status();
+
+    let scaled = |r: Result<f32, SensorError>, scale: f32| match r {
+        Ok(v) => (v * scale).round() as i64,
+        Err(_) => 0,
+    };
+    let code = |r: Result<f32, SensorError>| r.err().map(|e| e.code()).unwrap_or(0) as i64;
+
+    let values = [
+        scaled(readings.reading("T"), 100.),
+        scaled(readings.reading("pH"), 100.),
+        scaled(readings.reading("ORP"), 1.),
+        scaled(readings.reading("ec"), 1_000_000.),
+        status.seconds_since_success.map(|s| s as i64).unwrap_or(-1),
+        if status.connected { 1 } else { 2 },
+        code(readings.reading("T")),
+        code(readings.reading("pH")),
+        code(readings.reading("ORP")),
+        code(readings.reading("ec")),
+    ];
+
+    OBJECTS
+        .iter()
+        .zip(values.iter())
+        .map(|(obj, v)| {
+            let mut oid = base.to_vec();
+            oid.extend_from_slice(&[obj.arc, 0]);
+            (oid, Value::Integer(*v))
+        })
+        .collect()
+}
+
+/// Bind the SNMP port and start answering requests, if enabled.
+pub fn start(cfg: &SnmpConfig) -> Result<(), io::Error> {
+    if !cfg.enabled {
+        return Ok(());
+    }
+
+    let base = oid_base(cfg)?;
+    let socket = UdpSocket::bind(("0.0.0.0", cfg.port)).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Problem listening for SNMP on port {}: {}", cfg.port, e),
+        )
+    })?;
+    let community = cfg.community.clone();
+
+    thread::spawn(move || {
+        let mut buf = [0; 65_535];
+        loop {
+            let (len, peer) = match socket.recv_from(&mut buf) {
+                Ok(r) => r,
+                Err(e) => {
+                    events::record(
+                        Severity::Warning,
+                        "snmp",
+                        format!("Problem receiving an SNMP request: {}", e),
+                    );
+                    continue;
+                }
+            };
+
+            // Malformed requests, and ones with the wrong community, are dropped, as
+            // SNMP agents do.
+            if let Some(response) = respond(&buf[..len], &community, &base) {
+                activity::touch();
+                let _ = socket.send_to(&response, peer);
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// The response message for a request message.
+fn respond(msg: &[u8], community: &str, base: &[u32]) -> Option<Vec<u8>> {
+    let mut msg = Ber::new(Ber::new(msg).expect(SEQUENCE)?);
+    if decode_int(msg.expect(INTEGER)?)? != VERSION_2C {
+        return None;
+    }
+    if msg.expect(OCTET_STRING)? != community.as_bytes() {
+        return None;
+    }
+
+    let (pdu_type, pdu) = msg.read()?;
+    let mut pdu = Ber::new(pdu);
+    let request_id = decode_int(pdu.expect(INTEGER)?)?;
+    // Error status and index, or for GETBULK, non-repeaters and max-repetitions.
+    let field_a = decode_int(pdu.expect(INTEGER)?)?;
+    let field_b = decode_int(pdu.expect(INTEGER)?)?;
+
+    let mut requested = Vec::new();
+    let mut bindings = Ber::new(pdu.expect(SEQUENCE)?);
+    while !bindings.is_empty() {
+        let mut binding = Ber::new(bindings.expect(SEQUENCE)?);
+        let oid = decode_oid(binding.expect(OBJECT_IDENTIFIER)?)?;
+        let (tag, value) = binding.read()?;
+        requested.push((oid, Value::Raw(tlv(tag, value))));
+    }
+
+    let objects = snapshot(base);
+    let get = |oid: &[u32]| match objects.iter().find(|(o, _)| o == oid) {
+        Some((_, v)) => v.clone(),
+        None if objects
+            .iter()
+            .any(|(o, _)| oid.starts_with(&o[..o.len() - 1])) =>
+        {
+            Value::Exception(NO_SUCH_INSTANCE)
+        }
+        None => Value::Exception(NO_SUCH_OBJECT),
+    };
+    let next = |oid: &[u32]| match objects.iter().find(|(o, _)| o.as_slice() > oid) {
+        Some((o, v)) => (o.clone(), v.clone()),
+        None => (oid.to_vec(), Value::Exception(END_OF_MIB_VIEW)),
+    };
+
+    let (error_status, error_index, response) = match pdu_type {
+        GET_REQUEST => (
+            0,
+            0,
+            requested.iter().map(|(o, _)| (o.clone(), get(o))).collect(),
+        ),
+        GET_NEXT_REQUEST => (0, 0, requested.iter().map(|(o, _)| next(o)).collect()),
+        GET_BULK_REQUEST => {
+            let non_repeaters = (field_a.max(0) as usize).min(requested.len());
+            let max_repetitions = (field_b.max(0) as usize).min(MAX_REPETITIONS);
+
+            let mut result: Vec<_> = requested[..non_repeaters]
+                .iter()
+                .map(|(o, _)| next(o))
+                .collect();
+
+            let mut cursors: Vec<Vec<u32>> = requested[non_repeaters..]
+                .iter()
+                .map(|(o, _)| o.clone())
+                .collect();
+            for _ in 0..max_repetitions {
+                if cursors.is_empty() {
+                    break;
+                }
+                let mut all_ended = true;
+                for cursor in cursors.iter_mut() {
+                    let (oid, value) = next(cursor);
+                    if !matches!(value, Value::Exception(END_OF_MIB_VIEW)) {
+                        all_ended = false;
+                    }
+                    *cursor = oid.clone();
+                    result.push((oid, value));
+                }
+                if all_ended {
+                    break;
+                }
+            }
+            (0, 0, result)
+        }
+        SET_REQUEST => (ERROR_NOT_WRITABLE, 1, requested),
+        _ => return None,
+    };
+
+    let mut bindings = Vec::new();
+    for (oid, value) in &response {
+        let mut binding = tlv(OBJECT_IDENTIFIER, &encode_oid(oid));
+        binding.extend(value.encode());
+        bindings.extend(tlv(SEQUENCE, &binding));
+    }
+
+    let mut pdu = tlv(INTEGER, &encode_int(request_id));
+    pdu.extend(tlv(INTEGER, &encode_int(error_status)));
+    pdu.extend(tlv(INTEGER, &encode_int(error_index)));
+    pdu.extend(tlv(SEQUENCE, &bindings));
+
+    let mut msg = tlv(INTEGER, &encode_int(VERSION_2C));
+    msg.extend(tlv(OCTET_STRING, community.as_bytes()));
+    msg.extend(tlv(RESPONSE, &pdu));
+
+    Some(tlv(SEQUENCE, &msg))
+}
+
+/// Reads BER-encoded values, one at a time.
+struct Ber<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Ber<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// The next value's tag and contents.
+    fn read(&mut self) -> Option<(u8, &'a [u8])> {
+        let tag = *self.buf.first()?;
+        let first = *self.buf.get(1)? as usize;
+
+        let (len, header_len) = if first & 0x80 == 0 {
+            (first, 2)
+        } else {
+            let n = first & 0x7f;
+            if n == 0 || n > 4 {
+                return None;
+            }
+            let bytes = self.buf.get(2..2 + n)?;
+            let len = bytes.iter().fold(0, |acc, b| (acc << 8) | *b as usize);
+            (len, 2 + n)
+        };
+
+        let contents = self.buf.get(header_len..header_len + len)?;
+        self.buf = &self.buf[header_len + len..];
+        Some((tag, contents))
+    }
+
+    /// The next value's contents, if it has this tag.
+    fn expect(&mut self, tag: u8) -> Option<&'a [u8]> {
+        match self.read()? {
+            (t, contents) if t == tag => Some(contents),
+            _ => None,
+        }
+    }
+}
+
+fn tlv(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut result = vec![tag];
+    let len = contents.len();
+    if len < 0x80 {
+        result.push(len as u8);
+    } else {
+        let bytes = (len as u32).to_be_bytes();
+        let skip = bytes.iter().take_while(|b| **b == 0).count();
+        result.push(0x80 | (4 - skip) as u8);
+        result.extend_from_slice(&bytes[skip..]);
+    }
+    result.extend_from_slice(contents);
+    result
+}
+
+fn decode_int(contents: &[u8]) -> Option<i64> {
+    if contents.is_empty() || contents.len() > 8 {
+        return None;
+    }
+    // Sign-extend from the first byte.
+    let init = if contents[0] & 0x80 != 0 { -1 } else { 0 };
+    Some(contents.iter().fold(init, |acc, b| (acc << 8) | *b as i64))
+}
+
+/// Minimal two's complement.
+fn encode_int(v: i64) -> Vec<u8> {
+    let bytes = v.to_be_bytes();
+    let mut start = 0;
+    while start < 7 {
+        let redundant = (bytes[start] == 0 && bytes[start + 1] & 0x80 == 0)
+            || (bytes[start] == 0xff && bytes[start + 1] & 0x80 != 0);
+        if !redundant {
+            break;
+        }
+        start += 1;
+    }
+    bytes[start..].to_vec()
+}
+
+fn decode_oid(contents: &[u8]) -> Option<Vec<u32>> {
+    let mut arcs = Vec::new();
+    let mut arc: u32 = 0;
+    for b in contents {
+        arc = arc.checked_mul(128)? | (b & 0x7f) as u32;
+        if b & 0x80 == 0 {
+            if arcs.is_empty() {
+                let first = (arc / 40).min(2);
+                arcs.push(first);
+                arcs.push(arc - first * 40);
+            } else {
+                arcs.push(arc);
+            }
+            arc = 0;
+        }
+    }
+    if arcs.is_empty() {
+        None
+    } else {
+        Some(arcs)
+    }
+}
+
+fn encode_oid(oid: &[u32]) -> Vec<u8> {
+    let mut result = Vec::new();
+    let first = oid.first().copied().unwrap_or(0) * 40 + oid.get(1).copied().unwrap_or(0);
+
+    for arc in std::iter::once(first).chain(oid.iter().skip(2).copied()) {
+        let mut chunk = vec![(arc & 0x7f) as u8];
+        let mut rest = arc >> 7;
+        while rest > 0 {
+            chunk.push((rest & 0x7f) as u8 | 0x80);
+            rest >>= 7;
+        }
+        result.extend(chunk.iter().rev());
+    }
+    result
+}
+
+/// The MIB for our objects, under the configured base OID.
+fn mib(base: &[u32]) -> String {
+    // Name each arc between `enterprises` and our base, so the MIB stays valid SMIv2 for
+    // any base.
+    let mut defs = String::new();
+    let mut parent = "enterprises".to_owned();
+    let arcs = &base[ENTERPRISES.len()..];
+    for (i, arc) in arcs[..arcs.len() - 1].iter().enumerate() {
+        let name = format!("waterMonArc{}", i + 1);
+        defs += &format!("{} OBJECT IDENTIFIER ::= {{ {} {} }}\n", name, parent, arc);
+        parent = name;
+    }
+
+    let mut objects = String::new();
+    for obj in OBJECTS {
+        objects += &format!(
+            "
+{} OBJECT-TYPE
+    SYNTAX      {}
+    MAX-ACCESS  read-only
+    STATUS      current
+    DESCRIPTION
+        \"{}\"
+    ::= {{ waterMon {} }}
+",
+            obj.name, obj.syntax, obj.description, obj.arc
+        );
+    }
+
+    format!(
+        "WATER-MON-MIB DEFINITIONS ::= BEGIN
+
+IMPORTS
+    MODULE-IDENTITY, OBJECT-TYPE, Integer32, enterprises
+        FROM SNMPv2-SMI
+    TruthValue
+        FROM SNMPv2-TC;
+
+waterMon MODULE-IDENTITY
+    LAST-UPDATED \"202610140000Z\"
+    ORGANIZATION \"AnyLeaf\"
+    CONTACT-INFO \"https://www.anyleaf.org/water-monitor\"
+    DESCRIPTION
+        \"Readings from an AnyLeaf Water Monitor. Readings are scaled to integers, as
+        described for each object.\"
+    ::= {{ {} {} }}
+
+{}{}
+END
+",
+        parent,
+        arcs[arcs.len() - 1],
+        defs,
+        objects
+    )
+}
+
+#[get("/snmp/mib")]
+pub fn view_mib() -> Result<content::Plain<String>, ErrorResponse> {
+    match oid_base(&config::get().snmp) {
+        Ok(base) => Ok(content::Plain(mib(&base))),
+        Err(e) => Err(api::error(Status::InternalServerError, &e.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::{collection::vec, prelude::*, sample::select};
+
+    use super::*;
+
+    /// What requests bind each OID to.
+    const NULL: u8 = 0x05;
+
+    fn base() -> Vec<u32> {
+        oid_base(&SnmpConfig::default()).unwrap()
+    }
+
+    /// An OID as decoded: the first arc 0 to 2, and the second under 40 unless it's 2.
+    fn oid() -> impl Strategy<Value = Vec<u32>> {
+        (0..3u32, vec(any::<u32>(), 1..12)).prop_map(|(first, mut rest)| {
+            rest[0] = if first < 2 {
+                rest[0] % 40
+            } else {
+                rest[0] % 1_000
+            };
+            [vec![first], rest].concat()
+        })
+    }
+
+    fn request(pdu_type: u8, request_id: i64, fields: [i64; 2], oids: &[Vec<u32>]) -> Vec<u8> {
+        let mut bindings = Vec::new();
+        for oid in oids {
+            let mut binding = tlv(OBJECT_IDENTIFIER, &encode_oid(oid));
+            binding.extend(tlv(NULL, &[]));
+            bindings.extend(tlv(SEQUENCE, &binding));
+        }
+        let mut pdu = tlv(INTEGER, &encode_int(request_id));
+        pdu.extend(tlv(INTEGER, &encode_int(fields[0])));
+        pdu.extend(tlv(INTEGER, &encode_int(fields[1])));
+        pdu.extend(tlv(SEQUENCE, &bindings));
+
+        let mut msg = tlv(INTEGER, &encode_int(VERSION_2C));
+        msg.extend(tlv(OCTET_STRING, b"public"));
+        msg.extend(tlv(pdu_type, &pdu));
+        tlv(SEQUENCE, &msg)
+    }
+
+    proptest! {
+        #[test]
+        fn ints_round_trip(v: i64) {
+            prop_assert_eq!(decode_int(&encode_int(v)), Some(v));
+        }
+
+        #[test]
+        fn oids_round_trip(oid in oid()) {
+            prop_assert_eq!(decode_oid(&encode_oid(&oid)), Some(oid));
+        }
+
+        #[test]
+        fn any_bytes_are_answered_or_dropped(msg in vec(any::<u8>(), 0..128)) {
+            respond(&msg, "public", &base());
+        }
+
+        /// Requests are answered, with their ID, however they're cut short or garbled.
+        #[test]
+        fn requests_are_answered(
+            pdu_type in select(vec![GET_REQUEST, GET_NEXT_REQUEST, GET_BULK_REQUEST, SET_REQUEST]),
+            request_id in any::<i32>(),
+            fields: [i64; 2],
+            oids in vec(oid(), 0..8),
+            garble in any::<Option<(usize, u8)>>(),
+        ) {
+            let mut msg = request(pdu_type, request_id as i64, fields, &oids);
+            match garble {
+                Some((i, b)) => {
+                    let i = i % msg.len();
+                    msg[i] = b;
+                    respond(&msg, "public", &base());
+                }
+                None => {
+                    let response = respond(&msg, "public", &base()).unwrap();
+                    let mut response = Ber::new(Ber::new(&response).expect(SEQUENCE).unwrap());
+                    response.expect(INTEGER).unwrap();
+                    response.expect(OCTET_STRING).unwrap();
+                    let mut pdu = Ber::new(response.expect(RESPONSE).unwrap());
+                    let id = decode_int(pdu.expect(INTEGER).unwrap());
+                    prop_assert_eq!(id, Some(request_id as i64));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,111 @@
+//! Serving the web page as a single-page app: GETs of unknown paths outside `/api` get
+//! `index.html`, so deep links like `/history/week` reach the frontend's router, while
+//! unknown API paths still 404 with JSON. Fingerprinted assets, eg `app.3f9a1c2b.js`,
+//! are cached for a year, since a new build gives them new names; everything else,
+//! including `index.html`, is revalidated on each load. If the web page's files are
+//! missing, the basic dashboard stands in for it, so the app isn't a blank page.
+
+use std::path::PathBuf;
+
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::Method,
+    response::{self, content, NamedFile, Responder},
+    Request, Response,
+};
+
+use crate::{basic, selfcheck};
+
+const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+
+/// If the file name has a content hash, as bundlers add: `app.3f9a1c2b.js` or
+/// `index-BxY3z9aQ.js`.
+fn fingerprinted(path: &str) -> bool {
+    let name = path.rsplit('/').next().unwrap_or_default();
+    let stem = match name.rsplit_once('.') {
+        Some((stem, _)) => stem,
+        None => return false,
+    };
+    match stem.rsplit_once(['.', '-']) {
+        Some((_, hash)) => {
+            (8..=64).contains(&hash.len())
+                && hash.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_')
+                && hash.bytes().any(|b| b.is_ascii_digit())
+        }
+        None => false,
+    }
+}
+
+/// One of the web page's files, like its `index.html`, or the basic dashboard if that's
+/// missing.
+pub enum Index {
+    Page(NamedFile),
+    Builtin,
+}
+
+impl<'r> Responder<'r> for Index {
+    fn respond_to(self, req: &Request) -> response::Result<'r> {
+        match self {
+            Self::Page(file) => file.respond_to(req),
+            Self::Builtin => content::Html(basic::page(true)).respond_to(req),
+        }
+    }
+}
+
+fn index() -> Index {
+    match NamedFile::open(selfcheck::static_dir().join("index.html")) {
+        Ok(file) => Index::Page(file),
+        Err(_) => Index::Builtin,
+    }
+}
+
+/// The web page.
+#[get("/", rank = 20)]
+pub fn view_index() -> Index {
+    index()
+}
+
+/// One of the web page's files, or `index.html`, for paths the frontend routes. Paths
+/// under `/api`, or that look like files, 404 if there's no such file. Rocket's segments
+/// can't hold `..`, or start with a dot. This serves the files, rather than
+/// `StaticFiles`, since that 404s where there's no file, instead of forwarding here.
+#[get("/<path..>", rank = 20)]
+pub fn view_file(path: PathBuf) -> Option<Index> {
+    let file = selfcheck::static_dir().join(&path);
+    if file.is_file() {
+        return NamedFile::open(file).ok().map(Index::Page);
+    }
+    if path.starts_with("api") || path.extension().is_some() {
+        return None;
+    }
+    Some(index())
+}
+
+/// Sets `Cache-Control` on the web page's files.
+pub struct CacheHeaders;
+
+impl Fairing for CacheHeaders {
+    fn info(&self) -> Info {
+        Info {
+            name: "Static file caching",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        let path = request.uri().path();
+        if request.method() != Method::Get
+            || response.status().code != 200
+            || path == "/api"
+            || path.starts_with("/api/")
+        {
+            return;
+        }
+        let value = if fingerprinted(path) {
+            IMMUTABLE
+        } else {
+            "no-cache"
+        };
+        response.set_raw_header("Cache-Control", value);
+    }
+}
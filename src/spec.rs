@@ -0,0 +1,3592 @@
+//! A hand-maintained OpenAPI description of the HTTP API, served at `/api/spec.json`.
+//! When adding or changing a route, update its entry here; `check_routes` warns at
+//! launch about any mounted API route that isn't described.
+
+use rocket::Rocket;
+use serde_json::{json, Value};
+
+use crate::{registry::REGISTRY, tokens::Scope};
+
+/// Build the OpenAPI 3 document.
+pub fn spec() -> Value {
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "AnyLeaf Water Monitor API",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "Readings from an AnyLeaf Water Monitor connected over USB. \
+                The API is intended for use on a trusted local network. Reads are public, \
+                unless `auth.private` is set: then operations with an `x-token-scope`, \
+                which serve readings, history, and what's derived from them, need admin, \
+                or a token from `/api/tokens` with that scope, as `?token=` or a bearer \
+                token. Admin operations, like restoring a backup, need `auth.admin_token` as \
+                a bearer token, or a session from `/api/login`.\n\n\
+                Stable routes live under `/api/v1`. Operations marked `x-frozen` won't change \
+                their response shape within their `x-api-version`; new shapes go in a new \
+                version. Of the routes directly under `/api`, those that are also under \
+                `/api/v1` give the same responses there, and `/api/readings` is deprecated. \
+                The rest aren't versioned, and may change.",
+        },
+        // Operations are public unless they list their own `security`.
+        "security": [],
+        "paths": paths(),
+        "components": {
+            "schemas": schemas(),
+            "securitySchemes": {
+                "adminToken": {
+                    "type": "http",
+                    "scheme": "bearer",
+                    "description": "`auth.admin_token` from the config",
+                },
+                "deviceToken": {
+                    "type": "http",
+                    "scheme": "bearer",
+                    "description": "A pushing device's `token`, from its `[[devices]]` entry",
+                },
+                "session": {
+                    "type": "apiKey",
+                    "in": "cookie",
+                    "name": "session",
+                    "description": "Set by `/api/login`",
+                },
+                "viewerToken": {
+                    "type": "apiKey",
+                    "in": "query",
+                    "name": "token",
+                    "description": "A scoped token from `/api/tokens`, needed with `auth.private`; \
+                        also taken as a bearer token",
+                },
+            },
+        },
+    })
+}
+
+fn paths() -> Value {
+    let mut paths = json!({
+        "/api/v1/readings": {
+            "get": {
+                "summary": "Latest cached readings",
+                "description": "The server polls the Water Monitor in the background, and \
+                    returns the latest readings it has. Sensors are keyed in snake case, eg \
+                    `temperature` and `ph`. Values are rounded to each sensor's \
+                    `precision.decimals`, ties to even.",
+                "operationId": "getReadingsV1",
+                "x-api-version": "v1",
+                "x-frozen": true,
+                "responses": {
+                    "200": json_response("Latest readings", "ReadingsV1"),
+                    "default": json_response("Unexpected error", "ApiError"),
+                },
+            },
+        },
+        "/api/readings": {
+            "get": {
+                "summary": "Latest cached readings (deprecated)",
+                "description": "Deprecated alias of `/api/v1/readings`, with sensors keyed by \
+                    their ids, eg `T` and `pH`. Responses carry a `Deprecation` header, and a \
+                    `Link` header pointing to the successor.",
+                "operationId": "getReadings",
+                "deprecated": true,
+                "x-api-version": "unversioned",
+                "x-frozen": true,
+                "responses": {
+                    "200": json_response("Latest readings", "Readings"),
+                    "default": json_response("Unexpected error", "ApiError"),
+                },
+            },
+        },
+        "/api/debug/slow-requests": {
+            "get": {
+                "summary": "Slowest recent requests",
+                "description": "The slowest of the last 500 requests, slowest first. Only \
+                    recorded when the access log is enabled in config. `?token=` values \
+                    are redacted from paths.",
+                "operationId": "getSlowRequests",
+                "security": [{ "adminToken": [] }, { "session": [] }],
+                "parameters": [query_param("limit", "integer", "Max requests to return. Default 20.")],
+                "responses": {
+                    "200": json_response("Slow requests", "SlowRequests"),
+                    "401": json_response("Missing or wrong admin token", "ApiError"),
+                    "403": json_response("No admin token is configured", "ApiError"),
+                },
+            },
+        },
+        "/api/debug/serial": {
+            "get": {
+                "summary": "Serial link statistics",
+                "description": "Counts since the last reset, and latency and success rate \
+                    over the last 15 minutes.",
+                "operationId": "getSerialStats",
+                "responses": {
+                    "200": json_response("Serial stats", "SerialStats"),
+                },
+            },
+        },
+        "/api/debug/serial/reset": {
+            "post": {
+                "summary": "Reset serial link statistics",
+                "operationId": "resetSerialStats",
+                "security": [{ "adminToken": [] }, { "session": [] }],
+                "responses": {
+                    "200": json_response("The freshly-reset stats", "SerialStats"),
+                    "401": json_response("Missing or wrong admin token", "ApiError"),
+                    "403": json_response("No admin token is configured", "ApiError"),
+                },
+            },
+        },
+        "/api/debug/samples": {
+            "get": {
+                "summary": "The latest poll cycle's raw samples, and their medians",
+                "description": "Only with `polling.keep_cycle_samples` set.",
+                "operationId": "getCycleSamples",
+                "responses": {
+                    "200": json_response("The samples", "CycleSamples"),
+                    "404": json_response("Samples aren't being kept", "ApiError"),
+                },
+            },
+        },
+        "/api/debug/selftest": {
+            "post": {
+                "summary": "Self-test the readings encoding and serial link",
+                "description": "Round-trips readings through the encoder and decoder, and, \
+                    if the Water Monitor is connected, times the next readings request.",
+                "operationId": "runSelfTest",
+                "security": [{ "adminToken": [] }, { "session": [] }],
+                "responses": {
+                    "200": json_response("The self-test report", "SelfTest"),
+                    "401": json_response("Missing or wrong admin token", "ApiError"),
+                    "403": json_response("No admin token is configured", "ApiError"),
+                },
+            },
+        },
+        "/api/selfcheck": {
+            "get": {
+                "summary": "The checks run at startup",
+                "description": "That the web page's files are there, the port can be listened \
+                    on, serial ports can be opened, and the Water Monitor is plugged in. Only \
+                    the port stops the server starting; the others are warnings.",
+                "operationId": "getSelfCheck",
+                "responses": {
+                    "200": json_response("The results", "SelfCheck"),
+                },
+            },
+        },
+        "/api/history": {
+            "get": {
+                "summary": "Readings history, raw or in time buckets",
+                "description": "Without `bucket`, returns raw samples, up to 10000. With it, \
+                    returns every bucket in the range, up to 10000, with per-sensor stats; \
+                    stats are null for buckets without valid samples. Either way, stretches \
+                    without samples longer than `history.gap_factor` polling intervals are \
+                    listed in `gaps`. With `shape=columns`, `samples` or `buckets` is an \
+                    object with an array per field, for charting: `ts`, in seconds since the \
+                    epoch, then eg `count`, `maintenance` and each sensor, whose values, or \
+                    stats, are null for errors and empty buckets.",
+                "operationId": "getHistory",
+                "parameters": [
+                    query_param("from", "string", "Start time: RFC 3339, a local time without an offset, or a date for its local midnight. Default: an hour before `to`."),
+                    query_param("to", "string", "End time, exclusive, in the same formats. Default: now."),
+                    query_param("bucket", "string", "Bucket length, eg `30s`, `5m`, `1h` or `1d`."),
+                    query_param("agg", "string", "Stats per bucket: `avg`, `min`, `max`, or `all` (default)."),
+                    query_param("interpolate", "boolean", "Fill empty buckets in gaps up to `history.interpolate_max_secs` long, in a straight line between their neighbours."),
+                    query_param("tz", "string", "IANA timezone for local input times, and returned times. Default: `time.timezone`."),
+                    query_param("shape", "string", "`rows` (default), an object per sample or bucket, or `columns`, an array per field."),
+                    query_param("device", "string", "A device that pushes its readings, for its history. Default: `local`, this one."),
+                ],
+                "responses": {
+                    "200": json_response("History", "History"),
+                    "400": json_response("Invalid parameters, or too many samples or buckets", "ApiError"),
+                    "404": json_response("History is disabled, or the device's isn't kept", "ApiError"),
+                },
+            },
+        },
+        "/api/distribution": {
+            "get": {
+                "summary": "A sensor's histogram and percentiles over recent history",
+                "description": "Samples without a valid reading are left out, and counted in \
+                    `excluded`. Compacted rows count as their average, weighted by their \
+                    sample count.",
+                "operationId": "getDistribution",
+                "parameters": [
+                    {
+                        "name": "sensor",
+                        "in": "query",
+                        "required": true,
+                        "schema": { "type": "string" },
+                        "description": "A sensor id, eg `pH`; case-insensitive.",
+                    },
+                    query_param("hours", "integer", "How far back to look. Default: 24."),
+                    query_param("bins", "integer", "Equal-width bins, up to 1000. Default: 20."),
+                    query_param("min", "number", "The first bin edge. Default: the sensor's plausible minimum."),
+                    query_param("max", "number", "The last bin edge. Default: the sensor's plausible maximum."),
+                ],
+                "responses": {
+                    "200": json_response("Distribution", "Distribution"),
+                    "400": json_response("Invalid parameters", "ApiError"),
+                    "404": json_response("History is disabled", "ApiError"),
+                },
+            },
+        },
+        "/api/compare": {
+            "get": {
+                "summary": "Per-sensor stats for two ranges of history, and their difference",
+                "description": "Each range is a duration back from now, eg `7d`, two, eg \
+                    `7d..14d` for the week before last, or two times, eg \
+                    `2021-06-01..2021-06-08`. The ranges can't overlap, and each needs \
+                    samples. Time in range is against `status.targets`. The mean, \
+                    standard deviation and time in range are weighted by time, so a burst \
+                    of fast polling doesn't skew them.",
+                "operationId": "getComparison",
+                "parameters": [
+                    {
+                        "name": "range_a",
+                        "in": "query",
+                        "required": true,
+                        "schema": { "type": "string" },
+                    },
+                    {
+                        "name": "range_b",
+                        "in": "query",
+                        "required": true,
+                        "schema": { "type": "string" },
+                    },
+                    query_param("tz", "string", "IANA timezone for local input times, and returned times. Default: `time.timezone`."),
+                ],
+                "responses": {
+                    "200": json_response("Stats per sensor: `a`, `b`, and `delta`, B minus A", "Comparison"),
+                    "400": json_response("Invalid or overlapping ranges, or one without samples", "ApiError"),
+                    "404": json_response("History is disabled", "ApiError"),
+                },
+            },
+        },
+        "/api/reliability": {
+            "get": {
+                "summary": "How often poll cycles gave fully valid readings, and why the rest didn't",
+                "description": "One entry per `reliability.windows`. Counts are kept with \
+                    history, by minute, so survive restarts.",
+                "operationId": "getReliability",
+                "responses": {
+                    "200": {
+                        "description": "Reliability per window",
+                        "content": {
+                            "application/json": {
+                                "schema": {
+                                    "type": "array",
+                                    "items": { "$ref": "#/components/schemas/Reliability" },
+                                },
+                            },
+                        },
+                    },
+                    "404": json_response("History is disabled", "ApiError"),
+                },
+            },
+        },
+        "/api/storage": {
+            "get": {
+                "summary": "History disk usage, rows per tier, and the last prune",
+                "description": "Pruning runs after each compaction, when `history.max_age_days` \
+                    or `history.max_size_mb` is set. A dry-run size estimate assumes rows of \
+                    average size.",
+                "operationId": "getStorage",
+                "parameters": [
+                    query_param("dry_run", "boolean", "Also report what pruning now would delete."),
+                ],
+                "responses": {
+                    "200": json_response("Storage", "Storage"),
+                    "404": json_response("History is disabled", "ApiError"),
+                },
+            },
+        },
+        "/api/storage/verify": {
+            "post": {
+                "summary": "Check the history database and its rows",
+                "description": "Runs SQLite's integrity check, then checks rows for times that \
+                    go backwards or are in the future, values outside \
+                    `history.plausible`, and inconsistent aggregates. With \
+                    `quarantine=true`, rows with problems are moved to the `quarantine` \
+                    table; this needs the admin token, and is skipped if the file is corrupt.",
+                "operationId": "verifyStorage",
+                "parameters": [
+                    query_param("quarantine", "boolean", "Move rows with problems to the `quarantine` table."),
+                ],
+                "responses": {
+                    "200": json_response("Results", "VerifyReport"),
+                    "401": json_response("Quarantining, with a missing or wrong admin token", "ApiError"),
+                    "403": json_response("Quarantining, with no admin token configured", "ApiError"),
+                    "404": json_response("History is disabled", "ApiError"),
+                },
+            },
+        },
+        "/api/login": {
+            "post": {
+                "summary": "Log in with the password",
+                "description": "Sets an HttpOnly session cookie, which admin routes accept in \
+                    place of the admin token. A session ends after \
+                    `auth.session_idle_mins` without a request. Set the password with \
+                    `water-mon-app set-password`.",
+                "operationId": "login",
+                "requestBody": {
+                    "required": true,
+                    "content": {
+                        "application/json": {
+                            "schema": {
+                                "type": "object",
+                                "required": ["password"],
+                                "properties": { "password": { "type": "string" } },
+                            },
+                        },
+                    },
+                },
+                "responses": {
+                    "200": json_response("Logged in", "Session"),
+                    "401": json_response("Wrong password", "ApiError"),
+                    "403": json_response("No password is set", "ApiError"),
+                },
+            },
+        },
+        "/api/logout": {
+            "post": {
+                "summary": "End the session",
+                "operationId": "logout",
+                "responses": {
+                    "200": json_response("Logged out", "Session"),
+                },
+            },
+        },
+        "/api/session": {
+            "get": {
+                "summary": "Whether a password is set, and this request has a session",
+                "operationId": "getSession",
+                "responses": {
+                    "200": json_response("Session status", "Session"),
+                },
+            },
+        },
+        "/api/backup": {
+            "get": {
+                "summary": "Download a backup of the history database and config",
+                "description": "A zip with `manifest.json`, `history.sqlite3` (if history is \
+                    enabled) and `water-mon.toml`, the config in effect, with secrets \
+                    redacted, as listed in the manifest's `redacted`.",
+                "operationId": "getBackup",
+                "security": [{ "adminToken": [] }, { "session": [] }],
+                "responses": {
+                    "200": {
+                        "description": "Backup",
+                        "content": { "application/zip": {} },
+                    },
+                    "401": json_response("Missing or wrong admin token", "ApiError"),
+                    "403": json_response("No admin token is configured", "ApiError"),
+                    "404": json_response("History is disabled", "ApiError"),
+                    "500": json_response("Problem making the backup", "ApiError"),
+                },
+            },
+        },
+        "/api/restore": {
+            "post": {
+                "summary": "Restore a backup from `/api/backup`",
+                "description": "Checks the whole backup, including its config, as at \
+                    startup, then replaces the history database and config. Redacted \
+                    secrets are replaced with the current ones, and the current `[auth]` \
+                    settings are kept. Port and address changes apply after a restart.",
+                "operationId": "restoreBackup",
+                "security": [{ "adminToken": [] }, { "session": [] }],
+                "requestBody": {
+                    "required": true,
+                    "content": { "application/zip": {} },
+                },
+                "responses": {
+                    "200": json_response("What was restored", "RestoreResult"),
+                    "400": json_response("Invalid backup, or one from an incompatible version", "ApiError"),
+                    "401": json_response("Missing or wrong admin token", "ApiError"),
+                    "403": json_response("No admin token is configured", "ApiError"),
+                    "413": json_response("Backup too large", "ApiError"),
+                },
+            },
+        },
+        "/api/config/reload": {
+            "post": {
+                "summary": "Reload the config file",
+                "description": "As happens when `water-mon.toml` changes. The whole file \
+                    is checked first; on an error, the previous config stays in effect. \
+                    Settings only read at startup, like the port, keep their running values \
+                    until a restart.",
+                "operationId": "reloadConfig",
+                "security": [{ "adminToken": [] }, { "session": [] }],
+                "responses": {
+                    "200": json_response("What changed", "ConfigReload"),
+                    "400": json_response("Invalid config", "ApiError"),
+                    "401": json_response("Missing or wrong admin token", "ApiError"),
+                    "403": json_response("No admin token is configured", "ApiError"),
+                },
+            },
+        },
+        "/api/status/targets": {
+            "get": {
+                "summary": "The target ranges that readings' statuses are classified by",
+                "operationId": "getStatusTargets",
+                "responses": {
+                    "200": json_response("The `[status]` settings", "StatusSettings"),
+                },
+            },
+            "put": {
+                "summary": "Replace the target ranges",
+                "description": "Saved to the config file, and applied immediately. Targets \
+                    are in `units`, or the locale's; they're kept in the registry's, so a later \
+                    change of locale doesn't change them.",
+                "operationId": "setStatusTargets",
+                "security": [{ "adminToken": [] }, { "session": [] }],
+                "requestBody": {
+                    "required": true,
+                    "content": {
+                        "application/json": {
+                            "schema": { "$ref": "#/components/schemas/StatusSettings" },
+                        },
+                    },
+                },
+                "responses": {
+                    "200": json_response("The new settings", "StatusSettings"),
+                    "400": json_response("Invalid settings", "ApiError"),
+                    "401": json_response("Missing or wrong admin token", "ApiError"),
+                    "403": json_response("No admin token is configured", "ApiError"),
+                },
+            },
+        },
+        "/api/score": {
+            "get": {
+                "summary": "A water-quality score from 0 to 100, and what took points off it",
+                "description": "From each weighted sensor's status, per `[score]`. The \
+                    components' points add up to the score.",
+                "operationId": "getScore",
+                "responses": {
+                    "200": json_response("The score", "Score"),
+                },
+            },
+        },
+        "/api/sensors": {
+            "get": {
+                "summary": "Each sensor's units, plausible range and target",
+                "description": "Values are in `unit`; show them in `display_unit`, after \
+                    multiplying by `display_scale`. The EC sensor also has its probe's cell \
+                    constant, and its latest reading before scaling for it.",
+                "operationId": "getSensors",
+                "responses": {
+                    "200": {
+                        "description": "Sensors, in the order of readings",
+                        "content": {
+                            "application/json": {
+                                "schema": {
+                                    "type": "array",
+                                    "items": { "$ref": "#/components/schemas/Sensor" },
+                                },
+                            },
+                        },
+                    },
+                },
+            },
+        },
+        "/api/sensors/ec": {
+            "get": {
+                "summary": "The EC probe's settings",
+                "operationId": "getEcSettings",
+                "responses": {
+                    "200": json_response("The `[ec]` settings", "EcSettings"),
+                },
+            },
+            "put": {
+                "summary": "Set the EC probe's cell constant",
+                "description": "Saved to the config file, and applied from the next reading. \
+                    Stored history isn't rescaled.",
+                "operationId": "setEcSettings",
+                "security": [{ "adminToken": [] }, { "session": [] }],
+                "requestBody": {
+                    "required": true,
+                    "content": {
+                        "application/json": {
+                            "schema": { "$ref": "#/components/schemas/EcSettings" },
+                        },
+                    },
+                },
+                "responses": {
+                    "200": json_response("The new settings", "EcSettings"),
+                    "400": json_response("Invalid settings", "ApiError"),
+                    "401": json_response("Missing or wrong admin token", "ApiError"),
+                    "403": json_response("No admin token is configured", "ApiError"),
+                },
+            },
+        },
+        "/api/channels": {
+            "get": {
+                "summary": "Readings merged across devices",
+                "description": "Each channel from the config's `[[channels]]`, merged from \
+                    its sources' latest readings. Sources whose device is unreachable, or \
+                    whose reading is an error or stale, are left out.",
+                "operationId": "getChannels",
+                "responses": {
+                    "200": {
+                        "description": "Channels, in config order",
+                        "content": {
+                            "application/json": {
+                                "schema": {
+                                    "type": "array",
+                                    "items": { "$ref": "#/components/schemas/Channel" },
+                                },
+                            },
+                        },
+                    },
+                },
+            },
+        },
+        "/api/events": {
+            "get": {
+                "summary": "Recent events, newest first",
+                "operationId": "getEvents",
+                "parameters": [
+                    query_param("limit", "integer", "Max events to return. Default 100."),
+                    query_param("request_id", "string", "Only events raised handling this request"),
+                ],
+                "responses": {
+                    "200": {
+                        "description": "Events",
+                        "content": {
+                            "application/json": {
+                                "schema": {
+                                    "type": "array",
+                                    "items": { "$ref": "#/components/schemas/Event" },
+                                },
+                            },
+                        },
+                    },
+                },
+            },
+        },
+        "/api/connect/qr.svg": qr_path("image/svg+xml", "getConnectQrSvg"),
+        "/api/connect/qr.png": qr_path("image/png", "getConnectQrPng"),
+        "/api/modbus/map": {
+            "get": {
+                "summary": "The Modbus TCP register map",
+                "operationId": "getModbusMap",
+                "responses": {
+                    "200": json_response("Register map", "ModbusMap"),
+                },
+            },
+        },
+        "/api/snmp/mib": {
+            "get": {
+                "summary": "The SNMP MIB describing our objects",
+                "operationId": "getSnmpMib",
+                "responses": {
+                    "200": {
+                        "description": "MIB, in SMIv2",
+                        "content": { "text/plain": {} },
+                    },
+                    "500": json_response("`snmp.oid_base` is invalid", "ApiError"),
+                },
+            },
+        },
+        "/api/spec.json": {
+            "get": {
+                "summary": "This OpenAPI document",
+                "operationId": "getSpec",
+                "responses": {
+                    "200": {
+                        "description": "OpenAPI 3 document",
+                        "content": { "application/json": {} },
+                    },
+                },
+            },
+        },
+        "/api/docs": {
+            "get": {
+                "summary": "Swagger UI for this API",
+                "operationId": "getDocs",
+                "responses": {
+                    "200": {
+                        "description": "HTML page",
+                        "content": { "text/html": {} },
+                    },
+                },
+            },
+        },
+    });
+    extend(&mut paths, display_paths());
+    extend(&mut paths, system_paths());
+    extend(&mut paths, exporter_paths());
+    extend(&mut paths, alert_paths());
+    extend(&mut paths, notify_paths());
+    extend(&mut paths, maintenance_paths());
+    extend(&mut paths, interlock_paths());
+    extend(&mut paths, device_paths());
+    extend(&mut paths, validation_hook_paths());
+    extend(&mut paths, input_paths());
+    extend(&mut paths, simulate_paths());
+    extend(&mut paths, setup_paths());
+    extend(&mut paths, token_paths());
+    if cfg!(feature = "flight-controller") {
+        extend(&mut paths, fc_paths());
+    }
+
+    // Stable routes are under `/api/v1` too, with the same responses.
+    for route in crate::stable_routes() {
+        let path = openapi_path(route.uri.path());
+        let method = route.method.as_str().to_lowercase();
+        let mut op = paths[format!("/api{}", path)][&method].clone();
+        let id = format!("{}V1", op["operationId"].as_str().unwrap());
+        op["operationId"] = json!(id);
+        op["x-api-version"] = json!("v1");
+        op["x-frozen"] = json!(true);
+        paths[format!("/api/v1{}", path)][&method] = op;
+    }
+
+    // With `auth.private`, routes with a token scope need one, or admin.
+    for (path, ops) in paths.as_object_mut().unwrap().iter_mut() {
+        let (Some(scope), Some(get)) = (Scope::of(path), ops.get_mut("get")) else {
+            continue;
+        };
+        get["x-token-scope"] = json!(scope);
+        get["security"] =
+            json!([{}, { "viewerToken": [] }, { "adminToken": [] }, { "session": [] }]);
+        get["responses"]["401"] = json_response(
+            "With `auth.private`, no admin, nor a token with this route's scope",
+            "ApiError",
+        );
+    }
+    paths
+}
+
+/// A route's path, as OpenAPI writes it: `/ingest/<device>` is `/ingest/{device}`.
+fn openapi_path(path: &str) -> String {
+    path.replace('<', "{").replace('>', "}")
+}
+
+/// The app's health, and the machine's.
+fn system_paths() -> Value {
+    json!({
+        "/api/changes": {
+            "get": {
+                "summary": "Sensors whose reading or status changed after a reading",
+                "description": "Only sensors whose reading changed by at least its \
+                    precision, or whose status or error changed, after reading `since_seq`, \
+                    with the latest `seq` to pass next time. A reading is compared with the \
+                    one last reported as a change, so slow drift shows. Changes are tracked \
+                    since the app started: a `since_seq` from before then, or past the \
+                    latest, gets every sensor, with `complete`. Send the weak `ETag` back \
+                    in `If-None-Match`, with the same `since_seq`, for a 304 until \
+                    something changes.",
+                "operationId": "getChanges",
+                "parameters": [
+                    {
+                        "name": "since_seq",
+                        "in": "query",
+                        "required": true,
+                        "schema": { "type": "integer" },
+                    },
+                    {
+                        "name": "If-None-Match",
+                        "in": "header",
+                        "schema": { "type": "string" },
+                    },
+                ],
+                "responses": {
+                    "200": json_response("The changed sensors", "Changes"),
+                    "304": { "description": "Nothing's changed since the ETag sent" },
+                    "400": json_response("There's no `since_seq`", "ApiError"),
+                    "503": json_response("There are no readings yet", "ApiError"),
+                },
+            },
+        },
+        "/api/debug/memory": {
+            "get": {
+                "summary": "In-process buffers against their caps, and the process's memory",
+                "description": "Each buffer's length, cap, high-water mark since startup, \
+                    and entries dropped at the cap, with its policy: `drop_oldest` for logs \
+                    and windows, `drop_newest` for queues to a stuck writer, and `refuse` \
+                    for CoAP observers. Buffers kept per exporter, channel or sensor give \
+                    the longest. The resident size is from `/proc/self/status`, and null \
+                    elsewhere.",
+                "operationId": "getMemory",
+                "responses": {
+                    "200": json_response("Buffers and resident size", "Memory"),
+                },
+            },
+        },
+        "/api/device/indicator": {
+            "get": {
+                "summary": "What the Water Monitor's LED and buzzer show",
+                "description": "With `indicator.enabled`, red while an alert is active or a \
+                    reading is critical, blinking blue while readings are stale, amber for a \
+                    warning or an error, and green otherwise.",
+                "operationId": "getIndicator",
+                "responses": {
+                    "200": json_response("The indicator", "Indicator"),
+                },
+            },
+            "post": {
+                "summary": "Set the indicator by hand for a while, or follow alerts again",
+                "description": "`{\"color\": \"blue\", \"pattern\": \"blink\", \"minutes\": 5}` \
+                    sets it for up to `indicator.max_override_mins`, default 15 minutes, then \
+                    it follows alerts again. `{\"auto\": true}` does so at once.",
+                "operationId": "setIndicator",
+                "security": [{ "adminToken": [] }, { "session": [] }],
+                "requestBody": {
+                    "required": true,
+                    "content": {
+                        "application/json": {
+                            "schema": {
+                                "type": "object",
+                                "properties": {
+                                    "auto": { "type": "boolean" },
+                                    "color": { "$ref": "#/components/schemas/IndicatorColor" },
+                                    "pattern": { "$ref": "#/components/schemas/IndicatorPattern" },
+                                    "buzzer": { "type": "boolean" },
+                                    "minutes": { "type": "integer" },
+                                },
+                            },
+                        },
+                    },
+                },
+                "responses": {
+                    "200": json_response("The indicator", "Indicator"),
+                    "400": json_response("Invalid setting", "ApiError"),
+                    "404": json_response("`indicator.enabled` isn't set", "ApiError"),
+                    "409": json_response("The interlock blocks setting it by hand", "ApiError"),
+                },
+            },
+        },
+        "/api/readings/refresh": {
+            "post": {
+                "summary": "Poll now, and return the readings",
+                "description": "Wakes the poller, and waits up to 10 s for its cycle. With \
+                    `debug=true`, the readings have a `trace` of the cycle's serial \
+                    transactions: the bytes sent and received, in hex, the time to write, \
+                    send and read each, and how each sensor's slot decoded. Readings \
+                    responses have no CRC, so there's no check to show. One traced refresh \
+                    at a time.",
+                "operationId": "refreshReadings",
+                "security": [{ "adminToken": [] }, { "session": [] }],
+                "parameters": [query_param("debug", "boolean", "Include a trace of the serial transactions. Default false.")],
+                "responses": {
+                    "200": {
+                        "description": "The fresh readings, keyed as in `/api/readings`",
+                        "content": { "application/json": { "schema": {
+                            "allOf": [
+                                { "$ref": "#/components/schemas/Readings" },
+                                { "type": "object", "properties": {
+                                    "trace": { "$ref": "#/components/schemas/SerialTrace" },
+                                } },
+                            ],
+                        } } },
+                    },
+                    "409": json_response("A traced refresh is already running", "ApiError"),
+                    "503": json_response("The poller didn't finish a cycle in time", "ApiError"),
+                },
+            },
+        },
+        "/api/support-bundle": {
+            "get": {
+                "summary": "Download a bundle of diagnostics, for a support request",
+                "description": "A zip with `manifest.json`, listing its files, the settings \
+                    redacted, and anything left out and why; `config.toml`, the config in \
+                    effect, with secrets redacted; `config-problems.txt`; `events.json`; \
+                    `serial.json`, the serial link stats and the last readings requests and \
+                    responses, in hex; `selfcheck.json`; `system.json`, the app's version, \
+                    the OS and resources; `ports.json`, the serial ports found; and \
+                    `history.csv`, the last 24 hours of history. Exporter and notification \
+                    channel URLs are kept. Needs admin. `water-mon-app --support-bundle` \
+                    writes the same, without a server.",
+                "operationId": "getSupportBundle",
+                "security": [{ "adminToken": [] }, { "session": [] }],
+                "responses": {
+                    "200": {
+                        "description": "Support bundle",
+                        "content": { "application/zip": {} },
+                    },
+                    "401": json_response("Missing or wrong admin token", "ApiError"),
+                    "403": json_response("No admin token is configured", "ApiError"),
+                    "500": json_response("Problem making the bundle", "ApiError"),
+                },
+            },
+        },
+        "/api/devices": {
+            "get": {
+                "summary": "How often each other device is fetched, or pushes, and when it was last heard from",
+                "description": "Each device from the config's `[[devices]]`. Devices with a \
+                    `url` are fetched one at a time; when several are due, the highest \
+                    `priority` goes first. One that times out 3 times in a row is `demoted`, \
+                    and only retried every 60s until it responds. Devices with a `token` push \
+                    their readings to `/api/ingest/{device}`.",
+                "operationId": "getDevices",
+                "responses": {
+                    "200": {
+                        "description": "Devices, by `sort_order`, then in config order",
+                        "content": {
+                            "application/json": {
+                                "schema": {
+                                    "type": "array",
+                                    "items": { "$ref": "#/components/schemas/DeviceSchedule" },
+                                },
+                            },
+                        },
+                    },
+                },
+            },
+        },
+        "/api/ingest/{device}": {
+            "post": {
+                "summary": "Push a device's readings",
+                "description": "For senders that can't run this app, like an ESP32. The \
+                    readings are used as a fetched device's are, in channels and metrics, \
+                    and go stale `interval_secs` plus `status.stale_secs` after `time`. \
+                    They're kept in history, as `/api/history?device=<device>`, and alert \
+                    rules with the device test them. Pushing the latest readings again is \
+                    ignored, as a duplicate.",
+                "operationId": "ingest",
+                "security": [{ "deviceToken": [] }],
+                "parameters": [{
+                    "name": "device",
+                    "in": "path",
+                    "required": true,
+                    "description": "Its name, from `[[devices]]`",
+                    "schema": { "type": "string" },
+                }],
+                "requestBody": {
+                    "required": true,
+                    "content": {
+                        "application/json": {
+                            "schema": { "$ref": "#/components/schemas/Push" },
+                        },
+                    },
+                },
+                "responses": {
+                    "200": {
+                        "description": "Taken, or ignored as a duplicate",
+                        "content": {
+                            "application/json": {
+                                "schema": {
+                                    "type": "object",
+                                    "properties": { "duplicate": { "type": "boolean" } },
+                                },
+                            },
+                        },
+                    },
+                    "400": json_response("Invalid readings, an unknown sensor, a null or a value outside its plausible range, or a time in the future", "ApiError"),
+                    "401": json_response("Missing or wrong token", "ApiError"),
+                    "404": json_response("No such device pushes readings", "ApiError"),
+                    "409": json_response("Older than the latest readings pushed", "ApiError"),
+                },
+            },
+        },
+        "/api/health": {
+            "get": {
+                "summary": "App health",
+                "description": "Includes device connectivity, and watchdog counters.",
+                "operationId": "getHealth",
+                "responses": {
+                    "200": json_response("Health summary", "Health"),
+                },
+            },
+        },
+        "/api/version": {
+            "get": {
+                "summary": "This build's version, git commit, build time and features",
+                "description": "With `updates.check`, `update` has the latest daily check of \
+                    `updates.url` for a newer release. It's off by default. Nothing is \
+                    downloaded or installed.",
+                "operationId": "getVersion",
+                "responses": {
+                    "200": {
+                        "description": "Build info",
+                        "content": { "application/json": { "schema": {
+                            "type": "object",
+                            "properties": {
+                                "version": { "type": "string" },
+                                "git_commit": { "type": "string", "description": "Absent when built without git; `-dirty` with uncommitted changes" },
+                                "built": { "type": "string", "format": "date-time" },
+                                "features": { "type": "array", "items": { "type": "string" } },
+                                "os": { "type": "string" },
+                                "arch": { "type": "string" },
+                                "debug": { "type": "boolean" },
+                                "update": {
+                                    "type": "object",
+                                    "nullable": true,
+                                    "description": "Null until checked, and without `updates.check`",
+                                    "properties": {
+                                        "checked": { "type": "string", "format": "date-time" },
+                                        "latest": { "type": "string" },
+                                        "update_available": { "type": "boolean" },
+                                        "error": { "type": "string" },
+                                    },
+                                },
+                            },
+                        } } },
+                    },
+                },
+            },
+        },
+        "/api/system": {
+            "get": {
+                "summary": "The machine's disk space, memory, CPU temperature and load",
+                "description": "Each is `null` where the OS doesn't say; memory, temperature \
+                    and load are Linux only.",
+                "operationId": "getSystem",
+                "responses": {
+                    "200": json_response("System stats", "SystemStats"),
+                },
+            },
+        },
+    })
+}
+
+fn exporter_paths() -> Value {
+    json!({
+        "/api/exporters/{name}/test": {
+            "post": {
+                "summary": "Send an exporter one request, and return the reply",
+                "description": "For setting up an exporter: its body is filled in from the \
+                    latest readings and sent once, without retries, and the endpoint's status \
+                    and body are returned whatever they are. The exporter's counts aren't \
+                    changed.",
+                "operationId": "testExporter",
+                "security": [{ "adminToken": [] }, { "session": [] }],
+                "parameters": [{
+                    "name": "name",
+                    "in": "path",
+                    "required": true,
+                    "description": "As in `[[exporters]]`",
+                    "schema": { "type": "string" },
+                }],
+                "responses": {
+                    "200": json_response("What was sent, and the reply", "ExporterTest"),
+                    "401": json_response("Missing or wrong admin token", "ApiError"),
+                    "403": json_response("No admin token is configured", "ApiError"),
+                    "404": json_response("No such exporter", "ApiError"),
+                    "502": json_response("The endpoint couldn't be reached", "ApiError"),
+                },
+            },
+        },
+    })
+}
+
+fn alert_paths() -> Value {
+    json!({
+        "/api/alerts": {
+            "get": {
+                "summary": "Each alert rule, and its conditions' states",
+                "description": "As of the last poll cycle. Each condition, down to each \
+                    threshold and trend, says whether it's met, and the reading or rate it \
+                    was tested on, for debugging a rule that won't fire.",
+                "operationId": "getAlerts",
+                "responses": {
+                    "200": {
+                        "description": "Alerts",
+                        "content": { "application/json": { "schema": {
+                            "type": "array",
+                            "items": { "$ref": "#/components/schemas/AlertState" },
+                        } } },
+                    },
+                },
+            },
+        },
+        "/api/alerts/rules": {
+            "get": {
+                "summary": "The alert rules",
+                "operationId": "getAlertRules",
+                "responses": {
+                    "200": {
+                        "description": "The `[[alerts]]` settings",
+                        "content": { "application/json": { "schema": {
+                            "type": "array",
+                            "items": { "$ref": "#/components/schemas/AlertRule" },
+                        } } },
+                    },
+                },
+            },
+            "post": {
+                "summary": "Add an alert rule",
+                "description": "Saved to the config file, and evaluated from the next poll \
+                    cycle. Values without a `unit` are in the locale's; they're kept in the \
+                    registry's, so a later change of locale doesn't change them. Returns every \
+                    rule.",
+                "operationId": "addAlertRule",
+                "security": [{ "adminToken": [] }, { "session": [] }],
+                "requestBody": {
+                    "required": true,
+                    "content": { "application/json": { "schema": { "$ref": "#/components/schemas/AlertRule" } } },
+                },
+                "responses": {
+                    "200": {
+                        "description": "The rules",
+                        "content": { "application/json": { "schema": {
+                            "type": "array",
+                            "items": { "$ref": "#/components/schemas/AlertRule" },
+                        } } },
+                    },
+                    "400": json_response("Invalid rule", "ApiError"),
+                    "401": json_response("Missing or wrong admin token", "ApiError"),
+                    "403": json_response("No admin token is configured", "ApiError"),
+                    "409": json_response("A rule with that name exists", "ApiError"),
+                },
+            },
+        },
+        "/api/alerts/rules/{name}": {
+            "delete": {
+                "summary": "Remove an alert rule",
+                "operationId": "deleteAlertRule",
+                "security": [{ "adminToken": [] }, { "session": [] }],
+                "parameters": [{
+                    "name": "name",
+                    "in": "path",
+                    "required": true,
+                    "schema": { "type": "string" },
+                }],
+                "responses": {
+                    "200": {
+                        "description": "The remaining rules",
+                        "content": { "application/json": { "schema": {
+                            "type": "array",
+                            "items": { "$ref": "#/components/schemas/AlertRule" },
+                        } } },
+                    },
+                    "401": json_response("Missing or wrong admin token", "ApiError"),
+                    "403": json_response("No admin token is configured", "ApiError"),
+                    "404": json_response("No such rule", "ApiError"),
+                },
+            },
+        },
+    })
+}
+
+fn notify_paths() -> Value {
+    json!({
+        "/api/notify/routes": {
+            "get": {
+                "summary": "Where events are sent",
+                "description": "The notification channels, routes, default and alert rule \
+                    overrides, and in `effective`, the channels each known category's events \
+                    go to at each severity.",
+                "operationId": "getNotifyRoutes",
+                "responses": {
+                    "200": json_response("The routing", "NotifyRoutes"),
+                },
+            },
+        },
+        "/api/notify/test": {
+            "post": {
+                "summary": "Route a synthetic event, and deliver it",
+                "description": "Returns each channel the event is routed to, and if \
+                    delivery worked. With `dry_run`, nothing is sent. With `channel`, the \
+                    event goes to that channel alone, eg to test-fire an ntfy, Gotify or \
+                    Pushover preset.",
+                "operationId": "testNotify",
+                "security": [{ "adminToken": [] }, { "session": [] }],
+                "parameters": [
+                    query_param("dry_run", "boolean", "Only route the event."),
+                    query_param("channel", "string", "A channel to deliver to, instead of routing the event."),
+                ],
+                "requestBody": {
+                    "required": true,
+                    "content": { "application/json": { "schema": {
+                        "type": "object",
+                        "required": ["category", "severity"],
+                        "properties": {
+                            "category": { "type": "string", "description": "An event source, eg `watchdog`" },
+                            "severity": { "type": "string", "enum": ["Info", "Warning", "Alert"] },
+                            "message": { "type": "string" },
+                            "rule": { "type": "string", "description": "An alert rule, to test its `notify`" },
+                        },
+                    } } },
+                },
+                "responses": {
+                    "200": {
+                        "description": "Each channel, and if delivery worked",
+                        "content": { "application/json": { "schema": {
+                            "type": "array",
+                            "items": {
+                                "type": "object",
+                                "properties": {
+                                    "channel": { "type": "string" },
+                                    "delivered": { "type": "boolean", "nullable": true, "description": "Null for a dry run" },
+                                    "error": { "type": "string" },
+                                },
+                            },
+                        } } },
+                    },
+                    "400": json_response("Invalid event", "ApiError"),
+                    "401": json_response("Missing or wrong admin token", "ApiError"),
+                    "403": json_response("No admin token is configured", "ApiError"),
+                },
+            },
+        },
+        "/api/notify/verify": {
+            "post": {
+                "summary": "Check a signed request's signature",
+                "description": "For debugging a receiver of signed requests. With \
+                    `signing.secret` set, exporters and notification channels with `sign` \
+                    send `X-Signature-Timestamp`, the Unix time in seconds, and \
+                    `X-Signature`, `sha256=` and the hex HMAC-SHA256 of the timestamp, a `.` \
+                    and the raw body. Receivers should reject timestamps more than \
+                    `signing.max_age_secs` from their clock, as replays. A receiver, in \
+                    Python:\n\n\
+                    ```python\n\
+                    import hashlib, hmac, time\n\n\
+                    def verify(secret: bytes, body: bytes, timestamp: str, signature: str, max_age=300):\n\
+                    \x20   if abs(time.time() - int(timestamp)) > max_age:\n\
+                    \x20       return False\n\
+                    \x20   mac = hmac.new(secret, timestamp.encode() + b\".\" + body, hashlib.sha256)\n\
+                    \x20   return hmac.compare_digest(\"sha256=\" + mac.hexdigest(), signature)\n\
+                    ```",
+                "operationId": "verifySignature",
+                "security": [{ "adminToken": [] }, { "session": [] }],
+                "requestBody": {
+                    "required": true,
+                    "content": { "application/json": { "schema": {
+                        "type": "object",
+                        "required": ["body", "timestamp", "signature"],
+                        "properties": {
+                            "body": { "type": "string", "description": "The raw body, as received" },
+                            "timestamp": { "type": "integer", "description": "`X-Signature-Timestamp`" },
+                            "signature": { "type": "string", "description": "`X-Signature`" },
+                        },
+                    } } },
+                },
+                "responses": {
+                    "200": {
+                        "description": "If the signature matches, and the timestamp is recent",
+                        "content": { "application/json": { "schema": {
+                            "type": "object",
+                            "properties": {
+                                "valid": { "type": "boolean" },
+                                "age_secs": { "type": "integer", "description": "Negative if ahead of our clock" },
+                                "fresh": { "type": "boolean", "description": "Within `signing.max_age_secs`" },
+                                "hint": { "type": "string", "description": "What may be wrong, if anything" },
+                            },
+                        } } },
+                    },
+                    "400": json_response("Invalid request", "ApiError"),
+                    "401": json_response("Missing or wrong admin token", "ApiError"),
+                    "403": json_response("No admin token is configured", "ApiError"),
+                    "409": json_response("`signing.secret` isn't set", "ApiError"),
+                },
+            },
+        },
+    })
+}
+
+fn maintenance_paths() -> Value {
+    let admin_errors = json!({
+        "401": json_response("Missing or wrong admin token", "ApiError"),
+        "403": json_response("No admin token is configured", "ApiError"),
+    });
+    let mut start_responses = json!({
+        "200": json_response("Now in maintenance", "Maintenance"),
+        "400": json_response("Invalid duration", "ApiError"),
+        "409": json_response("Already in maintenance", "ApiError"),
+    });
+    extend(&mut start_responses, admin_errors.clone());
+    let mut stop_responses = json!({
+        "200": json_response("Maintenance stopped", "Maintenance"),
+        "409": json_response("Not in maintenance", "ApiError"),
+    });
+    extend(&mut stop_responses, admin_errors);
+
+    json!({
+        "/api/maintenance": {
+            "get": {
+                "summary": "Maintenance state, and past windows",
+                "operationId": "getMaintenance",
+                "responses": {
+                    "200": json_response("The state", "Maintenance"),
+                },
+            },
+        },
+        "/api/maintenance/start": {
+            "post": {
+                "summary": "Start maintenance, eg while cleaning probes",
+                "description": "Until it's stopped or expires, alert rules aren't evaluated, \
+                    only maintenance events are sent to notification channels, and samples \
+                    are flagged in history, and left out of stats and baselines. The window \
+                    is noted in history.",
+                "operationId": "startMaintenance",
+                "security": [{ "adminToken": [] }, { "session": [] }],
+                "parameters": [
+                    query_param("duration", "string", "Up to a day, eg `30m`, the default, or `2h`."),
+                    query_param("note", "string", "What's being done, for history."),
+                ],
+                "responses": start_responses,
+            },
+        },
+        "/api/maintenance/stop": {
+            "post": {
+                "summary": "End maintenance early",
+                "operationId": "stopMaintenance",
+                "security": [{ "adminToken": [] }, { "session": [] }],
+                "responses": stop_responses,
+            },
+        },
+    })
+}
+
+fn interlock_paths() -> Value {
+    let admin_errors = json!({
+        "401": json_response("Missing or wrong admin token", "ApiError"),
+        "403": json_response("No admin token is configured", "ApiError"),
+    });
+    let mut lockout_responses = json!({
+        "200": json_response("Locked out", "Interlock"),
+        "409": json_response("Already locked out", "ApiError"),
+        "500": json_response("The lockout couldn't be saved", "ApiError"),
+    });
+    extend(&mut lockout_responses, admin_errors.clone());
+    let mut release_responses = json!({
+        "200": json_response("Released", "Interlock"),
+        "409": json_response("Not locked out", "ApiError"),
+        "500": json_response("The lockout couldn't be removed", "ApiError"),
+    });
+    extend(&mut release_responses, admin_errors);
+
+    json!({
+        "/api/interlock": {
+            "get": {
+                "summary": "The safety interlock's conditions, and what each actuator is blocked by",
+                "description": "Every actuator, such as the indicator's buzzer, consults the \
+                    interlock before acting. Each is blocked by its own conditions, and all \
+                    are blocked while the interlock's inputs are `unavailable`.",
+                "operationId": "getInterlock",
+                "responses": {
+                    "200": json_response("The interlock", "Interlock"),
+                },
+            },
+        },
+        "/api/interlock/lockout": {
+            "post": {
+                "summary": "Lock out actuators until released",
+                "description": "The lockout is saved, so it lasts across restarts.",
+                "operationId": "lockOut",
+                "security": [{ "adminToken": [] }, { "session": [] }],
+                "parameters": [query_param("note", "string", "Why, eg `servicing the pump`.")],
+                "responses": lockout_responses,
+            },
+        },
+        "/api/interlock/release": {
+            "post": {
+                "summary": "Release the lockout",
+                "operationId": "releaseLockout",
+                "security": [{ "adminToken": [] }, { "session": [] }],
+                "responses": release_responses,
+            },
+        },
+    })
+}
+
+fn device_paths() -> Value {
+    let admin_errors = json!({
+        "401": json_response("Missing or wrong admin token", "ApiError"),
+        "403": json_response("No admin token is configured", "ApiError"),
+    });
+    let mut meta_responses = json!({
+        "200": json_response("Set", "DeviceMeta"),
+        "400": json_response("Invalid metadata", "ApiError"),
+        "404": json_response("No such device", "ApiError"),
+        "500": json_response("The metadata couldn't be saved", "ApiError"),
+    });
+    extend(&mut meta_responses, admin_errors.clone());
+    let mut purge_responses = json!({
+        "200": {
+            "description": "Purged; the remaining archived devices",
+            "content": {
+                "application/json": {
+                    "schema": {
+                        "type": "array",
+                        "items": { "$ref": "#/components/schemas/ArchivedDevice" },
+                    },
+                },
+            },
+        },
+        "404": json_response("No such archived device", "ApiError"),
+        "409": json_response("The device is still configured", "ApiError"),
+        "500": json_response("The metadata couldn't be saved", "ApiError"),
+    });
+    extend(&mut purge_responses, admin_errors);
+    let name = json!({
+        "name": "name",
+        "in": "path",
+        "required": true,
+        "description": "A `[[devices]]` name, or `local` for this one",
+        "schema": { "type": "string" },
+    });
+
+    json!({
+        "/api/devices/{name}/meta": {
+            "put": {
+                "summary": "Set a device's label, location, color and sort order",
+                "description": "Fields left out are cleared. They're in `/api/devices`, \
+                    history responses, StatsD tags with `metrics.tags`, and notifications, \
+                    whose titles use this device's label.",
+                "operationId": "setDeviceMeta",
+                "security": [{ "adminToken": [] }, { "session": [] }],
+                "parameters": [name.clone()],
+                "requestBody": {
+                    "required": true,
+                    "content": {
+                        "application/json": {
+                            "schema": { "$ref": "#/components/schemas/DeviceMeta" },
+                        },
+                    },
+                },
+                "responses": meta_responses,
+            },
+        },
+        "/api/devices/archived": {
+            "get": {
+                "summary": "Metadata of devices removed from the config",
+                "description": "Kept until purged with `DELETE /api/devices/{name}`.",
+                "operationId": "getArchivedDevices",
+                "responses": {
+                    "200": {
+                        "description": "Archived devices",
+                        "content": {
+                            "application/json": {
+                                "schema": {
+                                    "type": "array",
+                                    "items": { "$ref": "#/components/schemas/ArchivedDevice" },
+                                },
+                            },
+                        },
+                    },
+                },
+            },
+        },
+        "/api/devices/{name}": {
+            "delete": {
+                "summary": "Purge an archived device's metadata",
+                "operationId": "purgeDevice",
+                "security": [{ "adminToken": [] }, { "session": [] }],
+                "parameters": [name],
+                "responses": purge_responses,
+            },
+        },
+    })
+}
+
+fn validation_hook_paths() -> Value {
+    json!({
+        "/api/validation-hook": {
+            "get": {
+                "summary": "The validation hook's settings and counts, and its last verdicts",
+                "description": "With `validation_hook.url` set, each cycle's readings are \
+                    posted to it, and it can accept, replace, annotate or reject each value \
+                    before it's cached, recorded or tested by alerts. If it times out or \
+                    fails, `on_failure` decides: `open` uses the readings as they are, and \
+                    `closed` rejects them.",
+                "operationId": "getValidationHook",
+                "responses": {
+                    "200": json_response("The hook", "ValidationHook"),
+                },
+            },
+        },
+    })
+}
+
+fn input_paths() -> Value {
+    json!({
+        "/api/flow": {
+            "get": {
+                "summary": "The flow sensor's rate, and volume per day",
+                "operationId": "getFlow",
+                "responses": {
+                    "200": json_response("The flow", "Flow"),
+                    "404": json_response("No flow sensor is enabled", "ApiError"),
+                },
+            },
+        },
+        "/api/inputs": {
+            "get": {
+                "summary": "Binary inputs' states, like float switches",
+                "operationId": "getInputs",
+                "responses": {
+                    "200": {
+                        "description": "Each configured input",
+                        "content": {
+                            "application/json": {
+                                "schema": { "type": "array", "items": { "$ref": "#/components/schemas/InputState" } },
+                            },
+                        },
+                    },
+                },
+            },
+        },
+        "/api/inputs/{name}/changes": {
+            "get": {
+                "summary": "An input's changes of state, from history",
+                "operationId": "getInputChanges",
+                "parameters": [
+                    {
+                        "name": "name",
+                        "in": "path",
+                        "required": true,
+                        "description": "As in `[[inputs]]`",
+                        "schema": { "type": "string" },
+                    },
+                    query_param("from", "string", "Start time: RFC 3339, a local time without an offset, or a date for its local midnight. Default: a day before `to`."),
+                    query_param("to", "string", "End time, exclusive, in the same formats. Default: now."),
+                    query_param("tz", "string", "IANA timezone for local input times, and returned times. Default: `time.timezone`."),
+                ],
+                "responses": {
+                    "200": {
+                        "description": "Oldest first, up to 10000",
+                        "content": {
+                            "application/json": {
+                                "schema": { "type": "array", "items": { "$ref": "#/components/schemas/InputChange" } },
+                            },
+                        },
+                    },
+                    "400": json_response("Invalid times", "ApiError"),
+                    "404": json_response("No such input, or history is disabled", "ApiError"),
+                },
+            },
+        },
+    })
+}
+
+fn simulate_paths() -> Value {
+    json!({
+        "/api/simulate": {
+            "get": {
+                "summary": "The scenario replaying in place of the Water Monitor",
+                "operationId": "getSimulation",
+                "responses": {
+                    "200": json_response("Where it's up to", "Simulation"),
+                    "404": json_response("No scenario is loaded", "ApiError"),
+                },
+            },
+        },
+        "/api/simulate/scenario": {
+            "post": {
+                "summary": "Replay a scenario in place of the Water Monitor",
+                "description": "For testing: the poller talks to a simulated serial port, \
+                    answering from the scenario's timeline, from its start, with its \
+                    faults. Its readings are recorded, alerted on and exported like real \
+                    ones. The body is JSON, or TOML.",
+                "operationId": "loadScenario",
+                "security": [{ "adminToken": [] }, { "session": [] }],
+                "requestBody": {
+                    "required": true,
+                    "content": {
+                        "application/json": { "schema": { "$ref": "#/components/schemas/Scenario" } },
+                    },
+                },
+                "responses": {
+                    "200": json_response("Replaying", "Simulation"),
+                    "400": json_response("Invalid scenario", "ApiError"),
+                    "401": json_response("Missing or wrong admin token", "ApiError"),
+                    "403": json_response("No admin token is configured", "ApiError"),
+                },
+            },
+            "delete": {
+                "summary": "Stop the scenario, and go back to the Water Monitor",
+                "operationId": "stopScenario",
+                "security": [{ "adminToken": [] }, { "session": [] }],
+                "responses": {
+                    "200": json_response("Where it was up to", "Simulation"),
+                    "401": json_response("Missing or wrong admin token", "ApiError"),
+                    "403": json_response("No admin token is configured", "ApiError"),
+                    "404": json_response("No scenario is loaded", "ApiError"),
+                },
+            },
+        },
+    })
+}
+
+fn setup_paths() -> Value {
+    json!({
+        "/api/setup/status": {
+            "get": {
+                "summary": "What the app can be set up with, on first run",
+                "operationId": "getSetup",
+                "responses": {
+                    "200": json_response("In setup mode", "SetupStatus"),
+                    "404": json_response("Already set up", "ApiError"),
+                },
+            },
+        },
+        "/api/setup": {
+            "post": {
+                "summary": "Write the config file, and leave setup mode",
+                "description": "Only with no config file, so it needs no password. Each \
+                    field is optional. The app carries on with the new config, without a \
+                    restart.",
+                "operationId": "setUp",
+                "requestBody": {
+                    "required": true,
+                    "content": {
+                        "application/json": { "schema": { "$ref": "#/components/schemas/Setup" } },
+                    },
+                },
+                "responses": {
+                    "200": { "description": "Set up" },
+                    "400": json_response("Invalid setup", "ApiError"),
+                    "404": json_response("Already set up", "ApiError"),
+                    "500": json_response("Problem writing the config file", "ApiError"),
+                },
+            },
+        },
+    })
+}
+
+fn token_paths() -> Value {
+    let tokens = json!({
+        "description": "The tokens",
+        "content": { "application/json": { "schema": {
+            "type": "array",
+            "items": { "$ref": "#/components/schemas/Token" },
+        } } },
+    });
+    json!({
+        "/api/tokens": {
+            "get": {
+                "summary": "Scoped read-only tokens",
+                "description": "Without the tokens themselves, which are only kept hashed, \
+                    and with when each was last used, to within a minute.",
+                "operationId": "getTokens",
+                "security": [{ "adminToken": [] }, { "session": [] }],
+                "responses": {
+                    "200": tokens,
+                    "401": json_response("Missing or wrong admin token", "ApiError"),
+                    "403": json_response("No admin token is configured", "ApiError"),
+                },
+            },
+            "post": {
+                "summary": "Make a scoped read-only token",
+                "description": "With `auth.private` set, a token lets the operations with \
+                    its scopes in their `x-token-scope`, `readings`, `history` or `stats`, be \
+                    read without admin, eg by a pool service. It's sent as `?token=`, so a \
+                    URL can be shared, or as a bearer token. Limited to `devices`, it only \
+                    works if they include `local`, whose data these routes serve. The token \
+                    is only returned now.",
+                "operationId": "createToken",
+                "security": [{ "adminToken": [] }, { "session": [] }],
+                "requestBody": {
+                    "required": true,
+                    "content": { "application/json": { "schema": {
+                        "type": "object",
+                        "required": ["name"],
+                        "properties": {
+                            "name": { "type": "string", "description": "Who it's for" },
+                            "scopes": {
+                                "type": "array",
+                                "items": { "type": "string", "enum": ["readings", "history", "stats"] },
+                                "description": "Default all",
+                            },
+                            "devices": {
+                                "type": "array",
+                                "items": { "type": "string" },
+                                "description": "`local`, or `[[devices]]` names. Default any",
+                            },
+                            "expires": { "type": "string", "description": "A time or date, as in `/api/history`. Default never" },
+                        },
+                    } } },
+                },
+                "responses": {
+                    "200": {
+                        "description": "The token",
+                        "content": { "application/json": { "schema": {
+                            "allOf": [
+                                { "$ref": "#/components/schemas/Token" },
+                                {
+                                    "type": "object",
+                                    "properties": {
+                                        "token": { "type": "string" },
+                                        "url": { "type": "string", "description": "The readings, with the token, to share" },
+                                    },
+                                },
+                            ],
+                        } } },
+                    },
+                    "400": json_response("Invalid token", "ApiError"),
+                    "401": json_response("Missing or wrong admin token", "ApiError"),
+                    "403": json_response("No admin token is configured", "ApiError"),
+                },
+            },
+        },
+        "/api/tokens/{id}": {
+            "delete": {
+                "summary": "Revoke a scoped token",
+                "operationId": "deleteToken",
+                "security": [{ "adminToken": [] }, { "session": [] }],
+                "parameters": [{
+                    "name": "id",
+                    "in": "path",
+                    "required": true,
+                    "schema": { "type": "string" },
+                }],
+                "responses": {
+                    "200": tokens,
+                    "401": json_response("Missing or wrong admin token", "ApiError"),
+                    "403": json_response("No admin token is configured", "ApiError"),
+                    "404": json_response("No such token", "ApiError"),
+                },
+            },
+        },
+    })
+}
+
+/// Renderings of the readings for people, and displays.
+fn display_paths() -> Value {
+    json!({
+        "/api/readings/text": {
+            "get": {
+                "summary": "Latest readings as plain text",
+                "description": "One sensor per line, with its status. Numbers and units \
+                    follow the locale: `de-DE` gives `24,5 °C`, and `en-US`, `76.1 °F`.",
+                "operationId": "getReadingsText",
+                "parameters": [query_param("locale", "string", "Language tag, eg `de-DE`. Default: `locale.language`.")],
+                "responses": {
+                    "200": {
+                        "description": "Latest readings",
+                        "content": { "text/plain": { "schema": { "type": "string" } } },
+                    },
+                    "400": json_response("Invalid locale", "ApiError"),
+                },
+            },
+        },
+        "/api/snapshot.png": {
+            "get": {
+                "summary": "Latest readings, rendered as a PNG",
+                "description": "For displays that only show images. Each sensor has a row, \
+                    marked with its status color; sensors in error show a dash and the \
+                    error. A footer says when the readings are from, or that they're stale. \
+                    Numbers and units follow `locale.language`.",
+                "operationId": "getSnapshot",
+                "parameters": [
+                    query_param("width", "integer", "In pixels, from 64 to 2000. Default: 600."),
+                    query_param("height", "integer", "In pixels, from 64 to 2000. Default: 300."),
+                    query_param("theme", "string", "`light` (default) or `dark`."),
+                ],
+                "responses": {
+                    "200": {
+                        "description": "The image",
+                        "content": { "image/png": { "schema": { "type": "string", "format": "binary" } } },
+                    },
+                    "400": json_response("Invalid size or theme", "ApiError"),
+                },
+            },
+        },
+        "/api/export.parquet": {
+            "get": {
+                "summary": "History as a Parquet file",
+                "description": "Columns: `time`, a UTC timestamp in milliseconds; `count`, \
+                    the samples the row stands for, which is 1 for raw samples and more for \
+                    compacted ones; and a nullable float per sensor, null where there's no \
+                    valid reading. Compacted rows have their averages.",
+                "operationId": "exportParquet",
+                "parameters": [
+                    query_param("from", "string", "Start time, in the same formats as `/api/history`. Default: the start of history."),
+                    query_param("to", "string", "End time, exclusive. Default: now."),
+                    query_param("tz", "string", "IANA timezone for local input times. Default: `time.timezone`."),
+                    query_param("device", "string", "A device that pushes its readings, for its history. Default: `local`, this one."),
+                ],
+                "responses": {
+                    "200": {
+                        "description": "The file",
+                        "content": { "application/vnd.apache.parquet": { "schema": { "type": "string", "format": "binary" } } },
+                    },
+                    "400": json_response("Invalid parameters", "ApiError"),
+                    "404": json_response("History is disabled, or the device's isn't kept", "ApiError"),
+                },
+            },
+        },
+        "/api/export.csv": {
+            "get": {
+                "summary": "History as CSV, with a row for each outage",
+                "description": "Columns: `time`, in UTC; `count`, the samples the row stands \
+                    for; a value per sensor, empty where there's no valid reading; and \
+                    `outage_end` and `outage_cause`, set on outage rows. An outage is a \
+                    stretch without samples that buffered samples didn't fill, after the \
+                    app restarted (`app_down`) or while it couldn't read the Water Monitor \
+                    (`device_unreachable`), or while history was paused for a full disk \
+                    (`storage_full`); its row's `time` is when it started, and its \
+                    `count` is 0. A pushing device's outages aren't tracked.",
+                "operationId": "exportCsv",
+                "parameters": [
+                    query_param("from", "string", "Start time, in the same formats as `/api/history`. Default: the start of history."),
+                    query_param("to", "string", "End time, exclusive. Default: now."),
+                    query_param("tz", "string", "IANA timezone for local input times. Default: `time.timezone`."),
+                    query_param("device", "string", "A device that pushes its readings, for its history. Default: `local`, this one."),
+                ],
+                "responses": {
+                    "200": {
+                        "description": "The file",
+                        "content": { "text/csv": { "schema": { "type": "string" } } },
+                    },
+                    "400": json_response("Invalid parameters", "ApiError"),
+                    "404": json_response("History is disabled, or the device's isn't kept", "ApiError"),
+                },
+            },
+        },
+    })
+}
+
+fn schemas() -> Value {
+    let mut schemas = json!({
+        "SensorError": {
+            "type": "string",
+            "description": "`BadMeasurement`: the Water Monitor flagged this reading as \
+                invalid, without saying why. `NotConnected`: no reading has been taken \
+                from the Water Monitor. `ProbeDisconnected`: an open circuit at the \
+                probe. `OutOfRange`: the signal is outside the ADC's range. \
+                `NotStabilized`: the probe hasn't settled yet; a sensor with an earlier \
+                reading keeps that instead. `FrontendFault`: the \
+                Water Monitor couldn't reach the sensor's circuitry. `Rejected`: the \
+                validation hook rejected the reading. `Unknown(n)`: a status the app \
+                doesn't know, eg `Unknown(9)`.",
+        },
+        "SensorErrorDetail": {
+            "type": "object",
+            "properties": {
+                "error": { "$ref": "#/components/schemas/SensorError" },
+                "code": { "type": "integer", "description": "As in the Modbus and SNMP status registers" },
+                "hint": { "type": "string", "description": "What to do about it" },
+            },
+        },
+        "Reading": {
+            "description": "Either a value, or the reason there isn't one.",
+            "oneOf": [
+                {
+                    "type": "object",
+                    "properties": { "Ok": { "type": "number", "format": "float" } },
+                    "required": ["Ok"],
+                },
+                {
+                    "type": "object",
+                    "properties": { "Err": { "$ref": "#/components/schemas/SensorError" } },
+                    "required": ["Err"],
+                },
+            ],
+        },
+        "Readings": {
+            "type": "object",
+            "properties": {
+                "T": reading("Temperature, in °C"),
+                "pH": reading("pH"),
+                "ORP": reading("ORP, in mV"),
+                "ec": reading("Electrical conductivity, in S/cm"),
+                "DO": reading("Dissolved oxygen, in mg/L. Absent unless the hardware has it."),
+                "flow": reading("Flow rate, in L/min. Absent unless `flow.enabled`."),
+                "status": {
+                    "type": "object",
+                    "description": "Each sensor's status against its target range",
+                    "properties": {
+                        "T": { "$ref": "#/components/schemas/SensorStatus" },
+                        "pH": { "$ref": "#/components/schemas/SensorStatus" },
+                        "ORP": { "$ref": "#/components/schemas/SensorStatus" },
+                        "ec": { "$ref": "#/components/schemas/SensorStatus" },
+                        "DO": { "$ref": "#/components/schemas/SensorStatus" },
+                        "flow": { "$ref": "#/components/schemas/SensorStatus" },
+                    },
+                },
+                "errors": {
+                    "type": "object",
+                    "description": "Each sensor in error, with its code, and what to do about it",
+                    "additionalProperties": { "$ref": "#/components/schemas/SensorErrorDetail" },
+                },
+                "updated_at": {
+                    "type": "object",
+                    "description": "When each sensor last had a fresh reading. A sensor that's \
+                        not stabilized keeps its last reading, and is `stale` once that's older \
+                        than `status.stale_secs`.",
+                    "additionalProperties": { "type": "string", "format": "date-time" },
+                },
+                "stabilizing": {
+                    "type": "object",
+                    "description": "Each sensor warming up after power-up, whose readings \
+                        aren't tested by alert rules or counted in stats yet",
+                    "additionalProperties": {
+                        "type": "object",
+                        "properties": {
+                            "elapsed_secs": { "type": "number" },
+                            "remaining_secs": { "type": "number", "description": "At most; it may settle sooner" },
+                            "std_dev": { "type": "number", "description": "Of readings over `window_secs`, once there are a few" },
+                        },
+                    },
+                },
+                "quality": {
+                    "type": "object",
+                    "description": "Flags the validation hook attached, by sensor; absent without any",
+                    "additionalProperties": { "$ref": "#/components/schemas/Quality" },
+                },
+                "maintenance": {
+                    "description": "Only while in maintenance",
+                    "allOf": [{ "$ref": "#/components/schemas/MaintenanceStatus" }],
+                },
+                "inputs": {
+                    "type": "object",
+                    "description": "Each binary input's state, by name, once read; absent without inputs",
+                    "additionalProperties": { "type": "boolean" },
+                },
+                "seq": {
+                    "type": "integer",
+                    "nullable": true,
+                    "description": "Increases with each successful reading, across restarts, \
+                        which can skip ahead; null after a failed poll cycle",
+                },
+                "instance_id": { "type": "string", "format": "uuid" },
+            },
+            "required": ["T", "pH", "ORP", "ec", "status", "instance_id"],
+        },
+        "CycleSamples": {
+            "type": "object",
+            "properties": {
+                "samples": {
+                    "type": "array",
+                    "items": { "$ref": "#/components/schemas/RawReadings" },
+                },
+                "result": {
+                    "description": "Each sensor's median",
+                    "allOf": [{ "$ref": "#/components/schemas/RawReadings" }],
+                },
+            },
+        },
+        "RawReadings": {
+            "type": "object",
+            "description": "Readings, without statuses",
+            "properties": {
+                "T": { "$ref": "#/components/schemas/Reading" },
+                "pH": { "$ref": "#/components/schemas/Reading" },
+                "ORP": { "$ref": "#/components/schemas/Reading" },
+                "ec": { "$ref": "#/components/schemas/Reading" },
+                "DO": { "$ref": "#/components/schemas/Reading" },
+                "flow": { "$ref": "#/components/schemas/Reading" },
+            },
+        },
+        "SensorStatus": {
+            "type": "string",
+            "enum": ["ok", "warn", "critical", "error", "stale", "no_flow", "stabilizing", "rejected"],
+            "description": "`no_flow` is for sensors in `flow.gates`, while the flow sensor shows \
+                no flow. `stabilizing` is for probes warming up after power-up, per `[warmup]`. \
+                `rejected` is for readings the validation hook rejected.",
+        },
+        "History": {
+            "type": "object",
+            "properties": {
+                "from": { "type": "string", "format": "date-time" },
+                "to": { "type": "string", "format": "date-time" },
+                "tz": { "type": "string", "description": "The timezone times are given in" },
+                "device": {
+                    "$ref": "#/components/schemas/DeviceMeta",
+                    "description": "This device's metadata",
+                },
+                "gaps": {
+                    "type": "array",
+                    "description": "Stretches without samples, including at either end of the range",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "start": { "type": "string", "format": "date-time" },
+                            "end": { "type": "string", "format": "date-time" },
+                            "secs": { "type": "number" },
+                        },
+                    },
+                },
+                "samples": {
+                    "type": "array",
+                    "description": "Without `bucket`. Sensor values are null for errors. With \
+                        `shape=columns`, an object of arrays instead, with `ts` for `time`.",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "time": { "type": "string", "format": "date-time" },
+                            "maintenance": { "type": "boolean", "description": "Taken during maintenance; left out of buckets" },
+                            "stabilizing": {
+                                "type": "array",
+                                "items": { "type": "string" },
+                                "description": "Sensors that were warming up, whose values are left out of buckets",
+                            },
+                            "T": { "type": "number", "nullable": true },
+                            "pH": { "type": "number", "nullable": true },
+                            "ORP": { "type": "number", "nullable": true },
+                            "ec": { "type": "number", "nullable": true },
+                            "DO": { "type": "number", "nullable": true },
+                            "flow": { "type": "number", "nullable": true },
+                        },
+                    },
+                },
+                "bucket_secs": { "type": "number", "description": "With `bucket`" },
+                "buckets": {
+                    "type": "array",
+                    "description": "With `bucket`. With `shape=columns`, an object of arrays \
+                        instead, with `ts` for `start`, and an array per stat per sensor.",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "start": { "type": "string", "format": "date-time" },
+                            "count": { "type": "integer" },
+                            "interpolated": {
+                                "type": "boolean",
+                                "description": "Present, and true, if filled by `interpolate`",
+                            },
+                            "T": { "$ref": "#/components/schemas/BucketStats" },
+                            "pH": { "$ref": "#/components/schemas/BucketStats" },
+                            "ORP": { "$ref": "#/components/schemas/BucketStats" },
+                            "ec": { "$ref": "#/components/schemas/BucketStats" },
+                            "DO": { "$ref": "#/components/schemas/BucketStats" },
+                            "flow": { "$ref": "#/components/schemas/BucketStats" },
+                        },
+                    },
+                },
+            },
+        },
+        "BucketStats": {
+            "type": "object",
+            "description": "The stats requested with `agg`",
+            "properties": {
+                "avg": { "type": "number", "nullable": true },
+                "min": { "type": "number", "nullable": true },
+                "max": { "type": "number", "nullable": true },
+            },
+        },
+        "SlowRequests": {
+            "type": "object",
+            "properties": {
+                "enabled": { "type": "boolean" },
+                "requests": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "time": { "type": "string", "format": "date-time" },
+                            "method": { "type": "string" },
+                            "path": { "type": "string" },
+                            "status": { "type": "integer" },
+                            "client_ip": { "type": "string", "nullable": true },
+                            "duration_ms": { "type": "number" },
+                        },
+                    },
+                },
+            },
+        },
+        "SerialStats": {
+            "type": "object",
+            "properties": {
+                "seconds_recorded": { "type": "number" },
+                "transactions": { "type": "integer" },
+                "bytes_written": { "type": "integer" },
+                "bytes_read": { "type": "integer" },
+                "timeouts": { "type": "integer" },
+                "crc_failures": { "type": "integer" },
+                "io_errors": { "type": "integer" },
+                "resyncs": {
+                    "type": "integer",
+                    "description": "Times the response stream got out of step, eg from a dropped byte",
+                },
+                "resync_bytes_skipped": { "type": "integer" },
+                "queue_depth": {
+                    "type": "integer",
+                    "description": "Requests queued for the flight controller now",
+                },
+                "queue_max_depth": { "type": "integer" },
+                "queue_rejected": {
+                    "type": "integer",
+                    "description": "Requests refused because the queue was full",
+                },
+                "queue_expired": {
+                    "type": "integer",
+                    "description": "Requests dropped unsent, since their callers had stopped waiting",
+                },
+                "window_transactions": { "type": "integer" },
+                "window_success_rate": { "type": "number", "nullable": true },
+                "latency_p50_ms": { "type": "number", "nullable": true },
+                "latency_p95_ms": { "type": "number", "nullable": true },
+                "latency_p99_ms": { "type": "number", "nullable": true },
+            },
+        },
+        "SelfTest": {
+            "type": "object",
+            "properties": {
+                "pass": { "type": "boolean" },
+                "round_trip": {
+                    "type": "object",
+                    "properties": {
+                        "cases": { "type": "integer" },
+                        "failures": { "type": "array", "items": { "type": "string" } },
+                    },
+                },
+                "link": {
+                    "type": "object",
+                    "nullable": true,
+                    "description": "Null if the Water Monitor isn't connected",
+                    "properties": {
+                        "responded": { "type": "boolean" },
+                        "ok": { "type": "boolean" },
+                        "latency_ms": { "type": "number", "nullable": true },
+                    },
+                },
+            },
+        },
+        "Health": {
+            "type": "object",
+            "properties": {
+                "version": { "type": "string" },
+                "update_available": { "type": "string", "description": "A newer release, eg `v1.2.0`, with `updates.check`; absent otherwise" },
+                "instance": {
+                    "type": "object",
+                    "properties": {
+                        "id": { "type": "string", "format": "uuid", "description": "Made on first run, and kept" },
+                        "name": { "type": "string", "description": "`instance.name`, or else the hostname" },
+                    },
+                },
+                "addresses": {
+                    "type": "array",
+                    "items": { "type": "string", "format": "uri" },
+                    "description": "URLs other devices on the network can likely open the app at",
+                },
+                "activated_sockets": {
+                    "type": "array",
+                    "items": { "type": "string" },
+                    "description": "The sockets systemd passed, with socket activation",
+                },
+                "poller": {
+                    "type": "object",
+                    "properties": {
+                        "connected": { "type": "boolean" },
+                        "consecutive_failures": { "type": "integer" },
+                        "seconds_since_success": { "type": "number", "nullable": true },
+                        "watchdog_trips": { "type": "integer" },
+                        "failed_reopens": { "type": "integer" },
+                    },
+                },
+                "polling": {
+                    "type": "object",
+                    "description": "With `polling.adaptive`, the poller slows down while no clients are watching",
+                    "properties": {
+                        "mode": { "type": "string", "enum": ["fast", "idle"] },
+                        "interval_ms": { "type": "integer" },
+                        "seconds_since_activity": { "type": "number", "nullable": true },
+                    },
+                },
+                "components": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "name": { "type": "string" },
+                            "restarts": { "type": "integer" },
+                            "seconds_since_restart": { "type": "number", "nullable": true },
+                            "restarted_recently": { "type": "boolean" },
+                        },
+                    },
+                },
+                "history_check": {
+                    "allOf": [{ "$ref": "#/components/schemas/VerifyReport" }],
+                    "nullable": true,
+                    "description": "The latest history check: the quick one at startup, or a later verify",
+                },
+                "reliability_24h_pct": {
+                    "type": "number",
+                    "nullable": true,
+                    "description": "Percent of the last day's poll cycles with fully valid readings",
+                },
+                "resources": {
+                    "type": "object",
+                    "properties": {
+                        "history_paused": { "type": "boolean", "description": "History isn't stored while the disk is nearly full" },
+                        "skipped_samples": { "type": "integer" },
+                        "warning": { "type": "string" },
+                    },
+                },
+                "exporters": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "name": { "type": "string" },
+                            "queued": { "type": "integer" },
+                            "sent": { "type": "integer", "description": "Readings sent" },
+                            "failures": { "type": "integer", "description": "Requests that failed after retries" },
+                            "dropped": { "type": "integer", "description": "Readings not sent, from failed requests or a full queue" },
+                            "last_error": { "type": "string" },
+                        },
+                    },
+                },
+                "metrics": {
+                    "type": "object",
+                    "description": "StatsD or Graphite pushes",
+                    "properties": {
+                        "pushes": { "type": "integer" },
+                        "failures": { "type": "integer" },
+                        "last_error": { "type": "string" },
+                    },
+                },
+                "maintenance": {
+                    "nullable": true,
+                    "description": "Null unless in maintenance",
+                    "allOf": [{ "$ref": "#/components/schemas/MaintenanceStatus" }],
+                },
+                "interlock": { "$ref": "#/components/schemas/InterlockStatus" },
+            },
+        },
+        "SystemStats": {
+            "type": "object",
+            "properties": {
+                "disk": {
+                    "type": "object",
+                    "nullable": true,
+                    "description": "The disk holding the history database",
+                    "properties": {
+                        "free_bytes": { "type": "integer" },
+                        "total_bytes": { "type": "integer" },
+                    },
+                },
+                "database_bytes": { "type": "integer", "nullable": true },
+                "memory": {
+                    "type": "object",
+                    "nullable": true,
+                    "properties": {
+                        "total_bytes": { "type": "integer" },
+                        "available_bytes": { "type": "integer" },
+                    },
+                },
+                "cpu_temp_c": { "type": "number", "nullable": true },
+                "load_average": {
+                    "type": "array",
+                    "nullable": true,
+                    "items": { "type": "number" },
+                    "description": "Over 1, 5 and 15 minutes",
+                },
+            },
+        },
+        "Event": {
+            "type": "object",
+            "properties": {
+                "time": { "type": "string", "format": "date-time" },
+                "severity": { "type": "string", "enum": ["Info", "Warning", "Alert"] },
+                "source": { "type": "string" },
+                "message": { "type": "string" },
+                "rule": { "type": "string", "description": "The alert rule that raised it" },
+                "request_id": { "type": "string", "description": "The API request being handled when it was raised" },
+            },
+        },
+        "ModbusMap": {
+            "type": "object",
+            "properties": {
+                "enabled": { "type": "boolean" },
+                "port": { "type": "integer" },
+                "word_order": { "type": "string", "enum": ["high_first", "low_first"] },
+                "functions": {
+                    "type": "array",
+                    "items": { "type": "integer" },
+                    "description": "Function codes that read the map",
+                },
+                "registers": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "address": { "type": "integer", "description": "0-based" },
+                            "name": { "type": "string" },
+                            "type": { "type": "string", "enum": ["float32", "uint16"] },
+                            "description": { "type": "string" },
+                        },
+                    },
+                },
+            },
+        },
+        "Storage": {
+            "type": "object",
+            "properties": {
+                "path": { "type": "string" },
+                "file_bytes": { "type": "integer", "description": "Database and WAL file sizes" },
+                "used_bytes": { "type": "integer", "description": "Bytes in pages holding data" },
+                "max_bytes": { "type": "integer", "nullable": true },
+                "max_age_days": { "type": "integer", "nullable": true },
+                "tiers": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "name": { "type": "string" },
+                            "rows": { "type": "integer" },
+                            "oldest": { "type": "string", "format": "date-time", "nullable": true },
+                            "newest": { "type": "string", "format": "date-time", "nullable": true },
+                        },
+                    },
+                },
+                "last_prune": {
+                    "allOf": [{ "$ref": "#/components/schemas/PruneReport" }],
+                    "nullable": true,
+                },
+                "dry_run": { "$ref": "#/components/schemas/PruneReport" },
+            },
+        },
+        "PruneReport": {
+            "type": "object",
+            "properties": {
+                "time": { "type": "string", "format": "date-time" },
+                "dry_run": { "type": "boolean" },
+                "by_age": { "type": "array", "items": { "$ref": "#/components/schemas/TierRows" } },
+                "by_size": { "type": "array", "items": { "$ref": "#/components/schemas/TierRows" } },
+                "used_bytes_before": { "type": "integer" },
+                "used_bytes_after": { "type": "integer", "description": "Estimated, for a dry run" },
+            },
+        },
+        "TierRows": {
+            "type": "object",
+            "properties": {
+                "tier": { "type": "string" },
+                "rows": { "type": "integer" },
+            },
+        },
+        "VerifyReport": {
+            "type": "object",
+            "properties": {
+                "time": { "type": "string", "format": "date-time" },
+                "quick": { "type": "boolean", "description": "The startup check, which skips some index checks" },
+                "ok": { "type": "boolean" },
+                "integrity": {
+                    "type": "array",
+                    "items": { "type": "string" },
+                    "description": "SQLite's messages; `[\"ok\"]` if it passed",
+                },
+                "problems": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "table": { "type": "string" },
+                            "kind": { "type": "string", "description": "Eg `time_backwards`, `pH_implausible` or `misaligned`" },
+                            "rows": { "type": "integer" },
+                            "examples": { "type": "array", "items": { "type": "string", "format": "date-time" } },
+                        },
+                    },
+                },
+                "quarantined": { "type": "integer" },
+            },
+        },
+        "RestoreResult": {
+            "type": "object",
+            "properties": {
+                "created": { "type": "string", "format": "date-time" },
+                "restored_history": { "type": "boolean" },
+                "restored_config": { "type": "boolean" },
+            },
+        },
+        "ExporterTest": {
+            "type": "object",
+            "properties": {
+                "request_body": { "type": "string" },
+                "response": {
+                    "type": "object",
+                    "properties": {
+                        "status": { "type": "integer" },
+                        "status_line": { "type": "string" },
+                        "body": { "type": "string", "description": "Cut off at 64 KiB" },
+                    },
+                },
+            },
+        },
+        "ConfigReload": {
+            "type": "object",
+            "properties": {
+                "applied": {
+                    "type": "array",
+                    "items": { "type": "string" },
+                    "description": "Changed settings, as dotted paths, now in effect",
+                },
+                "needs_restart": {
+                    "type": "array",
+                    "items": { "type": "string" },
+                    "description": "Changed settings that apply after a restart",
+                },
+            },
+        },
+        "SelfCheck": {
+            "type": "object",
+            "properties": {
+                "checked": { "type": "string", "format": "date-time" },
+                "checks": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "name": { "type": "string" },
+                            "outcome": { "type": "string", "enum": ["pass", "warn", "fail"] },
+                            "detail": { "type": "string" },
+                            "fix": { "type": "string", "description": "For warnings and failures" },
+                        },
+                    },
+                },
+            },
+        },
+        "ApiError": {
+            "type": "object",
+            "properties": {
+                "error": { "type": "string", "description": "Human-readable description" },
+                "request_id": {
+                    "type": "string",
+                    "description": "As in `X-Request-Id`; find its events with `/api/events?request_id=`",
+                },
+            },
+            "required": ["error"],
+        },
+    });
+    extend(&mut schemas, sensor_schemas());
+    extend(&mut schemas, stats_schemas());
+    extend(&mut schemas, alert_schemas());
+    extend(&mut schemas, hardware_schemas());
+    extend(&mut schemas, device_schemas());
+    extend(&mut schemas, validation_hook_schemas());
+    if cfg!(feature = "flight-controller") {
+        extend(&mut schemas, fc_schemas());
+    }
+    let v1 = snake_case_readings(&schemas["Readings"]);
+    schemas["ReadingsV1"] = v1;
+    schemas
+}
+
+/// `Readings`, with sensors keyed in snake case, as in `/api/v1`.
+fn snake_case_readings(readings: &Value) -> Value {
+    let rename = |props: &mut Value| {
+        if let Some(props) = props.as_object_mut() {
+            for sensor in REGISTRY.iter() {
+                if let Some(v) = props.remove(sensor.id) {
+                    props.insert(sensor.key.into(), v);
+                }
+            }
+        }
+    };
+    let mut result = readings.clone();
+    rename(&mut result["properties"]);
+    rename(&mut result["properties"]["status"]["properties"]);
+    result
+}
+
+/// Inputs, the flow sensor, and the simulated Water Monitor. Separate since `json!` can
+/// only nest so deep.
+fn hardware_schemas() -> Value {
+    json!({
+        "InterlockStatus": {
+            "type": "object",
+            "properties": {
+                "engaged": { "type": "boolean", "description": "If any condition holds" },
+                "conditions": {
+                    "type": "array",
+                    "items": {
+                        "type": "string",
+                        "enum": ["unavailable", "sensor_error", "stale_data", "maintenance", "no_flow", "lockout"],
+                    },
+                },
+                "since": { "type": "string", "description": "When the conditions last changed" },
+                "lockout": {
+                    "type": "object",
+                    "description": "Absent unless locked out",
+                    "properties": {
+                        "since": { "type": "string" },
+                        "note": { "type": "string" },
+                    },
+                },
+            },
+        },
+        "Interlock": {
+            "allOf": [
+                { "$ref": "#/components/schemas/InterlockStatus" },
+                {
+                    "type": "object",
+                    "properties": {
+                        "actuators": {
+                            "type": "array",
+                            "items": {
+                                "type": "object",
+                                "properties": {
+                                    "name": { "type": "string", "description": "Eg `indicator.buzzer`" },
+                                    "blocked_by": { "type": "array", "items": { "type": "string" } },
+                                },
+                            },
+                        },
+                    },
+                },
+            ],
+        },
+        "Memory": {
+            "type": "object",
+            "properties": {
+                "rss_bytes": { "type": "integer", "nullable": true },
+                "peak_rss_bytes": { "type": "integer", "nullable": true },
+                "buffers": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "name": { "type": "string", "example": "history.writes" },
+                            "about": { "type": "string" },
+                            "len": { "type": "integer" },
+                            "cap": { "type": "integer" },
+                            "high_water": { "type": "integer" },
+                            "dropped": { "type": "integer" },
+                            "policy": { "type": "string", "enum": ["drop_oldest", "drop_newest", "refuse"] },
+                        },
+                    },
+                },
+            },
+        },
+        "SetupStatus": {
+            "type": "object",
+            "properties": {
+                "setup": { "type": "boolean" },
+                "ports": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "name": { "type": "string" },
+                            "water_monitor": { "type": "boolean", "description": "By its USB serial number" },
+                        },
+                    },
+                },
+                "profiles": { "type": "array", "items": { "type": "string" } },
+                "default_locale": { "type": "string" },
+            },
+        },
+        "Setup": {
+            "type": "object",
+            "properties": {
+                "port": { "type": "string", "description": "If unset, the Water Monitor is found by its USB serial number" },
+                "locale": { "type": "string" },
+                "profile": { "type": "string", "enum": ["pool", "spa", "aquarium", "hydroponics"] },
+                "password": { "type": "string" },
+            },
+        },
+        "Flow": {
+            "type": "object",
+            "properties": {
+                "rate": { "type": "number", "nullable": true, "description": "L/min; null until the second poll cycle" },
+                "no_flow": { "type": "boolean", "description": "Below `flow.min_lpm`" },
+                "today_liters": { "type": "number", "description": "Since local midnight" },
+                "days": {
+                    "type": "array",
+                    "description": "Most recent first, from history, with today as last saved",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "date": { "type": "string", "format": "date" },
+                            "liters": { "type": "number" },
+                        },
+                    },
+                },
+                "error": { "type": "string", "description": "Why pulses can't be counted" },
+            },
+        },
+        "InputState": {
+            "type": "object",
+            "properties": {
+                "name": { "type": "string" },
+                "pin": { "type": "integer", "description": "GPIO number" },
+                "active": { "type": "boolean", "nullable": true, "description": "After `inverted`; null until first read" },
+                "since": { "type": "string", "format": "date-time", "description": "When it last changed" },
+                "error": { "type": "string", "description": "Why the pin can't be read" },
+            },
+        },
+        "Simulation": {
+            "type": "object",
+            "properties": {
+                "name": { "type": "string" },
+                "elapsed_secs": { "type": "number" },
+                "segment": { "type": "integer", "description": "The segment playing, from 0" },
+                "done": { "type": "boolean", "description": "Past the last segment, without `repeat`; it's held" },
+                "responses": { "type": "integer", "description": "Readings requests answered" },
+            },
+        },
+        "Scenario": {
+            "type": "object",
+            "required": ["segments"],
+            "properties": {
+                "name": { "type": "string" },
+                "repeat": { "type": "boolean", "description": "Start over after the last segment, rather than holding it" },
+                "extended": { "type": "boolean", "description": "Answer extended readings requests, with `DO`" },
+                "segments": { "type": "array", "items": { "$ref": "#/components/schemas/ScenarioSegment" } },
+            },
+        },
+        "ScenarioSegment": {
+            "type": "object",
+            "required": ["secs"],
+            "properties": {
+                "secs": { "type": "number" },
+                "values": {
+                    "type": "object",
+                    "description": "By sensor; others keep their value from the segment before",
+                    "additionalProperties": { "type": "number" },
+                },
+                "ramps": {
+                    "type": "object",
+                    "description": "`[from, to]` by sensor, in a straight line over the segment",
+                    "additionalProperties": { "type": "array", "items": { "type": "number" } },
+                },
+                "errors": {
+                    "type": "object",
+                    "description": "By sensor, eg `ProbeDisconnected`, or a status byte",
+                    "additionalProperties": { "type": "string" },
+                },
+                "disconnected": { "type": "boolean", "description": "The device can't be found" },
+                "silent": { "type": "boolean", "description": "Requests aren't answered" },
+                "short_reads": { "type": "boolean", "description": "Responses lose their second half" },
+                "corrupt": { "type": "boolean", "description": "A byte of each response is flipped" },
+            },
+        },
+        "InputChange": {
+            "type": "object",
+            "properties": {
+                "time": { "type": "string", "format": "date-time" },
+                "active": { "type": "boolean" },
+            },
+        },
+        "IndicatorColor": { "type": "string", "enum": ["off", "green", "amber", "red", "blue"] },
+        "IndicatorPattern": { "type": "string", "enum": ["solid", "blink", "pulse"] },
+        "Indicator": {
+            "type": "object",
+            "properties": {
+                "enabled": { "type": "boolean" },
+                "supported": {
+                    "type": "boolean",
+                    "nullable": true,
+                    "description": "If the firmware echoed the command; null until tried since connecting",
+                },
+                "mode": { "type": "string", "enum": ["auto", "manual"] },
+                "state": {
+                    "type": "object",
+                    "description": "What it shows, or will from the next poll cycle",
+                    "properties": {
+                        "color": { "$ref": "#/components/schemas/IndicatorColor" },
+                        "pattern": { "$ref": "#/components/schemas/IndicatorPattern" },
+                        "buzzer": { "type": "boolean" },
+                    },
+                },
+                "until": { "type": "string", "format": "date-time", "description": "When a manual setting ends" },
+            },
+        },
+        "SerialTrace": {
+            "type": "object",
+            "properties": {
+                "transactions": {
+                    "type": "array",
+                    "description": "Up to 32, one per readings request in the cycle",
+                    "items": { "$ref": "#/components/schemas/SerialTransaction" },
+                },
+                "omitted": { "type": "integer", "description": "Transactions past the limit" },
+                "error": { "type": "string", "description": "Why the cycle failed, if it did" },
+                "duration_ms": { "type": "number" },
+            },
+        },
+        "SerialTransaction": {
+            "type": "object",
+            "properties": {
+                "attempt": { "type": "integer", "description": "From 1; a cycle takes `polling.samples_per_cycle`" },
+                "skipped_bytes": { "type": "integer", "description": "Left from an earlier response, cleared first" },
+                "request": { "type": "string", "description": "Hex" },
+                "response": { "type": "string", "description": "Hex; as much as arrived, up to 256 bytes" },
+                "truncated": { "type": "boolean" },
+                "write_us": { "type": "number" },
+                "flush_us": { "type": "number", "description": "Waiting for the request to be sent" },
+                "read_us": { "type": "number" },
+                "outcome": { "type": "string", "enum": ["Ok", "Timeout", "CrcFailure", "IoError"] },
+                "error": { "type": "string" },
+                "fields": {
+                    "type": "array",
+                    "description": "Each sensor's slot, for a complete response",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "sensor": { "type": "string" },
+                            "offset": { "type": "integer" },
+                            "bytes": { "type": "string", "description": "Hex: a status byte, then a big-endian f32" },
+                            "value": { "$ref": "#/components/schemas/Reading" },
+                        },
+                    },
+                },
+            },
+        },
+    })
+}
+
+/// Sensor metadata, and the settings behind it. Separate since `json!` can only nest so
+/// deep.
+fn device_schemas() -> Value {
+    json!({
+        "DeviceMeta": {
+            "type": "object",
+            "properties": {
+                "label": { "type": "string", "maxLength": 64, "description": "Eg `Reef Tank`" },
+                "location": { "type": "string", "maxLength": 128 },
+                "color": { "type": "string", "pattern": "^#[0-9a-fA-F]{6}$", "description": "Lowercased" },
+                "sort_order": { "type": "integer", "description": "Lower first; default 0" },
+            },
+        },
+        "ArchivedDevice": {
+            "allOf": [
+                { "$ref": "#/components/schemas/DeviceMeta" },
+                {
+                    "type": "object",
+                    "properties": { "name": { "type": "string" } },
+                },
+            ],
+        },
+    })
+}
+
+fn validation_hook_schemas() -> Value {
+    json!({
+        "Quality": {
+            "type": "object",
+            "properties": {
+                "flag": { "type": "string", "description": "Eg `suspect`" },
+                "reason": { "type": "string" },
+            },
+        },
+        "ValidationHook": {
+            "type": "object",
+            "properties": {
+                "enabled": { "type": "boolean", "description": "If `validation_hook.url` is set" },
+                "timeout_ms": { "type": "integer" },
+                "on_failure": { "type": "string", "enum": ["open", "closed"] },
+                "failing": { "type": "boolean", "description": "If the last request failed" },
+                "cycles": { "type": "integer", "description": "Cycles sent to the hook" },
+                "accepted": { "type": "integer" },
+                "replaced": { "type": "integer" },
+                "annotated": { "type": "integer" },
+                "rejected": { "type": "integer" },
+                "failures": { "type": "integer", "description": "Requests that timed out or failed" },
+                "last_latency_ms": { "type": "integer", "nullable": true },
+                "last_error": { "type": "string" },
+                "quality": {
+                    "type": "object",
+                    "description": "The last cycle's flags, by sensor id",
+                    "additionalProperties": { "$ref": "#/components/schemas/Quality" },
+                },
+                "rejections": {
+                    "type": "object",
+                    "description": "The last cycle's rejections, by sensor id, with the hook's reasons",
+                    "additionalProperties": { "type": "string", "nullable": true },
+                },
+            },
+        },
+    })
+}
+
+fn sensor_schemas() -> Value {
+    json!({
+        "Score": {
+            "type": "object",
+            "properties": {
+                "score": { "type": "integer", "minimum": 0, "maximum": 100, "nullable": true, "description": "Null if no weighted sensor has a reading" },
+                "rating": { "type": "string", "enum": ["good", "fair", "poor"], "nullable": true },
+                "color": { "type": "string", "nullable": true, "description": "Eg `#1e8e3e`" },
+                "components": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "sensor": { "type": "string" },
+                            "status": { "$ref": "#/components/schemas/SensorStatus" },
+                            "weight_pct": { "type": "number" },
+                            "points": { "type": "integer", "description": "Taken off the score; 0 or negative" },
+                            "reason": { "type": "string", "description": "Eg `ORP below range`" },
+                        },
+                    },
+                },
+            },
+        },
+        "Sensor": {
+            "type": "object",
+            "properties": {
+                "name": { "type": "string", "enum": ["T", "pH", "ORP", "ec", "DO", "flow"] },
+                "kind": {
+                    "type": "string",
+                    "enum": ["temperature", "ph", "orp", "conductivity", "dissolved_oxygen", "flow"]
+                },
+                "unit": { "type": "string" },
+                "display_unit": { "type": "string" },
+                "display_scale": { "type": "number" },
+                "plausible": {
+                    "type": "array",
+                    "description": "`[min, max]`; stored values outside it are flagged by verification",
+                    "items": { "type": "number" },
+                    "minItems": 2,
+                    "maxItems": 2,
+                },
+                "target": target_range(),
+                "probe": {
+                    "type": "string",
+                    "enum": ["k0.1", "k1", "k10"],
+                    "description": "EC only",
+                },
+                "raw": {
+                    "description": "EC only: the latest reading, before scaling for the probe",
+                    "allOf": [{ "$ref": "#/components/schemas/Reading" }],
+                },
+            },
+        },
+        "EcSettings": {
+            "type": "object",
+            "properties": {
+                "probe": {
+                    "type": "string",
+                    "enum": ["k0.1", "k1", "k10"],
+                    "description": "The probe's cell constant, K. The Water Monitor's \
+                        conductivity is multiplied by it.",
+                },
+            },
+        },
+        "StatusSettings": {
+            "type": "object",
+            "properties": {
+                "stale_secs": { "type": "integer" },
+                "warn_margin": {
+                    "type": "number",
+                    "description": "How far outside its target a reading is `warn` rather \
+                        than `critical`, as a fraction of the target's width",
+                },
+                "targets": {
+                    "type": "object",
+                    "description": "`[min, max]` per sensor; null for no target, or for EC, \
+                        the probe's default",
+                    "properties": {
+                        "T": target_range(),
+                        "pH": target_range(),
+                        "ORP": target_range(),
+                        "ec": target_range(),
+                        "DO": target_range(),
+                        "flow": target_range(),
+                    },
+                },
+                "units": {
+                    "type": "object",
+                    "description": "Each sensor's targets' unit, eg `°F`. Returned for every \
+                        sensor, in the locale's units; sensors left out of a request are in \
+                        the locale's.",
+                    "additionalProperties": { "type": "string" },
+                },
+            },
+        },
+        "DeviceSchedule": {
+            "type": "object",
+            "properties": {
+                "name": { "type": "string" },
+                "priority": { "type": "integer" },
+                "interval_secs": { "type": "integer", "description": "As configured" },
+                "effective_interval_secs": {
+                    "type": "number",
+                    "nullable": true,
+                    "description": "The mean time between its last 10 fetches",
+                },
+                "source": { "type": "string", "enum": ["fetch", "push"] },
+                "demoted": { "type": "boolean" },
+                "failures": { "type": "integer", "description": "Failed fetches in a row" },
+                "last_seen": {
+                    "type": "string",
+                    "format": "date-time",
+                    "nullable": true,
+                    "description": "When it was last fetched, or when its last pushed readings were taken",
+                },
+                "stale": { "type": "boolean", "description": "If its readings are too old to use" },
+                "label": { "type": "string" },
+                "location": { "type": "string" },
+                "color": { "type": "string" },
+                "sort_order": { "type": "integer" },
+            },
+        },
+        "Push": {
+            "type": "object",
+            "required": ["readings"],
+            "properties": {
+                "time": {
+                    "type": "string",
+                    "description": "When they were taken: RFC 3339, or a local time. Default: when they arrive.",
+                },
+                "readings": {
+                    "type": "object",
+                    "description": "Values by sensor id or snake case key, eg `pH` or `temperature`. Leave out sensors without a reading.",
+                    "additionalProperties": { "type": "number" },
+                },
+            },
+        },
+        "Channel": {
+            "type": "object",
+            "properties": {
+                "name": { "type": "string" },
+                "sensor": { "type": "string", "description": "What it measures" },
+                "unit": { "type": "string" },
+                "merge": { "type": "string", "enum": ["weighted", "median"] },
+                "value": {
+                    "type": "number",
+                    "nullable": true,
+                    "description": "Null if no source has a usable reading",
+                },
+                "status": {
+                    "type": "string",
+                    "enum": ["ok", "warn", "critical", "error"],
+                    "description": "Against the sensor's target",
+                },
+                "sources": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "device": { "type": "string", "description": "`local` is this one" },
+                            "sensor": { "type": "string" },
+                            "weight": { "type": "number" },
+                            "value": { "type": "number", "nullable": true },
+                            "included": { "type": "boolean" },
+                        },
+                    },
+                },
+            },
+        },
+    })
+}
+
+fn alert_schemas() -> Value {
+    json!({
+        "Token": {
+            "type": "object",
+            "properties": {
+                "id": { "type": "string" },
+                "name": { "type": "string" },
+                "scopes": {
+                    "type": "array",
+                    "items": { "type": "string", "enum": ["readings", "history", "stats"] },
+                },
+                "devices": { "type": "array", "items": { "type": "string" }, "description": "Absent if any" },
+                "created": { "type": "string", "format": "date-time" },
+                "expires": { "type": "string", "format": "date-time", "nullable": true },
+                "expired": { "type": "boolean" },
+                "last_used": { "type": "string", "format": "date-time", "nullable": true },
+            },
+        },
+        "NotifyRoutes": {
+            "type": "object",
+            "properties": {
+                "channels": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "name": { "type": "string" },
+                            "kind": { "type": "string", "enum": ["webhook", "ntfy", "gotify", "pushover"] },
+                            "digest_mins": { "type": "integer", "description": "If events are collected into digests, how long each collects for" },
+                        },
+                    },
+                },
+                "default": { "type": "array", "items": { "type": "string" } },
+                "routes": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "category": { "type": "string" },
+                            "severity": { "type": "string", "enum": ["Info", "Warning", "Alert"] },
+                            "channels": { "type": "array", "items": { "type": "string" } },
+                        },
+                    },
+                },
+                "rules": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "rule": { "type": "string" },
+                            "channels": { "type": "array", "items": { "type": "string" } },
+                        },
+                    },
+                },
+                "effective": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "category": { "type": "string" },
+                            "severity": { "type": "string", "enum": ["Info", "Warning", "Alert"] },
+                            "channels": { "type": "array", "items": { "type": "string" } },
+                        },
+                    },
+                },
+            },
+        },
+        "AlertRule": {
+            "type": "object",
+            "required": ["name", "when"],
+            "properties": {
+                "name": { "type": "string" },
+                "device": {
+                    "type": "string",
+                    "description": "A device that pushes its readings, to test them instead of this one's; then the rule can't test inputs, the score or baselines",
+                },
+                "notify": {
+                    "type": "array",
+                    "items": { "type": "string" },
+                    "description": "Notification channels for its events, instead of the routes'",
+                },
+                "between": {
+                    "type": "array",
+                    "items": { "type": "string" },
+                    "description": "Local start and end times, eg `[\"22:00\", \"06:00\"]`, which spans midnight",
+                },
+                "for_mins": { "type": "integer", "description": "Only fire once the condition has held this long, up to 1440" },
+                "critical": { "type": "boolean", "description": "Send its events at once, even to channels with digests" },
+                "when": { "$ref": "#/components/schemas/AlertCondition" },
+            },
+        },
+        "AlertCondition": {
+            "type": "object",
+            "description": "Exactly one of `all`, `any`, `below`, `above`, `rising`, \
+                `falling`, `stable`, `baseline` or `input`. Rates are per hour, fitted \
+                over `window_mins` of readings.",
+            "properties": {
+                "sensor": { "type": "string" },
+                "below": { "type": "number" },
+                "above": { "type": "number" },
+                "rising": { "type": "number", "description": "At least this per hour" },
+                "falling": { "type": "number", "description": "At least this per hour" },
+                "stable": { "type": "number", "description": "Within this per hour, either way" },
+                "baseline": { "$ref": "#/components/schemas/AlertBaseline" },
+                "unit": {
+                    "type": "string",
+                    "description": "Of its values, and a baseline's `low` and `high`, eg `°F`. \
+                        Default over the API: the locale's unit for the sensor. Rules are \
+                        returned in the locale's units, with this set.",
+                },
+                "input": { "type": "string", "description": "A binary input, instead of a sensor" },
+                "active": { "type": "boolean", "description": "The input's state that meets it. Default: true." },
+                "window_mins": { "type": "integer", "description": "For trends, up to 1440. Default: 15." },
+                "all": { "type": "array", "items": { "$ref": "#/components/schemas/AlertCondition" } },
+                "any": { "type": "array", "items": { "$ref": "#/components/schemas/AlertCondition" } },
+            },
+        },
+        "AlertState": {
+            "type": "object",
+            "properties": {
+                "name": { "type": "string" },
+                "active": { "type": "boolean" },
+                "since": { "type": "string", "format": "date-time" },
+                "in_hours": { "type": "boolean", "description": "Within its `between` times, or it has none" },
+                "learning": { "type": "boolean", "description": "A baseline it tests is still being learned" },
+                "when": {
+                    "nullable": true,
+                    "description": "Null until the first poll cycle after the rule was added",
+                    "allOf": [{ "$ref": "#/components/schemas/ConditionState" }],
+                },
+            },
+        },
+        "ConditionState": {
+            "type": "object",
+            "properties": {
+                "condition": { "type": "string", "description": "Eg `ORP below 600`" },
+                "met": { "type": "boolean" },
+                "value": { "type": "number", "description": "The reading, rate per hour, or input's 1 or 0, it was tested on" },
+                "baseline": { "$ref": "#/components/schemas/BaselineState" },
+                "conditions": { "type": "array", "items": { "$ref": "#/components/schemas/ConditionState" } },
+            },
+        },
+        "MaintenanceStatus": {
+            "type": "object",
+            "properties": {
+                "since": { "type": "string", "format": "date-time" },
+                "until": { "type": "string", "format": "date-time" },
+                "remaining_secs": { "type": "integer" },
+                "note": { "type": "string" },
+            },
+        },
+        "Maintenance": {
+            "type": "object",
+            "properties": {
+                "current": {
+                    "nullable": true,
+                    "description": "Null unless in maintenance",
+                    "allOf": [{ "$ref": "#/components/schemas/MaintenanceStatus" }],
+                },
+                "windows": {
+                    "type": "array",
+                    "description": "The latest 50, from history, most recent first",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "start": { "type": "string", "format": "date-time" },
+                            "end": { "type": "string", "format": "date-time" },
+                            "note": { "type": "string" },
+                        },
+                    },
+                },
+            },
+        },
+        "AlertBaseline": {
+            "type": "object",
+            "description": "Outside the band from the `low_pct` to the `high_pct` percentile of \
+                the last `days` of history, widened each side by `margin_pct` of its width. \
+                Relearned nightly.",
+            "properties": {
+                "days": { "type": "integer", "description": "Up to 366. Default: 14." },
+                "low_pct": { "type": "number", "description": "Default: 5." },
+                "high_pct": { "type": "number", "description": "Default: 95." },
+                "margin_pct": { "type": "number", "description": "Default: 10." },
+                "min_days": { "type": "integer", "description": "Learning until history covers this many days. Default: 3." },
+                "low": { "type": "number", "description": "Overrides the learned low edge" },
+                "high": { "type": "number", "description": "Overrides the learned high edge" },
+            },
+        },
+        "BaselineState": {
+            "type": "object",
+            "properties": {
+                "learning": { "type": "boolean", "description": "Too little history yet; only `low` and `high` apply" },
+                "low": { "type": "number", "description": "The band that applies, with its margin and overrides" },
+                "high": { "type": "number" },
+                "learned": { "type": "array", "items": { "type": "number" }, "description": "The learned percentiles, without the margin" },
+                "history_days": { "type": "number" },
+                "computed": { "type": "string", "format": "date-time" },
+            },
+        },
+    })
+}
+
+/// Distributions and comparisons of history. Separate since `json!` can only nest so
+/// deep.
+fn stats_schemas() -> Value {
+    json!({
+        "Changes": {
+            "type": "object",
+            "properties": {
+                "seq": { "type": "integer", "description": "The latest reading's; `since_seq` next time" },
+                "since_seq": { "type": "integer" },
+                "complete": {
+                    "type": "boolean",
+                    "description": "Every sensor is listed, since `since_seq` was from before the app started, or past the latest",
+                },
+                "sensors": {
+                    "type": "object",
+                    "description": "By sensor id",
+                    "additionalProperties": {
+                        "type": "object",
+                        "properties": {
+                            "value": { "type": "number", "nullable": true, "description": "At `[precision]`; null with an error" },
+                            "error": { "type": "string" },
+                            "status": { "type": "string" },
+                            "seq": { "type": "integer", "description": "The reading it changed at" },
+                        },
+                    },
+                },
+            },
+        },
+        "Distribution": {
+            "type": "object",
+            "properties": {
+                "sensor": { "type": "string" },
+                "unit": { "type": "string" },
+                "from": { "type": "string", "format": "date-time" },
+                "to": { "type": "string", "format": "date-time" },
+                "count": { "type": "integer", "description": "Valid samples" },
+                "excluded": { "type": "integer", "description": "Samples without a valid reading, or taken during maintenance" },
+                "edges": {
+                    "type": "array",
+                    "description": "`bins + 1` bin edges; the last bin includes its upper edge",
+                    "items": { "type": "number" },
+                },
+                "counts": { "type": "array", "items": { "type": "integer" } },
+                "below": { "type": "integer", "description": "Samples below the first edge" },
+                "above": { "type": "integer", "description": "Samples above the last edge" },
+                "percentiles": {
+                    "type": "object",
+                    "nullable": true,
+                    "description": "Null without samples",
+                    "properties": {
+                        "p5": { "type": "number" },
+                        "p25": { "type": "number" },
+                        "p50": { "type": "number" },
+                        "p75": { "type": "number" },
+                        "p95": { "type": "number" },
+                    },
+                },
+            },
+        },
+        "Comparison": {
+            "type": "object",
+            "properties": {
+                "range_a": { "$ref": "#/components/schemas/TimeRange" },
+                "range_b": { "$ref": "#/components/schemas/TimeRange" },
+                "tz": { "type": "string" },
+                "sensors": {
+                    "type": "object",
+                    "description": "Per sensor id: `a` and `b`, and `delta` without `count`",
+                    "additionalProperties": {
+                        "type": "object",
+                        "properties": {
+                            "a": { "$ref": "#/components/schemas/RangeStats" },
+                            "b": { "$ref": "#/components/schemas/RangeStats" },
+                            "delta": { "$ref": "#/components/schemas/RangeStats" },
+                        },
+                    },
+                },
+            },
+        },
+        "TimeRange": {
+            "type": "object",
+            "properties": {
+                "from": { "type": "string", "format": "date-time" },
+                "to": { "type": "string", "format": "date-time" },
+            },
+        },
+        "RangeStats": {
+            "type": "object",
+            "description": "Stats are null without samples",
+            "properties": {
+                "count": { "type": "integer" },
+                "mean": {
+                    "type": "number",
+                    "nullable": true,
+                    "description": "Time-weighted: each sample stands for the time until the next, up to the gap threshold",
+                },
+                "arithmetic_mean": { "type": "number", "nullable": true },
+                "min": { "type": "number", "nullable": true },
+                "max": { "type": "number", "nullable": true },
+                "stddev": { "type": "number", "nullable": true, "description": "Time-weighted" },
+                "in_range_pct": {
+                    "type": "number",
+                    "nullable": true,
+                    "description": "Percent of the time covered within the target; null without one",
+                },
+                "coverage": {
+                    "type": "number",
+                    "description": "The share of the range, from 0 to 1, that the samples stand for",
+                },
+                "weighting": {
+                    "type": "string",
+                    "enum": ["time", "samples"],
+                    "description": "`samples` if the samples stand for no time, so the stats are arithmetic",
+                },
+            },
+        },
+        "Session": {
+            "type": "object",
+            "properties": {
+                "logged_in": { "type": "boolean" },
+                "password_set": { "type": "boolean" },
+                "idle_mins": { "type": "integer" },
+            },
+        },
+        "Reliability": {
+            "type": "object",
+            "properties": {
+                "window": { "type": "string" },
+                "cycles": { "type": "integer" },
+                "valid": { "type": "integer", "description": "Cycles with every sensor valid" },
+                "valid_pct": { "type": "number", "nullable": true },
+                "no_device": { "type": "integer", "description": "The Water Monitor wasn't found" },
+                "timeout": { "type": "integer" },
+                "crc": { "type": "integer", "description": "A garbled response" },
+                "io_error": { "type": "integer" },
+                "sensor_errors": {
+                    "type": "object",
+                    "additionalProperties": { "type": "integer" },
+                    "description": "Cycles with an error, per sensor",
+                },
+                "error_kinds": {
+                    "type": "object",
+                    "additionalProperties": { "type": "integer" },
+                    "description": "Sensor errors by kind, eg `ProbeDisconnected`",
+                },
+            },
+        },
+    })
+}
+
+/// Routes of the `flight-controller` feature.
+fn fc_paths() -> Value {
+    json!({
+        "/api/controls": {
+            "get": {
+                "summary": "Latest control channels from the flight controller",
+                "description": "Requested every `flight_controller.controls_refresh_ms` while \
+                    `flight_controller.enabled` is set. If a request fails, the last controls \
+                    stay cached; `seconds_old` says how old they are.",
+                "operationId": "getControls",
+                "responses": {
+                    "200": json_response("Controls", "Controls"),
+                    "404": json_response("Flight controller support is disabled", "ApiError"),
+                    "503": json_response("No controls received yet", "ApiError"),
+                },
+            },
+        },
+        "/api/params": {
+            "get": {
+                "summary": "Latest position, attitude and their rates from the flight controller",
+                "description": "Requested every `flight_controller.params_refresh_ms` while \
+                    `flight_controller.enabled` is set. Frames with the wrong payload length \
+                    are rejected, and the last params stay cached.",
+                "operationId": "getParams",
+                "responses": {
+                    "200": json_response("Params", "Params"),
+                    "404": json_response("Flight controller support is disabled", "ApiError"),
+                    "503": json_response("No params received yet", "ApiError"),
+                },
+            },
+        },
+        "/api/device": {
+            "get": {
+                "summary": "About the flight controller: its clock, and firmware updates",
+                "description": "Its clock is set to ours on connecting, and every \
+                    `flight_controller.clock_sync_mins`.",
+                "operationId": "getDevice",
+                "responses": {
+                    "200": json_response("Device info", "Device"),
+                    "404": json_response("Flight controller support is disabled", "ApiError"),
+                },
+            },
+        },
+        "/api/device/firmware": {
+            "get": {
+                "summary": "The state of the latest firmware update",
+                "operationId": "getFirmwareUpdate",
+                "responses": {
+                    "200": json_response("The update's state", "FirmwareStatus"),
+                    "404": json_response("Flight controller support is disabled", "ApiError"),
+                },
+            },
+            "post": {
+                "summary": "Update the flight controller's firmware",
+                "description": "Checks the image's header and CRC-32, then returns. On its next \
+                    cycle, the poller pauses, checks the image's model and hardware revision \
+                    match the device's, checks the interlock again, and restarts it into its \
+                    DFU bootloader. Flash it \
+                    with the `command` in the status; polling resumes once it's back. Each \
+                    step is recorded as an event.",
+                "operationId": "updateFirmware",
+                "security": [{ "adminToken": [] }, { "session": [] }],
+                "requestBody": {
+                    "required": true,
+                    "content": { "application/octet-stream": {} },
+                },
+                "responses": {
+                    "200": json_response("The image passed its checks, and is queued", "FirmwareStatus"),
+                    "400": json_response("Not a valid image", "ApiError"),
+                    "401": json_response("Missing or wrong admin token", "ApiError"),
+                    "403": json_response("No admin token is configured", "ApiError"),
+                    "404": json_response("Flight controller support is disabled", "ApiError"),
+                    "409": json_response("An update is already in progress, or the interlock blocks `fc.firmware_update`", "ApiError"),
+                    "413": json_response("Image too large", "ApiError"),
+                },
+            },
+            "delete": {
+                "summary": "Cancel the firmware update in progress, and resume polling",
+                "operationId": "cancelFirmwareUpdate",
+                "security": [{ "adminToken": [] }, { "session": [] }],
+                "responses": {
+                    "200": json_response("The update's state", "FirmwareStatus"),
+                    "401": json_response("Missing or wrong admin token", "ApiError"),
+                    "403": json_response("No admin token is configured", "ApiError"),
+                    "404": json_response("Flight controller support is disabled", "ApiError"),
+                    "409": json_response("No update is in progress", "ApiError"),
+                },
+            },
+        },
+        "/api/motors/dirs": {
+            "post": {
+                "summary": "Set the motors' spin directions",
+                "description": "Sent to the flight controller between the poller's other \
+                    requests, then waits up to `flight_controller.ack_timeout_ms` for it to \
+                    acknowledge.",
+                "operationId": "setMotorDirs",
+                "security": [{ "adminToken": [] }, { "session": [] }],
+                "requestBody": {
+                    "required": true,
+                    "content": {
+                        "application/json": {
+                            "schema": { "$ref": "#/components/schemas/MotorDirs" },
+                        },
+                    },
+                },
+                "responses": {
+                    "200": json_response("The directions set", "MotorDirs"),
+                    "400": json_response("Invalid body", "ApiError"),
+                    "401": json_response("Missing or wrong admin token", "ApiError"),
+                    "403": json_response("No admin token is configured", "ApiError"),
+                    "404": json_response("Flight controller support is disabled", "ApiError"),
+                    "409": json_response("The interlock blocks `fc.motor_dirs`", "ApiError"),
+                    "502": json_response("The flight controller responded with something other than an Ack", "ApiError"),
+                    "503": json_response("The flight controller isn't connected, or too many commands are queued", "ApiError"),
+                    "504": json_response("No Ack in time", "ApiError"),
+                },
+            },
+        },
+    })
+}
+
+fn fc_schemas() -> Value {
+    json!({
+        "Device": {
+            "type": "object",
+            "properties": {
+                "clock": {
+                    "type": "object",
+                    "nullable": true,
+                    "description": "The latest clock sync; null before the first",
+                    "properties": {
+                        "last_sync": { "type": "string", "format": "date-time" },
+                        "offset_ms": {
+                            "type": "integer",
+                            "description": "How far ahead of ours its clock was, before we set it",
+                        },
+                        "round_trip_ms": { "type": "number" },
+                        "drift_ppm": { "type": "number", "nullable": true },
+                        "syncs": { "type": "integer" },
+                    },
+                },
+                "firmware": { "$ref": "#/components/schemas/FirmwareStatus" },
+            },
+        },
+        "FirmwareStatus": {
+            "type": "object",
+            "properties": {
+                "stage": {
+                    "type": "string",
+                    "enum": ["idle", "queued", "entering_bootloader", "dfu", "done", "failed", "cancelled"],
+                },
+                "image": {
+                    "type": "object",
+                    "nullable": true,
+                    "description": "The latest upload",
+                    "properties": {
+                        "model": { "type": "string" },
+                        "hardware_revision": { "type": "integer" },
+                        "version": { "type": "string" },
+                        "size": { "type": "integer" },
+                    },
+                },
+                "command": {
+                    "type": "string",
+                    "nullable": true,
+                    "description": "The `dfu-util` command to flash the image, while in DFU mode",
+                },
+                "message": { "type": "string" },
+                "updated": { "type": "string", "format": "date-time", "nullable": true },
+            },
+        },
+        "Controls": {
+            "type": "object",
+            "description": "Switch positions this app doesn't know, eg from newer firmware, \
+                are given as eg `Unknown(3)`.",
+            "properties": {
+                "roll": { "type": "number", "description": "-1 to 1" },
+                "pitch": { "type": "number", "description": "-1 to 1" },
+                "throttle": { "type": "number", "description": "0 to 1, or -1 to 1 if the stick auto-centers" },
+                "yaw": { "type": "number", "description": "-1 to 1" },
+                "arm_status": { "type": "string", "description": "`Disarmed` or `Armed`" },
+                "input_mode": { "type": "string", "description": "`Acro` or `Attitude`" },
+                "alt_hold": { "type": "string", "description": "`Disabled`, `AGL` or `MSL`" },
+                "seconds_old": { "type": "number" },
+            },
+        },
+        "Params": {
+            "type": "object",
+            "description": "Position (`s_`), velocity (`v_`) and acceleration (`a_`), linear \
+                and angular.",
+            "properties": {
+                "s_x": { "type": "number" },
+                "s_y": { "type": "number" },
+                "s_z_msl": { "type": "number" },
+                "s_z_agl": { "type": "number" },
+                "s_pitch": { "type": "number" },
+                "s_roll": { "type": "number" },
+                "s_yaw": { "type": "number" },
+                "v_x": { "type": "number" },
+                "v_y": { "type": "number" },
+                "v_z": { "type": "number" },
+                "v_pitch": { "type": "number" },
+                "v_roll": { "type": "number" },
+                "v_yaw": { "type": "number" },
+                "a_x": { "type": "number" },
+                "a_y": { "type": "number" },
+                "a_z": { "type": "number" },
+                "a_pitch": { "type": "number" },
+                "a_roll": { "type": "number" },
+                "a_yaw": { "type": "number" },
+                "seconds_old": { "type": "number" },
+            },
+        },
+        "MotorDirs": {
+            "type": "object",
+            "properties": {
+                "clockwise": {
+                    "type": "array",
+                    "items": { "type": "boolean" },
+                    "minItems": 4,
+                    "maxItems": 4,
+                    "description": "Motors 1 to 4; true for clockwise",
+                },
+            },
+            "required": ["clockwise"],
+        },
+    })
+}
+
+/// Add the entries of one JSON object to another.
+fn extend(into: &mut Value, from: Value) {
+    if let (Some(into), Value::Object(from)) = (into.as_object_mut(), from) {
+        into.extend(from);
+    }
+}
+
+fn json_response(description: &str, schema: &str) -> Value {
+    json!({
+        "description": description,
+        "content": {
+            "application/json": {
+                "schema": { "$ref": format!("#/components/schemas/{}", schema) },
+            },
+        },
+    })
+}
+
+fn query_param(name: &str, type_: &str, description: &str) -> Value {
+    json!({
+        "name": name,
+        "in": "query",
+        "required": false,
+        "description": description,
+        "schema": { "type": type_ },
+    })
+}
+
+fn qr_path(media_type: &str, operation_id: &str) -> Value {
+    json!({
+        "get": {
+            "summary": "QR code of the app's URL, for opening it on a phone",
+            "operationId": operation_id,
+            "parameters": [query_param("url", "string", "URL to encode. Default: the URL the client connected to, via a trusted proxy, or else our best-guess LAN URL, as in `/api/health`.")],
+            "responses": {
+                "200": {
+                    "description": "QR code. Cached for a day.",
+                    "content": { media_type: {} },
+                },
+                "400": json_response("The URL is too long to encode", "ApiError"),
+                "503": json_response("No LAN address was found, and no `url` was given", "ApiError"),
+            },
+        },
+    })
+}
+
+fn reading(description: &str) -> Value {
+    json!({
+        "description": description,
+        "allOf": [{ "$ref": "#/components/schemas/Reading" }],
+    })
+}
+
+fn target_range() -> Value {
+    json!({
+        "type": "array",
+        "nullable": true,
+        "items": { "type": "number" },
+        "minItems": 2,
+        "maxItems": 2,
+    })
+}
+
+/// Each mounted API route, as `method path`, in OpenAPI's form.
+fn mounted(rocket: &Rocket) -> Vec<(String, String)> {
+    rocket
+        .routes()
+        .filter(|route| route.uri.path().starts_with("/api"))
+        .map(|route| {
+            // Rocket's `<param..>` segments are `{param}` too.
+            let path = openapi_path(route.uri.path()).replace("..}", "}");
+            (route.method.as_str().to_lowercase(), path)
+        })
+        .collect()
+}
+
+/// Mounted API routes missing from the spec.
+fn undescribed(rocket: &Rocket) -> Vec<String> {
+    let spec = spec();
+    mounted(rocket)
+        .into_iter()
+        .filter(|(method, path)| spec["paths"][path][method].is_null())
+        .map(|(method, path)| format!("{} {}", method, path))
+        .collect()
+}
+
+/// Print a warning for every mounted API route missing from the spec, so it doesn't
+/// silently drift from the routes we actually serve.
+pub fn check_routes(rocket: &Rocket) {
+    for route in undescribed(rocket) {
+        println!("Warning: `{}` is missing from the API spec.", route);
+    }
+}
+
+/// A Swagger UI page pointed at our spec. The UI's assets load from a CDN, so this
+/// page needs internet access; the spec itself doesn't.
+pub const DOCS_PAGE: &str = r##"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="UTF-8">
+    <title>AnyLeaf Water Monitor API</title>
+    <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@4/swagger-ui.css">
+</head>
+<body>
+<div id="swagger-ui"></div>
+<script src="https://unpkg.com/swagger-ui-dist@4/swagger-ui-bundle.js"></script>
+<script>
+    window.onload = function() {
+        SwaggerUIBundle({url: "/api/spec.json", dom_id: "#swagger-ui"})
+    }
+</script>
+</body>
+</html>
+"##;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{build_rocket, config::AppConfig, ReadingsSource};
+
+    /// The spec and the mounted routes match, both ways.
+    #[test]
+    fn the_spec_describes_every_route() {
+        let rocket = build_rocket(&AppConfig::default(), ReadingsSource::Device);
+        assert_eq!(undescribed(&rocket), Vec::<String>::new());
+
+        let mounted = mounted(&rocket);
+        let spec = spec();
+        let unmounted: Vec<_> = spec["paths"]
+            .as_object()
+            .unwrap()
+            .iter()
+            .flat_map(|(path, ops)| {
+                let ops = ops.as_object().unwrap();
+                ops.keys().map(move |method| (method.clone(), path.clone()))
+            })
+            .filter(|(method, path)| {
+                !(method == "parameters" || mounted.contains(&(method.clone(), path.clone())))
+            })
+            .map(|(method, path)| format!("{} {}", method, path))
+            .collect();
+        assert_eq!(unmounted, Vec::<String>::new());
+    }
+
+    #[test]
+    fn scoped_routes_list_their_security() {
+        let spec = spec();
+        let history = &spec["paths"]["/api/history"]["get"];
+        assert_eq!(history["x-token-scope"], "history");
+        assert!(history["responses"]["401"].is_object());
+        assert!(spec["paths"]["/api/health"]["get"]["x-token-scope"].is_null());
+    }
+
+    #[test]
+    fn stable_routes_are_under_v1() {
+        let spec = spec();
+        for route in crate::stable_routes() {
+            let method = route.method.as_str().to_lowercase();
+            let path = format!("/api/v1{}", openapi_path(route.uri.path()));
+            let op = &spec["paths"][&path][&method];
+            assert_eq!(op["x-api-version"], "v1", "{}", path);
+            assert_eq!(op["x-frozen"], true, "{}", path);
+        }
+        let history = &spec["paths"]["/api/v1/history"]["get"];
+        assert_eq!(history["operationId"], "getHistoryV1");
+        assert_eq!(history["x-token-scope"], "history");
+    }
+}
@@ -0,0 +1,201 @@
+//! Classifies each sensor's reading as ok, warn, or critical, against the target ranges
+//! in `[status]`, so clients don't each need their own thresholds. Errors, stale
+//! readings, and those without flow past the probe, are their own statuses.
+
+use std::{collections::BTreeMap, io::Read};
+
+use rocket::{http::Status, response::content, Data};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::{self, ErrorResponse},
+    auth::Admin,
+    config::{self, StatusConfig},
+    flow, freshness, poller,
+    registry::{self, SensorMap, REGISTRY},
+    units::{self, Quantity},
+    validate, warmup, Readings, SensorError,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SensorStatus {
+    /// Within the target range, or there's no target.
+    Ok,
+    /// Outside the target range, by up to the warn margin.
+    Warn,
+    /// Further outside.
+    Critical,
+    /// No valid reading.
+    Error,
+    /// The last successful reading is too old to trust.
+    Stale,
+    /// The flow sensor shows no flow past the probe, so its reading doesn't mean much.
+    #[serde(rename = "no_flow")]
+    NoFlow,
+    /// The probe is still settling after power-up; see `[warmup]`.
+    Stabilizing,
+    /// The validation hook rejected the reading; see `[validation_hook]`.
+    Rejected,
+}
+
+impl SensorStatus {
+    /// A numeric code, for protocols without strings.
+    pub fn code(&self) -> u16 {
+        match self {
+            Self::Ok => 0,
+            Self::Warn => 1,
+            Self::Critical => 2,
+            Self::Error => 3,
+            Self::Stale => 4,
+            Self::NoFlow => 5,
+            Self::Stabilizing => 6,
+            Self::Rejected => 7,
+        }
+    }
+}
+
+/// A status for each sensor with a reading.
+pub type Statuses = SensorMap<SensorStatus>;
+
+fn classify_one(
+    reading: &Result<f32, SensorError>,
+    target: Option<[f32; 2]>,
+    cfg: &StatusConfig,
+    stale: bool,
+) -> SensorStatus {
+    let v = match reading {
+        Ok(v) => *v,
+        Err(SensorError::Rejected) => return SensorStatus::Rejected,
+        Err(_) => return SensorStatus::Error,
+    };
+    if stale {
+        return SensorStatus::Stale;
+    }
+
+    let [min, max] = match target {
+        Some(t) => t,
+        None => return SensorStatus::Ok,
+    };
+    let margin = cfg.warn_margin * (max - min);
+    if v >= min && v <= max {
+        SensorStatus::Ok
+    } else if v >= min - margin && v <= max + margin {
+        SensorStatus::Warn
+    } else {
+        SensorStatus::Critical
+    }
+}
+
+/// The status of a value derived from the latest readings, for the sensor it measures.
+/// No value is an error.
+pub fn classify_value(value: Option<f32>, sensor: &str, cfg: &StatusConfig) -> SensorStatus {
+    let reading = value.ok_or(SensorError::NotConnected);
+    let target = cfg.targets.get(sensor, config::get().ec.probe);
+    classify_one(&reading, target, cfg, false)
+}
+
+/// The status of each of `readings`, which are the latest. A sensor is stale if the last
+/// cycle was, or its own last fresh reading is.
+pub fn classify(readings: &Readings, cfg: &StatusConfig) -> Statuses {
+    let stale = poller::status()
+        .seconds_since_success
+        .is_none_or(|s| s > cfg.stale_secs as f32);
+    let app = config::get();
+
+    readings.map(|sensor, r| {
+        let stale = stale || freshness::stale(sensor.id, cfg);
+        match classify_one(&r, cfg.targets.get(sensor.id, app.ec.probe), cfg, stale) {
+            SensorStatus::Ok | SensorStatus::Warn | SensorStatus::Critical
+                if flow::gated(&app.flow, sensor.id) =>
+            {
+                SensorStatus::NoFlow
+            }
+            SensorStatus::Ok | SensorStatus::Warn | SensorStatus::Critical
+                if warmup::stabilizing(sensor.id) =>
+            {
+                SensorStatus::Stabilizing
+            }
+            status => status,
+        }
+    })
+}
+
+/// `[status]` over the API: targets in `units`, each sensor's unit.
+#[derive(Deserialize, Serialize)]
+struct Targets {
+    #[serde(flatten)]
+    status: StatusConfig,
+    /// By sensor id; the locale's units for sensors left out.
+    #[serde(default)]
+    units: BTreeMap<String, String>,
+}
+
+/// Settings kept in the registry's units, in the locale's.
+fn displayed(status: &StatusConfig) -> Targets {
+    let mut status = status.clone();
+    let mut units = BTreeMap::new();
+    for sensor in REGISTRY.iter() {
+        let to = units::display(sensor);
+        if let Some(Some(range)) = status.targets.get_mut(sensor.id) {
+            let from = units::canonical(sensor);
+            *range = range.map(|v| units::convert(sensor, from, to, v, Quantity::Level));
+        }
+        units.insert(sensor.id.to_owned(), to.to_owned());
+    }
+    Targets { status, units }
+}
+
+/// The active `[status]` settings, with targets in the locale's units, eg °F in the US.
+#[get("/status/targets")]
+pub fn view_targets() -> content::Json<String> {
+    content::Json(serde_json::to_string(&displayed(&config::get().status)).unwrap())
+}
+
+/// Replace the `[status]` settings, in the config file too. Takes effect immediately.
+/// Targets are in `units`, or the locale's; they're saved in the registry's, so they mean
+/// the same if the locale changes.
+#[put("/status/targets", data = "<data>")]
+pub fn set_targets(_admin: Admin, data: Data) -> Result<content::Json<String>, ErrorResponse> {
+    let mut body = String::new();
+    data.open()
+        .take(16 * 1_024)
+        .read_to_string(&mut body)
+        .map_err(|e| api::error(Status::BadRequest, &e.to_string()))?;
+    let Targets { mut status, units } = serde_json::from_str(&body)
+        .map_err(|e| api::error(Status::BadRequest, &format!("Invalid settings: {}", e)))?;
+    if let Some(unknown) = units.keys().find(|s| registry::get(s).is_none()) {
+        return Err(api::error(
+            Status::BadRequest,
+            &format!("Unknown sensor `{}` in `units`", unknown),
+        ));
+    }
+    for sensor in REGISTRY.iter() {
+        let from = units
+            .get(sensor.id)
+            .map_or_else(|| units::display(sensor), String::as_str);
+        units::check(sensor, from).map_err(|e| api::error(Status::BadRequest, &e))?;
+        if let Some(Some(range)) = status.targets.get_mut(sensor.id) {
+            let to = units::canonical(sensor);
+            *range = range.map(|v| units::convert(sensor, from, to, v, Quantity::Level));
+        }
+    }
+
+    if let Some(problem) = validate::status_problems(&status, config::get().ec.probe)
+        .into_iter()
+        .next()
+    {
+        return Err(api::error(Status::BadRequest, &problem.to_string()));
+    }
+
+    let config = config::set_section("status", &status).map_err(|e| {
+        api::error(
+            Status::InternalServerError,
+            &format!("Problem saving the config: {}", e),
+        )
+    })?;
+
+    Ok(content::Json(
+        serde_json::to_string(&displayed(&config.status)).unwrap(),
+    ))
+}
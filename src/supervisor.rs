@@ -0,0 +1,130 @@
+//! Runs background components (the poller, exporters) on their own threads, catching
+//! panics so a bug in one can't take down the web server. A component that panics or
+//! exits is logged with its backtrace, and restarted with exponential backoff.
+
+use std::{
+    backtrace::Backtrace,
+    cell::{Cell, RefCell},
+    panic::{self, AssertUnwindSafe},
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::events::{self, Severity};
+
+/// A component that ran at least this long before failing starts its backoff over.
+const STABLE_RUN_TIME: Duration = Duration::from_secs(60);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// Restarts within this long count as recent, in health reports.
+const RECENT: Duration = Duration::from_secs(15 * 60);
+
+static COMPONENTS: Mutex<Vec<Component>> = Mutex::new(Vec::new());
+
+thread_local! {
+    /// Set on supervised threads, so the panic hook knows to leave reporting to us.
+    static SUPERVISED: Cell<bool> = const { Cell::new(false) };
+    /// The message and backtrace of this thread's last panic.
+    static LAST_PANIC: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+struct Component {
+    name: &'static str,
+    restarts: u32,
+    last_restart: Option<Instant>,
+}
+
+#[derive(Serialize)]
+pub struct ComponentStatus {
+    pub name: &'static str,
+    pub restarts: u32,
+    pub seconds_since_restart: Option<f32>,
+    /// Restarted in the last 15 minutes.
+    pub restarted_recently: bool,
+}
+
+/// Install a panic hook that captures a backtrace for supervised threads. Panics
+/// elsewhere are reported as usual.
+pub fn install_panic_hook() {
+    let default_hook = panic::take_hook();
+
+    panic::set_hook(Box::new(move |info| {
+        if SUPERVISED.with(|s| s.get()) {
+            let report = format!("{}\n{}", info, Backtrace::force_capture());
+            LAST_PANIC.with(|p| *p.borrow_mut() = Some(report));
+        } else {
+            default_hook(info);
+        }
+    }));
+}
+
+/// Run `f` on a new thread, restarting it if it panics or returns.
+pub fn spawn(name: &'static str, f: fn()) {
+    COMPONENTS.lock().unwrap().push(Component {
+        name,
+        restarts: 0,
+        last_restart: None,
+    });
+
+    thread::Builder::new()
+        .name(name.into())
+        .spawn(move || supervise(name, f))
+        .expect("Problem spawning a thread");
+}
+
+fn supervise(name: &'static str, f: fn()) {
+    SUPERVISED.with(|s| s.set(true));
+    let mut backoff = Duration::from_secs(1);
+
+    loop {
+        let start = Instant::now();
+        let result = panic::catch_unwind(AssertUnwindSafe(f));
+
+        let msg = match result {
+            Ok(()) => format!("The {} exited unexpectedly.", name),
+            Err(_) => {
+                let report = LAST_PANIC
+                    .with(|p| p.borrow_mut().take())
+                    .unwrap_or_else(|| "(No panic message)".into());
+                format!("The {} panicked: {}", name, report)
+            }
+        };
+
+        if start.elapsed() >= STABLE_RUN_TIME {
+            backoff = Duration::from_secs(1);
+        }
+
+        events::record(
+            Severity::Warning,
+            "supervisor",
+            format!("{} Restarting in {}s.", msg, backoff.as_secs()),
+        );
+        thread::sleep(backoff);
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+
+        let mut components = COMPONENTS.lock().unwrap();
+        if let Some(c) = components.iter_mut().find(|c| c.name == name) {
+            c.restarts += 1;
+            c.last_restart = Some(Instant::now());
+        }
+    }
+}
+
+pub fn status() -> Vec<ComponentStatus> {
+    COMPONENTS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|c| ComponentStatus {
+            name: c.name,
+            restarts: c.restarts,
+            seconds_since_restart: c.last_restart.map(|t| t.elapsed().as_secs_f32()),
+            restarted_recently: c
+                .last_restart
+                .map(|t| t.elapsed() < RECENT)
+                .unwrap_or(false),
+        })
+        .collect()
+}
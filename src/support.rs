@@ -0,0 +1,309 @@
+//! Support bundles: a zip of what's usually asked for, one question at a time, when
+//! someone reports a problem. `GET /api/support-bundle` downloads one, with admin;
+//! `water-mon-app --support-bundle` writes one without a server, for when it won't start,
+//! or without a token. It has the config in effect, with secrets redacted and listed in
+//! `manifest.json`, the config's problems, recent events, serial link stats and the last
+//! readings frames, in hex, the startup checks, the app's version, the OS and serial
+//! ports, and the last day of history, as CSV. Exporter URLs and notification channel
+//! URLs are kept, since they're what's usually wrong; anything secret in them should go
+//! in `headers`.
+
+use std::{
+    env,
+    fmt::Write as _,
+    fs,
+    io::{self, Cursor, Write},
+    path::{Path, PathBuf},
+};
+
+use chrono::Utc;
+use rocket::{
+    http::{ContentType, Status},
+    response::{self, Responder, Response},
+    Request,
+};
+use serde::Serialize;
+use serialport::SerialPortType;
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+use crate::{
+    api::{self, ErrorResponse},
+    auth::{self, Admin},
+    cli::DataFormat,
+    config::{self, AppConfig, CONFIG_PATH},
+    events, migrate, selfcheck, serial_stats, system, validate,
+    version::{self, BuildInfo},
+};
+
+const HISTORY_HOURS: i64 = 24;
+
+/// All we keep.
+const MAX_EVENTS: usize = 1_000;
+
+#[derive(Serialize)]
+struct Manifest {
+    app_version: &'static str,
+    created: String,
+    /// `server`, from `/api/support-bundle`, or `cli`, from `--support-bundle`.
+    source: &'static str,
+    files: Vec<&'static str>,
+    /// Settings replaced with `(redacted)` in `config.toml`.
+    redacted: Vec<String>,
+    /// What couldn't be included, and why.
+    problems: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct Serial {
+    stats: serial_stats::SerialReport,
+    /// The last readings requests and responses, oldest first.
+    frames: Vec<serial_stats::Frame>,
+}
+
+#[derive(Serialize)]
+struct SystemInfo {
+    build: BuildInfo,
+    /// `PRETTY_NAME` from `/etc/os-release`, eg `Raspbian GNU/Linux 11 (bullseye)`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    distribution: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kernel: Option<String>,
+    working_dir: Option<String>,
+    stats: system::SystemStats,
+}
+
+#[derive(Serialize)]
+struct Port {
+    name: String,
+    /// `usb`, `pci`, `bluetooth` or `unknown`.
+    kind: &'static str,
+    /// USB vendor and product ids, in hex.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    serial_number: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    manufacturer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    product: Option<String>,
+    water_monitor: bool,
+}
+
+fn distribution() -> Option<String> {
+    let release = fs::read_to_string("/etc/os-release").ok()?;
+    release.lines().find_map(|l| {
+        l.strip_prefix("PRETTY_NAME=")
+            .map(|name| name.trim_matches('"').to_owned())
+    })
+}
+
+fn system_info() -> SystemInfo {
+    SystemInfo {
+        build: version::build_info(),
+        distribution: distribution(),
+        kernel: fs::read_to_string("/proc/sys/kernel/osrelease")
+            .ok()
+            .map(|k| k.trim().to_owned()),
+        working_dir: env::current_dir().ok().map(|d| d.display().to_string()),
+        stats: system::stats(),
+    }
+}
+
+fn ports() -> Result<Vec<Port>, serialport::Error> {
+    let ports = serialport::available_ports()?;
+    let water_monitor = crate::find_port(&ports).map(|p| p.port_name.clone());
+    Ok(ports
+        .into_iter()
+        .map(|p| {
+            let mut port = Port {
+                water_monitor: water_monitor.as_ref() == Some(&p.port_name),
+                name: p.port_name,
+                kind: "unknown",
+                vid: None,
+                pid: None,
+                serial_number: None,
+                manufacturer: None,
+                product: None,
+            };
+            match p.port_type {
+                SerialPortType::UsbPort(info) => {
+                    port.kind = "usb";
+                    port.vid = Some(format!("{:04x}", info.vid));
+                    port.pid = Some(format!("{:04x}", info.pid));
+                    port.serial_number = info.serial_number;
+                    port.manufacturer = info.manufacturer;
+                    port.product = info.product;
+                }
+                SerialPortType::PciPort => port.kind = "pci",
+                SerialPortType::BluetoothPort => port.kind = "bluetooth",
+                SerialPortType::Unknown => (),
+            }
+            port
+        })
+        .collect())
+}
+
+/// The last day of history, as CSV.
+fn history_csv(cfg: &AppConfig) -> Result<Vec<u8>, String> {
+    if !cfg.history.enabled {
+        return Err("`history.enabled` is off".into());
+    }
+    if !Path::new(&cfg.history.path).exists() {
+        return Err(format!(
+            "There's no history database at `{}`",
+            cfg.history.path
+        ));
+    }
+    let from_ms = Utc::now().timestamp_millis() - HISTORY_HOURS * 3_600_000;
+    let mut csv = Vec::new();
+    migrate::write_rows(
+        &cfg.history.path,
+        from_ms,
+        i64::MAX,
+        DataFormat::Csv,
+        &mut csv,
+    )?;
+    Ok(csv)
+}
+
+fn json(value: &impl Serialize) -> Vec<u8> {
+    serde_json::to_vec_pretty(value).unwrap()
+}
+
+/// Build a bundle. `cfg` is the config in effect, or why there's none.
+fn build(cfg: Result<&AppConfig, String>, source: &'static str) -> Result<Vec<u8>, io::Error> {
+    let mut files: Vec<(&'static str, Vec<u8>)> = Vec::new();
+    let mut manifest = Manifest {
+        app_version: env!("CARGO_PKG_VERSION"),
+        created: Utc::now().to_rfc3339(),
+        source,
+        files: Vec::new(),
+        redacted: Vec::new(),
+        problems: Vec::new(),
+    };
+
+    match &cfg {
+        Ok(cfg) => {
+            match validate::effective(cfg) {
+                Ok((text, redacted)) => {
+                    files.push(("config.toml", text.into_bytes()));
+                    manifest.redacted = redacted;
+                }
+                Err(e) => manifest
+                    .problems
+                    .push(format!("Problem writing the config: {}", e)),
+            }
+            let problems = validate::problems(cfg)
+                .iter()
+                .fold(String::new(), |mut s, p| {
+                    let _ = writeln!(s, "{:?}: {}", p.severity, p);
+                    s
+                });
+            files.push(("config-problems.txt", problems.into_bytes()));
+        }
+        Err(e) => manifest.problems.push(format!(
+            "`{}` couldn't be loaded, so isn't included, lest it has secrets in it: {}",
+            CONFIG_PATH, e
+        )),
+    }
+
+    files.push(("events.json", json(&events::recent(MAX_EVENTS))));
+    files.push((
+        "serial.json",
+        json(&Serial {
+            stats: serial_stats::report(),
+            frames: serial_stats::frames(),
+        }),
+    ));
+    let server = config::get().server;
+    files.push((
+        "selfcheck.json",
+        selfcheck::results(crate::listen_address(&server), crate::listen_port(&server))
+            .into_bytes(),
+    ));
+    files.push(("system.json", json(&system_info())));
+    match ports() {
+        Ok(ports) => files.push(("ports.json", json(&ports))),
+        Err(e) => manifest
+            .problems
+            .push(format!("Problem listing serial ports: {}", e)),
+    }
+    match cfg.clone().and_then(history_csv) {
+        Ok(csv) => files.push(("history.csv", csv)),
+        Err(e) => manifest
+            .problems
+            .push(format!("History isn't included: {}", e)),
+    }
+
+    manifest.files = files.iter().map(|(name, _)| *name).collect();
+    files.insert(0, ("manifest.json", json(&manifest)));
+
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+    for (name, contents) in files {
+        zip.start_file(name, options)?;
+        zip.write_all(&contents)?;
+    }
+    Ok(zip.finish()?.into_inner())
+}
+
+fn file_name() -> String {
+    format!(
+        "water-mon-support-{}.zip",
+        Utc::now().format("%Y%m%dT%H%M%SZ")
+    )
+}
+
+pub struct BundleFile {
+    bytes: Vec<u8>,
+    name: String,
+}
+
+impl<'r> Responder<'r> for BundleFile {
+    fn respond_to(self, _req: &Request) -> response::Result<'r> {
+        Response::build()
+            .header(ContentType::new("application", "zip"))
+            .raw_header(
+                "Content-Disposition",
+                format!("attachment; filename=\"{}\"", self.name),
+            )
+            .sized_body(Cursor::new(self.bytes))
+            .ok()
+    }
+}
+
+/// A support bundle. Requests from this machine need admin too: the IPv4, socket
+/// activation and unix socket listeners relay remote clients over loopback.
+#[get("/support-bundle")]
+pub fn view_support_bundle(
+    admin: Result<Admin, &'static str>,
+) -> Result<BundleFile, ErrorResponse> {
+    auth::require(admin)?;
+    let bytes = build(Ok(&config::get()), "server").map_err(|e| {
+        api::error(
+            Status::InternalServerError,
+            &format!("Problem making a support bundle: {}", e),
+        )
+    })?;
+    Ok(BundleFile {
+        bytes,
+        name: file_name(),
+    })
+}
+
+/// `water-mon-app --support-bundle`: write a bundle to `path`, or one named for the time
+/// in the working directory, without a server. Returns where it went.
+pub fn write(path: Option<PathBuf>) -> Result<PathBuf, String> {
+    let cfg = AppConfig::load(Path::new(CONFIG_PATH)).map_err(|e| e.to_string());
+    // For the parts that read it, like the startup checks.
+    config::set(cfg.clone().unwrap_or_default());
+    selfcheck::resolve_static_dir(&config::get().server);
+
+    let bytes = build(cfg.as_ref().map_err(|e| e.clone()), "cli")
+        .map_err(|e| format!("Problem making a support bundle: {}", e))?;
+    let path = path.unwrap_or_else(|| file_name().into());
+    fs::write(&path, bytes).map_err(|e| format!("Problem writing `{}`: {}", path.display(), e))?;
+    Ok(path)
+}
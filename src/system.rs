@@ -0,0 +1,255 @@
+//! The machine we run on: free disk space, memory, and the CPU temperature on a Pi, at
+//! `/api/system`, since an overheating or full Pi looks like a broken app. A guard checks
+//! the disk holding history every 30s: below `resources.min_free_disk_mb`, history
+//! inserts stop, so a full SD card doesn't leave the writer failing, while live readings
+//! and alerts carry on. It raises an alert, prunes at once if retention limits are set,
+//! and resumes once there's some room again, recording the stretch as an outage.
+
+use std::{
+    fs,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering},
+        Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use chrono::Utc;
+use rocket::response::content;
+use serde::Serialize;
+
+use crate::{
+    config,
+    events::{self, Severity},
+    locale, outages, retention,
+};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Free space needed above the minimum before history resumes, so it doesn't flap.
+const RESUME_MARGIN_MB: u64 = 50;
+
+const MB: u64 = 1_024 * 1_024;
+
+/// If history inserts are stopped for lack of disk space.
+static HISTORY_PAUSED: AtomicBool = AtomicBool::new(false);
+
+/// When history was paused, in ms since the epoch.
+static PAUSED_AT: AtomicI64 = AtomicI64::new(0);
+
+/// Samples dropped while paused.
+static SKIPPED: AtomicU64 = AtomicU64::new(0);
+
+/// Why history is paused, for `/api/health`.
+static WARNING: Mutex<Option<String>> = Mutex::new(None);
+
+#[derive(Clone, Copy, Serialize)]
+pub struct DiskSpace {
+    pub free_bytes: u64,
+    pub total_bytes: u64,
+}
+
+#[derive(Serialize)]
+pub struct Memory {
+    pub total_bytes: u64,
+    /// What can be allocated without swapping, including reclaimable caches.
+    pub available_bytes: u64,
+}
+
+#[derive(Serialize)]
+pub struct SystemStats {
+    /// The disk holding the history database.
+    pub disk: Option<DiskSpace>,
+    /// The database file and its write-ahead log.
+    pub database_bytes: Option<u64>,
+    pub memory: Option<Memory>,
+    /// °C. The throttling starts at 80 on a Pi.
+    pub cpu_temp_c: Option<f32>,
+    /// Over 1, 5 and 15 minutes.
+    pub load_average: Option<[f32; 3]>,
+}
+
+#[derive(Serialize)]
+pub struct GuardStatus {
+    pub history_paused: bool,
+    /// Samples not stored while paused.
+    pub skipped_samples: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub warning: Option<String>,
+}
+
+#[cfg(unix)]
+fn disk_space(path: &Path) -> Option<DiskSpace> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stats) } != 0 {
+        return None;
+    }
+    // The field types vary by platform.
+    #[allow(clippy::unnecessary_cast)]
+    let block = stats.f_frsize as u64;
+    #[allow(clippy::unnecessary_cast)]
+    Some(DiskSpace {
+        free_bytes: stats.f_bavail as u64 * block,
+        total_bytes: stats.f_blocks as u64 * block,
+    })
+}
+
+#[cfg(not(unix))]
+fn disk_space(_path: &Path) -> Option<DiskSpace> {
+    None
+}
+
+/// The directory holding the database, which needn't exist yet.
+fn history_dir(path: &str) -> &Path {
+    match Path::new(path).parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    }
+}
+
+fn memory() -> Option<Memory> {
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    let field = |name: &str| -> Option<u64> {
+        let line = meminfo.lines().find(|l| l.starts_with(name))?;
+        let kb: u64 = line[name.len()..]
+            .trim()
+            .trim_end_matches("kB")
+            .trim()
+            .parse()
+            .ok()?;
+        Some(kb * 1_024)
+    };
+    Some(Memory {
+        total_bytes: field("MemTotal:")?,
+        available_bytes: field("MemAvailable:")?,
+    })
+}
+
+fn cpu_temp() -> Option<f32> {
+    let millis: f32 = fs::read_to_string("/sys/class/thermal/thermal_zone0/temp")
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    Some(millis / 1_000.)
+}
+
+fn load_average() -> Option<[f32; 3]> {
+    let loadavg = fs::read_to_string("/proc/loadavg").ok()?;
+    let mut fields = loadavg.split_whitespace().map(|f| f.parse().ok());
+    Some([fields.next()??, fields.next()??, fields.next()??])
+}
+
+pub fn stats() -> SystemStats {
+    let path = config::get().history.path;
+    let database_bytes = fs::metadata(&path).ok().map(|m| {
+        m.len()
+            + fs::metadata(format!("{}-wal", path))
+                .map(|m| m.len())
+                .unwrap_or(0)
+    });
+
+    SystemStats {
+        disk: disk_space(history_dir(&path)),
+        database_bytes,
+        memory: memory(),
+        cpu_temp_c: cpu_temp(),
+        load_average: load_average(),
+    }
+}
+
+/// If history inserts are stopped. Checked by the history writer.
+pub fn history_paused() -> bool {
+    HISTORY_PAUSED.load(Ordering::Relaxed)
+}
+
+/// Count a sample that wasn't stored, for being paused.
+pub fn skip_sample() {
+    SKIPPED.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn guard_status() -> GuardStatus {
+    GuardStatus {
+        history_paused: history_paused(),
+        skipped_samples: SKIPPED.load(Ordering::Relaxed),
+        warning: WARNING.lock().unwrap().clone(),
+    }
+}
+
+/// Store history again, and note the stretch it was paused for as a possible outage.
+fn resume() {
+    if HISTORY_PAUSED.swap(false, Ordering::Relaxed) {
+        *WARNING.lock().unwrap() = None;
+        outages::storage_full(PAUSED_AT.load(Ordering::Relaxed));
+    }
+}
+
+fn check() {
+    let cfg = config::get();
+    let min_free = match cfg.resources.min_free_disk_mb {
+        Some(mb) if cfg.history.enabled => mb,
+        _ => {
+            resume();
+            return;
+        }
+    };
+    let free_mb = match disk_space(history_dir(&cfg.history.path)) {
+        Some(d) => d.free_bytes / MB,
+        None => return,
+    };
+    let locale = locale::configured();
+
+    if !history_paused() && free_mb < min_free {
+        let warning = format!(
+            "Only {} MiB of disk space is free, under `resources.min_free_disk_mb`, so \
+             history isn't being stored. Live readings and alerts carry on.",
+            locale.number(free_mb as f64, 0)
+        );
+        events::record(Severity::Alert, "resources", warning.clone());
+        *WARNING.lock().unwrap() = Some(warning);
+        PAUSED_AT.store(Utc::now().timestamp_millis(), Ordering::Relaxed);
+        HISTORY_PAUSED.store(true, Ordering::Relaxed);
+
+        // Retention may free enough; otherwise the user needs to make room.
+        let history = &cfg.history;
+        if history.max_age_days.is_some() || history.max_size_mb.is_some() {
+            if let Err(e) = retention::run(history) {
+                events::record(
+                    Severity::Warning,
+                    "retention",
+                    format!("Problem pruning history: {}", e),
+                );
+            }
+        }
+    } else if history_paused() && free_mb >= min_free + RESUME_MARGIN_MB {
+        events::record(
+            Severity::Info,
+            "resources",
+            format!(
+                "{} MiB of disk space is free again; storing history.",
+                locale.number(free_mb as f64, 0)
+            ),
+        );
+        resume();
+    }
+}
+
+/// Check disk space, forever; run this on its own thread.
+pub fn run() {
+    loop {
+        check();
+        thread::sleep(CHECK_INTERVAL);
+    }
+}
+
+/// Free disk space, memory, CPU temperature and load. Each is `null` where the OS
+/// doesn't say.
+#[get("/system")]
+pub fn view_system() -> content::Json<String> {
+    content::Json(serde_json::to_string(&stats()).unwrap())
+}
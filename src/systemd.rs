@@ -0,0 +1,333 @@
+//! Integration with systemd: readiness and status notifications, watchdog pings, socket
+//! activation, and writing unit files. Notifications are sent only when systemd asks for
+//! them by setting `NOTIFY_SOCKET`, so running outside systemd is unaffected.
+//!
+//! With socket activation, systemd binds the port, eg 80, and passes us the listener, so
+//! the service can run as an unprivileged user. Rocket can only bind its own listener,
+//! so it listens on a free port on localhost, and we forward connections on systemd's
+//! sockets to it, as for the unix socket.
+
+use std::{
+    env, fs,
+    io::{self, Write},
+    net::{Ipv4Addr, SocketAddr, TcpListener},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, OnceLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::net;
+
+pub const UNIT_NAME: &str = "water-mon-app.service";
+pub const SOCKET_UNIT_NAME: &str = "water-mon-app.socket";
+pub const DEFAULT_UNIT_PATH: &str = "/etc/systemd/system/water-mon-app.service";
+
+/// The first socket systemd passes, per `sd_listen_fds`.
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// Set once the poller has made its first attempt at discovering the device.
+static FIRST_POLL_DONE: AtomicBool = AtomicBool::new(false);
+
+/// Set at startup, if systemd passed us sockets.
+static ACTIVATION: OnceLock<Activation> = OnceLock::new();
+
+/// The TCP listeners systemd passed us.
+pub struct Activation {
+    listeners: Vec<TcpListener>,
+    pub addresses: Vec<SocketAddr>,
+    /// Of our own listener on localhost, which their connections are forwarded to.
+    pub backend_port: u16,
+}
+
+impl Activation {
+    /// The port clients connect to: the first socket's.
+    pub fn port(&self) -> u16 {
+        self.addresses[0].port()
+    }
+}
+
+struct NotifyState {
+    last_ping: Option<Instant>,
+    /// The last `STATUS=` we sent, so we only send changes.
+    connected: Option<bool>,
+}
+
+static STATE: Mutex<NotifyState> = Mutex::new(NotifyState {
+    last_ping: None,
+    connected: None,
+});
+
+/// Send a notification, eg `READY=1`, to systemd. Does nothing if we're not running
+/// under systemd.
+#[cfg(unix)]
+pub fn notify(msg: &str) {
+    use std::os::unix::net::UnixDatagram;
+
+    let path = match env::var("NOTIFY_SOCKET") {
+        Ok(p) => p,
+        Err(_) => return,
+    };
+
+    let socket = match UnixDatagram::unbound() {
+        Ok(s) => s,
+        Err(_) => return,
+    };
+
+    // A leading `@` means a socket in the abstract namespace.
+    let result = match path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            std::os::unix::net::SocketAddr::from_abstract_name(name)
+                .and_then(|addr| socket.send_to_addr(msg.as_bytes(), &addr))
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => return,
+        None => socket.send_to(msg.as_bytes(), &path),
+    };
+
+    if let Err(e) = result {
+        println!("Problem sending a notification to systemd: {}", e);
+    }
+}
+
+#[cfg(not(unix))]
+pub fn notify(_msg: &str) {}
+
+/// How often to ping systemd's watchdog, if it's enabled for this process: half of
+/// `WatchdogSec`, as systemd recommends.
+fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    Some(Duration::from_micros(usec / 2))
+}
+
+/// Ping the watchdog, if it's due. Called by the poller, so a hung poller gets the
+/// process restarted.
+pub fn ping_watchdog() {
+    let mut state = STATE.lock().unwrap();
+
+    if let Some(interval) = watchdog_interval() {
+        if state
+            .last_ping
+            .map(|t| t.elapsed() >= interval)
+            .unwrap_or(true)
+        {
+            notify("WATCHDOG=1");
+            state.last_ping = Some(Instant::now());
+        }
+    }
+}
+
+/// Called by the poller each cycle. Pings the watchdog, and reports device
+/// connectivity as our status.
+pub fn on_poll(connected: bool) {
+    FIRST_POLL_DONE.store(true, Ordering::Release);
+    ping_watchdog();
+
+    let mut state = STATE.lock().unwrap();
+    if state.connected != Some(connected) {
+        notify(if connected {
+            "STATUS=Water Monitor connected"
+        } else {
+            "STATUS=Waiting for the Water Monitor"
+        });
+        state.connected = Some(connected);
+    }
+}
+
+/// Called once the HTTP listener is bound. Signals readiness after the poller's first
+/// discovery attempt, without holding up the server.
+pub fn on_launch() {
+    thread::spawn(|| {
+        while !FIRST_POLL_DONE.load(Ordering::Acquire) {
+            thread::sleep(Duration::from_millis(50));
+        }
+        notify("READY=1");
+    });
+}
+
+/// Adopt the TCP listeners systemd passed in `LISTEN_FDS`, if it started us by socket
+/// activation. Called once at startup, before the server is set up.
+#[cfg(unix)]
+pub fn adopt_sockets() -> Result<(), io::Error> {
+    use std::os::unix::io::FromRawFd;
+
+    let for_us = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|p| p.parse::<u32>().ok())
+        == Some(std::process::id());
+    let count: i32 = match env::var("LISTEN_FDS").ok().and_then(|n| n.parse().ok()) {
+        Some(n) if for_us && n > 0 => n,
+        _ => return Ok(()),
+    };
+    // So programs we run don't take the sockets as passed to them.
+    for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(var);
+    }
+
+    let listeners: Vec<_> = (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| unsafe {
+            libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+            TcpListener::from_raw_fd(fd)
+        })
+        .collect();
+    let addresses = listeners
+        .iter()
+        .map(TcpListener::local_addr)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "systemd passed a socket that isn't a TCP listener; the socket unit \
+                     needs `ListenStream=` with a port, eg `ListenStream=80`: {}",
+                    e
+                ),
+            )
+        })?;
+    // Released straight away, for the server to take.
+    let backend_port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?
+        .local_addr()?
+        .port();
+
+    let _ = ACTIVATION.set(Activation {
+        listeners,
+        addresses,
+        backend_port,
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn adopt_sockets() -> Result<(), io::Error> {
+    Ok(())
+}
+
+/// systemd's sockets, if it started us by socket activation.
+pub fn activation() -> Option<&'static Activation> {
+    ACTIVATION.get()
+}
+
+/// Forward connections on systemd's sockets to our listener. Called once it's bound.
+pub fn forward_activated() {
+    let Some(activation) = ACTIVATION.get() else {
+        return;
+    };
+    let backend = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), activation.backend_port);
+    for listener in &activation.listeners {
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                net::forward_accepted(stream, backend, "socket activation");
+            }
+        });
+    }
+}
+
+/// The banner lines describing systemd's sockets, if it passed any.
+pub fn banner() -> Option<String> {
+    ACTIVATION.get().map(|activation| {
+        let addresses: Vec<_> = activation
+            .addresses
+            .iter()
+            .map(SocketAddr::to_string)
+            .collect();
+        format!(
+            "Listening on the sockets systemd passed, at {}, which are forwarded to \
+             127.0.0.1:{}.\n",
+            addresses.join(", "),
+            activation.backend_port
+        )
+    })
+}
+
+/// A unit file that runs this executable from the current directory. With a socket unit,
+/// it's started by the socket, and can run as an unprivileged user.
+pub fn unit_file(exe: &Path, working_dir: &Path, socket: bool) -> String {
+    let (after, user) = if socket {
+        (
+            format!("After=network.target {0}\nRequires={0}", SOCKET_UNIT_NAME),
+            "# systemd owns the port, so the app needn't run as root, eg:\n# User=water-mon\n",
+        )
+    } else {
+        ("After=network.target".into(), "")
+    };
+    format!(
+        "[Unit]
+Description=AnyLeaf Water Monitor app
+{}
+
+[Service]
+Type=notify
+ExecStart={} --log-format=journald
+WorkingDirectory={}
+Restart=on-failure
+WatchdogSec=30
+{}
+[Install]
+WantedBy=multi-user.target
+",
+        after,
+        exe.display(),
+        working_dir.display(),
+        user
+    )
+}
+
+/// A socket unit that listens on `port`, on all interfaces, and starts the service.
+pub fn socket_unit_file(port: u16) -> String {
+    format!(
+        "[Unit]
+Description=AnyLeaf Water Monitor app's socket
+
+[Socket]
+ListenStream={}
+BindIPv6Only=both
+
+[Install]
+WantedBy=sockets.target
+",
+        port
+    )
+}
+
+/// Write a unit file for this executable to `path`, and with `socket_port`, a socket unit
+/// next to it.
+pub fn install_service(path: &Path, socket_port: Option<u16>) -> Result<(), io::Error> {
+    let exe = env::current_exe()?;
+    let working_dir = env::current_dir()?;
+
+    let mut file = fs::File::create(path)?;
+    file.write_all(unit_file(&exe, &working_dir, socket_port.is_some()).as_bytes())?;
+
+    // Both, with a socket, so the app polls from boot, not from the first connection.
+    let units = match socket_port {
+        Some(port) => {
+            let socket_path = path.with_file_name(SOCKET_UNIT_NAME);
+            let mut file = fs::File::create(&socket_path)?;
+            file.write_all(socket_unit_file(port).as_bytes())?;
+            println!("Wrote `{}`.", socket_path.display());
+            format!("{} {}", SOCKET_UNIT_NAME, UNIT_NAME)
+        }
+        None => UNIT_NAME.into(),
+    };
+
+    println!(
+        "Wrote `{}`. To start the app now and on boot, run:\n\n    \
+         sudo systemctl daemon-reload\n    \
+         sudo systemctl enable --now {}\n",
+        path.display(),
+        units
+    );
+    Ok(())
+}
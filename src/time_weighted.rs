@@ -0,0 +1,149 @@
+//! Time-weighted statistics, for history whose samples aren't evenly spaced: with
+//! adaptive polling, a minute of 200 ms samples while something changes would otherwise
+//! outweigh an hour of samples a minute apart. A raw sample stands for the time until
+//! the next, up to the gap threshold, `history.gap_factor` polling intervals, so a gap
+//! isn't put down to the sample before it. A compacted row stands for its bucket, less
+//! the share of its samples that were errors. Coverage is the share of a range that the
+//! values stand for.
+
+use rusqlite::{params, Connection};
+
+use crate::history::{self, AGGREGATE_TIERS};
+
+/// A sensor's time-weighted stats over a range.
+pub struct Summary {
+    /// How long the values stand for, in ms.
+    pub covered_ms: f64,
+    /// `None` if the values stand for no time.
+    pub mean: Option<f64>,
+    /// The mean of the squares, for the standard deviation.
+    pub mean_sq: Option<f64>,
+    /// How long values were within the target, in ms.
+    pub in_range_ms: f64,
+}
+
+/// A query for every valid value of `sensor` from `?1` up to `?2`, as `v`, with how
+/// long it stands for, in ms, as `d`. Samples taken during maintenance, or while it was
+/// stabilizing, are left out.
+pub fn timed_values(sensor: &str, max_hold_ms: i64) -> String {
+    // Each sample holds until the next one, valid or not: an error ends what the value
+    // before it stands for.
+    let mut values = vec![format!(
+        "SELECT v, MIN(COALESCE(next, ?2), time + {2}) - time AS d FROM (\
+         SELECT time, LEAD(time) OVER (ORDER BY time) AS next, \
+         CASE WHEN {0} IS NOT NULL AND NOT maintenance AND {1} THEN {0} END AS v \
+         FROM samples WHERE time >= ?1 AND time < ?2) \
+         WHERE v IS NOT NULL",
+        sensor,
+        history::settled(sensor),
+        max_hold_ms
+    )];
+    for (table, bucket_ms) in AGGREGATE_TIERS.iter() {
+        values.push(format!(
+            "SELECT {0}_avg, MIN({2}, ?2 - time) * {0}_n / CAST(count AS REAL) FROM {1} \
+             WHERE time >= ?1 AND time < ?2 AND {0}_n > 0",
+            sensor, table, bucket_ms
+        ));
+    }
+    values.join(" UNION ALL ")
+}
+
+/// `sensor`'s time-weighted stats from `from_ms` up to `to_ms`, with time in `target`.
+pub fn summary(
+    conn: &Connection,
+    sensor: &str,
+    from_ms: i64,
+    to_ms: i64,
+    max_hold_ms: i64,
+    target: Option<[f32; 2]>,
+) -> Result<Summary, rusqlite::Error> {
+    let sql = format!(
+        "SELECT COALESCE(SUM(d), 0), SUM(v * d) / SUM(d), SUM(v * v * d) / SUM(d), \
+         COALESCE(SUM(CASE WHEN v >= ?3 AND v <= ?4 THEN d ELSE 0 END), 0) \
+         FROM ({}) WHERE d > 0",
+        timed_values(sensor, max_hold_ms)
+    );
+    let [min, max] = target.unwrap_or([f32::MIN, f32::MAX]);
+    conn.query_row(
+        &sql,
+        params![from_ms, to_ms, min as f64, max as f64],
+        |row| {
+            Ok(Summary {
+                covered_ms: row.get(0)?,
+                mean: row.get(1)?,
+                mean_sq: row.get(2)?,
+                in_range_ms: row.get(3)?,
+            })
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn insert(conn: &Connection, samples: &[(i64, f64)]) {
+        for (time, value) in samples.iter() {
+            conn.execute(
+                "INSERT INTO samples (time, T) VALUES (?1, ?2)",
+                params![time, value],
+            )
+            .unwrap();
+        }
+    }
+
+    /// A series of samples a minute apart, then a minute of them 200 ms apart.
+    #[test]
+    fn samples_are_weighted_by_time() {
+        const HOLD_MS: i64 = 180_000;
+        let conn = history::open(":memory:").unwrap();
+        let mut times = Vec::new();
+        times.extend((0..60).map(|i| (i * 60_000, 20.)));
+        times.extend((0..300).map(|i| (3_600_000 + i * 200, 30.)));
+        insert(&conn, &times);
+        // Per sample, the burst would outweigh the hour.
+        let naive = times.iter().map(|(_, v)| v).sum::<f64>() / times.len() as f64;
+        assert!(naive > 28.);
+
+        // Up to the last sample, and then past where it stops standing for anything.
+        for to_ms in [3_660_000, 3_900_000] {
+            let last_ms = (to_ms - 3_659_800).min(HOLD_MS) as f64;
+            let covered_ms = 3_600_000. + 59_800. + last_ms;
+            let expected = (20. * 3_600_000. + 30. * (59_800. + last_ms)) / covered_ms;
+
+            let got = summary(&conn, "T", 0, to_ms, HOLD_MS, None).unwrap();
+            assert!(
+                got.mean.is_some_and(|m| (m - expected).abs() < 1e-6),
+                "Up to {} ms, the time-weighted mean was {:?}, not {}",
+                to_ms,
+                got.mean,
+                expected
+            );
+            assert!((got.covered_ms - covered_ms).abs() < 1e-3);
+            assert!(got.mean.unwrap() < 21.);
+        }
+    }
+
+    /// The sample before a gap stands for no more than the hold, so coverage drops.
+    #[test]
+    fn gaps_arent_covered() {
+        const MIN: i64 = 60_000;
+        let conn = history::open(":memory:").unwrap();
+        let mut samples: Vec<_> = (0..10)
+            .map(|i| (i * MIN, if i < 5 { 7. } else { 8. }))
+            .collect();
+        samples.push((60 * MIN, 7.));
+        insert(&conn, &samples);
+
+        let got = summary(&conn, "T", 0, 61 * MIN, 3 * MIN, Some([6.5, 7.5])).unwrap();
+        // 9 minutes, the hold after the last before the gap, and the minute after it.
+        assert_eq!(got.covered_ms, (9 * MIN + 3 * MIN + MIN) as f64);
+        assert_eq!(got.in_range_ms, (5 * MIN + MIN) as f64);
+        let mean = (7. * 6. + 8. * 7.) / 13.;
+        assert!((got.mean.unwrap() - mean).abs() < 1e-9);
+
+        let empty = summary(&conn, "T", 100 * MIN, 110 * MIN, 3 * MIN, None).unwrap();
+        assert_eq!(empty.covered_ms, 0.);
+        assert_eq!(empty.mean, None);
+    }
+}
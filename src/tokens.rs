@@ -0,0 +1,493 @@
+//! Scoped, read-only tokens, for sharing a live view without admin access, eg with a
+//! pool service. With `auth.private` set, every route serving readings, or what's derived
+//! from them, needs admin, or a token with its scope; see `Scope::of`. Config checks
+//! refuse it with the CoAP and Modbus listeners, or SNMP's default community, which
+//! serve readings without either. Admins make tokens with `POST /api/tokens`, list them with
+//! `GET`, and revoke them with `DELETE /api/tokens/<id>`. A token can be limited to
+//! devices, the ones it can ask for with `?device=`, or this one, without, and can expire. It's sent as `?token=`, so a URL can be shared, or as
+//! `Authorization: Bearer`. Tokens are kept as SHA-256 hashes in `tokens.json`, in the
+//! working directory, so one is only shown when it's made, with when each was last
+//! used, so stale shares can be cleaned up.
+
+use std::{
+    fmt::Write,
+    fs,
+    io::{self, Read},
+    sync::Mutex,
+};
+
+use chrono::Utc;
+use rocket::{
+    http::Status,
+    request::{self, FromRequest},
+    response::content,
+    Data, Outcome, Request,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    api::{self, ErrorResponse},
+    auth::{self, Admin},
+    channels,
+    config::{self, AppConfig, SnmpConfig},
+    events::{self, Severity},
+    history, session, tz,
+};
+
+const PATH: &str = "tokens.json";
+
+/// Each token's last use is saved at most this often, in ms; the list has the latest.
+const SAVE_USE_EVERY_MS: i64 = 60_000;
+
+const PREFIX: &str = "wm_";
+
+/// `None` until loaded from `PATH`.
+static TOKENS: Mutex<Option<Vec<Token>>> = Mutex::new(None);
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// The latest readings, and what's derived from them, like alerts and the score.
+    Readings,
+    /// History, and its exports.
+    History,
+    /// Stats over history.
+    Stats,
+}
+
+impl Scope {
+    const ALL: [Self; 3] = [Self::Readings, Self::History, Self::Stats];
+
+    /// The scope covering a route, by its path. Routes taking a `Viewer` need one.
+    pub fn of(path: &str) -> Option<Self> {
+        let path = path.trim_end_matches('/');
+        // Stable routes are under `/api/v1` too.
+        let path = match path.strip_prefix("/api/v1/") {
+            Some(rest) => format!("/api/{}", rest),
+            None => path.to_owned(),
+        };
+        match path.as_str() {
+            "/api/readings" | "/api/readings/text" | "/basic" | "/api/snapshot.png"
+            | "/api/changes" | "/api/channels" | "/api/devices" | "/api/sensors" | "/api/score"
+            | "/api/alerts" | "/api/events" | "/api/flow" | "/api/inputs"
+            | "/api/debug/samples" => Some(Self::Readings),
+            "/api/history" | "/api/export.parquet" | "/api/export.csv" => Some(Self::History),
+            p if p.starts_with("/api/inputs/") && p.ends_with("/changes") => Some(Self::History),
+            "/api/distribution" | "/api/compare" | "/api/reliability" => Some(Self::Stats),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Token {
+    id: String,
+    name: String,
+    /// Hex SHA-256 of the token.
+    hash: String,
+    scopes: Vec<Scope>,
+    /// Any, if empty.
+    #[serde(default)]
+    devices: Vec<String>,
+    created_ms: i64,
+    expires_ms: Option<i64>,
+    last_used_ms: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct TokenInfo {
+    id: String,
+    name: String,
+    scopes: Vec<Scope>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    devices: Vec<String>,
+    created: String,
+    expires: Option<String>,
+    expired: bool,
+    last_used: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct NewToken {
+    /// Who it's for, eg `pool service`.
+    name: String,
+    #[serde(default = "all_scopes")]
+    scopes: Vec<Scope>,
+    #[serde(default)]
+    devices: Vec<String>,
+    /// A time or date, as in `/api/history`.
+    expires: Option<String>,
+}
+
+fn all_scopes() -> Vec<Scope> {
+    Scope::ALL.to_vec()
+}
+
+/// A new token, which is shown only now.
+#[derive(Serialize)]
+struct Created {
+    token: String,
+    /// The readings, with the token, to share.
+    url: String,
+    #[serde(flatten)]
+    info: TokenInfo,
+}
+
+fn hash(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .fold(String::new(), |mut s, b| {
+            let _ = write!(s, "{:02x}", b);
+            s
+        })
+}
+
+impl Token {
+    fn expired(&self, now_ms: i64) -> bool {
+        self.expires_ms.is_some_and(|t| t <= now_ms)
+    }
+
+    /// If it's unexpired, and has `scope` for `device`.
+    fn covers(&self, scope: Scope, device: &str, now_ms: i64) -> bool {
+        !self.expired(now_ms)
+            && self.scopes.contains(&scope)
+            && (self.devices.is_empty() || self.devices.iter().any(|d| d == device))
+    }
+
+    fn info(&self) -> TokenInfo {
+        TokenInfo {
+            id: self.id.clone(),
+            name: self.name.clone(),
+            scopes: self.scopes.clone(),
+            devices: self.devices.clone(),
+            created: history::format_time(self.created_ms),
+            expires: self.expires_ms.map(history::format_time),
+            expired: self.expired(Utc::now().timestamp_millis()),
+            last_used: self.last_used_ms.map(history::format_time),
+        }
+    }
+}
+
+/// Check `auth.private` has admin access to make tokens with.
+pub fn check(cfg: &AppConfig) -> Result<(), io::Error> {
+    let invalid = |msg: &str| {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Config error: {}", msg),
+        ))
+    };
+    let auth = &cfg.auth;
+    if !auth.private {
+        return Ok(());
+    }
+    if auth.admin_token.is_none() && !session::enabled(auth) {
+        return invalid(
+            "`auth.private` needs `auth.admin_token` or a password, to make tokens with",
+        );
+    }
+    // These serve readings to anyone who asks.
+    for (listener, enabled) in [("coap", cfg.coap.enabled), ("modbus", cfg.modbus.enabled)] {
+        if enabled {
+            return invalid(&format!(
+                "`auth.private` can't keep readings private with `{}.enabled`; disable it",
+                listener
+            ));
+        }
+    }
+    if cfg.snmp.enabled && cfg.snmp.community == SnmpConfig::default().community {
+        return invalid(
+            "`auth.private` can't keep readings private with SNMP's default community; set \
+             `snmp.community` to a secret, or disable it",
+        );
+    }
+    Ok(())
+}
+
+fn load() -> Vec<Token> {
+    let tokens = match fs::read_to_string(PATH) {
+        Ok(s) => serde_json::from_str(&s).map_err(|e| e.to_string()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.to_string()),
+    };
+    tokens.unwrap_or_else(|e| {
+        events::record(
+            Severity::Warning,
+            "tokens",
+            format!("Problem reading tokens from `{}`; none work: {}", PATH, e),
+        );
+        Vec::new()
+    })
+}
+
+fn save(tokens: &[Token]) -> Result<(), io::Error> {
+    let tmp = format!("{}.tmp", PATH);
+    fs::write(&tmp, serde_json::to_string(tokens).unwrap())?;
+    fs::rename(&tmp, PATH)
+}
+
+fn with_tokens<T>(f: impl FnOnce(&mut Vec<Token>) -> T) -> T {
+    let mut tokens = TOKENS.lock().unwrap();
+    f(tokens.get_or_insert_with(load))
+}
+
+/// If `given` is a token for `scope` and `device` that hasn't expired, noting its use.
+fn allows(given: &str, scope: Scope, device: &str) -> bool {
+    let now_ms = Utc::now().timestamp_millis();
+    let given = hash(given);
+    with_tokens(|tokens| {
+        let Some(token) = tokens
+            .iter_mut()
+            .find(|t| auth::constant_time_eq(t.hash.as_bytes(), given.as_bytes()))
+        else {
+            return false;
+        };
+        if !token.covers(scope, device, now_ms) {
+            return false;
+        }
+        let saved = token.last_used_ms;
+        token.last_used_ms = Some(now_ms);
+        if saved.is_none_or(|t| now_ms - t >= SAVE_USE_EVERY_MS) {
+            // Worst case, a restart forgets a use.
+            let _ = save(tokens);
+        }
+        true
+    })
+}
+
+/// The device a request reads: its `?device=`, or this one.
+fn device_of(req: &Request) -> String {
+    req.get_query_value::<String>("device")
+        .and_then(Result::ok)
+        .unwrap_or_else(|| channels::LOCAL.to_owned())
+}
+
+/// The token in a request's `?token=`, or `Authorization: Bearer` header.
+fn given<'r>(req: &'r Request) -> Option<&'r str> {
+    let query = req.uri().query().and_then(|q| {
+        q.split('&')
+            .find_map(|pair| pair.strip_prefix("token="))
+            .filter(|t| !t.is_empty())
+    });
+    query.or_else(|| {
+        req.headers()
+            .get_one("Authorization")
+            .and_then(|h| h.strip_prefix("Bearer "))
+    })
+}
+
+/// A request guard for routes a scoped token can read. Open to anyone unless
+/// `auth.private` is set.
+pub struct Viewer;
+
+impl<'a, 'r> FromRequest<'a, 'r> for Viewer {
+    type Error = &'static str;
+
+    fn from_request(req: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        if !config::get().auth.private || req.guard::<Admin>().is_success() {
+            return Outcome::Success(Viewer);
+        }
+        match (given(req), Scope::of(req.uri().path())) {
+            (Some(token), Some(scope)) if allows(token, scope, &device_of(req)) => {
+                Outcome::Success(Viewer)
+            }
+            _ => Outcome::Failure((
+                Status::Unauthorized,
+                "Needs admin, or a token with this route's scope",
+            )),
+        }
+    }
+}
+
+fn list(tokens: &[Token]) -> content::Json<String> {
+    let infos: Vec<_> = tokens.iter().map(Token::info).collect();
+    content::Json(serde_json::to_string(&infos).unwrap())
+}
+
+/// Each token, without the token itself, and when it was last used.
+#[get("/tokens")]
+pub fn view_tokens(_admin: Admin) -> content::Json<String> {
+    with_tokens(|tokens| list(tokens))
+}
+
+/// Make a token, returning it, and a URL of the readings with it, to share.
+#[post("/tokens", data = "<data>")]
+pub fn create_token(_admin: Admin, data: Data) -> Result<content::Json<String>, ErrorResponse> {
+    let mut body = String::new();
+    data.open()
+        .take(16 * 1_024)
+        .read_to_string(&mut body)
+        .map_err(|e| api::error(Status::BadRequest, &e.to_string()))?;
+    let new: NewToken = serde_json::from_str(&body)
+        .map_err(|e| api::error(Status::BadRequest, &format!("Invalid token: {}", e)))?;
+
+    if new.name.is_empty() {
+        return Err(api::error(Status::BadRequest, "A token needs a `name`"));
+    }
+    if new.scopes.is_empty() {
+        return Err(api::error(
+            Status::BadRequest,
+            "A token needs at least one scope: `readings`, `history` or `stats`",
+        ));
+    }
+    let cfg = config::get();
+    if let Some(unknown) = new
+        .devices
+        .iter()
+        .find(|d| *d != channels::LOCAL && !cfg.devices.iter().any(|c| &c.name == *d))
+    {
+        return Err(api::error(
+            Status::BadRequest,
+            &format!("No device named `{}`", unknown),
+        ));
+    }
+    let expires_ms = match new.expires {
+        Some(t) => Some(history::parse_time(&t, "expires", tz::configured())?.timestamp_millis()),
+        None => None,
+    };
+
+    let secret = format!(
+        "{}{}",
+        PREFIX,
+        base64::encode_config(rand::random::<[u8; 24]>(), base64::URL_SAFE_NO_PAD)
+    );
+    let token = Token {
+        id: rand::random::<[u8; 4]>()
+            .iter()
+            .fold(String::new(), |mut s, b| {
+                let _ = write!(s, "{:02x}", b);
+                s
+            }),
+        name: new.name,
+        hash: hash(&secret),
+        scopes: new.scopes,
+        devices: new.devices,
+        created_ms: Utc::now().timestamp_millis(),
+        expires_ms,
+        last_used_ms: None,
+    };
+
+    let info = token.info();
+    with_tokens(|tokens| {
+        tokens.push(token);
+        save(tokens)
+    })
+    .map_err(|e| {
+        api::error(
+            Status::InternalServerError,
+            &format!("Problem saving the token: {}", e),
+        )
+    })?;
+
+    let created = Created {
+        url: format!("/api/v1/readings?token={}", secret),
+        token: secret,
+        info,
+    };
+    Ok(content::Json(serde_json::to_string(&created).unwrap()))
+}
+
+/// Revoke a token, returning those left.
+#[delete("/tokens/<id>")]
+pub fn delete_token(_admin: Admin, id: String) -> Result<content::Json<String>, ErrorResponse> {
+    with_tokens(|tokens| {
+        let len = tokens.len();
+        tokens.retain(|t| t.id != id);
+        if tokens.len() == len {
+            return Err(api::error(
+                Status::NotFound,
+                &format!("No token with id `{}`", id),
+            ));
+        }
+        save(tokens).map_err(|e| {
+            api::error(
+                Status::InternalServerError,
+                &format!("Problem saving tokens: {}", e),
+            )
+        })?;
+        Ok(list(tokens))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scopes_cover_read_routes() {
+        for path in [
+            "/api/readings",
+            "/api/v1/readings",
+            "/basic",
+            "/api/snapshot.png",
+            "/api/score/",
+        ] {
+            assert_eq!(Scope::of(path), Some(Scope::Readings), "{}", path);
+        }
+        for path in [
+            "/api/history",
+            "/api/v1/history",
+            "/api/export.csv",
+            "/api/inputs/float/changes",
+            "/api/v1/inputs/float/changes",
+        ] {
+            assert_eq!(Scope::of(path), Some(Scope::History), "{}", path);
+        }
+        assert_eq!(Scope::of("/api/reliability"), Some(Scope::Stats));
+        assert_eq!(Scope::of("/api/v1/compare"), Some(Scope::Stats));
+        assert_eq!(Scope::of("/api/tokens"), None);
+        assert_eq!(Scope::of("/api/inputs/float"), None);
+    }
+
+    fn token(devices: &[&str]) -> Token {
+        Token {
+            id: "t1".into(),
+            name: "pool service".into(),
+            hash: hash("wm_test"),
+            scopes: vec![Scope::History],
+            devices: devices.iter().map(|d| d.to_string()).collect(),
+            created_ms: 0,
+            expires_ms: Some(1_000),
+            last_used_ms: None,
+        }
+    }
+
+    #[test]
+    fn tokens_for_this_device_cant_read_others() {
+        let local = token(&[channels::LOCAL]);
+        assert!(local.covers(Scope::History, channels::LOCAL, 0));
+        assert!(!local.covers(Scope::History, "well", 0));
+    }
+
+    #[test]
+    fn tokens_for_a_pushing_device_can_read_it() {
+        let well = token(&["well"]);
+        assert!(well.covers(Scope::History, "well", 0));
+        assert!(!well.covers(Scope::History, channels::LOCAL, 0));
+        assert!(!well.covers(Scope::Readings, "well", 0));
+        assert!(!well.covers(Scope::History, "well", 1_000));
+
+        let any = token(&[]);
+        assert!(any.covers(Scope::History, "well", 0));
+        assert!(any.covers(Scope::History, channels::LOCAL, 0));
+    }
+
+    #[test]
+    fn private_refuses_open_listeners() {
+        let cfg = |extra: &str| -> AppConfig {
+            toml::from_str(&format!(
+                "[auth]\nprivate = true\nadmin_token = \"an-admin-token-that-is-long\"\n{}",
+                extra
+            ))
+            .unwrap()
+        };
+        assert!(check(&cfg("")).is_ok());
+        assert!(check(&cfg("[coap]\nenabled = true\n")).is_err());
+        assert!(check(&cfg("[modbus]\nenabled = true\n")).is_err());
+        assert!(check(&cfg("[snmp]\nenabled = true\n")).is_err());
+        assert!(check(&cfg("[snmp]\nenabled = true\ncommunity = \"s3cret\"\n")).is_ok());
+
+        let open: AppConfig = toml::from_str("[coap]\nenabled = true\n").unwrap();
+        assert!(check(&open).is_ok());
+    }
+}
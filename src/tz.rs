@@ -0,0 +1,78 @@
+//! The local timezone, for human-facing times. Times are stored and compared in UTC;
+//! the zone only changes how they're shown, and how times without an offset are read.
+
+use std::io;
+
+use chrono::{DateTime, LocalResult, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use rocket::http::Status;
+
+use crate::{
+    api::{self, ErrorResponse},
+    config::{self, TimeConfig},
+};
+
+pub fn parse(name: &str) -> Result<Tz, String> {
+    name.parse::<Tz>().map_err(|e| {
+        format!(
+            "`{}` isn't an IANA timezone name, like `Europe/London`: {}",
+            name, e
+        )
+    })
+}
+
+/// Check the configured timezone at startup.
+pub fn check(cfg: &TimeConfig) -> Result<(), io::Error> {
+    parse(&cfg.timezone)
+        .map(|_| ())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("Config error: {}", e)))
+}
+
+/// The configured timezone.
+pub fn configured() -> Tz {
+    parse(&config::get().time.timezone).unwrap_or(Tz::UTC)
+}
+
+/// The zone from a `?tz=` override, or else the configured one.
+pub fn from_query(tz: Option<&str>) -> Result<Tz, ErrorResponse> {
+    match tz {
+        Some(name) => parse(name).map_err(|e| api::error(Status::BadRequest, &e)),
+        None => Ok(configured()),
+    }
+}
+
+/// An RFC 3339 time with the zone's offset at that time, from ms since the epoch.
+pub fn format(ms: i64, tz: Tz) -> String {
+    tz.timestamp_millis_opt(ms).unwrap().to_rfc3339()
+}
+
+/// A local time in the zone. Times skipped when clocks go forward don't exist; times
+/// repeated when they go back are the first.
+fn local(t: &NaiveDateTime, tz: Tz) -> Option<DateTime<Utc>> {
+    match tz.from_local_datetime(t) {
+        LocalResult::Single(t) | LocalResult::Ambiguous(t, _) => Some(t.with_timezone(&Utc)),
+        LocalResult::None => None,
+    }
+}
+
+/// When a local day starts. That's usually midnight, but some zones change their clocks
+/// at midnight, skipping it.
+pub fn start_of_day(date: NaiveDate, tz: Tz) -> DateTime<Utc> {
+    (0..24)
+        .find_map(|hour| local(&date.and_time(NaiveTime::from_hms_opt(hour, 0, 0)?), tz))
+        .unwrap_or_else(|| Utc.from_utc_datetime(&date.and_time(NaiveTime::MIN)))
+}
+
+/// Parse an RFC 3339 time, a local time without an offset, like `2021-06-01T08:00:00`,
+/// or a date like `2021-06-01`, for the start of that local day.
+pub fn parse_time(s: &str, tz: Tz) -> Option<DateTime<Utc>> {
+    if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+        return Some(t.with_timezone(&Utc));
+    }
+    if let Ok(t) = NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S%.f") {
+        return local(&t, tz);
+    }
+    NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .ok()
+        .map(|d| start_of_day(d, tz))
+}
@@ -0,0 +1,71 @@
+//! Thresholds in the user's units. Alert rules and status targets are kept, and
+//! evaluated, in the registry's units, eg °C; the API takes and gives them in the
+//! locale's, eg °F in the US, with the unit alongside, so a number is never ambiguous.
+//! Changing the locale changes how thresholds are shown, not what they are.
+
+use crate::{
+    locale,
+    registry::{Kind, SensorDef},
+};
+
+const FAHRENHEIT: &str = "°F";
+
+/// A threshold's kind: a level, or a change, eg a trend's rate, which has no offset.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Quantity {
+    Level,
+    Change,
+}
+
+/// The unit `sensor`'s thresholds are shown in, per the locale.
+pub fn display(sensor: &SensorDef) -> &'static str {
+    locale::configured().unit(sensor)
+}
+
+/// The registry's unit for `sensor`, which thresholds are kept in.
+pub fn canonical(sensor: &SensorDef) -> &'static str {
+    sensor.unit
+}
+
+/// Check `sensor`'s thresholds can be given in `unit`.
+pub fn check(sensor: &SensorDef, unit: &str) -> Result<(), String> {
+    let temperature = sensor.kind == Kind::Temperature;
+    if unit == sensor.unit || (temperature && unit == FAHRENHEIT) {
+        return Ok(());
+    }
+    Err(format!(
+        "`{}` thresholds are in `{}`{}, not `{}`",
+        sensor.id,
+        sensor.unit,
+        if temperature { " or `°F`" } else { "" },
+        unit
+    ))
+}
+
+/// `value` in `unit`, in the registry's unit.
+fn to_canonical(sensor: &SensorDef, unit: &str, value: f32, quantity: Quantity) -> f32 {
+    match quantity {
+        _ if sensor.kind != Kind::Temperature || unit != FAHRENHEIT => value,
+        Quantity::Level => (value - 32.) / 1.8,
+        Quantity::Change => value / 1.8,
+    }
+}
+
+/// `value` in the registry's unit, in `unit`.
+fn from_canonical(sensor: &SensorDef, unit: &str, value: f32, quantity: Quantity) -> f32 {
+    match quantity {
+        _ if sensor.kind != Kind::Temperature || unit != FAHRENHEIT => value,
+        Quantity::Level => value * 1.8 + 32.,
+        Quantity::Change => value * 1.8,
+    }
+}
+
+/// `value` in `from`, in `to`. Both must pass `check`.
+pub fn convert(sensor: &SensorDef, from: &str, to: &str, value: f32, quantity: Quantity) -> f32 {
+    from_canonical(
+        sensor,
+        to,
+        to_canonical(sensor, from, value, quantity),
+        quantity,
+    )
+}
@@ -0,0 +1,112 @@
+//! Listening on a unix domain socket, for reverse proxies on the same machine. Rocket
+//! only serves TCP, so we accept connections on the socket and forward each to our
+//! own TCP listener on localhost.
+
+use std::io;
+
+use crate::config::ServerConfig;
+
+#[cfg(not(unix))]
+pub fn start(cfg: &ServerConfig) -> Result<(), io::Error> {
+    match &cfg.unix_socket {
+        None => Ok(()),
+        Some(_) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Config error: `server.unix_socket` is set, but unix domain sockets aren't \
+             supported on this platform.",
+        )),
+    }
+}
+
+/// Create the socket and start forwarding its connections, if one is configured.
+#[cfg(unix)]
+pub fn start(cfg: &ServerConfig) -> Result<(), io::Error> {
+    imp::start(cfg)
+}
+
+/// The banner lines describing the socket, if there is one.
+pub fn banner(cfg: &ServerConfig) -> Option<String> {
+    cfg.unix_socket.as_ref().map(|path| {
+        format!(
+            "Listening on the unix socket `{}`. For nginx, use eg:\n\n    \
+             proxy_pass http://unix:{}:;\n",
+            path, path
+        )
+    })
+}
+
+#[cfg(unix)]
+mod imp {
+    use std::{
+        ffi::CString,
+        fs,
+        io::{self, ErrorKind},
+        net::{Ipv4Addr, SocketAddr},
+        os::unix::{ffi::OsStrExt, fs::PermissionsExt, net::UnixListener},
+        path::Path,
+        sync::OnceLock,
+        thread,
+    };
+
+    use crate::{config::ServerConfig, net};
+
+    /// Kept for the signal handler, which unlinks the socket on shutdown.
+    static SOCKET_PATH: OnceLock<CString> = OnceLock::new();
+
+    pub fn start(cfg: &ServerConfig) -> Result<(), io::Error> {
+        let path = match &cfg.unix_socket {
+            Some(p) => Path::new(p),
+            None => return Ok(()),
+        };
+
+        let mode = u32::from_str_radix(&cfg.unix_socket_mode, 8).map_err(|_| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Config error: `server.unix_socket_mode` must be octal, eg \"660\"; got \"{}\".",
+                    cfg.unix_socket_mode
+                ),
+            )
+        })?;
+
+        // A socket left over from an unclean exit would make binding fail.
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+
+        let listener = UnixListener::bind(path)?;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+
+        let _ = SOCKET_PATH.set(CString::new(path.as_os_str().as_bytes())?);
+        unsafe {
+            libc::signal(
+                libc::SIGINT,
+                on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t,
+            );
+            libc::signal(
+                libc::SIGTERM,
+                on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t,
+            );
+        }
+
+        let backend = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), crate::listen_port(cfg));
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                net::forward_accepted(stream, backend, "unix socket");
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Unlink the socket, then die from the signal as we would have without a handler.
+    extern "C" fn on_signal(sig: libc::c_int) {
+        unsafe {
+            if let Some(path) = SOCKET_PATH.get() {
+                libc::unlink(path.as_ptr());
+            }
+            libc::signal(sig, libc::SIG_DFL);
+            libc::raise(sig);
+        }
+    }
+}
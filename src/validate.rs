@@ -0,0 +1,451 @@
+//! Checking a config before it's used: at startup, on reload, and with
+//! `water-mon-app check-config`. Each problem names its setting, by TOML path, and what
+//! to do about it. Errors stop the app starting, or a reload applying; warnings are
+//! settings the app works around, but that probably aren't what was meant.
+
+use std::{collections::BTreeMap, fmt, io, path::Path};
+
+use crate::{
+    alerts, channels,
+    config::{AppConfig, CellConstant, StatusConfig, CONFIG_PATH},
+    exporters, flow, history, indicator, inputs, locale, metrics, notify, precision, proxy,
+    registry, score, session, signing, snmp, tokens, tz, validation_hook, version, warmup,
+};
+
+const REDACTED: &str = "(redacted)";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Debug)]
+pub struct Problem {
+    pub severity: Severity,
+    /// The setting, eg `status.targets.pH`.
+    pub path: String,
+    pub message: String,
+    /// What to do about it, if the message doesn't say.
+    pub fix: Option<String>,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "`{}`: {}", self.path, self.message)?;
+        if let Some(fix) = &self.fix {
+            write!(f, " {}", fix)?;
+        }
+        Ok(())
+    }
+}
+
+fn problem(severity: Severity, path: impl Into<String>, message: &str, fix: &str) -> Problem {
+    Problem {
+        severity,
+        path: path.into(),
+        message: message.into(),
+        fix: Some(fix.into()),
+    }
+}
+
+/// A problem from one of the checks that returns the first it finds.
+fn from_check(path: &str, result: Result<(), io::Error>) -> Option<Problem> {
+    result.err().map(|e| {
+        let message = e.to_string();
+        Problem {
+            severity: Severity::Error,
+            path: path.into(),
+            message: message
+                .strip_prefix("Config error: ")
+                .unwrap_or(&message)
+                .into(),
+            fix: None,
+        }
+    })
+}
+
+/// Problems with the `[status]` settings. Warnings, since older versions accepted them,
+/// but `PUT /api/status/targets` rejects them.
+pub fn status_problems(cfg: &StatusConfig, probe: CellConstant) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    if cfg.warn_margin.is_nan() || cfg.warn_margin < 0. {
+        problems.push(problem(
+            Severity::Warning,
+            "status.warn_margin",
+            "Can't be negative.",
+            "Use 0 for no warn band.",
+        ));
+    }
+    for name in registry::ids() {
+        if let Some([min, max]) = cfg.targets.get(name, probe) {
+            if min.is_nan() || max.is_nan() || min > max {
+                problems.push(problem(
+                    Severity::Warning,
+                    format!("status.targets.{}", name),
+                    "The min is above the max.",
+                    "Give it as [min, max].",
+                ));
+            }
+        }
+    }
+    problems
+}
+
+/// Every problem with `cfg`.
+pub fn problems(cfg: &AppConfig) -> Vec<Problem> {
+    let mut problems: Vec<_> = [
+        from_check("time.timezone", tz::check(&cfg.time)),
+        from_check("locale.language", locale::check(&cfg.locale)),
+        from_check("precision", precision::check(cfg)),
+        from_check("warmup", warmup::check(cfg)),
+        from_check("auth.password_hash", session::check(&cfg.auth)),
+        from_check("auth.private", tokens::check(cfg)),
+        from_check("server.trusted_proxies", proxy::check(&cfg.server)),
+        from_check("channels", channels::check(cfg)),
+        from_check("exporters", exporters::check(cfg)),
+        from_check("metrics", metrics::check(cfg)),
+        from_check("inputs", inputs::check(cfg)),
+        from_check("flow", flow::check(cfg)),
+        from_check("validation_hook", validation_hook::check(cfg)),
+        from_check("indicator.max_override_mins", indicator::check(cfg)),
+        from_check("score", score::check(cfg)),
+        from_check("alerts", alerts::check(cfg)),
+        from_check("notify", notify::check(cfg)),
+        from_check("signing", signing::check(cfg)),
+        from_check("updates", version::check(cfg)),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+
+    let server = &cfg.server;
+    if server.unix_socket.is_some() && u32::from_str_radix(&server.unix_socket_mode, 8).is_err() {
+        problems.push(problem(
+            Severity::Error,
+            "server.unix_socket_mode",
+            &format!("\"{}\" isn't octal.", server.unix_socket_mode),
+            "Use eg \"660\".",
+        ));
+    }
+    if cfg.snmp.enabled {
+        problems.extend(from_check(
+            "snmp.oid_base",
+            snmp::oid_base(&cfg.snmp).map(|_| ()),
+        ));
+    }
+    problems.extend(status_problems(&cfg.status, cfg.ec.probe));
+
+    let probe = cfg.ec.probe;
+    for name in registry::ids() {
+        let [min, max] = cfg.history.plausible.get(name, probe);
+        if min.is_nan() || max.is_nan() || min > max {
+            problems.push(problem(
+                Severity::Warning,
+                format!("history.plausible.{}", name),
+                "The min is above the max, so every stored value would be flagged.",
+                "Give it as [min, max].",
+            ));
+        }
+    }
+    let gap_factor = cfg.history.gap_factor;
+    if gap_factor.is_nan() || gap_factor <= 0. {
+        problems.push(problem(
+            Severity::Warning,
+            "history.gap_factor",
+            "Isn't positive, so every interval between samples counts as a gap.",
+            "Use eg 5.",
+        ));
+    }
+
+    let polling = &cfg.polling;
+    if polling.samples_per_cycle == 0 {
+        problems.push(problem(
+            Severity::Warning,
+            "polling.samples_per_cycle",
+            "0 is treated as 1.",
+            "Use 1 or more.",
+        ));
+    }
+    if polling.adaptive && polling.idle_interval_secs == 0 {
+        problems.push(problem(
+            Severity::Warning,
+            "polling.idle_interval_secs",
+            "0 polls as fast as possible while idle, rather than slower.",
+            "Use eg 30.",
+        ));
+    }
+    if cfg.watchdog.enabled && cfg.watchdog.max_consecutive_failures == 0 {
+        problems.push(problem(
+            Severity::Warning,
+            "watchdog.max_consecutive_failures",
+            "0 reopens the serial port after every poll, even successful ones.",
+            "Use eg 5, or disable the watchdog.",
+        ));
+    }
+    if cfg.access_log.enabled && cfg.access_log.max_size_kb == 0 {
+        problems.push(problem(
+            Severity::Warning,
+            "access_log.max_size_kb",
+            "0 rotates the log on every request.",
+            "Use eg 1024.",
+        ));
+    }
+
+    let reliability = &cfg.reliability;
+    for window in reliability.windows.iter() {
+        if history::parse_duration(window).is_none() {
+            problems.push(problem(
+                Severity::Warning,
+                "reliability.windows",
+                &format!(
+                    "`{}` isn't a duration, so `/api/reliability` fails.",
+                    window
+                ),
+                "Use durations like `1h` or `7d`.",
+            ));
+        }
+    }
+    if let Some(pct) = reliability.alert_below_pct {
+        if !(0. ..=100.).contains(&pct) {
+            problems.push(problem(
+                Severity::Warning,
+                "reliability.alert_below_pct",
+                &format!("{} isn't a percentage.", pct),
+                "Use a number from 0 to 100, or remove it for no alert.",
+            ));
+        }
+    }
+
+    problems
+}
+
+/// Check a whole config, as at startup: the first error, if any.
+pub fn check(cfg: &AppConfig) -> Result<(), io::Error> {
+    match problems(cfg)
+        .into_iter()
+        .find(|p| p.severity == Severity::Error)
+    {
+        Some(p) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Config error: {}", p),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// `cfg`, with secrets like the admin token replaced, and the settings that were.
+fn redacted(cfg: &AppConfig) -> (AppConfig, Vec<String>) {
+    let mut cfg = cfg.clone();
+    let mut paths = Vec::new();
+    let auth = &mut cfg.auth;
+    for (name, secret) in [
+        ("admin_token", &mut auth.admin_token),
+        ("password_hash", &mut auth.password_hash),
+        ("session_key", &mut auth.session_key),
+    ] {
+        if secret.is_some() {
+            *secret = Some(REDACTED.into());
+            paths.push(format!("auth.{}", name));
+        }
+    }
+    cfg.snmp.community = REDACTED.into();
+    paths.push("snmp.community".into());
+    if cfg.signing.secret.is_some() {
+        cfg.signing.secret = Some(REDACTED.into());
+        paths.push("signing.secret".into());
+    }
+    for device in cfg.devices.iter_mut() {
+        if device.token.is_some() {
+            device.token = Some(REDACTED.into());
+            paths.push(format!("devices.{}.token", device.name));
+        }
+    }
+    // Headers often carry tokens, eg `Authorization`.
+    for exporter in cfg.exporters.iter_mut() {
+        for (header, value) in exporter.headers.iter_mut() {
+            *value = REDACTED.into();
+            paths.push(format!("exporters.{}.headers.{}", exporter.name, header));
+        }
+    }
+    for channel in cfg.notify.channels.iter_mut() {
+        for (name, secret) in [("token", &mut channel.token), ("user", &mut channel.user)] {
+            if secret.is_some() {
+                *secret = Some(REDACTED.into());
+                paths.push(format!("notify.channels.{}.{}", channel.name, name));
+            }
+        }
+        for (header, value) in channel.headers.iter_mut() {
+            *value = REDACTED.into();
+            paths.push(format!(
+                "notify.channels.{}.headers.{}",
+                channel.name, header
+            ));
+        }
+    }
+    (cfg, paths)
+}
+
+/// Put `current`'s secrets back where `cfg` has them redacted, eg in a config from a
+/// backup; those it doesn't have are removed. Covers what `redacted` does.
+pub fn unredact(cfg: &mut AppConfig, current: &AppConfig) {
+    let restore = |secret: &mut Option<String>, current: Option<&String>| {
+        if secret.as_deref() == Some(REDACTED) {
+            *secret = current.cloned();
+        }
+    };
+    let auth = &mut cfg.auth;
+    restore(&mut auth.admin_token, current.auth.admin_token.as_ref());
+    restore(&mut auth.password_hash, current.auth.password_hash.as_ref());
+    restore(&mut auth.session_key, current.auth.session_key.as_ref());
+    if cfg.snmp.community == REDACTED {
+        cfg.snmp.community = current.snmp.community.clone();
+    }
+    restore(&mut cfg.signing.secret, current.signing.secret.as_ref());
+    for device in cfg.devices.iter_mut() {
+        let old = current.devices.iter().find(|d| d.name == device.name);
+        restore(&mut device.token, old.and_then(|d| d.token.as_ref()));
+    }
+    let restore_headers = |headers: &mut BTreeMap<String, String>,
+                           old: Option<&BTreeMap<String, String>>| {
+        headers.retain(|header, value| {
+            if value != REDACTED {
+                return true;
+            }
+            match old.and_then(|o| o.get(header)) {
+                Some(v) => {
+                    *value = v.clone();
+                    true
+                }
+                None => false,
+            }
+        });
+    };
+    for exporter in cfg.exporters.iter_mut() {
+        let old = current.exporters.iter().find(|e| e.name == exporter.name);
+        restore_headers(&mut exporter.headers, old.map(|e| &e.headers));
+    }
+    for channel in cfg.notify.channels.iter_mut() {
+        let old = current
+            .notify
+            .channels
+            .iter()
+            .find(|c| c.name == channel.name);
+        restore(&mut channel.token, old.and_then(|c| c.token.as_ref()));
+        restore(&mut channel.user, old.and_then(|c| c.user.as_ref()));
+        restore_headers(&mut channel.headers, old.map(|c| &c.headers));
+    }
+}
+
+/// The config in effect, as TOML, with defaults filled in and secrets redacted, and the
+/// settings that were.
+pub fn effective(cfg: &AppConfig) -> Result<(String, Vec<String>), toml::ser::Error> {
+    let (cfg, paths) = redacted(cfg);
+    // Serialized through a `Value`, which puts tables after plain values, as TOML needs.
+    let text = toml::Value::try_from(cfg).and_then(|v| toml::to_string(&v))?;
+    Ok((text, paths))
+}
+
+/// `water-mon-app check-config`: check the config file at `path`, print each problem, and
+/// the config in effect, with defaults filled in. Returns false if it has errors.
+pub fn check_config(path: Option<&Path>) -> bool {
+    let path = path.unwrap_or_else(|| Path::new(CONFIG_PATH));
+    if !path.exists() {
+        println!(
+            "`{}` doesn't exist, so every setting has its default.\n",
+            path.display()
+        );
+    }
+    let cfg = match AppConfig::load(path) {
+        Ok(c) => c,
+        Err(e) => {
+            println!("Error: {}", e);
+            return false;
+        }
+    };
+
+    let problems = problems(&cfg);
+    let errors = problems
+        .iter()
+        .filter(|p| p.severity == Severity::Error)
+        .count();
+    for p in problems.iter() {
+        let severity = match p.severity {
+            Severity::Warning => "WARN ",
+            Severity::Error => "ERROR",
+        };
+        println!("{}  {}", severity, p.path);
+        println!("       {}", p.message);
+        if let Some(fix) = &p.fix {
+            println!("       Fix: {}", fix);
+        }
+    }
+    match (problems.len(), errors) {
+        (0, _) => println!("`{}` is valid.", path.display()),
+        (_, 0) => println!("Only warnings; the app will run."),
+        (n, e) => println!("{} problems, {} of them errors; the app won't start.", n, e),
+    }
+
+    match effective(&cfg) {
+        Ok((text, _)) => println!(
+            "\nThe config in effect, with defaults filled in and secrets redacted:\n\n{}",
+            text
+        ),
+        Err(e) => println!("Problem showing the config in effect: {}", e),
+    }
+
+    errors == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+        [auth]
+        admin_token = "an-admin-token-that-is-long"
+
+        [signing]
+        secret = "a-signing-secret"
+
+        [[exporters]]
+        name = "influx"
+        url = "http://localhost:8086/write"
+        body = "water ph={{pH}}"
+        headers = { Authorization = "Token abc" }
+    "#;
+
+    #[test]
+    fn effective_redacts_secrets() {
+        let cfg: AppConfig = toml::from_str(CONFIG).unwrap();
+        let (text, paths) = effective(&cfg).unwrap();
+        for secret in [
+            "an-admin-token-that-is-long",
+            "a-signing-secret",
+            "Token abc",
+        ] {
+            assert!(!text.contains(secret), "`{}` isn't redacted", secret);
+        }
+        assert!(paths.contains(&"signing.secret".to_string()));
+        assert!(paths.contains(&"exporters.influx.headers.Authorization".to_string()));
+    }
+
+    #[test]
+    fn unredact_restores_current_secrets() {
+        let current: AppConfig = toml::from_str(CONFIG).unwrap();
+        let (text, _) = effective(&current).unwrap();
+
+        let mut cfg: AppConfig = toml::from_str(&text).unwrap();
+        unredact(&mut cfg, &current);
+        assert_eq!(cfg.signing.secret.as_deref(), Some("a-signing-secret"));
+        assert_eq!(cfg.snmp.community, current.snmp.community);
+        assert_eq!(cfg.exporters[0].headers["Authorization"], "Token abc");
+
+        // Secrets we don't have are removed, rather than left as placeholders.
+        let mut cfg: AppConfig = toml::from_str(&text).unwrap();
+        unredact(&mut cfg, &toml::from_str("").unwrap());
+        assert_eq!(cfg.auth.admin_token, None);
+        assert_eq!(cfg.signing.secret, None);
+        assert!(cfg.exporters[0].headers.is_empty());
+    }
+}
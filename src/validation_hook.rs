@@ -0,0 +1,390 @@
+//! An external service that vets each cycle's readings before they're used, eg a lab's
+//! QC service. With `validation_hook.url` set, the poller posts each cycle's readings to
+//! it, and waits up to `timeout_ms` for its reply, which can accept each value, replace
+//! it, annotate it with a quality flag, or reject it. A rejected value is a `Rejected`
+//! error, with the status `rejected`, in the cache, history, alerts and everything else;
+//! flags are on readings responses, as `quality`. If the hook times out or fails,
+//! `on_failure` decides: `open` uses the readings as they are, and `closed` rejects them
+//! all. A failure, and recovery, are events, and the counts are at
+//! `/api/validation-hook`. Without a `url`, the poller doesn't call in here at all.
+//!
+//! The request's body is `{"time": ..., "instance_id": ..., "readings": {"pH": 7.4,
+//! "ORP": null}, "errors": {"ORP": "ProbeDisconnected"}}`, keyed by sensor id. A 2xx
+//! reply's body is `{"sensors": {"pH": {"verdict": "annotate", "flag": "suspect"},
+//! "T": {"verdict": "reject", "reason": "drift"}}}`, with `verdict` `accept`,
+//! `annotate` or `reject`, and optionally `value`, to replace the reading. Sensors left
+//! out, and an empty reply, are accepted.
+
+use std::{
+    collections::BTreeMap,
+    io,
+    sync::{mpsc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use rocket::response::content;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    config::{self, AppConfig, FailPolicy, ValidationHookConfig},
+    events::{self, Severity},
+    instance, net, registry, Readings, SensorError,
+};
+
+const SOURCE: &str = "validation_hook";
+
+/// Long enough for a local service; the poll cycle waits for it.
+const MAX_TIMEOUT_MS: u32 = 2_000;
+
+const MAX_FLAG_LEN: usize = 32;
+
+const MAX_REASON_LEN: usize = 256;
+
+static STATE: Mutex<State> = Mutex::new(State {
+    in_flight: false,
+    failing: false,
+    stats: Stats {
+        cycles: 0,
+        accepted: 0,
+        replaced: 0,
+        annotated: 0,
+        rejected: 0,
+        failures: 0,
+        last_latency_ms: None,
+        last_error: None,
+    },
+    quality: BTreeMap::new(),
+    rejections: BTreeMap::new(),
+});
+
+struct State {
+    /// If a request is still out, from a cycle that stopped waiting for it.
+    in_flight: bool,
+    /// If the last cycle's request failed.
+    failing: bool,
+    stats: Stats,
+    /// The last cycle's flags, by sensor id.
+    quality: BTreeMap<&'static str, Quality>,
+    /// The last cycle's rejections, by sensor id, with the hook's reasons.
+    rejections: BTreeMap<&'static str, Option<String>>,
+}
+
+#[derive(Clone, Serialize)]
+struct Stats {
+    /// Cycles sent to the hook.
+    cycles: u64,
+    /// Values, over every cycle.
+    accepted: u64,
+    replaced: u64,
+    annotated: u64,
+    rejected: u64,
+    /// Requests that timed out or failed.
+    failures: u64,
+    last_latency_ms: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_error: Option<String>,
+}
+
+/// A flag the hook attached to a value.
+#[derive(Clone, Serialize)]
+pub struct Quality {
+    pub flag: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+#[derive(Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Action {
+    Accept,
+    Annotate,
+    Reject,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Verdict {
+    verdict: Action,
+    /// Replaces the reading.
+    #[serde(default)]
+    value: Option<f32>,
+    #[serde(default)]
+    flag: Option<String>,
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+struct Reply {
+    #[serde(default)]
+    sensors: BTreeMap<String, Verdict>,
+}
+
+#[derive(Serialize)]
+struct HookStatus {
+    enabled: bool,
+    timeout_ms: u32,
+    on_failure: FailPolicy,
+    failing: bool,
+    #[serde(flatten)]
+    stats: Stats,
+    /// The last cycle's.
+    quality: BTreeMap<&'static str, Quality>,
+    /// The last cycle's, with the hook's reasons.
+    rejections: BTreeMap<&'static str, Option<String>>,
+}
+
+pub fn check(cfg: &AppConfig) -> Result<(), io::Error> {
+    let invalid = |msg: String| {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Config error: {}", msg),
+        ))
+    };
+    let hook = &cfg.validation_hook;
+    if let Some(url) = &hook.url {
+        if let Err(e) = net::split_url(url) {
+            return invalid(format!("`validation_hook.url`: {}", e));
+        }
+    }
+    if hook.timeout_ms == 0 || hook.timeout_ms > MAX_TIMEOUT_MS {
+        return invalid(format!(
+            "`validation_hook.timeout_ms` must be from 1 to {}",
+            MAX_TIMEOUT_MS
+        ));
+    }
+    Ok(())
+}
+
+/// What we post: every sensor's value, `null` for errors, and the errors.
+fn body(readings: &Readings) -> String {
+    let mut errors = BTreeMap::new();
+    for (sensor, reading) in readings.iter() {
+        if let Err(e) = reading {
+            errors.insert(sensor.id, e);
+        }
+    }
+    json!({
+        "time": Utc::now().to_rfc3339(),
+        "instance_id": instance::id(),
+        "readings": readings.map(|_, r| r.ok()),
+        "errors": errors,
+    })
+    .to_string()
+}
+
+/// Post `body`, waiting at most `timeout` for the reply's body. The request runs on its
+/// own thread, so one that's slow to connect, send, and reply, each within `timeout`,
+/// doesn't hold up the cycle longer; until it's done, later cycles fail straight away.
+fn call(url: &str, body: String, timeout: Duration) -> Result<String, String> {
+    {
+        let mut state = STATE.lock().unwrap();
+        if state.in_flight {
+            return Err("Its last request, which timed out, is still out".into());
+        }
+        state.in_flight = true;
+    }
+    let (tx, rx) = mpsc::channel();
+    let url = url.to_owned();
+    thread::spawn(move || {
+        let headers = [("Content-Type", "application/json")];
+        let response = net::http_request("POST", &url, &headers, &body, timeout);
+        STATE.lock().unwrap().in_flight = false;
+        let _ = tx.send(response);
+    });
+
+    match rx.recv_timeout(timeout) {
+        Ok(Ok(r)) if (200..300).contains(&r.status) => Ok(r.body),
+        Ok(Ok(r)) => Err(format!("It replied `{}`", r.status_line)),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err(format!("No reply within {} ms", timeout.as_millis())),
+    }
+}
+
+/// The hook's verdicts, by sensor id, checking they make sense.
+fn parse(body: &str) -> Result<BTreeMap<&'static str, Verdict>, String> {
+    let reply: Reply = if body.trim().is_empty() {
+        Reply::default()
+    } else {
+        serde_json::from_str(body).map_err(|e| format!("Invalid reply: {}", e))?
+    };
+    let mut verdicts = BTreeMap::new();
+    for (id, verdict) in reply.sensors {
+        let sensor = registry::get(&id).ok_or_else(|| format!("No sensor `{}`", id))?;
+        if let Some(value) = verdict.value {
+            let [min, max] = sensor.plausible;
+            if !value.is_finite() || value < min || value > max {
+                return Err(format!(
+                    "`{}`'s value of {} is outside its plausible range, {} to {}",
+                    id, value, min, max
+                ));
+            }
+        }
+        match &verdict.flag {
+            Some(flag)
+                if flag.is_empty()
+                    || flag.len() > MAX_FLAG_LEN
+                    || !flag
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') =>
+            {
+                return Err(format!(
+                    "`{}`'s flag must be 1 to {} letters, digits, `_` or `-`",
+                    id, MAX_FLAG_LEN
+                ));
+            }
+            None if verdict.verdict == Action::Annotate => {
+                return Err(format!("`{}` is annotated without a `flag`", id));
+            }
+            _ => (),
+        }
+        if verdict
+            .reason
+            .as_ref()
+            .is_some_and(|r| r.chars().count() > MAX_REASON_LEN)
+        {
+            return Err(format!(
+                "`{}`'s reason is over {} characters",
+                id, MAX_REASON_LEN
+            ));
+        }
+        verdicts.insert(sensor.id, verdict);
+    }
+    Ok(verdicts)
+}
+
+/// Vet `readings` with the hook, waiting at most `timeout_ms`. Called by the poller, for
+/// each cycle's readings, only while `validation_hook.url` is set.
+pub fn apply(readings: &mut Readings, cfg: &ValidationHookConfig) {
+    let url = match &cfg.url {
+        Some(url) => url,
+        None => return,
+    };
+    let started = Instant::now();
+    let timeout = Duration::from_millis(cfg.timeout_ms as u64);
+    let result = call(url, body(readings), timeout).and_then(|body| parse(&body));
+
+    let mut state = STATE.lock().unwrap();
+    state.stats.cycles += 1;
+    state.stats.last_latency_ms = Some(started.elapsed().as_millis() as u32);
+    state.quality.clear();
+    state.rejections.clear();
+    let event = match result {
+        Ok(mut verdicts) => {
+            for (sensor, reading) in readings.clone().iter() {
+                let value = match reading {
+                    Ok(v) => v,
+                    // Nothing to vet.
+                    Err(_) => continue,
+                };
+                let verdict = match verdicts.remove(sensor.id) {
+                    Some(v) => v,
+                    None => {
+                        state.stats.accepted += 1;
+                        continue;
+                    }
+                };
+                if verdict.verdict == Action::Reject {
+                    readings.set(sensor.id, Some(Err(SensorError::Rejected)));
+                    state.rejections.insert(sensor.id, verdict.reason);
+                    state.stats.rejected += 1;
+                    continue;
+                }
+                match verdict.value {
+                    Some(v) if v != value => {
+                        readings.set(sensor.id, Some(Ok(v)));
+                        state.stats.replaced += 1;
+                    }
+                    _ => state.stats.accepted += 1,
+                }
+                if let Some(flag) = verdict.flag {
+                    state.quality.insert(
+                        sensor.id,
+                        Quality {
+                            flag,
+                            reason: verdict.reason,
+                        },
+                    );
+                    state.stats.annotated += 1;
+                }
+            }
+            let recovered = state.failing;
+            state.failing = false;
+            state.stats.last_error = None;
+            recovered.then(|| {
+                (
+                    Severity::Info,
+                    "The validation hook is working again.".to_owned(),
+                )
+            })
+        }
+        Err(e) => {
+            state.stats.failures += 1;
+            let what = match cfg.on_failure {
+                FailPolicy::Open => "readings are used unvetted",
+                FailPolicy::Closed => "readings are rejected",
+            };
+            if cfg.on_failure == FailPolicy::Closed {
+                for (sensor, reading) in readings.clone().iter() {
+                    if reading.is_ok() {
+                        readings.set(sensor.id, Some(Err(SensorError::Rejected)));
+                        state.rejections.insert(sensor.id, Some(e.clone()));
+                        state.stats.rejected += 1;
+                    }
+                }
+            }
+            let failed = !state.failing;
+            state.failing = true;
+            state.stats.last_error = Some(e.clone());
+            failed.then(|| {
+                (
+                    Severity::Warning,
+                    format!(
+                        "Problem calling the validation hook: {}. Until it works again, {}.",
+                        e, what
+                    ),
+                )
+            })
+        }
+    };
+    drop(state);
+    if let Some((severity, message)) = event {
+        events::record(severity, SOURCE, message);
+    }
+}
+
+/// The last cycle's quality flags, by sensor. Empty while the hook is off.
+pub fn quality() -> BTreeMap<&'static str, Quality> {
+    if config::get().validation_hook.url.is_none() {
+        return BTreeMap::new();
+    }
+    STATE.lock().unwrap().quality.clone()
+}
+
+/// Forget the last cycle's flags and rejections. Called by the poller, only while the
+/// hook is on, after a cycle without readings.
+pub fn clear() {
+    let mut state = STATE.lock().unwrap();
+    state.quality.clear();
+    state.rejections.clear();
+}
+
+/// The hook's settings and counts, and the last cycle's flags and rejections.
+#[get("/validation-hook")]
+pub fn view_status() -> content::Json<String> {
+    let cfg = config::get().validation_hook;
+    let state = STATE.lock().unwrap();
+    let status = HookStatus {
+        enabled: cfg.url.is_some(),
+        timeout_ms: cfg.timeout_ms,
+        on_failure: cfg.on_failure,
+        failing: state.failing,
+        stats: state.stats.clone(),
+        quality: state.quality.clone(),
+        rejections: state.rejections.clone(),
+    };
+    content::Json(serde_json::to_string(&status).unwrap())
+}
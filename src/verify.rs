@@ -0,0 +1,294 @@
+//! Checks the history database for corruption, and its rows for values that can't be
+//! right: times that go backwards or are in the future, readings outside the configured
+//! plausible ranges, and aggregates that contradict themselves. Bad rows can be moved to
+//! the `quarantine` table, for a look later, rather than deleted.
+//!
+//! A quick check runs at startup; its result, or that of the latest check, is on
+//! `/api/health`.
+
+use std::{sync::Mutex, time::Duration};
+
+use chrono::Utc;
+use rocket::{http::Status, response::content};
+use rusqlite::{Connection, OpenFlags};
+use serde::Serialize;
+
+use crate::{
+    api::{self, ErrorResponse},
+    auth::{self, Admin},
+    config::{self, HistoryConfig},
+    events::{self, Severity},
+    history::{self, AGGREGATE_TIERS},
+    registry,
+};
+
+/// Times this far ahead of the clock are flagged as in the future.
+const FUTURE_TOLERANCE_MS: i64 = 60 * 1_000;
+
+/// Example times to report per problem.
+const EXAMPLES: u32 = 5;
+
+/// Integrity messages to report; SQLite can list a great many for a badly damaged file.
+const MAX_INTEGRITY_MESSAGES: u32 = 100;
+
+static LAST_CHECK: Mutex<Option<VerifyReport>> = Mutex::new(None);
+
+#[derive(Clone, Serialize)]
+pub struct Problem {
+    table: &'static str,
+    kind: String,
+    rows: u64,
+    /// Times of the first few rows with the problem.
+    examples: Vec<String>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct VerifyReport {
+    time: String,
+    /// If this was the quick check, which skips some of SQLite's index checks.
+    quick: bool,
+    ok: bool,
+    /// SQLite's integrity check messages; `["ok"]` if it passed.
+    integrity: Vec<String>,
+    problems: Vec<Problem>,
+    quarantined: u64,
+}
+
+/// A row check: rows of `table` matching `condition` have a problem.
+struct Check {
+    table: &'static str,
+    kind: String,
+    condition: String,
+}
+
+fn checks(cfg: &HistoryConfig, now: i64) -> Vec<Check> {
+    let probe = config::get().ec.probe;
+    let future = format!("time > {}", now + FUTURE_TOLERANCE_MS);
+
+    // There's one Water Monitor, so raw samples should be in time order by insertion.
+    let mut result = vec![
+        Check {
+            table: "samples",
+            kind: "time_backwards".into(),
+            condition: "rowid IN (SELECT rowid FROM (SELECT rowid, time, \
+                 LAG(time) OVER (ORDER BY rowid) AS prev FROM samples) WHERE time < prev)"
+                .into(),
+        },
+        Check {
+            table: "samples",
+            kind: "time_in_future".into(),
+            condition: future.clone(),
+        },
+    ];
+    for name in registry::ids() {
+        let [min, max] = cfg.plausible.get(name, probe);
+        result.push(Check {
+            table: "samples",
+            kind: format!("{}_implausible", name),
+            condition: format!("{0} < {1} OR {0} > {2}", name, min, max),
+        });
+    }
+
+    for (table, bucket_ms) in AGGREGATE_TIERS.iter() {
+        result.push(Check {
+            table,
+            kind: "time_in_future".into(),
+            condition: future.clone(),
+        });
+        result.push(Check {
+            table,
+            kind: "misaligned".into(),
+            condition: format!("time % {} != 0", bucket_ms),
+        });
+
+        let mut inconsistent = Vec::new();
+        for name in registry::ids() {
+            let [min, max] = cfg.plausible.get(name, probe);
+            result.push(Check {
+                table,
+                kind: format!("{}_implausible", name),
+                condition: format!("{0}_min < {1} OR {0}_max > {2}", name, min, max),
+            });
+            inconsistent.push(format!("{0}_n > count OR {0}_min > {0}_max", name));
+        }
+        result.push(Check {
+            table,
+            kind: "inconsistent".into(),
+            condition: inconsistent.join(" OR "),
+        });
+    }
+
+    result
+}
+
+/// The row as a JSON object, for the quarantine table.
+fn row_json(table: &str) -> String {
+    let mut columns = vec!["time".to_owned()];
+    if table != "samples" {
+        columns.push("count".into());
+    }
+    for name in registry::ids() {
+        if table == "samples" {
+            columns.push(name.to_string());
+        } else {
+            for stat in ["avg", "min", "max", "n"].iter() {
+                columns.push(format!("{}_{}", name, stat));
+            }
+        }
+    }
+
+    let pairs: Vec<String> = columns.iter().map(|c| format!("'{0}', {0}", c)).collect();
+    format!("json_object({})", pairs.join(", "))
+}
+
+/// Check the database. With `quarantine`, move rows with problems to the `quarantine`
+/// table; this needs a writable connection, and is skipped if the file is corrupt.
+pub fn verify(
+    conn: &Connection,
+    cfg: &HistoryConfig,
+    quick: bool,
+    quarantine: bool,
+) -> Result<VerifyReport, rusqlite::Error> {
+    let pragma = if quick {
+        "quick_check"
+    } else {
+        "integrity_check"
+    };
+    let integrity = {
+        let mut stmt = conn.prepare(&format!("PRAGMA {}({})", pragma, MAX_INTEGRITY_MESSAGES))?;
+        let rows = stmt.query_map([], |row| row.get(0))?;
+        rows.collect::<Result<Vec<String>, _>>()?
+    };
+    let intact = integrity == ["ok"];
+
+    let now = Utc::now().timestamp_millis();
+    let mut problems = Vec::new();
+    let mut quarantined = 0;
+
+    for check in checks(cfg, now) {
+        let rows: i64 = conn.query_row(
+            &format!(
+                "SELECT COUNT(*) FROM {} WHERE {}",
+                check.table, check.condition
+            ),
+            [],
+            |row| row.get(0),
+        )?;
+        if rows == 0 {
+            continue;
+        }
+
+        let examples = {
+            let mut stmt = conn.prepare(&format!(
+                "SELECT time FROM {} WHERE {} ORDER BY time LIMIT {}",
+                check.table, check.condition, EXAMPLES
+            ))?;
+            let times = stmt.query_map([], |row| row.get(0))?;
+            times
+                .map(|t| t.map(history::format_time))
+                .collect::<Result<Vec<_>, _>>()?
+        };
+
+        if quarantine && intact {
+            let _lock = history::lock_writes();
+            let tx = conn.unchecked_transaction()?;
+            tx.execute(
+                &format!(
+                    "INSERT INTO quarantine (quarantined, source, reason, time, row) \
+                     SELECT ?1, ?2, ?3, time, {} FROM {} WHERE {}",
+                    row_json(check.table),
+                    check.table,
+                    check.condition
+                ),
+                rusqlite::params![now, check.table, check.kind],
+            )?;
+            quarantined += tx.execute(
+                &format!("DELETE FROM {} WHERE {}", check.table, check.condition),
+                [],
+            )? as u64;
+            tx.commit()?;
+        }
+
+        problems.push(Problem {
+            table: check.table,
+            kind: check.kind,
+            rows: rows as u64,
+            examples,
+        });
+    }
+
+    Ok(VerifyReport {
+        time: Utc::now().to_rfc3339(),
+        quick,
+        ok: intact && problems.is_empty(),
+        integrity,
+        problems,
+        quarantined,
+    })
+}
+
+fn report(verified: &VerifyReport) {
+    if !verified.ok {
+        let rows: u64 = verified.problems.iter().map(|p| p.rows).sum();
+        events::record(
+            Severity::Alert,
+            "verify",
+            format!(
+                "History check found problems: integrity `{}`, and {} rows with bad values. \
+                 See `/api/health`.",
+                verified.integrity.join("; "),
+                rows
+            ),
+        );
+    }
+    *LAST_CHECK.lock().unwrap() = Some(verified.clone());
+}
+
+/// The latest check, for `/api/health`.
+pub fn last_check() -> Option<VerifyReport> {
+    LAST_CHECK.lock().unwrap().clone()
+}
+
+/// Run the quick check. Called on its own thread at startup.
+pub fn startup() {
+    let cfg = config::get().history;
+    let result = Connection::open_with_flags(&cfg.path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .and_then(|c| c.busy_timeout(Duration::from_secs(10)).map(|_| c))
+        .and_then(|c| verify(&c, &cfg, true, false));
+
+    match result {
+        Ok(r) => report(&r),
+        Err(e) => events::record(
+            Severity::Warning,
+            "verify",
+            format!("Problem checking the history database: {}", e),
+        ),
+    }
+}
+
+/// Run the full check. With `quarantine=true`, which needs admin, rows with problems are
+/// moved to the `quarantine` table.
+#[post("/storage/verify?<quarantine>")]
+pub fn verify_storage(
+    admin: Result<Admin, &'static str>,
+    quarantine: Option<bool>,
+) -> Result<content::Json<String>, ErrorResponse> {
+    let cfg = config::get().history;
+    let quarantine = quarantine == Some(true);
+
+    let conn = if quarantine {
+        auth::require(admin)?;
+        if !cfg.enabled {
+            return Err(api::error(Status::NotFound, "History is disabled"));
+        }
+        history::open(&cfg.path)
+            .map_err(|e| api::error(Status::InternalServerError, &e.to_string()))?
+    } else {
+        history::open_reader()?
+    };
+
+    let verified = verify(&conn, &cfg, false, quarantine).map_err(history::query_error)?;
+    report(&verified);
+
+    Ok(content::Json(serde_json::to_string(&verified).unwrap()))
+}
@@ -0,0 +1,240 @@
+//! What this build is, at `/api/version`, and, if `updates.check` is set, whether
+//! there's a newer release. The check fetches `updates.url` daily, on its own thread,
+//! so a network that's down never holds up startup. A newer release shows on
+//! `/api/health`, and is noted once as an info event. Nothing is downloaded or
+//! installed. It's off by default, so nothing is sent anywhere unasked.
+
+use std::{io, sync::Mutex, thread, time::Duration};
+
+use chrono::{TimeZone, Utc};
+use rocket::response::content;
+use serde::Serialize;
+
+use crate::{
+    config::{self, AppConfig},
+    events::{self, Severity},
+    history, net,
+};
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+const CHECK_EVERY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// After a failed check.
+const RETRY_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// How often to see if `updates.check` was turned on.
+const IDLE_INTERVAL: Duration = Duration::from_secs(60);
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+static STATUS: Mutex<Option<UpdateStatus>> = Mutex::new(None);
+
+#[derive(Serialize)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    /// Absent when built without git, eg from a source archive. `-dirty` if there were
+    /// uncommitted changes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub git_commit: Option<&'static str>,
+    /// RFC 3339, UTC.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub built: Option<String>,
+    pub features: Vec<&'static str>,
+    pub os: &'static str,
+    pub arch: &'static str,
+    pub debug: bool,
+}
+
+#[derive(Clone, Serialize)]
+pub struct UpdateStatus {
+    /// RFC 3339.
+    pub checked: String,
+    /// The latest release, eg `v1.2.0`, as the releases URL gave it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latest: Option<String>,
+    pub update_available: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct VersionResponse {
+    #[serde(flatten)]
+    build: BuildInfo,
+    /// `None` until checked, and without `updates.check`.
+    update: Option<UpdateStatus>,
+}
+
+/// The Cargo features this was built with.
+pub fn features() -> Vec<&'static str> {
+    let mut features = Vec::new();
+    if cfg!(feature = "flight-controller") {
+        features.push("flight-controller");
+    }
+    if cfg!(feature = "gpio") {
+        features.push("gpio");
+    }
+    features
+}
+
+pub fn build_info() -> BuildInfo {
+    BuildInfo {
+        version: VERSION,
+        git_commit: option_env!("WATER_MON_GIT_COMMIT"),
+        built: option_env!("WATER_MON_BUILD_TIME")
+            .and_then(|t| t.parse().ok())
+            .and_then(|t| Utc.timestamp_opt(t, 0).single())
+            .map(|t| t.to_rfc3339()),
+        features: features(),
+        os: std::env::consts::OS,
+        arch: std::env::consts::ARCH,
+        debug: cfg!(debug_assertions),
+    }
+}
+
+/// Check `[updates]`.
+pub fn check(cfg: &AppConfig) -> Result<(), io::Error> {
+    let invalid = |msg: String| {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Config error: {}", msg),
+        ))
+    };
+    match &cfg.updates.url {
+        Some(url) => {
+            if let Err(e) = net::split_url(url) {
+                return invalid(format!("`updates.url`: {}", e));
+            }
+        }
+        None if cfg.updates.check => {
+            return invalid("`updates.check` is set, but `updates.url` isn't".into())
+        }
+        None => (),
+    }
+    Ok(())
+}
+
+/// A version's numbers, eg `[1, 2, 0]` for `v1.2.0`, without any pre-release or build
+/// suffix.
+fn numbers(version: &str) -> Option<Vec<u64>> {
+    let version = version.trim().trim_start_matches(['v', 'V']);
+    let version = version.split(['-', '+']).next()?;
+    version.split('.').map(|n| n.parse().ok()).collect()
+}
+
+/// If `latest` is a later version than `current`. Missing numbers count as 0.
+fn newer(latest: &str, current: &str) -> bool {
+    match (numbers(latest), numbers(current)) {
+        (Some(mut latest), Some(mut current)) => {
+            let len = latest.len().max(current.len());
+            latest.resize(len, 0);
+            current.resize(len, 0);
+            latest > current
+        }
+        _ => false,
+    }
+}
+
+/// The latest release, from the releases URL.
+fn fetch(url: &str) -> Result<String, String> {
+    let user_agent = format!("water-mon-app/{}", VERSION);
+    let headers = [
+        ("Accept", "application/json"),
+        ("User-Agent", user_agent.as_str()),
+    ];
+    let response =
+        net::http_request("GET", url, &headers, "", TIMEOUT).map_err(|e| e.to_string())?;
+    if response.status != 200 {
+        return Err(format!("`{}` returned {}", url, response.status_line));
+    }
+    let release: serde_json::Value = serde_json::from_str(&response.body)
+        .map_err(|e| format!("`{}` didn't return JSON: {}", url, e))?;
+    ["tag_name", "version"]
+        .iter()
+        .find_map(|key| release.get(key).and_then(|v| v.as_str()))
+        .map(str::to_owned)
+        .ok_or_else(|| format!("`{}` returned no `tag_name` or `version`", url))
+}
+
+/// `None` until checked, and without `updates.check`.
+pub fn update_status() -> Option<UpdateStatus> {
+    if !config::get().updates.check {
+        return None;
+    }
+    STATUS.lock().unwrap().clone()
+}
+
+/// The newer release, if there is one, for `/api/health`.
+pub fn update_available() -> Option<String> {
+    update_status()
+        .filter(|s| s.update_available)
+        .and_then(|s| s.latest)
+}
+
+/// Check for updates daily, while `updates.check` is set. Runs on its own thread.
+pub fn run() {
+    let mut noted: Option<String> = None;
+    loop {
+        let cfg = config::get().updates;
+        let url = match (&cfg.url, cfg.check) {
+            (Some(url), true) => url.clone(),
+            _ => {
+                thread::sleep(IDLE_INTERVAL);
+                continue;
+            }
+        };
+
+        let checked = history::format_time(Utc::now().timestamp_millis());
+        let status = match fetch(&url) {
+            Ok(latest) => UpdateStatus {
+                checked,
+                update_available: newer(&latest, VERSION),
+                latest: Some(latest),
+                error: None,
+            },
+            Err(e) => UpdateStatus {
+                checked,
+                latest: None,
+                update_available: false,
+                error: Some(e),
+            },
+        };
+
+        if status.update_available && noted != status.latest {
+            noted = status.latest.clone();
+            events::record(
+                Severity::Info,
+                "updates",
+                format!(
+                    "Update available: {}; this is version {}",
+                    status.latest.as_deref().unwrap_or_default(),
+                    VERSION
+                ),
+            );
+        }
+        let wait = if status.error.is_some() {
+            RETRY_AFTER
+        } else {
+            CHECK_EVERY
+        };
+        *STATUS.lock().unwrap() = Some(status);
+
+        // In steps, so turning the check off stops it.
+        let mut waited = Duration::ZERO;
+        while waited < wait && config::get().updates.check {
+            thread::sleep(IDLE_INTERVAL);
+            waited += IDLE_INTERVAL;
+        }
+    }
+}
+
+/// The version, git commit, build time and features, and the latest update check.
+#[get("/version")]
+pub fn view_version() -> content::Json<String> {
+    let response = VersionResponse {
+        build: build_info(),
+        update: update_status(),
+    };
+    content::Json(serde_json::to_string(&response).unwrap())
+}
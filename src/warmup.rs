@@ -0,0 +1,256 @@
+//! Probe warm-up. Some probes, ORP especially, drift for minutes after power-up while
+//! they condition, so their first readings aren't the water's. After the Water Monitor
+//! connects, or a probe reconnects after reading `ProbeDisconnected`, a sensor with a
+//! warm-up policy is `stabilizing`: until its readings over `window_secs` vary by less
+//! than `max_std_dev`, or for `max_secs` at most. Meanwhile its alert conditions aren't
+//! tested, it's left out of history's stats and compaction, and its readings are still
+//! stored, flagged, and shown, with an estimate of how long is left.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::{
+    config::{self, AppConfig, WarmupConfig, WarmupPolicy},
+    events::{self, Severity},
+    memory::{Buffer, Policy},
+    registry::{self, Kind, SensorDef, SensorMap, REGISTRY},
+    Readings, SensorError,
+};
+
+/// Fewer readings than this in the window don't show it's settled.
+const MIN_WINDOW_SAMPLES: usize = 3;
+
+/// Readings per window, however long `window_secs` is.
+const MAX_WINDOW_LEN: usize = 10_000;
+
+pub const BUFFER: Buffer = Buffer {
+    name: "warmup.window",
+    cap: MAX_WINDOW_LEN,
+    policy: Policy::DropOldest,
+    about: "Readings over `window_secs`, per sensor warming up",
+};
+
+/// Sensors warming up, by id. Settled ones aren't here.
+static STATE: Mutex<BTreeMap<&'static str, Warming>> = Mutex::new(BTreeMap::new());
+
+struct Warming {
+    since: Instant,
+    /// Readings over the last `window_secs`, oldest first.
+    window: VecDeque<(Instant, f32)>,
+}
+
+impl Warming {
+    fn new(now: Instant) -> Self {
+        Self {
+            since: now,
+            window: VecDeque::new(),
+        }
+    }
+
+    /// The window's standard deviation, once it has enough readings.
+    fn std_dev(&self) -> Option<f32> {
+        if self.window.len() < MIN_WINDOW_SAMPLES {
+            return None;
+        }
+        let n = self.window.len() as f32;
+        let mean = self.window.iter().map(|(_, v)| v).sum::<f32>() / n;
+        let variance = self
+            .window
+            .iter()
+            .map(|(_, v)| (v - mean).powi(2))
+            .sum::<f32>()
+            / n;
+        Some(variance.sqrt())
+    }
+}
+
+/// A sensor that's stabilizing, for the readings response.
+#[derive(Clone, Copy, Serialize)]
+pub struct Stabilizing {
+    pub elapsed_secs: f32,
+    /// At most this long left, at `max_secs`; it may settle sooner.
+    pub remaining_secs: f32,
+    /// Of the readings over `window_secs`, once there are a few.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub std_dev: Option<f32>,
+}
+
+fn default_policy(kind: Kind) -> Option<WarmupPolicy> {
+    match kind {
+        Kind::Orp => Some(WarmupPolicy {
+            max_secs: 600,
+            max_std_dev: Some(2.),
+            window_secs: 60,
+        }),
+        _ => None,
+    }
+}
+
+/// A sensor's warm-up policy, if it has one.
+pub fn policy(sensor: &SensorDef, cfg: &WarmupConfig) -> Option<WarmupPolicy> {
+    if !cfg.enabled {
+        return None;
+    }
+    cfg.sensors
+        .get(sensor.id)
+        .copied()
+        .or_else(|| default_policy(sensor.kind))
+        .filter(|p| p.max_secs > 0)
+}
+
+/// Check `[warmup]`.
+pub fn check(cfg: &AppConfig) -> Result<(), io::Error> {
+    let invalid = |msg: String| {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Config error: {}", msg),
+        ))
+    };
+    for (sensor, policy) in cfg.warmup.sensors.iter() {
+        if registry::get(sensor).is_none() {
+            return invalid(format!(
+                "`warmup.sensors` has an unknown sensor, `{}`",
+                sensor
+            ));
+        }
+        if let Some(max) = policy.max_std_dev {
+            if !max.is_finite() || max <= 0. {
+                return invalid(format!(
+                    "`warmup.sensors.{}.max_std_dev` must be above 0",
+                    sensor
+                ));
+            }
+            if policy.window_secs == 0 {
+                return invalid(format!(
+                    "`warmup.sensors.{}.window_secs` must be above 0 with `max_std_dev`",
+                    sensor
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Start warming up every sensor with a policy. Called by the poller when it connects
+/// to the Water Monitor, which powers the probes.
+pub fn restart() {
+    let cfg = config::get().warmup;
+    let now = Instant::now();
+    let mut state = STATE.lock().unwrap();
+    state.clear();
+    for sensor in REGISTRY.iter() {
+        if policy(sensor, &cfg).is_some() {
+            state.insert(sensor.id, Warming::new(now));
+        }
+    }
+}
+
+/// Note a cycle's readings, settling sensors that have stabilized. Called by the poller,
+/// before the readings are stored.
+pub fn update(readings: &Readings) {
+    let cfg = config::get().warmup;
+    let now = Instant::now();
+    let mut settled = Vec::new();
+    {
+        let mut state = STATE.lock().unwrap();
+        for sensor in REGISTRY.iter() {
+            let policy = match policy(sensor, &cfg) {
+                Some(p) => p,
+                None => {
+                    state.remove(sensor.id);
+                    continue;
+                }
+            };
+            let value = match readings.get(sensor.id) {
+                Some(Ok(v)) => v,
+                // Until it's back, so its warm-up starts then.
+                Some(Err(SensorError::ProbeDisconnected)) => {
+                    state.insert(sensor.id, Warming::new(now));
+                    continue;
+                }
+                _ => continue,
+            };
+            let warming = match state.get_mut(sensor.id) {
+                Some(w) => w,
+                None => continue,
+            };
+
+            let window = Duration::from_secs(policy.window_secs as u64);
+            if warming.window.len() == MAX_WINDOW_LEN {
+                warming.window.pop_front();
+                BUFFER.dropped(1);
+            }
+            warming.window.push_back((now, value));
+            while warming
+                .window
+                .front()
+                .is_some_and(|(t, _)| now.duration_since(*t) > window)
+            {
+                warming.window.pop_front();
+            }
+            BUFFER.note(warming.window.len());
+
+            let elapsed = now.duration_since(warming.since);
+            let steady = elapsed >= window
+                && policy
+                    .max_std_dev
+                    .zip(warming.std_dev())
+                    .is_some_and(|(max, sd)| sd < max);
+            if steady || elapsed.as_secs() >= policy.max_secs as u64 {
+                settled.push((sensor.id, elapsed));
+            }
+        }
+        for (id, _) in settled.iter() {
+            state.remove(id);
+        }
+    }
+
+    for (id, elapsed) in settled {
+        events::record(
+            Severity::Info,
+            "warmup",
+            format!("{} stabilized after {:.0} s", id, elapsed.as_secs_f32()),
+        );
+    }
+}
+
+/// If a sensor is stabilizing, so its readings don't count yet.
+pub fn stabilizing(sensor: &str) -> bool {
+    STATE.lock().unwrap().contains_key(sensor)
+}
+
+/// Each sensor that's stabilizing, with how long it's been, and how long is left.
+pub fn status() -> SensorMap<Stabilizing> {
+    let cfg = config::get().warmup;
+    let state = STATE.lock().unwrap();
+    let mut result = SensorMap::empty();
+    for sensor in REGISTRY.iter() {
+        if let (Some(warming), Some(policy)) = (state.get(sensor.id), policy(sensor, &cfg)) {
+            let elapsed = warming.since.elapsed().as_secs_f32();
+            result.set(
+                sensor.id,
+                Some(Stabilizing {
+                    elapsed_secs: elapsed,
+                    remaining_secs: (policy.max_secs as f32 - elapsed).max(0.),
+                    std_dev: warming.std_dev(),
+                }),
+            );
+        }
+    }
+    result
+}
+
+/// The sensors stabilizing, as a bit per sensor, in registry order, for history.
+pub fn mask() -> i64 {
+    let state = STATE.lock().unwrap();
+    registry::ids()
+        .enumerate()
+        .filter(|(_, id)| state.contains_key(id))
+        .fold(0, |mask, (i, _)| mask | 1 << i)
+}
@@ -0,0 +1,189 @@
+//! Running as a Windows service, so users don't have to keep a console window open.
+//! The service runs this executable with `service run`; without that, the app runs in
+//! the console as usual.
+//!
+//! Serial discovery already matches the Water Monitor by its USB serial number rather
+//! than its COM port, so the port number changing between boots is fine.
+
+use std::io;
+
+use crate::cli::ServiceCommand;
+
+pub const SERVICE_NAME: &str = "water-mon-app";
+
+#[cfg(not(windows))]
+pub fn handle(_cmd: ServiceCommand) -> Result<(), io::Error> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        format!(
+            "The `{}` service is only supported on Windows. On Linux, see `install-service`.",
+            SERVICE_NAME
+        ),
+    ))
+}
+
+#[cfg(windows)]
+pub fn handle(cmd: ServiceCommand) -> Result<(), io::Error> {
+    match cmd {
+        ServiceCommand::Install => imp::install(),
+        ServiceCommand::Uninstall => imp::uninstall(),
+        ServiceCommand::Start => imp::start(),
+        ServiceCommand::Run => imp::run(),
+    }
+    .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("Service error: {}", e)))
+}
+
+#[cfg(windows)]
+mod imp {
+    use std::{
+        env,
+        ffi::{OsStr, OsString},
+        sync::mpsc,
+        thread,
+        time::Duration,
+    };
+
+    use windows_service::{
+        define_windows_service,
+        service::{
+            ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl,
+            ServiceExitCode, ServiceInfo, ServiceStartType, ServiceState, ServiceStatus,
+            ServiceType,
+        },
+        service_control_handler::{self, ServiceControlHandlerResult},
+        service_dispatcher,
+        service_manager::{ServiceManager, ServiceManagerAccess},
+        Result,
+    };
+
+    use super::SERVICE_NAME;
+    use crate::events::{self, Severity};
+
+    /// Written next to the executable, since a service has no console.
+    const LOG_FILE: &str = "water-mon.log";
+
+    pub fn install() -> Result<()> {
+        let manager = ServiceManager::local_computer(
+            None::<&str>,
+            ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+        )?;
+
+        let info = ServiceInfo {
+            name: SERVICE_NAME.into(),
+            display_name: "AnyLeaf Water Monitor".into(),
+            service_type: ServiceType::OWN_PROCESS,
+            start_type: ServiceStartType::AutoStart,
+            error_control: ServiceErrorControl::Normal,
+            executable_path: env::current_exe().map_err(windows_service::Error::Winapi)?,
+            launch_arguments: vec!["service".into(), "run".into()],
+            dependencies: vec![],
+            account_name: None,
+            account_password: None,
+        };
+
+        let service = manager.create_service(&info, ServiceAccess::CHANGE_CONFIG)?;
+        service.set_description("Serves readings from an AnyLeaf Water Monitor")?;
+
+        println!(
+            "Installed the `{}` service; it starts on boot. Start it now with \
+             `water-mon-app service start`.",
+            SERVICE_NAME
+        );
+        Ok(())
+    }
+
+    pub fn uninstall() -> Result<()> {
+        let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+        let service = manager.open_service(
+            SERVICE_NAME,
+            ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE,
+        )?;
+
+        if service.query_status()?.current_state != ServiceState::Stopped {
+            service.stop()?;
+        }
+        service.delete()?;
+
+        println!("Uninstalled the `{}` service.", SERVICE_NAME);
+        Ok(())
+    }
+
+    pub fn start() -> Result<()> {
+        let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+        let service = manager.open_service(SERVICE_NAME, ServiceAccess::START)?;
+        service.start(&[] as &[&OsStr])?;
+
+        println!("Started the `{}` service.", SERVICE_NAME);
+        Ok(())
+    }
+
+    define_windows_service!(ffi_service_main, service_main);
+
+    /// Hand this process to the service manager, which calls `service_main`.
+    pub fn run() -> Result<()> {
+        service_dispatcher::start(SERVICE_NAME, ffi_service_main)
+    }
+
+    fn service_main(_args: Vec<OsString>) {
+        if let Err(e) = run_service() {
+            events::record(
+                Severity::Alert,
+                "service",
+                format!("The service failed: {}", e),
+            );
+        }
+    }
+
+    fn run_service() -> Result<()> {
+        // Services start in System32; run from the executable's directory so we find
+        // `static` and the config file.
+        if let Some(dir) = env::current_exe()
+            .ok()
+            .and_then(|p| p.parent().map(|p| p.to_owned()))
+        {
+            let _ = env::set_current_dir(&dir);
+        }
+        let _ = events::set_log_file(LOG_FILE.as_ref());
+
+        let (stop_tx, stop_rx) = mpsc::channel();
+
+        let handler = move |control: ServiceControl| match control {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                let _ = stop_tx.send(());
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        };
+        let status = service_control_handler::register(SERVICE_NAME, handler)?;
+
+        let set_state = |state: ServiceState, controls_accepted: ServiceControlAccept| {
+            status.set_service_status(ServiceStatus {
+                service_type: ServiceType::OWN_PROCESS,
+                current_state: state,
+                controls_accepted,
+                exit_code: ServiceExitCode::Win32(0),
+                checkpoint: 0,
+                wait_hint: Duration::from_secs(5),
+                process_id: None,
+            })
+        };
+
+        set_state(
+            ServiceState::Running,
+            ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+        )?;
+
+        thread::spawn(|| crate::run_server(crate::ReadingsSource::Device));
+        events::record(Severity::Info, "service", "Started the service.");
+
+        // Rocket can't be stopped from outside; once we've reported stopping, exiting
+        // the process is our shutdown.
+        let _ = stop_rx.recv();
+        events::record(Severity::Info, "service", "Stopping the service.");
+        set_state(ServiceState::StopPending, ServiceControlAccept::empty())?;
+        set_state(ServiceState::Stopped, ServiceControlAccept::empty())?;
+
+        std::process::exit(0);
+    }
+}
@@ -11,7 +11,7 @@ function format(val, precision) {
 function update_readings() {
     // Request readings, and update the display
 
-    fetch("/api/readings", {
+    fetch("/api/v1/readings", {
         method: "GET",
         headers: {
             "X-CSRFToken": getCookie(),